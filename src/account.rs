@@ -1,9 +1,65 @@
 use serde::Deserialize;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct Account {
     pub login: String,
     pub server: Option<String>,
     pub port: Option<u16>,
+    /// Password used on connection instead of asking for it
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
     pub autoconnect: bool,
+    /// Nick used in channels, the local part of the JID by default
+    #[serde(default)]
+    pub nick: Option<String>,
+    #[serde(default)]
+    pub tls: Tls,
+    #[serde(default)]
+    pub transport: Transport,
+    /// URL of the WebSocket or BOSH service, looked up in the host-meta of the domain by default
+    pub url: Option<String>,
+    /// Join the channels bookmarked to be joined automatically on connection
+    #[serde(default = "default_autojoin")]
+    pub autojoin: bool,
+}
+
+fn default_autojoin() -> bool {
+    true
+}
+
+/// How the connection is carried, for networks allowing nothing but HTTPS
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    /// XMPP over WebSocket, RFC 7395
+    WebSocket,
+    /// XMPP over BOSH, XEP-0206
+    Bosh,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport::Tcp
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Tls {
+    /// SHA-256 fingerprint the certificate of the server must have, trusted even if not signed by
+    /// a known authority
+    pub pin: Option<String>,
+    /// Days before the expiry of the certificate of the server to warn about it
+    pub expiry_warning: i32,
+}
+
+impl Default for Tls {
+    fn default() -> Self {
+        Self {
+            pin: None,
+            expiry_warning: 14,
+        }
+    }
 }
@@ -0,0 +1,73 @@
+//! Control a running aparté through its control socket, as in
+//!
+//!     aparte-ctl send juliet@example.com "on my way"
+//!     aparte-ctl join room@conference.example.com
+//!     aparte-ctl presence away "Back in 10 minutes"
+//!     aparte-ctl unread
+//!     aparte-ctl command "/win 2"
+//!
+//! The answer of aparté is printed as JSON, the exit status telling whether the request succeeded.
+//! An aparté started with `--profile <name>` is controlled with `aparte-ctl --profile <name> …`,
+//! or with the profile in APARTE_PROFILE as set for the scripts aparté runs.
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process::exit;
+
+// Where the sockets of each profile are, shared with aparté
+#[allow(dead_code)]
+#[path = "../profile.rs"]
+mod profile;
+
+const USAGE: &str = "Usage: aparte-ctl [--profile <name>] send <jid> <message>|join <channel>|presence <show> [<status>]|unread|command <command>";
+
+/// Argument quoted as the ones of commands
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() >= 2 && args[0] == "--profile" {
+        if let Err(err) = profile::set(&args[1]) {
+            eprintln!("{}", err);
+            exit(2);
+        }
+        args.drain(..2);
+    }
+    if args.is_empty() || args[0] == "-h" || args[0] == "--help" {
+        eprintln!("{}", USAGE);
+        exit(2);
+    }
+
+    let path = match profile::control_socket() {
+        Ok(path) => path,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        },
+    };
+    let mut stream = match UnixStream::connect(&path) {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!("Cannot connect to aparté on {}: {}", path.display(), err);
+            exit(1);
+        },
+    };
+
+    let request: Vec<String> = args.iter().map(|arg| quote(arg)).collect();
+    if let Err(err) = writeln!(stream, "{}", request.join(" ")) {
+        eprintln!("Cannot send request: {}", err);
+        exit(1);
+    }
+
+    let mut answer = String::new();
+    if let Err(err) = BufReader::new(stream).read_line(&mut answer) {
+        eprintln!("Cannot read answer: {}", err);
+        exit(1);
+    }
+    print!("{}", answer);
+    let ok = serde_json::from_str::<serde_json::Value>(&answer).map(|answer| answer["ok"] == true).unwrap_or(false);
+    if !ok {
+        exit(1);
+    }
+}
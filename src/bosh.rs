@@ -0,0 +1,259 @@
+//! XMPP over BOSH, XEP-0124 and XEP-0206: stanzas are posted in HTTP requests, and received in
+//! the answers to the requests the server holds until it has something to send
+use futures::future::{self, Either};
+use futures::stream::FuturesUnordered;
+use futures::task::{self, Task};
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request, Uri};
+use std::collections::VecDeque;
+use std::io;
+use tokio_xmpp::{Error as XmppError, Packet};
+use url::Url;
+use uuid::Uuid;
+use xmpp_parsers::Element;
+
+use crate::client::{Connection, Stanzas};
+use crate::https::{self, HttpsClient};
+use crate::websocket::{serialize, NS_STREAMS};
+
+const NS_HTTPBIND: &str = "http://jabber.org/protocol/httpbind";
+const NS_XBOSH: &str = "urn:xmpp:xbosh";
+/// Seconds the server holds a request at most
+const WAIT: &str = "60";
+
+fn error<E: ToString>(err: E) -> XmppError {
+    XmppError::Io(io::Error::new(io::ErrorKind::Other, err.to_string()))
+}
+
+fn post(client: &HttpsClient, url: &Uri, body: Element) -> Box<dyn Future<Item = Element, Error = XmppError>> {
+    let request = Request::post(url.clone())
+        .header(CONTENT_TYPE, "text/xml; charset=utf-8")
+        .body(Body::from(serialize(&body)));
+    let request = match request {
+        Ok(request) => request,
+        Err(err) => return Box::new(future::err(error(err))),
+    };
+    Box::new(https::fetch(client, request).map_err(error).and_then(|body| {
+        String::from_utf8_lossy(&body).parse::<Element>().map_err(|err| error(format!("Invalid BOSH answer: {}", err)))
+    }))
+}
+
+/// Session opened with a BOSH service
+pub struct Bosh {
+    client: HttpsClient,
+    url: Uri,
+    domain: String,
+    sid: String,
+    rid: u64,
+    /// Requests the server accepts to handle at once
+    requests: usize,
+    features: Element,
+    outgoing: Vec<Element>,
+    pending: FuturesUnordered<Box<dyn Future<Item = Element, Error = XmppError>>>,
+    received: VecDeque<Element>,
+    terminated: bool,
+    /// Tasks reading and writing, sending and receiving being driven by both
+    reader: Option<Task>,
+    writer: Option<Task>,
+}
+
+impl Bosh {
+    /// Create a session with the service at `url` for `domain`
+    pub fn open(client: HttpsClient, url: Url, domain: String) -> Box<dyn Future<Item = Bosh, Error = XmppError>> {
+        let uri: Uri = match url.as_str().parse() {
+            Ok(uri) => uri,
+            Err(err) => return Box::new(future::err(error(err))),
+        };
+        // Request ids only need to be unpredictable and far from 2^53
+        let bytes = Uuid::new_v4();
+        let bytes = bytes.as_bytes();
+        let rid = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64;
+        let body = Element::builder("body").ns(NS_HTTPBIND)
+            .attr("content", "text/xml; charset=utf-8")
+            .attr("hold", "1")
+            .attr("rid", rid.to_string())
+            .attr("to", domain.as_str())
+            .attr("ver", "1.6")
+            .attr("wait", WAIT)
+            .attr("xml:lang", "en")
+            .attr("xmpp:version", "1.0")
+            .attr("xmlns:xmpp", NS_XBOSH)
+            .build();
+
+        Box::new(post(&client, &uri, body).and_then(move |answer| {
+            if answer.attr("type") == Some("terminate") {
+                return Either::A(future::err(error(format!("BOSH session refused: {}", answer.attr("condition").unwrap_or("no reason")))));
+            }
+            let sid = match answer.attr("sid") {
+                Some(sid) => sid.to_string(),
+                None => return Either::A(future::err(error("BOSH session without id"))),
+            };
+            let bosh = Bosh {
+                client: client,
+                url: uri,
+                domain: domain,
+                sid: sid,
+                rid: rid,
+                requests: answer.attr("requests").and_then(|requests| requests.parse().ok()).unwrap_or(2),
+                features: Element::builder("features").ns(NS_STREAMS).build(),
+                outgoing: Vec::new(),
+                pending: FuturesUnordered::new(),
+                received: answer.children().cloned().collect(),
+                terminated: false,
+                reader: None,
+                writer: None,
+            };
+            Either::B(bosh.wait_features())
+        }))
+    }
+
+    /// Next body of the session
+    fn body(&mut self) -> Element {
+        self.rid += 1;
+        Element::builder("body").ns(NS_HTTPBIND)
+            .attr("rid", self.rid.to_string())
+            .attr("sid", self.sid.as_str())
+            .build()
+    }
+
+    fn request(&mut self, mut body: Element) {
+        for stanza in self.outgoing.drain(..) {
+            body.append_child(stanza);
+        }
+        self.pending.push(post(&self.client, &self.url, body));
+        // The task reading polls the answer
+        if let Some(reader) = &self.reader {
+            reader.notify();
+        }
+    }
+
+    fn wait_features(self) -> Box<dyn Future<Item = Bosh, Error = XmppError>> {
+        Box::new(self.into_future().map_err(|(err, _)| err).and_then(|(packet, mut bosh)| match packet {
+            Some(Packet::Stanza(features)) if features.is("features", NS_STREAMS) => {
+                bosh.features = features;
+                Either::A(future::ok(bosh))
+            },
+            Some(_) => Either::B(bosh.wait_features()),
+            None => Either::A(future::err(XmppError::Disconnected)),
+        }))
+    }
+}
+
+impl Stream for Bosh {
+    type Item = Packet;
+    type Error = XmppError;
+
+    fn poll(&mut self) -> Poll<Option<Packet>, XmppError> {
+        self.reader = Some(task::current());
+        loop {
+            if let Some(stanza) = self.received.pop_front() {
+                return Ok(Async::Ready(Some(Packet::Stanza(stanza))));
+            }
+            if self.terminated {
+                return Ok(Async::Ready(None));
+            }
+            match self.pending.poll()? {
+                Async::Ready(Some(answer)) => {
+                    self.terminated = answer.attr("type") == Some("terminate");
+                    self.received.extend(answer.children().cloned());
+                    if let Some(writer) = self.writer.take() {
+                        writer.notify();
+                    }
+                },
+                // Always leave a request for the server to answer with what it has to send
+                Async::Ready(None) => {
+                    let body = self.body();
+                    self.request(body);
+                },
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+impl Sink for Bosh {
+    type SinkItem = Packet;
+    type SinkError = XmppError;
+
+    fn start_send(&mut self, packet: Packet) -> StartSend<Packet, XmppError> {
+        match packet {
+            Packet::Stanza(stanza) => self.outgoing.push(stanza),
+            Packet::StreamEnd => {
+                let mut body = self.body();
+                body.set_attr("type", "terminate");
+                self.request(body);
+            },
+            Packet::StreamStart(_) | Packet::Text(_) => {},
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), XmppError> {
+        if self.outgoing.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+        if self.pending.len() >= self.requests {
+            self.writer = Some(task::current());
+            return Ok(Async::NotReady);
+        }
+        let body = self.body();
+        self.request(body);
+        Ok(Async::Ready(()))
+    }
+
+    fn close(&mut self) -> Poll<(), XmppError> {
+        self.poll_complete()
+    }
+}
+
+impl Stanzas for Bosh {
+    fn features(&self) -> &Element {
+        &self.features
+    }
+
+    fn restart(mut self: Box<Self>) -> Box<dyn Future<Item = Connection, Error = XmppError>> {
+        let mut body = self.body();
+        body.set_attr("to", self.domain.as_str());
+        body.set_attr("xml:lang", "en");
+        body.set_attr("xmpp:restart", "true");
+        body.set_attr("xmlns:xmpp", NS_XBOSH);
+        self.request(body);
+        Box::new(self.wait_features().map(|bosh| Box::new(bosh) as Connection))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body() {
+        let mut bosh = Bosh {
+            client: https::client(None),
+            url: "https://server.tld/http-bind".parse().unwrap(),
+            domain: String::from("server.tld"),
+            sid: String::from("sid"),
+            rid: 41,
+            requests: 2,
+            features: Element::builder("features").ns(NS_STREAMS).build(),
+            outgoing: Vec::new(),
+            pending: FuturesUnordered::new(),
+            received: VecDeque::new(),
+            terminated: false,
+            reader: None,
+            writer: None,
+        };
+        let body = bosh.body();
+        assert_eq!(serialize(&body), r#"<body xmlns="http://jabber.org/protocol/httpbind" rid="42" sid="sid"/>"#);
+        assert_eq!(bosh.body().attr("rid"), Some("43"));
+        let mut restart = bosh.body();
+        restart.set_attr("xmpp:restart", "true");
+        restart.set_attr("xmlns:xmpp", NS_XBOSH);
+        assert!(serialize(&restart).contains(r#"xmlns:xmpp="urn:xmpp:xbosh" xmpp:restart="true""#));
+
+        let answer: Element = r#"<body xmlns="http://jabber.org/protocol/httpbind" xmlns:stream="http://etherx.jabber.org/streams"><stream:features><bind xmlns="urn:ietf:params:xml:ns:xmpp-bind"/></stream:features></body>"#.parse().unwrap();
+        let features = answer.children().next().unwrap();
+        assert!(features.is("features", NS_STREAMS));
+    }
+}
@@ -0,0 +1,662 @@
+//! Connection of an account, opened here rather than by tokio-xmpp's Client to decide how the
+//! certificate of the server is trusted, to honor the server and port set in the config, and to
+//! carry it over WebSocket or BOSH when only HTTPS goes through
+use futures::future::{self, Either, Loop};
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use hyper::{Body, Request};
+use native_tls::TlsConnector as NativeTlsConnector;
+use sasl::client::Mechanism;
+use sasl::client::mechanisms::Plain;
+use sasl::common::Credentials;
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tokio::net::TcpStream;
+use tokio_tls::{TlsConnector, TlsStream};
+use tokio_xmpp::xmpp_stream::XMPPStream;
+use tokio_xmpp::{ConnecterError, Error as XmppError, Packet, ProtocolError};
+use trust_dns_resolver::AsyncResolver;
+use trust_dns_resolver::config::LookupIpStrategy;
+use url::Url;
+use xmpp_parsers::bind::BindQuery;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::sasl::{Auth, Challenge, Failure, Mechanism as XmppMechanism, Response, Success};
+use xmpp_parsers::{Element, FullJid};
+
+use crate::account::{Account, Transport};
+use crate::bosh::Bosh;
+use crate::https;
+use crate::scram::{Binding, Hash, Scram};
+use crate::tls::{Certificate, Policy, Trust};
+use crate::websocket;
+
+const NS_CLIENT: &str = "jabber:client";
+const NS_TLS: &str = "urn:ietf:params:xml:ns:xmpp-tls";
+const NS_SASL: &str = "urn:ietf:params:xml:ns:xmpp-sasl";
+const NS_BIND: &str = "urn:ietf:params:xml:ns:xmpp-bind";
+const NS_CHANNEL_BINDING: &str = "urn:xmpp:sasl-cb:0";
+const DEFAULT_PORT: u16 = 5222;
+const BIND_ID: &str = "bind";
+
+const NS_XRD: &str = "http://docs.oasis-open.org/ns/xri/xrd-1.0";
+const REL_WEBSOCKET: &str = "urn:xmpp:alt-connections:websocket";
+const REL_BOSH: &str = "urn:xmpp:alt-connections:xbosh";
+
+/// Packets exchanged with the server, whatever carries them
+pub trait Stanzas: Stream<Item = Packet, Error = XmppError> + Sink<SinkItem = Packet, SinkError = XmppError> {
+    /// Features offered by the server when the stream was last started
+    fn features(&self) -> &Element;
+    /// Start the stream again, once authenticated
+    fn restart(self: Box<Self>) -> Box<dyn Future<Item = Connection, Error = XmppError>>;
+}
+
+pub type Connection = Box<dyn Stanzas>;
+
+/// Stream over TCP, secured with STARTTLS
+struct Tcp(XMPPStream<TlsStream<TcpStream>>);
+
+impl Stream for Tcp {
+    type Item = Packet;
+    type Error = XmppError;
+
+    fn poll(&mut self) -> Poll<Option<Packet>, XmppError> {
+        self.0.poll().map_err(XmppError::from)
+    }
+}
+
+impl Sink for Tcp {
+    type SinkItem = Packet;
+    type SinkError = XmppError;
+
+    fn start_send(&mut self, packet: Packet) -> StartSend<Packet, XmppError> {
+        self.0.start_send(packet).map_err(XmppError::Io)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), XmppError> {
+        self.0.poll_complete().map_err(XmppError::Io)
+    }
+
+    fn close(&mut self) -> Poll<(), XmppError> {
+        self.0.close().map_err(XmppError::Io)
+    }
+}
+
+impl Stanzas for Tcp {
+    fn features(&self) -> &Element {
+        &self.0.stream_features
+    }
+
+    fn restart(self: Box<Self>) -> Box<dyn Future<Item = Connection, Error = XmppError>> {
+        Box::new(self.0.restart().map(|stream| Box::new(Tcp(stream)) as Connection))
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Xmpp(XmppError),
+    /// Authentication failed, and why
+    Auth(String),
+    /// Certificate of the server not trusted, and why
+    Certificate(Certificate, String),
+}
+
+impl From<XmppError> for Error {
+    fn from(err: XmppError) -> Self {
+        Error::Xmpp(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Xmpp(err) => write!(f, "{}", err),
+            Error::Auth(reason) => write!(f, "authentication failed, {}", reason),
+            Error::Certificate(_, reason) => write!(f, "certificate not trusted, {}", reason),
+        }
+    }
+}
+
+/// How a connection was made, kept for diagnostics
+#[derive(Debug, Clone)]
+pub struct Details {
+    /// Transport the stream is carried over
+    pub transport: String,
+    /// Address of the server connected to, none for BOSH whose requests go through connections
+    /// of their own
+    pub address: Option<SocketAddr>,
+    pub certificate: Certificate,
+    pub trust: Trust,
+    /// SASL mechanism authenticated with, none for a stream authenticated beforehand
+    pub mechanism: Option<String>,
+}
+
+pub enum Event {
+    /// Connected, with how, and the features the server offers
+    Online(Details, Element),
+    Disconnected,
+    Stanza(Element),
+}
+
+fn resolver() -> Result<AsyncResolver, XmppError> {
+    let (config, mut options) = trust_dns_resolver::system_conf::read_system_conf()?;
+    options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+    let (resolver, background) = AsyncResolver::new(config, options);
+    tokio::runtime::current_thread::spawn(background);
+    Ok(resolver)
+}
+
+/// Hosts to connect to in order, from the SRV records of the domain unless the account sets them
+fn targets(resolver: &AsyncResolver, domain: &str, server: Option<String>, port: Option<u16>) -> Box<dyn Future<Item = VecDeque<(String, u16)>, Error = XmppError>> {
+    if server.is_some() || port.is_some() {
+        let host = server.unwrap_or(domain.to_string());
+        return Box::new(future::ok(vec![(host, port.unwrap_or(DEFAULT_PORT))].into()));
+    }
+
+    let fallback = (domain.to_string(), DEFAULT_PORT);
+    Box::new(resolver.lookup_srv(format!("_xmpp-client._tcp.{}.", domain).as_str()).then(move |lookup| {
+        let mut records: Vec<(u16, String, u16)> = match lookup {
+            Ok(lookup) => lookup.iter().map(|srv| (srv.priority(), srv.target().to_string(), srv.port())).collect(),
+            Err(_) => Vec::new(),
+        };
+        records.sort_by_key(|(priority, _, _)| *priority);
+        let mut targets: VecDeque<(String, u16)> = records.into_iter().map(|(_, host, port)| (host, port)).collect();
+        if targets.is_empty() {
+            targets.push_back(fallback);
+        }
+        Ok(targets)
+    }))
+}
+
+/// Connect to the first target answering, trying all the addresses of a target at once
+pub fn tcp(domain: String, server: Option<String>, port: Option<u16>) -> Box<dyn Future<Item = TcpStream, Error = XmppError>> {
+    Box::new(future::result(resolver()).and_then(move |resolver| {
+        targets(&resolver, &domain, server, port).and_then(move |targets| {
+            future::loop_fn((resolver, targets, None), |(resolver, mut targets, error): (AsyncResolver, VecDeque<(String, u16)>, Option<XmppError>)| {
+                let (host, port) = match targets.pop_front() {
+                    Some(target) => target,
+                    None => return Either::A(future::err(error.unwrap_or(XmppError::Connection(ConnecterError::AllFailed)))),
+                };
+                let addresses: Box<dyn Future<Item = Vec<IpAddr>, Error = XmppError>> = match IpAddr::from_str(&host) {
+                    Ok(ip) => Box::new(future::ok(vec![ip])),
+                    Err(_) => Box::new(resolver.lookup_ip(host.as_str())
+                        .map(|ips| ips.iter().collect())
+                        .map_err(|err| XmppError::Connection(ConnecterError::Resolve(err)))),
+                };
+                Either::B(addresses.and_then(move |ips| {
+                    match ips.is_empty() {
+                        true => Either::A(future::err(XmppError::Connection(ConnecterError::AllFailed))),
+                        false => Either::B(future::select_ok(ips.into_iter().map(move |ip| TcpStream::connect(&SocketAddr::new(ip, port))))
+                            .map(|(stream, _)| stream)
+                            .map_err(XmppError::Io)),
+                    }
+                }).then(move |result| match result {
+                    Ok(stream) => Ok(Loop::Break(stream)),
+                    Err(err) => Ok(Loop::Continue((resolver, targets, Some(err)))),
+                }))
+            })
+        })
+    }))
+}
+
+/// Wait for the server to accept to start TLS
+fn proceed(stream: XMPPStream<TcpStream>) -> Box<dyn Future<Item = XMPPStream<TcpStream>, Error = XmppError>> {
+    Box::new(stream.into_future().map_err(|(err, _)| err.into()).and_then(|(packet, stream)| match packet {
+        Some(Packet::Stanza(ref stanza)) if stanza.is("proceed", NS_TLS) => Either::A(future::ok(stream)),
+        Some(Packet::Stanza(ref stanza)) if stanza.is("failure", NS_TLS) => Either::A(future::err(ProtocolError::NoTls.into())),
+        Some(_) => Either::B(proceed(stream)),
+        None => Either::A(future::err(XmppError::Disconnected)),
+    }))
+}
+
+/// Open a stream and start TLS, checking the certificate against the known authorities if
+/// `verify`
+fn tls(jid: jid::Jid, account: Account, verify: bool) -> Box<dyn Future<Item = TlsStream<TcpStream>, Error = XmppError>> {
+    let domain = jid.clone().domain();
+    let tls_domain = domain.clone();
+    Box::new(tcp(domain, account.server, account.port)
+        .and_then(move |tcp| XMPPStream::start(tcp, jid, NS_CLIENT.to_string()))
+        .and_then(|stream| match stream.stream_features.get_child("starttls", NS_TLS) {
+            Some(_) => Ok(stream),
+            None => Err(ProtocolError::NoTls.into()),
+        })
+        .and_then(|stream| stream.send_stanza(Element::builder("starttls").ns(NS_TLS).build()).map_err(XmppError::Io))
+        .and_then(proceed)
+        .and_then(move |stream| handshake(&tls_domain, stream.into_inner(), verify)))
+}
+
+fn handshake(domain: &str, tcp: TcpStream, verify: bool) -> Box<dyn Future<Item = TlsStream<TcpStream>, Error = XmppError>> {
+    let connector = NativeTlsConnector::builder()
+        .danger_accept_invalid_certs(!verify)
+        .danger_accept_invalid_hostnames(!verify)
+        .build();
+    match connector {
+        Ok(connector) => Box::new(TlsConnector::from(connector).connect(domain, tcp).map_err(XmppError::Tls)),
+        Err(err) => Box::new(future::err(XmppError::Tls(err))),
+    }
+}
+
+/// Open a TLS connection to a host directly, as for HTTPS
+fn direct(host: String, port: u16, verify: bool) -> Box<dyn Future<Item = TlsStream<TcpStream>, Error = XmppError>> {
+    Box::new(tcp(host.clone(), Some(host.clone()), Some(port)).and_then(move |tcp| handshake(&host, tcp, verify)))
+}
+
+/// Open a TLS connection with `open`, checking the certificate against the known authorities. A
+/// second connection, not checking it, is made if it fails to check whether it is trusted
+/// otherwise as `policy` tells.
+fn secure<F>(open: F, policy: Policy) -> Box<dyn Future<Item = (TlsStream<TcpStream>, Certificate, Trust), Error = Error>>
+    where F: Fn(bool) -> Box<dyn Future<Item = TlsStream<TcpStream>, Error = XmppError>> + 'static
+{
+    Box::new(open(true).map(|stream| (stream, true)).or_else(move |err| match err {
+        XmppError::Tls(_) => Either::A(open(false).map(|stream| (stream, false))),
+        err => Either::B(future::err(err)),
+    }).map_err(Error::from).and_then(move |(stream, verified)| {
+        let certificate = match stream.get_ref().peer_certificate() {
+            Ok(Some(certificate)) => certificate,
+            Ok(None) => return Err(XmppError::InvalidState.into()),
+            Err(err) => return Err(XmppError::Tls(err).into()),
+        };
+        let certificate = match certificate.to_der().map_err(|err| err.to_string()).and_then(|der| Certificate::from_der(&der)) {
+            Ok(certificate) => certificate,
+            Err(err) => return Err(XmppError::Io(io::Error::new(io::ErrorKind::InvalidData, err)).into()),
+        };
+        // Nothing is sent over the connection before the certificate is trusted
+        match policy.check(&certificate, verified) {
+            Ok(trust) => Ok((stream, certificate, trust)),
+            Err(reason) => Err(Error::Certificate(certificate, reason)),
+        }
+    }))
+}
+
+/// URL of a service of the domain, as `rel`, in its host-meta
+pub fn alternative(host_meta: &Element, rel: &str) -> Option<String> {
+    host_meta.children()
+        .filter(|link| link.is("Link", NS_XRD) && link.attr("rel") == Some(rel))
+        .filter_map(|link| link.attr("href"))
+        .map(String::from)
+        .next()
+}
+
+/// URL of the WebSocket or BOSH service of an account, looked up with XEP-0156 unless set
+fn service(domain: String, account: &Account, rel: &'static str) -> Box<dyn Future<Item = Url, Error = XmppError>> {
+    let invalid = |err: String| XmppError::Io(io::Error::new(io::ErrorKind::InvalidData, err));
+    if let Some(url) = &account.url {
+        return Box::new(future::result(Url::parse(url).map_err(|err| invalid(format!("Invalid URL {}: {}", url, err)))));
+    }
+
+    let request = match Request::get(format!("https://{}/.well-known/host-meta", domain)).body(Body::empty()) {
+        Ok(request) => request,
+        Err(err) => return Box::new(future::err(invalid(format!("Invalid domain {}: {}", domain, err)))),
+    };
+    Box::new(https::fetch(&https::client(None), request).map_err(invalid).and_then(move |body| {
+        let host_meta = String::from_utf8_lossy(&body).parse::<Element>()
+            .map_err(|err| invalid(format!("Invalid host-meta of {}: {}", domain, err)))?;
+        let url = alternative(&host_meta, rel).ok_or(invalid(format!("No {} service advertised by {}", rel, domain)))?;
+        Url::parse(&url).map_err(|err| invalid(format!("Invalid URL {}: {}", url, err)))
+    }))
+}
+
+/// tls-server-end-point channel binding of a connection, the hash of the certificate of the server
+fn end_point(stream: &TlsStream<TcpStream>) -> Option<Vec<u8>> {
+    stream.get_ref().tls_server_end_point().ok().and_then(|end_point| end_point)
+}
+
+/// Address of the server a TLS connection is made to
+fn peer(stream: &TlsStream<TcpStream>) -> Option<SocketAddr> {
+    stream.get_ref().get_ref().peer_addr().ok()
+}
+
+/// Host and port of an HTTPS or secure WebSocket URL, refusing anything unencrypted
+fn endpoint(url: &Url) -> Result<(String, u16), XmppError> {
+    match (url.scheme(), url.host_str()) {
+        ("https", Some(host)) | ("wss", Some(host)) => Ok((host.to_string(), url.port().unwrap_or(443))),
+        _ => Err(XmppError::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("Unencrypted or invalid URL {}", url)))),
+    }
+}
+
+/// Name of a condition, as not-authorized
+fn condition(failure: &Failure) -> String {
+    let mut name = String::new();
+    for c in format!("{:?}", failure.defined_condition).chars() {
+        if c.is_uppercase() && !name.is_empty() {
+            name.push('-');
+        }
+        name.extend(c.to_lowercase());
+    }
+    name
+}
+
+/// Mechanisms to try in order, the -PLUS ones binding the authentication to the TLS connection so
+/// that someone in the middle can't relay it
+fn mechanisms(features: &Element, username: &str, password: &str, binding: Option<Vec<u8>>) -> Result<Vec<Box<dyn Mechanism>>, String> {
+    let offered: Vec<String> = match features.get_child("mechanisms", NS_SASL) {
+        Some(mechanisms) => mechanisms.children().filter(|child| child.is("mechanism", NS_SASL)).map(|child| child.text()).collect(),
+        None => Vec::new(),
+    };
+    // Servers offering -PLUS tell which bindings they support with XEP-0440, we only support
+    // tls-server-end-point
+    let bindings: HashSet<String> = match features.get_child("sasl-channel-binding", NS_CHANNEL_BINDING) {
+        Some(bindings) => bindings.children().filter_map(|binding| binding.attr("type")).map(String::from).collect(),
+        None => HashSet::new(),
+    };
+    let plus_offered = offered.iter().any(|mechanism| mechanism.ends_with("-PLUS"));
+    let unbound = match plus_offered {
+        true => Binding::None,
+        false => Binding::Unsupported,
+    };
+
+    let mut mechanisms: Vec<Box<dyn Mechanism>> = Vec::new();
+    if let Some(binding) = binding.filter(|_| bindings.contains("tls-server-end-point")) {
+        mechanisms.push(Box::new(Scram::new(Hash::Sha256, username, password, Binding::EndPoint(binding.clone()))?));
+        mechanisms.push(Box::new(Scram::new(Hash::Sha1, username, password, Binding::EndPoint(binding))?));
+    }
+    mechanisms.push(Box::new(Scram::new(Hash::Sha256, username, password, unbound.clone())?));
+    mechanisms.push(Box::new(Scram::new(Hash::Sha1, username, password, unbound)?));
+    let credentials = Credentials::default().with_username(username).with_password(password);
+    mechanisms.push(Box::new(Plain::from_credentials(credentials)?));
+
+    let mechanisms: Vec<Box<dyn Mechanism>> = mechanisms.into_iter().filter(|mechanism| offered.iter().any(|offered| offered == mechanism.name())).collect();
+    match mechanisms.is_empty() {
+        true => Err(format!("no mechanism in common, the server offers {}", offered.join(", "))),
+        false => Ok(mechanisms),
+    }
+}
+
+/// Authenticate with the strongest mechanism offered by the server, `binding` being the
+/// tls-server-end-point channel binding of the connection if it has one for itself
+fn auth(stream: Connection, username: String, password: String, binding: Option<Vec<u8>>) -> Box<dyn Future<Item = (Connection, String), Error = Error>> {
+    let mut mechanism = match mechanisms(stream.features(), &username, &password, binding) {
+        Ok(mechanisms) => mechanisms.into_iter().next().unwrap(),
+        Err(err) => return Box::new(future::err(Error::Auth(err))),
+    };
+    info!("Authenticating with {}", mechanism.name());
+    let name = mechanism.name().to_string();
+    let auth = match (mechanism.initial(), XmppMechanism::from_str(mechanism.name())) {
+        (Ok(initial), Ok(name)) => Auth { mechanism: name, data: initial },
+        (Err(err), _) => return Box::new(future::err(Error::Auth(err))),
+        (_, Err(err)) => return Box::new(future::err(Error::Auth(err.to_string()))),
+    };
+    Box::new(stream.send(Packet::Stanza(auth.into())).map_err(Error::from)
+        .and_then(move |stream| challenges(stream, mechanism))
+        .and_then(|stream| stream.restart().map_err(Error::from))
+        .map(move |stream| (stream, name)))
+}
+
+/// Answer the challenges of the server until it tells whether we are authenticated
+fn challenges(stream: Connection, mut mechanism: Box<dyn Mechanism>) -> Box<dyn Future<Item = Connection, Error = Error>> {
+    Box::new(stream.into_future().map_err(|(err, _)| Error::from(err)).and_then(move |(packet, stream)| {
+        let stanza = match packet {
+            Some(Packet::Stanza(stanza)) => stanza,
+            Some(_) => return challenges(stream, mechanism),
+            None => return Box::new(future::err(XmppError::Disconnected.into())),
+        };
+        if let Ok(challenge) = Challenge::try_from(stanza.clone()) {
+            match mechanism.response(&challenge.data) {
+                Ok(response) => Box::new(stream.send(Packet::Stanza(Response { data: response }.into())).map_err(Error::from)
+                    .and_then(move |stream| challenges(stream, mechanism))),
+                Err(err) => Box::new(future::err(Error::Auth(err))),
+            }
+        } else if let Ok(success) = Success::try_from(stanza.clone()) {
+            // Check the signature of the server with SCRAM
+            match mechanism.success(&success.data) {
+                Ok(()) => Box::new(future::ok(stream)),
+                Err(err) => Box::new(future::err(Error::Auth(err))),
+            }
+        } else if let Ok(failure) = Failure::try_from(stanza.clone()) {
+            let reason = match failure.texts.values().next() {
+                Some(text) => format!("the server refused with {}, {}", condition(&failure), text),
+                None => format!("the server refused with {}", condition(&failure)),
+            };
+            Box::new(future::err(Error::Auth(reason)))
+        } else {
+            challenges(stream, mechanism)
+        }
+    }))
+}
+
+/// Bind the resource of the JID
+fn bind(stream: Connection, resource: String) -> Box<dyn Future<Item = Connection, Error = XmppError>> {
+    if stream.features().get_child("bind", NS_BIND).is_none() {
+        return Box::new(future::ok(stream));
+    }
+    let iq = Iq::from_set(BIND_ID, BindQuery::new(Some(resource)));
+    Box::new(stream.send(Packet::Stanza(iq.into())).and_then(bound))
+}
+
+fn bound(stream: Connection) -> Box<dyn Future<Item = Connection, Error = XmppError>> {
+    Box::new(stream.into_future().map_err(|(err, _)| err).and_then(|(packet, stream)| {
+        let iq = match packet {
+            Some(Packet::Stanza(stanza)) => Iq::try_from(stanza).ok(),
+            Some(_) => None,
+            None => return Either::A(future::err(XmppError::Disconnected)),
+        };
+        match iq {
+            Some(Iq { id, payload: IqType::Result(_), .. }) if id == BIND_ID => Either::A(future::ok(stream)),
+            Some(Iq { id, .. }) if id == BIND_ID => Either::A(future::err(ProtocolError::InvalidBindResponse.into())),
+            _ => Either::B(bound(stream)),
+        }
+    }))
+}
+
+/// Connect and authenticate over the transport of the account, trusting the certificate of the
+/// server as `policy` tells
+fn connect(jid: &FullJid, password: String, account: Account, policy: Policy) -> Box<dyn Future<Item = (Connection, Details), Error = Error>> {
+    let stream_jid = match jid::Jid::from_str(&jid.to_string()) {
+        Ok(jid) => jid,
+        Err(_) => return Box::new(future::err(XmppError::InvalidState.into())),
+    };
+    let domain = jid.domain.clone();
+    let username = jid.node.clone().unwrap_or_default();
+    let resource = jid.resource.clone();
+    let start_jid = stream_jid.clone();
+
+    let connected: Box<dyn Future<Item = (Connection, Details, Option<Vec<u8>>), Error = Error>> = match account.transport {
+        Transport::Tcp => Box::new(secure(move |verify| tls(stream_jid.clone(), account.clone(), verify), policy).and_then(move |(stream, certificate, trust)| {
+            let binding = end_point(&stream);
+            let details = Details {
+                transport: String::from("TCP with STARTTLS"),
+                address: peer(&stream),
+                certificate: certificate,
+                trust: trust,
+                mechanism: None,
+            };
+            XMPPStream::start(stream, start_jid, NS_CLIENT.to_string())
+                .map(move |stream| (Box::new(Tcp(stream)) as Connection, details, binding))
+                .map_err(Error::from)
+        })),
+        Transport::WebSocket => Box::new(service(domain.clone(), &account, REL_WEBSOCKET).map_err(Error::from).and_then(move |url| {
+            let (host, port) = endpoint(&url)?;
+            Ok(secure(move |verify| direct(host.clone(), port, verify), policy).and_then(move |(stream, certificate, trust)| {
+                let binding = end_point(&stream);
+                let details = Details {
+                    transport: format!("WebSocket {}", url),
+                    address: peer(&stream),
+                    certificate: certificate,
+                    trust: trust,
+                    mechanism: None,
+                };
+                websocket::open(url, stream, domain)
+                    .map(move |stream| (Box::new(stream) as Connection, details, binding))
+                    .map_err(Error::from)
+            }))
+        }).flatten()),
+        Transport::Bosh => Box::new(service(domain.clone(), &account, REL_BOSH).map_err(Error::from).and_then(move |url| {
+            let (host, port) = endpoint(&url)?;
+            // The connections of the session, made by hyper, must have the certificate checked here.
+            // The session spans several of them, so the authentication can't be bound to one.
+            Ok(secure(move |verify| direct(host.clone(), port, verify), policy).and_then(move |(_, certificate, trust)| {
+                let fingerprint = match trust {
+                    Trust::Authority => None,
+                    Trust::Pinned | Trust::Accepted => Some(certificate.fingerprint.clone()),
+                };
+                let details = Details {
+                    transport: format!("BOSH {}", url),
+                    address: None,
+                    certificate: certificate,
+                    trust: trust,
+                    mechanism: None,
+                };
+                Bosh::open(https::client(fingerprint), url, domain)
+                    .map(move |stream| (Box::new(stream) as Connection, details, None))
+                    .map_err(Error::from)
+            }))
+        }).flatten()),
+    };
+
+    Box::new(connected.and_then(move |(stream, mut details, binding)| {
+        auth(stream, username, password, binding)
+            .and_then(move |(stream, mechanism)| {
+                details.mechanism = Some(mechanism);
+                bind(stream, resource).map_err(Error::from).map(move |stream| (stream, details))
+            })
+    }))
+}
+
+enum State {
+    Connecting(Box<dyn Future<Item = (Connection, Details), Error = Error>>),
+    Connected(Connection),
+    Disconnected,
+    Invalid,
+}
+
+/// Stream of the events of a connection, and sink of the packets sent over it
+pub struct Client {
+    state: State,
+}
+
+impl Client {
+    pub fn new(jid: &FullJid, password: String, account: Account, policy: Policy) -> Self {
+        Self {
+            state: State::Connecting(connect(jid, password, account, policy)),
+        }
+    }
+
+    /// Client of a stream already authenticated, as the one of a fake server
+    #[cfg(any(test, feature = "fakeserver"))]
+    pub fn connected(stream: Connection, details: Details) -> Self {
+        Self {
+            state: State::Connecting(Box::new(future::ok((stream, details)))),
+        }
+    }
+}
+
+impl Stream for Client {
+    type Item = Event;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match mem::replace(&mut self.state, State::Invalid) {
+            State::Connecting(mut connect) => match connect.poll()? {
+                Async::Ready((stream, details)) => {
+                    let features = stream.features().clone();
+                    self.state = State::Connected(stream);
+                    Ok(Async::Ready(Some(Event::Online(details, features))))
+                },
+                Async::NotReady => {
+                    self.state = State::Connecting(connect);
+                    Ok(Async::NotReady)
+                },
+            },
+            State::Connected(mut stream) => {
+                stream.poll_complete()?;
+                loop {
+                    match stream.poll()? {
+                        Async::Ready(Some(Packet::Stanza(stanza))) => {
+                            self.state = State::Connected(stream);
+                            return Ok(Async::Ready(Some(Event::Stanza(stanza))));
+                        },
+                        // Whitespace between stanzas
+                        Async::Ready(Some(Packet::Text(_))) => continue,
+                        Async::Ready(Some(Packet::StreamStart(_))) => return Err(XmppError::from(ProtocolError::InvalidStreamStart).into()),
+                        Async::Ready(Some(Packet::StreamEnd)) | Async::Ready(None) => {
+                            self.state = State::Disconnected;
+                            return Ok(Async::Ready(Some(Event::Disconnected)));
+                        },
+                        Async::NotReady => {
+                            self.state = State::Connected(stream);
+                            return Ok(Async::NotReady);
+                        },
+                    }
+                }
+            },
+            State::Disconnected => {
+                self.state = State::Disconnected;
+                Ok(Async::Ready(None))
+            },
+            State::Invalid => Err(XmppError::InvalidState.into()),
+        }
+    }
+}
+
+impl Sink for Client {
+    type SinkItem = Packet;
+    type SinkError = Error;
+
+    fn start_send(&mut self, packet: Packet) -> StartSend<Packet, Error> {
+        match &mut self.state {
+            State::Connected(stream) => Ok(stream.start_send(packet)?),
+            _ => Ok(AsyncSink::NotReady(packet)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        match &mut self.state {
+            State::Connected(stream) => Ok(stream.poll_complete()?),
+            _ => Ok(Async::Ready(())),
+        }
+    }
+
+    fn close(&mut self) -> Poll<(), Error> {
+        match &mut self.state {
+            State::Connected(stream) => Ok(stream.close()?),
+            _ => Ok(Async::Ready(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_meta() {
+        let host_meta: Element = r#"<XRD xmlns="http://docs.oasis-open.org/ns/xri/xrd-1.0">
+            <Link rel="urn:xmpp:alt-connections:xbosh" href="https://server.tld/http-bind"/>
+            <Link rel="urn:xmpp:alt-connections:websocket" href="wss://server.tld/xmpp-websocket"/>
+        </XRD>"#.parse().unwrap();
+        assert_eq!(alternative(&host_meta, REL_WEBSOCKET), Some(String::from("wss://server.tld/xmpp-websocket")));
+        assert_eq!(alternative(&host_meta, REL_BOSH), Some(String::from("https://server.tld/http-bind")));
+        assert_eq!(alternative(&host_meta, "urn:xmpp:alt-connections:httppoll"), None);
+
+        assert_eq!(endpoint(&Url::parse("wss://server.tld/xmpp-websocket").unwrap()).unwrap(), (String::from("server.tld"), 443));
+        assert_eq!(endpoint(&Url::parse("https://server.tld:5281/http-bind").unwrap()).unwrap(), (String::from("server.tld"), 5281));
+        assert!(endpoint(&Url::parse("ws://server.tld/xmpp-websocket").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_mechanisms() {
+        let names = |features: &str, binding: Option<Vec<u8>>| -> Result<Vec<String>, String> {
+            let features: Element = features.parse().unwrap();
+            mechanisms(&features, "user", "pencil", binding).map(|mechanisms| mechanisms.iter().map(|mechanism| mechanism.name().to_string()).collect())
+        };
+        let plus = r#"<features xmlns="http://etherx.jabber.org/streams">
+            <mechanisms xmlns="urn:ietf:params:xml:ns:xmpp-sasl">
+                <mechanism>PLAIN</mechanism><mechanism>SCRAM-SHA-1</mechanism><mechanism>SCRAM-SHA-256-PLUS</mechanism><mechanism>SCRAM-SHA-256</mechanism>
+            </mechanisms>
+            <sasl-channel-binding xmlns="urn:xmpp:sasl-cb:0"><channel-binding type="tls-exporter"/><channel-binding type="tls-server-end-point"/></sasl-channel-binding>
+        </features>"#;
+        assert_eq!(names(plus, Some(vec![1])).unwrap(), vec!["SCRAM-SHA-256-PLUS", "SCRAM-SHA-256", "SCRAM-SHA-1", "PLAIN"]);
+        // Not bound over BOSH
+        assert_eq!(names(plus, None).unwrap()[0], "SCRAM-SHA-256");
+        let unknown = plus.replace("tls-server-end-point", "tls-unique");
+        assert_eq!(names(&unknown, Some(vec![1])).unwrap()[0], "SCRAM-SHA-256");
+
+        let plain = r#"<features xmlns="http://etherx.jabber.org/streams"><mechanisms xmlns="urn:ietf:params:xml:ns:xmpp-sasl"><mechanism>PLAIN</mechanism><mechanism>EXTERNAL</mechanism></mechanisms></features>"#;
+        assert_eq!(names(plain, None).unwrap(), vec!["PLAIN"]);
+        let external = plain.replace("<mechanism>PLAIN</mechanism>", "");
+        assert_eq!(names(&external, None), Err(String::from("no mechanism in common, the server offers EXTERNAL")));
+    }
+}
@@ -0,0 +1,50 @@
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// Escape sequence asking the terminal to set the system clipboard (OSC 52), working over ssh
+pub fn osc52(text: &str) -> String {
+    format!("\x1b]52;c;{}\x07", base64::encode(text))
+}
+
+/// Command setting the clipboard of the graphical session, if there is one
+fn command() -> Option<(&'static str, &'static [&'static str])> {
+    if env::var_os("WAYLAND_DISPLAY").is_some() {
+        Some(("wl-copy", &[]))
+    } else if env::var_os("DISPLAY").is_some() {
+        Some(("xclip", &["-selection", "clipboard"]))
+    } else {
+        None
+    }
+}
+
+/// Set the clipboard with wl-copy or xclip, for terminals ignoring OSC 52. Nothing is done
+/// outside of a graphical session.
+pub fn copy(text: &str) -> Result<(), String> {
+    let (program, args) = match command() {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| format!("Cannot run {}: {}", program, err))?;
+    let written = child.stdin.take().unwrap().write_all(text.as_bytes());
+    // xclip keeps running to serve the selection, it is reaped without blocking the event loop
+    thread::spawn(move || child.wait());
+    written.map_err(|err| format!("Cannot copy with {}: {}", program, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_osc52() {
+        assert_eq!(osc52("héllo"), "\x1b]52;c;aMOpbGxv\x07");
+    }
+}
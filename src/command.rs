@@ -1,3 +1,4 @@
+use futures::{future, Future};
 use std::convert::TryFrom;
 use std::io::Error as IoError;
 use std::rc::Rc;
@@ -226,10 +227,31 @@ impl TryFrom<&str> for Command {
     }
 }
 
+/// Result of a command, resolved right away unless it waits for the network
+pub type CommandFuture = Box<dyn Future<Item = (), Error = String>>;
+
+/// What command handlers return on success: nothing when done, or a boxed future of the rest of
+/// the command, driven by core without blocking the interface
+pub trait IntoCommandFuture {
+    fn into_command_future(self) -> CommandFuture;
+}
+
+impl IntoCommandFuture for () {
+    fn into_command_future(self) -> CommandFuture {
+        Box::new(future::ok(()))
+    }
+}
+
+impl<F: Future<Item = (), Error = String> + ?Sized + 'static> IntoCommandFuture for Box<F> {
+    fn into_command_future(self) -> CommandFuture {
+        Box::new(self)
+    }
+}
+
 pub struct CommandParser {
     pub name: &'static str,
     pub help: &'static str,
-    pub parser: Box<dyn Fn(Rc<Aparte>, Command) -> Result<(), String>>,
+    pub parser: Box<dyn Fn(Rc<Aparte>, Command) -> CommandFuture>,
     pub completions: Vec<Option<Box<dyn Fn(&Aparte, Command) -> Vec<String>>>>,
 }
 
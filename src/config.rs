@@ -1,9 +1,672 @@
+use chrono::{Duration, NaiveTime};
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use termion::event::Key;
+use xmpp_parsers::BareJid;
 
 use crate::account::Account;
+use crate::theme::Theme;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub accounts: HashMap<String, Account>,
+    /// Name of the theme to use, either built-in or defined in `themes`
+    pub theme: Option<String>,
+    #[serde(default)]
+    pub themes: HashMap<String, Theme>,
+    #[serde(default)]
+    pub notifications: Notifications,
+    #[serde(default)]
+    pub previews: Previews,
+    #[serde(default)]
+    pub translation: Translation,
+    #[serde(default)]
+    pub reconnect: Reconnect,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+    #[serde(default)]
+    pub away: Away,
+    #[serde(default)]
+    pub pep: Pep,
+    /// Locale the interface is translated for, as fr_FR, the one of the environment by default
+    pub locale: Option<String>,
+    /// Hunspell dictionary the messages being typed are checked with, as en_US, none by default
+    pub spelling: Option<String>,
+    /// Nick used in some channels by JID, instead of the one of their bookmark or of the account
+    #[serde(default)]
+    pub nicks: HashMap<String, String>,
+    /// How contacts are grouped in the roster sidebar
+    #[serde(default)]
+    pub roster_grouping: RosterGrouping,
+    #[serde(default)]
+    pub macros: Macros,
+    /// Local history kept for some contacts and channels by JID, older messages being removed
+    /// from the history and left out of the log file whatever the server archives
+    #[serde(default)]
+    pub retention: HashMap<String, Retention>,
+    /// Start in low bandwidth mode, for metered connections
+    #[serde(default)]
+    pub low_bandwidth: bool,
+    /// Report the mouse to scroll, focus panes and windows and open URLs, at the expense of the
+    /// selection of the terminal
+    #[serde(default)]
+    pub mouse: bool,
+    /// Conversations whose messages are shown and confirmed before being sent, as announcement
+    /// channels
+    #[serde(default)]
+    pub confirm: Vec<String>,
+    /// Messages longer than this many lines, as pastes, are shown and confirmed before being sent,
+    /// 0 never asking
+    #[serde(default = "default_confirm_lines")]
+    pub confirm_lines: usize,
+    /// Actions run when contacts come online or go offline
+    #[serde(default)]
+    pub triggers: Vec<Trigger>,
+    /// Shell commands run on events
+    #[serde(default)]
+    pub hooks: Vec<Hook>,
+    /// Optional plugins enabled or disabled by name, every plugin is enabled by default
+    #[serde(default)]
+    pub plugins: HashMap<String, bool>,
+}
+
+impl Config {
+    pub fn plugin_enabled(&self, name: &str) -> bool {
+        self.plugins.get(name).cloned().unwrap_or(true)
+    }
+
+    /// Nick to join a channel with: the one configured for the channel, the one of its bookmark
+    /// or the one configured for the account
+    pub fn channel_nick(&self, channel: &BareJid, bookmark: Option<String>, account: &BareJid) -> Option<String> {
+        let account = account.to_string();
+        self.nicks.get(&channel.to_string()).cloned()
+            .or(bookmark)
+            .or(self.accounts.values().find(|stored| stored.login == account).and_then(|stored| stored.nick.clone()))
+    }
+
+    /// Whether to join the bookmarked channels of an account, accounts not in the config file
+    /// joining them
+    pub fn autojoin(&self, account: &BareJid) -> bool {
+        let account = account.to_string();
+        self.accounts.values().find(|stored| stored.login == account).map_or(true, |stored| stored.autojoin)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Notifications {
+    /// Send desktop notifications over DBus
+    pub desktop: bool,
+    /// Ring a bell when desktop notifications aren't available, as set in `bells`
+    pub bell: bool,
+    /// Bell of each kind of alert, audible, visual or none
+    pub bells: Bells,
+    /// Notification level of contacts and channels, by default every direct message is
+    /// notified but only mentions are in channels
+    pub conversations: HashMap<String, NotificationLevel>,
+    /// Words notified like mentions
+    pub keywords: Vec<String>,
+    /// Time range without notifications, as "22:00-08:00"
+    pub quiet_hours: Option<QuietHours>,
+}
+
+impl Default for Notifications {
+    fn default() -> Self {
+        Self {
+            desktop: true,
+            bell: true,
+            bells: Bells::default(),
+            conversations: HashMap::new(),
+            keywords: Vec::new(),
+            quiet_hours: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Previews {
+    /// Download images linked in messages to display them inline, only on terminals supporting
+    /// the kitty graphics protocol
+    pub enabled: bool,
+    /// Size in bytes above which images aren't downloaded
+    pub max_size: u64,
+}
+
+impl Default for Previews {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_size: 5 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Reconnect {
+    /// Reconnect automatically when a connection is lost
+    pub enabled: bool,
+    /// Failed reconnections after which a warning is shown in the status bar
+    pub alert_after: u32,
+    /// Also send a notification, or ring the bell, when showing the warning
+    pub notify: bool,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            alert_after: 3,
+            notify: true,
+        }
+    }
+}
+
+/// Pace of the stanzas sent, not to be disconnected by servers limiting it when pasting many
+/// lines or running scripts
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    /// Stanzas sent per second, 0 not to limit them
+    pub rate: f64,
+    /// Stanzas sent at once before being paced
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            rate: 5.,
+            burst: 20,
+        }
+    }
+}
+
+/// Presence switched to away after some time without keyboard input, and back on activity
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Away {
+    /// Minutes without keyboard input before going away, 0 to never go away
+    pub after: u64,
+    /// Status shown to contacts while away
+    pub status: Option<String>,
+}
+
+impl Default for Away {
+    fn default() -> Self {
+        Self {
+            after: 10,
+            status: None,
+        }
+    }
+}
+
+/// What we publish about ourselves to our contacts
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Pep {
+    /// Publish the tune played by media players, as told by playerctl over MPRIS
+    pub mpris: bool,
+}
+
+/// Service translating messages on /translate. `{lang}` is replaced by the target language in
+/// both the command and the URL.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Translation {
+    /// Shell command reading the text to translate on its standard input and writing the
+    /// translation on its standard output
+    pub command: Option<String>,
+    /// HTTPS endpoint the text to translate is posted to, answering with the translation, or HTTP
+    /// one for a service running on this machine
+    pub url: Option<String>,
+    /// Language to translate to, the one of the locale by default
+    pub lang: Option<String>,
+}
+
+/// How the terminal signals an alert
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bell {
+    /// Terminal bell
+    Audible,
+    /// Flash of the status bar, for silent environments
+    Visual,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Bells {
+    /// Direct messages, and messages of channels notifying every message
+    pub message: Bell,
+    /// Mentions and keywords in channels
+    pub mention: Bell,
+    /// Changes of status, as a connection being lost or a contact coming online
+    pub status: Bell,
+}
+
+impl Default for Bells {
+    fn default() -> Self {
+        Self {
+            message: Bell::Audible,
+            mention: Bell::Audible,
+            status: Bell::Audible,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationLevel {
+    All,
+    Mentions,
+    None,
+}
+
+impl FromStr for NotificationLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(NotificationLevel::All),
+            "mentions" => Ok(NotificationLevel::Mentions),
+            "none" => Ok(NotificationLevel::None),
+            _ => Err(format!("Invalid notification level {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for NotificationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NotificationLevel::All => write!(f, "all"),
+            NotificationLevel::Mentions => write!(f, "mentions"),
+            NotificationLevel::None => write!(f, "none"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RosterGrouping {
+    /// Groups of the roster
+    Groups,
+    /// Server of the contacts, or gateway they are bridged through
+    Servers,
+}
+
+impl Default for RosterGrouping {
+    fn default() -> Self {
+        RosterGrouping::Groups
+    }
+}
+
+impl FromStr for RosterGrouping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "groups" => Ok(RosterGrouping::Groups),
+            "servers" => Ok(RosterGrouping::Servers),
+            _ => Err(format!("Invalid roster grouping {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            // Range over midnight
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bounds = s.splitn(2, '-').map(|bound| NaiveTime::parse_from_str(bound.trim(), "%H:%M"));
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(start)), Some(Ok(end))) => Ok(QuietHours { start: start, end: end }),
+            _ => Err(format!("Invalid quiet hours {}, expected HH:MM-HH:MM", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for QuietHours {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        QuietHours::from_str(&s)
+    }
+}
+
+/// Duration written as a number followed by a unit, as "30m", "24h", "7d" or "2w"
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Retention(pub Duration);
+
+impl FromStr for Retention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.len() - s.chars().last().map(char::len_utf8).unwrap_or(0);
+        let count = i64::from_str(&s[..split]).ok().filter(|count| *count > 0);
+        let duration = match (count, &s[split..]) {
+            (Some(count), "m") => Duration::minutes(count),
+            (Some(count), "h") => Duration::hours(count),
+            (Some(count), "d") => Duration::days(count),
+            (Some(count), "w") => Duration::weeks(count),
+            _ => return Err(format!("Invalid retention {}, expected a duration as 30m, 24h, 7d or 2w", s)),
+        };
+        Ok(Retention(duration))
+    }
+}
+
+impl TryFrom<String> for Retention {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Retention::from_str(&s)
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let minutes = self.0.num_minutes();
+        match minutes {
+            _ if minutes % (7 * 24 * 60) == 0 => write!(f, "{}w", minutes / (7 * 24 * 60)),
+            _ if minutes % (24 * 60) == 0 => write!(f, "{}d", minutes / (24 * 60)),
+            _ if minutes % 60 == 0 => write!(f, "{}h", minutes / 60),
+            _ => write!(f, "{}m", minutes),
+        }
+    }
+}
+
+/// Presence change of a contact starting a trigger
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceChange {
+    /// First resource of the contact becoming available
+    Online,
+    /// Last resource of the contact becoming unavailable
+    Offline,
+}
+
+impl Default for PresenceChange {
+    fn default() -> Self {
+        PresenceChange::Online
+    }
+}
+
+impl FromStr for PresenceChange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "online" => Ok(PresenceChange::Online),
+            "offline" => Ok(PresenceChange::Offline),
+            _ => Err(format!("Invalid presence change {}, expected online or offline", s)),
+        }
+    }
+}
+
+impl fmt::Display for PresenceChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PresenceChange::Online => write!(f, "online"),
+            PresenceChange::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+/// Actions run when a contact comes online or goes offline, any of them can be combined
+#[derive(Debug, Clone, Deserialize)]
+pub struct Trigger {
+    pub jid: String,
+    #[serde(default)]
+    pub on: PresenceChange,
+    /// Notify the change
+    #[serde(default)]
+    pub notify: bool,
+    /// Command to run, as typed in the input
+    pub command: Option<String>,
+    /// Message sent to the contact, only once
+    pub message: Option<String>,
+}
+
+/// Event running a hook
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookEvent {
+    /// Message received from a contact or in a channel
+    Message,
+    /// Contact coming online
+    Online,
+    /// Our nick mentioned in a channel
+    Mention,
+    /// Call ringing, to answer it in another client
+    Call,
+}
+
+impl fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HookEvent::Message => write!(f, "message"),
+            HookEvent::Online => write!(f, "online"),
+            HookEvent::Mention => write!(f, "mention"),
+            HookEvent::Call => write!(f, "call"),
+        }
+    }
+}
+
+fn default_confirm_lines() -> usize {
+    5
+}
+
+fn default_hook_rate() -> usize {
+    10
+}
+
+/// Shell command run on an event, getting its details in APARTE_* environment variables and as
+/// JSON on its standard input
+#[derive(Debug, Clone, Deserialize)]
+pub struct Hook {
+    pub on: HookEvent,
+    pub command: String,
+    /// Runs per minute at most, the events coming faster being dropped
+    #[serde(default = "default_hook_rate")]
+    pub rate: usize,
+}
+
+/// Keys recording and replaying keyboard macros, each followed by the register holding the macro
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Macros {
+    /// Start recording into a register, or stop recording
+    pub record: KeyBinding,
+    /// Replay a register, or the last replayed one when followed by the key itself
+    pub replay: KeyBinding,
+}
+
+impl Default for Macros {
+    fn default() -> Self {
+        Self {
+            record: KeyBinding(Key::Alt('q')),
+            replay: KeyBinding(Key::Alt('@')),
+        }
+    }
+}
+
+/// Key written as a character, `alt-<char>`, `ctrl-<char>` or `f<number>`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeyBinding(pub Key);
+
+impl FromStr for KeyBinding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let single = |c: &str| {
+            let mut chars = c.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Some(c),
+                _ => None,
+            }
+        };
+        let lower = s.to_lowercase();
+        let key = if let Some(c) = single(s) {
+            Some(Key::Char(c))
+        } else if lower.starts_with("alt-") {
+            single(&s[4..]).map(Key::Alt)
+        } else if lower.starts_with("ctrl-") {
+            single(&s[5..]).map(|c| Key::Ctrl(c.to_ascii_lowercase()))
+        } else if lower.starts_with('f') {
+            u8::from_str(&s[1..]).ok().filter(|n| *n >= 1 && *n <= 12).map(Key::F)
+        } else {
+            None
+        };
+        key.map(KeyBinding).ok_or(format!("Invalid key {}, expected a character, alt-<char>, ctrl-<char> or f<number>", s))
+    }
+}
+
+impl TryFrom<String> for KeyBinding {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        KeyBinding::from_str(&s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quiet_hours() {
+        let night = QuietHours::from_str("22:00-08:00").unwrap();
+        assert!(night.contains(NaiveTime::from_hms_opt(23, 30, 0).unwrap()));
+        assert!(night.contains(NaiveTime::from_hms_opt(7, 59, 0).unwrap()));
+        assert!(!night.contains(NaiveTime::from_hms_opt(8, 0, 0).unwrap()));
+        assert!(!night.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+
+        let lunch = QuietHours::from_str("12:00 - 13:30").unwrap();
+        assert!(lunch.contains(NaiveTime::from_hms_opt(12, 45, 0).unwrap()));
+        assert!(!lunch.contains(NaiveTime::from_hms_opt(14, 0, 0).unwrap()));
+
+        assert!(QuietHours::from_str("22h-8h").is_err());
+    }
+
+    #[test]
+    fn test_plugins_config() {
+        let config: Config = toml::from_str(r#"
+            roster_grouping = "servers"
+            [accounts]
+            [plugins]
+            carbons = false
+            history = true
+        "#).unwrap();
+        assert!(!config.plugin_enabled("carbons"));
+        assert!(config.plugin_enabled("history"));
+        assert!(config.plugin_enabled("notifications"));
+        assert_eq!(config.roster_grouping, RosterGrouping::Servers);
+    }
+
+    #[test]
+    fn test_retention() {
+        assert_eq!(Retention::from_str("24h"), Ok(Retention(Duration::hours(24))));
+        assert_eq!(Retention::from_str("14d").unwrap().to_string(), "2w");
+        assert_eq!(Retention::from_str("90m").unwrap().to_string(), "90m");
+        assert!(Retention::from_str("0h").is_err());
+        assert!(Retention::from_str("24").is_err());
+        assert!(Retention::from_str("").is_err());
+
+        let config: Config = toml::from_str(r#"
+            [accounts]
+            [retention]
+            "secret@server.tld" = "1d"
+        "#).unwrap();
+        assert_eq!(config.retention["secret@server.tld"], Retention(Duration::days(1)));
+    }
+
+    #[test]
+    fn test_key_binding() {
+        assert_eq!(KeyBinding::from_str("q"), Ok(KeyBinding(Key::Char('q'))));
+        assert_eq!(KeyBinding::from_str("alt-@"), Ok(KeyBinding(Key::Alt('@'))));
+        assert_eq!(KeyBinding::from_str("Ctrl-X"), Ok(KeyBinding(Key::Ctrl('x'))));
+        assert_eq!(KeyBinding::from_str("f5"), Ok(KeyBinding(Key::F(5))));
+        assert!(KeyBinding::from_str("f13").is_err());
+        assert!(KeyBinding::from_str("alt-ab").is_err());
+
+        let config: Config = toml::from_str(r#"
+            [accounts]
+            [macros]
+            record = "f9"
+        "#).unwrap();
+        assert_eq!(config.macros.record.0, Key::F(9));
+        assert_eq!(config.macros.replay.0, Key::Alt('@'));
+    }
+
+    #[test]
+    fn test_notifications_config() {
+        let notifications: Notifications = toml::from_str(r#"
+            keywords = ["release"]
+            quiet_hours = "22:00-08:00"
+
+            [conversations]
+            "busy@conference.server.tld" = "mentions"
+            "spam@server.tld" = "none"
+        "#).unwrap();
+
+        assert_eq!(notifications.conversations.get("spam@server.tld"), Some(&NotificationLevel::None));
+        assert_eq!(notifications.keywords, vec!["release"]);
+        assert!(notifications.desktop);
+        assert!(notifications.quiet_hours.is_some());
+        assert_eq!(notifications.bells, Bells::default());
+
+        let notifications: Notifications = toml::from_str(r#"
+            [bells]
+            message = "visual"
+            status = "none"
+        "#).unwrap();
+        assert_eq!(notifications.bells.message, Bell::Visual);
+        assert_eq!(notifications.bells.mention, Bell::Audible);
+        assert_eq!(notifications.bells.status, Bell::None);
+    }
+
+    #[test]
+    fn test_channel_nick() {
+        let config: Config = toml::from_str(r#"
+            [accounts.work]
+            login = "me@work.tld"
+            nick = "Me"
+
+            [nicks]
+            "team@conference.work.tld" = "Me (work)"
+        "#).unwrap();
+        let account = BareJid::from_str("me@work.tld").unwrap();
+        let team = BareJid::from_str("team@conference.work.tld").unwrap();
+        let other = BareJid::from_str("other@conference.work.tld").unwrap();
+
+        assert_eq!(config.channel_nick(&team, Some(String::from("bookmark")), &account).as_deref(), Some("Me (work)"));
+        assert_eq!(config.channel_nick(&other, Some(String::from("bookmark")), &account).as_deref(), Some("bookmark"));
+        assert_eq!(config.channel_nick(&other, None, &account).as_deref(), Some("Me"));
+        assert_eq!(config.channel_nick(&other, None, &BareJid::from_str("me@home.tld").unwrap()), None);
+    }
 }
@@ -1,6 +1,10 @@
 use xmpp_parsers::roster::Subscription;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
-use xmpp_parsers::BareJid;
+use std::str::FromStr;
+use xmpp_parsers::{BareJid, FullJid, Jid, JidParseError};
+
+use crate::config::PresenceChange;
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug)]
 pub enum Presence {
@@ -12,7 +16,7 @@ pub enum Presence {
     Xa,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialOrd, Ord)]
 pub struct Group(pub String);
 
 impl Hash for Group {
@@ -29,6 +33,76 @@ impl PartialEq for Group {
 
 impl Eq for Group {}
 
+/// Resources of each contact currently available, telling when contacts come online or go
+/// offline
+#[derive(Default)]
+pub struct Availability {
+    online: HashMap<BareJid, HashSet<String>>,
+}
+
+impl Availability {
+    /// Handle the presence of a resource, returns the change if the contact came online or went
+    /// offline
+    pub fn update(&mut self, jid: &FullJid, presence: &Presence) -> Option<PresenceChange> {
+        let contact: BareJid = jid.clone().into();
+        let resources = self.online.entry(contact).or_insert_with(HashSet::new);
+        match presence {
+            Presence::Unavailable => match resources.remove(&jid.resource) && resources.is_empty() {
+                true => Some(PresenceChange::Offline),
+                false => None,
+            },
+            _ => match resources.insert(jid.resource.clone()) && resources.len() == 1 {
+                true => Some(PresenceChange::Online),
+                false => None,
+            },
+        }
+    }
+
+    /// Forget every resource, as contacts aren't going offline when we are
+    pub fn clear(&mut self) {
+        self.online.clear();
+    }
+}
+
+/// Tune, mood or activity a contact publishes (XEP-0118, XEP-0107 and XEP-0108), none once they
+/// stop publishing it
+#[derive(Clone, Debug, PartialEq)]
+pub enum Published {
+    Tune(Option<String>),
+    Mood(Option<String>),
+    Activity(Option<String>),
+}
+
+/// What a contact last published about themselves
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserInfo {
+    pub tune: Option<String>,
+    pub mood: Option<String>,
+    pub activity: Option<String>,
+}
+
+impl UserInfo {
+    /// Apply what was published, returns whether it changed
+    pub fn update(&mut self, published: Published) -> bool {
+        let (field, value) = match published {
+            Published::Tune(tune) => (&mut self.tune, tune),
+            Published::Mood(mood) => (&mut self.mood, mood),
+            Published::Activity(activity) => (&mut self.activity, activity),
+        };
+        match *field != value {
+            true => {
+                *field = value;
+                true
+            },
+            false => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tune.is_none() && self.mood.is_none() && self.activity.is_none()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Contact {
     pub jid: BareJid,
@@ -36,6 +110,7 @@ pub struct Contact {
     pub subscription: Subscription,
     pub presence: Presence,
     pub groups: Vec<Group>,
+    pub info: UserInfo,
 }
 
 impl Hash for Contact {
@@ -51,3 +126,96 @@ impl PartialEq for Contact {
 }
 
 impl Eq for Contact {}
+
+/// Characters escaped in the local part of JIDs, as gateways do for the addresses of legacy
+/// networks (XEP-0106: JID Escaping)
+const ESCAPES: [(char, &str); 10] = [
+    (' ', "\\20"), ('"', "\\22"), ('&', "\\26"), ('\'', "\\27"), ('/', "\\2f"),
+    (':', "\\3a"), ('<', "\\3c"), ('>', "\\3e"), ('@', "\\40"), ('\\', "\\5c"),
+];
+
+/// Character escaped by a sequence starting a string
+fn escaped(string: &str) -> Option<char> {
+    let sequence = string.get(..3)?;
+    ESCAPES.iter().find(|(_, escape)| escape.eq_ignore_ascii_case(sequence)).map(|(c, _)| *c)
+}
+
+/// Local part of a JID escaped, a backslash only being escaped when it starts what would be an
+/// escape sequence
+pub fn escape_node(node: &str) -> String {
+    let mut escaped_node = String::with_capacity(node.len());
+    for (index, c) in node.char_indices() {
+        match ESCAPES.iter().find(|(escaped, _)| *escaped == c) {
+            Some(('\\', _)) if escaped(&node[index..]).is_none() => escaped_node.push(c),
+            Some((_, escape)) => escaped_node.push_str(escape),
+            None => escaped_node.push(c),
+        }
+    }
+    escaped_node
+}
+
+/// Local part of a JID as typed by users
+pub fn unescape_node(node: &str) -> String {
+    let mut unescaped = String::with_capacity(node.len());
+    let mut chars = node.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match escaped(&node[index..]).filter(|_| c == '\\') {
+            Some(escaped) => {
+                unescaped.push(escaped);
+                chars.nth(1);
+            },
+            None => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// JID shown with its local part unescaped, as user@gmail.com@gateway for
+/// user\\40gmail.com@gateway
+pub fn display_jid(jid: &BareJid) -> String {
+    match &jid.node {
+        Some(node) => format!("{}@{}", unescape_node(node), jid.domain),
+        None => jid.domain.clone(),
+    }
+}
+
+/// JID typed by users, either escaped or not: the local part is escaped when it contains
+/// characters JIDs can't, as user@gmail.com@gateway
+pub fn parse_jid(jid: &str) -> Result<Jid, JidParseError> {
+    // The resource may contain any character, the local part ends with the last @ before it
+    let resource = jid.find('/').unwrap_or(jid.len());
+    let at = match jid[..resource].rfind('@') {
+        Some(at) => at,
+        None => return Jid::from_str(jid),
+    };
+    let node = &jid[..at];
+    match node.chars().any(|c| c != '/' && c != '\\' && ESCAPES.iter().any(|(escaped, _)| *escaped == c)) {
+        true => Jid::from_str(&format!("{}@{}", escape_node(node), &jid[at + 1..])),
+        false => Jid::from_str(jid),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jid_escaping() {
+        assert_eq!(escape_node("user@gmail.com"), "user\\40gmail.com");
+        assert_eq!(escape_node("d'artagnan"), "d\\27artagnan");
+        assert_eq!(escape_node("c:\\net"), "c\\3a\\net");
+        assert_eq!(escape_node("c:\\5commas"), "c\\3a\\5c5commas");
+        assert_eq!(unescape_node("user\\40gmail.com"), "user@gmail.com");
+        assert_eq!(unescape_node("c\\3a\\net"), "c:\\net");
+        assert_eq!(unescape_node("c\\3A\\5c5commas"), "c:\\5commas");
+        assert_eq!(unescape_node("\\4"), "\\4");
+
+        let jid = BareJid::from_str("user\\40gmail.com@gateway.tld").unwrap();
+        assert_eq!(display_jid(&jid), "user@gmail.com@gateway.tld");
+        assert_eq!(parse_jid("user@gmail.com@gateway.tld").unwrap(), Jid::Bare(jid.clone()));
+        assert_eq!(parse_jid("user\\40gmail.com@gateway.tld").unwrap(), Jid::Bare(jid));
+        assert_eq!(parse_jid("juliet@capulet.lit/balcony").unwrap().to_string(), "juliet@capulet.lit/balcony");
+        assert_eq!(parse_jid("room@chat.lit/nick@home").unwrap().to_string(), "room@chat.lit/nick@home");
+        assert_eq!(parse_jid("capulet.lit").unwrap().to_string(), "capulet.lit");
+    }
+}
@@ -0,0 +1,178 @@
+//! Control socket, so that external tools as aparte-ctl or window manager scripts can make aparté
+//! send messages, join channels, change presence or tell unread counts. Requests are lines of
+//! arguments quoted as the ones of commands, each answered by a JSON object on a line.
+use chrono::Utc;
+use futures::{future, Future, Stream};
+use std::convert::TryFrom;
+use std::fs;
+use std::io::BufReader;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+use tokio::io::AsyncRead;
+use serde_json::{json, Map, Value};
+use tokio::net::UnixListener;
+use uuid::Uuid;
+use xmpp_parsers::{BareJid, Jid};
+
+use crate::command::Command;
+use crate::conversation::Conversation;
+use crate::core::Aparte;
+use crate::message::Message;
+use crate::plugins::conversation::ConversationPlugin;
+use crate::plugins::ui::UIPlugin;
+use crate::profile;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Request {
+    Send(BareJid, String),
+    Join(String),
+    /// Show and status, handled by /presence
+    Presence(String, Option<String>),
+    Unread,
+    /// Any command, as typed in the input
+    Command(String),
+}
+
+fn parse(line: &str) -> Result<Request, String> {
+    let command = Command::try_from(format!("/{}", line).as_str()).map_err(String::from)?;
+    let args: Vec<&str> = command.args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["send", jid, body] => match BareJid::from_str(jid) {
+            Ok(jid) => Ok(Request::Send(jid, body.to_string())),
+            Err(err) => Err(format!("Invalid JID {}: {}", jid, err)),
+        },
+        ["join", channel] => Ok(Request::Join(channel.to_string())),
+        ["presence", show] => Ok(Request::Presence(show.to_string(), None)),
+        ["presence", show, status] => Ok(Request::Presence(show.to_string(), Some(status.to_string()))),
+        ["unread"] => Ok(Request::Unread),
+        ["command", command] => Ok(Request::Command(command.to_string())),
+        ["send", ..] | ["join", ..] | ["presence", ..] | ["unread", ..] | ["command", ..] => Err(format!("Wrong arguments for {}", args[0])),
+        _ => Err(format!("Unknown request {}, expected send, join, presence, unread or command", args[0])),
+    }
+}
+
+/// Handle a request, returns the fields to add to the answer
+fn handle(aparte: Rc<Aparte>, request: Request) -> Result<Map<String, Value>, String> {
+    match request {
+        Request::Send(to, body) => {
+            let from: Jid = match aparte.current_connection() {
+                Some(connection) => connection.into(),
+                None => return Err(format!("Not connected")),
+            };
+            let channel = match aparte.get_plugin::<ConversationPlugin>().unwrap().get(&to) {
+                Some(Conversation::Channel(_)) => true,
+                _ => false,
+            };
+            let id = Uuid::new_v4().to_string();
+            let to = Jid::Bare(to);
+            let message = match channel {
+                true => Message::outgoing_groupchat(id, Utc::now(), &from, &to, &body),
+                false => Message::outgoing_chat(id, Utc::now(), &from, &to, &body),
+            };
+            crate::send_message(aparte, message);
+        },
+        Request::Join(channel) => aparte.parse_command(Command::new(vec![String::from("join"), channel]))?,
+        Request::Presence(show, status) => {
+            let mut args = vec![String::from("presence"), show];
+            args.extend(status);
+            aparte.parse_command(Command::new(args))?;
+        },
+        Request::Unread => {
+            let unread = aparte.get_plugin_mut::<UIPlugin>().unwrap().unread();
+            let windows: Map<String, Value> = unread.into_iter().map(|(window, unread, mentions)| {
+                (window, json!({"unread": unread, "mentions": mentions}))
+            }).collect();
+            let mut fields = Map::new();
+            fields.insert(String::from("unread"), Value::Object(windows));
+            return Ok(fields);
+        },
+        Request::Command(command) => {
+            let command = Command::try_from(command.as_str()).map_err(String::from)?;
+            aparte.parse_command(command)?;
+        },
+    }
+    Ok(Map::new())
+}
+
+fn answer(result: Result<Map<String, Value>, String>) -> String {
+    let answer = match result {
+        Ok(mut fields) => {
+            fields.insert(String::from("ok"), Value::Bool(true));
+            Value::Object(fields)
+        },
+        Err(err) => json!({"ok": false, "error": err}),
+    };
+    format!("{}\n", answer)
+}
+
+/// Listen on a socket in a directory only accessible by us, unless another instance already
+/// does. The directory being private, the socket isn't reachable by others even for the time
+/// it has the permissions of the umask.
+pub fn bind(path: &Path) -> Result<UnixListener, String> {
+    if let Some(dir) = path.parent() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir).map_err(|err| format!("Cannot create {}: {}", dir.display(), err))?;
+        // Created by an older version with the default permissions
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700)).map_err(|err| format!("Cannot restrict access to {}: {}", dir.display(), err))?;
+    }
+    if StdUnixStream::connect(path).is_ok() {
+        return Err(format!("Another instance listens on {}", path.display()));
+    }
+    // Left by an instance that didn't quit cleanly
+    let _ = fs::remove_file(path);
+
+    UnixListener::bind(path).map_err(|err| format!("Cannot listen on {}: {}", path.display(), err))
+}
+
+/// Listen on the control socket
+pub fn serve(aparte: Rc<Aparte>) -> impl Future<Item = (), Error = ()> {
+    future::lazy(move || {
+        let listener = match profile::control_socket().and_then(|path| bind(&path)) {
+            Ok(listener) => listener,
+            Err(err) => {
+                warn!("{}", err);
+                return future::Either::A(future::ok(()));
+            },
+        };
+
+        future::Either::B(listener.incoming().map_err(|err| warn!("Control socket error: {}", err)).for_each(move |stream| {
+            let (reader, writer) = stream.split();
+            let aparte = Rc::clone(&aparte);
+            let answers = tokio::io::lines(BufReader::new(reader)).map(move |line| {
+                answer(parse(&line).and_then(|request| handle(Rc::clone(&aparte), request)))
+            });
+            let session = answers.fold(writer, |writer, answer| {
+                tokio::io::write_all(writer, answer).map(|(writer, _)| writer)
+            });
+            tokio::runtime::current_thread::spawn(session.map(|_| ()).map_err(|err| warn!("Control connection error: {}", err)));
+            Ok(())
+        }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(r#"send juliet@example.com "on my way""#), Ok(Request::Send(BareJid::from_str("juliet@example.com").unwrap(), String::from("on my way"))));
+        assert_eq!(parse("join room@conference.example.com"), Ok(Request::Join(String::from("room@conference.example.com"))));
+        assert_eq!(parse(r#"presence away "Lunch""#), Ok(Request::Presence(String::from("away"), Some(String::from("Lunch")))));
+        assert_eq!(parse("unread"), Ok(Request::Unread));
+        assert_eq!(parse(r#"command "/win 2""#), Ok(Request::Command(String::from("/win 2"))));
+        assert!(parse("send juliet@example.com").is_err());
+        assert!(parse("dance").is_err());
+    }
+
+    #[test]
+    fn test_answer() {
+        assert_eq!(answer(Ok(Map::new())), "{\"ok\":true}\n");
+        let mut fields = Map::new();
+        fields.insert(String::from("unread"), json!({"room@conference.example.com": {"unread": 2, "mentions": 1}}));
+        assert_eq!(answer(Ok(fields)), "{\"ok\":true,\"unread\":{\"room@conference.example.com\":{\"mentions\":1,\"unread\":2}}}\n");
+        assert_eq!(answer(Err(String::from("Not \"connected\""))), "{\"error\":\"Not \\\"connected\\\"\",\"ok\":false}\n");
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
-use xmpp_parsers::BareJid;
+use xmpp_parsers::{BareJid, FullJid};
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug, Copy)]
 pub enum Affiliation {
@@ -28,10 +28,13 @@ pub struct Occupant {
 
 #[derive(Clone, Debug)]
 pub struct Channel {
+    /// Account the channel was joined on
+    pub account: FullJid,
     pub jid: BareJid,
     pub nick: String,
     pub name: Option<String>,
     pub occupants: HashMap<String, Occupant>,
+    pub subject: Option<String>,
 }
 
 pub struct Chat {
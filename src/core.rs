@@ -1,21 +1,81 @@
-use futures::Sink;
+use chrono::{DateTime, Utc};
+use futures::{future, Async, Future, Sink};
 use futures::unsync::mpsc::UnboundedSender;
+use futures::unsync::oneshot;
 use std::any::{Any, TypeId};
-use std::cell::{RefCell, RefMut, Ref};
+use std::cell::{Cell, RefCell, RefMut, Ref};
 use std::collections::HashMap;
 use std::fmt;
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::path::PathBuf;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+use tokio::runtime::current_thread::TaskExecutor;
+use tokio::timer::{Delay, Timeout};
 use tokio_xmpp::Packet;
-use xmpp_parsers::{Element, FullJid, BareJid, presence, iq};
+use xmpp_parsers::{Element, FullJid, BareJid, Jid, presence, iq};
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::stanza_error::StanzaError;
 use xmpp_parsers;
 
 use crate::{contact, conversation};
 use crate::message::Message;
 use crate::command::{Command, CommandParser};
 use crate::config::Config;
+use crate::i18n;
+use crate::plugins::calls::Call;
+use crate::invitation::Invitation;
+use crate::client::Details;
+use crate::queue::SendQueue;
+use crate::settings::{self, Settings};
+use crate::store::Subject;
+
+/// Delay after which a request sent with send_iq is given up
+const IQ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Defined condition of an error followed by its description, if any
+pub fn stanza_error_text(error: &StanzaError) -> String {
+    let condition = Element::from(error.defined_condition.clone()).name().to_string();
+    match error.texts.get("").or(error.texts.values().next()) {
+        Some(text) => format!("{}: {}", condition, text),
+        None => condition,
+    }
+}
+
+/// Why a request sent with send_iq failed
+#[derive(Debug, Clone)]
+pub enum IqError {
+    /// Error answered by the entity
+    Error(StanzaError),
+    Timeout,
+    Disconnected,
+}
+
+impl fmt::Display for IqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IqError::Error(error) => write!(f, "{}", stanza_error_text(error)),
+            IqError::Timeout => write!(f, "no answer after {}s", IQ_TIMEOUT.as_secs()),
+            IqError::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+/// Request sent with send_iq waiting for its answer
+struct PendingIq {
+    to: Option<Jid>,
+    sender: oneshot::Sender<Iq>,
+}
+
+/// Whether a JID is the one of an account, or of its server, which answer on behalf of it
+fn is_account(account: &FullJid, jid: &Jid) -> bool {
+    let bare: BareJid = account.clone().into();
+    match jid {
+        Jid::Full(jid) => jid == account,
+        Jid::Bare(jid) => *jid == bare || (jid.node.is_none() && jid.domain == account.domain),
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum CommandOrMessage {
@@ -24,27 +84,179 @@ pub enum CommandOrMessage {
 }
 
 pub enum Event {
+    /// Connection of an account started, before it is online
+    Connecting(FullJid),
     Connected(FullJid),
-    #[allow(dead_code)]
     Disconnected(FullJid),
     Message(Message),
+    /// Message fetched from an archive, with the id of the query which fetched it
+    ArchivedMessage(String, Message),
+    /// Error returned for a message of a conversation, with the id of the message
+    MessageError(BareJid, Option<String>, String),
     Chat(BareJid),
-    Join(FullJid),
-    Iq(iq::Iq),
-    Presence(presence::Presence),
+    /// Channel joined on an account, with the occupant we join as
+    Join(FullJid, FullJid),
+    /// Iq received on an account
+    Iq(FullJid, iq::Iq),
+    /// Presence received on an account
+    Presence(FullJid, presence::Presence),
     ReadPassword(Command),
     Win(String),
     Contact(contact::Contact),
     ContactUpdate(contact::Contact),
+    /// Presence of a resource of a contact, unavailable when it goes offline
+    ResourcePresence(FullJid, contact::Presence),
     Occupant(conversation::Occupant),
+    /// Occupant of a channel leaving it, by nick
+    OccupantLeft(BareJid, String),
+    /// Occupant of a channel, maybe us, changing nick from the first one to the second one
+    NickChange(BareJid, String, String),
+    /// Nick change refused by a channel, with the refused nick and the error condition
+    NickChangeError(BareJid, String, String),
+    Moved(BareJid, BareJid, Option<String>),
+    Invitation(Invitation),
+    /// Tune, mood or activity published by a contact
+    UserInfo(BareJid, contact::Published),
+    /// Call ringing, which we can only decline
+    Call(Call),
+    /// Call which stopped ringing, by session id with the reason
+    CallEnded(String, String),
+    /// Subject of a channel, sent on join and when changed
+    Subject(BareJid, Subject),
     Signal(i32),
+    /// Keyboard input, telling the user is there
+    Activity,
+    /// Raw stanza received from the server
+    ReceivedStanza(Element),
+    /// Raw stanza sent to the server
+    SentStanza(Element),
     Quit,
 }
 
+/// Kind of an event, to subscribe to the events of this kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Connecting,
+    Connected,
+    Disconnected,
+    Message,
+    ArchivedMessage,
+    MessageError,
+    Chat,
+    Join,
+    Iq,
+    Presence,
+    ReadPassword,
+    Win,
+    Contact,
+    ContactUpdate,
+    ResourcePresence,
+    Occupant,
+    OccupantLeft,
+    NickChange,
+    NickChangeError,
+    Moved,
+    Invitation,
+    UserInfo,
+    Call,
+    CallEnded,
+    Subject,
+    Signal,
+    Activity,
+    ReceivedStanza,
+    SentStanza,
+    Quit,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Connecting(..) => EventKind::Connecting,
+            Event::Connected(..) => EventKind::Connected,
+            Event::Disconnected(..) => EventKind::Disconnected,
+            Event::Message(..) => EventKind::Message,
+            Event::ArchivedMessage(..) => EventKind::ArchivedMessage,
+            Event::MessageError(..) => EventKind::MessageError,
+            Event::Chat(..) => EventKind::Chat,
+            Event::Join(..) => EventKind::Join,
+            Event::Iq(..) => EventKind::Iq,
+            Event::Presence(..) => EventKind::Presence,
+            Event::ReadPassword(..) => EventKind::ReadPassword,
+            Event::Win(..) => EventKind::Win,
+            Event::Contact(..) => EventKind::Contact,
+            Event::ContactUpdate(..) => EventKind::ContactUpdate,
+            Event::ResourcePresence(..) => EventKind::ResourcePresence,
+            Event::Occupant(..) => EventKind::Occupant,
+            Event::OccupantLeft(..) => EventKind::OccupantLeft,
+            Event::NickChange(..) => EventKind::NickChange,
+            Event::NickChangeError(..) => EventKind::NickChangeError,
+            Event::Moved(..) => EventKind::Moved,
+            Event::Invitation(..) => EventKind::Invitation,
+            Event::UserInfo(..) => EventKind::UserInfo,
+            Event::Call(..) => EventKind::Call,
+            Event::CallEnded(..) => EventKind::CallEnded,
+            Event::Subject(..) => EventKind::Subject,
+            Event::Signal(..) => EventKind::Signal,
+            Event::Activity => EventKind::Activity,
+            Event::ReceivedStanza(..) => EventKind::ReceivedStanza,
+            Event::SentStanza(..) => EventKind::SentStanza,
+            Event::Quit => EventKind::Quit,
+        }
+    }
+}
+
+/// Whether an event goes on to the handlers of lower priority once handled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Propagation {
+    Continue,
+    /// Consume the event, the handlers of lower priority don't get it
+    Stop,
+}
+
+/// Order in which handlers get an event, from the highest priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Priority(pub i32);
+
+impl Priority {
+    /// Filters, handling events before they are stored or displayed
+    pub const HIGH: Priority = Priority(100);
+    /// Annotations of the events that went through the filters, needed to display them
+    pub const ANNOTATE: Priority = Priority(50);
+    /// Plugins' on_event
+    pub const NORMAL: Priority = Priority(0);
+}
+
+/// Handler of the events of a kind, or of every event, by a plugin
+struct Handler {
+    plugin: TypeId,
+    kind: Option<EventKind>,
+    priority: Priority,
+    callback: Box<dyn Fn(&mut dyn AnyPlugin, Rc<Aparte>, &Event) -> Propagation>,
+}
+
+/// Commands of a plugin not known in advance, as the ones of scripts, run when no command of
+/// that name was added
+struct CommandProvider {
+    plugin: TypeId,
+    /// Names of the commands, for completion
+    names: Box<dyn Fn(&dyn AnyPlugin) -> Vec<String>>,
+    /// Run a command, None when the plugin doesn't know it
+    run: Box<dyn Fn(&mut dyn AnyPlugin, Rc<Aparte>, &Command) -> Option<Result<(), String>>>,
+}
+
+/// Stanzas written differently to the log file, or left out of it
+struct LogFilter {
+    plugin: TypeId,
+    filter: Box<dyn Fn(&dyn AnyPlugin, &Aparte, &Element) -> Option<String>>,
+}
+
 pub trait Plugin: fmt::Display {
     fn new() -> Self where Self: Sized;
+    /// Called once every plugin is added, plugins subscribe to events here
     fn init(&mut self, mgr: &Aparte) -> Result<(), ()>;
-    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event);
+    /// Every event, with the normal priority and after the handlers subscribed with a higher one
+    fn on_event(&mut self, _aparte: Rc<Aparte>, _event: &Event) {
+    }
 }
 
 pub trait AnyPlugin: Any + Plugin {
@@ -67,25 +279,58 @@ impl<T> AnyPlugin for T where T: Any + Plugin {
     }
 }
 
+/// Counters kept for each connection, used for diagnostics
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStats {
+    pub online_since: Option<DateTime<Utc>>,
+    pub sent: u64,
+    pub received: u64,
+    /// Stanzas waiting for the rate limit
+    pub queued: usize,
+    /// How the connection was made, once online
+    pub details: Option<Details>,
+}
+
 pub struct Connection {
     pub sink: UnboundedSender<Packet>,
     pub account: FullJid,
+    pub stats: ConnectionStats,
+    /// Stanzas waiting for the rate limit
+    pub queue: SendQueue,
+    /// Features offered by the server once online
+    pub features: Option<Element>,
 }
 
 pub struct Aparte {
     pub commands: HashMap<String, CommandParser>,
     plugins: HashMap<TypeId, RefCell<Box<dyn AnyPlugin>>>,
+    /// Event handlers, from the highest priority
+    handlers: RefCell<Vec<Rc<Handler>>>,
+    command_providers: RefCell<Vec<Rc<CommandProvider>>>,
+    log_filters: RefCell<Vec<LogFilter>>,
     connections: RefCell<HashMap<String, Connection>>,
+    /// Requests sent with send_iq waiting for an answer, by account and id
+    iqs: RefCell<HashMap<String, HashMap<String, PendingIq>>>,
     current_connection: RefCell<Option<String>>,
     event_lock: RefCell<()>,
     event_queue: RefCell<Vec<Event>>,
+    /// Event whose delivery was interrupted by a busy plugin, with the handlers left to call
+    interrupted: RefCell<Option<(Vec<Rc<Handler>>, Event)>>,
+    dispatch_scheduled: Cell<bool>,
+    flush_scheduled: Cell<bool>,
+    /// Whether connections were closed to quit, their last stanzas being still written
+    closing: Cell<bool>,
+    // Handle on the shared instance, to dispatch events from methods not taking an Rc
+    this: RefCell<Weak<Aparte>>,
     pub config: Config,
-
+    pub config_path: PathBuf,
+    /// Settings changed with /set, for every conversation or for some of them
+    pub settings: RefCell<Settings>,
 }
 
 impl Aparte {
     pub fn new(config_path: PathBuf) -> Self {
-        let mut config_file = match OpenOptions::new().read(true).write(true).create(true).open(config_path) {
+        let mut config_file = match OpenOptions::new().read(true).write(true).create(true).open(&config_path) {
             Err(err) => panic!("Cannot read config file {}", err),
             Ok(config_file) => config_file,
         };
@@ -97,14 +342,33 @@ impl Aparte {
             Ok(config) => config,
         };
 
+        let settings = match Settings::load(&settings::default_path()) {
+            Ok(settings) => settings,
+            Err(err) => {
+                warn!("{}", err);
+                Settings::default()
+            },
+        };
+
         Self {
             commands: HashMap::new(),
             plugins: HashMap::new(),
+            handlers: RefCell::new(Vec::new()),
+            command_providers: RefCell::new(Vec::new()),
+            log_filters: RefCell::new(Vec::new()),
             connections: RefCell::new(HashMap::new()),
+            iqs: RefCell::new(HashMap::new()),
             current_connection: RefCell::new(None),
             event_lock: RefCell::new(()),
             event_queue: RefCell::new(Vec::new()),
+            interrupted: RefCell::new(None),
+            dispatch_scheduled: Cell::new(false),
+            flush_scheduled: Cell::new(false),
+            closing: Cell::new(false),
+            this: RefCell::new(Weak::new()),
             config: config,
+            config_path: config_path,
+            settings: RefCell::new(settings),
         }
     }
 
@@ -112,16 +376,58 @@ impl Aparte {
         self.commands.insert(command.name.to_string(), command);
     }
 
+    /// Run the commands of a plugin that aren't added in advance, the ones added being run
+    /// first. Their names are completed as the other ones.
+    pub fn provide_commands<T, N, R>(&self, names: N, run: R)
+        where T: 'static,
+              N: Fn(&T) -> Vec<String> + 'static,
+              R: Fn(&mut T, Rc<Aparte>, &Command) -> Option<Result<(), String>> + 'static
+    {
+        self.command_providers.borrow_mut().push(Rc::new(CommandProvider {
+            plugin: TypeId::of::<T>(),
+            /* Calling unwrap here on purpose as we expect panic if plugin is not of the right type */
+            names: Box::new(move |plugin| names(plugin.as_any().downcast_ref::<T>().unwrap())),
+            run: Box::new(move |plugin, aparte, command| run(plugin.as_any_mut().downcast_mut::<T>().unwrap(), aparte, command)),
+        }));
+    }
+
+    /// Run a command. Its error is returned if it fails right away, commands waiting for the
+    /// network go on in the event loop, logging their error as spawned futures.
     pub fn parse_command(self: Rc<Self>, command: Command) -> Result<(), String> {
-        match Rc::clone(&self).commands.get(&command.args[0]) {
-            Some(parser) => (parser.parser)(self, command),
-            None => Err(format!("Unknown command {}", command.args[0])),
+        if let Some(parser) = Rc::clone(&self).commands.get(&command.args[0]) {
+            let mut rest = (parser.parser)(Rc::clone(&self), command);
+            return match rest.poll()? {
+                Async::Ready(()) => Ok(()),
+                Async::NotReady => {
+                    self.spawn(rest);
+                    Ok(())
+                },
+            };
+        }
+
+        let providers = self.command_providers.borrow().clone();
+        for provider in providers {
+            let plugin = match self.plugins.get(&provider.plugin) {
+                Some(plugin) => plugin,
+                None => continue,
+            };
+            let mut plugin = plugin.borrow_mut();
+            if let Some(result) = (provider.run)(&mut **plugin, Rc::clone(&self), &command) {
+                return result;
+            }
         }
+        Err(i18n::trf("Unknown command {}", &[&command.args[0]]))
     }
 
     pub fn autocomplete(&self, command: Command) -> Vec<String> {
         if command.cursor == 0 {
-            self.commands.iter().map(|c| c.0.to_string()).collect()
+            let mut commands: Vec<String> = self.commands.iter().map(|c| c.0.to_string()).collect();
+            for provider in self.command_providers.borrow().iter() {
+                if let Some(plugin) = self.plugins.get(&provider.plugin) {
+                    commands.extend((provider.names)(&**plugin.borrow()));
+                }
+            }
+            commands
         } else {
             if let Some(parser) = self.commands.get(&command.args[0]) {
                 if command.cursor - 1 < parser.completions.len() {
@@ -142,6 +448,76 @@ impl Aparte {
     pub fn add_plugin<T: 'static + fmt::Display + Plugin>(&mut self, plugin: T) {
         info!("Add plugin `{}`", plugin);
         self.plugins.insert(TypeId::of::<T>(), RefCell::new(Box::new(plugin)));
+        self.add_handler(Handler {
+            plugin: TypeId::of::<T>(),
+            kind: None,
+            priority: Priority::NORMAL,
+            callback: Box::new(|plugin, aparte, event| {
+                plugin.as_plugin().on_event(aparte, event);
+                Propagation::Continue
+            }),
+        });
+    }
+
+    /// Handle the events of a kind with a plugin, before or after the other plugins depending on
+    /// the priority. Plugins still get every event in on_event, unless a handler stops it.
+    pub fn subscribe<T, F>(&self, kind: EventKind, priority: Priority, handler: F)
+        where T: 'static,
+              F: Fn(&mut T, Rc<Aparte>, &Event) -> Propagation + 'static
+    {
+        self.add_handler(Handler {
+            plugin: TypeId::of::<T>(),
+            kind: Some(kind),
+            priority: priority,
+            callback: Box::new(move |plugin, aparte, event| {
+                /* Calling unwrap here on purpose as we expect panic if plugin is not of the right type */
+                handler(plugin.as_any_mut().downcast_mut::<T>().unwrap(), aparte, event)
+            }),
+        });
+    }
+
+    /// Write some stanzas differently to the log file, or leave them out, the first filter
+    /// rewriting a stanza being used
+    pub fn filter_log<T, F>(&self, filter: F)
+        where T: 'static,
+              F: Fn(&T, &Aparte, &Element) -> Option<String> + 'static
+    {
+        self.log_filters.borrow_mut().push(LogFilter {
+            plugin: TypeId::of::<T>(),
+            /* Calling unwrap here on purpose as we expect panic if plugin is not of the right type */
+            filter: Box::new(move |plugin, aparte, stanza| filter(plugin.as_any().downcast_ref::<T>().unwrap(), aparte, stanza)),
+        });
+    }
+
+    /// Stanza as written to the log file
+    pub fn loggable(&self, stanza: &Element) -> String {
+        for filter in self.log_filters.borrow().iter() {
+            let plugin = match self.plugins.get(&filter.plugin) {
+                Some(plugin) => plugin,
+                None => continue,
+            };
+            // Left as is rather than panicking while the plugin is busy
+            let plugin = match plugin.try_borrow() {
+                Ok(plugin) => plugin,
+                Err(_) => continue,
+            };
+            if let Some(loggable) = (filter.filter)(&**plugin, self, stanza) {
+                return loggable;
+            }
+        }
+        String::from(stanza)
+    }
+
+    /// Insert a handler after the ones of the same priority, so that they are called in the order
+    /// they are added
+    fn add_handler(&self, handler: Handler) {
+        let mut handlers = self.handlers.borrow_mut();
+        let index = handlers.iter().position(|other| other.priority < handler.priority).unwrap_or(handlers.len());
+        handlers.insert(index, Rc::new(handler));
+    }
+
+    pub fn has_plugin<T: 'static>(&self) -> bool {
+        self.plugins.contains_key(&TypeId::of::<T>())
     }
 
     pub fn get_plugin<T: 'static>(&self) -> Option<Ref<T>> {
@@ -167,9 +543,17 @@ impl Aparte {
     }
 
     pub fn add_connection(&self, account: FullJid, sink: UnboundedSender<Packet>) {
+        // Stanzas still waiting on the previous connection of the account are sent on this one
+        let queue = match self.connections.borrow_mut().remove(&account.to_string()) {
+            Some(previous) => previous.queue,
+            None => SendQueue::new(self.config.rate_limit.clone(), Instant::now()),
+        };
         let connection = Connection {
             account: account,
             sink: sink,
+            stats: ConnectionStats::default(),
+            queue: queue,
+            features: None,
         };
 
         let account = connection.account.to_string();
@@ -190,6 +574,66 @@ impl Aparte {
         }
     }
 
+    /// Accounts having a connection, online or not
+    pub fn connections(&self) -> Vec<FullJid> {
+        self.connections.borrow().values().map(|connection| connection.account.clone()).collect()
+    }
+
+    pub fn connection_stats(&self) -> Option<(FullJid, ConnectionStats)> {
+        let current_connection = self.current_connection.borrow();
+        let connections = self.connections.borrow();
+        match &*current_connection {
+            Some(current_connection) => connections.get(current_connection).map(|connection| {
+                let mut stats = connection.stats.clone();
+                stats.queued = connection.queue.len();
+                (connection.account.clone(), stats)
+            }),
+            None => None,
+        }
+    }
+
+    pub fn connection_online(&self, account: &FullJid, features: Element) {
+        if let Some(connection) = self.connections.borrow_mut().get_mut(&account.to_string()) {
+            connection.stats.online_since = Some(Utc::now());
+            connection.features = Some(features);
+        }
+        self.flush();
+    }
+
+    /// Remember how the connection of an account was made
+    pub fn connection_details(&self, account: &FullJid, details: Details) {
+        if let Some(connection) = self.connections.borrow_mut().get_mut(&account.to_string()) {
+            connection.stats.details = Some(details);
+        }
+    }
+
+    /// Features offered by the server of an account, once online
+    pub fn stream_features(&self, account: &FullJid) -> Option<Element> {
+        self.connections.borrow().get(&account.to_string()).and_then(|connection| connection.features.clone())
+    }
+
+    pub fn connection_offline(&self, account: &FullJid) {
+        if let Some(connection) = self.connections.borrow_mut().get_mut(&account.to_string()) {
+            connection.stats.online_since = None;
+        }
+        // Answers won't come on a new stream, the pending requests fail right away
+        self.iqs.borrow_mut().remove(&account.to_string());
+    }
+
+    /// Whether stanzas can be sent on the current connection
+    pub fn is_online(&self) -> bool {
+        match self.connection_stats() {
+            Some((_, stats)) => stats.online_since.is_some(),
+            None => false,
+        }
+    }
+
+    pub fn connection_received(&self, account: &FullJid) {
+        if let Some(connection) = self.connections.borrow_mut().get_mut(&account.to_string()) {
+            connection.stats.received += 1;
+        }
+    }
+
     pub fn init(&mut self) -> Result<(), ()> {
         for (_, plugin) in self.plugins.iter() {
             if let Err(err) = plugin.borrow_mut().as_plugin().init(&self) {
@@ -200,30 +644,308 @@ impl Aparte {
         Ok(())
     }
 
+    /// Share the instance once every plugin and command is added
+    pub fn into_rc(self) -> Rc<Self> {
+        let aparte = Rc::new(self);
+        aparte.this.replace(Rc::downgrade(&aparte));
+        aparte
+    }
+
+    /// Send a stanza on the current connection, once the rate limit allows it
     pub fn send(&self, element: Element) {
-        debug!("SEND: {:?}", element);
-        let packet = Packet::Stanza(element);
-        // TODO use correct connection
-        let mut connections = self.connections.borrow_mut();
-        let current_connection = connections.iter_mut().next().unwrap().1;
-        let mut sink = &current_connection.sink;
-        if let Err(e) = sink.start_send(packet) {
-            warn!("Cannot send packet: {}", e);
+        match self.current_connection() {
+            Some(account) => self.send_on(&account, element),
+            None => warn!("Cannot send packet: not connected"),
+        }
+    }
+
+    /// Send a stanza on the connection of an account, once the rate limit allows it
+    pub fn send_on(&self, account: &FullJid, element: Element) {
+        match self.connections.borrow_mut().get_mut(&account.to_string()) {
+            Some(connection) => connection.queue.push(element),
+            None => return warn!("Cannot send packet: {} not connected", account),
+        }
+        self.flush();
+    }
+
+    /// Send the stanzas waiting whose turn has come on every connection online, the next ones
+    /// being sent later and the others once online
+    fn flush(&self) {
+        let mut sent = Vec::new();
+        let delay = {
+            let mut connections = self.connections.borrow_mut();
+            let now = Instant::now();
+            let mut delay = None;
+            for connection in connections.values_mut().filter(|connection| connection.stats.online_since.is_some()) {
+                while let Some(element) = connection.queue.pop(now) {
+                    let mut sink = &connection.sink;
+                    if let Err(e) = sink.start_send(Packet::Stanza(element.clone())) {
+                        warn!("Cannot send packet: {}", e);
+                        continue;
+                    }
+                    connection.stats.sent += 1;
+                    sent.push(element);
+                }
+                delay = match (delay, connection.queue.delay()) {
+                    (Some(delay), Some(other)) => Some(std::cmp::min(delay, other)),
+                    (delay, other) => delay.or(other),
+                };
+            }
+            delay
+        };
+
+        for element in sent {
+            debug!("SEND: {}", self.loggable(&element));
+            self.event_queue.borrow_mut().push(Event::SentStanza(element));
+        }
+        let this = self.this.borrow().upgrade();
+        if let Some(aparte) = this {
+            if let Some(delay) = delay {
+                Rc::clone(&aparte).schedule_flush(delay);
+            }
+            aparte.dispatch();
+        }
+    }
+
+    fn schedule_flush(self: Rc<Self>, delay: Duration) {
+        if self.flush_scheduled.get() {
+            return;
+        }
+
+        let aparte = Rc::clone(&self);
+        let flush = Delay::new(Instant::now() + delay).then(move |_| {
+            aparte.flush_scheduled.set(false);
+            aparte.flush();
+            Ok(())
+        });
+
+        match TaskExecutor::current().spawn_local(Box::new(flush)) {
+            Ok(()) => self.flush_scheduled.set(true),
+            Err(err) => warn!("Cannot schedule sending: {:?}", err),
+        }
+    }
+
+    /// Close every connection, sending the stanzas still waiting for the rate limit right away
+    /// then ending the streams
+    pub fn close_connections(&self) {
+        let connections: Vec<Connection> = self.connections.borrow_mut().drain().map(|(_, connection)| connection).collect();
+        self.current_connection.replace(None);
+        for mut connection in connections {
+            self.closing.set(true);
+            for element in connection.queue.drain() {
+                debug!("SEND: {}", self.loggable(&element));
+                if let Err(e) = connection.sink.unbounded_send(Packet::Stanza(element)) {
+                    warn!("Cannot send packet: {}", e);
+                }
+            }
+            // The connection ends once its last stanzas are written, as its sender is dropped
+            let _ = connection.sink.unbounded_send(Packet::StreamEnd);
+        }
+    }
+
+    /// Whether connections were closed and are still writing their last stanzas
+    pub fn is_closing(&self) -> bool {
+        self.closing.get()
+    }
+
+    /// Send a request on the current connection, the future resolving to its result. It fails
+    /// with the error the entity answers, or if no answer comes in time or the connection is lost
+    /// before.
+    pub fn send_iq(self: Rc<Self>, iq: Iq) -> Box<dyn Future<Item = Iq, Error = IqError>> {
+        match self.current_connection() {
+            Some(account) => self.send_iq_on(&account, iq),
+            None => Box::new(future::err(IqError::Disconnected)),
+        }
+    }
+
+    /// Send a request on the connection of an account, its answer being expected on the same
+    /// connection
+    pub fn send_iq_on(self: Rc<Self>, account: &FullJid, iq: Iq) -> Box<dyn Future<Item = Iq, Error = IqError>> {
+        let online = self.connections.borrow().get(&account.to_string()).map_or(false, |connection| connection.stats.online_since.is_some());
+        if !online {
+            return Box::new(future::err(IqError::Disconnected));
+        }
+        let account = account.clone();
+        let id = iq.id.clone();
+        let answer = self.expect_iq(&account, &iq);
+        self.send_on(&account, iq.into());
+
+        let aparte = Rc::clone(&self);
+        Box::new(Timeout::new(answer, IQ_TIMEOUT).then(move |answer| {
+            if let Some(pending) = aparte.iqs.borrow_mut().get_mut(&account.to_string()) {
+                pending.remove(&id);
+            }
+            match answer {
+                Ok(Iq { payload: IqType::Error(error), .. }) => Err(IqError::Error(error)),
+                Ok(iq) => Ok(iq),
+                Err(err) if err.is_inner() => Err(IqError::Disconnected),
+                Err(err) => {
+                    if let Some(err) = err.into_timer() {
+                        warn!("Timer error for request {}: {}", id, err);
+                    }
+                    Err(IqError::Timeout)
+                },
+            }
+        }))
+    }
+
+    fn expect_iq(&self, account: &FullJid, iq: &Iq) -> oneshot::Receiver<Iq> {
+        let (sender, receiver) = oneshot::channel();
+        let mut iqs = self.iqs.borrow_mut();
+        let pending = iqs.entry(account.to_string()).or_insert_with(HashMap::new);
+        pending.insert(iq.id.clone(), PendingIq { to: iq.to.clone(), sender: sender });
+        receiver
+    }
+
+    /// Hand an answer received on a connection to the request waiting for it, returns whether one
+    /// was. Answers must come from the entity the request was sent to, not to be spoofed by
+    /// another one guessing its id.
+    pub fn answer_iq(&self, account: &FullJid, iq: &Iq) -> bool {
+        match iq.payload {
+            IqType::Result(_) | IqType::Error(_) => {},
+            IqType::Get(_) | IqType::Set(_) => return false,
+        }
+        let mut iqs = self.iqs.borrow_mut();
+        let pending = match iqs.get_mut(&account.to_string()) {
+            Some(pending) => pending,
+            None => return false,
+        };
+        let expected = match pending.get(&iq.id) {
+            Some(request) => match (&request.to, &iq.from) {
+                (Some(to), Some(from)) => to == from,
+                // Requests to our account are answered by our server on its behalf
+                (None, None) => true,
+                (None, Some(from)) => is_account(account, from),
+                (Some(to), None) => is_account(account, to),
+            },
+            None => false,
+        };
+        match expected {
+            true => pending.remove(&iq.id).unwrap().sender.send(iq.clone()).is_ok(),
+            false => false,
         }
     }
 
+    /// Run a future in the event loop, so that commands and plugins don't block the UI while
+    /// waiting for the network. Its error is logged as the ones of commands.
+    pub fn spawn<F>(self: Rc<Self>, future: F) where F: Future<Item = (), Error = String> + 'static {
+        let aparte = Rc::clone(&self);
+        let future = future.or_else(move |err| {
+            aparte.log(err);
+            Ok(())
+        });
+        if let Err(err) = TaskExecutor::current().spawn_local(Box::new(future)) {
+            warn!("Cannot spawn task: {:?}", err);
+        }
+    }
+
+    /// Queue an event for every plugin. Events emitted while dispatching are handled once the
+    /// current one has been delivered to every plugin, so plugins can freely trigger events from
+    /// their handlers.
     pub fn event(self: Rc<Self>, event: Event) {
         self.event_queue.borrow_mut().push(event);
-        if let Ok(_lock) = self.event_lock.try_borrow_mut() {
-            while self.event_queue.borrow().len() > 0 {
-                let event = self.event_queue.borrow_mut().remove(0);
-                for (_, plugin) in self.plugins.iter() {
-                    plugin.borrow_mut().as_plugin().on_event(Rc::clone(&self), &event);
+        self.dispatch();
+    }
+
+    fn dispatch(self: Rc<Self>) {
+        let _lock = match self.event_lock.try_borrow_mut() {
+            Ok(lock) => lock,
+            // Already dispatching, the event will be handled by the running loop
+            Err(_) => return,
+        };
+
+        // The caller still holds a plugin, which thus cannot receive events yet
+        if self.plugins.values().any(|plugin| plugin.try_borrow_mut().is_err()) {
+            drop(_lock);
+            self.schedule_dispatch();
+            return;
+        }
+
+        // Deliveries interrupted by a busy plugin go first, not to reorder events
+        let interrupted = self.interrupted.borrow_mut().take();
+        if let Some((handlers, event)) = interrupted {
+            if !Rc::clone(&self).deliver(handlers, event) {
+                return;
+            }
+        }
+
+        loop {
+            let event = {
+                let mut queue = self.event_queue.borrow_mut();
+                if queue.is_empty() {
+                    break;
                 }
+                queue.remove(0)
+            };
+
+            let kind = event.kind();
+            let handlers: Vec<Rc<Handler>> = self.handlers.borrow().iter()
+                .filter(|handler| handler.kind.map_or(true, |handled| handled == kind))
+                .cloned()
+                .collect();
+            if !Rc::clone(&self).deliver(handlers, event) {
+                return;
             }
         }
     }
 
+    /// Call the handlers of an event in turn, until one stops its propagation. When a plugin is
+    /// busy, the event is kept with the handlers left to call and delivered on the next dispatch,
+    /// false being returned to stop dispatching meanwhile.
+    fn deliver(self: Rc<Self>, mut handlers: Vec<Rc<Handler>>, event: Event) -> bool {
+        while !handlers.is_empty() {
+            let propagation = {
+                let handler = &handlers[0];
+                let plugin = match self.plugins.get(&handler.plugin) {
+                    Some(plugin) => plugin,
+                    None => {
+                        warn!("Handler of a missing plugin, skipping it");
+                        handlers.remove(0);
+                        continue;
+                    },
+                };
+                match plugin.try_borrow_mut() {
+                    Ok(mut plugin) => Some((handler.callback)(&mut **plugin, Rc::clone(&self), &event)),
+                    Err(_) => None,
+                }
+            };
+            let propagation = match propagation {
+                Some(propagation) => propagation,
+                None => {
+                    debug!("Plugin busy, delivering the event later");
+                    *self.interrupted.borrow_mut() = Some((handlers, event));
+                    self.schedule_dispatch();
+                    return false;
+                },
+            };
+            if propagation == Propagation::Stop {
+                break;
+            }
+            handlers.remove(0);
+        }
+        true
+    }
+
+    fn schedule_dispatch(self: Rc<Self>) {
+        if self.dispatch_scheduled.get() {
+            return;
+        }
+
+        let aparte = Rc::clone(&self);
+        let dispatch = future::lazy(move || {
+            aparte.dispatch_scheduled.set(false);
+            aparte.dispatch();
+            Ok(())
+        });
+
+        match TaskExecutor::current().spawn_local(Box::new(dispatch)) {
+            Ok(()) => self.dispatch_scheduled.set(true),
+            // Not running in the event loop yet, events will be dispatched with the next one
+            Err(_) => {},
+        }
+    }
+
+    /// Show a message in the console, translated by the caller
     pub fn log(self: Rc<Self>, message: String) {
         let message = Message::log(message);
         self.event(Event::Message(message));
@@ -252,7 +974,7 @@ macro_rules! parse_command_args {
     );
     ($aparte:ident, $command:ident, $index:ident, $arg:ident) => (
         if $command.args.len() <= $index {
-            return Err(format!("Missing {} argument", stringify!($arg)))
+            return Err($crate::i18n::trf("Missing {} argument", &[&stringify!($arg)]))
         }
         let $arg = $command.args[$index].clone();
     );
@@ -267,11 +989,11 @@ macro_rules! parse_command_args {
 
         $index += 1;
 
-        parse_command_args!($command, $index, $($(($attr))? $args),*);
+        parse_command_args!($aparte, $command, $index, $($(($attr))? $args),*);
     );
     ($aparte:ident, $command:ident, $index:ident, $arg:ident, $($(($attr:ident))? $args:ident),+) => (
         if $command.args.len() <= $index {
-            return Err(format!("Missing {} argument", stringify!($arg)))
+            return Err($crate::i18n::trf("Missing {} argument", &[&stringify!($arg)]))
         }
 
         let $arg = $command.args[$index].clone();
@@ -303,35 +1025,54 @@ macro_rules! generate_command_completions {
 
 #[macro_export]
 macro_rules! command_def {
-    ($name:ident, $help: tt, |$aparte:ident, $command:ident| $body:block) => (
+    // Commands whose name isn't a valid identifier, as accept-file
+    ($name:ident = $command_name:literal, $($rest:tt)*) => (
+        command_def!(@named $name, $command_name, $($rest)*);
+    );
+    ($name:ident, $($rest:tt)*) => (
+        command_def!(@named $name, stringify!($name), $($rest)*);
+    );
+    (@named $name:ident, $command_name:expr, $help: tt, |$aparte:ident, $command:ident| $body:block) => (
         fn $name() -> CommandParser {
             let completions = Vec::<Option<Box<dyn Fn(&Aparte, Command) -> Vec<String>>>>::new();
 
             CommandParser {
-                name: stringify!($name),
+                name: $command_name,
                 help: $help,
-                parser: Box::new(|$aparte: Rc<Aparte>, $command: Command| -> Result<(), String> {
-                    #[allow(unused_mut)]
-                    $body
+                parser: Box::new(|$aparte: Rc<Aparte>, $command: Command| -> $crate::command::CommandFuture {
+                    let run = || -> Result<_, String> {
+                        #[allow(unused_mut)]
+                        $body
+                    };
+                    match run() {
+                        Ok(rest) => $crate::command::IntoCommandFuture::into_command_future(rest),
+                        Err(err) => Box::new(futures::future::err(err)),
+                    }
                 }),
                 completions: completions,
             }
         }
     );
-    ($name:ident, $help: tt, $($(($attr:ident))? $argnames:ident$(: $args:tt)?),*, |$aparte:ident, $command:ident| $body:block) => (
+    (@named $name:ident, $command_name:expr, $help: tt, $($(($attr:ident))? $argnames:ident$(: $args:tt)?),*, |$aparte:ident, $command:ident| $body:block) => (
         fn $name() -> CommandParser {
             let mut completions = Vec::<Option<Box<dyn Fn(&Aparte, Command) -> Vec<String>>>>::new();
 
             generate_command_completions!(completions, $($argnames$(: $args)?),*);
 
             CommandParser {
-                name: stringify!($name),
+                name: $command_name,
                 help: $help,
-                parser: Box::new(|$aparte: Rc<Aparte>, $command: Command| -> Result<(), String> {
-                    #[allow(unused_mut)]
-                    let mut index = 1;
-                    parse_command_args!($aparte, $command, index, $($(($attr))? $argnames),*);
-                    $body
+                parser: Box::new(|$aparte: Rc<Aparte>, $command: Command| -> $crate::command::CommandFuture {
+                    let run = || -> Result<_, String> {
+                        #[allow(unused_mut)]
+                        let mut index = 1;
+                        parse_command_args!($aparte, $command, index, $($(($attr))? $argnames),*);
+                        $body
+                    };
+                    match run() {
+                        Ok(rest) => $crate::command::IntoCommandFuture::into_command_future(rest),
+                        Err(err) => Box::new(futures::future::err(err)),
+                    }
                 }),
                 completions: completions,
             }
@@ -342,6 +1083,8 @@ macro_rules! command_def {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
 
     command_def!{
         no_args,
@@ -359,6 +1102,49 @@ mod tests {
         assert_eq!(cmd.help, "help");
     }
 
+    command_def!{
+        dashed = "dashed-name",
+        "help",
+        |_aparte, _command| {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_command_with_dashed_name() {
+        let cmd = dashed();
+
+        assert_eq!(cmd.name, "dashed-name");
+    }
+
+    command_def!{
+        deferred,
+        "help",
+        (optional) fail,
+        |_aparte, _command| {
+            Ok(Box::new(future::lazy(move || match fail {
+                Some(_) => Err(String::from("Failed once resolved")),
+                None => Ok(()),
+            })))
+        }
+    }
+
+    #[test]
+    fn test_command_future() {
+        let config = std::env::temp_dir().join(format!("aparte-test-deferred-{}.toml", std::process::id()));
+        std::fs::write(&config, "[accounts]\n").unwrap();
+        let mut aparte = Aparte::new(config.clone());
+        std::fs::remove_file(config).unwrap();
+        aparte.add_command(deferred());
+        let aparte = Rc::new(aparte);
+
+        let command = Command::new(vec![String::from("deferred")]);
+        assert_eq!((deferred().parser)(Rc::clone(&aparte), command.clone()).wait(), Ok(()));
+        assert_eq!(Rc::clone(&aparte).parse_command(command), Ok(()));
+        let command = Command::new(vec![String::from("deferred"), String::from("fail")]);
+        assert_eq!(Rc::clone(&aparte).parse_command(command), Err(String::from("Failed once resolved")));
+    }
+
     command_def!{
         one_arg,
         "help",
@@ -439,4 +1225,280 @@ mod tests {
         assert_eq!(cmd.help, "help");
         assert_eq!(cmd.completions.len(), 2);
     }
+
+    struct Counter {
+        events: usize,
+    }
+
+    impl Plugin for Counter {
+        fn new() -> Counter {
+            Counter { events: 0 }
+        }
+
+        fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+            self.events += 1;
+            // Trigger another event from a handler
+            if let Event::Signal(0) = event {
+                aparte.event(Event::Signal(1));
+            }
+        }
+    }
+
+    impl fmt::Display for Counter {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "Counter")
+        }
+    }
+
+    #[test]
+    fn test_event_while_plugin_is_borrowed() {
+        let config = std::env::temp_dir().join(format!("aparte-test-{}.toml", std::process::id()));
+        std::fs::write(&config, "[accounts]\n").unwrap();
+        let mut aparte = Aparte::new(config.clone());
+        std::fs::remove_file(config).unwrap();
+        aparte.add_plugin(Counter::new());
+        let aparte = Rc::new(aparte);
+
+        {
+            let _counter = aparte.get_plugin_mut::<Counter>().unwrap();
+            // Must not panic, the event is delivered once the plugin is released
+            Rc::clone(&aparte).event(Event::Signal(2));
+        }
+        assert_eq!(aparte.get_plugin::<Counter>().unwrap().events, 0);
+
+        Rc::clone(&aparte).event(Event::Signal(0));
+        assert_eq!(aparte.get_plugin::<Counter>().unwrap().events, 3);
+    }
+
+    #[test]
+    fn test_delivery_interrupted_by_busy_plugin() {
+        let config = std::env::temp_dir().join(format!("aparte-test-busy-{}.toml", std::process::id()));
+        std::fs::write(&config, "[accounts]\n").unwrap();
+        let mut aparte = Aparte::new(config.clone());
+        std::fs::remove_file(config).unwrap();
+        aparte.add_plugin(Counter::new());
+        let aparte = Rc::new(aparte);
+
+        let handlers = aparte.handlers.borrow().clone();
+        {
+            let _counter = aparte.get_plugin_mut::<Counter>().unwrap();
+            assert!(!Rc::clone(&aparte).deliver(handlers, Event::Signal(2)));
+        }
+        assert!(aparte.interrupted.borrow().is_some());
+
+        // The interrupted event is delivered before the queued ones
+        Rc::clone(&aparte).event(Event::Signal(0));
+        assert!(aparte.interrupted.borrow().is_none());
+        assert_eq!(aparte.get_plugin::<Counter>().unwrap().events, 3);
+    }
+
+    fn iq(xml: &str) -> Iq {
+        let element: Element = xml.parse().unwrap();
+        Iq::try_from(element).unwrap()
+    }
+
+    #[test]
+    fn test_answer_iq() {
+        let config = std::env::temp_dir().join(format!("aparte-test-iq-{}.toml", std::process::id()));
+        std::fs::write(&config, "[accounts]\n").unwrap();
+        let aparte = Aparte::new(config.clone());
+        std::fs::remove_file(config).unwrap();
+        let account = FullJid::from_str("me@server.tld/aparte").unwrap();
+        let other_account = FullJid::from_str("me@other.tld/aparte").unwrap();
+
+        let answer = aparte.expect_iq(&account, &iq(r#"<iq xmlns="jabber:client" type="get" id="ping" to="contact@server.tld/phone"><ping xmlns="urn:xmpp:ping"/></iq>"#));
+        assert!(!aparte.answer_iq(&account, &iq(r#"<iq xmlns="jabber:client" type="get" id="ping" from="contact@server.tld/phone"><ping xmlns="urn:xmpp:ping"/></iq>"#)));
+        assert!(!aparte.answer_iq(&account, &iq(r#"<iq xmlns="jabber:client" type="result" id="other" from="contact@server.tld/phone"/>"#)));
+        // Not from whom the request was sent to, or not on the same connection
+        assert!(!aparte.answer_iq(&account, &iq(r#"<iq xmlns="jabber:client" type="result" id="ping" from="spoofer@server.tld/phone"/>"#)));
+        assert!(!aparte.answer_iq(&other_account, &iq(r#"<iq xmlns="jabber:client" type="result" id="ping" from="contact@server.tld/phone"/>"#)));
+        assert!(aparte.answer_iq(&account, &iq(r#"<iq xmlns="jabber:client" type="result" id="ping" from="contact@server.tld/phone"/>"#)));
+        assert_eq!(answer.wait().unwrap().from.unwrap().to_string(), "contact@server.tld/phone");
+        // Only awaited once
+        assert!(!aparte.answer_iq(&account, &iq(r#"<iq xmlns="jabber:client" type="result" id="ping" from="contact@server.tld/phone"/>"#)));
+
+        // Requests to our account are answered by our server
+        let answer = aparte.expect_iq(&account, &iq(r#"<iq xmlns="jabber:client" type="get" id="roster"><query xmlns="jabber:iq:roster"/></iq>"#));
+        assert!(aparte.answer_iq(&account, &iq(r#"<iq xmlns="jabber:client" type="result" id="roster" from="me@server.tld"/>"#)));
+        assert!(answer.wait().is_ok());
+
+        // Failed right away once disconnected
+        let answer = aparte.expect_iq(&account, &iq(r#"<iq xmlns="jabber:client" type="get" id="version" to="server.tld"><query xmlns="jabber:iq:version"/></iq>"#));
+        aparte.connection_offline(&account);
+        assert!(answer.wait().is_err());
+    }
+
+    #[test]
+    fn test_send_on_account() {
+        use futures::Stream;
+        let config = std::env::temp_dir().join(format!("aparte-test-send-{}.toml", std::process::id()));
+        std::fs::write(&config, "[accounts]\n").unwrap();
+        let aparte = Aparte::new(config.clone()).into_rc();
+        std::fs::remove_file(config).unwrap();
+        let account = FullJid::from_str("me@server.tld/aparte").unwrap();
+        let other_account = FullJid::from_str("me@other.tld/aparte").unwrap();
+        let features = Element::builder("features").ns("http://etherx.jabber.org/streams").build();
+        let (sink, mut stream) = futures::unsync::mpsc::unbounded();
+        aparte.add_connection(account.clone(), sink);
+        aparte.connection_online(&account, features.clone());
+        let (other_sink, mut other_stream) = futures::unsync::mpsc::unbounded();
+        aparte.add_connection(other_account.clone(), other_sink);
+        aparte.connection_online(&other_account, features);
+
+        // The request goes on the connection it is answered on, not on the current one
+        let request = iq(r#"<iq xmlns="jabber:client" type="get" id="version" to="server.tld"><query xmlns="jabber:iq:version"/></iq>"#);
+        let _answer = Rc::clone(&aparte).send_iq_on(&account, request);
+        assert_eq!(aparte.current_connection(), Some(other_account.clone()));
+        assert!(!aparte.answer_iq(&other_account, &iq(r#"<iq xmlns="jabber:client" type="result" id="version" from="server.tld"/>"#)));
+        aparte.send(Element::builder("presence").ns("jabber:client").build());
+
+        // Names of the stanzas sent so far on a connection
+        let names = |stream: &mut futures::unsync::mpsc::UnboundedReceiver<Packet>| future::lazy(|| {
+            let mut names = Vec::new();
+            while let Ok(Async::Ready(Some(Packet::Stanza(element)))) = stream.poll() {
+                names.push(element.name().to_string());
+            }
+            Ok::<_, ()>(names)
+        }).wait().unwrap();
+        assert_eq!(names(&mut stream), vec!["iq"]);
+        assert_eq!(names(&mut other_stream), vec!["presence"]);
+
+        // Stanzas sent while offline wait for the connection of the account made again
+        aparte.connection_offline(&account);
+        aparte.send_on(&account, Element::builder("message").ns("jabber:client").build());
+        aparte.send_on(&account, Element::builder("presence").ns("jabber:client").build());
+        assert!(names(&mut stream).is_empty());
+        let (sink, mut stream) = futures::unsync::mpsc::unbounded();
+        aparte.add_connection(account.clone(), sink);
+        assert!(names(&mut stream).is_empty());
+        aparte.connection_online(&account, Element::builder("features").ns("http://etherx.jabber.org/streams").build());
+        assert_eq!(names(&mut stream), vec!["message", "presence"]);
+    }
+
+    /// Drops the signals it handles, remembering whether the counter got them first
+    struct Filter {
+        signals: Vec<(i32, usize)>,
+    }
+
+    impl Plugin for Filter {
+        fn new() -> Filter {
+            Filter { signals: Vec::new() }
+        }
+
+        fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+            aparte.subscribe(EventKind::Signal, Priority::HIGH, |filter: &mut Filter, aparte, event| {
+                let counted = aparte.get_plugin::<Counter>().unwrap().events;
+                match event {
+                    Event::Signal(signal) if *signal >= 10 => {
+                        filter.signals.push((*signal, counted));
+                        Propagation::Stop
+                    },
+                    _ => Propagation::Continue,
+                }
+            });
+            Ok(())
+        }
+    }
+
+    impl fmt::Display for Filter {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "Filter")
+        }
+    }
+
+    #[test]
+    fn test_event_priority_and_cancellation() {
+        let config = std::env::temp_dir().join(format!("aparte-test-priority-{}.toml", std::process::id()));
+        std::fs::write(&config, "[accounts]\n").unwrap();
+        let mut aparte = Aparte::new(config.clone());
+        std::fs::remove_file(config).unwrap();
+        aparte.add_plugin(Counter::new());
+        aparte.add_plugin(Filter::new());
+        aparte.init().unwrap();
+        let aparte = Rc::new(aparte);
+
+        Rc::clone(&aparte).event(Event::Signal(2));
+        Rc::clone(&aparte).event(Event::Signal(10));
+        Rc::clone(&aparte).event(Event::Quit);
+        assert_eq!(aparte.get_plugin::<Counter>().unwrap().events, 2);
+        // Handled before the counter, which never got it
+        assert_eq!(aparte.get_plugin::<Filter>().unwrap().signals, vec![(10, 1)]);
+    }
+
+    #[test]
+    fn test_handler_of_missing_plugin() {
+        let config = std::env::temp_dir().join(format!("aparte-test-missing-{}.toml", std::process::id()));
+        std::fs::write(&config, "[accounts]\n").unwrap();
+        let mut aparte = Aparte::new(config.clone());
+        std::fs::remove_file(config).unwrap();
+        aparte.add_plugin(Counter::new());
+        // Never added, its handler must be skipped rather than panicking
+        aparte.subscribe(EventKind::Signal, Priority::HIGH, |_filter: &mut Filter, _aparte, _event| Propagation::Stop);
+        let aparte = Rc::new(aparte);
+
+        Rc::clone(&aparte).event(Event::Signal(2));
+        assert_eq!(aparte.get_plugin::<Counter>().unwrap().events, 1);
+    }
+
+    /// Provides a command added at runtime and hides presences from the log
+    struct Echo {
+        echoed: Vec<String>,
+    }
+
+    impl Plugin for Echo {
+        fn new() -> Echo {
+            Echo { echoed: Vec::new() }
+        }
+
+        fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+            aparte.provide_commands(|_echo: &Echo| vec![String::from("echo")], |echo: &mut Echo, _aparte, command| {
+                match command.args[0].as_str() {
+                    "echo" => {
+                        echo.echoed.push(command.args[1..].join(" "));
+                        Some(Ok(()))
+                    },
+                    _ => None,
+                }
+            });
+            aparte.filter_log(|_echo: &Echo, _aparte, stanza| match stanza.name() {
+                "presence" => Some(String::from("<presence/> left out")),
+                _ => None,
+            });
+            Ok(())
+        }
+    }
+
+    impl fmt::Display for Echo {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "Echo")
+        }
+    }
+
+    #[test]
+    fn test_provided_commands_and_log_filters() {
+        let config = std::env::temp_dir().join(format!("aparte-test-provided-{}.toml", std::process::id()));
+        std::fs::write(&config, "[accounts]\n").unwrap();
+        let mut aparte = Aparte::new(config.clone());
+        std::fs::remove_file(config).unwrap();
+        aparte.add_command(no_args());
+        aparte.add_plugin(Echo::new());
+        aparte.init().unwrap();
+        let aparte = Rc::new(aparte);
+
+        let mut names = aparte.autocomplete(Command { args: vec![String::new()], cursor: 0 });
+        names.sort();
+        assert_eq!(names, vec!["echo", "no_args"]);
+        assert!(Rc::clone(&aparte).parse_command(Command::new(vec![String::from("echo"), String::from("hi")])).is_ok());
+        assert_eq!(aparte.get_plugin::<Echo>().unwrap().echoed, vec![String::from("hi")]);
+        assert_eq!(Rc::clone(&aparte).parse_command(Command::new(vec![String::from("nope")])), Err(String::from("Unknown command nope")));
+
+        let presence: Element = "<presence xmlns='jabber:client'/>".parse().unwrap();
+        assert_eq!(aparte.loggable(&presence), "<presence/> left out");
+        let message: Element = "<message xmlns='jabber:client'/>".parse().unwrap();
+        assert_eq!(aparte.loggable(&message), String::from(&message));
+    }
 }
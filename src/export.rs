@@ -0,0 +1,440 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use openssl::sha::Sha256;
+use rusqlite::{params, Connection, OpenFlags, NO_PARAMS};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use xmpp_parsers::{BareJid, Jid};
+
+use crate::message::{Message, XmppMessage};
+use crate::store;
+
+/// Formats the history is exported to with /export
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Mbox,
+    Txt,
+}
+
+pub const FORMATS: [&str; 3] = ["json", "mbox", "txt"];
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "mbox" => Ok(Format::Mbox),
+            "txt" => Ok(Format::Txt),
+            format => Err(format!("Unknown format {}, expected one of {}", format, FORMATS.join(", "))),
+        }
+    }
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Mbox => "mbox",
+            Format::Txt => "txt",
+        }
+    }
+}
+
+/// Logs imported with /import
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    /// History exported with /export as JSON
+    Json,
+    /// Chat logs of Profanity, a file or a directory of them
+    Profanity,
+    /// Database of Gajim, logs.db
+    Gajim,
+}
+
+pub const SOURCES: [&str; 3] = ["json", "profanity", "gajim"];
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Source::Json),
+            "profanity" => Ok(Source::Profanity),
+            "gajim" => Ok(Source::Gajim),
+            source => Err(format!("Unknown format {}, expected one of {}", source, SOURCES.join(", "))),
+        }
+    }
+}
+
+impl Source {
+    /// Source of logs guessed from their path, directories and other files being Profanity logs
+    pub fn guess(path: &Path) -> Source {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Source::Json,
+            Some("db") | Some("sqlite") => Source::Gajim,
+            _ => Source::Profanity,
+        }
+    }
+}
+
+/// Message as exported to JSON
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    id: String,
+    conversation: String,
+    direction: String,
+    #[serde(rename = "type")]
+    type_: String,
+    timestamp: String,
+    from: String,
+    to: String,
+    body: String,
+}
+
+impl Record {
+    fn new(message: &Message) -> Option<Record> {
+        let conversation = store::conversation(message)?;
+        let (direction, type_, id, timestamp, from_full, to_full, body) = match message {
+            Message::Incoming(XmppMessage::Chat(message)) => ("incoming", "chat", &message.id, &message.timestamp, &message.from_full, &message.to_full, &message.body),
+            Message::Outgoing(XmppMessage::Chat(message)) => ("outgoing", "chat", &message.id, &message.timestamp, &message.from_full, &message.to_full, &message.body),
+            Message::Incoming(XmppMessage::Groupchat(message)) => ("incoming", "groupchat", &message.id, &message.timestamp, &message.from_full, &message.to_full, &message.body),
+            Message::Outgoing(XmppMessage::Groupchat(message)) => ("outgoing", "groupchat", &message.id, &message.timestamp, &message.from_full, &message.to_full, &message.body),
+            Message::Log(_) => return None,
+        };
+        Some(Record {
+            id: id.clone(),
+            conversation: conversation.to_string(),
+            direction: direction.to_string(),
+            type_: type_.to_string(),
+            timestamp: timestamp.to_rfc3339(),
+            from: from_full.to_string(),
+            to: to_full.to_string(),
+            body: body.to_string(),
+        })
+    }
+
+    fn message(&self) -> Result<Message, String> {
+        let timestamp = DateTime::parse_from_rfc3339(&self.timestamp).map_err(|err| format!("Invalid timestamp {}: {}", self.timestamp, err))?.with_timezone(&Utc);
+        let from = Jid::from_str(&self.from).map_err(|err| format!("Invalid JID {}: {}", self.from, err))?;
+        let to = Jid::from_str(&self.to).map_err(|err| format!("Invalid JID {}: {}", self.to, err))?;
+        match (self.direction.as_str(), self.type_.as_str()) {
+            ("incoming", "chat") => Ok(Message::incoming_chat(self.id.clone(), timestamp, &from, &to, &self.body)),
+            ("outgoing", "chat") => Ok(Message::outgoing_chat(self.id.clone(), timestamp, &from, &to, &self.body)),
+            ("incoming", "groupchat") => Ok(Message::incoming_groupchat(self.id.clone(), timestamp, &from, &to, &self.body)),
+            ("outgoing", "groupchat") => Ok(Message::outgoing_groupchat(self.id.clone(), timestamp, &from, &to, &self.body)),
+            (direction, type_) => Err(format!("Invalid {} {} message", direction, type_)),
+        }
+    }
+}
+
+/// Name of the sender of a message as written in text exports, me for ours
+fn sender(message: &Message) -> String {
+    match message {
+        Message::Incoming(XmppMessage::Chat(message)) => message.from.to_string(),
+        Message::Incoming(XmppMessage::Groupchat(message)) => match &*message.from_full {
+            Jid::Full(from) => from.resource.clone(),
+            Jid::Bare(from) => from.to_string(),
+        },
+        Message::Outgoing(_) => String::from("me"),
+        Message::Log(_) => String::new(),
+    }
+}
+
+fn mbox(record: &Record, message: &Message) -> String {
+    let timestamp = message.timestamp();
+    let from = Jid::from_str(&record.from).map(|from| BareJid::from(from).to_string()).unwrap_or_else(|_| record.from.clone());
+    let mut mbox = format!("From {} {}\n", from, timestamp.format("%a %b %e %H:%M:%S %Y"));
+    mbox.push_str(&format!("From: <{}>\nTo: <{}>\nDate: {}\nMessage-ID: <{}>\nSubject: {}\nContent-Type: text/plain; charset=utf-8\n\n", record.from, record.to, timestamp.to_rfc2822(), record.id, record.conversation));
+    for line in record.body.lines() {
+        // Lines looking like the start of a message are quoted, as mboxrd does
+        if line.trim_start_matches('>').starts_with("From ") {
+            mbox.push('>');
+        }
+        mbox.push_str(line);
+        mbox.push('\n');
+    }
+    mbox.push('\n');
+    mbox
+}
+
+/// Messages exported in a format, oldest first
+pub fn export(messages: &[Message], format: Format) -> String {
+    let records: Vec<(Record, &Message)> = messages.iter().filter_map(|message| Record::new(message).map(|record| (record, message))).collect();
+    match format {
+        Format::Json => {
+            let records: Vec<&Record> = records.iter().map(|(record, _)| record).collect();
+            serde_json::to_string_pretty(&records).unwrap()
+        },
+        Format::Mbox => records.iter().map(|(record, message)| mbox(record, message)).collect(),
+        Format::Txt => {
+            let mut txt = String::new();
+            let mut conversation = None;
+            for (record, message) in &records {
+                if conversation != Some(&record.conversation) {
+                    txt.push_str(&format!("== {}\n", record.conversation));
+                    conversation = Some(&record.conversation);
+                }
+                let timestamp = Local.from_utc_datetime(&message.timestamp().naive_utc());
+                let padding = " ".repeat("0000-00-00 00:00:00 - ".len());
+                let body = record.body.lines().collect::<Vec<_>>().join(&format!("\n{}", padding));
+                txt.push_str(&format!("{} - {}: {}\n", timestamp.format("%Y-%m-%d %H:%M:%S"), sender(message), body));
+            }
+            txt
+        },
+    }
+}
+
+/// Id of an imported message without one, the same when the message is imported again, even by
+/// another build of Aparté
+fn imported_id(conversation: &str, timestamp: &DateTime<Utc>, from: &str, body: &str) -> String {
+    let mut hasher = Sha256::new();
+    let seconds = format!("{}.{:09}", timestamp.timestamp(), timestamp.timestamp_subsec_nanos());
+    for field in &[conversation, &seconds, from, body] {
+        hasher.update(field.as_bytes());
+        hasher.update(b"\0");
+    }
+    let digest: String = hasher.finish()[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("import-{}", digest)
+}
+
+fn json(path: &Path) -> Result<Vec<Message>, String> {
+    let content = fs::read_to_string(path).map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+    let records: Vec<Record> = serde_json::from_str(&content).map_err(|err| format!("Invalid history {}: {}", path.display(), err))?;
+    records.iter().map(Record::message).collect()
+}
+
+/// Messages of the Gajim database, whose kinds of log lines are:
+/// 2 for channel messages, 3 and 4 for received messages, 5 and 6 for sent ones
+fn gajim(path: &Path, account: &BareJid) -> Result<Vec<Message>, String> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(|err| format!("Cannot open {}: {}", path.display(), err))?;
+    let query = "SELECT jids.jid, logs.contact_name, logs.time, logs.kind, logs.message FROM logs JOIN jids USING (jid_id) WHERE logs.kind IN (2, 3, 4, 5, 6) AND logs.message IS NOT NULL";
+    // Databases of Gajim 1.0 and later keep the logs of every account, the account of each log
+    // line being one of the JIDs
+    let multi_account = connection.prepare("SELECT account_id FROM logs LIMIT 0").is_ok();
+    let query = match multi_account {
+        true => format!("{} AND logs.account_id = (SELECT jid_id FROM jids WHERE jid = ?1) ORDER BY logs.time", query),
+        false => format!("{} ORDER BY logs.time", query),
+    };
+    let mut statement = connection.prepare(&query).map_err(|err| format!("Not a Gajim database {}: {}", path.display(), err))?;
+    let row = |row: &rusqlite::Row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, Option<String>>(1)?,
+            row.get::<_, f64>(2)?,
+            row.get::<_, i64>(3)?,
+            row.get::<_, String>(4)?,
+        ))
+    };
+    let rows = match multi_account {
+        true => statement.query_map(params![account.to_string()], row),
+        false => statement.query_map(NO_PARAMS, row),
+    }.map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+
+    let us = Jid::Bare(account.clone());
+    let mut messages = Vec::new();
+    for row in rows {
+        let (jid, nick, time, kind, body) = row.map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+        let conversation = match BareJid::from_str(&jid) {
+            Ok(conversation) => conversation,
+            Err(_) => continue,
+        };
+        let timestamp = Utc.timestamp_opt(time as i64, ((time.fract()) * 1e9) as u32).single().unwrap_or_else(Utc::now);
+        let id = imported_id(&jid, &timestamp, nick.as_deref().unwrap_or(""), &body);
+        let message = match (kind, nick) {
+            (2, Some(nick)) => {
+                let from = Jid::Full(conversation.clone().with_resource(nick));
+                Message::incoming_groupchat(id, timestamp, &from, &us, &body)
+            },
+            (2, None) => continue,
+            (3, _) | (4, _) => Message::incoming_chat(id, timestamp, &Jid::Bare(conversation), &us, &body),
+            (_, _) => Message::outgoing_chat(id, timestamp, &us, &Jid::Bare(conversation), &body),
+        };
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// Log files of Profanity in a directory, recursively
+fn profanity_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let entries = fs::read_dir(path).map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+    for entry in entries {
+        let path = entry.map_err(|err| format!("Cannot read {}: {}", path.display(), err))?.path();
+        if path.is_dir() || path.extension().and_then(|extension| extension.to_str()) == Some("log") {
+            profanity_files(&path, files)?;
+        }
+    }
+    Ok(())
+}
+
+/// Messages of a Profanity log file, kept in a directory named after the contact, or the channel
+/// in a rooms directory, as juliet_at_capulet.lit/2020_04_05.log. Lines are written as
+/// `<timestamp> - <sender>: <body>`, the sender being me for our messages, timestamps being only
+/// times in old versions, continuation lines of a message having no timestamp.
+fn profanity(path: &Path, account: &BareJid) -> Result<Vec<Message>, String> {
+    let directory = path.parent().and_then(|directory| directory.file_name()).and_then(|name| name.to_str()).unwrap_or("");
+    let conversation = BareJid::from_str(&directory.replace("_at_", "@")).map_err(|_| format!("Cannot guess the conversation of {}", path.display()))?;
+    let channel = path.parent().and_then(Path::parent).and_then(Path::file_name).and_then(|name| name.to_str()) == Some("rooms");
+    let date = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| NaiveDate::parse_from_str(stem, "%Y_%m_%d").ok());
+    let content = fs::read_to_string(path).map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+
+    let timestamp = |timestamp: &str| -> Option<DateTime<Utc>> {
+        if let Ok(timestamp) = DateTime::parse_from_rfc3339(timestamp) {
+            return Some(timestamp.with_timezone(&Utc));
+        }
+        let time = NaiveTime::parse_from_str(timestamp, "%H:%M:%S").ok()?;
+        Local.from_local_datetime(&NaiveDateTime::new(date?, time)).single().map(|timestamp| timestamp.with_timezone(&Utc))
+    };
+
+    let mut lines: Vec<(DateTime<Utc>, String, String)> = Vec::new();
+    for line in content.lines() {
+        let parsed = line.find(" - ").and_then(|index| {
+            let (sender, body) = line[index + 3..].split_at(line[index + 3..].find(": ")?);
+            Some((timestamp(&line[..index])?, sender.to_string(), body[2..].to_string()))
+        });
+        match (parsed, lines.last_mut()) {
+            (Some(parsed), _) => lines.push(parsed),
+            (None, Some((_, _, body))) => {
+                body.push('\n');
+                body.push_str(line);
+            },
+            (None, None) => {},
+        }
+    }
+
+    let us = Jid::Bare(account.clone());
+    let name = conversation.to_string();
+    Ok(lines.into_iter().map(|(timestamp, sender, body)| {
+        let id = imported_id(&name, &timestamp, &sender, &body);
+        match (channel, sender.as_str()) {
+            (false, "me") => Message::outgoing_chat(id, timestamp, &us, &Jid::Bare(conversation.clone()), &body),
+            (false, _) => Message::incoming_chat(id, timestamp, &Jid::Bare(conversation.clone()), &us, &body),
+            (true, "me") => Message::outgoing_groupchat(id, timestamp, &us, &Jid::Bare(conversation.clone()), &body),
+            (true, nick) => Message::incoming_groupchat(id, timestamp, &Jid::Full(conversation.clone().with_resource(nick)), &us, &body),
+        }
+    }).collect())
+}
+
+/// Messages of logs exported by another client or with /export, `account` being us in their
+/// conversations
+pub fn import(path: &Path, source: Source, account: &BareJid) -> Result<Vec<Message>, String> {
+    match source {
+        Source::Json => json(path),
+        Source::Gajim => gajim(path, account),
+        Source::Profanity => {
+            let mut files = Vec::new();
+            profanity_files(path, &mut files)?;
+            files.sort();
+            let mut messages = Vec::new();
+            for file in files {
+                messages.extend(profanity(&file, account)?);
+            }
+            Ok(messages)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use uuid::Uuid;
+
+    fn messages() -> Vec<Message> {
+        let us = Jid::from_str("romeo@montague.lit/orchard").unwrap();
+        let juliet = Jid::from_str("juliet@capulet.lit/balcony").unwrap();
+        vec![
+            Message::incoming_chat("1", Utc.timestamp_opt(1000, 0).unwrap(), &juliet, &us, "Wherefore art thou?\nFrom the balcony"),
+            Message::outgoing_chat("2", Utc.timestamp_opt(1001, 0).unwrap(), &us, &juliet, "Here"),
+        ]
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("aparte-export-test-{}-{}", process::id(), Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_export() {
+        let txt = export(&messages(), Format::Txt);
+        let time = |seconds| Local.from_utc_datetime(&Utc.timestamp_opt(seconds, 0).unwrap().naive_utc()).format("%Y-%m-%d %H:%M:%S").to_string();
+        assert_eq!(txt, format!("== juliet@capulet.lit\n{} - juliet@capulet.lit: Wherefore art thou?\n                      From the balcony\n{} - me: Here\n", time(1000), time(1001)));
+
+        let mbox = export(&messages(), Format::Mbox);
+        assert!(mbox.starts_with("From juliet@capulet.lit Thu Jan  1 00:16:40 1970\nFrom: <juliet@capulet.lit/balcony>\n"));
+        assert!(mbox.contains("\nWherefore art thou?\n>From the balcony\n\nFrom romeo@montague.lit "));
+
+        let dir = temp_dir();
+        let path = dir.join("history.json");
+        fs::write(&path, export(&messages(), Format::Json)).unwrap();
+        let account = BareJid::from_str("romeo@montague.lit").unwrap();
+        let imported = import(&path, Source::guess(&path), &account).unwrap();
+        assert_eq!(imported, messages());
+        assert_eq!(imported[0].body(), "Wherefore art thou?\nFrom the balcony");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_profanity() {
+        let dir = temp_dir();
+        let logs = dir.join("romeo_at_montague.lit").join("juliet_at_capulet.lit");
+        fs::create_dir_all(&logs).unwrap();
+        fs::write(logs.join("2020_04_05.log"), "2020-04-05T14:21:31.123456+02:00 - juliet@capulet.lit: Wherefore art thou?\nTwo lines\n2020-04-05T14:22:00+02:00 - me: Here\n").unwrap();
+        let rooms = dir.join("romeo_at_montague.lit").join("rooms").join("verona_at_chat.lit");
+        fs::create_dir_all(&rooms).unwrap();
+        fs::write(rooms.join("2020_04_05.log"), "14:21:31 - mercutio: A plague\n").unwrap();
+
+        let account = BareJid::from_str("romeo@montague.lit").unwrap();
+        let messages = import(&dir, Source::guess(&dir), &account).unwrap();
+        assert_eq!(messages.len(), 3);
+        match &messages[0] {
+            Message::Incoming(XmppMessage::Chat(message)) => {
+                assert_eq!(message.from.to_string(), "juliet@capulet.lit");
+                assert_eq!(&*message.body, "Wherefore art thou?\nTwo lines");
+                assert_eq!(message.timestamp, Utc.with_ymd_and_hms(2020, 4, 5, 12, 21, 31).unwrap() + chrono::Duration::microseconds(123456));
+            },
+            _ => panic!("Not an incoming chat message"),
+        }
+        assert!(matches!(&messages[1], Message::Outgoing(XmppMessage::Chat(message)) if &*message.body == "Here"));
+        assert!(matches!(&messages[2], Message::Incoming(XmppMessage::Groupchat(message)) if message.from_full.to_string() == "verona@chat.lit/mercutio"));
+
+        // Imported again, messages keep the same ids
+        assert_eq!(import(&dir, Source::Profanity, &account).unwrap()[0].id(), messages[0].id());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_imported_id() {
+        // Ids must not depend on the build, messages imported again would be duplicated otherwise
+        let id = imported_id("juliet@capulet.lit", &Utc.timestamp_opt(1000, 0).unwrap(), "juliet", "Hello");
+        assert_eq!(id, "import-15a5796e47184918");
+    }
+
+    #[test]
+    fn test_import_gajim() {
+        let dir = temp_dir();
+        let path = dir.join("logs.db");
+        let connection = Connection::open(&path).unwrap();
+        connection.execute_batch(
+            "CREATE TABLE jids (jid_id INTEGER PRIMARY KEY, jid TEXT UNIQUE, type INTEGER);
+            CREATE TABLE logs (log_line_id INTEGER PRIMARY KEY, account_id INTEGER, jid_id INTEGER, contact_name TEXT, time INTEGER, kind INTEGER, message TEXT);
+            INSERT INTO jids VALUES (1, 'romeo@montague.lit', 0), (2, 'benvolio@montague.lit', 0), (3, 'juliet@capulet.lit', 0);
+            INSERT INTO logs VALUES (1, 1, 3, NULL, 1000, 4, 'Wherefore art thou?'), (2, 2, 3, NULL, 1001, 4, 'Not to Benvolio'), (3, 1, 3, NULL, 1002, 6, 'Here');",
+        ).unwrap();
+        drop(connection);
+
+        let account = BareJid::from_str("romeo@montague.lit").unwrap();
+        let messages = import(&path, Source::guess(&path), &account).unwrap();
+        assert_eq!(messages.iter().map(Message::body).collect::<Vec<_>>(), vec!["Wherefore art thou?", "Here"]);
+        assert!(matches!(&messages[1], Message::Outgoing(XmppMessage::Chat(message)) if message.from.to_string() == "romeo@montague.lit"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,541 @@
+//! Fake XMPP server, to test plugins end-to-end without a live server. A server plays a script of
+//! stanzas: the ones the client is expected to send, and the ones sent back to it. Scripts are
+//! written with `expect` and `send`, or replayed from the SEND and RECV lines of aparte.log.
+//!
+//! The client is connected to the server through a stream of packets, as the ones of TCP,
+//! WebSocket and BOSH, and runs as when connected to a live server. Built in tests, and with the
+//! `fakeserver` feature where `--replay <aparte.log>` replays a recorded session.
+//!
+//! ```ignore
+//! let server = FakeServer::new()
+//!     .expect("<iq xmlns='jabber:client' type='set' id='1'><enable xmlns='urn:xmpp:carbons:2'/></iq>")
+//!     .send("<iq xmlns='jabber:client' type='result' id='1'/>");
+//! fakeserver::run(&aparte, &account, server).unwrap();
+//! ```
+//!
+//! Stanzas are expected in order, unexpected ones being ignored, and match when they have the
+//! attributes, children and text of the expected ones. Ids of the requests differing from the
+//! recorded ones, the id of a matched stanza replaces the recorded one in the stanzas sent next,
+//! as their id or the queryid of archived messages. What the user does in between, as joining a
+//! channel, is scripted with `act`.
+use futures::task::{self, Task};
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::runtime::current_thread::{self, Runtime};
+use tokio::timer::Delay;
+use tokio_xmpp::{Error as XmppError, Packet};
+use uuid::Uuid;
+use xmpp_parsers::{Element, FullJid};
+
+use crate::account::Account;
+use crate::client::{self, Client, Details};
+use crate::core::{Aparte, Event, Plugin};
+use crate::plugins::{certificates, disco};
+use crate::tls::{Certificate, Trust};
+
+/// Time the client is given by default to send each expected stanza
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Time the client is given to handle the last stanzas once the script was played
+const SETTLE: Duration = Duration::from_millis(50);
+
+enum Step {
+    /// Stanza the client must send
+    Expect(Element),
+    /// Stanza sent to the client
+    Send(Element),
+    /// Something the client does, as the user would
+    Act(Box<dyn FnOnce(Rc<Aparte>)>),
+}
+
+pub struct FakeServer {
+    script: VecDeque<Step>,
+    /// Actual ids of the stanzas sent by the client, by recorded id
+    ids: HashMap<String, String>,
+    /// Time the client is given to send each expected stanza
+    timeout: Duration,
+    /// Features of the stream offered to the client
+    features: Element,
+    /// Every stanza sent by the client
+    pub received: Vec<Element>,
+    /// Task of the client reading, waiting for the next stanza sent
+    reader: Option<Task>,
+    /// Task playing the script, waiting for the client
+    player: Option<Task>,
+}
+
+fn parse(xml: &str) -> Element {
+    match xml.parse() {
+        Ok(element) => element,
+        Err(err) => panic!("Invalid stanza {}: {}", xml, err),
+    }
+}
+
+/// Whether a stanza has the attributes, children and text of an expected one, ids aside
+pub fn matches(expected: &Element, actual: &Element) -> bool {
+    if expected.name() != actual.name() || expected.ns() != actual.ns() {
+        return false;
+    }
+    let attrs = expected.attrs().filter(|(name, _)| *name != "id").all(|(name, value)| actual.attr(name) == Some(value));
+    let text = expected.text();
+    let text = text.trim().is_empty() || text == actual.text();
+    attrs && text && expected.children().all(|expected| actual.children().any(|actual| matches(expected, actual)))
+}
+
+impl FakeServer {
+    pub fn new() -> Self {
+        Self {
+            script: VecDeque::new(),
+            ids: HashMap::new(),
+            timeout: TIMEOUT,
+            features: Element::builder("features").ns("http://etherx.jabber.org/streams").build(),
+            received: Vec::new(),
+            reader: None,
+            player: None,
+        }
+    }
+
+    /// Expect the client to send a stanza
+    pub fn expect(mut self, xml: &str) -> Self {
+        self.script.push_back(Step::Expect(parse(xml)));
+        self
+    }
+
+    /// Send a stanza to the client
+    pub fn send(mut self, xml: &str) -> Self {
+        self.script.push_back(Step::Send(parse(xml)));
+        self
+    }
+
+    /// Make the client do something once the stanzas before were exchanged
+    pub fn act<F: FnOnce(Rc<Aparte>) + 'static>(mut self, act: F) -> Self {
+        self.script.push_back(Step::Act(Box::new(act)));
+        self
+    }
+
+    /// Give the client another time to send each expected stanza
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Offer other features of the stream, as `<features xmlns='http://etherx.jabber.org/streams'>…`
+    pub fn features(mut self, xml: &str) -> Self {
+        self.features = parse(xml);
+        self
+    }
+
+    /// Script of an exchange recorded in aparte.log, stanzas left out of the log being skipped
+    pub fn replay(log: &str) -> Self {
+        let mut server = FakeServer::new();
+        for line in log.lines() {
+            let (step, xml): (fn(Element) -> Step, &str) = match (line.find("SEND: "), line.find("RECV: ")) {
+                (Some(index), _) => (Step::Expect, &line[index + 6..]),
+                (None, Some(index)) => (Step::Send, &line[index + 6..]),
+                (None, None) => continue,
+            };
+            if let Ok(element) = xml.parse() {
+                server.script.push_back(step(element));
+            }
+        }
+        server
+    }
+
+    /// Whether every step of the script was played
+    pub fn is_done(&self) -> bool {
+        self.script.is_empty()
+    }
+
+    fn received(&mut self, element: Element) {
+        if let Some(Step::Expect(expected)) = self.script.front() {
+            if matches(expected, &element) {
+                if let (Some(recorded), Some(actual)) = (expected.attr("id"), element.attr("id")) {
+                    self.ids.insert(recorded.to_string(), actual.to_string());
+                }
+                self.script.pop_front();
+            }
+        }
+        self.received.push(element);
+        self.wake();
+    }
+
+    /// Let the client and the script go on
+    fn wake(&mut self) {
+        for task in self.reader.take().into_iter().chain(self.player.take()) {
+            task.notify();
+        }
+    }
+
+    /// Next thing the client must do, once the ones before were done
+    fn next_act(&mut self) -> Option<Box<dyn FnOnce(Rc<Aparte>)>> {
+        match self.script.front() {
+            Some(Step::Act(_)) => {},
+            _ => return None,
+        }
+        match self.script.pop_front() {
+            Some(Step::Act(act)) => Some(act),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Next stanza to send to the client, once the ones expected before were received
+    fn next(&mut self) -> Option<Element> {
+        match self.script.front() {
+            Some(Step::Send(_)) => {},
+            _ => return None,
+        }
+        let mut element = match self.script.pop_front() {
+            Some(Step::Send(element)) => element,
+            _ => unreachable!(),
+        };
+        self.map_ids(&mut element);
+        Some(element)
+    }
+
+    /// Replace the recorded ids of a stanza by the actual ones, as its id and the queryid of the
+    /// archived messages it carries
+    fn map_ids(&self, element: &mut Element) {
+        for attr in &["id", "queryid"] {
+            if let Some(id) = element.attr(attr).and_then(|id| self.ids.get(id)).cloned() {
+                element.set_attr(*attr, id);
+            }
+        }
+        for child in element.children_mut() {
+            self.map_ids(child);
+        }
+    }
+
+    /// Play the script with the client, connected and online on an account. Fails with the stanza
+    /// still expected when the client doesn't send it in time.
+    pub fn play(self, aparte: Rc<Aparte>, account: FullJid) -> impl Future<Item = FakeServer, Error = String> {
+        let mut timeout = Delay::new(Instant::now() + self.timeout);
+        let features = self.features.clone();
+        let server = Rc::new(RefCell::new(self));
+        let stream = FakeStream { server: Rc::clone(&server), features };
+        let details = Details {
+            transport: String::from("fake server"),
+            address: None,
+            certificate: certificate(),
+            trust: Trust::Authority,
+            mechanism: None,
+        };
+        let mut client = Some(Client::connected(Box::new(stream), details));
+        let mut received = 0;
+        let mut settling = false;
+        future::poll_fn(move || {
+            // Connecting once in the event loop, for the tasks spawned on connection to run in it
+            if let Some(client) = client.take() {
+                current_thread::spawn(crate::run_client(Rc::clone(&aparte), account.to_string(), account.clone(), Account::default(), client));
+            }
+
+            // Acting once online, as the user would, the client not sending anything before
+            match aparte.stream_features(&account) {
+                Some(_) => loop {
+                    let act = server.borrow_mut().next_act();
+                    match act {
+                        Some(act) => act(Rc::clone(&aparte)),
+                        None => break,
+                    }
+                    server.borrow_mut().wake();
+                },
+                None => task::current().notify(),
+            }
+
+            let mut this = server.borrow_mut();
+            this.player = Some(task::current());
+            if this.received.len() != received {
+                received = this.received.len();
+                if !settling {
+                    timeout.reset(Instant::now() + this.timeout);
+                }
+            }
+
+            match this.script.front() {
+                // Leaving the client some time to handle the last stanzas sent
+                None => {
+                    if !settling {
+                        settling = true;
+                        timeout.reset(Instant::now() + SETTLE);
+                    }
+                    match timeout.poll() {
+                        Ok(Async::NotReady) => Ok(Async::NotReady),
+                        _ => Ok(Async::Ready(mem::replace(&mut *this, FakeServer::new()))),
+                    }
+                },
+                Some(step) => match timeout.poll() {
+                    Ok(Async::NotReady) => Ok(Async::NotReady),
+                    _ => match step {
+                        Step::Expect(expected) => Err(format!("Expected {}", String::from(expected))),
+                        Step::Send(element) => Err(format!("Not read by the client {}", String::from(element))),
+                        Step::Act(_) => unreachable!(),
+                    },
+                },
+            }
+        })
+    }
+}
+
+/// Certificate the fake server is trusted with
+fn certificate() -> Certificate {
+    Certificate {
+        subject: String::from("CN=fakeserver"),
+        issuer: String::from("CN=fakeserver"),
+        names: Vec::new(),
+        fingerprint: String::new(),
+        not_before: String::new(),
+        not_after: String::new(),
+        expires_in: i32::MAX,
+    }
+}
+
+/// Stream of the client connected to a fake server, reading the stanzas the script sends and
+/// handing the server the ones written
+struct FakeStream {
+    server: Rc<RefCell<FakeServer>>,
+    features: Element,
+}
+
+impl Stream for FakeStream {
+    type Item = Packet;
+    type Error = XmppError;
+
+    fn poll(&mut self) -> Poll<Option<Packet>, XmppError> {
+        let mut server = self.server.borrow_mut();
+        match server.next() {
+            Some(element) => Ok(Async::Ready(Some(Packet::Stanza(element)))),
+            None => {
+                server.reader = Some(task::current());
+                Ok(Async::NotReady)
+            },
+        }
+    }
+}
+
+impl Sink for FakeStream {
+    type SinkItem = Packet;
+    type SinkError = XmppError;
+
+    fn start_send(&mut self, packet: Packet) -> StartSend<Packet, XmppError> {
+        if let Packet::Stanza(element) = packet {
+            self.server.borrow_mut().received(element);
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), XmppError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl client::Stanzas for FakeStream {
+    fn features(&self) -> &Element {
+        &self.features
+    }
+
+    fn restart(self: Box<Self>) -> Box<dyn Future<Item = client::Connection, Error = XmppError>> {
+        Box::new(future::ok(self as client::Connection))
+    }
+}
+
+/// Bodies of the messages shown, logs included, for tests to check what the user sees
+pub struct Recorder {
+    pub bodies: Vec<String>,
+}
+
+impl Plugin for Recorder {
+    fn new() -> Recorder {
+        Recorder { bodies: Vec::new() }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, _aparte: Rc<Aparte>, event: &Event) {
+        if let Event::Message(message) = event {
+            self.bodies.push(message.body().to_string());
+        }
+    }
+}
+
+impl fmt::Display for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Recorder")
+    }
+}
+
+/// Client without accounts configured, with the plugins connecting needs, the ones added by
+/// `add` and a Recorder
+pub fn aparte<F: FnOnce(&mut Aparte)>(add: F) -> Rc<Aparte> {
+    let config = std::env::temp_dir().join(format!("aparte-fakeserver-{}-{}.toml", std::process::id(), Uuid::new_v4()));
+    std::fs::write(&config, "[accounts]\n").unwrap();
+    let mut aparte = Aparte::new(config.clone());
+    std::fs::remove_file(config).unwrap();
+    aparte.add_plugin(disco::Disco::new());
+    aparte.add_plugin(certificates::CertificatesPlugin::new());
+    add(&mut aparte);
+    aparte.add_plugin(Recorder::new());
+    aparte.init().unwrap();
+    aparte.into_rc()
+}
+
+/// Play the script of a server with the client until its end, in an event loop of its own
+pub fn run(aparte: &Rc<Aparte>, account: &FullJid, server: FakeServer) -> Result<FakeServer, String> {
+    let mut runtime = Runtime::new().map_err(|err| format!("Cannot start event loop: {}", err))?;
+    runtime.block_on(server.play(Rc::clone(aparte), account.clone()))
+}
+
+/// Script of a session recorded in aparte.log, and the account its stanzas were sent to
+#[cfg(feature = "fakeserver")]
+fn session(path: &std::path::Path) -> Result<(FakeServer, FullJid), String> {
+    let log = std::fs::read_to_string(path).map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+    let server = FakeServer::replay(&log);
+    let account = server.script.iter().find_map(|step| match step {
+        Step::Send(element) => element.attr("to").and_then(|to| to.parse::<FullJid>().ok()),
+        Step::Expect(_) | Step::Act(_) => None,
+    }).ok_or_else(|| format!("No stanza received in {}", path.display()))?;
+    Ok((server, account))
+}
+
+/// Replay a session recorded in aparte.log, with `--replay <aparte.log>`
+#[cfg(feature = "fakeserver")]
+pub fn replay(aparte: Rc<Aparte>, path: &std::path::Path) -> impl Future<Item = (), Error = ()> {
+    let error_aparte = Rc::clone(&aparte);
+    future::result(session(path))
+        .and_then(move |(server, account)| server.play(aparte, account))
+        .map(|_| ())
+        .map_err(move |err| error_aparte.log(format!("Replay stopped: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use std::str::FromStr;
+    use xmpp_parsers::{BareJid, Jid};
+    use crate::conversation::Conversation;
+    use crate::message::Message;
+    use crate::plugins::{bookmarks, carbons, conversation, mentions};
+    use crate::store::{MemoryStore, MessageStore};
+
+    #[test]
+    fn test_matches() {
+        let expected = parse("<message xmlns='jabber:client' to='juliet@capulet.lit' id='1'><body>Hi</body></message>");
+        assert!(matches(&expected, &parse("<message xmlns='jabber:client' to='juliet@capulet.lit' id='2' type='chat'><body>Hi</body><active xmlns='http://jabber.org/protocol/chatstates'/></message>")));
+        assert!(!matches(&expected, &parse("<message xmlns='jabber:client' to='juliet@capulet.lit'><body>Hello</body></message>")));
+        assert!(!matches(&expected, &parse("<message xmlns='jabber:client' to='romeo@montague.lit'><body>Hi</body></message>")));
+        assert!(!matches(&expected, &parse("<message xmlns='jabber:client' to='juliet@capulet.lit'/>")));
+    }
+
+    #[test]
+    fn test_carbons() {
+        let aparte = aparte(|aparte| {
+            aparte.add_plugin(conversation::ConversationPlugin::new());
+            aparte.add_plugin(carbons::CarbonsPlugin::new());
+        });
+        let account = FullJid::from_str("romeo@montague.lit/orchard").unwrap();
+        // As logged, the id of the request being another one each time
+        let log = "\
+[00:00:00.000] (1) DEBUG SEND: <iq xmlns='jabber:client' type='set' id='recorded'><enable xmlns='urn:xmpp:carbons:2'/></iq>
+[00:00:00.100] (1) DEBUG RECV: <iq xmlns='jabber:client' type='result' id='recorded' from='romeo@montague.lit'/>
+[00:00:01.000] (1) DEBUG RECV: <message xmlns='jabber:client' type='chat' from='juliet@capulet.lit/balcony' to='romeo@montague.lit/orchard' id='1'><body>Wherefore art thou?</body></message>
+";
+        let server = FakeServer::replay(log).features("<features xmlns='http://etherx.jabber.org/streams'><sm xmlns='urn:xmpp:sm:3'/></features>");
+        let server = run(&aparte, &account, server).unwrap();
+        assert!(server.is_done());
+        assert!(aparte.stream_features(&account).unwrap().has_child("sm", "urn:xmpp:sm:3"));
+        let (_, stats) = aparte.connection_stats().unwrap();
+        assert_eq!(stats.details.unwrap().transport, "fake server");
+        // The carbons request, and the presence sent once online
+        assert_eq!(server.received.len(), 2);
+        // Carbons were enabled, the request being answered
+        assert_eq!(aparte.get_plugin::<Recorder>().unwrap().bodies, vec![String::from("Connected as romeo@montague.lit/orchard"), String::from("Wherefore art thou?")]);
+
+        // Failing when the client doesn't send what is expected
+        let server = FakeServer::new()
+            .timeout(Duration::from_millis(100))
+            .send("<message xmlns='jabber:client' type='chat' from='juliet@capulet.lit/balcony' to='romeo@montague.lit/orchard' id='2'><body>Ping me</body></message>")
+            .expect("<iq xmlns='jabber:client' type='get'><ping xmlns='urn:xmpp:ping'/></iq>");
+        let err = run(&aparte, &account, server).err().unwrap();
+        assert!(err.starts_with("Expected ") && err.ends_with("<ping xmlns=\"urn:xmpp:ping\"/></iq>"));
+    }
+
+    #[test]
+    fn test_muc() {
+        let aparte = aparte(|aparte| {
+            aparte.add_plugin(conversation::ConversationPlugin::new());
+            aparte.add_plugin(bookmarks::BookmarksPlugin::new());
+        });
+        let account = FullJid::from_str("romeo@montague.lit/orchard").unwrap();
+        // Channels bookmarked to be joined automatically are joined once connected
+        let server = FakeServer::new()
+            .expect("<iq xmlns='jabber:client' type='get' id='bookmarks'><query xmlns='jabber:iq:private'><storage xmlns='storage:bookmarks'/></query></iq>")
+            .send("<iq xmlns='jabber:client' type='result' id='bookmarks'><query xmlns='jabber:iq:private'><storage xmlns='storage:bookmarks'><conference jid='verona@chat.montague.lit' name='Verona' autojoin='true'><nick>romeo</nick></conference></storage></query></iq>")
+            .expect("<presence xmlns='jabber:client' to='verona@chat.montague.lit/romeo'><x xmlns='http://jabber.org/protocol/muc'/></presence>")
+            .send("<presence xmlns='jabber:client' from='verona@chat.montague.lit/mercutio' to='romeo@montague.lit/orchard'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='member' role='participant'/></x></presence>")
+            .send("<presence xmlns='jabber:client' from='verona@chat.montague.lit/romeo' to='romeo@montague.lit/orchard'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='none' role='participant'/><status code='110'/></x></presence>")
+            .send("<message xmlns='jabber:client' type='groupchat' from='verona@chat.montague.lit/mercutio' to='romeo@montague.lit/orchard' id='1'><subject>Fair Verona</subject></message>")
+            .send("<message xmlns='jabber:client' type='groupchat' from='verona@chat.montague.lit/mercutio' to='romeo@montague.lit/orchard' id='2'><body>A plague o' both your houses!</body></message>");
+        assert!(run(&aparte, &account, server).unwrap().is_done());
+
+        let conversations = aparte.get_plugin::<conversation::ConversationPlugin>().unwrap();
+        let channel = match conversations.get(&BareJid::from_str("verona@chat.montague.lit").unwrap()) {
+            Some(Conversation::Channel(channel)) => channel,
+            _ => panic!("Channel not joined"),
+        };
+        assert_eq!(channel.account, account);
+        assert_eq!(channel.nick, "romeo");
+        assert_eq!(channel.subject.as_deref(), Some("Fair Verona"));
+        let mut occupants: Vec<&String> = channel.occupants.keys().collect();
+        occupants.sort();
+        assert_eq!(occupants, vec!["mercutio", "romeo"]);
+        assert!(aparte.get_plugin::<Recorder>().unwrap().bodies.contains(&String::from("A plague o' both your houses!")));
+    }
+
+    #[test]
+    fn test_mam() {
+        let aparte = aparte(|aparte| {
+            aparte.add_plugin(conversation::ConversationPlugin::new());
+            aparte.add_plugin(mentions::MentionsPlugin::new());
+        });
+        let account = FullJid::from_str("romeo@montague.lit/orchard").unwrap();
+        let us = Jid::from_str("verona@chat.montague.lit/romeo").unwrap();
+        let mercutio = Jid::from_str("verona@chat.montague.lit/mercutio").unwrap();
+        let mut store = MemoryStore::new();
+        store.insert_mention(&Message::incoming_groupchat("2", Utc.timestamp_opt(1000, 0).unwrap(), &mercutio, &us, "romeo: ho!")).unwrap();
+
+        // Messages around a mention are fetched from the archive of its channel
+        let context = Rc::new(RefCell::new(None));
+        let fetched = Rc::clone(&context);
+        let server = FakeServer::new()
+            .act(move |aparte| {
+                let query = {
+                    let mut mentions = aparte.get_plugin_mut::<mentions::MentionsPlugin>().unwrap();
+                    mentions.list(&store).unwrap();
+                    mentions.context(&store, 1, false).unwrap().2.unwrap()
+                };
+                let id = query.id.clone();
+                let answer_aparte = Rc::clone(&aparte);
+                current_thread::spawn(aparte.send_iq(query).then(move |answer| {
+                    *fetched.borrow_mut() = answer_aparte.get_plugin_mut::<mentions::MentionsPlugin>().unwrap().archive_complete(&id, answer);
+                    Ok(())
+                }));
+            })
+            .expect("<iq xmlns='jabber:client' type='set' to='verona@chat.montague.lit' id='query'><query xmlns='urn:xmpp:mam:2'/></iq>")
+            .send("<message xmlns='jabber:client' from='verona@chat.montague.lit' to='romeo@montague.lit/orchard'><result xmlns='urn:xmpp:mam:2' queryid='query' id='1'><forwarded xmlns='urn:xmpp:forward:0'><delay xmlns='urn:xmpp:delay' stamp='1970-01-01T00:16:30Z'/><message xmlns='jabber:client' type='groupchat' from='verona@chat.montague.lit/mercutio'><body>Good morrow</body></message></forwarded></result></message>")
+            .send("<message xmlns='jabber:client' from='verona@chat.montague.lit' to='romeo@montague.lit/orchard'><result xmlns='urn:xmpp:mam:2' queryid='query' id='3'><forwarded xmlns='urn:xmpp:forward:0'><delay xmlns='urn:xmpp:delay' stamp='1970-01-01T00:16:50Z'/><message xmlns='jabber:client' type='groupchat' from='verona@chat.montague.lit/benvolio'><body>Here comes Romeo</body></message></forwarded></result></message>")
+            .send("<iq xmlns='jabber:client' type='result' from='verona@chat.montague.lit' id='query'><fin xmlns='urn:xmpp:mam:2' complete='true'/></iq>");
+        assert!(run(&aparte, &account, server).unwrap().is_done());
+
+        let (room, lines) = context.borrow_mut().take().unwrap();
+        assert_eq!(room, BareJid::from_str("verona@chat.montague.lit").unwrap());
+        assert!(lines[0].ends_with(", from the archive"));
+        assert!(lines[1].ends_with("<mercutio> Good morrow"));
+        assert!(lines[2].starts_with("»") && lines[2].ends_with("<mercutio> romeo: ho!"));
+        assert!(lines[3].ends_with("<benvolio> Here comes Romeo"));
+    }
+}
@@ -0,0 +1,67 @@
+/// Score of a candidate matching a pattern, the lower the better, none if it doesn't match.
+/// Each word of the pattern is found after the previous one in the candidate, its chars in order
+/// without being contiguous and ignoring case: "ju ex" matches juliet@example.com. Chars away
+/// from each other, and words not starting the ones of the candidate, make the score worse.
+pub fn score(pattern: &str, candidate: &str) -> Option<usize> {
+    let candidate: Vec<char> = candidate.chars().flat_map(char::to_lowercase).collect();
+    let mut position = 0;
+    let mut score = 0;
+
+    for word in pattern.split_whitespace() {
+        let word: Vec<char> = word.chars().flat_map(char::to_lowercase).collect();
+        // Best of the matches starting at each occurrence of the first char
+        let (best, end) = (position..candidate.len())
+            .filter(|start| candidate[*start] == word[0])
+            .filter_map(|start| word_score(&candidate, &word, start))
+            .min()?;
+        score += best;
+        position = end;
+    }
+
+    Some(score)
+}
+
+/// Score of the chars of a word found in order from `start`, with the position after the last one
+fn word_score(candidate: &[char], word: &[char], start: usize) -> Option<(usize, usize)> {
+    let mut score = match start == 0 || !candidate[start - 1].is_alphanumeric() {
+        true => 0,
+        false => 1,
+    };
+    let mut previous = start;
+    for c in &word[1..] {
+        let found = previous + 1 + candidate[previous + 1..].iter().position(|candidate| candidate == c)?;
+        score += found - previous - 1;
+        previous = found;
+    }
+    Some((score, previous + 1))
+}
+
+/// Indexes of the candidates matching a pattern, best first and in their order when as good
+pub fn filter<S: AsRef<str>>(pattern: &str, candidates: &[S]) -> Vec<usize> {
+    let mut matches: Vec<(usize, usize)> = candidates.iter().enumerate()
+        .filter_map(|(index, candidate)| score(pattern, candidate.as_ref()).map(|score| (score, index)))
+        .collect();
+    matches.sort();
+    matches.into_iter().map(|(_, index)| index).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score() {
+        assert_eq!(score("ju ex", "juliet@example.com"), Some(0));
+        assert_eq!(score("JUL", "Juliet"), Some(0));
+        assert_eq!(score("jt", "juliet@example.com"), Some(4));
+        assert_eq!(score("ex ju", "juliet@example.com"), None);
+        assert_eq!(score("", "console"), Some(0));
+        assert_eq!(score("le", "console"), Some(1));
+
+        let candidates = ["console", "romeo@montague.lit", "juliet@example.com", "julia@capulet.lit"];
+        assert_eq!(filter("ju ex", &candidates), vec![2]);
+        assert_eq!(filter("ju", &candidates), vec![2, 3]);
+        assert_eq!(filter("mon", &candidates), vec![1]);
+        assert_eq!(filter("e", &candidates), vec![2, 0, 1, 3]);
+    }
+}
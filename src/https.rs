@@ -0,0 +1,242 @@
+//! HTTPS client, its connections being opened as the ones of the accounts so that the certificate
+//! of the server can be checked against a known fingerprint instead of the known authorities
+use futures::future::Either;
+use futures::{future, Future, Poll, Stream};
+use hyper::client::connect::{Connect, Connected, Destination, HttpConnector};
+use hyper::header::LOCATION;
+use hyper::{Body, Client, Method, Request};
+use native_tls::TlsConnector as NativeTlsConnector;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::timer::Timeout;
+use tokio_tls::{TlsConnector, TlsStream};
+use url::Url;
+
+use crate::tls::Certificate;
+
+pub type HttpsClient = Client<Connector>;
+
+/// Time after which a download is abandoned
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+/// Redirections followed by a download
+const MAX_REDIRECTS: usize = 10;
+
+fn error<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+/// Whether a host is this machine, as a service run locally
+fn is_loopback(host: &str) -> bool {
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback(),
+        Err(_) => host == "localhost",
+    }
+}
+
+/// Connection to a server, encrypted unless it runs on this machine
+pub enum Transport {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.read(buf),
+            Transport::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.write(buf),
+            Transport::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Plain(stream) => stream.flush(),
+            Transport::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Transport {}
+
+impl AsyncWrite for Transport {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            Transport::Plain(stream) => AsyncWrite::shutdown(stream),
+            Transport::Tls(stream) => stream.shutdown(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Connector {
+    http: HttpConnector,
+    /// Fingerprint the certificate must have, checked instead of the known authorities
+    fingerprint: Option<String>,
+    /// Whether servers running on this machine may be connected to without encryption
+    loopback_http: bool,
+}
+
+impl Connect for Connector {
+    type Transport = Transport;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = (Self::Transport, Connected), Error = io::Error> + Send>;
+
+    fn connect(&self, destination: Destination) -> Self::Future {
+        if destination.scheme() == "http" && self.loopback_http && is_loopback(destination.host()) {
+            return Box::new(self.http.connect(destination).map(|(tcp, connected)| (Transport::Plain(tcp), connected)));
+        }
+        if destination.scheme() != "https" {
+            return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, format!("Refusing unencrypted connection to {}", destination.host()))));
+        }
+
+        let host = destination.host().to_string();
+        let fingerprint = self.fingerprint.clone();
+        let connector = NativeTlsConnector::builder()
+            .danger_accept_invalid_certs(fingerprint.is_some())
+            .danger_accept_invalid_hostnames(fingerprint.is_some())
+            .build();
+        let connector = match connector {
+            Ok(connector) => TlsConnector::from(connector),
+            Err(err) => return Box::new(future::err(error(err))),
+        };
+
+        Box::new(self.http.connect(destination).and_then(move |(tcp, connected)| {
+            connector.connect(&host, tcp).map_err(error).and_then(move |stream| {
+                if let Some(fingerprint) = fingerprint {
+                    let der = stream.get_ref().peer_certificate().map_err(error)?
+                        .ok_or(error("No certificate"))?
+                        .to_der().map_err(error)?;
+                    let certificate = Certificate::from_der(&der).map_err(error)?;
+                    if certificate.fingerprint != fingerprint {
+                        return Err(error(format!("Certificate of {} changed, it is {}", host, certificate.fingerprint)));
+                    }
+                }
+                Ok((Transport::Tls(stream), connected))
+            })
+        }))
+    }
+}
+
+fn connector(fingerprint: Option<String>, loopback_http: bool) -> Connector {
+    let mut http = HttpConnector::new(1);
+    http.enforce_http(false);
+    Connector {
+        http: http,
+        fingerprint: fingerprint,
+        loopback_http: loopback_http,
+    }
+}
+
+/// Client checking that certificates have `fingerprint` if set, or are signed by a known authority
+pub fn client(fingerprint: Option<String>) -> HttpsClient {
+    Client::builder().build(connector(fingerprint, false))
+}
+
+/// Body of the answer to a request, unless it failed or is larger than `max_size` bytes. Up to
+/// `redirects` redirections of GET requests are followed, as curl --location does.
+fn fetch_at_most(client: &HttpsClient, request: Request<Body>, max_size: usize, redirects: usize) -> Box<dyn Future<Item = Vec<u8>, Error = String>> {
+    let uri = request.uri().clone();
+    let get = request.method() == Method::GET;
+    let client = client.clone();
+    Box::new(client.request(request).map_err(|err| err.to_string()).and_then(move |response| {
+        let status = response.status();
+        let location = response.headers().get(LOCATION).and_then(|location| location.to_str().ok());
+        if let (true, true, Some(location)) = (get, status.is_redirection(), location) {
+            if redirects == 0 {
+                return Either::A(Either::A(future::err(format!("{} redirected too many times", uri))));
+            }
+            let location = Url::parse(&uri.to_string()).and_then(|uri| uri.join(location));
+            let request = location.map_err(|err| err.to_string()).and_then(|location| Request::get(location.as_str()).body(Body::empty()).map_err(|err| err.to_string()));
+            return Either::A(match request {
+                Ok(request) => Either::B(fetch_at_most(&client, request, max_size, redirects - 1)),
+                Err(err) => Either::A(future::err(format!("Invalid redirection of {}: {}", uri, err))),
+            });
+        }
+
+        let large = format!("{} is larger than {} bytes", uri, max_size);
+        Either::B(response.into_body().map_err(|err| err.to_string()).fold(Vec::new(), move |mut body, chunk| {
+            if body.len() + chunk.len() > max_size {
+                return Err(large.clone());
+            }
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        }).and_then(move |body| match status.is_success() {
+            true => Ok(body),
+            false => Err(format!("{} answered {}", uri, status)),
+        }))
+    }))
+}
+
+/// Body of the answer to a request, unless it failed
+pub fn fetch(client: &HttpsClient, request: Request<Body>) -> Box<dyn Future<Item = Vec<u8>, Error = String>> {
+    fetch_at_most(client, request, usize::max_value(), 0)
+}
+
+/// Body of the answer to a request made on behalf of the user, as a download, unless it failed, is
+/// larger than `max_size` bytes or took too long. Unlike the long polling of BOSH, which uses fetch,
+/// such requests are expected to be answered quickly.
+pub fn download(request: Request<Body>, max_size: usize) -> Box<dyn Future<Item = Vec<u8>, Error = String>> {
+    download_with(&client(None), request, max_size)
+}
+
+/// Download from a service configured by the user, which may run on this machine without
+/// encryption, as a translation service at http://localhost:5000
+pub fn download_configured(request: Request<Body>, max_size: usize) -> Box<dyn Future<Item = Vec<u8>, Error = String>> {
+    download_with(&Client::builder().build(connector(None, true)), request, max_size)
+}
+
+fn download_with(client: &HttpsClient, request: Request<Body>, max_size: usize) -> Box<dyn Future<Item = Vec<u8>, Error = String>> {
+    let uri = request.uri().clone();
+    Box::new(Timeout::new(fetch_at_most(client, request, max_size, MAX_REDIRECTS), DOWNLOAD_TIMEOUT).map_err(move |err| {
+        match err.into_inner() {
+            Some(err) => err,
+            None => format!("{} didn't answer after {}s", uri, DOWNLOAD_TIMEOUT.as_secs()),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::service_fn_ok;
+    use hyper::{Response, Server, StatusCode};
+    use tokio::runtime::current_thread::{Runtime, TaskExecutor};
+
+    #[test]
+    fn test_download() {
+        assert!(is_loopback("localhost") && is_loopback("127.0.0.1") && is_loopback("[::1]"));
+        assert!(!is_loopback("example.org") && !is_loopback("192.168.1.1"));
+
+        let mut runtime = Runtime::new().unwrap();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into())
+            .executor(TaskExecutor::current())
+            .serve(|| service_fn_ok(|request: Request<Body>| match request.uri().path() {
+                "/moved" => Response::builder().status(StatusCode::FOUND).header(LOCATION, "/translation").body(Body::empty()).unwrap(),
+                "/loop" => Response::builder().status(StatusCode::FOUND).header(LOCATION, "/loop").body(Body::empty()).unwrap(),
+                _ => Response::new(Body::from("Bonjour")),
+            }));
+        let url = format!("http://{}", server.local_addr());
+        runtime.spawn(server.map_err(|err| panic!("{}", err)));
+        let get = |path: &str| Request::get(format!("{}{}", url, path)).body(Body::empty()).unwrap();
+
+        // Services configured by the user may run on this machine without encryption
+        assert_eq!(runtime.block_on(download_configured(get("/translation"), 100)).unwrap(), b"Bonjour");
+        let err = runtime.block_on(download(get("/translation"), 100)).unwrap_err();
+        assert!(err.contains("Refusing unencrypted connection"), "{}", err);
+
+        // Redirections are followed, a few times only
+        assert_eq!(runtime.block_on(download_configured(get("/moved"), 100)).unwrap(), b"Bonjour");
+        assert!(runtime.block_on(download_configured(get("/loop"), 100)).unwrap_err().ends_with("redirected too many times"));
+    }
+}
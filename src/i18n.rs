@@ -0,0 +1,212 @@
+//! Translations of the interface. Catalogs are TOML files mapping English messages to their
+//! translation, as gettext does with msgids, `{}` standing for the arguments of the message in
+//! order. Messages are translated where they are built, with `tr` or `trf` for the ones with
+//! arguments. Help of commands is translated the same way, keyed by the English help so that a
+//! translation isn't shown anymore once the help it translates has changed, and timestamps are
+//! formatted as usual for the locale.
+//!
+//! Built-in catalogs are in locales/, others being read from the locales directory of the config,
+//! as ~/.config/aparté/locales/de.toml, which also override built-in ones.
+//!
+//! ```toml
+//! time = "%H:%M:%S"
+//!
+//! [messages]
+//! "Invalid JID {}: {}" = "JID {} invalide : {}"
+//!
+//! [[help]]
+//! source = "/help <command> …"
+//! translation = "/help <commande> …"
+//! ```
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::rc::Rc;
+
+use crate::profile;
+
+/// Catalogs built in, by language
+const BUILT_IN: &[(&str, &str)] = &[
+    ("fr", include_str!("../locales/fr.toml")),
+];
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Catalog {
+    /// Format of times, as in messages
+    time: Option<String>,
+    /// Format of times to the minute
+    short_time: Option<String>,
+    /// Format of dates with their time
+    date_time: Option<String>,
+    #[serde(default)]
+    messages: HashMap<String, String>,
+    /// Help of commands
+    #[serde(default)]
+    help: Vec<Help>,
+}
+
+/// Translation of the help of a command
+#[derive(Debug, Deserialize)]
+struct Help {
+    /// Help as written in the command
+    source: String,
+    translation: String,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Rc<Catalog>> = RefCell::new(Rc::new(Catalog::default()));
+}
+
+fn current() -> Rc<Catalog> {
+    CURRENT.with(|current| Rc::clone(&current.borrow()))
+}
+
+/// Locale of the environment, as set for messages
+pub fn env_locale() -> Option<String> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"].iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|locale| !locale.is_empty())
+}
+
+/// Languages a locale as fr_FR.UTF-8 is looked for, from the most specific
+fn languages(locale: &str) -> Vec<String> {
+    let locale = locale.split(|c| c == '.' || c == '@').next().unwrap_or("");
+    let mut languages = vec![locale.to_string()];
+    if let Some(language) = locale.split('_').next().filter(|language| *language != locale) {
+        languages.push(language.to_string());
+    }
+    languages.retain(|language| !language.is_empty() && language != "C" && language != "POSIX" && language != "en");
+    languages
+}
+
+/// Catalog of a locale, none if aparté isn't translated in its language
+pub fn catalog(locale: &str) -> Result<Option<Catalog>, String> {
+    for language in languages(locale) {
+        let path = profile::config_dir().join("locales").join(format!("{}.toml", language));
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => match BUILT_IN.iter().find(|(built_in, _)| *built_in == language) {
+                Some((_, content)) => content.to_string(),
+                None => continue,
+            },
+        };
+        return toml::from_str(&content).map(Some).map_err(|err| format!("Invalid translations {}: {}", language, err));
+    }
+    Ok(None)
+}
+
+/// Catalogs built in, by language
+#[cfg(test)]
+pub fn built_in() -> Vec<(&'static str, Catalog)> {
+    BUILT_IN.iter().map(|(language, content)| (*language, toml::from_str(content).unwrap())).collect()
+}
+
+/// Translate the interface for a locale, the one of the environment when none is configured
+pub fn load(locale: Option<&str>) -> Result<(), String> {
+    let locale = match locale.map(String::from).or_else(env_locale) {
+        Some(locale) => locale,
+        None => return Ok(()),
+    };
+    let catalog = catalog(&locale)?.unwrap_or_default();
+    CURRENT.with(|current| current.replace(Rc::new(catalog)));
+    Ok(())
+}
+
+/// Message with its `{}` placeholders replaced by arguments in order
+pub fn format(message: &str, arguments: &[&dyn fmt::Display]) -> String {
+    let mut parts = message.split("{}");
+    let mut formatted = parts.next().unwrap_or("").to_string();
+    for (index, part) in parts.enumerate() {
+        if let Some(argument) = arguments.get(index) {
+            formatted.push_str(&argument.to_string());
+        }
+        formatted.push_str(part);
+    }
+    formatted
+}
+
+impl Catalog {
+    fn translate(&self, message: &str) -> String {
+        self.messages.get(message).cloned().unwrap_or_else(|| message.to_string())
+    }
+
+    /// Translation of the help of a command, none when it isn't translated or its translation is
+    /// one of an older help
+    pub fn help(&self, help: &str) -> Option<&str> {
+        self.help.iter().find(|translated| translated.source == help).map(|translated| translated.translation.as_str())
+    }
+
+    /// Helps the catalog translates
+    #[cfg(test)]
+    pub fn translated_helps(&self) -> impl Iterator<Item = &str> {
+        self.help.iter().map(|translated| translated.source.as_str())
+    }
+}
+
+/// Message translated, the message itself when there is no translation
+pub fn tr(message: &str) -> String {
+    current().translate(message)
+}
+
+/// Message with arguments translated then formatted
+pub fn trf(message: &str, arguments: &[&dyn fmt::Display]) -> String {
+    format(&tr(message), arguments)
+}
+
+/// Help of a command translated
+pub fn help(help: &str) -> String {
+    current().help(help).unwrap_or(help).to_string()
+}
+
+/// Format of times, with seconds
+pub fn time_format() -> String {
+    current().time.clone().unwrap_or_else(|| String::from("%T"))
+}
+
+/// Format of times to the minute
+pub fn short_time_format() -> String {
+    current().short_time.clone().unwrap_or_else(|| String::from("%R"))
+}
+
+/// Format of dates with their time
+pub fn date_time_format() -> String {
+    current().date_time.clone().unwrap_or_else(|| String::from("%F %R"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(languages("fr_FR.UTF-8"), vec!["fr_FR", "fr"]);
+        assert_eq!(languages("de"), vec!["de"]);
+        assert!(languages("C").is_empty());
+        assert!(languages("en_US.UTF-8").contains(&String::from("en_US")));
+
+        let catalog: Catalog = toml::from_str(r#"
+            [messages]
+            "No connection found" = "Aucune connexion"
+            "Invalid JID {}: {}" = "JID {} invalide : {}"
+
+            [[help]]
+            source = "/help <command>"
+            translation = "/help <commande>"
+        "#).unwrap();
+        assert_eq!(catalog.translate("No connection found"), "Aucune connexion");
+        assert_eq!(format(&catalog.translate("Invalid JID {}: {}"), &[&"a@b@c", &"invalid domain"]), "JID a@b@c invalide : invalid domain");
+        assert_eq!(catalog.translate("Unknown setting {}"), "Unknown setting {}");
+        assert_eq!(format("{} of {}", &[&1, &"two"]), "1 of two");
+        assert_eq!(catalog.help("/help <command>"), Some("/help <commande>"));
+        // Translations of an older help aren't shown
+        assert_eq!(catalog.help("/help [<command>]"), None);
+
+        // Built-in catalogs are valid
+        for (language, content) in BUILT_IN {
+            assert!(toml::from_str::<Catalog>(content).is_ok(), "Invalid catalog {}", language);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::{BareJid, Element, Jid, ns};
+
+pub const NS_CONFERENCE: &str = "jabber:x:conference";
+
+/// Invitation to a room, either sent directly by the inviter (XEP-0249) or mediated by the room
+/// (XEP-0045)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invitation {
+    pub room: BareJid,
+    pub inviter: Option<BareJid>,
+    pub reason: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Invitation {
+    /// Parse an invitation from a payload of a message sent by a given JID
+    pub fn parse(from: &Jid, payload: &Element) -> Option<Invitation> {
+        let from = BareJid::from(from.clone());
+        let non_empty = |text: String| match text.trim() {
+            "" => None,
+            text => Some(text.to_string()),
+        };
+
+        if payload.is("x", NS_CONFERENCE) {
+            Some(Invitation {
+                room: BareJid::from_str(payload.attr("jid")?).ok()?,
+                inviter: Some(from),
+                reason: payload.attr("reason").map(String::from).and_then(non_empty),
+                password: payload.attr("password").map(String::from),
+            })
+        } else if payload.is("x", ns::MUC_USER) {
+            let invite = payload.get_child("invite", ns::MUC_USER)?;
+            Some(Invitation {
+                room: from,
+                inviter: invite.attr("from").and_then(|inviter| Jid::from_str(inviter).ok()).map(BareJid::from),
+                reason: invite.get_child("reason", ns::MUC_USER).map(Element::text).and_then(non_empty),
+                password: payload.get_child("password", ns::MUC_USER).map(Element::text),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Decline sent through the room, which forwards it to the inviter
+    pub fn decline(&self, reason: Option<&str>) -> Element {
+        let mut decline = Element::builder("decline").ns(ns::MUC_USER);
+        if let Some(inviter) = &self.inviter {
+            decline = decline.attr("to", inviter.to_string());
+        }
+        if let Some(reason) = reason {
+            decline = decline.append(Element::builder("reason").ns(ns::MUC_USER).append(reason).build());
+        }
+
+        Element::builder("message").ns("jabber:client")
+            .attr("to", self.room.to_string())
+            .attr("id", Uuid::new_v4().to_hyphenated().to_string())
+            .append(Element::builder("x").ns(ns::MUC_USER).append(decline.build()).build())
+            .build()
+    }
+}
+
+impl fmt::Display for Invitation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invitation to {}", self.room)?;
+        if let Some(inviter) = &self.inviter {
+            write!(f, " from {}", inviter)?;
+        }
+        if let Some(reason) = &self.reason {
+            write!(f, " ({})", reason)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_direct_invitation() {
+        let from = Jid::from_str("friend@server.tld/laptop").unwrap();
+        let payload: Element = r#"<x xmlns="jabber:x:conference" jid="room@conference.server.tld" reason="Come and see" password="secret"/>"#.parse().unwrap();
+        assert_eq!(Invitation::parse(&from, &payload), Some(Invitation {
+            room: BareJid::from_str("room@conference.server.tld").unwrap(),
+            inviter: Some(BareJid::from_str("friend@server.tld").unwrap()),
+            reason: Some(String::from("Come and see")),
+            password: Some(String::from("secret")),
+        }));
+    }
+
+    #[test]
+    fn test_mediated_invitation() {
+        let from = Jid::from_str("room@conference.server.tld").unwrap();
+        let payload: Element = r#"<x xmlns="http://jabber.org/protocol/muc#user"><invite from="friend@server.tld/laptop"><reason>Come and see</reason></invite></x>"#.parse().unwrap();
+        let invitation = Invitation::parse(&from, &payload).unwrap();
+        assert_eq!(invitation.to_string(), "Invitation to room@conference.server.tld from friend@server.tld (Come and see)");
+
+        let decline = invitation.decline(Some("Busy"));
+        assert_eq!(decline.attr("to"), Some("room@conference.server.tld"));
+        let decline = decline.get_child("x", ns::MUC_USER).unwrap().get_child("decline", ns::MUC_USER).unwrap();
+        assert_eq!(decline.attr("to"), Some("friend@server.tld"));
+        assert_eq!(decline.get_child("reason", ns::MUC_USER).unwrap().text(), "Busy");
+
+        // Status codes sent by the room aren't invitations
+        let payload: Element = r#"<x xmlns="http://jabber.org/protocol/muc#user"><status code="104"/></x>"#.parse().unwrap();
+        assert!(Invitation::parse(&from, &payload).is_none());
+    }
+}
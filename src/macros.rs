@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use termion::event::Key;
+
+use crate::config;
+
+/// What the recorder expects after a record or replay key
+enum Pending {
+    Record,
+    Replay,
+}
+
+/// Keyboard macros recorded into registers named by a character, vi-style
+pub struct Macros {
+    record: Key,
+    replay: Key,
+    pending: Option<Pending>,
+    /// Register being recorded with the keys typed so far
+    recording: Option<(char, Vec<Key>)>,
+    registers: HashMap<char, Vec<Key>>,
+    last: Option<char>,
+}
+
+/// Change of the state of the recorder worth telling the user about
+#[derive(Debug, PartialEq)]
+pub enum Notice {
+    Recording(char),
+    Recorded(char, usize),
+    Empty(char),
+}
+
+impl Macros {
+    pub fn new(config: &config::Macros) -> Self {
+        Self {
+            record: config.record.0.clone(),
+            replay: config.replay.0.clone(),
+            pending: None,
+            recording: None,
+            registers: HashMap::new(),
+            last: None,
+        }
+    }
+
+    /// Handle a typed key, returns the keys to process instead: none for the keys driving the
+    /// recorder, the keys of a macro when replaying it, or the key itself
+    pub fn feed(&mut self, key: Key) -> (Vec<Key>, Option<Notice>) {
+        let keys = match (self.pending.take(), key) {
+            (Some(Pending::Record), Key::Char(register)) if register.is_alphanumeric() => {
+                self.recording = Some((register, Vec::new()));
+                return (Vec::new(), Some(Notice::Recording(register)));
+            },
+            (Some(Pending::Replay), Key::Char(register)) if register.is_alphanumeric() => self.play(register),
+            (Some(Pending::Replay), key) if key == self.replay => match self.last {
+                Some(register) => self.play(register),
+                None => return (Vec::new(), None),
+            },
+            // Anything else cancels, as Esc does
+            (Some(_), _) => return (Vec::new(), None),
+            (None, key) if key == self.record => {
+                return match self.recording.take() {
+                    Some((register, keys)) => {
+                        let count = keys.len();
+                        self.registers.insert(register, keys);
+                        (Vec::new(), Some(Notice::Recorded(register, count)))
+                    },
+                    None => {
+                        self.pending = Some(Pending::Record);
+                        (Vec::new(), None)
+                    },
+                };
+            },
+            (None, key) if key == self.replay => {
+                self.pending = Some(Pending::Replay);
+                return (Vec::new(), None);
+            },
+            (None, key) => vec![key],
+        };
+
+        if let Some((_, recorded)) = &mut self.recording {
+            recorded.extend(keys.iter().cloned());
+        }
+        let notice = match keys.is_empty() {
+            true => self.last.map(Notice::Empty),
+            false => None,
+        };
+        (keys, notice)
+    }
+
+    fn play(&mut self, register: char) -> Vec<Key> {
+        self.last = Some(register);
+        self.registers.get(&register).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(macros: &mut Macros, keys: &[Key]) -> Vec<Key> {
+        keys.iter().flat_map(|key| macros.feed(key.clone()).0).collect()
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let mut macros = Macros::new(&config::Macros::default());
+
+        assert_eq!(macros.feed(Key::Alt('q')), (Vec::new(), None));
+        assert_eq!(macros.feed(Key::Char('a')), (Vec::new(), Some(Notice::Recording('a'))));
+        let typed = [Key::Char('/'), Key::Char('w'), Key::Char('\n'), Key::F(3)];
+        assert_eq!(feed(&mut macros, &typed), typed.to_vec());
+        assert_eq!(macros.feed(Key::Alt('q')), (Vec::new(), Some(Notice::Recorded('a', 4))));
+        assert!(macros.recording.is_none());
+
+        assert_eq!(feed(&mut macros, &[Key::Alt('@'), Key::Char('a')]), typed.to_vec());
+        assert_eq!(feed(&mut macros, &[Key::Alt('@'), Key::Alt('@')]), typed.to_vec());
+
+        // Replaying while recording records the keys of the macro
+        feed(&mut macros, &[Key::Alt('q'), Key::Char('b'), Key::Alt('@'), Key::Char('a'), Key::Char('x'), Key::Alt('q')]);
+        assert_eq!(macros.registers[&'b'].len(), 5);
+
+        assert_eq!(macros.feed(Key::Alt('@')), (Vec::new(), None));
+        assert_eq!(macros.feed(Key::Char('c')), (Vec::new(), Some(Notice::Empty('c'))));
+        assert_eq!(feed(&mut macros, &[Key::Alt('q'), Key::Esc, Key::Char('x')]), vec![Key::Char('x')]);
+    }
+}
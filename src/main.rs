@@ -1,4 +1,3 @@
-#![feature(drain_filter)]
 #![feature(trait_alias)]
 #![feature(specialization)]
 #[macro_use]
@@ -15,73 +14,224 @@ extern crate tokio_file_unix;
 extern crate dirs;
 extern crate signal_hook;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use futures::{future, Future, Sink, Stream};
 use log::LevelFilter;
 use signal_hook::iterator::Signals;
 use std::convert::TryFrom;
+use std::fs;
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use tokio::runtime::current_thread::Runtime;
-use tokio_xmpp::{Client, Error as XmppError};
 use uuid::Uuid;
-use xmpp_parsers::iq::Iq;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::ping::Ping;
 use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType as XmppParsersMessageType};
-use xmpp_parsers::muc::Muc;
 use xmpp_parsers::presence::{Presence, Show as PresenceShow, Type as PresenceType};
-use xmpp_parsers::{Element, Jid};
+use xmpp_parsers::stanza_error::StanzaError;
+use xmpp_parsers::{ns, Element, BareJid, FullJid, Jid};
 
 mod core;
 mod config;
 mod account;
 mod contact;
 mod conversation;
+mod invitation;
 mod message;
 mod command;
 mod terminus;
+mod macros;
+mod theme;
+mod store;
+mod settings;
+mod wizard;
 mod plugins;
+mod control;
+mod client;
+mod tls;
+mod scram;
+mod https;
+mod websocket;
+mod bosh;
+mod relay;
+mod socks5;
+mod spell;
+mod emoji;
+mod fuzzy;
+mod export;
+mod clipboard;
+mod pubsub;
+mod queue;
+mod i18n;
+mod profile;
+#[cfg(any(test, feature = "fakeserver"))]
+#[cfg_attr(not(test), allow(dead_code))]
+mod fakeserver;
+#[cfg(feature = "simulate")]
+mod simulate;
 
-use crate::core::{Aparte, Plugin, Event, CommandOrMessage};
-use crate::message::{Message};
-use crate::command::{CommandParser, Command};
+use crate::client::{Client, Error as ClientError, Event as ClientEvent};
+use crate::core::{Aparte, Plugin, Event, CommandOrMessage, stanza_error_text};
+use crate::message::{Message, XmppMessage};
+use crate::command::{CommandFuture, CommandParser, Command};
 
-fn handle_stanza(aparte: Rc<Aparte>, stanza: Element) {
+/// Plugins that can be disabled in the `[plugins]` section of the config
+const OPTIONAL_PLUGINS: [&str; 12] = ["carbons", "blocking", "moved", "bookmarks", "notes", "history", "notifications", "mentions", "health", "scripts", "away", "pep"];
+
+/// Languages preferred for the bodies of messages of a conversation
+fn conversation_lang(aparte: &Aparte, jid: &Jid) -> Option<String> {
+    let jid = match jid {
+        Jid::Bare(jid) => jid.clone(),
+        Jid::Full(jid) => jid.clone().into(),
+    };
+    let conversations = aparte.get_plugin::<plugins::conversation::ConversationPlugin>().unwrap();
+    conversations.lang(&jid).map(str::to_string)
+}
+
+/// Tag an outgoing message with the language of its conversation and send it, or queue it until
+/// the connection is back
+fn send_message(aparte: Rc<Aparte>, mut message: Message) {
+    let to = match &message {
+        Message::Outgoing(XmppMessage::Chat(chat)) => Jid::Bare((*chat.to).clone()),
+        Message::Outgoing(XmppMessage::Groupchat(groupchat)) => Jid::Bare((*groupchat.to).clone()),
+        _ => return,
+    };
+    if let Some(lang) = conversation_lang(&aparte, &to) {
+        message.set_lang(&lang);
+    }
+
+    let held = aparte.get_plugin_mut::<plugins::confirm::ConfirmPlugin>().unwrap().hold(message.clone()).is_none();
+    if held {
+        let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+        ui.log_to(Rc::clone(&aparte), &to.to_string(), plugins::confirm::preview(&message));
+        return;
+    }
+
+    if !aparte.is_online() {
+        // Shown as pending until the connection is back
+        let message = aparte.get_plugin_mut::<plugins::outbox::OutboxPlugin>().unwrap().push(message);
+        Rc::clone(&aparte).event(Event::Message(message));
+        return;
+    }
+
+    Rc::clone(&aparte).event(Event::Message(message.clone()));
+    if let Ok(xmpp_message) = Element::try_from(message) {
+        aparte.send(xmpp_message);
+    }
+}
+
+fn handle_stanza(aparte: Rc<Aparte>, account: &FullJid, stanza: Element) {
+    Rc::clone(&aparte).event(Event::ReceivedStanza(stanza.clone()));
     if let Some(message) = XmppParsersMessage::try_from(stanza.clone()).ok() {
         handle_message(aparte, message);
     } else if let Some(iq) = Iq::try_from(stanza.clone()).ok() {
-        Rc::clone(&aparte).event(Event::Iq(iq));
+        // Errors of awaited requests are reported by whoever sent them
+        let awaited = aparte.answer_iq(account, &iq);
+        if let (false, IqType::Error(error)) = (awaited, &iq.payload) {
+            let from = iq.from.as_ref().map(|from| from.to_string()).unwrap_or(format!("server"));
+            Rc::clone(&aparte).log(format!("Error from {} for request {}: {}", from, iq.id, stanza_error_text(error)));
+        }
+        Rc::clone(&aparte).event(Event::Iq(account.clone(), iq));
     } else if let Some(presence) = Presence::try_from(stanza.clone()).ok() {
-        Rc::clone(&aparte).event(Event::Presence(presence));
+        Rc::clone(&aparte).event(Event::Presence(account.clone(), presence));
     }
 }
 
 fn handle_message(aparte: Rc<Aparte>, message: XmppParsersMessage) {
-    if let (Some(from), Some(to)) = (message.from, message.to) {
-        if let Some(ref body) = message.bodies.get("") {
-            match message.type_ {
-                XmppParsersMessageType::Error => {},
-                XmppParsersMessageType::Chat => {
-                    let id = message.id.unwrap_or_else(|| Uuid::new_v4().to_string());
-                    let timestamp = Utc::now();
-                    let message = Message::incoming_chat(id, timestamp, &from, &to, &body.0);
-                    Rc::clone(&aparte).event(Event::Message(message));
-                },
-                XmppParsersMessageType::Groupchat => {
-                    let id = message.id.unwrap_or_else(|| Uuid::new_v4().to_string());
-                    let timestamp = Utc::now();
-                    let message = Message::incoming_groupchat(id, timestamp, &from, &to, &body.0);
-                    Rc::clone(&aparte).event(Event::Message(message));
-                },
-                _ => {},
+    if message.type_ == XmppParsersMessageType::Error {
+        if let Some(from) = &message.from {
+            let error = message.payloads.iter().find_map(|payload| StanzaError::try_from(payload.clone()).ok());
+            let text = match error {
+                Some(error) => stanza_error_text(&error),
+                None => format!("undefined-condition"),
+            };
+            Rc::clone(&aparte).event(Event::MessageError(from.clone().into(), message.id.clone(), text));
+        }
+        return;
+    }
+
+    if let (Some(from), Some(to)) = (message.from.clone(), message.to.clone()) {
+        let lang = conversation_lang(&aparte, &from);
+        if let Some((_, body)) = message.get_best_body(message::preferred_langs(lang.as_deref())) {
+            // Gateways relay messages of legacy networks without type, as private messages
+            let bridged = match aparte.get_plugin::<plugins::gateway::GatewayPlugin>() {
+                Some(gateways) => gateways.is_bridged(&from),
+                None => false,
+            };
+            let type_ = match message.type_ {
+                XmppParsersMessageType::Normal if bridged => XmppParsersMessageType::Chat,
+                ref type_ => type_.clone(),
+            };
+            let oob = message.payloads.iter()
+                .find(|payload| payload.is("x", plugins::preview::NS_OOB))
+                .and_then(|oob| oob.get_child("url", plugins::preview::NS_OOB))
+                .map(Element::text);
+            let id = message.id.clone().unwrap_or_else(|| Uuid::new_v4().to_string());
+            let timestamp = Utc::now();
+            let received = match type_ {
+                XmppParsersMessageType::Chat => Some(Message::incoming_chat(id, timestamp, &from, &to, &body.0)),
+                XmppParsersMessageType::Groupchat => Some(Message::incoming_groupchat(id, timestamp, &from, &to, &body.0)),
+                _ => None,
+            };
+            if let Some(mut received) = received {
+                if let Some(oob) = &oob {
+                    received.set_oob(oob.trim());
+                }
+                Rc::clone(&aparte).event(Event::Message(received));
+            }
+        }
+
+        // A subject without body is the subject of a channel, sent on join and when changed
+        if message.type_ == XmppParsersMessageType::Groupchat && message.bodies.is_empty() {
+            if let Some((_, subject)) = message.get_best_subject(message::preferred_langs(lang.as_deref())) {
+                let delay = message.payloads.iter().find_map(|payload| xmpp_parsers::delay::Delay::try_from(payload.clone()).ok());
+                let timestamp = delay.and_then(|delay| DateTime::parse_from_rfc3339(&delay.stamp.format("%+")).ok());
+                let nick = match &from {
+                    Jid::Full(from) => Some(from.resource.clone()),
+                    Jid::Bare(_) => None,
+                };
+                let subject = store::Subject {
+                    nick: nick,
+                    subject: subject.0.clone(),
+                    timestamp: timestamp.map(|timestamp| timestamp.with_timezone(&Utc)).unwrap_or_else(Utc::now),
+                };
+                Rc::clone(&aparte).event(Event::Subject(BareJid::from(from.clone()), subject));
             }
         }
 
         for payload in message.payloads {
-            if let Some(received) = xmpp_parsers::carbons::Received::try_from(payload).ok() {
+            if let Ok(moved) = plugins::moved::Moved::try_from(payload.clone()) {
+                let old = match &from {
+                    Jid::Bare(from) => from.clone(),
+                    Jid::Full(from) => from.clone().into(),
+                };
+                Rc::clone(&aparte).event(Event::Moved(old, moved.new, moved.reason));
+            } else if let Some(invitation) = invitation::Invitation::parse(&from, &payload) {
+                Rc::clone(&aparte).event(Event::Invitation(invitation));
+            } else if let Some(event) = plugins::calls::event(&from, &payload) {
+                Rc::clone(&aparte).event(event);
+            } else if let Some(event) = plugins::pep::event(&from, &payload) {
+                Rc::clone(&aparte).event(event);
+            } else if let Ok(result) = xmpp_parsers::mam::Result_::try_from(payload.clone()) {
+                if let (Some(queryid), Some(original)) = (result.queryid, result.forwarded.stanza) {
+                    if let (Some(from), Some((_, body))) = (original.from.as_ref(), original.get_best_body(message::preferred_langs(None))) {
+                        let timestamp = result.forwarded.delay.and_then(|delay| DateTime::parse_from_rfc3339(&delay.stamp.format("%+")).ok());
+                        let timestamp = timestamp.map(|timestamp| timestamp.with_timezone(&Utc)).unwrap_or_else(Utc::now);
+                        let message = match original.type_ {
+                            XmppParsersMessageType::Groupchat => Message::incoming_groupchat(result.id, timestamp, &from, &to, &body.0),
+                            _ => Message::incoming_chat(result.id, timestamp, &from, &to, &body.0),
+                        };
+                        Rc::clone(&aparte).event(Event::ArchivedMessage(queryid.0, message));
+                    }
+                }
+            } else if let Some(received) = xmpp_parsers::carbons::Received::try_from(payload).ok() {
                 if let Some(ref original) = received.forwarded.stanza {
                     if original.type_ != XmppParsersMessageType::Error {
                         if let (Some(from), Some(to)) = (original.from.as_ref(), original.to.as_ref()) {
-                            if let Some(body) = original.bodies.get("") {
+                            let lang = conversation_lang(&aparte, &from);
+                            if let Some((_, body)) = original.get_best_body(message::preferred_langs(lang.as_deref())) {
                                 let id = original.id.clone().unwrap_or_else(|| Uuid::new_v4().to_string());
                                 let timestamp = Utc::now();
                                 let message = Message::incoming_chat(id, timestamp, &from, &to, &body.0);
@@ -95,6 +245,87 @@ fn handle_message(aparte: Rc<Aparte>, message: XmppParsersMessage) {
     }
 }
 
+/// Run a client of an account until it's disconnected, handing the stanzas it receives to the
+/// plugins and sending the ones they send through it
+fn run_client(aparte: Rc<Aparte>, account: String, full_jid: FullJid, config: account::Account, client: Client) -> impl Future<Item = (), Error = ()> {
+    let bare_jid: BareJid = full_jid.clone().into();
+
+    let (sink, stream) = client.split();
+    let (tx, rx) = futures::unsync::mpsc::unbounded();
+
+    Rc::clone(&aparte).add_connection(full_jid.clone(), tx);
+    Rc::clone(&aparte).event(Event::Connecting(full_jid.clone()));
+
+    tokio::runtime::current_thread::spawn(
+        rx.forward(
+            sink.sink_map_err(|_| panic!("Pipe"))
+            ).map(|(rx, mut sink)| {
+            drop(rx);
+            let _ = sink.close();
+        }).map_err(|e| {
+                panic!("Send error: {:?}", e);
+            })
+        );
+
+    let error_jid = full_jid.clone();
+    let error_account = account.clone();
+    let event_aparte = Rc::clone(&aparte);
+    let certificate_jid = bare_jid.clone();
+    let error_config = config.clone();
+    let client = stream.for_each(move |event| {
+        if let ClientEvent::Online(details, features) = event {
+            event_aparte.connection_online(&full_jid, features);
+            event_aparte.connection_details(&full_jid, details.clone());
+            Rc::clone(&event_aparte).log(i18n::trf("Connected as {}", &[&account]));
+            let warning = event_aparte.get_plugin_mut::<plugins::certificates::CertificatesPlugin>().unwrap().connected(&bare_jid, &config, details.certificate, details.trust);
+            if let Some(warning) = warning {
+                Rc::clone(&event_aparte).log(warning);
+            }
+
+            Rc::clone(&event_aparte).event(Event::Connected(full_jid.clone()));
+
+            let mut presence = Presence::new(PresenceType::None);
+            presence.show = Some(PresenceShow::Chat);
+            presence.add_payload(event_aparte.get_plugin::<plugins::disco::Disco>().unwrap().caps());
+
+            event_aparte.send(presence.into());
+        } else if let ClientEvent::Disconnected = event {
+            event_aparte.connection_offline(&full_jid);
+            Rc::clone(&event_aparte).log(i18n::trf("Disconnected from {}", &[&account]));
+            Rc::clone(&event_aparte).event(Event::Disconnected(full_jid.clone()));
+        } else if let ClientEvent::Stanza(stanza) = event {
+            debug!("RECV: {}", event_aparte.loggable(&stanza));
+            event_aparte.connection_received(&full_jid);
+
+            handle_stanza(Rc::clone(&event_aparte), &full_jid, stanza);
+        }
+
+        future::ok(())
+    });
+
+    let error_aparte = Rc::clone(&aparte);
+    client.map_err(move |error| {
+        error_aparte.connection_offline(&error_jid);
+        Rc::clone(&error_aparte).event(Event::Disconnected(error_jid));
+        match error {
+            ClientError::Auth(reason) => {
+                Rc::clone(&error_aparte).log(format!("Authentication of {} failed: {}", error_account, reason));
+            },
+            ClientError::Certificate(certificate, reason) => {
+                let lines = {
+                    let mut certificates = error_aparte.get_plugin_mut::<plugins::certificates::CertificatesPlugin>().unwrap();
+                    certificates.refused(&certificate_jid, &error_config, certificate, reason);
+                    certificates.describe(&certificate_jid).unwrap_or_default()
+                };
+                Rc::clone(&error_aparte).log(lines.join("\n"));
+            },
+            ClientError::Xmpp(error) => {
+                Rc::clone(&error_aparte).log(format!("Connection of {} failed: {}", error_account, error));
+            },
+        }
+    })
+}
+
 command_def!{
     connect,
     r#"/connect <account>
@@ -113,73 +344,135 @@ Examples:
             aparte.config.accounts.iter().map(|(_, account)| account.login.clone()).collect()
         }
     },
-    (password) password,
-    |aparte, _command| {
+    (optional) password,
+    |aparte, command| {
+        let config = aparte.config.accounts.values().find(|stored| stored.login == account).cloned().unwrap_or_default();
+        let password = match password.or(config.password.clone()) {
+            Some(password) => password,
+            None => {
+                Rc::clone(&aparte).event(Event::ReadPassword(command.clone()));
+                return Ok(());
+            },
+        };
+
         if let Ok(jid) = Jid::from_str(&account) {
             let full_jid = match jid {
                 Jid::Full(jid) => jid,
                 Jid::Bare(jid) => jid.with_resource("aparte"),
             };
-            Rc::clone(&aparte).log(format!("Connecting to {}", account));
-            let client = Client::new(&full_jid.to_string(), &password).unwrap();
-
-            let (sink, stream) = client.split();
-            let (tx, rx) = futures::unsync::mpsc::unbounded();
-
-            Rc::clone(&aparte).add_connection(full_jid.clone(), tx);
+            {
+                let connect = Command::new(vec![String::from("connect"), account.clone(), password.clone()]);
+                aparte.get_plugin_mut::<plugins::reconnect::ReconnectPlugin>().unwrap().register(full_jid.clone(), connect);
+            }
+            Rc::clone(&aparte).log(i18n::trf("Connecting to {}", &[&account]));
+            let bare_jid: BareJid = full_jid.clone().into();
+            let policy = aparte.get_plugin::<plugins::certificates::CertificatesPlugin>().unwrap().policy(&bare_jid, &config);
+            let client = Client::new(&full_jid, password, config.clone(), policy);
+            tokio::runtime::current_thread::spawn(run_client(Rc::clone(&aparte), account, full_jid, config, client));
 
-            tokio::runtime::current_thread::spawn(
-                rx.forward(
-                    sink.sink_map_err(|_| panic!("Pipe"))
-                    ).map(|(rx, mut sink)| {
-                    drop(rx);
-                    let _ = sink.close();
-                }).map_err(|e| {
-                        panic!("Send error: {:?}", e);
-                    })
-                );
+            Ok(())
+        } else {
+            Err(format!("Invalid JID {}", account))
+        }
+    }
+}
 
-            let event_aparte = Rc::clone(&aparte);
-            let client = stream.for_each(move |event| {
-                if event.is_online() {
-                    Rc::clone(&event_aparte).log(format!("Connected as {}", account));
 
-                    Rc::clone(&event_aparte).event(Event::Connected(full_jid.clone()));
+command_def!{
+    register,
+    r#"/register <server>|set <field> [<value>]|submit|cancel
 
-                    let mut presence = Presence::new(PresenceType::None);
-                    presence.show = Some(PresenceShow::Chat);
+  server  Server to create an account on
+  field   Number or name of a field of the registration form
+  value   Value of the field, asked without echo for password fields
 
-                    event_aparte.send(presence.into());
-                } else if let Some(stanza) = event.into_stanza() {
-                    debug!("RECV: {}", String::from(&stanza));
+Description:
+  Create an account on a server. The registration form is displayed in the
+  register window, fill its fields with set and send it with submit, or drop
+  it with cancel. Images of the form, such as CAPTCHAs, are saved to temporary
+  files whose path is displayed under their field. Once created, the account is
+  added to the config.
 
-                    handle_stanza(Rc::clone(&event_aparte), stanza);
+Examples:
+  /register server.tld
+  /register set username me
+  /register set password
+  /register submit"#,
+    action: {
+        completion: |_aparte, _command| {
+            vec![String::from("set"), String::from("submit"), String::from("cancel")]
+        }
+    },
+    (optional) field,
+    (optional) value,
+    |aparte, command| {
+        match action.as_str() {
+            "set" => {
+                let field = field.ok_or(format!("Missing field"))?;
+                let value = match value {
+                    Some(_) => command.args[3..].join(" "),
+                    None => {
+                        let private = aparte.get_plugin::<plugins::register::RegisterPlugin>().unwrap().is_private(&field);
+                        match private {
+                            true => {
+                                Rc::clone(&aparte).event(Event::ReadPassword(command.clone()));
+                                return Ok(Box::new(future::ok(())) as CommandFuture);
+                            },
+                            false => return Err(i18n::tr("Missing value")),
+                        }
+                    },
+                };
+                let lines = aparte.get_plugin_mut::<plugins::register::RegisterPlugin>().unwrap().set(&field, &value)?;
+                let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+                ui.clear_window(plugins::register::WINDOW);
+                ui.log_to(Rc::clone(&aparte), plugins::register::WINDOW, lines.join("\n"));
+            },
+            "submit" => {
+                let (jid, request) = aparte.get_plugin_mut::<plugins::register::RegisterPlugin>().unwrap().submit()?;
+                Rc::clone(&aparte).log(format!("Registering {}", jid));
+                if let Some(request) = request {
+                    return Ok(Box::new(plugins::register::RegisterPlugin::send_request(Rc::clone(&aparte), request)));
                 }
+            },
+            "cancel" => {
+                aparte.get_plugin_mut::<plugins::register::RegisterPlugin>().unwrap().cancel()?;
+                Rc::clone(&aparte).log(format!("Registration cancelled"));
+            },
+            server => {
+                let server = BareJid::from_str(server).map_err(|err| format!("Invalid server {}: {}", server, err))?;
+                plugins::register::RegisterPlugin::start(Rc::clone(&aparte), server)?;
+            },
+        }
+        Ok(Box::new(future::ok(())))
+    }
+}
 
-                future::ok(())
-            });
+command_def!{
+    password,
+    r#"/password [<password>]
 
-            let error_aparte = Rc::clone(&aparte);
-            let client = client.map_err(move |error| {
-                match error {
-                    XmppError::Auth(auth) => {
-                        Rc::clone(&error_aparte).log(format!("Authentication failed {}", auth));
-                    },
-                    error => {
-                        Rc::clone(&error_aparte).log(format!("Connection error {:?}", error));
-                    },
-                }
-            });
+  password  New password of the current account, asked without echo by default
 
-            tokio::runtime::current_thread::spawn(client);
+Description:
+  Change the password of the current account on its server. Reconnections use
+  the new password, update it in the config if it is stored there.
 
-            Ok(())
-        } else {
-            Err(format!("Invalid JID {}", account))
-        }
+Examples:
+  /password"#,
+    (optional) password,
+    |aparte, command| {
+        let account = aparte.current_connection().ok_or_else(|| i18n::tr("No connection found"))?;
+        let password = match password {
+            Some(password) => password,
+            None => {
+                Rc::clone(&aparte).event(Event::ReadPassword(command.clone()));
+                return Ok(Box::new(future::ok(())) as CommandFuture);
+            },
+        };
+        let request = plugins::register::RegisterPlugin::change_password(&account, &password);
+        Ok(Box::new(plugins::register::RegisterPlugin::send_request(Rc::clone(&aparte), request)))
     }
 }
-
 command_def!{
     win,
     r#"Usage: /win <window>
@@ -187,7 +480,9 @@ command_def!{
   window        Name of the window to switch to
 
 Description:
-  Switch to a given window.
+  Switch to a given window. Ctrl-t opens a switcher listing the windows
+  and the contacts, filtered by the words typed as "ju ex" for
+  juliet@example.com.
 
 Examples:
   /win console
@@ -204,11 +499,136 @@ Examples:
     }
 }
 
+command_def!{
+    split,
+    r#"/split [<window>]
+
+  window        Window to display in the new pane
+
+Description:
+  Split the focused pane horizontally, the new pane displaying the given window
+  or the first window not already displayed. Use Ctrl-o to move the focus
+  between panes.
+
+Examples:
+  /split
+  /split contact@server.tld"#,
+    (optional) window: {
+        completion: |aparte, _command| {
+            let ui = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap();
+            ui.get_windows()
+        }
+    },
+    |aparte, _command| {
+        let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+        ui.split(terminus::Orientation::Vertical, window)
+    }
+}
+
+command_def!{
+    vsplit,
+    r#"/vsplit [<window>]
+
+  window        Window to display in the new pane
+
+Description:
+  Split the focused pane vertically, the new pane displaying the given window
+  or the first window not already displayed. Use Ctrl-o to move the focus
+  between panes.
+
+Examples:
+  /vsplit
+  /vsplit channel@conference.server.tld"#,
+    (optional) window: {
+        completion: |aparte, _command| {
+            let ui = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap();
+            ui.get_windows()
+        }
+    },
+    |aparte, _command| {
+        let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+        ui.split(terminus::Orientation::Horizontal, window)
+    }
+}
+
+command_def!{
+    only,
+    r#"/only
+
+Description:
+  Close all panes but the focused one.
+
+Example:
+  /only"#,
+    |aparte, _command| {
+        let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+        ui.only();
+        Ok(())
+    }
+}
+
+command_def!{
+    edit,
+    r#"/edit
+
+Description:
+  Compose a message in $VISUAL or $EDITOR, the text is put back in the
+  input once the editor exits. Alt-e does the same with the current
+  content of the input.
+
+Example:
+  /edit"#,
+    |aparte, _command| {
+        let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+        ui.edit()
+    }
+}
+
+command_def!{
+    mouse,
+    r#"/mouse [on|off]
+
+Description:
+  Report the mouse to Aparté: the wheel scrolls the current window, a click
+  focuses a pane, selects a window in the status bar or a contact in the
+  roster, and opens the URL clicked. The terminal can't select text while
+  the mouse is reported, most terminals still do with Shift held. Without
+  argument, toggle it. Set mouse in the config to report it at startup.
+
+Examples:
+  /mouse
+  /mouse off"#,
+    (optional) state: {
+        completion: |_aparte, _command| {
+            vec![String::from("on"), String::from("off")]
+        }
+    },
+    |aparte, _command| {
+        let enabled = {
+            let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+            let enabled = match state.as_deref() {
+                None => !ui.is_mouse_enabled(),
+                Some("on") => true,
+                Some("off") => false,
+                Some(state) => return Err(format!("Unknown mouse state {}, expected on or off", state)),
+            };
+            ui.set_mouse(enabled);
+            enabled
+        };
+        match enabled {
+            true => aparte.log(format!("Mouse reported, hold Shift to select text")),
+            false => aparte.log(format!("Mouse not reported")),
+        }
+        Ok(())
+    }
+}
+
 command_def!{
     msg,
     r#"/msg <contact> [<message>]
 
-  contact       Contact to send a message to
+  contact       Contact to send a message to, contacts of gateways being
+                either escaped or not, as user@gmail.com@gateway.tld
   message       Optionnal message to be sent
 
 Description:
@@ -222,14 +642,17 @@ Example:
     contact: {
         completion: |aparte, _command| {
             let contact = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
-            contact.contacts.iter().map(|c| c.0.to_string()).collect()
+            match aparte.current_connection() {
+                Some(account) => contact.contacts(&account).iter().map(|c| contact::display_jid(&c.jid)).collect(),
+                None => Vec::new(),
+            }
         }
     },
     (optional) message,
     |aparte, _command| {
         match aparte.current_connection() {
             Some(connection) => {
-                match Jid::from_str(&contact.clone()) {
+                match contact::parse_jid(&contact) {
                     Ok(jid) => {
                         let to = match jid.clone() {
                             Jid::Bare(jid) => jid,
@@ -241,24 +664,31 @@ Example:
                             let from: Jid = connection.into();
                             let timestamp = Utc::now();
                             let message = Message::outgoing_chat(id, timestamp, &from, &jid, &message.unwrap());
-                            Rc::clone(&aparte).event(Event::Message(message.clone()));
-
-                            aparte.send(Element::try_from(message).unwrap());
+                            send_message(Rc::clone(&aparte), message);
                         }
                         Ok(())
                     },
                     Err(err) => {
-                        Err(format!("Invalid JID {}: {}", contact, err))
+                        Err(i18n::trf("Invalid JID {}: {}", &[&contact, &err]))
                     }
                 }
             },
             None => {
-                Err(format!("No connection found"))
+                Err(i18n::tr("No connection found"))
             }
         }
     }
 }
 
+/// Our occupant JID in a channel, with the nick configured for it
+fn channel_occupant(aparte: &Aparte, connection: &FullJid, channel: BareJid) -> FullJid {
+    let bookmark = aparte.get_plugin::<plugins::bookmarks::BookmarksPlugin>()
+        .and_then(|bookmarks| bookmarks.conferences.iter().find(|conference| conference.jid == channel).and_then(|conference| conference.nick.clone()));
+    let account = BareJid::from(Jid::Full(connection.clone()));
+    let nick = aparte.settings.borrow().channel_nick(&aparte.config, &channel, bookmark, &account).unwrap_or_else(|| connection.node.clone().unwrap());
+    channel.with_resource(nick)
+}
+
 command_def!{
     join,
     r#"/join <channel>
@@ -277,151 +707,2846 @@ Example:
                     Ok(jid) => {
                         let to = match jid {
                             Jid::Full(jid) => jid,
-                            Jid::Bare(jid) => {
-                                let node = connection.node.clone().unwrap();
-                                jid.with_resource(node)
-                            }
+                            Jid::Bare(jid) => channel_occupant(&aparte, &connection, jid),
                         };
-                        let from: Jid = connection.into();
+                        let from: Jid = connection.clone().into();
 
-                        let mut presence = Presence::new(PresenceType::None);
-                        presence = presence.with_to(Jid::Full(to.clone()));
-                        presence = presence.with_from(from);
-                        presence.add_payload(Muc::new());
-                        aparte.send(presence.into());
-                        aparte.event(Event::Join(to.clone()));
+                        aparte.send_on(&connection, plugins::conversation::ConversationPlugin::join_presence(&aparte, from, to.clone(), None));
+                        aparte.event(Event::Join(connection, to.clone()));
 
                         Ok(())
                     },
                     Err(err) => {
-                        Err(format!("Invalid JID {}: {}", muc, err))
+                        Err(i18n::trf("Invalid JID {}: {}", &[&muc, &err]))
                     }
                 }
             },
             None => {
-                Err(format!("No connection found"))
+                Err(i18n::tr("No connection found"))
             }
         }
     }
 }
 
 command_def!{
-    quit,
-    r#"/quit
+    roster,
+    r#"/roster export|import <path>
+/roster groups|servers
+
+  path          File to write the roster to or read it from
 
 Description:
-  Quit Aparté.
+  Export the roster of the current account to a file, or import a previously
+  exported roster into the current account. Imported contacts are added to the
+  roster and asked for a presence subscription.
 
-Example:
-  /quit"#,
+  Group contacts of the roster sidebar by roster group, or by server. Contacts
+  bridged through IRC gateways are grouped by IRC network.
+
+Examples:
+  /roster export roster.xml
+  /roster import roster.xml
+  /roster servers"#,
+    action: {
+        completion: |_aparte, _command| {
+            vec!["export".to_string(), "import".to_string(), "groups".to_string(), "servers".to_string()]
+        }
+    },
+    (optional) path,
     |aparte, _command| {
-        aparte.event(Event::Quit);
+        if let Ok(grouping) = config::RosterGrouping::from_str(&action) {
+            let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+            ui.set_roster_grouping(grouping);
+            return Ok(());
+        }
 
-        Ok(())
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => return Err(i18n::tr("Missing path")),
+        };
+        match action.as_str() {
+            "export" => {
+                let account = match aparte.current_connection() {
+                    Some(account) => account,
+                    None => return Err(i18n::tr("No connection found")),
+                };
+                let result = {
+                    let contact = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
+                    contact.export(&account, &path)
+                };
+                let count = result?;
+                Rc::clone(&aparte).log(format!("Exported {} contacts to {}", count, path.display()));
+                Ok(())
+            },
+            "import" => {
+                let account = match aparte.current_connection() {
+                    Some(account) => account,
+                    None => return Err(i18n::tr("No connection found")),
+                };
+
+                let stanzas = {
+                    let contact = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
+                    contact.import(&account, &path)
+                }?;
+                let count = stanzas.len() / 2;
+                for stanza in stanzas {
+                    aparte.send_on(&account, stanza);
+                }
+                Rc::clone(&aparte).log(format!("Imported {} contacts from {}", count, path.display()));
+                Ok(())
+            },
+            action => Err(format!("Unknown roster action {}", action)),
+        }
     }
 }
 
 command_def!{
-    help,
-    r#"/help <command>
+    moved,
+    r#"/moved accept [<contact>]
+/moved announce <jid> [<reason>]
 
-  command       Name of command
+  contact       Old address of a contact that moved (default to the last one)
+  jid           Your new address
+  reason        Optional reason sent along the notification
 
 Description:
-  Print help of a given command.
+  Subscribe to the new address of a contact that announced its move, once
+  confirmed by the statement published by its old account, or announce to all
+  your contacts that you moved to a new address, publishing that statement.
+  Alt-m subscribes to the new address of the last contact that moved.
 
 Examples:
-  /help help
-  /help win"#,
-    cmd: {
-        completion: |aparte, _command| {
-            aparte.commands.iter().map(|c| c.0.to_string()).collect()
+  /moved accept
+  /moved accept contact@old-server.tld
+  /moved announce me@new-server.tld "Old server is shutting down""#,
+    action: {
+        completion: |_aparte, _command| {
+            vec!["accept".to_string(), "announce".to_string()]
         }
     },
-    |aparte, _command| {
-        let command = aparte.commands.get(&cmd);
-        match command {
-            Some(command) => Rc::clone(&aparte).log(command.help.to_string()),
-            None => Rc::clone(&aparte).log(format!("Unknown command {}", cmd)),
+    (optional) jid,
+    |aparte, command| {
+        if aparte.current_connection().is_none() {
+            return Err(i18n::tr("No connection found"));
         }
 
-        Ok(())
+        let jid = match jid {
+            Some(jid) => match BareJid::from_str(&jid) {
+                Ok(jid) => Some(jid),
+                Err(err) => return Err(i18n::trf("Invalid JID {}: {}", &[&jid, &err])),
+            },
+            None => None,
+        };
+
+        match action.as_str() {
+            "accept" => plugins::moved::MovedPlugin::resubscribe(aparte, jid),
+            "announce" => {
+                let new = match jid {
+                    Some(jid) => jid,
+                    None => return Err(format!("Missing jid argument")),
+                };
+                let reason = command.args.get(3).cloned();
+                let (statement, stanzas) = {
+                    let moved = aparte.get_plugin::<plugins::moved::MovedPlugin>().unwrap();
+                    moved.announce(&aparte, &new, reason)
+                };
+                aparte.send(statement.into());
+                let count = stanzas.len();
+                for stanza in stanzas {
+                    aparte.send(stanza);
+                }
+                Rc::clone(&aparte).log(format!("Announced move to {} to {} contacts", new, count));
+                Ok(())
+            },
+            action => Err(format!("Unknown moved action {}", action)),
+        }
     }
 }
 
-fn main() {
-    const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-    let data_dir = dirs::data_dir().unwrap();
-    let aparte_data = data_dir.join("aparté");
+command_def!{
+    block,
+    r#"/block <jid> [spam|abuse [<reason>]]
 
-    if let Err(e) = std::fs::create_dir_all(&aparte_data) {
-        panic!("Cannot create aparté data dir: {}", e);
-    }
+  jid           JID to block
+  spam|abuse    Optionally report the JID to your server operators
+  reason        Optional description of the report
 
-    let aparte_log = aparte_data.join("aparte.log");
-    if let Err(e) = simple_logging::log_to_file(aparte_log, LevelFilter::Debug) {
-        panic!("Cannot setup log to file: {}", e);
-    }
+Description:
+  Block all communications with a given JID, optionally reporting it as a
+  source of spam or abuse.
+
+Examples:
+  /block spammer@server.tld
+  /block spammer@server.tld spam "Join spam wave""#,
+    jid,
+    (optional) kind,
+    (optional) reason,
+    |aparte, _command| {
+        if aparte.current_connection().is_none() {
+            return Err(i18n::tr("No connection found"));
+        }
 
-    let conf_dir = dirs::config_dir().unwrap();
-    let aparte_conf = conf_dir.join("aparté");
+        let report = match kind {
+            Some(kind) => Some(plugins::blocking::Report::new(&kind, reason)?),
+            None => None,
+        };
 
-    if let Err(e) = std::fs::create_dir_all(&aparte_conf) {
-        panic!("Cannot create aparté data dir: {}", e);
+        let iq = {
+            let blocking = aparte.get_plugin::<plugins::blocking::BlockingPlugin>().unwrap();
+            blocking.block(&jid, report)
+        }?;
+        aparte.send(iq);
+        Ok(())
     }
+}
 
-    let config = aparte_conf.join("config.toml");
+command_def!{
+    unblock,
+    r#"/unblock <jid>
 
-    info!("Starting aparté");
+  jid           JID to unblock
 
-    let mut aparte = Aparte::new(config);
-    aparte.add_plugin(plugins::disco::Disco::new());
-    aparte.add_plugin(plugins::carbons::CarbonsPlugin::new());
-    aparte.add_plugin(plugins::contact::ContactPlugin::new());
-    aparte.add_plugin(plugins::conversation::ConversationPlugin::new());
-    aparte.add_plugin(plugins::ui::UIPlugin::new());
+Description:
+  Unblock a previously blocked JID.
 
-    aparte.add_command(help());
-    aparte.add_command(connect());
-    aparte.add_command(win());
-    aparte.add_command(msg());
-    aparte.add_command(join());
-    aparte.add_command(quit());
+Example:
+  /unblock contact@server.tld"#,
+    jid: {
+        completion: |aparte, _command| {
+            let blocking = aparte.get_plugin::<plugins::blocking::BlockingPlugin>().unwrap();
+            blocking.blocked.iter().cloned().collect()
+        }
+    },
+    |aparte, _command| {
+        if aparte.current_connection().is_none() {
+            return Err(i18n::tr("No connection found"));
+        }
 
-    aparte.init().unwrap();
+        let iq = {
+            let blocking = aparte.get_plugin::<plugins::blocking::BlockingPlugin>().unwrap();
+            blocking.unblock(&jid)
+        }?;
+        aparte.send(iq);
+        Ok(())
+    }
+}
 
-    let aparte = Rc::new(aparte);
+command_def!{
+    report,
+    r#"/report <jid> [spam|abuse] [<reason>]
 
-    Rc::clone(&aparte).log(r#"
-▌ ▌   ▜               ▐      ▞▀▖         ▐   ▞
-▌▖▌▞▀▖▐ ▞▀▖▞▀▖▛▚▀▖▞▀▖ ▜▀ ▞▀▖ ▙▄▌▛▀▖▝▀▖▙▀▖▜▀ ▞▀▖
-▙▚▌▛▀ ▐ ▌ ▖▌ ▌▌▐ ▌▛▀  ▐ ▖▌ ▌ ▌ ▌▙▄▘▞▀▌▌  ▐ ▖▛▀
-▘ ▘▝▀▘ ▘▝▀ ▝▀ ▘▝ ▘▝▀▘  ▀ ▝▀  ▘ ▘▌  ▝▀▘▘   ▀ ▝▀▘
-"#.to_string());
-    Rc::clone(&aparte).log(format!("Version: {}", VERSION));
+  jid           JID to report
+  spam|abuse    Kind of report (default to spam)
+  reason        Optional description of the report
 
-    let mut rt = Runtime::new().unwrap();
-    let command_stream = {
-        let ui = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap();
-        ui.command_stream(Rc::clone(&aparte))
-    };
+Description:
+  Report a JID as a source of spam or abuse to your server operators and
+  block it.
 
-    let sig_aparte = Rc::clone(&aparte); // TODO use ARC ?
-    let signals = Signals::new(&[signal_hook::SIGWINCH]).unwrap().into_async().unwrap().for_each(move |sig| {
-        Rc::clone(&sig_aparte).event(Event::Signal(sig));
-        Ok(())
+Examples:
+  /report spammer@server.tld
+  /report troll@server.tld abuse "Insults in every room""#,
+    jid,
+    (optional) kind,
+    (optional) reason,
+    |aparte, _command| {
+        if aparte.current_connection().is_none() {
+            return Err(i18n::tr("No connection found"));
+        }
+
+        let report = plugins::blocking::Report::new(&kind.unwrap_or("spam".to_string()), reason)?;
+        let iq = {
+            let blocking = aparte.get_plugin::<plugins::blocking::BlockingPlugin>().unwrap();
+            blocking.block(&jid, Some(report))
+        }?;
+        aparte.send(iq);
+        Rc::clone(&aparte).log(format!("Reported {}", jid));
+        Ok(())
+    }
+}
+
+command_def!{
+    note,
+    r#"/note <jid> [<text>]
+
+  jid   Contact to annotate
+  text  Private note about the contact, the note is removed when omitted
+
+Description:
+  Attach a private note to a contact (XEP-0145). Notes are stored on the
+  server so they are shared between your clients.
+
+Examples:
+  /note contact@server.tld Met at the conference
+  /note contact@server.tld"#,
+    jid: {
+        completion: |aparte, _command| {
+            let contact = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
+            match aparte.current_connection() {
+                Some(account) => contact.contacts(&account).iter().map(|contact| contact.jid.to_string()).collect(),
+                None => Vec::new(),
+            }
+        }
+    },
+    |aparte, command| {
+        if aparte.current_connection().is_none() {
+            return Err(i18n::tr("No connection found"));
+        }
+
+        let jid = BareJid::from_str(&jid).map_err(|err| i18n::trf("Invalid JID {}: {}", &[&jid, &err]))?;
+        let text = command.args[2..].join(" ");
+        let iq = {
+            let mut notes = aparte.get_plugin_mut::<plugins::notes::NotesPlugin>().unwrap();
+            notes.set(&aparte, jid.clone(), &text)
+        };
+
+        let log = Rc::clone(&aparte);
+        Ok(Box::new(Rc::clone(&aparte).send_iq(iq).map(move |_| match text.is_empty() {
+            true => log.log(format!("Note about {} removed", jid)),
+            false => log.log(format!("Note about {} saved", jid)),
+        }).map_err(|err| format!("Cannot store the notes: {}", err))))
+    }
+}
+
+command_def!{
+    whois,
+    r#"/whois <jid>
+
+  jid  Contact to describe
+
+Description:
+  Print what is known about a contact: name, subscription, groups, presence,
+  the tune, mood and activity they publish and your private note.
+
+Example:
+  /whois contact@server.tld"#,
+    jid: {
+        completion: |aparte, _command| {
+            let contact = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
+            match aparte.current_connection() {
+                Some(account) => contact.contacts(&account).iter().map(|contact| contact.jid.to_string()).collect(),
+                None => Vec::new(),
+            }
+        }
+    },
+    |aparte, _command| {
+        let jid = BareJid::from_str(&jid).map_err(|err| i18n::trf("Invalid JID {}: {}", &[&jid, &err]))?;
+        let mut lines = vec![format!("{}", jid)];
+        {
+            let contacts = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
+            let contact = aparte.current_connection().and_then(|account| contacts.contact(&account, &jid).cloned());
+            match contact {
+                Some(contact) => {
+                    if let Some(name) = &contact.name {
+                        lines.push(format!("  Name: {}", name));
+                    }
+                    lines.push(format!("  Subscription: {:?}", contact.subscription));
+                    if !contact.groups.is_empty() {
+                        let groups: Vec<&str> = contact.groups.iter().map(|group| group.0.as_str()).collect();
+                        lines.push(format!("  Groups: {}", groups.join(", ")));
+                    }
+                    lines.push(format!("  Presence: {:?}", contact.presence));
+                    if let Some(tune) = &contact.info.tune {
+                        lines.push(format!("  Tune: {}", tune));
+                    }
+                    if let Some(mood) = &contact.info.mood {
+                        lines.push(format!("  Mood: {}", mood));
+                    }
+                    if let Some(activity) = &contact.info.activity {
+                        lines.push(format!("  Activity: {}", activity));
+                    }
+                },
+                None => lines.push(format!("  Not in your roster")),
+            }
+        }
+        if let Some(notes) = aparte.get_plugin::<plugins::notes::NotesPlugin>() {
+            if let Some(note) = notes.get(&jid) {
+                lines.push(format!("  Note: {}", note));
+            }
+        }
+
+        for line in lines {
+            Rc::clone(&aparte).log(line);
+        }
+        Ok(())
+    }
+}
+
+command_def!{
+    tune,
+    r#"/tune [<artist> - <title>]
+
+  artist  Artist of the tune you are listening to
+  title   Title of the tune
+
+Description:
+  Tell your contacts what you are listening to (XEP-0118), or that you stopped
+  listening without argument. Set mpris = true in the [pep] section of the
+  config to publish what your media players play instead.
+
+Examples:
+  /tune Yes - Roundabout
+  /tune"#,
+    |aparte, command| {
+        if !aparte.is_online() {
+            return Err(i18n::tr("Not connected"));
+        }
+        let text = command.args[1..].join(" ");
+        let (artist, title) = match text.find(" - ") {
+            Some(index) => (Some(&text[..index]), Some(&text[index + 3..])),
+            None => (None, Some(text.as_str())),
+        };
+        plugins::pep::PepPlugin::publish(Rc::clone(&aparte), ns::TUNE, plugins::pep::tune(artist, title));
+        Ok(())
+    }
+}
+
+command_def!{
+    mood,
+    r#"/mood [<mood> [<text>]]
+
+  mood  How you feel, such as happy, tired or in_love
+  text  Why you feel so
+
+Description:
+  Tell your contacts how you feel (XEP-0107), or stop telling them without
+  argument.
+
+Examples:
+  /mood happy Sunny day
+  /mood"#,
+    (optional) mood: {
+        completion: |_aparte, _command| {
+            plugins::pep::MOODS.iter().map(|mood| mood.to_string()).collect()
+        }
+    },
+    |aparte, command| {
+        if !aparte.is_online() {
+            return Err(i18n::tr("Not connected"));
+        }
+        let text = command.args.get(2..).map(|text| text.join(" ")).unwrap_or_default();
+        let mood = plugins::pep::mood(mood.as_deref(), &text)?;
+        plugins::pep::PepPlugin::publish(Rc::clone(&aparte), ns::MOOD, mood);
+        Ok(())
+    }
+}
+
+command_def!{
+    activity,
+    r#"/activity [<general>[/<specific>] [<text>]]
+
+  general   What you are doing, such as eating, relaxing or working
+  specific  What exactly, such as having_lunch, reading or coding
+  text      More about it
+
+Description:
+  Tell your contacts what you are doing (XEP-0108), or stop telling them
+  without argument.
+
+Examples:
+  /activity working/coding On aparté
+  /activity relaxing
+  /activity"#,
+    (optional) activity: {
+        completion: |_aparte, _command| {
+            plugins::pep::ACTIVITIES.iter().map(|activity| activity.to_string()).collect()
+        }
+    },
+    |aparte, command| {
+        if !aparte.is_online() {
+            return Err(i18n::tr("Not connected"));
+        }
+        let text = command.args.get(2..).map(|text| text.join(" ")).unwrap_or_default();
+        let activity = plugins::pep::activity(activity.as_deref(), &text)?;
+        plugins::pep::PepPlugin::publish(Rc::clone(&aparte), plugins::pep::NS_ACTIVITY, activity);
+        Ok(())
+    }
+}
+
+command_def!{
+    ping,
+    r#"/ping [<jid>]
+
+  jid  Entity to ping, your server by default
+
+Description:
+  Check that an entity answers (XEP-0199) and print how long it took.
+
+Examples:
+  /ping
+  /ping contact@server.tld/phone"#,
+    (optional) jid,
+    |aparte, _command| {
+        let account = match aparte.current_connection() {
+            Some(account) => account,
+            None => return Err(i18n::tr("Not connected")),
+        };
+        let jid = match jid {
+            Some(jid) => Jid::from_str(&jid).map_err(|err| i18n::trf("Invalid JID {}: {}", &[&jid, &err]))?,
+            None => Jid::Bare(BareJid::domain(&account.domain)),
+        };
+
+        let sent = Instant::now();
+        let iq = Iq::from_get(Uuid::new_v4().to_hyphenated().to_string(), Ping).with_to(jid.clone());
+        let log = Rc::clone(&aparte);
+        let error_jid = jid.clone();
+        Ok(Box::new(Rc::clone(&aparte).send_iq(iq).map(move |_| {
+            let elapsed = sent.elapsed();
+            log.log(format!("Pong from {} in {}ms", jid, elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())));
+        }).map_err(move |err| format!("Cannot ping {}: {}", error_jid, err))))
+    }
+}
+
+command_def!{
+    presence,
+    r#"/presence available|away|chat|dnd|xa [<status>]
+
+  status  Message shown to your contacts along your availability
+
+Description:
+  Change your availability on the current account. While available, your
+  presence is switched to away after the idle time set in the [away] section
+  of the config, and back on keyboard input.
+
+Examples:
+  /presence away
+  /presence dnd "In a meeting""#,
+    show: {
+        completion: |_aparte, _command| {
+            vec![String::from("available"), String::from("away"), String::from("chat"), String::from("dnd"), String::from("xa")]
+        }
+    },
+    (optional) status,
+    |aparte, _command| {
+        if aparte.current_connection().is_none() {
+            return Err(i18n::tr("Not connected"));
+        }
+
+        let availability = match show.as_str() {
+            "available" => None,
+            "away" => Some(PresenceShow::Away),
+            "chat" => Some(PresenceShow::Chat),
+            "dnd" => Some(PresenceShow::Dnd),
+            "xa" => Some(PresenceShow::Xa),
+            _ => return Err(format!("Unknown availability {}, expected available, away, chat, dnd or xa", show)),
+        };
+        aparte.send(plugins::away::presence(&aparte, availability.clone(), status.as_deref()));
+        if let Some(mut away) = aparte.get_plugin_mut::<plugins::away::AwayPlugin>() {
+            away.set_presence(availability, status.clone());
+        }
+
+        match status {
+            Some(status) => Rc::clone(&aparte).log(format!("Presence set to {} ({})", show, status)),
+            None => Rc::clone(&aparte).log(format!("Presence set to {}", show)),
+        }
+        Ok(())
+    }
+}
+
+command_def!{
+    theme,
+    r#"/theme <name>
+
+  name          Name of the theme
+
+Description:
+  Switch to another theme. Built-in themes are default, light and solarized,
+  other themes can be defined in the [themes] section of the config file.
+
+Examples:
+  /theme light
+  /theme solarized"#,
+    name: {
+        completion: |aparte, _command| {
+            let mut themes: Vec<String> = theme::BUILTIN_THEMES.iter().map(|name| name.to_string()).collect();
+            themes.extend(aparte.config.themes.keys().cloned());
+            themes
+        }
+    },
+    |aparte, _command| {
+        match theme::Theme::find(&aparte.config.themes, &name) {
+            Some(theme) => {
+                let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+                ui.set_theme(theme);
+                Ok(())
+            },
+            None => Err(i18n::trf("Unknown theme {}", &[&name])),
+        }
+    }
+}
+
+/// Most emoji printed by /emoji
+const MAX_EMOJI_RESULTS: usize = 50;
+
+command_def!{
+    emoji,
+    r#"/emoji <search>...
+
+  search  Words of the shortcodes to find
+
+Description:
+  Find emoji by their shortcodes, as used in the input: :shortcode: is replaced
+  by its emoji once typed, and Tab after :short completes it, each emoji
+  matching in turn.
+
+Examples:
+  /emoji cat
+  /emoji heart eyes"#,
+    search,
+    |aparte, command| {
+        let mut words = vec![search];
+        words.extend(command.args[2..].iter().cloned());
+        let found = emoji::search(&words);
+        if found.is_empty() {
+            return Err(format!("No emoji matching {}", words.join(" ")));
+        }
+        for (name, emoji) in found.iter().take(MAX_EMOJI_RESULTS) {
+            Rc::clone(&aparte).log(format!("{} :{}:", emoji, name));
+        }
+        if found.len() > MAX_EMOJI_RESULTS {
+            aparte.log(format!("{} more, search more words to narrow it down", found.len() - MAX_EMOJI_RESULTS));
+        }
+        Ok(())
+    }
+}
+
+/// Conversation given to a command, or the one of the current window
+fn conversation_or_current(aparte: &Aparte, conversation: Option<String>) -> Result<String, String> {
+    match conversation {
+        Some(conversation) => Ok(conversation),
+        None => {
+            let ui = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap();
+            match ui.current_window() {
+                Some(window) if window != "console" => Ok(window.to_string()),
+                _ => Err(i18n::tr("No conversation given")),
+            }
+        },
+    }
+}
+
+fn set_notification_level(aparte: Rc<Aparte>, conversation: Option<String>, level: Option<config::NotificationLevel>) -> Result<(), String> {
+    let conversation = conversation_or_current(&aparte, conversation)?;
+    let jid = BareJid::from_str(&conversation).map_err(|_| format!("Invalid JID {}", conversation))?;
+    let channel = match aparte.get_plugin::<plugins::conversation::ConversationPlugin>().unwrap().get(&jid) {
+        Some(conversation::Conversation::Channel(_)) => true,
+        _ => false,
+    };
+    let setting = aparte.settings.borrow().get(&jid, "notifications");
+
+    let level = {
+        let mut notifications = aparte.get_plugin_mut::<plugins::notifications::NotificationsPlugin>().unwrap();
+        notifications.set_level(&conversation, level);
+        notifications.level(&conversation, channel, setting)
+    };
+    aparte.log(format!("Notifications for {}: {}", conversation, level));
+    Ok(())
+}
+
+command_def!{
+    mute,
+    r#"/mute [<conversation>]
+
+  conversation  Contact or channel to mute, the current one by default
+
+Description:
+  Stop notifying messages of a conversation until /unmute.
+
+Examples:
+  /mute
+  /mute channel@conference.server.tld"#,
+    (optional) conversation: {
+        completion: |aparte, _command| {
+            let ui = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap();
+            ui.get_windows()
+        }
+    },
+    |aparte, _command| {
+        set_notification_level(aparte, conversation, Some(config::NotificationLevel::None))
+    }
+}
+
+command_def!{
+    unmute,
+    r#"/unmute [<conversation>]
+
+  conversation  Contact or channel to unmute, the current one by default
+
+Description:
+  Notify messages of a conversation again, as configured in the
+  [notifications] section of the config file.
+
+Examples:
+  /unmute
+  /unmute channel@conference.server.tld"#,
+    (optional) conversation: {
+        completion: |aparte, _command| {
+            let ui = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap();
+            ui.get_windows()
+        }
+    },
+    |aparte, _command| {
+        set_notification_level(aparte, conversation, None)
+    }
+}
+
+command_def!{
+    notify,
+    r#"/notify all|mentions|none [<conversation>]
+
+  conversation  Contact or channel, the current one by default
+
+Description:
+  Choose which messages of a conversation are notified: all of them, only
+  those mentioning your nick or one of the configured keywords, or none.
+
+Examples:
+  /notify mentions
+  /notify all channel@conference.server.tld"#,
+    level: {
+        completion: |_aparte, _command| {
+            vec!["all".to_string(), "mentions".to_string(), "none".to_string()]
+        }
+    },
+    (optional) conversation: {
+        completion: |aparte, _command| {
+            let ui = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap();
+            ui.get_windows()
+        }
+    },
+    |aparte, _command| {
+        let level = config::NotificationLevel::from_str(&level)?;
+        set_notification_level(aparte, conversation, Some(level))
+    }
+}
+
+command_def!{
+    retention,
+    r#"/retention [<duration>|off [<conversation>]]
+
+  duration      History to keep, as 30m, 24h, 7d or 2w
+  conversation  Contact or channel, the current one by default
+
+Description:
+  Keep only the recent local history of a conversation, whatever the server
+  archives. Older messages are removed from the history, and messages of the
+  conversation are left out of the log file. Without argument, show the
+  retention of the current conversation. The change lasts for this session,
+  set it in the [retention] section of the config to make it permanent.
+
+Examples:
+  /retention 24h
+  /retention 7d secret@server.tld
+  /retention off"#,
+    (optional) duration: {
+        completion: |_aparte, _command| {
+            vec!["off".to_string(), "1h".to_string(), "24h".to_string(), "7d".to_string()]
+        }
+    },
+    (optional) conversation: {
+        completion: |aparte, _command| {
+            let ui = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap();
+            ui.get_windows()
+        }
+    },
+    |aparte, _command| {
+        let conversation = conversation_or_current(&aparte, conversation)?;
+        let jid = BareJid::from_str(&conversation).map_err(|_| format!("Invalid JID {}", conversation))?;
+        let retention = match duration.as_deref() {
+            None => {
+                let retention = aparte.get_plugin::<plugins::retention::RetentionPlugin>().unwrap().retention(&jid);
+                match retention {
+                    Some(retention) => aparte.log(format!("History of {} kept for {}", jid, retention)),
+                    None => aparte.log(format!("History of {} kept forever", jid)),
+                }
+                return Ok(());
+            },
+            Some("off") => None,
+            Some(duration) => Some(config::Retention::from_str(duration)?),
+        };
+
+        let expired = aparte.get_plugin_mut::<plugins::retention::RetentionPlugin>().unwrap().set_retention(&aparte, &jid, retention)?;
+        match retention {
+            Some(retention) => aparte.log(format!("History of {} kept for {}, {} older messages removed", jid, retention, expired)),
+            None => aparte.log(format!("History of {} kept forever", jid)),
+        }
+        Ok(())
+    }
+}
+
+/// Conversation of the current window, for settings applying to it only
+fn current_conversation(aparte: &Aparte) -> Result<BareJid, String> {
+    let conversation = conversation_or_current(aparte, None)?;
+    BareJid::from_str(&conversation).map_err(|_| format!("Invalid JID {}", conversation))
+}
+
+/// Change a setting, or unset it without `value`, for the current conversation if `local`
+fn change_setting(aparte: Rc<Aparte>, local: bool, key: &str, value: Option<&str>) -> Result<(), String> {
+    let jid = match local {
+        true => Some(current_conversation(&aparte)?),
+        false => None,
+    };
+    // Hunspell has to know a dictionary before it is saved
+    if let Some(dictionary) = value.filter(|value| key == "spelling" && *value != "off") {
+        settings::canonical(key, dictionary)?;
+        aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap().check_dictionary(dictionary)?;
+    }
+    aparte.settings.borrow_mut().set(jid.as_ref(), key, value)?;
+    if key == "color" {
+        let color = value.map(theme::Color::from_str).transpose()?;
+        theme::set_color(jid.as_ref().map(|jid| jid.to_string()).as_deref(), color);
+    }
+    if key == "spelling" {
+        // The configured dictionary is used again once unset for every conversation
+        let dictionary = match (&jid, value) {
+            (None, None) => aparte.config.spelling.as_deref(),
+            (_, value) => value,
+        };
+        let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+        ui.set_dictionary(jid.as_ref().map(|jid| jid.to_string()).as_deref(), dictionary)?;
+    }
+    if key == "logging" {
+        let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+        ui.update_buffer_limits(&aparte);
+    }
+
+    let scope = match &jid {
+        Some(jid) => jid.to_string(),
+        None => String::from("every conversation"),
+    };
+    match value {
+        Some(value) => aparte.log(format!("{} set to {} for {}", key, value, scope)),
+        None => aparte.log(format!("{} unset for {}", key, scope)),
+    }
+    Ok(())
+}
+
+/// Arguments of /set and /unset, with their -local flag
+fn setting_args(command: &Command) -> (bool, Vec<String>) {
+    match command.args.get(1).map(String::as_str) {
+        Some("-local") => (true, command.args[2..].to_vec()),
+        _ => (false, command.args[1..].to_vec()),
+    }
+}
+
+command_def!{
+    set,
+    r#"/set [-local] <key> [<value>]
+
+  key    color, logging, nick, notifications or spelling
+  value  Value of the setting, shown when left out
+
+Description:
+  Change a setting for every conversation, or with -local for the current
+  conversation only. Settings are kept across sessions, and take precedence
+  over the config file. Settings for a conversation take precedence over the
+  ones for every conversation.
+
+  color          Color of the nicks, as a name, colorN or #rrggbb
+  logging        on or off, off keeping messages out of the history and of the
+                 log file
+  nick           Nick to join channels with
+  notifications  all, mentions or none
+  spelling       Hunspell dictionary the input is checked with, as en_US, or
+                 off. Misspelled words are underlined, Alt-s replaces the one
+                 under the cursor by its suggestions in turn
+
+Examples:
+  /set -local notifications mentions
+  /set -local color #268bd2
+  /set logging off"#,
+    |aparte, command| {
+        let (local, args) = setting_args(&command);
+        let key = args.get(0).ok_or(format!("Missing key argument"))?;
+        if args.len() > 1 {
+            return change_setting(aparte, local, key, Some(&args[1..].join(" ")));
+        }
+
+        let jid = match local {
+            true => Some(current_conversation(&aparte)?),
+            false => None,
+        };
+        if !settings::KEYS.contains(&key.as_str()) {
+            return Err(i18n::trf("Unknown setting {}, expected one of {}", &[&key, &settings::KEYS.join(", ")]));
+        }
+        let value = match &jid {
+            Some(jid) => aparte.settings.borrow().get::<String>(jid, key),
+            None => aparte.settings.borrow().get_raw(None, key).map(String::from),
+        };
+        match value {
+            Some(value) => aparte.log(format!("{}: {}", key, value)),
+            None => aparte.log(format!("{} isn't set", key)),
+        }
+        Ok(())
+    }
+}
+
+command_def!{
+    unset,
+    r#"/unset [-local] <key>
+
+  key  color, logging, nick, notifications or spelling
+
+Description:
+  Unset a setting changed with /set for every conversation, or with -local for
+  the current conversation only.
+
+Examples:
+  /unset -local color
+  /unset logging"#,
+    |aparte, command| {
+        let (local, args) = setting_args(&command);
+        let key = args.get(0).ok_or(format!("Missing key argument"))?;
+        change_setting(aparte, local, key, None)
+    }
+}
+
+command_def!{
+    scripts,
+    r#"/scripts [reload]
+
+Description:
+  List the Rhai scripts loaded from the scripts directory next to the config
+  file, and the commands they add. Reload them after editing them.
+
+Examples:
+  /scripts
+  /scripts reload"#,
+    (optional) action: {
+        completion: |_aparte, _command| {
+            vec!["reload".to_string()]
+        }
+    },
+    |aparte, _command| {
+        let (errors, names, commands) = {
+            let mut scripts = match aparte.get_plugin_mut::<plugins::scripts::ScriptsPlugin>() {
+                Some(scripts) => scripts,
+                None => return Err(i18n::tr("Scripts are disabled")),
+            };
+            let errors = match action.as_deref() {
+                None => Vec::new(),
+                Some("reload") => scripts.reload(),
+                Some(action) => return Err(i18n::trf("Unknown action {}", &[&action])),
+            };
+            (errors, scripts.scripts(), scripts.commands())
+        };
+        for err in errors {
+            Rc::clone(&aparte).log(err);
+        }
+        match names.is_empty() {
+            true => Rc::clone(&aparte).log(format!("No script loaded")),
+            false => Rc::clone(&aparte).log(format!("Scripts: {}", names.join(", "))),
+        }
+        if !commands.is_empty() {
+            let commands: Vec<String> = commands.iter().map(|command| format!("/{}", command)).collect();
+            aparte.log(format!("Script commands: {}", commands.join(", ")));
+        }
+        Ok(())
+    }
+}
+
+command_def!{
+    confirm,
+    r#"/confirm [on|off] [<conversation>]
+
+  conversation  Contact or channel, the current one by default
+
+Description:
+  Show messages sent to a conversation as they will be sent and wait for y
+  to be pressed before sending them, any other key giving them back to edit.
+  A safety net for announcement channels with many members. Without argument,
+  tell whether the current conversation asks for confirmation. The change
+  lasts for this session, add the conversation to the confirm list of the
+  config to make it permanent.
+
+Examples:
+  /confirm on
+  /confirm off announces@conference.server.tld"#,
+    (optional) mode: {
+        completion: |_aparte, _command| {
+            vec!["on".to_string(), "off".to_string()]
+        }
+    },
+    (optional) conversation: {
+        completion: |aparte, _command| {
+            let ui = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap();
+            ui.get_windows()
+        }
+    },
+    |aparte, _command| {
+        let conversation = conversation_or_current(&aparte, conversation)?;
+        let jid = BareJid::from_str(&conversation).map_err(|_| format!("Invalid JID {}", conversation))?;
+        let enabled = match mode.as_deref() {
+            None => aparte.get_plugin::<plugins::confirm::ConfirmPlugin>().unwrap().is_enabled(&jid),
+            Some(mode) => {
+                let enabled = match mode {
+                    "on" => true,
+                    "off" => false,
+                    mode => return Err(format!("Unknown mode {}, expected on or off", mode)),
+                };
+                aparte.get_plugin_mut::<plugins::confirm::ConfirmPlugin>().unwrap().set_enabled(&jid, enabled);
+                enabled
+            },
+        };
+        match enabled {
+            true => aparte.log(format!("Messages to {} are confirmed before being sent", jid)),
+            false => aparte.log(format!("Messages to {} are sent right away", jid)),
+        }
+        Ok(())
+    }
+}
+
+command_def!{
+    trigger,
+    r#"/trigger [list|add <jid> online|offline notify|command|send [<argument>]|remove <index>]
+
+  jid       Contact to watch
+  argument  Command to run for command, message to send for send
+  index     Trigger to remove, as numbered by /trigger list
+
+Description:
+  Act when a contact comes online, with its first resource, or goes offline,
+  with its last one. notify logs the change and notifies it, command runs a
+  command as typed in the input, send sends a message to the contact once.
+  Triggers added here last for this session, add them to the [[triggers]]
+  of the config to make them permanent.
+
+Examples:
+  /trigger add alice@server.tld online notify
+  /trigger add alice@server.tld online send "Call me back"
+  /trigger add bob@server.tld offline command "/notify mentions bob@server.tld"
+  /trigger remove 0"#,
+    (optional) action: {
+        completion: |_aparte, _command| {
+            vec![String::from("list"), String::from("add"), String::from("remove")]
+        }
+    },
+    (optional) target: {
+        completion: |aparte, _command| {
+            let contact = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
+            match aparte.current_connection() {
+                Some(account) => contact.contacts(&account).iter().map(|contact| contact.jid.to_string()).collect(),
+                None => Vec::new(),
+            }
+        }
+    },
+    (optional) on: {
+        completion: |_aparte, _command| {
+            vec![String::from("online"), String::from("offline")]
+        }
+    },
+    (optional) run: {
+        completion: |_aparte, _command| {
+            vec![String::from("notify"), String::from("command"), String::from("send")]
+        }
+    },
+    (optional) argument,
+    |aparte, _command| {
+        match action.as_deref().unwrap_or("list") {
+            "list" => {
+                let mut lines = aparte.get_plugin::<plugins::triggers::TriggersPlugin>().unwrap().list();
+                lines.insert(0, match lines.len() {
+                    0 => format!("No trigger, add one with /trigger add"),
+                    _ => format!("Triggers:"),
+                });
+                aparte.log(lines.join("\n"));
+            },
+            "add" => {
+                let jid = target.ok_or(format!("Missing contact"))?;
+                let jid = BareJid::from_str(&jid).map_err(|err| i18n::trf("Invalid JID {}: {}", &[&jid, &err]))?;
+                let on = config::PresenceChange::from_str(on.as_deref().unwrap_or("online"))?;
+                let action = match (run.as_deref(), argument) {
+                    (Some("notify"), _) | (None, _) => plugins::triggers::Action::Notify,
+                    (Some("command"), Some(command)) => plugins::triggers::Action::Command(command),
+                    (Some("send"), Some(message)) => plugins::triggers::Action::Send(message),
+                    (Some(run @ "command"), None) | (Some(run @ "send"), None) => return Err(format!("Missing argument of {}", run)),
+                    (Some(run), _) => return Err(format!("Unknown trigger action {}", run)),
+                };
+                let rule = plugins::triggers::Rule { jid: jid, on: on, action: action };
+                let log = format!("Trigger added {}", rule);
+                aparte.get_plugin_mut::<plugins::triggers::TriggersPlugin>().unwrap().add(rule);
+                aparte.log(log);
+            },
+            "remove" => {
+                let index = target.ok_or(format!("Missing trigger index"))?;
+                let index = usize::from_str(&index).map_err(|_| format!("Invalid trigger index {}", index))?;
+                let rule = aparte.get_plugin_mut::<plugins::triggers::TriggersPlugin>().unwrap().remove(index)?;
+                aparte.log(format!("Trigger removed {}", rule));
+            },
+            action => return Err(i18n::trf("Unknown action {}", &[&action])),
+        }
+        Ok(())
+    }
+}
+
+command_def!{
+    lang,
+    r#"/lang [<language>|off]
+
+  language  Language tag, as en or pt-BR
+
+Description:
+  Set the language of the messages sent in the current conversation, and
+  pick the body in this language from messages written in several ones,
+  as sent by translation bots. Without argument, show the current one.
+
+Examples:
+  /lang fr
+  /lang off"#,
+    (optional) language,
+    |aparte, _command| {
+        let conversation = conversation_or_current(&aparte, None)?;
+        let jid = BareJid::from_str(&conversation).map_err(|err| i18n::trf("Invalid JID {}: {}", &[&conversation, &err]))?;
+        match language.as_deref() {
+            None => {
+                let lang = conversation_lang(&aparte, &Jid::Bare(jid));
+                aparte.log(format!("Language of {}: {}", conversation, lang.as_deref().unwrap_or("unspecified")));
+            },
+            Some("off") => {
+                aparte.get_plugin_mut::<plugins::conversation::ConversationPlugin>().unwrap().set_lang(jid, None);
+                aparte.log(format!("Language of {}: unspecified", conversation));
+            },
+            Some(lang) if message::is_lang_tag(lang) => {
+                aparte.get_plugin_mut::<plugins::conversation::ConversationPlugin>().unwrap().set_lang(jid, Some(lang.to_string()));
+                aparte.log(format!("Language of {}: {}", conversation, lang));
+            },
+            Some(lang) => return Err(format!("Invalid language {}", lang)),
+        }
+        Ok(())
+    }
+}
+
+command_def!{
+    resend,
+    r#"/resend
+
+Description:
+  Send now the messages written while disconnected, instead of waiting for
+  the connection to be back.
+
+Example:
+  /resend"#,
+    |aparte, _command| {
+        if !aparte.is_online() {
+            return Err(i18n::tr("Not connected"));
+        }
+
+        let count = aparte.get_plugin::<plugins::outbox::OutboxPlugin>().unwrap().len();
+        if count == 0 {
+            return Err(i18n::tr("No pending message"));
+        }
+
+        Rc::clone(&aparte).log(format!("Sending {} pending messages", count));
+        plugins::outbox::OutboxPlugin::flush(aparte);
+        Ok(())
+    }
+}
+
+command_def!{
+    cancel,
+    r#"/cancel [<conversation>|all]
+
+  conversation  Contact or channel, the current one by default
+
+Description:
+  Drop the messages written while disconnected to a conversation, or to
+  every conversation, instead of sending them once connected again.
+
+Examples:
+  /cancel
+  /cancel all"#,
+    (optional) conversation: {
+        completion: |aparte, _command| {
+            let ui = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap();
+            let mut windows = ui.get_windows();
+            windows.push("all".to_string());
+            windows
+        }
+    },
+    |aparte, _command| {
+        let cancelled = match conversation.as_deref() {
+            Some("all") => aparte.get_plugin_mut::<plugins::outbox::OutboxPlugin>().unwrap().cancel(None),
+            _ => {
+                let conversation = conversation_or_current(&aparte, conversation)?;
+                let jid = BareJid::from_str(&conversation).map_err(|err| i18n::trf("Invalid JID {}: {}", &[&conversation, &err]))?;
+                aparte.get_plugin_mut::<plugins::outbox::OutboxPlugin>().unwrap().cancel(Some(&jid))
+            },
+        };
+
+        if cancelled.is_empty() {
+            return Err(i18n::tr("No pending message"));
+        }
+
+        Rc::clone(&aparte).log(format!("Cancelled {} pending messages", cancelled.len()));
+        let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+        for mut message in cancelled {
+            message.set_pending(false);
+            message.set_error("cancelled");
+            ui.replace_message(Rc::clone(&aparte), &message);
+        }
+        Ok(())
+    }
+}
+
+command_def!{
+    translate,
+    r#"/translate [<index>]
+
+  index  Message to translate counting back from the last one, 1 by default
+
+Description:
+  Translate a message received in the current conversation with the
+  command or URL set in the [translation] section of the config file. The
+  translation is displayed beneath the message.
+
+Examples:
+  /translate
+  /translate 3"#,
+    (optional) index,
+    |aparte, _command| {
+        let conversation = conversation_or_current(&aparte, None)?;
+        let index = match index {
+            Some(index) => usize::from_str(&index).map_err(|_| format!("Invalid message index {}", index))?,
+            None => 1,
+        };
+        let translate = aparte.get_plugin::<plugins::translate::TranslatePlugin>().unwrap();
+        translate.translate(Rc::clone(&aparte), &conversation, index)
+    }
+}
+
+command_def!{
+    open,
+    r#"/open [<index>]
+
+  index  Number of the URL as listed by /urls, the last one by default
+
+Description:
+  Open a URL received in the current conversation with the default
+  application. Received URLs are followed by their index, which keeps
+  designating the same URL as new ones arrive.
+
+Examples:
+  /open
+  /open 2"#,
+    (optional) index,
+    |aparte, _command| {
+        let conversation = conversation_or_current(&aparte, None)?;
+        let index = match index {
+            Some(index) => Some(usize::from_str(&index).map_err(|_| format!("Invalid URL index {}", index))?),
+            None => None,
+        };
+        let urls = aparte.get_plugin::<plugins::urls::UrlsPlugin>().unwrap();
+        plugins::urls::open(urls.get(&conversation, index)?)
+    }
+}
+
+command_def!{
+    copy,
+    r#"/copy [url [<index>]]
+
+  index  Number of the URL as listed by /urls, the last one by default
+
+Description:
+  Copy the selected message to the clipboard: the one found by the search of
+  Ctrl-f, the last one of the window otherwise. With url, copy a URL received
+  in the current conversation instead. Alt-w and Alt-u do the same.
+
+  The clipboard is set through the terminal (OSC 52), which works over ssh,
+  and with wl-copy or xclip in a graphical session.
+
+Examples:
+  /copy
+  /copy url
+  /copy url 2"#,
+    (optional) what: {
+        completion: |_aparte, _command| {
+            vec![String::from("url")]
+        }
+    },
+    (optional) index,
+    |aparte, _command| {
+        let text = match what.as_deref() {
+            None => {
+                let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+                ui.selected_message().map(|message| message.body().to_string()).ok_or(format!("No message to copy"))?
+            },
+            Some("url") => {
+                let conversation = conversation_or_current(&aparte, None)?;
+                let index = match index {
+                    Some(index) => Some(usize::from_str(&index).map_err(|_| format!("Invalid URL index {}", index))?),
+                    None => None,
+                };
+                let urls = aparte.get_plugin::<plugins::urls::UrlsPlugin>().unwrap();
+                urls.get(&conversation, index)?.to_string()
+            },
+            Some(what) => return Err(format!("Cannot copy {}, expected url", what)),
+        };
+        aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap().copy(&text)
+    }
+}
+
+command_def!{
+    urls,
+    r#"/urls
+
+Description:
+  List URLs received in the current conversation, to be opened with /open.
+
+Example:
+  /urls"#,
+    |aparte, _command| {
+        let conversation = conversation_or_current(&aparte, None)?;
+        let lines: Vec<String> = {
+            let urls = aparte.get_plugin::<plugins::urls::UrlsPlugin>().unwrap();
+            urls.urls(&conversation).iter().map(|(index, url)| format!("  {}: {}", index, url)).collect()
+        };
+
+        if lines.is_empty() {
+            return Err(format!("No URL in {}", conversation));
+        }
+
+        Rc::clone(&aparte).log(format!("URLs in {}", conversation));
+        for line in lines {
+            Rc::clone(&aparte).log(line);
+        }
+        Ok(())
+    }
+}
+
+command_def!{
+    disco,
+    r#"/disco [<jid>|<index>] [<node>]
+
+  jid    Entity to browse, your server by default
+  index  Number of an item of the tree being browsed
+  node   Node of the entity to browse
+
+Description:
+  Browse the services of an entity in the disco window: its identities,
+  features and items. Items are numbered, give their number to drill into
+  them, to find channel services, upload components or gateways.
+
+Examples:
+  /disco
+  /disco conference.server.tld
+  /disco 2
+  /disco pubsub.server.tld princely_musings"#,
+    (optional) target,
+    (optional) node,
+    |aparte, _command| {
+        let account = match aparte.current_connection() {
+            Some(account) => account,
+            None => return Err(i18n::tr("Not connected")),
+        };
+
+        let queries = {
+            let mut disco = aparte.get_plugin_mut::<plugins::disco::Disco>().unwrap();
+            match target {
+                Some(target) => match usize::from_str(&target) {
+                    Ok(index) => disco.expand(index)?,
+                    Err(_) => match Jid::from_str(&target) {
+                        Ok(jid) => disco.browse(jid, node),
+                        Err(err) => return Err(i18n::trf("Invalid JID {}: {}", &[&target, &err])),
+                    },
+                },
+                None => disco.browse(Jid::Bare(BareJid::domain(&account.domain)), node),
+            }
+        };
+
+        {
+            let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+            ui.add_log_window(plugins::disco::WINDOW);
+            ui.change_window(plugins::disco::WINDOW);
+        }
+        Ok(Box::new(plugins::disco::Disco::send(Rc::clone(&aparte), queries)))
+    }
+}
+
+command_def!{
+    rooms,
+    r#"/rooms [<service>]
+
+  service  Channel service, conference.<your server> by default
+
+Description:
+  List the public rooms of a channel service in the rooms window, with
+  their number of occupants when the service publishes it. Select a room
+  with the arrows and press Enter to join it.
+
+Examples:
+  /rooms
+  /rooms conference.server.tld"#,
+    (optional) service,
+    |aparte, _command| {
+        let account = match aparte.current_connection() {
+            Some(account) => account,
+            None => return Err(i18n::tr("Not connected")),
+        };
+
+        let service = match service {
+            Some(service) => Jid::from_str(&service).map_err(|err| i18n::trf("Invalid JID {}: {}", &[&service, &err]))?,
+            None => Jid::Bare(BareJid::domain(&format!("conference.{}", account.domain))),
+        };
+        Ok(Box::new(plugins::rooms::RoomsPlugin::list(Rc::clone(&aparte), service)))
+    }
+}
+
+command_def!{
+    gateway,
+    r#"/gateway list|register <jid>|unregister <jid>
+
+  jid  Gateway to register with or unregister from
+
+Description:
+  Manage gateways bridging legacy networks, as biboumi for IRC or Slack and
+  Signal bridges. list looks for the gateways of your server and shows them
+  in the gateways window. register fetches the registration form of a
+  gateway, fill it with /register set and send it with /register submit.
+  Contacts and channels of the network are then reachable through the
+  gateway, and what it relays is shown in their conversations.
+
+Examples:
+  /gateway list
+  /gateway register biboumi.server.tld
+  /gateway unregister signal.server.tld"#,
+    action: {
+        completion: |_aparte, _command| {
+            vec![String::from("list"), String::from("register"), String::from("unregister")]
+        }
+    },
+    (optional) jid: {
+        completion: |aparte, _command| {
+            aparte.get_plugin::<plugins::gateway::GatewayPlugin>().unwrap().gateways()
+        }
+    },
+    |aparte, _command| {
+        let account = aparte.current_connection().ok_or_else(|| i18n::tr("Not connected"))?;
+        let jid = match (action.as_str(), jid) {
+            ("list", _) => None,
+            (_, Some(jid)) => Some(BareJid::from_str(&jid).map_err(|err| i18n::trf("Invalid JID {}: {}", &[&jid, &err]))?),
+            (_, None) => return Err(format!("Missing gateway")),
+        };
+
+        let request = match (action.as_str(), jid) {
+            ("list", _) => return Ok(Box::new(plugins::gateway::GatewayPlugin::show(Rc::clone(&aparte), &account)) as CommandFuture),
+            ("register", Some(jid)) => aparte.get_plugin_mut::<plugins::register::RegisterPlugin>().unwrap().start_gateway(jid),
+            ("unregister", Some(jid)) => plugins::register::RegisterPlugin::unregister(jid),
+            (action, _) => return Err(i18n::trf("Unknown action {}", &[&action])),
+        };
+        Ok(Box::new(plugins::register::RegisterPlugin::send_request(Rc::clone(&aparte), request)))
+    }
+}
+
+/// Channel displayed in the current window, for the moderation commands
+fn current_channel(aparte: &Aparte) -> Result<BareJid, String> {
+    let conversation = conversation_or_current(aparte, None)?;
+    let jid = BareJid::from_str(&conversation).map_err(|_| i18n::trf("{} is not a channel", &[&conversation]))?;
+    match aparte.get_plugin::<plugins::conversation::ConversationPlugin>().unwrap().get(&jid) {
+        Some(conversation::Conversation::Channel(_)) => Ok(jid),
+        _ => Err(i18n::trf("{} is not a channel", &[&conversation])),
+    }
+}
+
+fn set_role(aparte: Rc<Aparte>, nick: &str, role: &str, reason: Option<String>) -> Result<CommandFuture, String> {
+    let channel = current_channel(&aparte)?;
+    let request = plugins::mucadmin::MucAdminPlugin::set_role(&channel, nick, role, reason.as_deref())?;
+    Ok(Box::new(plugins::mucadmin::MucAdminPlugin::send(aparte, request)))
+}
+
+fn set_affiliation(aparte: Rc<Aparte>, jid: &str, affiliation: &str, reason: Option<String>) -> Result<CommandFuture, String> {
+    let channel = current_channel(&aparte)?;
+    let jid = BareJid::from_str(jid).map_err(|err| i18n::trf("Invalid JID {}: {}", &[&jid, &err]))?;
+    let request = plugins::mucadmin::MucAdminPlugin::set_affiliation(&channel, &jid, affiliation, reason.as_deref())?;
+    Ok(Box::new(plugins::mucadmin::MucAdminPlugin::send(aparte, request)))
+}
+
+fn occupant_nicks(aparte: &Aparte) -> Vec<String> {
+    match conversation_or_current(aparte, None) {
+        Ok(channel) => plugins::mucadmin::occupant_nicks(aparte, &channel),
+        Err(_) => Vec::new(),
+    }
+}
+
+command_def!{
+    nick,
+    r#"/nick <nick>
+
+  nick  New nick in the current channel
+
+Description:
+  Change your nick in the current channel. The default nick of a channel
+  can be set in its bookmark, in the nicks section of the config file, or
+  for every channel with the nick of the account.
+
+Example:
+  /nick aparte_fan"#,
+    nick,
+    |aparte, _command| {
+        let channel = current_channel(&aparte)?;
+        aparte.send(plugins::conversation::ConversationPlugin::nick_presence(&channel, &nick));
+        Ok(())
+    }
+}
+
+command_def!{
+    kick,
+    r#"/kick <nick> [<reason>]
+
+  nick    Occupant of the current channel
+  reason  Optional reason given to the occupant
+
+Description:
+  Kick an occupant out of the current channel. Requires to be moderator.
+
+Examples:
+  /kick troll
+  /kick troll "Be nice""#,
+    nick: {
+        completion: |aparte, _command| {
+            occupant_nicks(&aparte)
+        }
+    },
+    (optional) reason,
+    |aparte, _command| {
+        set_role(aparte, &nick, "none", reason)
+    }
+}
+
+command_def!{
+    ban,
+    r#"/ban <jid> [<reason>]
+
+  jid     JID to ban from the current channel
+  reason  Optional reason of the ban
+
+Description:
+  Ban a JID from the current channel. Requires to be admin.
+
+Examples:
+  /ban spammer@server.tld
+  /ban spammer@server.tld "Spam""#,
+    jid,
+    (optional) reason,
+    |aparte, _command| {
+        set_affiliation(aparte, &jid, "outcast", reason)
+    }
+}
+
+command_def!{
+    voice,
+    r#"/voice <nick>
+
+  nick  Occupant of the current channel
+
+Description:
+  Grant voice to an occupant of the current channel, so that they can speak
+  in a moderated channel. Requires to be moderator.
+
+Example:
+  /voice visitor"#,
+    nick: {
+        completion: |aparte, _command| {
+            occupant_nicks(&aparte)
+        }
+    },
+    |aparte, _command| {
+        set_role(aparte, &nick, "participant", None)
+    }
+}
+
+command_def!{
+    op,
+    r#"/op <nick>
+
+  nick  Occupant of the current channel
+
+Description:
+  Make an occupant of the current channel moderator. Requires to be admin.
+
+Example:
+  /op friend"#,
+    nick: {
+        completion: |aparte, _command| {
+            occupant_nicks(&aparte)
+        }
+    },
+    |aparte, _command| {
+        set_role(aparte, &nick, "moderator", None)
+    }
+}
+
+command_def!{
+    affiliation,
+    r#"/affiliation <jid> owner|admin|member|outcast|none [<reason>]
+
+  jid          JID whose affiliation with the current channel is changed
+  affiliation  New affiliation, outcast bans the JID and none removes its
+               affiliation
+  reason       Optional reason of the change
+
+Description:
+  Change the affiliation of a JID with the current channel. Requires to be
+  admin, or owner to grant admin and owner affiliations.
+
+Examples:
+  /affiliation friend@server.tld member
+  /affiliation friend@server.tld admin "Welcome aboard""#,
+    jid,
+    affiliation: {
+        completion: |_aparte, _command| {
+            plugins::mucadmin::AFFILIATIONS.iter().map(|affiliation| affiliation.to_string()).collect()
+        }
+    },
+    (optional) reason,
+    |aparte, _command| {
+        set_affiliation(aparte, &jid, &affiliation, reason)
+    }
+}
+
+command_def!{
+    subject,
+    r#"/subject <text>
+
+  text  New subject of the current channel
+
+Description:
+  Change the subject of the current channel. Depending on its
+  configuration, it may require to be moderator.
+
+Example:
+  /subject Release party tonight"#,
+    text,
+    |aparte, command| {
+        let channel = current_channel(&aparte)?;
+        let text = match command.args.len() > 2 {
+            true => command.args[1..].join(" "),
+            false => text,
+        };
+        aparte.send(plugins::mucadmin::subject(&channel, &text));
+        Ok(())
+    }
+}
+
+/// Number of previous subjects listed by /topic history
+const TOPIC_HISTORY: usize = 20;
+
+command_def!{
+    topic,
+    r#"/topic [history|restore <index>]
+
+  index  Number of a subject as listed by /topic history
+
+Description:
+  Show the subject of the current channel. history lists its previous
+  subjects, newest first, with who set them and when. restore sets the
+  subject back to one of them, to recover an overwritten subject.
+
+Examples:
+  /topic
+  /topic history
+  /topic restore 2"#,
+    (optional) action: {
+        completion: |_aparte, _command| {
+            vec![String::from("history"), String::from("restore")]
+        }
+    },
+    (optional) index,
+    |aparte, _command| {
+        let channel = current_channel(&aparte)?;
+        let subjects = || match aparte.get_plugin::<plugins::history::HistoryPlugin>() {
+            Some(history) => history.subjects(&channel, TOPIC_HISTORY),
+            None => Err(i18n::tr("The history plugin is disabled, subjects aren't recorded")),
+        };
+
+        match action.as_deref() {
+            None => {
+                let subject = match aparte.get_plugin::<plugins::conversation::ConversationPlugin>().unwrap().get(&channel) {
+                    Some(conversation::Conversation::Channel(channel)) => channel.subject.clone(),
+                    _ => None,
+                };
+                match subject {
+                    Some(subject) => aparte.log(format!("Subject of {}: {}", channel, subject)),
+                    None => aparte.log(format!("No subject for {}", channel)),
+                }
+            },
+            Some("history") => {
+                let subjects = subjects()?;
+                let mut lines = vec![format!("Subjects of {}:", channel)];
+                for (index, subject) in subjects.iter().enumerate() {
+                    let timestamp = subject.timestamp.with_timezone(&chrono::Local).format(&i18n::date_time_format()).to_string();
+                    let nick = subject.nick.as_deref().unwrap_or("the channel");
+                    lines.push(match subject.subject.as_str() {
+                        "" => format!("  [{}] {} removed by {}", index + 1, timestamp, nick),
+                        text => format!("  [{}] {} by {}: {}", index + 1, timestamp, nick, text),
+                    });
+                }
+                if subjects.is_empty() {
+                    lines.push(String::from("  No subject recorded"));
+                }
+                let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+                ui.log_to(Rc::clone(&aparte), &channel.to_string(), lines.join("\n"));
+            },
+            Some("restore") => {
+                let index = index.ok_or(format!("Missing index"))?;
+                let subject = match usize::from_str(&index) {
+                    Ok(index) if index >= 1 => subjects()?.into_iter().nth(index - 1),
+                    _ => None,
+                };
+                let subject = subject.ok_or(format!("No subject {}, see /topic history", index))?;
+                aparte.send(plugins::mucadmin::subject(&channel, &subject.subject));
+            },
+            Some(action) => return Err(i18n::trf("Unknown action {}", &[&action])),
+        }
+        Ok(())
+    }
+}
+command_def!{
+    invite,
+    r#"/invite <jid> [<reason>]
+
+  jid     JID to invite in the current channel
+  reason  Optional message sent with the invitation
+
+Description:
+  Invite a contact in the current channel, the invitation being sent
+  through the channel.
+
+Examples:
+  /invite friend@server.tld
+  /invite friend@server.tld "Come and see""#,
+    jid: {
+        completion: |aparte, _command| {
+            let contacts = aparte.get_plugin::<plugins::contact::ContactPlugin>().unwrap();
+            match aparte.current_connection() {
+                Some(account) => contacts.contacts(&account).iter().map(|contact| contact.jid.to_string()).collect(),
+                None => Vec::new(),
+            }
+        }
+    },
+    (optional) reason,
+    |aparte, _command| {
+        let channel = current_channel(&aparte)?;
+        let jid = BareJid::from_str(&jid).map_err(|err| i18n::trf("Invalid JID {}: {}", &[&jid, &err]))?;
+        aparte.send(plugins::mucadmin::invite(&channel, &jid, reason.as_deref()));
+        Rc::clone(&aparte).log(format!("Invited {} in {}", jid, channel));
+        Ok(())
+    }
+}
+
+fn invited_rooms(aparte: &Aparte) -> Vec<String> {
+    aparte.get_plugin::<plugins::invitations::InvitationsPlugin>().unwrap().rooms()
+}
+
+fn take_invitation(aparte: &Aparte, room: Option<String>) -> Result<invitation::Invitation, String> {
+    let room = match room {
+        Some(room) => Some(BareJid::from_str(&room).map_err(|err| i18n::trf("Invalid JID {}: {}", &[&room, &err]))?),
+        None => None,
+    };
+    aparte.get_plugin_mut::<plugins::invitations::InvitationsPlugin>().unwrap().take(room)
+}
+
+command_def!{
+    accept,
+    r#"/accept [<channel>]
+
+  channel  Channel we were invited to (default to the last invitation)
+
+Description:
+  Join a channel we were invited to, with the password given by the
+  invitation.
+
+Examples:
+  /accept
+  /accept channel@conference.server.tld"#,
+    (optional) room: {
+        completion: |aparte, _command| {
+            invited_rooms(&aparte)
+        }
+    },
+    |aparte, _command| {
+        let connection = aparte.current_connection().ok_or_else(|| i18n::tr("No connection found"))?;
+        let invitation = take_invitation(&aparte, room)?;
+        let to = channel_occupant(&aparte, &connection, invitation.room);
+        aparte.send_on(&connection, plugins::conversation::ConversationPlugin::join_presence(&aparte, Jid::Full(connection.clone()), to.clone(), invitation.password));
+        aparte.event(Event::Join(connection, to));
+        Ok(())
+    }
+}
+
+command_def!{
+    decline,
+    r#"/decline [<channel>|<caller>] [<reason>]
+
+  channel  Channel we were invited to (default to the last invitation)
+  caller   Contact calling us (default to the last call)
+  reason   Optional message sent to the inviter
+
+Description:
+  Decline a call ringing, or an invitation to a channel when no call is
+  ringing. The decline of an invitation is sent to the inviter through the
+  channel.
+
+Examples:
+  /decline
+  /decline contact@server.tld
+  /decline channel@conference.server.tld
+  /decline channel@conference.server.tld "Not now""#,
+    (optional) room: {
+        completion: |aparte, _command| {
+            let mut rooms = aparte.get_plugin::<plugins::calls::CallsPlugin>().unwrap().callers();
+            rooms.extend(invited_rooms(&aparte));
+            rooms
+        }
+    },
+    (optional) reason,
+    |aparte, _command| {
+        let call = aparte.get_plugin_mut::<plugins::calls::CallsPlugin>().unwrap().take(room.as_deref());
+        if let Some(call) = call {
+            aparte.send(call.decline());
+            Rc::clone(&aparte).log(format!("Declined the {}", call));
+            return Ok(());
+        }
+
+        let invitation = take_invitation(&aparte, room)?;
+        aparte.send(invitation.decline(reason.as_deref()));
+        Rc::clone(&aparte).log(format!("Declined the invitation to {}", invitation.room));
+        Ok(())
+    }
+}
+
+command_def!{
+    configure,
+    r#"/configure [set <field> <value>|submit|cancel]
+
+  field  Number or name of a field of the configuration form
+  value  New value of the field, yes or no for yes/no fields and comma
+         separated values for fields with several values
+
+Description:
+  Edit the configuration of the current channel, to make it persistent or
+  members-only for instance. Requires to be owner of the channel. The
+  configuration form is displayed in the configure window, change its fields
+  with set and send it with submit, or drop it with cancel.
+
+Examples:
+  /configure
+  /configure set 3 yes
+  /configure set muc#roomconfig_membersonly yes
+  /configure submit"#,
+    (optional) action: {
+        completion: |_aparte, _command| {
+            vec![String::from("set"), String::from("submit"), String::from("cancel")]
+        }
+    },
+    (optional) field,
+    (optional) value,
+    |aparte, command| {
+        match action.as_deref() {
+            None => {
+                let channel = current_channel(&aparte)?;
+                let request = plugins::mucadmin::MucAdminPlugin::configure(&channel);
+                Ok(Box::new(plugins::mucadmin::MucAdminPlugin::send(Rc::clone(&aparte), request)) as CommandFuture)
+            },
+            Some("set") => {
+                let (field, value) = match (field, value) {
+                    (Some(field), Some(_)) => (field, command.args[3..].join(" ")),
+                    _ => return Err(format!("Missing field or value")),
+                };
+                let lines = aparte.get_plugin_mut::<plugins::mucadmin::MucAdminPlugin>().unwrap().set_config(&field, &value)?.render();
+                let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+                ui.clear_window(plugins::mucadmin::CONFIGURE_WINDOW);
+                ui.log_to(Rc::clone(&aparte), plugins::mucadmin::CONFIGURE_WINDOW, lines.join("\n"));
+                Ok(Box::new(future::ok(())))
+            },
+            Some("submit") => {
+                let (room, request) = aparte.get_plugin_mut::<plugins::mucadmin::MucAdminPlugin>().unwrap().submit_config()?;
+                {
+                    let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+                    ui.clear_window(plugins::mucadmin::CONFIGURE_WINDOW);
+                    ui.change_window(&room.to_string());
+                }
+                Ok(Box::new(plugins::mucadmin::MucAdminPlugin::send(Rc::clone(&aparte), request)))
+            },
+            Some("cancel") => {
+                let (room, iq) = aparte.get_plugin_mut::<plugins::mucadmin::MucAdminPlugin>().unwrap().cancel_config()?;
+                {
+                    let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+                    ui.clear_window(plugins::mucadmin::CONFIGURE_WINDOW);
+                    ui.change_window(&room.to_string());
+                }
+                aparte.send(iq.into());
+                Ok(Box::new(future::ok(())))
+            },
+            Some(action) => Err(i18n::trf("Unknown action {}", &[&action])),
+        }
+    }
+}
+
+/// Arguments of a command and the value of its --format option
+fn format_args(command: &Command) -> Result<(Vec<String>, Option<String>), String> {
+    let mut args = Vec::new();
+    let mut format = None;
+    let mut iter = command.args[1..].iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--format" => format = Some(iter.next().ok_or(format!("Missing format"))?.clone()),
+            _ => args.push(arg.clone()),
+        }
+    }
+    Ok((args, format))
+}
+
+command_def!{
+    export,
+    r#"/export [<jid>] [--format json|mbox|txt]
+
+  jid     Contact or channel whose history is exported, every conversation
+          when left out
+  format  json (the default) to import it again with /import, mbox to read it
+          with a mail client or txt
+
+Description:
+  Export the history of a conversation, or of every one, from the history
+  database to a file in the exports directory of aparté's data directory.
+
+Examples:
+  /export
+  /export juliet@capulet.lit --format txt"#,
+    |aparte, command| {
+        let (args, format) = format_args(&command)?;
+        let format = match format {
+            Some(format) => export::Format::from_str(&format)?,
+            None => export::Format::Json,
+        };
+        let jid = match args.get(0) {
+            Some(jid) => Some(BareJid::from_str(jid).map_err(|err| i18n::trf("Invalid JID {}: {}", &[&jid, &err]))?),
+            None => None,
+        };
+
+        let messages = match aparte.get_plugin::<plugins::history::HistoryPlugin>() {
+            Some(history) => history.export(jid.as_ref())?,
+            None => return Err(i18n::tr("The history plugin is disabled, messages aren't recorded")),
+        };
+        let dir = profile::data_dir().join("exports");
+        fs::create_dir_all(&dir).map_err(|err| format!("Cannot create {}: {}", dir.display(), err))?;
+        let name = jid.map(|jid| jid.to_string()).unwrap_or(String::from("history"));
+        let path = dir.join(format!("{}.{}", name, format.extension()));
+        fs::write(&path, export::export(&messages, format)).map_err(|err| format!("Cannot write {}: {}", path.display(), err))?;
+        aparte.log(format!("Exported {} messages to {}", messages.len(), path.display()));
+        Ok(())
+    }
+}
+
+command_def!{
+    import,
+    r#"/import <path> [--format json|profanity|gajim]
+
+  path    Logs to import: a file exported with /export, a Profanity log file or
+          directory of them, as ~/.local/share/profanity/chatlogs/<account>,
+          or the logs.db database of Gajim
+  format  Client the logs come from, guessed from the path when left out
+
+Description:
+  Import the history of conversations logged by another client, or exported
+  with /export, into the history database. The logs are the ones of the
+  current account. Messages already imported are skipped.
+
+Examples:
+  /import ~/.local/share/profanity/chatlogs/romeo_at_montague.lit
+  /import ~/.local/share/gajim/logs.db"#,
+    |aparte, command| {
+        let (args, format) = format_args(&command)?;
+        let path = args.get(0).ok_or(format!("Missing path argument"))?;
+        let path = profile::expand_home(path)?;
+        let source = match format {
+            Some(format) => export::Source::from_str(&format)?,
+            None => export::Source::guess(&path),
+        };
+        let account = aparte.current_connection().ok_or_else(|| i18n::tr("No connection found"))?;
+        let account = BareJid::from(Jid::Full(account));
+
+        let messages = export::import(&path, source, &account)?;
+        match aparte.get_plugin_mut::<plugins::history::HistoryPlugin>() {
+            Some(mut history) => history.import(&messages)?,
+            None => return Err(i18n::tr("The history plugin is disabled, messages aren't recorded")),
+        }
+        aparte.log(format!("Imported {} messages from {}", messages.len(), path.display()));
+        Ok(())
+    }
+}
+
+command_def!{
+    profile,
+    r#"/profile [list]
+
+Description:
+  Show the profile aparté runs with, started with --profile <name>, and where
+  its config, history, caches, logs and control socket are. Each profile keeps
+  its own, so that several instances, as one for work and one for personal
+  accounts, can run side by side. List the profiles created so far with list.
+
+Examples:
+  /profile
+  /profile list"#,
+    (optional) action: {
+        completion: |_aparte, _command| {
+            vec![String::from("list")]
+        }
+    },
+    |aparte, _command| {
+        let current = profile::name().unwrap_or(String::from("default"));
+        match action.as_deref() {
+            None => {
+                let lines = vec![
+                    format!("Profile: {}", current),
+                    format!("  Config: {}", aparte.config_path.display()),
+                    format!("  Data: {}", profile::data_dir().display()),
+                    match profile::control_socket() {
+                        Ok(path) => format!("  Control socket: {}", path.display()),
+                        Err(err) => format!("  Control socket: {}", err),
+                    },
+                ];
+                aparte.log(lines.join("\n"));
+            },
+            Some("list") => {
+                let profiles: Vec<String> = profile::list().into_iter().map(|name| match name == current {
+                    true => format!("{} (loaded)", name),
+                    false => name,
+                }).collect();
+                aparte.log(format!("Profiles: {}", profiles.join(", ")));
+            },
+            Some(action) => return Err(i18n::trf("Unknown action {}", &[&action])),
+        }
+        Ok(())
+    }
+}
+
+command_def!{
+    mentions,
+    r#"/mentions [<index>]
+
+  index  Number of a mention as listed by /mentions
+
+Description:
+  List the messages mentioning you in channels, across sessions, in the
+  mentions window. Give the number of a mention to jump to its channel and
+  display the messages around it, fetched from the history or from the
+  archive of the channel.
+
+Examples:
+  /mentions
+  /mentions 3"#,
+    (optional) index,
+    |aparte, _command| {
+        let index = match index {
+            Some(index) => usize::from_str(&index).map_err(|_| format!("Invalid mention index {}", index))?,
+            None => {
+                let lines = {
+                    let history = aparte.get_plugin::<plugins::history::HistoryPlugin>().unwrap();
+                    aparte.get_plugin_mut::<plugins::mentions::MentionsPlugin>().unwrap().list(history.store())?
+                };
+                let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+                ui.add_log_window(plugins::mentions::WINDOW);
+                ui.clear_window(plugins::mentions::WINDOW);
+                match lines.is_empty() {
+                    true => ui.log_to(Rc::clone(&aparte), plugins::mentions::WINDOW, format!("No mention yet")),
+                    false => ui.log_to(Rc::clone(&aparte), plugins::mentions::WINDOW, lines.join("\n")),
+                }
+                ui.change_window(plugins::mentions::WINDOW);
+                return Ok(Box::new(future::ok(())) as CommandFuture);
+            },
+        };
+
+        let low_bandwidth = plugins::bandwidth::is_low(&aparte);
+        let (room, lines, query) = {
+            let history = aparte.get_plugin::<plugins::history::HistoryPlugin>().unwrap();
+            aparte.get_plugin_mut::<plugins::mentions::MentionsPlugin>().unwrap().context(history.store(), index, low_bandwidth)?
+        };
+        let joined = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap().get_windows().contains(&room.to_string());
+        if !joined {
+            Rc::clone(&aparte).parse_command(Command::new(vec!["join".to_string(), room.to_string()]))?;
+        }
+
+        {
+            let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+            ui.change_window(&room.to_string());
+            ui.log_to(Rc::clone(&aparte), &room.to_string(), lines.join("\n"));
+        }
+        match query {
+            Some(query) => Ok(Box::new(plugins::mentions::MentionsPlugin::fetch(Rc::clone(&aparte), query))),
+            None => Ok(Box::new(future::ok(()))),
+        }
+    }
+}
+
+command_def!{
+    xmlconsole,
+    r#"/xmlconsole [off|<filter>...]
+
+  filter  message, presence, iq or a JID, only stanzas matching one of the
+          filters are shown
+
+Description:
+  Open a window showing the raw stanzas exchanged with the server, or stop
+  showing them with off.
+
+Examples:
+  /xmlconsole
+  /xmlconsole iq presence
+  /xmlconsole contact@server.tld
+  /xmlconsole off"#,
+    |aparte, command| {
+        if command.args.get(1).map(String::as_str) == Some("off") {
+            let mut console = aparte.get_plugin_mut::<plugins::xmlconsole::XmlConsolePlugin>().unwrap();
+            console.close();
+            return Ok(());
+        }
+
+        let mut filters = Vec::new();
+        for filter in &command.args[1..] {
+            filters.push(plugins::xmlconsole::Filter::from_str(filter)?);
+        }
+
+        aparte.get_plugin_mut::<plugins::xmlconsole::XmlConsolePlugin>().unwrap().open(filters);
+        let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+        ui.add_log_window(plugins::xmlconsole::WINDOW);
+        ui.change_window(plugins::xmlconsole::WINDOW);
+        Ok(())
+    }
+}
+
+command_def!{
+    xmlsend,
+    r#"/xmlsend <xml>
+
+  xml  Stanza to send, quote it when it contains quotes
+
+Description:
+  Send a hand-written stanza to the server, for debugging.
+
+Example:
+  /xmlsend "<iq type='get' id='ping' to='server.tld'><ping xmlns='urn:xmpp:ping'/></iq>""#,
+    xml,
+    |aparte, command| {
+        if aparte.current_connection().is_none() {
+            return Err(i18n::tr("No connection found"));
+        }
+
+        let xml = match command.args.len() > 2 {
+            true => command.args[1..].join(" "),
+            false => xml,
+        };
+        let mut stanza = Element::from_str(&xml).map_err(|err| format!("Invalid XML: {}", err))?;
+        // Stanzas without namespace belong to the client stream
+        if stanza.ns().is_none() {
+            stanza = Element::from_str(&xml.replacen(&format!("<{}", stanza.name()), &format!("<{} xmlns='jabber:client'", stanza.name()), 1))
+                .map_err(|err| format!("Invalid XML: {}", err))?;
+        }
+        aparte.send(stanza);
+        Ok(())
+    }
+}
+
+command_def!{
+    pubsub,
+    r#"/pubsub nodes|items|publish|subscribe|delete <service> [<node>] [<item>]
+
+  service  Pubsub service, or your own JID for your PEP nodes
+  node     Node of the service, required but to list nodes
+  item     Item to publish as XML, quoted when it contains quotes, or id of
+           the item to delete
+
+Description:
+  Talk to a publish-subscribe service (XEP-0060), to debug PEP or use
+  services built on it. Payloads of items are printed as indented XML.
+
+  nodes      List the nodes of the service, or the children of a node
+  items      Print the items of a node
+  publish    Publish an item on a node, with its own namespace
+  subscribe  Subscribe to a node with your JID
+  delete     Delete a node, or retract one of its items
+
+Examples:
+  /pubsub nodes pubsub.server.tld
+  /pubsub items me@server.tld urn:xmpp:microblog:0
+  /pubsub publish pubsub.server.tld news "<entry xmlns='http://www.w3.org/2005/Atom'><title>Hi</title></entry>"
+  /pubsub delete pubsub.server.tld news 368866411b877c30"#,
+    action: {
+        completion: |_aparte, _command| {
+            pubsub::ACTIONS.iter().map(|action| action.to_string()).collect()
+        }
+    },
+    service,
+    (optional) node,
+    |aparte, command| {
+        let account = match aparte.current_connection() {
+            Some(account) => account,
+            None => return Err(i18n::tr("Not connected")),
+        };
+        let action = pubsub::Action::from_str(&action)?;
+        let service = Jid::from_str(&service).map_err(|err| i18n::trf("Invalid JID {}: {}", &[&service, &err]))?;
+        let args = command.args.get(4..).map(|args| args.join(" ")).unwrap_or_default();
+        let iq = pubsub::request(action, service.clone(), node.as_deref(), &args, &BareJid::from(Jid::Full(account)))?;
+
+        let log = Rc::clone(&aparte);
+        Ok(Box::new(Rc::clone(&aparte).send_iq(iq).map(move |iq| {
+            let payload = match iq.payload {
+                IqType::Result(payload) => payload,
+                _ => None,
+            };
+            for line in pubsub::answer(action, node.as_deref(), payload) {
+                Rc::clone(&log).log(line);
+            }
+        }).map_err(move |err| format!("Pubsub request to {} failed: {}", service, err))))
+    }
+}
+
+command_def!{
+    connstat,
+    r#"/connstat
+
+Description:
+  Print diagnostics about the current connection: transport, security
+  negotiation and stanza counters.
+
+Example:
+  /connstat"#,
+    |aparte, _command| {
+        let (account, stats) = match aparte.connection_stats() {
+            Some(stats) => stats,
+            None => return Err(i18n::tr("No connection found")),
+        };
+
+        let status = match stats.online_since {
+            Some(since) => format!("online since {} ({}s)", since.with_timezone(&chrono::Local).format(&i18n::time_format()), (Utc::now() - since).num_seconds()),
+            None => format!("connecting"),
+        };
+
+        let mut lines = vec![
+            format!("Connection {}", account),
+            format!("  Status: {}", status),
+        ];
+        if let Some(details) = &stats.details {
+            lines.push(match details.address {
+                Some(address) => format!("  Transport: {} to {}", details.transport, address),
+                None => format!("  Transport: {}", details.transport),
+            });
+            lines.push(format!("  TLS: certificate {} {}", details.certificate.fingerprint, details.trust));
+            lines.push(match &details.mechanism {
+                Some(mechanism) if mechanism.ends_with("-PLUS") => format!("  SASL: {}, bound to the TLS connection", mechanism),
+                Some(mechanism) => format!("  SASL: {}", mechanism),
+                None => format!("  SASL: authenticated beforehand"),
+            });
+        }
+        if let Some(features) = aparte.stream_features(&account) {
+            let names: Vec<&str> = features.children().map(|feature| feature.name()).collect();
+            lines.push(format!("  Stream features: {}", names.join(", ")));
+            lines.push(match features.has_child("sm", "urn:xmpp:sm:3") {
+                true => format!("  Stream management: offered, not used"),
+                false => format!("  Stream management: not offered"),
+            });
+        }
+        lines.push(format!("  Stanzas: {} sent, {} received, {} waiting", stats.sent, stats.received, stats.queued));
+
+        for line in lines {
+            Rc::clone(&aparte).log(line);
+        }
+        Ok(())
+    }
+}
+
+command_def!{
+    bandwidth,
+    r#"/bandwidth [low|normal]
+
+Description:
+  Switch to low bandwidth mode for tethered or metered connections: images
+  linked in messages aren't downloaded, and less history is fetched from
+  archives and from the channels you join. Without argument, show the
+  current mode. Set low_bandwidth in the config to start in this mode.
+
+Examples:
+  /bandwidth low
+  /bandwidth normal"#,
+    (optional) mode: {
+        completion: |_aparte, _command| {
+            vec![String::from("low"), String::from("normal")]
+        }
+    },
+    |aparte, _command| {
+        let low = match mode.as_deref() {
+            None => plugins::bandwidth::is_low(&aparte),
+            Some("low") => true,
+            Some("normal") => false,
+            Some(mode) => return Err(format!("Unknown bandwidth mode {}", mode)),
+        };
+        aparte.get_plugin_mut::<plugins::bandwidth::BandwidthPlugin>().unwrap().set_low(low);
+        match low {
+            true => aparte.log(format!("Low bandwidth mode")),
+            false => aparte.log(format!("Normal bandwidth mode")),
+        }
+        Ok(())
+    }
+}
+
+command_def!{
+    certificate,
+    r#"/certificate [accept] [<account>]
+
+  account  Account whose server certificate is shown, the current one by default
+
+Description:
+  Show the certificate of the server of an account, its SHA-256 fingerprint,
+  validity and how it is trusted. Certificates not signed by a known authority,
+  or changed since accepted, are refused: check their fingerprint and accept
+  them to connect, they are then trusted until they change. Fingerprints
+  pinned with the tls.pin option of an account are trusted, and only them.
+
+Examples:
+  /certificate
+  /certificate accept me@server.tld"#,
+    (optional) action: {
+        completion: |_aparte, _command| {
+            vec![String::from("accept")]
+        }
+    },
+    (optional) account,
+    |aparte, _command| {
+        let (accept, account) = match action {
+            Some(action) if action == "accept" => (true, account),
+            action => (false, action),
+        };
+        let jid = match account {
+            Some(account) => BareJid::from_str(&account).map_err(|err| i18n::trf("Invalid JID {}: {}", &[&account, &err]))?,
+            None => aparte.current_connection().map(|jid| jid.into()).ok_or_else(|| i18n::tr("Not connected"))?,
+        };
+
+        if accept {
+            let certificate = aparte.get_plugin_mut::<plugins::certificates::CertificatesPlugin>().unwrap().accept(&jid)?;
+            Rc::clone(&aparte).log(format!("Certificate {} of {} accepted", certificate.fingerprint, jid.domain));
+            return plugins::reconnect::ReconnectPlugin::reconnect_account(aparte, &jid);
+        }
+
+        let lines = aparte.get_plugin::<plugins::certificates::CertificatesPlugin>().unwrap().describe(&jid)?;
+        Rc::clone(&aparte).log(lines.join("\n"));
+        Ok(())
+    }
+}
+
+command_def!{
+    reconnect,
+    r#"/reconnect [now]
+
+Description:
+  Show the state of the lost connections, which are retried with an
+  increasing delay, or retry them immediately with now.
+
+Examples:
+  /reconnect
+  /reconnect now"#,
+    (optional) action: {
+        completion: |_aparte, _command| {
+            vec![String::from("now")]
+        }
+    },
+    |aparte, _command| {
+        match action.as_deref() {
+            Some("now") => plugins::reconnect::ReconnectPlugin::reconnect_now(aparte),
+            Some(action) => Err(i18n::trf("Unknown action {}", &[&action])),
+            None => {
+                let status = aparte.get_plugin::<plugins::reconnect::ReconnectPlugin>().unwrap().status();
+                if status.is_empty() {
+                    return Err(format!("No lost connection"));
+                }
+                for line in status {
+                    Rc::clone(&aparte).log(line);
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+command_def!{
+    accept_file = "accept-file",
+    r#"/accept-file [<path>]
+
+  path          File or directory to save the file to
+
+Description:
+  Download the oldest file offered, to the download directory unless a path is
+  given. Existing files are never overwritten.
+
+Examples:
+  /accept-file
+  /accept-file ~/Pictures"#,
+    (optional) path,
+    |aparte, _command| {
+        let (offer, accept, progress) = {
+            let mut transfers = aparte.get_plugin_mut::<plugins::filetransfer::FileTransferPlugin>().unwrap();
+            transfers.accept(path.as_deref())?
+        };
+        aparte.send(accept.into());
+        Rc::clone(&aparte).event(Event::Message(progress));
+        plugins::filetransfer::FileTransferPlugin::connect(aparte, offer);
+
+        Ok(())
+    }
+}
+
+command_def!{
+    decline_file = "decline-file",
+    r#"/decline-file
+
+Description:
+  Refuse the oldest file offered.
+
+Example:
+  /decline-file"#,
+    |aparte, _command| {
+        let (offer, terminate) = {
+            let mut transfers = aparte.get_plugin_mut::<plugins::filetransfer::FileTransferPlugin>().unwrap();
+            transfers.decline()?
+        };
+        aparte.send(terminate.into());
+        Rc::clone(&aparte).log(format!("Declined {} from {}", offer, offer.from));
+
+        Ok(())
+    }
+}
+
+command_def!{
+    sendfile,
+    r#"/sendfile <path> [<jid>]
+
+  path          File to send
+  jid           Contact to send it to, the current conversation by default
+
+Description:
+  Send a file directly to a contact, through the proxy of your server or
+  in-band if there is none, when HTTP upload isn't available or the file is
+  too large for it. Files are sent to a single client: give a full JID, or
+  one of the clients of the contact supporting file transfer is chosen.
+
+Examples:
+  /sendfile ~/notes.txt
+  /sendfile ~/notes.txt juliet@capulet.lit/balcony"#,
+    path,
+    (optional) jid,
+    |aparte, _command| {
+        let account = aparte.current_connection().ok_or_else(|| i18n::tr("Not connected"))?;
+        let jid = match jid {
+            Some(jid) => jid,
+            None => current_conversation(&aparte)?.to_string(),
+        };
+        let to = match Jid::from_str(&jid) {
+            Ok(Jid::Full(jid)) => jid,
+            Ok(Jid::Bare(jid)) => aparte.get_plugin::<plugins::caps::CapsPlugin>().unwrap().resource(&jid, ns::JINGLE_FT)
+                .ok_or(format!("No client of {} known to support file transfer, give a full JID", jid))?,
+            Err(err) => return Err(i18n::trf("Invalid JID {}: {}", &[&jid, &err])),
+        };
+
+        let (sid, initiate, progress) = {
+            let mut transfers = aparte.get_plugin_mut::<plugins::filetransfer::FileTransferPlugin>().unwrap();
+            transfers.send(account, to.clone(), &path)?
+        };
+        Rc::clone(&aparte).event(Event::Message(progress));
+        let refused_aparte = Rc::clone(&aparte);
+        Ok(Box::new(Rc::clone(&aparte).send_iq(initiate).then(move |answer| match answer {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                refused_aparte.get_plugin_mut::<plugins::filetransfer::FileTransferPlugin>().unwrap().cancel(&sid);
+                Err(format!("Cannot send {} to {}: {}", path, to, err))
+            },
+        })))
+    }
+}
+
+/// Time given to connections closed on quit to write their last stanzas
+const QUIT_DELAY: Duration = Duration::from_millis(500);
+
+/// Quit cleanly: leave channels and go offline with a status, send what waits for the rate
+/// limit, then end the streams and restore the terminal. The history is written as messages come,
+/// its database being closed on quit.
+fn quit_cleanly(aparte: Rc<Aparte>, status: Option<&str>) {
+    // Every account leaves the channels it joined then goes offline
+    for account in aparte.connections() {
+        let leaves: Vec<Element> = match aparte.get_plugin::<plugins::conversation::ConversationPlugin>() {
+            Some(conversations) => conversations.channels()
+                .filter(|channel| channel.account == account)
+                .map(|channel| plugins::conversation::ConversationPlugin::leave_presence(channel, status))
+                .collect(),
+            None => Vec::new(),
+        };
+        for leave in leaves {
+            aparte.send_on(&account, leave);
+        }
+
+        let mut presence = Presence::new(PresenceType::Unavailable);
+        if let Some(status) = status {
+            presence.statuses.insert(String::new(), status.to_string());
+        }
+        aparte.send_on(&account, presence.into());
+    }
+    aparte.close_connections();
+
+    aparte.event(Event::Quit);
+}
+
+command_def!{
+    quit,
+    r#"/quit [<message>]
+
+  message  Status shown to your contacts and in the channels you leave
+
+Description:
+  Quit Aparté, leaving channels and going offline. SIGTERM quits the same
+  way.
+
+Examples:
+  /quit
+  /quit "See you tomorrow""#,
+    (optional) message,
+    |aparte, _command| {
+        quit_cleanly(aparte, message.as_deref());
+
+        Ok(())
+    }
+}
+
+command_def!{
+    help,
+    r#"/help <command>
+
+  command       Name of command
+
+Description:
+  Print help of a given command.
+
+Examples:
+  /help help
+  /help win"#,
+    cmd: {
+        completion: |aparte, _command| {
+            aparte.commands.iter().map(|c| c.0.to_string()).collect()
+        }
+    },
+    |aparte, _command| {
+        let command = aparte.commands.get(&cmd);
+        match command {
+            Some(command) => Rc::clone(&aparte).log(i18n::help(&command.help)),
+            None => Rc::clone(&aparte).log(i18n::trf("Unknown command {}", &[&cmd])),
+        }
+
+        Ok(())
+    }
+}
+
+fn main() {
+    const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+    let mut args = std::env::args().skip_while(|arg| arg != "--profile").skip(1);
+    if let Some(name) = args.next() {
+        if let Err(err) = profile::set(&name) {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    }
+
+    let aparte_data = profile::data_dir();
+
+    if let Err(e) = std::fs::create_dir_all(&aparte_data) {
+        panic!("Cannot create aparté data dir: {}", e);
+    }
+
+    let aparte_log = aparte_data.join("aparte.log");
+    if let Err(e) = simple_logging::log_to_file(aparte_log, LevelFilter::Debug) {
+        panic!("Cannot setup log to file: {}", e);
+    }
+
+    if std::env::args().any(|arg| arg == "--attach") {
+        if let Err(err) = relay::attach() {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let detached = std::env::args().any(|arg| arg == "--detached");
+
+    let aparte_conf = profile::config_dir();
+
+    if let Err(e) = std::fs::create_dir_all(&aparte_conf) {
+        panic!("Cannot create aparté data dir: {}", e);
+    }
+
+    let config = aparte_conf.join("config.toml");
+
+    info!("Starting aparté");
+
+    let first_run = std::fs::metadata(&config).map(|metadata| metadata.len() == 0).unwrap_or(true);
+    if first_run && termion::is_tty(&std::io::stdin()) {
+        if let Err(err) = wizard::run(&config) {
+            println!("{}", err);
+            if let Err(err) = std::fs::write(&config, "[accounts]\n") {
+                panic!("Cannot write config file: {}", err);
+            }
+        }
+    }
+
+    if detached {
+        if let Err(err) = relay::daemonize() {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    let mut aparte = Aparte::new(config);
+    if let Err(err) = i18n::load(aparte.config.locale.as_deref()) {
+        warn!("{}", err);
+    }
+    aparte.add_plugin(plugins::disco::Disco::new());
+    for name in aparte.config.plugins.keys() {
+        if !OPTIONAL_PLUGINS.contains(&name.as_str()) {
+            warn!("Unknown optional plugin {} in config", name);
+        }
+    }
+
+    aparte.add_plugin(plugins::contact::ContactPlugin::new());
+    aparte.add_plugin(plugins::conversation::ConversationPlugin::new());
+    let relay = relay::Relay::new();
+    match detached {
+        true => aparte.add_plugin(plugins::ui::UIPlugin::relayed(relay.clone())),
+        false => aparte.add_plugin(plugins::ui::UIPlugin::new()),
+    }
+    aparte.add_plugin(plugins::urls::UrlsPlugin::new());
+    aparte.add_plugin(plugins::preview::PreviewPlugin::new());
+    aparte.add_plugin(plugins::xmlconsole::XmlConsolePlugin::new());
+    aparte.add_plugin(plugins::slowmode::SlowModePlugin::new());
+    aparte.add_plugin(plugins::outbox::OutboxPlugin::new());
+    aparte.add_plugin(plugins::translate::TranslatePlugin::new());
+    aparte.add_plugin(plugins::rooms::RoomsPlugin::new());
+    aparte.add_plugin(plugins::mucadmin::MucAdminPlugin::new());
+    aparte.add_plugin(plugins::invitations::InvitationsPlugin::new());
+    aparte.add_plugin(plugins::reconnect::ReconnectPlugin::new());
+    aparte.add_plugin(plugins::register::RegisterPlugin::new());
+    aparte.add_plugin(plugins::retention::RetentionPlugin::new());
+    aparte.add_plugin(plugins::gateway::GatewayPlugin::new());
+    aparte.add_plugin(plugins::caps::CapsPlugin::new());
+    aparte.add_plugin(plugins::triggers::TriggersPlugin::new());
+    aparte.add_plugin(plugins::bandwidth::BandwidthPlugin::new());
+    aparte.add_plugin(plugins::confirm::ConfirmPlugin::new());
+    aparte.add_plugin(plugins::hooks::HooksPlugin::new());
+    aparte.add_plugin(plugins::certificates::CertificatesPlugin::new());
+    aparte.add_plugin(plugins::filetransfer::FileTransferPlugin::new());
+    aparte.add_plugin(plugins::calls::CallsPlugin::new());
+    if aparte.config.plugin_enabled("carbons") {
+        aparte.add_plugin(plugins::carbons::CarbonsPlugin::new());
+    }
+    if aparte.config.plugin_enabled("blocking") {
+        aparte.add_plugin(plugins::blocking::BlockingPlugin::new());
+    }
+    if aparte.config.plugin_enabled("moved") {
+        aparte.add_plugin(plugins::moved::MovedPlugin::new());
+    }
+    if aparte.config.plugin_enabled("bookmarks") {
+        aparte.add_plugin(plugins::bookmarks::BookmarksPlugin::new());
+    }
+    if aparte.config.plugin_enabled("notes") {
+        aparte.add_plugin(plugins::notes::NotesPlugin::new());
+    }
+    if aparte.config.plugin_enabled("history") {
+        aparte.add_plugin(plugins::history::HistoryPlugin::new());
+    }
+    if aparte.config.plugin_enabled("notifications") {
+        aparte.add_plugin(plugins::notifications::NotificationsPlugin::new());
+    }
+    if aparte.config.plugin_enabled("mentions") {
+        aparte.add_plugin(plugins::mentions::MentionsPlugin::new());
+    }
+    if aparte.config.plugin_enabled("health") {
+        aparte.add_plugin(plugins::health::HealthPlugin::new());
+    }
+    if aparte.config.plugin_enabled("scripts") {
+        aparte.add_plugin(plugins::scripts::ScriptsPlugin::new());
+    }
+    if aparte.config.plugin_enabled("away") {
+        aparte.add_plugin(plugins::away::AwayPlugin::new());
+    }
+    if aparte.config.plugin_enabled("pep") {
+        aparte.add_plugin(plugins::pep::PepPlugin::new());
+    }
+
+    aparte.add_command(help());
+    aparte.add_command(connect());
+    aparte.add_command(register());
+    aparte.add_command(password());
+    aparte.add_command(win());
+    aparte.add_command(split());
+    aparte.add_command(vsplit());
+    aparte.add_command(only());
+    aparte.add_command(edit());
+    aparte.add_command(mouse());
+    aparte.add_command(msg());
+    aparte.add_command(join());
+    aparte.add_command(roster());
+    if aparte.has_plugin::<plugins::moved::MovedPlugin>() {
+        aparte.add_command(moved());
+    }
+    if aparte.has_plugin::<plugins::blocking::BlockingPlugin>() {
+        aparte.add_command(block());
+        aparte.add_command(unblock());
+        aparte.add_command(report());
+    }
+    if aparte.has_plugin::<plugins::notes::NotesPlugin>() {
+        aparte.add_command(note());
+    }
+    aparte.add_command(whois());
+    if aparte.has_plugin::<plugins::pep::PepPlugin>() {
+        aparte.add_command(tune());
+        aparte.add_command(mood());
+        aparte.add_command(activity());
+    }
+    aparte.add_command(ping());
+    aparte.add_command(presence());
+    aparte.add_command(theme());
+    aparte.add_command(emoji());
+    if aparte.has_plugin::<plugins::notifications::NotificationsPlugin>() {
+        aparte.add_command(mute());
+        aparte.add_command(unmute());
+        aparte.add_command(notify());
+    }
+    aparte.add_command(retention());
+    aparte.add_command(set());
+    aparte.add_command(unset());
+    aparte.add_command(accept_file());
+    aparte.add_command(decline_file());
+    aparte.add_command(sendfile());
+    aparte.add_command(confirm());
+    aparte.add_command(scripts());
+    aparte.add_command(trigger());
+    aparte.add_command(lang());
+    aparte.add_command(resend());
+    aparte.add_command(cancel());
+    aparte.add_command(translate());
+    aparte.add_command(open());
+    aparte.add_command(urls());
+    aparte.add_command(copy());
+    aparte.add_command(disco());
+    aparte.add_command(rooms());
+    aparte.add_command(gateway());
+    aparte.add_command(nick());
+    aparte.add_command(kick());
+    aparte.add_command(ban());
+    aparte.add_command(voice());
+    aparte.add_command(op());
+    aparte.add_command(affiliation());
+    aparte.add_command(subject());
+    aparte.add_command(topic());
+    aparte.add_command(invite());
+    aparte.add_command(accept());
+    aparte.add_command(decline());
+    aparte.add_command(configure());
+    // Mentions are kept in the history
+    if aparte.has_plugin::<plugins::mentions::MentionsPlugin>() && aparte.has_plugin::<plugins::history::HistoryPlugin>() {
+        aparte.add_command(mentions());
+    }
+    aparte.add_command(export());
+    aparte.add_command(import());
+    aparte.add_command(profile());
+    aparte.add_command(xmlconsole());
+    aparte.add_command(xmlsend());
+    aparte.add_command(pubsub());
+    aparte.add_command(connstat());
+    aparte.add_command(bandwidth());
+    aparte.add_command(reconnect());
+    aparte.add_command(certificate());
+    aparte.add_command(quit());
+
+    aparte.init().unwrap();
+
+    let aparte = aparte.into_rc();
+
+    Rc::clone(&aparte).log(r#"
+▌ ▌   ▜               ▐      ▞▀▖         ▐   ▞
+▌▖▌▞▀▖▐ ▞▀▖▞▀▖▛▚▀▖▞▀▖ ▜▀ ▞▀▖ ▙▄▌▛▀▖▝▀▖▙▀▖▜▀ ▞▀▖
+▙▚▌▛▀ ▐ ▌ ▖▌ ▌▌▐ ▌▛▀  ▐ ▖▌ ▌ ▌ ▌▙▄▘▞▀▌▌  ▐ ▖▛▀
+▘ ▘▝▀▘ ▘▝▀ ▝▀ ▘▝ ▘▝▀▘  ▀ ▝▀  ▘ ▘▌  ▝▀▘▘   ▀ ▝▀▘
+"#.to_string());
+    Rc::clone(&aparte).log(format!("Version: {}", VERSION));
+
+    let mut rt = Runtime::new().unwrap();
+    let command_stream = {
+        let ui = aparte.get_plugin::<plugins::ui::UIPlugin>().unwrap();
+        ui.command_stream(Rc::clone(&aparte))
+    };
+
+    let spelling = {
+        let mut ui = aparte.get_plugin_mut::<plugins::ui::UIPlugin>().unwrap();
+        ui.listen_spelling(Rc::clone(&aparte))
+    };
+    rt.spawn(spelling);
+
+    let sig_aparte = Rc::clone(&aparte); // TODO use ARC ?
+    // Closing the terminal a session was detached from doesn't end it
+    let signals = match detached {
+        true => vec![signal_hook::SIGWINCH, signal_hook::SIGTERM, signal_hook::SIGHUP],
+        false => vec![signal_hook::SIGWINCH, signal_hook::SIGTERM],
+    };
+    let signals = Signals::new(&signals).unwrap().into_async().unwrap().for_each(move |sig| {
+        match sig {
+            signal_hook::SIGTERM => quit_cleanly(Rc::clone(&sig_aparte), None),
+            sig => Rc::clone(&sig_aparte).event(Event::Signal(sig)),
+        }
+        Ok(())
     }).map_err(|e| panic!("{}", e));
 
     rt.spawn(signals);
 
+    let autoconnect: Vec<String> = aparte.config.accounts.values().filter(|account| account.autoconnect).map(|account| account.login.clone()).collect();
+    for login in autoconnect {
+        let connect_aparte = Rc::clone(&aparte);
+        rt.spawn(future::lazy(move || {
+            let command = Command::new(vec!["connect".to_string(), login]);
+            if let Err(err) = Rc::clone(&connect_aparte).parse_command(command) {
+                connect_aparte.log(err);
+            }
+            Ok(())
+        }));
+    }
+
+    let mut args = std::env::args().skip_while(|arg| arg != "--register").skip(1);
+    if let Some(server) = args.next() {
+        let register_aparte = Rc::clone(&aparte);
+        rt.spawn(future::lazy(move || {
+            let command = Command::new(vec!["register".to_string(), server]);
+            if let Err(err) = Rc::clone(&register_aparte).parse_command(command) {
+                register_aparte.log(err);
+            }
+            Ok(())
+        }));
+    }
+
+    rt.spawn(control::serve(Rc::clone(&aparte)));
+    if detached {
+        rt.spawn(relay::serve(Rc::clone(&aparte), relay));
+    }
+
+    #[cfg(feature = "simulate")]
+    {
+        if std::env::args().any(|arg| arg == "--simulate") {
+            rt.spawn(simulate::start(Rc::clone(&aparte)));
+        }
+    }
+
+    #[cfg(feature = "fakeserver")]
+    {
+        if let Some(log) = std::env::args().skip_while(|arg| arg != "--replay").nth(1) {
+            rt.spawn(fakeserver::replay(Rc::clone(&aparte), &PathBuf::from(log)));
+        }
+    }
+
+    let quit_aparte = Rc::clone(&aparte);
     rt.block_on(command_stream.for_each(move |command_or_message| {
         match command_or_message {
-            CommandOrMessage::Message(message) => {
-                Rc::clone(&aparte).event(Event::Message(message.clone()));
-                if let Ok(xmpp_message) = Element::try_from(message) {
-                    aparte.send(xmpp_message);
-                }
-            }
+            CommandOrMessage::Message(message) => send_message(Rc::clone(&aparte), message),
             CommandOrMessage::Command(command) => {
                 match Rc::clone(&aparte).parse_command(command.clone()) {
                     Err(err) => Rc::clone(&aparte).log(err),
@@ -432,4 +3557,67 @@ fn main() {
 
         Ok(())
     }));
+
+    if quit_aparte.is_closing() {
+        let _ = rt.block_on(tokio::timer::Delay::new(Instant::now() + QUIT_DELAY));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Async;
+    use futures::unsync::mpsc;
+    use tokio_xmpp::Packet;
+
+    #[test]
+    fn test_quit_cleanly() {
+        let aparte = fakeserver::aparte(|aparte| aparte.add_plugin(plugins::conversation::ConversationPlugin::new()));
+        let features = Element::builder("features").ns("http://etherx.jabber.org/streams").build();
+        let mut streams = Vec::new();
+        for (account, channel) in &[("romeo@montague.lit/orchard", "verona@chat.montague.lit/romeo"), ("juliet@capulet.lit/balcony", "capulet@chat.capulet.lit/juliet")] {
+            let account = FullJid::from_str(account).unwrap();
+            let (sink, stream) = mpsc::unbounded();
+            aparte.add_connection(account.clone(), sink);
+            aparte.connection_online(&account, features.clone());
+            Rc::clone(&aparte).event(Event::Join(account, FullJid::from_str(channel).unwrap()));
+            streams.push(stream);
+        }
+        quit_cleanly(Rc::clone(&aparte), Some("Farewell"));
+        assert!(aparte.is_closing());
+        let sent: Vec<Vec<String>> = streams.iter_mut().map(|stream| future::lazy(|| {
+            let mut sent = Vec::new();
+            while let Ok(Async::Ready(Some(Packet::Stanza(element)))) = stream.poll() {
+                sent.push(format!("{} {}", element.name(), element.attr("to").unwrap_or("")));
+            }
+            Ok::<_, ()>(sent)
+        }).wait().unwrap()).collect();
+        assert_eq!(sent[0], vec!["presence verona@chat.montague.lit/romeo", "presence "]);
+        assert_eq!(sent[1], vec!["presence capulet@chat.capulet.lit/juliet", "presence "]);
+    }
+
+    #[test]
+    fn test_pubsub_examples() {
+        // Examples of the help work as typed
+        let help = pubsub().help;
+        for example in help.lines().filter(|line| line.starts_with("  /pubsub ")) {
+            let command = Command::try_from(example.trim()).unwrap();
+            let action = pubsub::Action::from_str(&command.args[1]).unwrap();
+            let service = Jid::from_str(&command.args[2]).unwrap();
+            let args = command.args.get(4..).map(|args| args.join(" ")).unwrap_or_default();
+            let account = BareJid::from_str("me@server.tld").unwrap();
+            assert!(pubsub::request(action, service, command.args.get(3).map(String::as_str), &args, &account).is_ok(), "{}", example);
+        }
+    }
+
+    #[test]
+    fn test_help_translations() {
+        // Translations of help are of the current help of commands, they wouldn't be shown otherwise
+        let helps = [help().help, msg().help];
+        for (language, catalog) in i18n::built_in() {
+            for translated in catalog.translated_helps() {
+                assert!(helps.contains(&translated), "Stale help translation in {}: {}", language, translated.lines().next().unwrap_or(""));
+            }
+        }
+    }
 }
@@ -1,29 +1,177 @@
 use chrono::{Utc, DateTime};
 use std::convert::TryFrom;
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::hash;
+use std::rc::Rc;
 use uuid::Uuid;
 use xmpp_parsers::{BareJid, Jid};
 
+/// Size below which the interned bare JIDs are never pruned
+const MIN_INTERNED: usize = 64;
+
+thread_local! {
+    static BARE_JIDS: RefCell<Interned> = RefCell::new(Interned { jids: HashSet::new(), prune_at: MIN_INTERNED });
+}
+
+struct Interned {
+    jids: HashSet<Rc<BareJid>>,
+    /// Size at which the bare JIDs no message points to anymore get dropped
+    prune_at: usize,
+}
+
+/// Shared handle on the bare part of a JID. Messages of a conversation all point to the same
+/// bare JID instead of each holding its own copy, which is forgotten once no message needs it.
+pub fn intern(jid: &Jid) -> Rc<BareJid> {
+    let bare = match jid {
+        Jid::Bare(jid) => jid.clone(),
+        Jid::Full(jid) => jid.clone().into(),
+    };
+
+    BARE_JIDS.with(|interned| {
+        let mut interned = interned.borrow_mut();
+        if let Some(jid) = interned.jids.get(&bare) {
+            return Rc::clone(jid);
+        }
+
+        // Pruning each time the table doubles keeps interning amortized constant time
+        if interned.jids.len() >= interned.prune_at {
+            interned.jids.retain(|jid| Rc::strong_count(jid) > 1);
+            interned.prune_at = usize::max(2 * interned.jids.len(), MIN_INTERNED);
+        }
+
+        let jid = Rc::new(bare);
+        interned.jids.insert(Rc::clone(&jid));
+        jid
+    })
+}
+
+/// Action described by a body starting with /me (XEP-0245), without the /me prefix
+pub fn action(body: &str) -> Option<&str> {
+    if body.starts_with("/me ") {
+        Some(&body[4..])
+    } else {
+        None
+    }
+}
+
+/// Whether a text mentions a nick, as a whole word and regardless of its case, so that "Al"
+/// isn't mentioned by "always" but is by "al: hi"
+pub fn mentions(text: &str, nick: &str) -> bool {
+    if nick.is_empty() {
+        return false;
+    }
+    let text = text.to_lowercase();
+    let nick = nick.to_lowercase();
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    text.match_indices(&nick).any(|(index, _)| {
+        let before = text[..index].chars().next_back();
+        let after = text[index + nick.len()..].chars().next();
+        !before.map_or(false, is_word) && !after.map_or(false, is_word)
+    })
+}
+
+/// URLs found in a text, with their byte offset
+pub fn urls(text: &str) -> Vec<(usize, &str)> {
+    let mut urls = Vec::new();
+    let mut start = None;
+    for (index, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+        match (start, c.is_whitespace()) {
+            (None, false) => start = Some(index),
+            (Some(word_start), true) => {
+                start = None;
+                let word = &text[word_start..index];
+                let offset = match ["http://", "https://", "ftp://"].iter().filter_map(|scheme| word.find(scheme)).min() {
+                    Some(offset) => offset,
+                    None => continue,
+                };
+                let mut url = &word[offset..];
+                // Leave out punctuation following the URL, but keep closing parenthesis that are
+                // part of it
+                loop {
+                    let trimmed = url.trim_end_matches(|c| ".,;:!?'\"<>".contains(c));
+                    url = if trimmed.ends_with(')') && trimmed.matches('(').count() < trimmed.matches(')').count() {
+                        &trimmed[..trimmed.len() - 1]
+                    } else {
+                        trimmed
+                    };
+                    if url == trimmed {
+                        break;
+                    }
+                }
+                if !url.ends_with("://") {
+                    urls.push((word_start + offset, url));
+                }
+            },
+            _ => {},
+        }
+    }
+    urls
+}
+
+/// Languages of the body to pick from a multi-language message, best first. A regional language
+/// also accepts its primary language, so "fr-CA" accepts "fr" bodies.
+pub fn preferred_langs(lang: Option<&str>) -> Vec<&str> {
+    match lang {
+        Some(lang) => match lang.find('-') {
+            Some(index) => vec![lang, &lang[..index]],
+            None => vec![lang],
+        },
+        None => Vec::new(),
+    }
+}
+
+/// Whether a text is a well formed language tag (BCP 47), as "en" or "pt-BR"
+pub fn is_lang_tag(tag: &str) -> bool {
+    tag.split('-').enumerate().all(|(index, subtag)| {
+        let valid_chars = match index {
+            0 => subtag.chars().all(|c| c.is_ascii_alphabetic()),
+            _ => subtag.chars().all(|c| c.is_ascii_alphanumeric()),
+        };
+        valid_chars && subtag.len() >= 1 && subtag.len() <= 8
+    })
+}
+
 #[derive(Debug, Clone)]
 pub struct ChatMessage {
     pub id: String,
     pub timestamp: DateTime<Utc>,
-    pub from: BareJid,
-    pub from_full: Jid,
-    pub to: BareJid,
-    pub to_full: Jid,
-    pub body: String,
+    pub from: Rc<BareJid>,
+    pub from_full: Rc<Jid>,
+    pub to: Rc<BareJid>,
+    pub to_full: Rc<Jid>,
+    pub body: Rc<str>,
+    /// Error returned instead of delivering an outgoing message
+    pub error: Option<Rc<str>>,
+    /// Outgoing message waiting for a connection to be sent
+    pub pending: bool,
+    /// Translation requested with /translate
+    pub translation: Option<Rc<str>>,
+    /// Language of the body (xml:lang), unspecified when None
+    pub lang: Option<Rc<str>>,
+    /// URL of a file shared out of band (XEP-0066), as uploaded files are
+    pub oob: Option<Rc<str>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct GroupchatMessage {
     pub id: String,
     pub timestamp: DateTime<Utc>,
-    pub from: BareJid,
-    pub from_full: Jid,
-    pub to: BareJid,
-    pub to_full: Jid,
-    pub body: String,
+    pub from: Rc<BareJid>,
+    pub from_full: Rc<Jid>,
+    pub to: Rc<BareJid>,
+    pub to_full: Rc<Jid>,
+    pub body: Rc<str>,
+    /// Error returned instead of delivering an outgoing message
+    pub error: Option<Rc<str>>,
+    /// Outgoing message waiting for a connection to be sent
+    pub pending: bool,
+    /// Translation requested with /translate
+    pub translation: Option<Rc<str>>,
+    /// Language of the body (xml:lang), unspecified when None
+    pub lang: Option<Rc<str>>,
+    /// URL of a file shared out of band (XEP-0066), as uploaded files are
+    pub oob: Option<Rc<str>>,
 }
 
 #[derive(Debug, Clone)]
@@ -48,90 +196,70 @@ pub enum Message {
 
 impl Message {
     pub fn incoming_chat<I: Into<String>>(id: I, timestamp: DateTime<Utc>, from_full: &Jid, to_full: &Jid, body: &str) -> Self {
-        let from = match from_full {
-            Jid::Bare(from_full) => from_full.clone(),
-            Jid::Full(from_full) => from_full.clone().into(),
-        };
-
-        let to = match to_full {
-            Jid::Bare(to_full) => to_full.clone(),
-            Jid::Full(to_full) => to_full.clone().into(),
-        };
-
         Message::Incoming(XmppMessage::Chat(ChatMessage {
             id: id.into(),
             timestamp: timestamp,
-            from: from,
-            from_full: from_full.clone(),
-            to: to.clone(),
-            to_full: to_full.clone(),
-            body: body.to_string(),
+            from: intern(from_full),
+            from_full: Rc::new(from_full.clone()),
+            to: intern(to_full),
+            to_full: Rc::new(to_full.clone()),
+            body: Rc::from(body),
+            error: None,
+            pending: false,
+            translation: None,
+            lang: None,
+            oob: None,
         }))
     }
 
     pub fn outgoing_chat<I: Into<String>>(id: I, timestamp: DateTime<Utc>, from_full: &Jid, to_full: &Jid, body: &str) -> Self {
-        let from = match from_full {
-            Jid::Bare(from_full) => from_full.clone(),
-            Jid::Full(from_full) => from_full.clone().into(),
-        };
-
-        let to = match to_full {
-            Jid::Bare(to_full) => to_full.clone(),
-            Jid::Full(to_full) => to_full.clone().into(),
-        };
-
         Message::Outgoing(XmppMessage::Chat(ChatMessage {
             id: id.into(),
             timestamp: timestamp,
-            from: from,
-            from_full: from_full.clone(),
-            to: to.clone(),
-            to_full: to_full.clone(),
-            body: body.to_string(),
+            from: intern(from_full),
+            from_full: Rc::new(from_full.clone()),
+            to: intern(to_full),
+            to_full: Rc::new(to_full.clone()),
+            body: Rc::from(body),
+            error: None,
+            pending: false,
+            translation: None,
+            lang: None,
+            oob: None,
         }))
     }
 
     pub fn incoming_groupchat<I: Into<String>>(id: I, timestamp: DateTime<Utc>, from_full: &Jid, to_full: &Jid, body: &str) -> Self {
-        let from = match from_full {
-            Jid::Bare(from_full) => from_full.clone(),
-            Jid::Full(from_full) => from_full.clone().into(),
-        };
-
-        let to = match to_full {
-            Jid::Bare(to_full) => to_full.clone(),
-            Jid::Full(to_full) => to_full.clone().into(),
-        };
-
         Message::Incoming(XmppMessage::Groupchat(GroupchatMessage {
             id: id.into(),
             timestamp: timestamp,
-            from: from,
-            from_full: from_full.clone(),
-            to: to.clone(),
-            to_full: to_full.clone(),
-            body: body.to_string(),
+            from: intern(from_full),
+            from_full: Rc::new(from_full.clone()),
+            to: intern(to_full),
+            to_full: Rc::new(to_full.clone()),
+            body: Rc::from(body),
+            error: None,
+            pending: false,
+            translation: None,
+            lang: None,
+            oob: None,
         }))
     }
 
     pub fn outgoing_groupchat<I: Into<String>>(id: I, timestamp: DateTime<Utc>, from_full: &Jid, to_full: &Jid, body: &str) -> Self {
-        let from = match from_full {
-            Jid::Bare(from_full) => from_full.clone(),
-            Jid::Full(from_full) => from_full.clone().into(),
-        };
-
-        let to = match to_full {
-            Jid::Bare(to_full) => to_full.clone(),
-            Jid::Full(to_full) => to_full.clone().into(),
-        };
-
         Message::Outgoing(XmppMessage::Groupchat(GroupchatMessage {
             id: id.into(),
             timestamp: timestamp,
-            from: from,
-            from_full: from_full.clone(),
-            to: to.clone(),
-            to_full: to_full.clone(),
-            body: body.to_string(),
+            from: intern(from_full),
+            from_full: Rc::new(from_full.clone()),
+            to: intern(to_full),
+            to_full: Rc::new(to_full.clone()),
+            body: Rc::from(body),
+            error: None,
+            pending: false,
+            translation: None,
+            lang: None,
+            oob: None,
         }))
     }
 
@@ -143,16 +271,94 @@ impl Message {
         })
     }
 
-    #[allow(dead_code)]
+    pub fn id(&self) -> &str {
+        match self {
+            Message::Outgoing(XmppMessage::Chat(ChatMessage { id, .. }))
+                | Message::Incoming(XmppMessage::Chat(ChatMessage { id, .. }))
+                | Message::Outgoing(XmppMessage::Groupchat(GroupchatMessage { id, .. }))
+                | Message::Incoming(XmppMessage::Groupchat(GroupchatMessage { id, .. })) => &id,
+            Message::Log(LogMessage { id, .. }) => &id,
+        }
+    }
+
+    pub fn timestamp(&self) -> &DateTime<Utc> {
+        match self {
+            Message::Outgoing(XmppMessage::Chat(ChatMessage { timestamp, .. }))
+                | Message::Incoming(XmppMessage::Chat(ChatMessage { timestamp, .. }))
+                | Message::Outgoing(XmppMessage::Groupchat(GroupchatMessage { timestamp, .. }))
+                | Message::Incoming(XmppMessage::Groupchat(GroupchatMessage { timestamp, .. })) => &timestamp,
+            Message::Log(LogMessage { timestamp, .. }) => &timestamp,
+        }
+    }
+
+    /// Mark an outgoing message as not delivered
+    pub fn set_error(&mut self, error: &str) {
+        match self {
+            Message::Outgoing(XmppMessage::Chat(message)) => message.error = Some(Rc::from(error)),
+            Message::Outgoing(XmppMessage::Groupchat(message)) => message.error = Some(Rc::from(error)),
+            _ => {},
+        }
+    }
+
+    /// Mark an outgoing message as waiting for a connection, or as sent
+    pub fn set_pending(&mut self, pending: bool) {
+        match self {
+            Message::Outgoing(XmppMessage::Chat(message)) => message.pending = pending,
+            Message::Outgoing(XmppMessage::Groupchat(message)) => message.pending = pending,
+            _ => {},
+        }
+    }
+
+    pub fn set_translation(&mut self, translation: &str) {
+        match self {
+            Message::Incoming(XmppMessage::Chat(message)) | Message::Outgoing(XmppMessage::Chat(message)) => message.translation = Some(Rc::from(translation)),
+            Message::Incoming(XmppMessage::Groupchat(message)) | Message::Outgoing(XmppMessage::Groupchat(message)) => message.translation = Some(Rc::from(translation)),
+            Message::Log(_) => {},
+        }
+    }
+
+    /// Attach the URL of a file shared out of band to a received message
+    pub fn set_oob(&mut self, url: &str) {
+        match self {
+            Message::Incoming(XmppMessage::Chat(message)) => message.oob = Some(Rc::from(url)),
+            Message::Incoming(XmppMessage::Groupchat(message)) => message.oob = Some(Rc::from(url)),
+            _ => {},
+        }
+    }
+
+    pub fn oob(&self) -> Option<&str> {
+        match self {
+            Message::Outgoing(XmppMessage::Chat(ChatMessage { oob, .. }))
+                | Message::Incoming(XmppMessage::Chat(ChatMessage { oob, .. }))
+                | Message::Outgoing(XmppMessage::Groupchat(GroupchatMessage { oob, .. }))
+                | Message::Incoming(XmppMessage::Groupchat(GroupchatMessage { oob, .. })) => oob.as_deref(),
+            Message::Log(_) => None,
+        }
+    }
+
+    /// Tag the body of an outgoing message with its language
+    pub fn set_lang(&mut self, lang: &str) {
+        match self {
+            Message::Outgoing(XmppMessage::Chat(message)) => message.lang = Some(Rc::from(lang)),
+            Message::Outgoing(XmppMessage::Groupchat(message)) => message.lang = Some(Rc::from(lang)),
+            _ => {},
+        }
+    }
+
     pub fn body(&self) -> &str {
         match self {
             Message::Outgoing(XmppMessage::Chat(ChatMessage { body, .. }))
                 | Message::Incoming(XmppMessage::Chat(ChatMessage { body, .. }))
                 | Message::Outgoing(XmppMessage::Groupchat(GroupchatMessage { body, .. }))
-                | Message::Incoming(XmppMessage::Groupchat(GroupchatMessage { body, .. }))
-                | Message::Log(LogMessage { body, .. }) => &body,
+                | Message::Incoming(XmppMessage::Groupchat(GroupchatMessage { body, .. })) => &body,
+            Message::Log(LogMessage { body, .. }) => &body,
         }
     }
+
+    /// Whether the body mentions a nick, see mentions()
+    pub fn mentions(&self, nick: &str) -> bool {
+        mentions(self.body(), nick)
+    }
 }
 
 impl hash::Hash for Message {
@@ -204,19 +410,124 @@ impl TryFrom<Message> for xmpp_parsers::Element {
                 Err(())
             },
             Message::Outgoing(XmppMessage::Chat(message)) => {
-                let mut xmpp_message = xmpp_parsers::message::Message::new(Some(Jid::Bare(message.to)));
+                let mut xmpp_message = xmpp_parsers::message::Message::new(Some(Jid::Bare((*message.to).clone())));
                 xmpp_message.id = Some(message.id);
                 xmpp_message.type_ = xmpp_parsers::message::MessageType::Chat;
-                xmpp_message.bodies.insert(String::new(), xmpp_parsers::message::Body(message.body));
+                xmpp_message.bodies.insert(message.lang.as_deref().unwrap_or("").to_string(), xmpp_parsers::message::Body(message.body.to_string()));
                 Ok(xmpp_message.into())
             },
             Message::Outgoing(XmppMessage::Groupchat(message)) => {
-                let mut xmpp_message = xmpp_parsers::message::Message::new(Some(Jid::Bare(message.to)));
+                let mut xmpp_message = xmpp_parsers::message::Message::new(Some(Jid::Bare((*message.to).clone())));
                 xmpp_message.id = Some(message.id);
                 xmpp_message.type_ = xmpp_parsers::message::MessageType::Groupchat;
-                xmpp_message.bodies.insert(String::new(), xmpp_parsers::message::Body(message.body));
+                xmpp_message.bodies.insert(message.lang.as_deref().unwrap_or("").to_string(), xmpp_parsers::message::Body(message.body.to_string()));
                 Ok(xmpp_message.into())
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_intern() {
+        let alice = intern(&Jid::from_str("alice@example.org/phone").unwrap());
+        assert!(Rc::ptr_eq(&alice, &intern(&Jid::from_str("alice@example.org/laptop").unwrap())));
+
+        // Bare JIDs nothing points to anymore are dropped as new ones come
+        for i in 0..10 * MIN_INTERNED {
+            intern(&Jid::from_str(&format!("contact{}@example.org", i)).unwrap());
+        }
+        BARE_JIDS.with(|interned| assert!(interned.borrow().jids.len() <= 2 * MIN_INTERNED));
+        assert!(Rc::ptr_eq(&alice, &intern(&Jid::from_str("alice@example.org").unwrap())));
+    }
+
+    #[test]
+    fn test_me_action() {
+        assert_eq!(action("/me waves"), Some("waves"));
+        assert_eq!(action("/meh"), None);
+        assert_eq!(action("/me"), None);
+        assert_eq!(action("hello /me waves"), None);
+    }
+
+    #[test]
+    fn test_mentions() {
+        assert!(mentions("nick: hello", "nick"));
+        assert!(mentions("hello Nick!", "nick"));
+        assert!(mentions("cc @nick, [nick]", "nick"));
+        assert!(mentions("hi nick.name", "nick.name"));
+        assert!(!mentions("nickname", "nick"));
+        assert!(!mentions("the_nick", "nick"));
+        assert!(mentions("Al: hi", "al") && !mentions("always", "al"));
+        assert!(!mentions("anything", ""));
+    }
+
+    #[test]
+    fn test_preferred_langs() {
+        assert_eq!(preferred_langs(None), Vec::<&str>::new());
+        assert_eq!(preferred_langs(Some("fr")), vec!["fr"]);
+        assert_eq!(preferred_langs(Some("fr-CA")), vec!["fr-CA", "fr"]);
+
+        assert!(is_lang_tag("en") && is_lang_tag("pt-BR") && is_lang_tag("zh-Hant-TW"));
+        assert!(!is_lang_tag("") && !is_lang_tag("fr-") && !is_lang_tag("1en") && !is_lang_tag("français"));
+    }
+
+    #[test]
+    fn test_outgoing_lang() {
+        let us = Jid::from_str("me@server.tld/aparte").unwrap();
+        let contact = Jid::from_str("contact@server.tld").unwrap();
+
+        let mut message = Message::outgoing_chat("1", Utc::now(), &us, &contact, "Bonjour");
+        message.set_lang("fr");
+        let element = xmpp_parsers::Element::try_from(message).unwrap();
+        let message = xmpp_parsers::message::Message::try_from(element).unwrap();
+        assert_eq!(message.get_best_body(vec!["fr"]), Some((String::from("fr"), &xmpp_parsers::message::Body(String::from("Bonjour")))));
+        assert!(message.bodies.get("").is_none());
+    }
+
+    #[test]
+    fn test_urls() {
+        assert_eq!(urls("see https://example.org/page."), vec![(4, "https://example.org/page")]);
+        assert_eq!(urls("(http://a.tld/b_(c)) and <ftp://files.tld>"), vec![(1, "http://a.tld/b_(c)"), (26, "ftp://files.tld")]);
+        assert_eq!(urls("no link here, nor http://"), Vec::<(usize, &str)>::new());
+    }
+
+    #[test]
+    fn test_only_outgoing_messages_fail() {
+        let us = Jid::from_str("me@server.tld/aparte").unwrap();
+        let contact = Jid::from_str("contact@server.tld").unwrap();
+
+        let mut outgoing = Message::outgoing_chat("1", Utc::now(), &us, &contact, "Hello");
+        outgoing.set_error("service-unavailable");
+        match outgoing {
+            Message::Outgoing(XmppMessage::Chat(message)) => assert_eq!(message.error.as_deref(), Some("service-unavailable")),
+            _ => unreachable!(),
+        }
+
+        let mut incoming = Message::incoming_chat("2", Utc::now(), &contact, &us, "Hello");
+        incoming.set_error("service-unavailable");
+        match incoming {
+            Message::Incoming(XmppMessage::Chat(message)) => assert!(message.error.is_none()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_messages_share_bare_jids() {
+        let us = Jid::from_str("me@server.tld/aparte").unwrap();
+        let first = Message::incoming_chat("1", Utc::now(), &Jid::from_str("contact@server.tld/phone").unwrap(), &us, "Hello");
+        let second = Message::incoming_chat("2", Utc::now(), &Jid::from_str("contact@server.tld/laptop").unwrap(), &us, "Hi");
+
+        match (first, second) {
+            (Message::Incoming(XmppMessage::Chat(first)), Message::Incoming(XmppMessage::Chat(second))) => {
+                assert!(Rc::ptr_eq(&first.from, &second.from));
+                assert!(Rc::ptr_eq(&first.to, &second.to));
+                assert_eq!(first.from.to_string(), "contact@server.tld");
+            },
+            _ => unreachable!(),
+        }
+    }
+}
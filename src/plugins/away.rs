@@ -0,0 +1,184 @@
+use futures::Future;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::runtime::current_thread::TaskExecutor;
+use tokio::timer::Delay;
+use xmpp_parsers::presence::{Presence, Show as PresenceShow, Type as PresenceType};
+use xmpp_parsers::Element;
+
+use crate::config;
+use crate::core::{Plugin, Aparte, Event};
+use crate::plugins::disco::Disco;
+
+/// Delay between two checks of the idle time
+const CHECK_INTERVAL: u64 = 30;
+
+/// Our presence with an availability and a status, with our capabilities
+pub fn presence(aparte: &Aparte, show: Option<PresenceShow>, status: Option<&str>) -> Element {
+    let mut presence = Presence::new(PresenceType::None);
+    presence.show = show;
+    if let Some(status) = status {
+        presence.statuses.insert(String::new(), status.to_string());
+    }
+    presence.add_payload(aparte.get_plugin::<Disco>().unwrap().caps());
+    presence.into()
+}
+
+fn show_name(show: &Option<PresenceShow>) -> &'static str {
+    match show {
+        None => "available",
+        Some(PresenceShow::Away) => "away",
+        Some(PresenceShow::Chat) => "chat",
+        Some(PresenceShow::Dnd) => "dnd",
+        Some(PresenceShow::Xa) => "xa",
+    }
+}
+
+/// Switch the presence to away after some time without keyboard input, and back to the one
+/// chosen with /presence on activity. Only available presences are switched, an explicit away
+/// or dnd is left as is.
+pub struct AwayPlugin {
+    config: config::Away,
+    last_activity: Instant,
+    /// Availability and status chosen with /presence, or the one sent when connecting
+    show: Option<PresenceShow>,
+    status: Option<String>,
+    /// Whether the presence was switched to away for being idle
+    away: bool,
+    check_scheduled: bool,
+}
+
+impl AwayPlugin {
+    /// Remember the presence chosen with /presence, which is never overridden when it isn't an
+    /// available one
+    pub fn set_presence(&mut self, show: Option<PresenceShow>, status: Option<String>) {
+        self.show = show;
+        self.status = status;
+        self.away = false;
+    }
+
+    fn available(&self) -> bool {
+        match self.show {
+            None | Some(PresenceShow::Chat) => true,
+            Some(_) => false,
+        }
+    }
+
+    /// Whether to go away, being available and idle for long enough
+    fn idle(&mut self, now: Instant) -> bool {
+        if self.away || !self.available() || self.config.after == 0 {
+            return false;
+        }
+        self.away = now.duration_since(self.last_activity) >= Duration::from_secs(self.config.after * 60);
+        self.away
+    }
+
+    /// Whether to come back from away, the presence having been switched for being idle
+    fn active(&mut self, now: Instant) -> bool {
+        self.last_activity = now;
+        std::mem::replace(&mut self.away, false)
+    }
+
+    fn schedule_check(aparte: Rc<Aparte>) {
+        let check = Delay::new(Instant::now() + Duration::from_secs(CHECK_INTERVAL)).then(move |_| {
+            let away = match aparte.current_connection() {
+                Some(_) => {
+                    let mut plugin = aparte.get_plugin_mut::<AwayPlugin>().unwrap();
+                    match plugin.idle(Instant::now()) {
+                        true => Some((plugin.config.after, plugin.config.status.clone())),
+                        false => None,
+                    }
+                },
+                None => None,
+            };
+            if let Some((after, status)) = away {
+                aparte.send(presence(&aparte, Some(PresenceShow::Away), status.as_deref()));
+                Rc::clone(&aparte).log(format!("Presence set to away, idle for {} minutes", after));
+            }
+            AwayPlugin::schedule_check(aparte);
+            Ok(())
+        });
+
+        if let Err(err) = TaskExecutor::current().spawn_local(Box::new(check)) {
+            warn!("Cannot schedule auto-away: {:?}", err);
+        }
+    }
+}
+
+impl Plugin for AwayPlugin {
+    fn new() -> AwayPlugin {
+        Self {
+            config: config::Away::default(),
+            last_activity: Instant::now(),
+            show: Some(PresenceShow::Chat),
+            status: None,
+            away: false,
+            check_scheduled: false,
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        self.config = aparte.config.away.clone();
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Connected(_) => {
+                // As sent when connecting
+                self.set_presence(Some(PresenceShow::Chat), None);
+                // Plugins are initialized before the runtime is started
+                if !self.check_scheduled && self.config.after > 0 {
+                    self.check_scheduled = true;
+                    AwayPlugin::schedule_check(aparte);
+                }
+            },
+            Event::Activity => {
+                if self.active(Instant::now()) && aparte.current_connection().is_some() {
+                    aparte.send(presence(&aparte, self.show.clone(), self.status.as_deref()));
+                    Rc::clone(&aparte).log(format!("Presence set back to {}", show_name(&self.show)));
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for AwayPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Auto-away")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_away() {
+        let mut plugin = AwayPlugin::new();
+        let start = plugin.last_activity;
+        let later = start + Duration::from_secs(11 * 60);
+        assert!(!plugin.idle(start + Duration::from_secs(9 * 60)));
+        assert!(plugin.idle(later));
+        // Only once
+        assert!(!plugin.idle(later));
+        assert!(plugin.active(later));
+        assert!(!plugin.active(later));
+        assert!(!plugin.idle(later + Duration::from_secs(60)));
+
+        // Never over an explicit presence
+        plugin.set_presence(Some(PresenceShow::Dnd), Some(String::from("In a meeting")));
+        assert!(!plugin.idle(later + Duration::from_secs(3600)));
+        plugin.set_presence(None, None);
+        assert!(plugin.idle(later + Duration::from_secs(3600)));
+        // Choosing a presence while away cancels it
+        plugin.set_presence(Some(PresenceShow::Xa), None);
+        assert!(!plugin.active(later + Duration::from_secs(3601)));
+
+        plugin.config.after = 0;
+        plugin.set_presence(None, None);
+        assert!(!plugin.idle(later + Duration::from_secs(36000)));
+    }
+}
@@ -0,0 +1,56 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::core::{Plugin, Aparte, Event};
+
+/// Maximum number of messages fetched by an archive query in low bandwidth mode
+pub const ARCHIVE_PAGE: usize = 20;
+
+/// Number of messages channels send when joining them in low bandwidth mode
+pub const JOIN_HISTORY: u32 = 10;
+
+/// Low bandwidth mode, for tethered or metered connections: images aren't downloaded, and less
+/// history is fetched from archives and channels
+pub struct BandwidthPlugin {
+    low: bool,
+}
+
+impl BandwidthPlugin {
+    pub fn is_low(&self) -> bool {
+        self.low
+    }
+
+    pub fn set_low(&mut self, low: bool) {
+        self.low = low;
+    }
+}
+
+/// Whether the low bandwidth mode is enabled
+pub fn is_low(aparte: &Aparte) -> bool {
+    match aparte.get_plugin::<BandwidthPlugin>() {
+        Some(bandwidth) => bandwidth.is_low(),
+        None => false,
+    }
+}
+
+impl Plugin for BandwidthPlugin {
+    fn new() -> BandwidthPlugin {
+        Self {
+            low: false,
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        self.low = aparte.config.low_bandwidth;
+        Ok(())
+    }
+
+    fn on_event(&mut self, _aparte: Rc<Aparte>, _event: &Event) {
+    }
+}
+
+impl fmt::Display for BandwidthPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Low bandwidth mode")
+    }
+}
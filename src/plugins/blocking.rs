@@ -0,0 +1,237 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::{BareJid, Element, Jid, ns};
+use xmpp_parsers::blocking::{Block, BlocklistRequest, BlocklistResult, Unblock};
+use xmpp_parsers::iq::{Iq, IqType};
+
+use crate::core::{Plugin, Aparte, Event, EventKind, Priority, Propagation};
+use crate::message::{Message, XmppMessage};
+
+pub const NS_REPORTING: &str = "urn:xmpp:reporting:0";
+
+/// XEP-0377 report attached to a blocking request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Report {
+    Spam(Option<String>),
+    Abuse(Option<String>),
+}
+
+impl Report {
+    pub fn new(kind: &str, text: Option<String>) -> Result<Self, String> {
+        match kind {
+            "spam" => Ok(Report::Spam(text)),
+            "abuse" => Ok(Report::Abuse(text)),
+            kind => Err(format!("Unknown report kind {}", kind)),
+        }
+    }
+}
+
+impl From<Report> for Element {
+    fn from(report: Report) -> Element {
+        let (kind, text) = match report {
+            Report::Spam(text) => ("spam", text),
+            Report::Abuse(text) => ("abuse", text),
+        };
+
+        let mut builder = Element::builder("report").ns(NS_REPORTING)
+            .append(Element::builder(kind).ns(NS_REPORTING).build());
+        if let Some(text) = text {
+            builder = builder.append(Element::builder("text").ns(NS_REPORTING).append(text).build());
+        }
+
+        builder.build()
+    }
+}
+
+pub struct BlockingPlugin {
+    pub blocked: HashSet<String>,
+}
+
+impl BlockingPlugin {
+    fn request(&self) -> Element {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let iq = Iq::from_get(id, BlocklistRequest);
+        iq.into()
+    }
+
+    /// Whether a message comes from a blocked contact or server, which the server may still relay
+    /// through carbons or archives
+    fn is_blocked(&self, message: &Message) -> bool {
+        match message {
+            Message::Incoming(XmppMessage::Chat(message)) => {
+                self.blocked.contains(&message.from.to_string()) || self.blocked.contains(&message.from.domain)
+            },
+            _ => false,
+        }
+    }
+
+    /// Build a blocking request for `jid`, reporting it to the server operators if a report is
+    /// given.
+    pub fn block(&self, jid: &str, report: Option<Report>) -> Result<Element, String> {
+        let jid = match Jid::from_str(jid) {
+            Ok(jid) => jid,
+            Err(err) => return Err(format!("Invalid JID {}: {}", jid, err)),
+        };
+
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let mut iq: Element = Iq::from_set(id, Block { items: vec![jid] }).into();
+
+        if let Some(report) = report {
+            let item = iq.get_child_mut("block", ns::BLOCKING).and_then(|block| block.get_child_mut("item", ns::BLOCKING));
+            if let Some(item) = item {
+                item.append_child(report.into());
+            }
+        }
+
+        Ok(iq)
+    }
+
+    pub fn unblock(&self, jid: &str) -> Result<Element, String> {
+        let jid = match Jid::from_str(jid) {
+            Ok(jid) => jid,
+            Err(err) => return Err(format!("Invalid JID {}: {}", jid, err)),
+        };
+
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        Ok(Iq::from_set(id, Unblock { items: vec![jid] }).into())
+    }
+}
+
+impl Plugin for BlockingPlugin {
+    fn new() -> BlockingPlugin {
+        Self {
+            blocked: HashSet::new(),
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        // Before the messages get stored or displayed
+        aparte.subscribe(EventKind::Message, Priority::HIGH, |blocking: &mut BlockingPlugin, _aparte, event| {
+            match event {
+                Event::Message(message) if blocking.is_blocked(message) => Propagation::Stop,
+                _ => Propagation::Continue,
+            }
+        });
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Connected(_jid) => aparte.send(self.request()),
+            Event::Iq(account, iq) => {
+                match iq.payload.clone() {
+                    IqType::Result(Some(payload)) => {
+                        if let Ok(blocklist) = BlocklistResult::try_from(payload) {
+                            self.blocked = blocklist.items.iter().map(|jid| jid.to_string()).collect();
+                        }
+                    },
+                    IqType::Set(payload) => {
+                        // Blocking pushes sent by our server once a request is processed, and by
+                        // no one else
+                        let bare: BareJid = account.clone().into();
+                        match &iq.from {
+                            None => {},
+                            Some(Jid::Bare(from)) if *from == bare => {},
+                            Some(_) => return,
+                        }
+                        if payload.has_ns(ns::BLOCKING) {
+                            let mut result = Iq::from_result(iq.id.clone(), None::<BlocklistResult>);
+                            result.to = iq.from.clone();
+                            aparte.send_on(account, result.into());
+                        }
+
+                        if let Ok(block) = Block::try_from(payload.clone()) {
+                            for jid in block.items {
+                                Rc::clone(&aparte).log(format!("Blocked {}", jid));
+                                self.blocked.insert(jid.to_string());
+                            }
+                        } else if let Ok(unblock) = Unblock::try_from(payload) {
+                            if unblock.items.is_empty() {
+                                self.blocked.clear();
+                            }
+                            for jid in unblock.items {
+                                Rc::clone(&aparte).log(format!("Unblocked {}", jid));
+                                self.blocked.remove(&jid.to_string());
+                            }
+                        }
+                    },
+                    _ => {},
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for BlockingPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0191: Blocking Command")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xmpp_parsers::FullJid;
+    use crate::fakeserver::{self, FakeServer};
+
+    #[test]
+    fn test_block_with_report() {
+        let blocking = BlockingPlugin::new();
+        let iq = blocking.block("spammer@server.tld", Some(Report::Spam(Some("Join spam".to_string())))).unwrap();
+
+        let item = iq.get_child("block", ns::BLOCKING).unwrap().get_child("item", ns::BLOCKING).unwrap();
+        assert_eq!(item.attr("jid"), Some("spammer@server.tld"));
+
+        let report = item.get_child("report", NS_REPORTING).unwrap();
+        assert!(report.has_child("spam", NS_REPORTING));
+        assert_eq!(report.get_child("text", NS_REPORTING).unwrap().text(), "Join spam");
+    }
+
+    #[test]
+    fn test_block_without_report() {
+        let blocking = BlockingPlugin::new();
+        let iq = blocking.block("spammer@server.tld", None).unwrap();
+
+        let item = iq.get_child("block", ns::BLOCKING).unwrap().get_child("item", ns::BLOCKING).unwrap();
+        assert!(!item.has_child("report", NS_REPORTING));
+    }
+
+    #[test]
+    fn test_blocked_messages() {
+        let mut blocking = BlockingPlugin::new();
+        blocking.blocked.insert(String::from("spammer@server.tld"));
+        blocking.blocked.insert(String::from("spam.tld"));
+        let us = Jid::from_str("me@server.tld/aparte").unwrap();
+        let message = |from: &str| Message::incoming_chat("id", chrono::Utc::now(), &Jid::from_str(from).unwrap(), &us, "Buy now");
+
+        assert!(blocking.is_blocked(&message("spammer@server.tld/bot")));
+        assert!(blocking.is_blocked(&message("anyone@spam.tld/bot")));
+        assert!(!blocking.is_blocked(&message("contact@server.tld/phone")));
+        assert!(!blocking.is_blocked(&Message::outgoing_chat("id", chrono::Utc::now(), &us, &Jid::from_str("spammer@server.tld").unwrap(), "Stop")));
+    }
+
+    #[test]
+    fn test_pushes() {
+        let aparte = fakeserver::aparte(|aparte| aparte.add_plugin(BlockingPlugin::new()));
+        let account = FullJid::from_str("me@server.tld/aparte").unwrap();
+        let server = FakeServer::new()
+            .expect("<iq xmlns='jabber:client' type='get' id='blocklist'><blocklist xmlns='urn:xmpp:blocking'/></iq>")
+            .send("<iq xmlns='jabber:client' type='result' id='blocklist'><blocklist xmlns='urn:xmpp:blocking'/></iq>")
+            // Only our server tells what we block
+            .send("<iq xmlns='jabber:client' type='set' id='spoofed' from='mallory@evil.tld'><block xmlns='urn:xmpp:blocking'><item jid='juliet@capulet.lit'/></block></iq>")
+            .send("<iq xmlns='jabber:client' type='set' id='push' from='me@server.tld'><block xmlns='urn:xmpp:blocking'><item jid='spammer@server.tld'/></block></iq>")
+            .expect("<iq xmlns='jabber:client' type='result' id='push' to='me@server.tld'/>");
+        let server = fakeserver::run(&aparte, &account, server).unwrap();
+        assert!(server.is_done());
+        assert!(!server.received.iter().any(|stanza| stanza.attr("id") == Some("spoofed")));
+
+        let blocking = aparte.get_plugin::<BlockingPlugin>().unwrap();
+        assert!(blocking.blocked.contains("spammer@server.tld"));
+        assert!(!blocking.blocked.contains("juliet@capulet.lit"));
+    }
+}
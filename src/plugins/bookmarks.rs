@@ -0,0 +1,114 @@
+use futures::Future;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+use uuid::Uuid;
+use xmpp_parsers::{BareJid, Element, FullJid, Jid};
+use xmpp_parsers::bookmarks::{Autojoin, Conference, Storage};
+use xmpp_parsers::iq::{Iq, IqType, IqGetPayload, IqSetPayload, IqResultPayload};
+
+use crate::core::{Plugin, Aparte, Event};
+use crate::plugins::conversation::ConversationPlugin;
+
+pub const NS_PRIVATE: &str = "jabber:iq:private";
+
+/// XEP-0049 private XML storage query wrapping a single payload
+#[derive(Debug, Clone)]
+pub struct PrivateXml {
+    pub payload: Element,
+}
+
+impl TryFrom<Element> for PrivateXml {
+    type Error = ();
+
+    fn try_from(element: Element) -> Result<Self, Self::Error> {
+        if !element.is("query", NS_PRIVATE) {
+            return Err(());
+        }
+
+        match element.children().next() {
+            Some(payload) => Ok(PrivateXml { payload: payload.clone() }),
+            None => Err(()),
+        }
+    }
+}
+
+impl From<PrivateXml> for Element {
+    fn from(private: PrivateXml) -> Element {
+        Element::builder("query").ns(NS_PRIVATE).append(private.payload).build()
+    }
+}
+
+impl IqGetPayload for PrivateXml {}
+impl IqSetPayload for PrivateXml {}
+impl IqResultPayload for PrivateXml {}
+
+pub struct BookmarksPlugin {
+    account: Option<FullJid>,
+    pub conferences: Vec<Conference>,
+}
+
+impl BookmarksPlugin {
+    /// Fetch the bookmarks stored on the server, joining the channels to join automatically
+    fn fetch(aparte: Rc<Aparte>, account: &FullJid) -> impl Future<Item = (), Error = String> {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let iq = Iq::from_get(id, PrivateXml { payload: Storage::new().into() });
+        let answer_aparte = Rc::clone(&aparte);
+        aparte.send_iq_on(account, iq).map(move |answer| {
+            let storage = match answer.payload {
+                IqType::Result(Some(payload)) => PrivateXml::try_from(payload).ok().and_then(|private| Storage::try_from(private.payload).ok()),
+                _ => None,
+            };
+            if let Some(storage) = storage {
+                answer_aparte.get_plugin_mut::<BookmarksPlugin>().unwrap().received(Rc::clone(&answer_aparte), storage);
+            }
+        }).map_err(|err| format!("Cannot fetch the bookmarks: {}", err))
+    }
+
+    fn received(&mut self, aparte: Rc<Aparte>, storage: Storage) {
+        self.conferences = storage.conferences;
+
+        let (full, account, nick) = match &self.account {
+            Some(account) => (account.clone(), BareJid::from(Jid::Full(account.clone())), account.node.clone().unwrap_or(account.resource.clone())),
+            None => return,
+        };
+        if !aparte.config.autojoin(&account) {
+            return;
+        }
+
+        let mut conversation = aparte.get_plugin_mut::<ConversationPlugin>().unwrap();
+        for conference in self.conferences.iter().filter(|conference| conference.autojoin == Autojoin::True) {
+            let nick = aparte.settings.borrow().channel_nick(&aparte.config, &conference.jid, conference.nick.clone(), &account).unwrap_or(nick.clone());
+            conversation.queue_join(Rc::clone(&aparte), full.clone(), conference.jid.clone().with_resource(nick), conference.password.clone());
+        }
+    }
+}
+
+impl Plugin for BookmarksPlugin {
+    fn new() -> BookmarksPlugin {
+        Self {
+            account: None,
+            conferences: Vec::new(),
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Connected(jid) => {
+                self.account = Some(jid.clone());
+                Rc::clone(&aparte).spawn(BookmarksPlugin::fetch(Rc::clone(&aparte), jid));
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for BookmarksPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0048: Bookmarks")
+    }
+}
@@ -0,0 +1,285 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+use uuid::Uuid;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::jingle_message::JingleMI;
+use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType};
+use xmpp_parsers::{ns, BareJid, Element, FullJid, Jid};
+
+use crate::core::{Plugin, Aparte, Event};
+
+/// Media of the RTP descriptions of a call, as audio or video
+fn media<'a>(descriptions: impl Iterator<Item = &'a Element>) -> Vec<String> {
+    descriptions.filter(|description| description.is("description", ns::JINGLE_RTP))
+        .filter_map(|description| description.attr("media"))
+        .map(String::from)
+        .collect()
+}
+
+/// Call ringing, proposed with a Jingle message (XEP-0353) or started as a Jingle RTP session
+/// (XEP-0167). Calls aren't answered, only shown so that they can be answered from another client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub from: Jid,
+    pub sid: String,
+    /// Media of the call, as audio or video
+    pub media: Vec<String>,
+    /// Whether proposed with a Jingle message rather than started as a session
+    pub proposed: bool,
+}
+
+/// Media of the call a session-initiate starts, none if it isn't one
+fn session_media(jingle: &Element) -> Vec<String> {
+    match jingle.attr("action") {
+        Some("session-initiate") => media(jingle.children()
+            .filter(|content| content.is("content", ns::JINGLE))
+            .flat_map(|content| content.children())),
+        _ => Vec::new(),
+    }
+}
+
+impl Call {
+    /// Call started by a session-initiate, if it is one
+    fn session(from: &Jid, jingle: &Element) -> Option<Call> {
+        let media = session_media(jingle);
+        match media.is_empty() {
+            true => None,
+            false => Some(Call {
+                from: from.clone(),
+                sid: jingle.attr("sid")?.to_string(),
+                media: media,
+                proposed: false,
+            }),
+        }
+    }
+
+    /// Answer declining the call, to send to the caller
+    pub fn decline(&self) -> Element {
+        match self.proposed {
+            true => {
+                let mut message = XmppParsersMessage::new(Some(self.from.clone()));
+                message.type_ = MessageType::Chat;
+                message.payloads.push(Element::builder("reject").ns(ns::JINGLE_MESSAGE).attr("id", self.sid.as_str()).build());
+                message.into()
+            },
+            false => Iq {
+                from: None,
+                to: Some(self.from.clone()),
+                id: Uuid::new_v4().to_hyphenated().to_string(),
+                payload: IqType::Set(Element::builder("jingle").ns(ns::JINGLE)
+                    .attr("action", "session-terminate")
+                    .attr("sid", self.sid.as_str())
+                    .append(Element::builder("reason").ns(ns::JINGLE)
+                        .append(Element::builder("decline").ns(ns::JINGLE).build())
+                        .build())
+                    .build()),
+            }.into(),
+        }
+    }
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.media.iter().any(|media| media == "video") {
+            true => write!(f, "video call from {}", self.from),
+            false => write!(f, "audio call from {}", self.from),
+        }
+    }
+}
+
+/// Event of a Jingle message payload of a message from `from`: a call proposed, or one ended by
+/// the caller retracting it or by one of our clients answering it
+pub fn event(from: &Jid, payload: &Element) -> Option<Event> {
+    match JingleMI::try_from(payload.clone()).ok()? {
+        JingleMI::Propose { sid, description } => {
+            let media = media(std::iter::once(&description));
+            match media.is_empty() {
+                true => None,
+                false => Some(Event::Call(Call {
+                    from: from.clone(),
+                    sid: sid.0,
+                    media: media,
+                    proposed: true,
+                })),
+            }
+        },
+        JingleMI::Retract(sid) => Some(Event::CallEnded(sid.0, String::from("retracted"))),
+        JingleMI::Accept(sid) | JingleMI::Proceed(sid) => Some(Event::CallEnded(sid.0, String::from("answered elsewhere"))),
+        JingleMI::Reject(sid) => Some(Event::CallEnded(sid.0, String::from("declined"))),
+    }
+}
+
+/// Calls ringing, shown with the way to decline them instead of being silently dropped
+pub struct CallsPlugin {
+    calls: Vec<Call>,
+}
+
+impl CallsPlugin {
+    /// Whether a Jingle action is about a call rather than a file transfer
+    pub fn handles(&self, jingle: &Element) -> bool {
+        let sid = jingle.attr("sid");
+        self.calls.iter().any(|call| !call.proposed && Some(call.sid.as_str()) == sid)
+            || !session_media(jingle).is_empty()
+    }
+
+    /// Stop ringing for a call, the last one or the one from `jid`
+    pub fn take(&mut self, jid: Option<&str>) -> Option<Call> {
+        let index = match jid {
+            Some(jid) => self.calls.iter().rposition(|call| call.from.to_string() == jid || BareJid::from(call.from.clone()).to_string() == jid)?,
+            None => self.calls.len().checked_sub(1)?,
+        };
+        Some(self.calls.remove(index))
+    }
+
+    /// Callers of the calls ringing
+    pub fn callers(&self) -> Vec<String> {
+        self.calls.iter().map(|call| BareJid::from(call.from.clone()).to_string()).collect()
+    }
+
+    /// Handle a Jingle action of a call session, returns the event it raises
+    fn jingle(&mut self, from: &Jid, jingle: &Element) -> Option<Event> {
+        if let Some(call) = Call::session(from, jingle) {
+            return Some(Event::Call(call));
+        }
+        let sid = jingle.attr("sid")?;
+        match jingle.attr("action") {
+            Some("session-terminate") if self.calls.iter().any(|call| call.sid == sid && &call.from == from) => {
+                let reason = jingle.get_child("reason", ns::JINGLE)
+                    .and_then(|reason| reason.children().find(|child| child.name() != "text"))
+                    .map(|reason| reason.name().to_string())
+                    .unwrap_or(String::from("ended"));
+                Some(Event::CallEnded(sid.to_string(), reason))
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether a call is from one of our own clients, which rings on every client of the account
+    fn own(call: &Call, account: Option<FullJid>) -> bool {
+        let from = BareJid::from(call.from.clone());
+        account.map_or(false, |account| from.node == account.node && from.domain == account.domain)
+    }
+}
+
+impl Plugin for CallsPlugin {
+    fn new() -> CallsPlugin {
+        Self {
+            calls: Vec::new(),
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Iq(_, iq) => {
+                let (from, jingle) = match (&iq.from, &iq.payload) {
+                    (Some(from), IqType::Set(jingle)) if jingle.is("jingle", ns::JINGLE) && self.handles(jingle) => (from, jingle),
+                    _ => return,
+                };
+                aparte.send(Iq {
+                    from: None,
+                    to: iq.from.clone(),
+                    id: iq.id.clone(),
+                    payload: IqType::Result(None),
+                }.into());
+                if let Some(event) = self.jingle(from, jingle) {
+                    Rc::clone(&aparte).event(event);
+                }
+            },
+            Event::Call(call) => {
+                if CallsPlugin::own(call, aparte.current_connection()) || self.calls.iter().any(|ringing| ringing.sid == call.sid) {
+                    return;
+                }
+                self.calls.push(call.clone());
+                Rc::clone(&aparte).log(format!("Incoming {}, use /decline to refuse it or answer it from another client", call));
+            },
+            Event::CallEnded(sid, reason) => {
+                if let Some(index) = self.calls.iter().position(|call| &call.sid == sid) {
+                    let call = self.calls.remove(index);
+                    Rc::clone(&aparte).log(format!("Missed {}, {}", call, reason));
+                }
+            },
+            Event::Disconnected(_) => self.calls.clear(),
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for CallsPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Calls")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_calls() {
+        let from = Jid::from_str("romeo@montague.lit/orchard").unwrap();
+        let jingle: Element = r#"<jingle xmlns="urn:xmpp:jingle:1" action="session-initiate" initiator="romeo@montague.lit/orchard" sid="a73sjjvkla37jfea">
+            <content creator="initiator" name="voice">
+                <description xmlns="urn:xmpp:jingle:apps:rtp:1" media="audio"><payload-type id="96" name="speex" clockrate="16000"/></description>
+                <transport xmlns="urn:xmpp:jingle:transports:ice-udp:1"/>
+            </content>
+            <content creator="initiator" name="webcam">
+                <description xmlns="urn:xmpp:jingle:apps:rtp:1" media="video"/>
+                <transport xmlns="urn:xmpp:jingle:transports:ice-udp:1"/>
+            </content>
+        </jingle>"#.parse().unwrap();
+
+        let mut plugin = CallsPlugin::new();
+        assert!(plugin.handles(&jingle));
+        let call = match plugin.jingle(&from, &jingle) {
+            Some(Event::Call(call)) => call,
+            _ => panic!("No call"),
+        };
+        assert_eq!(call.media, vec!["audio", "video"]);
+        assert_eq!(call.to_string(), "video call from romeo@montague.lit/orchard");
+        plugin.calls.push(call);
+
+        let terminate: Element = r#"<jingle xmlns="urn:xmpp:jingle:1" action="session-terminate" sid="a73sjjvkla37jfea"><reason><cancel/></reason></jingle>"#.parse().unwrap();
+        assert!(plugin.handles(&terminate));
+        match plugin.jingle(&from, &terminate) {
+            Some(Event::CallEnded(sid, reason)) => assert_eq!((sid.as_str(), reason.as_str()), ("a73sjjvkla37jfea", "cancel")),
+            _ => panic!("Not ended"),
+        }
+
+        // File transfers are left to the file transfer plugin
+        let file: Element = r#"<jingle xmlns="urn:xmpp:jingle:1" action="session-initiate" sid="851ba2"><content creator="initiator" name="a-file-offer"><description xmlns="urn:xmpp:jingle:apps:file-transfer:5"/></content></jingle>"#.parse().unwrap();
+        assert!(!plugin.handles(&file));
+
+        let decline = plugin.take(Some("romeo@montague.lit")).unwrap().decline();
+        let reason = decline.get_child("jingle", ns::JINGLE).unwrap().get_child("reason", ns::JINGLE).unwrap();
+        assert!(reason.has_child("decline", ns::JINGLE));
+        assert!(plugin.take(None).is_none());
+    }
+
+    #[test]
+    fn test_proposal() {
+        let from = Jid::from_str("romeo@montague.lit/orchard").unwrap();
+        let propose: Element = r#"<propose xmlns="urn:xmpp:jingle-message:0" id="ca3cf894-5325-482f-a412-a6e9f832298d"><description xmlns="urn:xmpp:jingle:apps:rtp:1" media="audio"/></propose>"#.parse().unwrap();
+        let call = match event(&from, &propose) {
+            Some(Event::Call(call)) => call,
+            _ => panic!("No call"),
+        };
+        assert!(call.proposed);
+        assert_eq!(call.to_string(), "audio call from romeo@montague.lit/orchard");
+        let reject = call.decline();
+        assert_eq!(reject.attr("to"), Some("romeo@montague.lit/orchard"));
+        assert_eq!(reject.get_child("reject", ns::JINGLE_MESSAGE).unwrap().attr("id"), Some("ca3cf894-5325-482f-a412-a6e9f832298d"));
+
+        let retract: Element = r#"<retract xmlns="urn:xmpp:jingle-message:0" id="ca3cf894-5325-482f-a412-a6e9f832298d"/>"#.parse().unwrap();
+        match event(&from, &retract) {
+            Some(Event::CallEnded(sid, reason)) => assert_eq!((sid.as_str(), reason.as_str()), ("ca3cf894-5325-482f-a412-a6e9f832298d", "retracted")),
+            _ => panic!("Not ended"),
+        }
+        assert!(CallsPlugin::own(&call, Some(FullJid::from_str("romeo@montague.lit/phone").unwrap())));
+    }
+}
@@ -0,0 +1,237 @@
+use futures::Future;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::{BareJid, FullJid, Jid};
+use xmpp_parsers::caps::{self, Caps};
+use xmpp_parsers::disco::DiscoInfoResult;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::presence::{self, Presence};
+
+use crate::core::{Plugin, Aparte, Event, IqError};
+use crate::profile;
+
+fn cache_path() -> PathBuf {
+    profile::data_dir().join("caps.toml")
+}
+
+/// Capabilities (XEP-0115) of the entities we receive presences from, so that we only send them
+/// what they understand
+pub struct CapsPlugin {
+    /// Features of each verified ver, shared by every client advertising it
+    cache: BTreeMap<String, Vec<String>>,
+    /// Ver advertised by each full JID
+    peers: HashMap<String, String>,
+    /// Vers being queried, not to query them once per client advertising them
+    querying: HashSet<String>,
+    /// Where the cache is persisted, none in tests
+    path: Option<PathBuf>,
+}
+
+impl CapsPlugin {
+    /// Whether a JID supports a feature, None if its capabilities aren't known (yet). For a bare
+    /// JID, whether any of its resources does.
+    pub fn supports(&self, jid: &Jid, feature: &str) -> Option<bool> {
+        let supports = |ver: &String| self.cache.get(ver).map(|features| features.iter().any(|var| var == feature));
+        match jid {
+            Jid::Full(_) => self.peers.get(&jid.to_string()).and_then(supports),
+            Jid::Bare(bare) => {
+                let known: Vec<bool> = self.peers.iter()
+                    .filter(|(peer, _)| Jid::from_str(peer).ok().map(BareJid::from).as_ref() == Some(bare))
+                    .filter_map(|(_, ver)| supports(ver))
+                    .collect();
+                match known.is_empty() {
+                    true => None,
+                    false => Some(known.into_iter().any(|supported| supported)),
+                }
+            },
+        }
+    }
+
+    /// Resource of a contact supporting a feature, to send it what only clients do
+    pub fn resource(&self, jid: &BareJid, feature: &str) -> Option<FullJid> {
+        self.peers.keys()
+            .filter_map(|peer| FullJid::from_str(peer).ok())
+            .filter(|peer| peer.node == jid.node && peer.domain == jid.domain)
+            .find(|peer| self.supports(&Jid::Full(peer.clone()), feature) == Some(true))
+    }
+
+    /// Handle a presence, returns the disco#info query to send if its caps aren't known, with
+    /// the caps its answer should match
+    fn presence(&mut self, presence: &Presence) -> Option<(Caps, Iq)> {
+        let from = presence.from.clone()?;
+        if presence.type_ == presence::Type::Unavailable {
+            self.peers.remove(&from.to_string());
+            return None;
+        }
+
+        let caps = presence.payloads.iter().find_map(|payload| Caps::try_from(payload.clone()).ok())?;
+        let ver = caps.hash.to_base64();
+        self.peers.insert(from.to_string(), ver.clone());
+        if self.cache.contains_key(&ver) || self.querying.contains(&ver) {
+            return None;
+        }
+
+        self.querying.insert(ver);
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let query = caps::query_caps(caps.clone());
+        Some((caps, Iq::from_get(id, query).with_to(from)))
+    }
+
+    /// Handle an answer to a disco#info query, caching the features if they match the hash they
+    /// were advertised with
+    fn answer(&mut self, caps: Caps, answer: Result<Iq, IqError>) -> Result<(), String> {
+        let ver = caps.hash.to_base64();
+        self.querying.remove(&ver);
+
+        let info = match answer.map(|answer| answer.payload) {
+            Ok(IqType::Result(Some(payload))) => DiscoInfoResult::try_from(payload).map_err(|err| format!("Invalid disco#info for caps {}: {}", ver, err))?,
+            Ok(_) => return Err(format!("No disco#info for caps {}", ver)),
+            Err(err) => return Err(format!("No disco#info for caps {}: {}", ver, err)),
+        };
+        let hash = caps::hash_caps(&caps::compute_disco(&info), caps.hash.algo.clone())?;
+        if hash != caps.hash {
+            return Err(format!("Caps {} don't match their disco#info, got {}", ver, hash.to_base64()));
+        }
+
+        self.cache.insert(ver, info.features.into_iter().map(|feature| feature.var).collect());
+        self.save();
+        Ok(())
+    }
+
+    fn load(&mut self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        match fs::read_to_string(path) {
+            Ok(content) => match toml::from_str(&content) {
+                Ok(cache) => self.cache = cache,
+                Err(err) => warn!("Cannot parse caps cache {}: {}", path.display(), err),
+            },
+            Err(_) => {},
+        }
+    }
+
+    fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let content = match toml::to_string(&self.cache) {
+            Ok(content) => content,
+            Err(err) => {
+                warn!("Cannot serialize caps cache: {}", err);
+                return;
+            },
+        };
+        if let Err(err) = fs::write(path, content) {
+            warn!("Cannot write caps cache {}: {}", path.display(), err);
+        }
+    }
+}
+
+impl Plugin for CapsPlugin {
+    fn new() -> CapsPlugin {
+        Self {
+            cache: BTreeMap::new(),
+            peers: HashMap::new(),
+            querying: HashSet::new(),
+            path: None,
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        self.path = Some(cache_path());
+        self.load();
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Presence(account, presence) => {
+                if let Some((caps, query)) = self.presence(presence) {
+                    let answer_aparte = Rc::clone(&aparte);
+                    let answer = Rc::clone(&aparte).send_iq_on(account, query).then(move |answer| {
+                        if let Err(err) = answer_aparte.get_plugin_mut::<CapsPlugin>().unwrap().answer(caps, answer) {
+                            warn!("{}", err);
+                        }
+                        Ok(())
+                    });
+                    aparte.spawn(answer);
+                }
+            },
+            Event::Disconnected(_) => {
+                self.peers.clear();
+                self.querying.clear();
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for CapsPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0115: Entity Capabilities")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xmpp_parsers::Element;
+
+    // Example from XEP-0115 §5.2
+    const INFO: &str = r#"<query xmlns="http://jabber.org/protocol/disco#info" node="http://code.google.com/p/exodus#QgayPKawpkPSDYmwT/WM94uAlu0="><identity category="client" type="pc" name="Exodus 0.9.1"/><feature var="http://jabber.org/protocol/caps"/><feature var="http://jabber.org/protocol/disco#info"/><feature var="http://jabber.org/protocol/disco#items"/><feature var="http://jabber.org/protocol/muc"/></query>"#;
+
+    fn presence(from: &str, ver: &str) -> Presence {
+        let element: Element = format!(r#"<presence xmlns="jabber:client" from="{}"><c xmlns="http://jabber.org/protocol/caps" hash="sha-1" node="http://code.google.com/p/exodus" ver="{}"/></presence>"#, from, ver).parse().unwrap();
+        Presence::try_from(element).unwrap()
+    }
+
+    fn result(id: &str, query: &str) -> Iq {
+        let element: Element = format!(r#"<iq xmlns="jabber:client" type="result" id="{}">{}</iq>"#, id, query).parse().unwrap();
+        Iq::try_from(element).unwrap()
+    }
+
+    #[test]
+    fn test_caps() {
+        let mut plugin = CapsPlugin::new();
+        let phone = Jid::from_str("contact@server.tld/phone").unwrap();
+        let laptop = Jid::from_str("contact@server.tld/laptop").unwrap();
+
+        let (caps, query) = plugin.presence(&presence("contact@server.tld/phone", "QgayPKawpkPSDYmwT/WM94uAlu0=")).unwrap();
+        let query = Element::from(query);
+        assert_eq!(query.get_child("query", "http://jabber.org/protocol/disco#info").unwrap().attr("node"), Some("http://code.google.com/p/exodus#QgayPKawpkPSDYmwT/WM94uAlu0="));
+        // Another client with the same caps is not queried again
+        assert!(plugin.presence(&presence("contact@server.tld/laptop", "QgayPKawpkPSDYmwT/WM94uAlu0=")).is_none());
+        assert_eq!(plugin.supports(&phone, "http://jabber.org/protocol/muc"), None);
+
+        plugin.answer(caps, Ok(result(query.attr("id").unwrap(), INFO))).unwrap();
+        assert_eq!(plugin.supports(&phone, "http://jabber.org/protocol/muc"), Some(true));
+        assert_eq!(plugin.supports(&laptop, "urn:xmpp:receipts"), Some(false));
+        assert_eq!(plugin.supports(&Jid::from_str("contact@server.tld").unwrap(), "http://jabber.org/protocol/muc"), Some(true));
+        assert_eq!(plugin.supports(&Jid::from_str("other@server.tld").unwrap(), "http://jabber.org/protocol/muc"), None);
+
+        let unavailable: Element = r#"<presence xmlns="jabber:client" from="contact@server.tld/phone" type="unavailable"/>"#.parse().unwrap();
+        plugin.presence(&Presence::try_from(unavailable).unwrap());
+        assert_eq!(plugin.supports(&phone, "http://jabber.org/protocol/muc"), None);
+    }
+
+    #[test]
+    fn test_caps_mismatch() {
+        let mut plugin = CapsPlugin::new();
+        let (caps, query) = plugin.presence(&presence("liar@server.tld/phone", "AAAAAAAAAAAAAAAAAAAAAAAAAAA=")).unwrap();
+        assert!(plugin.answer(caps, Ok(result(&query.id, INFO))).is_err());
+        assert_eq!(plugin.supports(&Jid::from_str("liar@server.tld/phone").unwrap(), "http://jabber.org/protocol/muc"), None);
+        // A later presence can query them again
+        let (caps, _) = plugin.presence(&presence("liar@server.tld/phone", "AAAAAAAAAAAAAAAAAAAAAAAAAAA=")).unwrap();
+        assert!(plugin.answer(caps, Err(IqError::Timeout)).is_err());
+        assert!(plugin.presence(&presence("liar@server.tld/phone", "AAAAAAAAAAAAAAAAAAAAAAAAAAA=")).is_some());
+    }
+}
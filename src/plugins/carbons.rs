@@ -1,7 +1,7 @@
+use futures::Future;
 use std::fmt;
 use std::rc::Rc;
 use uuid::Uuid;
-use xmpp_parsers::Element;
 use xmpp_parsers::carbons;
 use xmpp_parsers::iq::Iq;
 
@@ -12,10 +12,9 @@ pub struct CarbonsPlugin {
 }
 
 impl CarbonsPlugin {
-    fn enable(&self) -> Element {
+    fn enable(&self) -> Iq {
         let id = Uuid::new_v4().to_hyphenated().to_string();
-        let iq = Iq::from_set(id, carbons::Enable);
-        iq.into()
+        Iq::from_set(id, carbons::Enable)
     }
 }
 
@@ -31,7 +30,10 @@ impl Plugin for CarbonsPlugin {
 
     fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
         match event {
-            Event::Connected(_jid) => aparte.send(self.enable()),
+            Event::Connected(jid) => {
+                let enable = Rc::clone(&aparte).send_iq_on(jid, self.enable());
+                aparte.spawn(enable.map(|_| ()).map_err(|err| format!("Cannot enable carbons: {}", err)));
+            },
             _ => {},
         }
     }
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::fmt;
+use xmpp_parsers::BareJid;
+
+use crate::account::Account;
+use crate::core::{Plugin, Aparte};
+use crate::profile;
+use crate::tls::{Accepted, Certificate, Policy, Trust};
+
+/// Certificates of the servers of the accounts, and the ones refused until the user accepts them
+pub struct CertificatesPlugin {
+    certificates: HashMap<BareJid, (Certificate, Trust)>,
+    /// Certificates refused, why, and whether they can be accepted, which pinned ones can't
+    refused: HashMap<BareJid, (Certificate, String, bool)>,
+    accepted: Accepted,
+}
+
+impl CertificatesPlugin {
+    /// What the certificate of the server of an account is checked against
+    pub fn policy(&self, jid: &BareJid, account: &Account) -> Policy {
+        Policy {
+            pin: account.tls.pin.clone(),
+            accepted: self.accepted.get(&jid.domain).cloned(),
+        }
+    }
+
+    /// Remember the certificate an account is connected with, returns a warning if it expires soon
+    pub fn connected(&mut self, jid: &BareJid, account: &Account, certificate: Certificate, trust: Trust) -> Option<String> {
+        self.refused.remove(jid);
+        let warning = match certificate.expires_in {
+            days if days < 0 => Some(format!("The certificate of {} expired {} days ago", jid.domain, -days)),
+            days if days <= account.tls.expiry_warning => Some(format!("The certificate of {} expires in {} days", jid.domain, days)),
+            _ => None,
+        };
+        self.certificates.insert(jid.clone(), (certificate, trust));
+        warning
+    }
+
+    pub fn refused(&mut self, jid: &BareJid, account: &Account, certificate: Certificate, reason: String) {
+        self.certificates.remove(jid);
+        self.refused.insert(jid.clone(), (certificate, reason, account.tls.pin.is_none()));
+    }
+
+    /// Trust the certificate refused for an account, until it changes
+    pub fn accept(&mut self, jid: &BareJid) -> Result<Certificate, String> {
+        match self.refused.get(jid) {
+            Some((_, _, true)) => {},
+            Some((_, _, false)) => return Err(format!("The certificate of {} is pinned, change the pin in the config to trust another one", jid.domain)),
+            None => return Err(format!("No certificate refused for {}", jid)),
+        }
+        let (certificate, _, _) = self.refused.remove(jid).unwrap();
+        self.accepted.accept(&jid.domain, &certificate.fingerprint)?;
+        Ok(certificate)
+    }
+
+    /// Description of the certificate of an account
+    pub fn describe(&self, jid: &BareJid) -> Result<Vec<String>, String> {
+        if let Some((certificate, trust)) = self.certificates.get(jid) {
+            let mut lines = vec![format!("Certificate of {}, {}", jid.domain, trust)];
+            lines.extend(certificate.details().into_iter().map(|line| format!("  {}", line)));
+            return Ok(lines);
+        }
+        if let Some((certificate, reason, acceptable)) = self.refused.get(jid) {
+            let mut lines = vec![format!("Certificate of {} refused, {}", jid.domain, reason)];
+            lines.extend(certificate.details().into_iter().map(|line| format!("  {}", line)));
+            if *acceptable {
+                lines.push(format!("Check its fingerprint and use /certificate accept {} to trust it", jid));
+            }
+            return Ok(lines);
+        }
+        Err(format!("No certificate known for {}", jid))
+    }
+}
+
+impl Plugin for CertificatesPlugin {
+    fn new() -> CertificatesPlugin {
+        Self {
+            certificates: HashMap::new(),
+            refused: HashMap::new(),
+            accepted: Accepted::new(profile::data_dir().join("certificates.toml")),
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        if let Err(err) = self.accepted.load() {
+            warn!("{}", err);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CertificatesPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Server certificates")
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use xmpp_parsers::BareJid;
+
+use crate::core::{Plugin, Aparte};
+use crate::message::{self, Message, XmppMessage};
+
+/// Conversation a message is sent to, if it is an outgoing one
+fn recipient(message: &Message) -> Option<&BareJid> {
+    match message {
+        Message::Outgoing(XmppMessage::Chat(message)) => Some(&message.to),
+        Message::Outgoing(XmppMessage::Groupchat(message)) => Some(&message.to),
+        _ => None,
+    }
+}
+
+/// Message as it will be sent, followed by how to confirm it
+pub fn preview(message: &Message) -> String {
+    let to = match recipient(message) {
+        Some(to) => to.to_string(),
+        None => String::new(),
+    };
+    let body = match message::action(message.body()) {
+        Some(action) => format!("* {}", action),
+        None => message.body().to_string(),
+    };
+    let lines: Vec<String> = body.lines().map(|line| format!("  {}", line)).collect();
+    format!("Send to {}? Press y to send, any other key to edit\n{}", to, lines.join("\n"))
+}
+
+/// Messages of some conversations, as announcement channels with thousands of members, and long
+/// messages, as pastes, shown and confirmed before being sent
+pub struct ConfirmPlugin {
+    conversations: HashSet<BareJid>,
+    /// Lines of messages confirmed whatever their conversation, 0 for none
+    max_lines: usize,
+    /// Message waiting for confirmation
+    pending: Option<Message>,
+    /// Id of the message just confirmed, sent without asking again
+    confirmed: Option<String>,
+}
+
+impl ConfirmPlugin {
+    pub fn is_enabled(&self, conversation: &BareJid) -> bool {
+        self.conversations.contains(conversation)
+    }
+
+    pub fn set_enabled(&mut self, conversation: &BareJid, enabled: bool) {
+        match enabled {
+            true => self.conversations.insert(conversation.clone()),
+            false => self.conversations.remove(conversation),
+        };
+    }
+
+    /// Hold a message sent to a conversation asking for confirmation, returns it if it can be sent
+    /// right away
+    pub fn hold(&mut self, message: Message) -> Option<Message> {
+        let confirmed = self.confirmed.take();
+        let held = match recipient(&message) {
+            Some(to) => (self.is_enabled(to) || (self.max_lines > 0 && message.body().lines().count() > self.max_lines))
+                && confirmed.as_deref() != Some(message.id()),
+            None => false,
+        };
+        match held {
+            true => {
+                self.pending = Some(message);
+                None
+            },
+            false => Some(message),
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Confirm or cancel the message held, returns it
+    pub fn answer(&mut self, confirm: bool) -> Option<Message> {
+        let message = self.pending.take()?;
+        if confirm {
+            self.confirmed = Some(message.id().to_string());
+        }
+        Some(message)
+    }
+}
+
+impl Plugin for ConfirmPlugin {
+    fn new() -> ConfirmPlugin {
+        Self {
+            conversations: HashSet::new(),
+            max_lines: 0,
+            pending: None,
+            confirmed: None,
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        self.max_lines = aparte.config.confirm_lines;
+        for jid in &aparte.config.confirm {
+            match BareJid::from_str(jid) {
+                Ok(jid) => { self.conversations.insert(jid); },
+                Err(err) => warn!("Invalid JID {} in confirm config: {}", jid, err),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ConfirmPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Confirm before send")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use xmpp_parsers::Jid;
+
+    #[test]
+    fn test_confirm() {
+        let us = Jid::from_str("me@server.tld/aparte").unwrap();
+        let announces = Jid::from_str("announces@conference.server.tld").unwrap();
+        let contact = Jid::from_str("contact@server.tld").unwrap();
+        let mut plugin = ConfirmPlugin::new();
+        plugin.set_enabled(&BareJid::from(announces.clone()), true);
+
+        let message = Message::outgoing_groupchat("1", Utc::now(), &us, &announces, "Release tomorrow\nStay tuned");
+        assert!(plugin.hold(message.clone()).is_none());
+        assert!(plugin.is_pending());
+        assert_eq!(preview(&message), "Send to announces@conference.server.tld? Press y to send, any other key to edit\n  Release tomorrow\n  Stay tuned");
+
+        // Sent once confirmed, without asking again
+        let confirmed = plugin.answer(true).unwrap();
+        assert!(!plugin.is_pending());
+        assert_eq!(plugin.hold(confirmed), Some(message.clone()));
+        // But asked again if sent again
+        assert!(plugin.hold(message.clone()).is_none());
+        assert_eq!(plugin.answer(false), Some(message.clone()));
+        assert!(plugin.hold(message).is_none());
+
+        let chat = Message::outgoing_chat("2", Utc::now(), &us, &contact, "/me waves");
+        assert_eq!(plugin.hold(chat.clone()), Some(chat.clone()));
+        assert_eq!(preview(&chat).lines().nth(1), Some("  * waves"));
+
+        // Pastes are confirmed in every conversation
+        plugin.max_lines = 2;
+        let paste = Message::outgoing_chat("3", Utc::now(), &us, &contact, "fn main() {\n    println!(\"Hello\");\n}");
+        assert!(plugin.hold(paste.clone()).is_none());
+        assert_eq!(plugin.answer(true), Some(paste.clone()));
+        assert_eq!(plugin.hold(paste.clone()), Some(paste));
+    }
+}
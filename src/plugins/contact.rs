@@ -1,13 +1,25 @@
+use futures::Future;
 use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::collections::HashMap;
+use std::str::FromStr;
 use uuid::Uuid;
-use xmpp_parsers::{Element, roster, ns, Jid, BareJid, presence};
+use xmpp_parsers::{Element, roster, ns, Jid, BareJid, FullJid, presence};
 use xmpp_parsers::iq::{Iq, IqType};
 use std::convert::TryFrom;
 
 use crate::core::{Plugin, Aparte, Event};
 use crate::contact;
+use crate::profile;
+
+const NS_ROSTER_VERSIONING: &str = "urn:xmpp:features:rosterver";
+
+/// Roster of an account as last received, with its version
+fn cache_path(account: &BareJid) -> PathBuf {
+    profile::data_dir().join("rosters").join(format!("{}.xml", account))
+}
 
 impl From<roster::Group> for contact::Group {
     fn from(item: roster::Group) -> Self {
@@ -28,26 +40,272 @@ impl From<roster::Item> for contact::Contact {
             subscription: item.subscription.clone(),
             presence: contact::Presence::Unavailable,
             groups: groups,
+            info: contact::UserInfo::default(),
+        }
+    }
+}
+
+impl From<contact::Group> for roster::Group {
+    fn from(group: contact::Group) -> Self {
+        Self(group.0)
+    }
+}
+
+impl From<contact::Contact> for roster::Item {
+    fn from(contact: contact::Contact) -> Self {
+        Self {
+            jid: contact.jid,
+            name: contact.name,
+            subscription: contact.subscription,
+            ask: roster::Ask::None,
+            groups: contact.groups.into_iter().map(|group| group.into()).collect(),
+        }
+    }
+}
+
+/// Roster of an account, with its version and where it is cached
+struct AccountRoster {
+    contacts: HashMap<BareJid, contact::Contact>,
+    /// Version of the roster, for the server to only send what changed since
+    version: Option<String>,
+    /// Where the roster of the account is cached
+    cache: Option<PathBuf>,
+}
+
+impl AccountRoster {
+    fn new() -> Self {
+        Self {
+            contacts: HashMap::new(),
+            version: None,
+            cache: None,
+        }
+    }
+
+    /// Request of the roster, only of the changes since the cached version if the server
+    /// supports versioning
+    fn request(&self, versioning: bool) -> Iq {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let ver = match versioning {
+            true => Some(self.version.clone().unwrap_or_default()),
+            false => None,
+        };
+        Iq::from_get(id, roster::Roster { ver: ver, items: Vec::new() })
+    }
+
+    /// Load the roster cached at `path`, to show the contacts before the server sends them
+    fn load(&mut self, path: PathBuf) -> Result<Vec<contact::Contact>, String> {
+        self.cache = Some(path.clone());
+        self.version = None;
+        self.contacts.clear();
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => return Ok(Vec::new()),
+        };
+        let roster = Element::from_str(&content).map_err(|err| err.to_string())
+            .and_then(|element| roster::Roster::try_from(element).map_err(|err| err.to_string()))
+            .map_err(|err| format!("Invalid roster cache {}: {}", path.display(), err))?;
+
+        self.version = roster.ver;
+        Ok(roster.items.into_iter().map(|item| {
+            let contact: contact::Contact = item.into();
+            self.contacts.insert(contact.jid.clone(), contact.clone());
+            contact
+        }).collect())
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let path = match &self.cache {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut items: Vec<roster::Item> = self.contacts.values().map(|contact| contact.clone().into()).collect();
+        items.sort_by(|a, b| a.jid.to_string().cmp(&b.jid.to_string()));
+        let roster: Element = roster::Roster { ver: self.version.clone(), items: items }.into();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| format!("Cannot create {}: {}", dir.display(), err))?;
+        }
+        fs::write(path, String::from(&roster)).map_err(|err| format!("Cannot cache roster to {}: {}", path.display(), err))
+    }
+
+    /// Update the contacts with a roster received, either whole or a push of some items, returns
+    /// the contacts added or changed
+    fn update(&mut self, roster: roster::Roster, push: bool) -> Vec<contact::Contact> {
+        if !push {
+            let jids: Vec<BareJid> = roster.items.iter().map(|item| item.jid.clone()).collect();
+            self.contacts.retain(|jid, _| jids.contains(jid));
+        }
+        if roster.ver.is_some() {
+            self.version = roster.ver;
+        }
+
+        let mut updated = Vec::new();
+        for item in roster.items {
+            if item.subscription == roster::Subscription::Remove {
+                self.contacts.remove(&item.jid);
+                continue;
+            }
+            let mut contact: contact::Contact = item.into();
+            if let Some(known) = self.contacts.get(&contact.jid) {
+                contact.presence = known.presence.clone();
+                contact.info = known.info.clone();
+            }
+            self.contacts.insert(contact.jid.clone(), contact.clone());
+            updated.push(contact);
         }
+
+        if let Err(err) = self.save() {
+            warn!("{}", err);
+        }
+        updated
+    }
+
+    /// Write the roster to `path` as a `jabber:iq:roster` query element so it can be imported
+    /// back in any account.
+    fn export(&self, path: &Path) -> Result<usize, String> {
+        let mut items: Vec<roster::Item> = self.contacts.values().map(|contact| contact.clone().into()).collect();
+        items.sort_by(|a, b| a.jid.to_string().cmp(&b.jid.to_string()));
+        let count = items.len();
+
+        let roster: Element = roster::Roster { ver: None, items: items }.into();
+        match fs::write(path, String::from(&roster)) {
+            Ok(()) => Ok(count),
+            Err(err) => Err(format!("Cannot write roster to {}: {}", path.display(), err)),
+        }
+    }
+
+    /// Read a roster previously written by `export` and build the stanzas adding each contact
+    /// missing from this roster: a roster push followed by a subscription request.
+    fn import(&self, path: &Path) -> Result<Vec<Element>, String> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) => return Err(format!("Cannot read roster from {}: {}", path.display(), err)),
+        };
+
+        let roster = match Element::from_str(&content) {
+            Ok(element) => match roster::Roster::try_from(element) {
+                Ok(roster) => roster,
+                Err(err) => return Err(format!("Invalid roster in {}: {}", path.display(), err)),
+            },
+            Err(err) => return Err(format!("Invalid roster in {}: {}", path.display(), err)),
+        };
+
+        let mut stanzas = Vec::new();
+        for mut item in roster.items {
+            if self.contacts.contains_key(&item.jid) {
+                continue;
+            }
+
+            // Subscription state belongs to the old account, only the contact itself is moved
+            item.subscription = roster::Subscription::None;
+            item.ask = roster::Ask::None;
+
+            let jid = item.jid.clone();
+            let id = Uuid::new_v4().to_hyphenated().to_string();
+            stanzas.push(Iq::from_set(id, roster::Roster { ver: None, items: vec![item] }).into());
+            stanzas.push(presence::Presence::new(presence::Type::Subscribe).with_to(Jid::Bare(jid)).into());
+        }
+
+        Ok(stanzas)
     }
 }
 
 pub struct ContactPlugin {
-    pub contacts: HashMap<BareJid, contact::Contact>,
+    /// Roster of each account, by bare JID of the account
+    rosters: HashMap<BareJid, AccountRoster>,
 }
 
 impl ContactPlugin {
-    fn request(&self) -> Element {
-        let id = Uuid::new_v4().to_hyphenated().to_string();
-        let iq = Iq::from_get(id, roster::Roster { ver: None, items: Vec::new() });
-        iq.into()
+    /// Contacts in the roster of an account
+    pub fn contacts(&self, account: &FullJid) -> Vec<&contact::Contact> {
+        match self.rosters.get(&account.clone().into()) {
+            Some(roster) => roster.contacts.values().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Contact in the roster of an account
+    pub fn contact(&self, account: &FullJid, jid: &BareJid) -> Option<&contact::Contact> {
+        self.rosters.get(&account.clone().into()).and_then(|roster| roster.contacts.get(jid))
+    }
+
+    /// Handle the answer to the request of the roster, empty if it didn't change since the
+    /// cached version
+    fn received(aparte: Rc<Aparte>, account: &FullJid, answer: Iq) {
+        let contacts = match answer.payload {
+            IqType::Result(Some(payload)) => match roster::Roster::try_from(payload) {
+                Ok(roster) => {
+                    let mut plugin = aparte.get_plugin_mut::<ContactPlugin>().unwrap();
+                    plugin.rosters.entry(account.clone().into()).or_insert_with(AccountRoster::new).update(roster, false)
+                },
+                Err(err) => return warn!("Invalid roster: {}", err),
+            },
+            _ => Vec::new(),
+        };
+        for contact in contacts {
+            Rc::clone(&aparte).event(Event::Contact(contact));
+        }
+    }
+
+    fn connected(&self, aparte: Rc<Aparte>, account: &FullJid) {
+        let versioning = aparte.stream_features(account)
+            .map_or(false, |features| features.has_child("ver", NS_ROSTER_VERSIONING));
+        let request = match self.rosters.get(&account.clone().into()) {
+            Some(roster) => roster.request(versioning),
+            None => AccountRoster::new().request(versioning),
+        };
+        let request = Rc::clone(&aparte).send_iq_on(account, request);
+        let answer_aparte = Rc::clone(&aparte);
+        let answer_account = account.clone();
+        aparte.spawn(request.map(move |answer| ContactPlugin::received(answer_aparte, &answer_account, answer))
+            .map_err(|err| format!("Cannot get roster: {}", err)));
+    }
+
+    /// Handle a change of the roster of the account it was received on, pushed by its server
+    fn pushed(&mut self, aparte: Rc<Aparte>, account: &FullJid, iq: &Iq, payload: &Element) {
+        // Only our server can push changes of our roster
+        let bare: BareJid = account.clone().into();
+        match &iq.from {
+            None => {},
+            Some(Jid::Bare(from)) if *from == bare => {},
+            Some(_) => return,
+        }
+        let roster = match roster::Roster::try_from(payload.clone()) {
+            Ok(roster) => roster,
+            Err(_) => return,
+        };
+
+        let mut result = Iq::from_result(iq.id.clone(), None::<roster::Roster>);
+        result.to = iq.from.clone();
+        aparte.send_on(account, result.into());
+
+        for contact in self.rosters.entry(bare).or_insert_with(AccountRoster::new).update(roster, true) {
+            Rc::clone(&aparte).event(Event::Contact(contact));
+        }
+    }
+
+    /// Write the roster of an account to `path` as a `jabber:iq:roster` query element so it can
+    /// be imported back in any account.
+    pub fn export(&self, account: &FullJid, path: &Path) -> Result<usize, String> {
+        match self.rosters.get(&account.clone().into()) {
+            Some(roster) => roster.export(path),
+            None => AccountRoster::new().export(path),
+        }
+    }
+
+    /// Read a roster previously written by `export` and build the stanzas adding each contact
+    /// to an account: a roster push followed by a subscription request.
+    pub fn import(&self, account: &FullJid, path: &Path) -> Result<Vec<Element>, String> {
+        match self.rosters.get(&account.clone().into()) {
+            Some(roster) => roster.import(path),
+            None => AccountRoster::new().import(path),
+        }
     }
 }
 
 impl Plugin for ContactPlugin {
     fn new() -> ContactPlugin {
         Self {
-            contacts: HashMap::new(),
+            rosters: HashMap::new(),
         }
     }
 
@@ -57,27 +315,37 @@ impl Plugin for ContactPlugin {
 
     fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
         match event {
-            Event::Connected(_jid) => aparte.send(self.request()),
-            Event::Iq(iq) => {
-                if let IqType::Result(Some(payload)) = iq.payload.clone() {
+            Event::Connecting(jid) => {
+                // Loaded once, the roster being kept up to date while reconnecting
+                let account: BareJid = jid.clone().into();
+                if self.rosters.contains_key(&account) {
+                    return;
+                }
+                let mut roster = AccountRoster::new();
+                match roster.load(cache_path(&account)) {
+                    Ok(contacts) => for contact in contacts {
+                        Rc::clone(&aparte).event(Event::Contact(contact));
+                    },
+                    Err(err) => warn!("{}", err),
+                }
+                self.rosters.insert(account, roster);
+            },
+            Event::Connected(jid) => self.connected(aparte, jid),
+            Event::Iq(account, iq) => {
+                if let IqType::Set(payload) = &iq.payload {
                     if payload.is("query", ns::ROSTER) {
-                        if let Ok(roster) = roster::Roster::try_from(payload.clone()) {
-                            for item in roster.items {
-                                let contact: contact::Contact = item.clone().into();
-                                self.contacts.insert(contact.jid.clone(), contact.clone());
-                                Rc::clone(&aparte).event(Event::Contact(contact.clone()));
-                            }
-                        }
+                        self.pushed(Rc::clone(&aparte), account, iq, payload);
                     }
                 }
             },
-            Event::Presence(presence) => {
+            Event::Presence(account, presence) => {
                 if let Some(from) = &presence.from {
                     let jid = match from {
                         Jid::Bare(jid) => jid.clone(),
                         Jid::Full(jid) => jid.clone().into(),
                     };
-                    if let Some(contact) = self.contacts.get_mut(&jid) {
+                    let contact = self.rosters.get_mut(&account.clone().into()).and_then(|roster| roster.contacts.get_mut(&jid));
+                    if let Some(contact) = contact {
                         contact.presence = match presence.show {
                             Some(presence::Show::Away) => contact::Presence::Away,
                             Some(presence::Show::Chat) => contact::Presence::Chat,
@@ -86,6 +354,25 @@ impl Plugin for ContactPlugin {
                             None => contact::Presence::Available,
                         };
                         Rc::clone(&aparte).event(Event::ContactUpdate(contact.clone()));
+
+                        if let Jid::Full(resource) = from {
+                            let resource_presence = match presence.type_ {
+                                presence::Type::None => contact.presence.clone(),
+                                presence::Type::Unavailable => contact::Presence::Unavailable,
+                                _ => return,
+                            };
+                            Rc::clone(&aparte).event(Event::ResourcePresence(resource.clone(), resource_presence));
+                        }
+                    }
+                }
+            },
+            Event::UserInfo(jid, published) => {
+                // Published to every account having the contact
+                for roster in self.rosters.values_mut() {
+                    if let Some(contact) = roster.contacts.get_mut(jid) {
+                        if contact.info.update(published.clone()) {
+                            Rc::clone(&aparte).event(Event::ContactUpdate(contact.clone()));
+                        }
                     }
                 }
             },
@@ -99,3 +386,158 @@ impl fmt::Display for ContactPlugin {
         write!(f, "Contact management")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fakeserver::{self, FakeServer};
+
+    fn contact(jid: &str, name: Option<&str>, groups: &[&str]) -> contact::Contact {
+        contact::Contact {
+            jid: BareJid::from_str(jid).unwrap(),
+            name: name.map(String::from),
+            subscription: roster::Subscription::Both,
+            presence: contact::Presence::Unavailable,
+            groups: groups.iter().map(|group| contact::Group(group.to_string())).collect(),
+            info: contact::UserInfo::default(),
+        }
+    }
+
+    fn requested(iq: Iq) -> Option<String> {
+        match iq.payload {
+            IqType::Get(payload) => roster::Roster::try_from(payload).unwrap().ver,
+            _ => panic!("Not a get"),
+        }
+    }
+
+    #[test]
+    fn test_cache() {
+        let path = std::env::temp_dir().join(format!("aparte-roster-{}.xml", Uuid::new_v4()));
+        let mut plugin = AccountRoster::new();
+        assert!(plugin.load(path.clone()).unwrap().is_empty());
+        plugin.update(roster::Roster {
+            ver: Some(String::from("ver1")),
+            items: vec![
+                contact("romeo@montague.lit", Some("Romeo"), &["Friends", "Verona"]).into(),
+                contact("juliet@capulet.lit", None, &[]).into(),
+            ],
+        }, false);
+
+        let mut cached = AccountRoster::new();
+        let mut contacts = cached.load(path.clone()).unwrap();
+        contacts.sort_by(|a, b| a.jid.to_string().cmp(&b.jid.to_string()));
+        assert_eq!(cached.version, Some(String::from("ver1")));
+        assert_eq!(contacts.len(), 2);
+        assert_eq!(contacts[0].jid, BareJid::from_str("juliet@capulet.lit").unwrap());
+        assert_eq!(contacts[0].name, None);
+        assert!(contacts[0].groups.is_empty());
+        assert_eq!(contacts[1].name, Some(String::from("Romeo")));
+        assert_eq!(contacts[1].subscription, roster::Subscription::Both);
+        assert_eq!(contacts[1].groups, vec![contact::Group(String::from("Friends")), contact::Group(String::from("Verona"))]);
+        assert_eq!(requested(cached.request(true)), Some(String::from("ver1")));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_push() {
+        let mut plugin = AccountRoster::new();
+        plugin.update(roster::Roster {
+            ver: Some(String::from("ver1")),
+            items: vec![contact("romeo@montague.lit", None, &[]).into(), contact("juliet@capulet.lit", None, &[]).into()],
+        }, false);
+        plugin.contacts.get_mut(&BareJid::from_str("romeo@montague.lit").unwrap()).unwrap().presence = contact::Presence::Available;
+
+        let mut removed: roster::Item = contact("juliet@capulet.lit", None, &[]).into();
+        removed.subscription = roster::Subscription::Remove;
+        let updated = plugin.update(roster::Roster {
+            ver: Some(String::from("ver2")),
+            items: vec![removed, contact("romeo@montague.lit", Some("Romeo"), &[]).into()],
+        }, true);
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].name, Some(String::from("Romeo")));
+        assert_eq!(updated[0].presence, contact::Presence::Available);
+        assert_eq!(plugin.contacts.len(), 1);
+        assert_eq!(plugin.version, Some(String::from("ver2")));
+
+        assert_eq!(requested(plugin.request(false)), None);
+        assert_eq!(requested(AccountRoster::new().request(true)), Some(String::new()));
+    }
+
+    #[test]
+    fn test_export_import() {
+        let path = std::env::temp_dir().join(format!("aparte-export-{}.xml", Uuid::new_v4()));
+        let mut exported = AccountRoster::new();
+        exported.update(roster::Roster {
+            ver: None,
+            items: vec![
+                contact("romeo@montague.lit", Some("Romeo"), &["Friends", "Verona"]).into(),
+                contact("juliet@capulet.lit", None, &[]).into(),
+            ],
+        }, false);
+        assert_eq!(exported.export(&path).unwrap(), 2);
+
+        // Contacts already in the roster of the account aren't added again
+        let mut importing = AccountRoster::new();
+        importing.update(roster::Roster { ver: None, items: vec![contact("juliet@capulet.lit", None, &[]).into()] }, false);
+        let stanzas = importing.import(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(stanzas.len(), 2);
+
+        let item = match Iq::try_from(stanzas[0].clone()).unwrap().payload {
+            IqType::Set(payload) => roster::Roster::try_from(payload).unwrap().items.remove(0),
+            _ => panic!("Not a set"),
+        };
+        assert_eq!(item.jid, BareJid::from_str("romeo@montague.lit").unwrap());
+        assert_eq!(item.name, Some(String::from("Romeo")));
+        assert_eq!(item.groups, vec![roster::Group(String::from("Friends")), roster::Group(String::from("Verona"))]);
+        // The subscription belongs to the old account, it is requested again
+        assert_eq!(item.subscription, roster::Subscription::None);
+        let subscribe = presence::Presence::try_from(stanzas[1].clone()).unwrap();
+        assert_eq!(subscribe.type_, presence::Type::Subscribe);
+        assert_eq!(subscribe.to, Some(Jid::Bare(item.jid)));
+
+        fs::write(&path, "<query xmlns='jabber:iq:private'/>").unwrap();
+        assert!(importing.import(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(importing.import(&path).is_err());
+    }
+
+    #[test]
+    fn test_rosters_by_account() {
+        let aparte = fakeserver::aparte(|aparte| aparte.add_plugin(ContactPlugin::new()));
+        let romeo = FullJid::from_str("romeo@montague.lit/orchard").unwrap();
+        let juliet = FullJid::from_str("juliet@capulet.lit/balcony").unwrap();
+        let server = FakeServer::new()
+            .expect("<iq xmlns='jabber:client' type='get' id='roster'><query xmlns='jabber:iq:roster'/></iq>")
+            .send("<iq xmlns='jabber:client' type='result' id='roster'><query xmlns='jabber:iq:roster'><item jid='juliet@capulet.lit' subscription='both'/></query></iq>");
+        assert!(fakeserver::run(&aparte, &romeo, server).unwrap().is_done());
+        let server = FakeServer::new()
+            .expect("<iq xmlns='jabber:client' type='get' id='roster'><query xmlns='jabber:iq:roster'/></iq>")
+            .send("<iq xmlns='jabber:client' type='result' id='roster'><query xmlns='jabber:iq:roster'><item jid='romeo@montague.lit' subscription='both'/></query></iq>");
+        assert!(fakeserver::run(&aparte, &juliet, server).unwrap().is_done());
+        // Once reconnected, the roster not having changed
+        let server = FakeServer::new()
+            .expect("<iq xmlns='jabber:client' type='get' id='roster'><query xmlns='jabber:iq:roster'/></iq>")
+            .send("<iq xmlns='jabber:client' type='result' id='roster'/>")
+            // Pushed by the server of the account, or by the other account which can't
+            .send("<iq xmlns='jabber:client' type='set' id='push1'><query xmlns='jabber:iq:roster'><item jid='nurse@capulet.lit'/></query></iq>")
+            .expect("<iq xmlns='jabber:client' type='result' id='push1'/>")
+            .send("<iq xmlns='jabber:client' type='set' id='push2' from='romeo@montague.lit'><query xmlns='jabber:iq:roster'><item jid='tybalt@capulet.lit'/></query></iq>")
+            .send("<presence xmlns='jabber:client' from='romeo@montague.lit/orchard'/>");
+        assert!(fakeserver::run(&aparte, &juliet, server).unwrap().is_done());
+
+        let plugin = aparte.get_plugin::<ContactPlugin>().unwrap();
+        let jids = |account| {
+            let mut jids: Vec<String> = plugin.contacts(account).iter().map(|contact| contact.jid.to_string()).collect();
+            jids.sort();
+            jids
+        };
+        assert_eq!(jids(&romeo), vec!["juliet@capulet.lit"]);
+        assert_eq!(jids(&juliet), vec!["nurse@capulet.lit", "romeo@montague.lit"]);
+        // Presences only count for the roster of the account they were received on
+        let contact = |account, jid| plugin.contact(account, &BareJid::from_str(jid).unwrap()).unwrap().presence.clone();
+        assert_eq!(contact(&juliet, "romeo@montague.lit"), contact::Presence::Available);
+        assert_eq!(contact(&romeo, "juliet@capulet.lit"), contact::Presence::Unavailable);
+    }
+}
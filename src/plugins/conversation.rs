@@ -1,17 +1,177 @@
-use std::collections::HashMap;
+use futures::Future;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
 use std::fmt;
 use std::rc::Rc;
-use xmpp_parsers::{Jid, BareJid, muc};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+use xmpp_parsers::{Element, Jid, BareJid, FullJid, muc, presence};
+use xmpp_parsers::stanza_error::StanzaError;
+use xmpp_parsers::muc::muc::History;
 
 use crate::core::{Plugin, Aparte, Event};
 use crate::conversation;
+use crate::plugins::{bandwidth, disco};
+
+/// Maximum number of rooms being joined at the same time
+const JOIN_CONCURRENCY: usize = 3;
+/// Delay between two joins of the queue
+const JOIN_INTERVAL: Duration = Duration::from_millis(250);
+/// Delay after which a room not answering to our join is considered failed
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct ConversationPlugin {
     conversations: HashMap<String, conversation::Conversation>,
+    /// Rooms to join, with the account to join them on
+    join_queue: VecDeque<(FullJid, FullJid, Option<String>)>,
+    joining: HashSet<BareJid>,
+    join_scheduled: bool,
+    join_count: usize,
+    join_total: usize,
+    // Language of the messages of each conversation, set with /lang
+    langs: HashMap<BareJid, String>,
 }
 
 impl ConversationPlugin {
+    pub fn get(&self, jid: &BareJid) -> Option<&conversation::Conversation> {
+        self.conversations.get(&jid.to_string())
+    }
+
+    pub fn lang(&self, jid: &BareJid) -> Option<&str> {
+        self.langs.get(jid).map(|lang| lang.as_str())
+    }
+
+    /// Set the language of the messages sent to a conversation, and preferred in the ones
+    /// received from it. None goes back to messages without language.
+    pub fn set_lang(&mut self, jid: BareJid, lang: Option<String>) {
+        match lang {
+            Some(lang) => self.langs.insert(jid, lang),
+            None => self.langs.remove(&jid),
+        };
+    }
+
+    /// Presence joining a channel, asking for less history in low bandwidth mode
+    pub fn join_presence(aparte: &Aparte, from: Jid, to: FullJid, password: Option<String>) -> Element {
+        let mut muc = muc::Muc::new();
+        if let Some(password) = password {
+            muc = muc.with_password(password);
+        }
+        if bandwidth::is_low(aparte) {
+            muc = muc.with_history(History::new().with_maxstanzas(bandwidth::JOIN_HISTORY));
+        }
+        let caps = aparte.get_plugin::<disco::Disco>().unwrap().caps();
+
+        let mut presence = presence::Presence::new(presence::Type::None);
+        presence = presence.with_to(Jid::Full(to));
+        presence = presence.with_from(from);
+        presence.add_payload(muc);
+        presence.add_payload(caps);
+        presence.into()
+    }
+
+    /// Presence changing our nick in a channel
+    pub fn nick_presence(channel: &BareJid, nick: &str) -> Element {
+        presence::Presence::new(presence::Type::None)
+            .with_to(Jid::Full(channel.clone().with_resource(nick)))
+            .into()
+    }
+
+    /// Channels joined
+    pub fn channels(&self) -> impl Iterator<Item = &conversation::Channel> {
+        self.conversations.values().filter_map(|conversation| match conversation {
+            conversation::Conversation::Channel(channel) => Some(channel),
+            _ => None,
+        })
+    }
+
+    /// Presence leaving a channel, with the status shown to its occupants
+    pub fn leave_presence(channel: &conversation::Channel, status: Option<&str>) -> Element {
+        let mut presence = presence::Presence::new(presence::Type::Unavailable)
+            .with_to(Jid::Full(channel.jid.clone().with_resource(channel.nick.clone())));
+        if let Some(status) = status {
+            presence.statuses.insert(String::new(), status.to_string());
+        }
+        presence.into()
+    }
+
+    /// Queue a room to be joined. Joins are staggered and limited in number to avoid being
+    /// rate limited by the server when joining a lot of rooms at once.
+    pub fn queue_join(&mut self, aparte: Rc<Aparte>, account: FullJid, jid: FullJid, password: Option<String>) {
+        self.join_queue.push_back((account, jid, password));
+        self.join_total += 1;
+        self.schedule_join(aparte);
+    }
+
+    fn schedule_join(&mut self, aparte: Rc<Aparte>) {
+        if self.join_scheduled || self.join_queue.is_empty() || self.joining.len() >= JOIN_CONCURRENCY {
+            return;
+        }
+
+        self.join_scheduled = true;
+        let delay = Delay::new(Instant::now() + JOIN_INTERVAL);
+        tokio::runtime::current_thread::spawn(delay.map(move |_| {
+            ConversationPlugin::join_next(aparte);
+        }).map_err(|err| warn!("Join queue timer error: {}", err)));
+    }
+
+    fn join_next(aparte: Rc<Aparte>) {
+        let next = {
+            let mut plugin = aparte.get_plugin_mut::<ConversationPlugin>().unwrap();
+            plugin.join_scheduled = false;
+            match plugin.join_queue.pop_front() {
+                Some((account, jid, password)) => {
+                    plugin.joining.insert(jid.clone().into());
+                    plugin.join_count += 1;
+                    Some((account, jid, password, plugin.join_count, plugin.join_total))
+                },
+                None => None,
+            }
+        };
+
+        if let Some((account, jid, password, count, total)) = next {
+            if aparte.connections().contains(&account) {
+                let room: BareJid = jid.clone().into();
+                Rc::clone(&aparte).log(format!("Joining {} ({}/{})", room, count, total));
+                aparte.send_on(&account, ConversationPlugin::join_presence(&aparte, Jid::Full(account.clone()), jid.clone(), password));
+                Rc::clone(&aparte).event(Event::Join(account, jid.clone()));
+
+                let timeout = Delay::new(Instant::now() + JOIN_TIMEOUT);
+                let timeout_aparte = Rc::clone(&aparte);
+                tokio::runtime::current_thread::spawn(timeout.map(move |_| {
+                    let room: BareJid = jid.into();
+                    let timed_out = {
+                        let mut plugin = timeout_aparte.get_plugin_mut::<ConversationPlugin>().unwrap();
+                        plugin.joined(Rc::clone(&timeout_aparte), &room)
+                    };
+                    if timed_out {
+                        timeout_aparte.log(format!("Cannot join {}: timeout", room));
+                    }
+                }).map_err(|err| warn!("Join queue timer error: {}", err)));
+            } else {
+                let mut plugin = aparte.get_plugin_mut::<ConversationPlugin>().unwrap();
+                plugin.joining.remove(&jid.into());
+            }
+        }
+
+        let mut plugin = aparte.get_plugin_mut::<ConversationPlugin>().unwrap();
+        plugin.schedule_join(Rc::clone(&aparte));
+    }
+
+    /// Free the slot used by a room of the join queue once the room answered our join, returns
+    /// false if the room wasn't being joined.
+    fn joined(&mut self, aparte: Rc<Aparte>, jid: &BareJid) -> bool {
+        if !self.joining.remove(jid) {
+            return false;
+        }
+
+        if self.joining.is_empty() && self.join_queue.is_empty() {
+            self.join_count = 0;
+            self.join_total = 0;
+        }
+
+        self.schedule_join(aparte);
+        true
+    }
 }
 
 impl From<muc::user::Role> for conversation::Role {
@@ -41,6 +201,12 @@ impl Plugin for ConversationPlugin {
     fn new() -> ConversationPlugin {
         Self {
             conversations: HashMap::new(),
+            join_queue: VecDeque::new(),
+            joining: HashSet::new(),
+            join_scheduled: false,
+            join_count: 0,
+            join_total: 0,
+            langs: HashMap::new(),
         }
     }
 
@@ -56,23 +222,71 @@ impl Plugin for ConversationPlugin {
                 });
                 self.conversations.insert(jid.to_string(), conversation);
             },
-            Event::Join(jid) => {
+            Event::Join(account, jid) => {
                 let channel_jid: BareJid = jid.clone().into();
                 let conversation = conversation::Conversation::Channel(conversation::Channel {
+                    account: account.clone(),
                     jid: channel_jid.clone(),
                     nick: jid.resource.clone(),
                     name: None,
                     occupants: HashMap::new(),
+                    subject: None,
                 });
                 self.conversations.insert(channel_jid.to_string(), conversation);
             },
-            Event::Presence(presence) => {
+            Event::Presence(_, presence) => {
                 if let Some(Jid::Full(from)) = &presence.from {
                     let channel_jid: BareJid = from.clone().into();
+                    if self.joining.contains(&channel_jid) {
+                        if presence.type_ == presence::Type::Error {
+                            if self.joined(Rc::clone(&aparte), &channel_jid) {
+                                Rc::clone(&aparte).log(format!("Cannot join {}: error", channel_jid));
+                            }
+                        } else {
+                            let self_presence = presence.payloads.iter().filter_map(|payload| muc::user::MucUser::try_from(payload.clone()).ok())
+                                .any(|muc_user| muc_user.status.contains(&muc::user::Status::SelfPresence));
+                            if self_presence {
+                                self.joined(Rc::clone(&aparte), &channel_jid);
+                            }
+                        }
+                    }
+
                     if let Some(conversation::Conversation::Channel(channel)) = self.conversations.get_mut(&channel_jid.to_string()) {
+                        // Errors from another nick than ours answer a nick change
+                        if presence.type_ == presence::Type::Error && from.resource != channel.nick {
+                            let error = presence.payloads.iter().find_map(|payload| StanzaError::try_from(payload.clone()).ok());
+                            let condition = match error {
+                                Some(error) => Element::from(error.defined_condition).name().to_string(),
+                                None => String::from("error"),
+                            };
+                            Rc::clone(&aparte).event(Event::NickChangeError(channel_jid.clone(), from.resource.clone(), condition));
+                            return;
+                        }
+
                         for payload in presence.clone().payloads {
                             if let Some(muc_user) = muc::user::MucUser::try_from(payload).ok() {
+                                if presence.type_ == presence::Type::Unavailable {
+                                    channel.occupants.remove(&from.resource);
+                                    let new_nick = match muc_user.status.contains(&muc::user::Status::NewNick) {
+                                        true => muc_user.items.iter().find_map(|item| item.nick.clone()),
+                                        false => None,
+                                    };
+                                    match new_nick {
+                                        Some(new_nick) => {
+                                            if from.resource == channel.nick {
+                                                channel.nick = new_nick.clone();
+                                            }
+                                            Rc::clone(&aparte).event(Event::NickChange(channel_jid.clone(), from.resource.clone(), new_nick));
+                                        },
+                                        None => Rc::clone(&aparte).event(Event::OccupantLeft(channel_jid.clone(), from.resource.clone())),
+                                    }
+                                    continue;
+                                }
+
                                 for item in muc_user.items {
+                                    if item.role == muc::user::Role::None {
+                                        continue;
+                                    }
                                     let occupant_jid = match item.jid {
                                         Some(full) => Some(full.into()),
                                         None => None,
@@ -91,6 +305,11 @@ impl Plugin for ConversationPlugin {
                     }
                 }
             },
+            Event::Subject(channel, subject) => {
+                if let Some(conversation::Conversation::Channel(channel)) = self.conversations.get_mut(&channel.to_string()) {
+                    channel.subject = Some(subject.subject.clone()).filter(|subject| !subject.is_empty());
+                }
+            },
             _ => {},
         }
     }
@@ -101,3 +320,58 @@ impl fmt::Display for ConversationPlugin {
         write!(f, "Conversations management")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use crate::fakeserver::{self, FakeServer};
+
+    fn join(room: &str) -> String {
+        format!("<presence xmlns='jabber:client' to='{}@chat.server.tld/me'><x xmlns='http://jabber.org/protocol/muc'/></presence>", room)
+    }
+
+    fn joined(room: &str) -> String {
+        format!("<presence xmlns='jabber:client' from='{}@chat.server.tld/me'><x xmlns='http://jabber.org/protocol/muc#user'><item affiliation='member' role='participant'/><status code='110'/></x></presence>", room)
+    }
+
+    #[test]
+    fn test_join_queue() {
+        let aparte = fakeserver::aparte(|aparte| aparte.add_plugin(ConversationPlugin::new()));
+        let account = FullJid::from_str("me@server.tld/aparte").unwrap();
+        let rooms = ["one", "two", "three", "four"];
+        let queued = account.clone();
+        let server = FakeServer::new()
+            .act(move |aparte| {
+                for room in &rooms {
+                    let jid = FullJid::from_str(&format!("{}@chat.server.tld/me", room)).unwrap();
+                    aparte.get_plugin_mut::<ConversationPlugin>().unwrap().queue_join(Rc::clone(&aparte), queued.clone(), jid, None);
+                }
+            })
+            .expect(&join("one"))
+            .expect(&join("two"))
+            .expect(&join("three"))
+            // The fourth isn't even scheduled until one of the rooms being joined answers
+            .act(|aparte| {
+                let plugin = aparte.get_plugin::<ConversationPlugin>().unwrap();
+                assert_eq!(plugin.joining.len(), JOIN_CONCURRENCY);
+                assert_eq!(plugin.join_queue.len(), 1);
+                assert!(!plugin.join_scheduled);
+            })
+            .send(&joined("two"))
+            .expect(&join("four"))
+            .send(&joined("one"))
+            .send(&joined("three"))
+            .send(&joined("four"));
+        let server = fakeserver::run(&aparte, &account, server).unwrap();
+        assert!(server.is_done());
+
+        let joins: Vec<&str> = server.received.iter().filter(|stanza| stanza.name() == "presence").filter_map(|stanza| stanza.attr("to")).collect();
+        assert_eq!(joins, vec!["one@chat.server.tld/me", "two@chat.server.tld/me", "three@chat.server.tld/me", "four@chat.server.tld/me"]);
+
+        // Counting from the start again once every room answered
+        let plugin = aparte.get_plugin::<ConversationPlugin>().unwrap();
+        assert!(plugin.joining.is_empty());
+        assert_eq!((plugin.join_count, plugin.join_total), (0, 0));
+    }
+}
@@ -1,11 +1,187 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
 use std::rc::Rc;
+use futures::{future, Future};
+use uuid::Uuid;
+use xmpp_parsers::{Element, Jid, ns};
+use xmpp_parsers::caps::{self, Caps};
+use xmpp_parsers::disco::{DiscoInfoQuery, DiscoInfoResult, DiscoItemsQuery, DiscoItemsResult, Feature, Identity, Item};
+use xmpp_parsers::hashes::Algo;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::stanza_error::{DefinedCondition, ErrorType, StanzaError};
 
-use crate::core::{Plugin, Aparte, Event};
+use crate::core::{Plugin, Aparte, Event, IqError};
+use crate::plugins::ui::UIPlugin;
+
+pub const WINDOW: &str = "disco";
+
+/// Node identifying Aparté in our entity capabilities
+pub const NODE: &str = "https://github.com/paulfariello/aparte";
+
+/// Entity found while browsing, or the root of the browsing
+#[derive(Debug, Clone)]
+pub struct DiscoEntry {
+    pub jid: Jid,
+    pub node: Option<String>,
+    pub name: Option<String>,
+    /// Identities as category/type, with their name if any
+    identities: Vec<String>,
+    features: Vec<String>,
+    /// Indexes of the child items, None until the items are received
+    children: Option<Vec<usize>>,
+    error: Option<String>,
+}
+
+impl DiscoEntry {
+    fn new(jid: Jid, node: Option<String>, name: Option<String>) -> Self {
+        Self {
+            jid: jid,
+            node: node,
+            name: name,
+            identities: Vec::new(),
+            features: Vec::new(),
+            children: None,
+            error: None,
+        }
+    }
+}
+
+/// Tree of entities browsed with /disco, each entity being numbered to drill into it
+#[derive(Debug, Clone)]
+pub struct DiscoTree {
+    entries: Vec<DiscoEntry>,
+    /// Entity whose features are displayed
+    selected: usize,
+}
+
+impl DiscoTree {
+    pub fn new(jid: Jid, node: Option<String>) -> Self {
+        Self {
+            entries: vec![DiscoEntry::new(jid, node, None)],
+            selected: 0,
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&DiscoEntry> {
+        self.entries.get(index)
+    }
+
+    fn select(&mut self, index: usize) -> Result<(), String> {
+        match self.entries.get_mut(index) {
+            Some(entry) => {
+                entry.error = None;
+                self.selected = index;
+                Ok(())
+            },
+            None => Err(format!("No item {} in the disco browser", index)),
+        }
+    }
+
+    fn set_info(&mut self, index: usize, info: DiscoInfoResult) {
+        if let Some(entry) = self.entries.get_mut(index) {
+            entry.identities = info.identities.iter().map(|identity| match &identity.name {
+                Some(name) => format!("{}/{} ({})", identity.category, identity.type_, name),
+                None => format!("{}/{}", identity.category, identity.type_),
+            }).collect();
+            entry.features = info.features.into_iter().map(|feature| feature.var).collect();
+        }
+    }
+
+    fn set_items(&mut self, index: usize, items: DiscoItemsResult) {
+        let first = self.entries.len();
+        for item in items.items {
+            self.entries.push(DiscoEntry::new(item.jid, item.node, item.name));
+        }
+        let children = (first..self.entries.len()).collect();
+        if let Some(entry) = self.entries.get_mut(index) {
+            entry.children = Some(children);
+        }
+    }
+
+    fn set_error(&mut self, index: usize, error: String) {
+        if let Some(entry) = self.entries.get_mut(index) {
+            entry.error = Some(error);
+        }
+    }
+
+    fn render_entry(&self, index: usize, depth: usize, lines: &mut Vec<String>) {
+        let entry = &self.entries[index];
+        let indent = "  ".repeat(depth);
+        let marker = if entry.children.is_some() { "▾" } else { "▸" };
+
+        let mut line = format!("{}{} [{}] {}", indent, marker, index, entry.jid);
+        if let Some(node) = &entry.node {
+            line.push_str(&format!(" node={}", node));
+        }
+        if let Some(name) = &entry.name {
+            line.push_str(&format!(" \"{}\"", name));
+        }
+        if !entry.identities.is_empty() {
+            line.push_str(&format!(" — {}", entry.identities.join(", ")));
+        }
+        if let Some(error) = &entry.error {
+            line.push_str(&format!(" ✗ {}", error));
+        }
+        lines.push(line);
+
+        if index == self.selected {
+            for feature in &entry.features {
+                lines.push(format!("{}    · {}", indent, feature));
+            }
+        }
+
+        for child in entry.children.iter().flatten() {
+            self.render_entry(*child, depth + 1, lines);
+        }
+    }
+
+    /// Lines displaying the tree, features are only listed for the selected entity
+    pub fn render(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        self.render_entry(0, 0, &mut lines);
+        lines
+    }
+}
+
+/// Query of the identities and features of an entity
+pub fn info_query(jid: Jid) -> Iq {
+    Iq::from_get(Uuid::new_v4().to_hyphenated().to_string(), DiscoInfoQuery { node: None }).with_to(jid)
+}
+
+/// Query of the items of an entity
+pub fn items_query(jid: Jid) -> Iq {
+    Iq::from_get(Uuid::new_v4().to_hyphenated().to_string(), DiscoItemsQuery { node: None }).with_to(jid)
+}
+
+/// Identities and features answered to a disco#info query, if it succeeded
+pub fn info_result(answer: Result<Iq, IqError>) -> Option<DiscoInfoResult> {
+    match answer.ok()?.payload {
+        IqType::Result(Some(payload)) => DiscoInfoResult::try_from(payload).ok(),
+        _ => None,
+    }
+}
+
+/// Items answered to a disco#items query, none if it failed
+pub fn items_result(answer: Result<Iq, IqError>) -> Vec<Item> {
+    match answer.map(|answer| answer.payload) {
+        Ok(IqType::Result(Some(payload))) => DiscoItemsResult::try_from(payload).map(|result| result.items).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Request {
+    Info,
+    Items,
+}
 
 #[allow(non_camel_case_types)]
 pub struct Disco<'a> {
     features: Vec<&'a str>,
+    tree: Option<DiscoTree>,
+    /// Queries of the browser waiting for an answer, by id
+    requests: HashMap<String, (usize, Request)>,
 }
 
 impl<'a> Disco<'a> {
@@ -15,18 +191,158 @@ impl<'a> Disco<'a> {
 
         Ok(())
     }
+
+    /// What we answer to a disco#info query
+    pub fn info(&self) -> DiscoInfoResult {
+        let mut features = vec![Feature::new(ns::DISCO_INFO), Feature::new(ns::CAPS)];
+        features.extend(self.features.iter().map(|feature| Feature::new(*feature)));
+        DiscoInfoResult {
+            node: None,
+            identities: vec![Identity {
+                category: String::from("client"),
+                type_: String::from("console"),
+                lang: None,
+                name: Some(String::from("Aparté")),
+            }],
+            features: features,
+            extensions: Vec::new(),
+        }
+    }
+
+    /// Entity capabilities (XEP-0115) to include in our presences
+    pub fn caps(&self) -> Caps {
+        let hash = caps::hash_caps(&caps::compute_disco(&self.info()), Algo::Sha_1).unwrap();
+        Caps::new(NODE, hash)
+    }
+
+    /// Answer to a disco#info query of another entity, about us or about our capabilities node
+    fn query(&self, iq: &Iq) -> Option<Element> {
+        let query = match &iq.payload {
+            IqType::Get(payload) => DiscoInfoQuery::try_from(payload.clone()).ok()?,
+            _ => return None,
+        };
+
+        let mut answer = match &query.node {
+            None => Iq::from_result(iq.id.clone(), Some(self.info())),
+            Some(node) if *node == format!("{}#{}", NODE, self.caps().hash.to_base64()) => {
+                let mut info = self.info();
+                info.node = query.node.clone();
+                Iq::from_result(iq.id.clone(), Some(info))
+            },
+            Some(node) => Iq::from_error(iq.id.clone(), StanzaError::new(ErrorType::Cancel, DefinedCondition::ItemNotFound, "en", format!("Unknown node {}", node))),
+        };
+        answer.to = iq.from.clone();
+        Some(answer.into())
+    }
+
+    fn queries(&mut self, index: usize) -> Vec<Iq> {
+        let entry = self.tree.as_ref().unwrap().get(index).unwrap();
+        let (jid, node) = (entry.jid.clone(), entry.node.clone());
+
+        let info_id = Uuid::new_v4().to_hyphenated().to_string();
+        let items_id = Uuid::new_v4().to_hyphenated().to_string();
+        self.requests.insert(info_id.clone(), (index, Request::Info));
+        self.requests.insert(items_id.clone(), (index, Request::Items));
+
+        vec![
+            Iq::from_get(info_id, DiscoInfoQuery { node: node.clone() }).with_to(jid.clone()),
+            Iq::from_get(items_id, DiscoItemsQuery { node: node }).with_to(jid),
+        ]
+    }
+
+    /// Start browsing from an entity, returns the queries to send
+    pub fn browse(&mut self, jid: Jid, node: Option<String>) -> Vec<Iq> {
+        self.tree = Some(DiscoTree::new(jid, node));
+        self.requests.clear();
+        self.queries(0)
+    }
+
+    /// Drill into an entity of the tree being browsed, returns the queries to send
+    pub fn expand(&mut self, index: usize) -> Result<Vec<Iq>, String> {
+        match &mut self.tree {
+            Some(tree) => tree.select(index)?,
+            None => return Err(format!("Nothing is being browsed, use /disco <jid> first")),
+        }
+        Ok(self.queries(index))
+    }
+
+    /// Send the queries of the browser, the tree being updated as answers come
+    pub fn send(aparte: Rc<Aparte>, queries: Vec<Iq>) -> impl Future<Item = (), Error = String> {
+        future::join_all(queries.into_iter().map(move |query| {
+            let id = query.id.clone();
+            let answer_aparte = Rc::clone(&aparte);
+            Rc::clone(&aparte).send_iq(query).then(move |answer| {
+                let mut disco = answer_aparte.get_plugin_mut::<Disco>().unwrap();
+                if disco.answer(&id, answer) {
+                    disco.update_ui(Rc::clone(&answer_aparte));
+                }
+                Ok(())
+            })
+        }).collect::<Vec<_>>()).map(|_| ())
+    }
+
+    /// Fill the tree with the answer to one of its queries, unless browsing started anew since
+    fn answer(&mut self, id: &str, answer: Result<Iq, IqError>) -> bool {
+        let (index, request) = match self.requests.remove(id) {
+            Some(request) => request,
+            None => return false,
+        };
+        let tree = match &mut self.tree {
+            Some(tree) => tree,
+            None => return false,
+        };
+
+        match (answer.map(|iq| iq.payload), request) {
+            (Ok(IqType::Result(Some(payload))), Request::Info) => match DiscoInfoResult::try_from(payload) {
+                Ok(info) => tree.set_info(index, info),
+                Err(err) => tree.set_error(index, format!("invalid info: {}", err)),
+            },
+            (Ok(IqType::Result(Some(payload))), Request::Items) => match DiscoItemsResult::try_from(payload) {
+                Ok(items) => tree.set_items(index, items),
+                Err(err) => tree.set_error(index, format!("invalid items: {}", err)),
+            },
+            (Ok(IqType::Result(None)), Request::Items) => tree.set_items(index, DiscoItemsResult { node: None, items: Vec::new() }),
+            (Err(IqError::Error(error)), _) => {
+                let condition = Element::from(error.defined_condition.clone()).name().to_string();
+                tree.set_error(index, condition);
+            },
+            (Err(err), _) => tree.set_error(index, err.to_string()),
+            _ => {},
+        }
+        true
+    }
+
+    fn update_ui(&self, aparte: Rc<Aparte>) {
+        if let Some(tree) = &self.tree {
+            let mut ui = aparte.get_plugin_mut::<UIPlugin>().unwrap();
+            ui.clear_window(WINDOW);
+            ui.log_to(Rc::clone(&aparte), WINDOW, tree.render().join("\n"));
+        }
+    }
 }
 
 impl<'a> Plugin for Disco<'a> {
     fn new() -> Disco<'a> {
-        Disco { features: Vec::new() }
+        Disco {
+            features: Vec::new(),
+            tree: None,
+            requests: HashMap::new(),
+        }
     }
 
     fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
         Ok(())
     }
 
-    fn on_event(&mut self, _aparte: Rc<Aparte>, _event: &Event) {
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Iq(account, iq) => {
+                if let Some(answer) = self.query(iq) {
+                    aparte.send_on(account, answer);
+                }
+            },
+            _ => {},
+        }
     }
 }
 
@@ -35,3 +351,66 @@ impl<'a> fmt::Display for Disco<'a> {
         write!(f, "XEP-0030: Service Discovery")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_browse() {
+        let mut disco = Disco::new();
+        let queries = disco.browse(Jid::from_str("server.tld").unwrap(), None);
+        assert_eq!(queries.len(), 2);
+
+        let ids: Vec<String> = queries.iter().map(|query| query.id.clone()).collect();
+        let info: Element = format!(r#"<iq xmlns="jabber:client" type="result" id="{}"><query xmlns="http://jabber.org/protocol/disco#info"><identity category="server" type="im"/><feature var="http://jabber.org/protocol/disco#info"/><feature var="urn:xmpp:ping"/></query></iq>"#, ids[0]).parse().unwrap();
+        let items: Element = format!(r#"<iq xmlns="jabber:client" type="result" id="{}"><query xmlns="http://jabber.org/protocol/disco#items"><item jid="conference.server.tld" name="Chatrooms"/><item jid="upload.server.tld"/></query></iq>"#, ids[1]).parse().unwrap();
+        assert!(disco.answer(&ids[0], Ok(Iq::try_from(info).unwrap())));
+        assert!(disco.answer(&ids[1], Ok(Iq::try_from(items).unwrap())));
+        // Answered once
+        assert!(!disco.answer(&ids[1], Err(IqError::Timeout)));
+
+        assert_eq!(disco.tree.as_ref().unwrap().render(), vec![
+            "▾ [0] server.tld — server/im",
+            "    · http://jabber.org/protocol/disco#info",
+            "    · urn:xmpp:ping",
+            "  ▸ [1] conference.server.tld \"Chatrooms\"",
+            "  ▸ [2] upload.server.tld",
+        ]);
+
+        let ids: Vec<String> = disco.expand(2).unwrap().iter().map(|query| query.id.clone()).collect();
+        assert!(disco.answer(&ids[0], Err(IqError::Timeout)));
+        assert_eq!(disco.tree.as_ref().unwrap().render()[2], "  ▸ [2] upload.server.tld ✗ no answer after 30s");
+        assert!(disco.expand(3).is_err());
+    }
+
+    #[test]
+    fn test_caps() {
+        let mut disco = Disco::new();
+        disco.add_feature("urn:xmpp:carbons:2").unwrap();
+        let ver = disco.caps().hash.to_base64();
+
+        let query = |node: String| {
+            let element: Element = format!(r#"<iq xmlns="jabber:client" type="get" id="info" from="contact@server.tld/phone"><query xmlns="http://jabber.org/protocol/disco#info" node="{}"/></iq>"#, node).parse().unwrap();
+            Iq::try_from(element).unwrap()
+        };
+
+        let answer = Iq::try_from(disco.query(&query(format!("{}#{}", NODE, ver))).unwrap()).unwrap();
+        assert_eq!(answer.to.unwrap().to_string(), "contact@server.tld/phone");
+        let info = match answer.payload {
+            IqType::Result(Some(payload)) => DiscoInfoResult::try_from(payload).unwrap(),
+            _ => panic!("Expected a disco#info result"),
+        };
+        assert_eq!(info.features.len(), 3);
+        // The advertised ver must match what peers compute from our disco#info
+        assert_eq!(caps::hash_caps(&caps::compute_disco(&info), Algo::Sha_1).unwrap().to_base64(), ver);
+
+        let answer = Iq::try_from(disco.query(&query(format!("{}#stale", NODE))).unwrap()).unwrap();
+        assert!(match answer.payload { IqType::Error(_) => true, _ => false });
+
+        // Features added later change the ver
+        disco.add_feature("urn:xmpp:blocking").unwrap();
+        assert_ne!(disco.caps().hash.to_base64(), ver);
+    }
+}
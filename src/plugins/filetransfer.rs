@@ -0,0 +1,1244 @@
+use chrono::Utc;
+use futures::future::{self, Either, Loop};
+use futures::Future;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use tokio::net::TcpStream;
+use uuid::Uuid;
+use xmpp_parsers::disco::{DiscoInfoQuery, DiscoInfoResult, DiscoItemsQuery, DiscoItemsResult};
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::stanza_error::{DefinedCondition, ErrorType, StanzaError};
+use xmpp_parsers::{ns, BareJid, Element, FullJid, Jid};
+
+use crate::client;
+use crate::core::{Plugin, Aparte, Event};
+use crate::message::{LogMessage, Message};
+use crate::plugins::calls::CallsPlugin;
+use crate::plugins::disco;
+use crate::plugins::ui::UIPlugin;
+use crate::{profile, socks5};
+
+const NS_BYTESTREAMS: &str = "http://jabber.org/protocol/bytestreams";
+
+/// Bytes read or written at once on a SOCKS5 bytestream
+const CHUNK: usize = 16 * 1024;
+/// Bytes sent in each in-band data
+const BLOCK_SIZE: u16 = 4096;
+/// Priority of a proxy candidate, its type preference being 10 (XEP-0260)
+const PROXY_PRIORITY: u32 = 10 << 16;
+/// Progress shown every so many bytes, for files of unknown size
+const PROGRESS_STEP: u64 = 1024 * 1024;
+
+/// Size in bytes, in a human readable unit
+pub fn size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Candidate {
+    cid: String,
+    host: String,
+    /// JID of the proxy, or of the peer for direct candidates
+    jid: String,
+    port: u16,
+    priority: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Transport {
+    /// In-band bytestream (XEP-0261), the data being sent in iqs
+    Ibb { sid: String, block_size: u16 },
+    /// SOCKS5 bytestream (XEP-0260), through one of the candidates of the sender
+    Socks5 { sid: String, candidates: Vec<Candidate> },
+}
+
+impl Transport {
+    fn parse(transport: &Element) -> Option<Transport> {
+        let sid = transport.attr("sid")?.to_string();
+        if transport.is("transport", ns::JINGLE_IBB) {
+            let block_size = transport.attr("block-size")?.parse().ok()?;
+            Some(Transport::Ibb { sid: sid, block_size: block_size })
+        } else if transport.is("transport", ns::JINGLE_S5B) {
+            let mut candidates: Vec<Candidate> = transport.children()
+                .filter(|candidate| candidate.is("candidate", ns::JINGLE_S5B))
+                .filter_map(|candidate| Some(Candidate {
+                    cid: candidate.attr("cid")?.to_string(),
+                    host: candidate.attr("host")?.to_string(),
+                    jid: candidate.attr("jid").unwrap_or("").to_string(),
+                    port: match candidate.attr("port") {
+                        Some(port) => port.parse().ok()?,
+                        None => 1080,
+                    },
+                    priority: candidate.attr("priority")?.parse().ok()?,
+                }))
+                .collect();
+            candidates.sort_by(|a, b| b.priority.cmp(&a.priority));
+            Some(Transport::Socks5 { sid: sid, candidates: candidates })
+        } else {
+            None
+        }
+    }
+
+    /// Transport in our answer, we don't offer SOCKS5 candidates of our own
+    fn answer(&self) -> Element {
+        match self {
+            Transport::Ibb { sid, block_size } => Element::builder("transport").ns(ns::JINGLE_IBB)
+                .attr("sid", sid.as_str())
+                .attr("block-size", block_size.to_string())
+                .build(),
+            Transport::Socks5 { sid, .. } => Element::builder("transport").ns(ns::JINGLE_S5B)
+                .attr("sid", sid.as_str())
+                .attr("mode", "tcp")
+                .build(),
+        }
+    }
+
+    /// Transport in our offer, with the proxy we connect through
+    fn offer(&self) -> Element {
+        let mut transport = self.answer();
+        if let Transport::Socks5 { candidates, .. } = self {
+            for candidate in candidates {
+                transport.append_child(Element::builder("candidate").ns(ns::JINGLE_S5B)
+                    .attr("cid", candidate.cid.as_str())
+                    .attr("host", candidate.host.as_str())
+                    .attr("jid", candidate.jid.as_str())
+                    .attr("port", candidate.port.to_string())
+                    .attr("priority", candidate.priority.to_string())
+                    .attr("type", "proxy")
+                    .build());
+            }
+        }
+        transport
+    }
+}
+
+/// SOCKS5 bytestreams proxy (XEP-0065) of our server, for peers we cannot connect to directly
+#[derive(Debug, Clone, PartialEq)]
+struct Proxy {
+    jid: Jid,
+    host: String,
+    port: u16,
+}
+
+impl Proxy {
+    /// Proxy in the answer to a bytestreams query
+    fn parse(jid: Jid, query: &Element) -> Option<Proxy> {
+        let streamhost = query.get_child("streamhost", NS_BYTESTREAMS)?;
+        Some(Proxy {
+            jid: jid,
+            host: streamhost.attr("host")?.to_string(),
+            port: streamhost.attr("port")?.parse().ok()?,
+        })
+    }
+}
+
+/// File offered in a Jingle session (XEP-0234), to us or by us
+#[derive(Debug, Clone)]
+pub struct Offer {
+    /// Peer offering the file, or the one we offer it to
+    pub from: Jid,
+    /// Our JID the offer was sent to or from
+    account: FullJid,
+    incoming: bool,
+    sid: String,
+    /// Name and creator of the content of the session holding the file
+    content: String,
+    creator: String,
+    description: Element,
+    pub name: String,
+    pub size: Option<u64>,
+    transport: Transport,
+}
+
+impl Offer {
+    /// Offer in a session-initiate, or the reason to terminate the session if not supported
+    fn parse(from: Jid, account: FullJid, jingle: &Element) -> Result<Offer, &'static str> {
+        let sid = jingle.attr("sid").ok_or("failed-application")?;
+        let content = jingle.get_child("content", ns::JINGLE).ok_or("failed-application")?;
+        let description = content.get_child("description", ns::JINGLE_FT).ok_or("unsupported-applications")?;
+        let file = description.get_child("file", ns::JINGLE_FT).ok_or("unsupported-applications")?;
+        let transport = content.children()
+            .find(|child| child.name() == "transport")
+            .and_then(Transport::parse)
+            .ok_or("unsupported-transports")?;
+
+        // Only the name of the file is kept, not where it was on the sender's side
+        let name = file.get_child("name", ns::JINGLE_FT)
+            .map(|name| name.text())
+            .and_then(|name| Path::new(&name).file_name().map(|name| name.to_string_lossy().to_string()))
+            .unwrap_or_else(|| String::from("file"));
+
+        Ok(Offer {
+            from: from,
+            account: account,
+            incoming: true,
+            sid: sid.to_string(),
+            content: content.attr("name").unwrap_or("file").to_string(),
+            creator: content.attr("creator").unwrap_or("initiator").to_string(),
+            description: description.clone(),
+            name: name,
+            size: file.get_child("size", ns::JINGLE_FT).and_then(|size| size.text().parse().ok()),
+            transport: transport,
+        })
+    }
+
+    /// Offer of one of our files to `to`, through the proxy of our server if it has one and
+    /// in-band otherwise
+    fn outgoing(account: FullJid, to: FullJid, name: String, size: u64, proxy: Option<&Proxy>) -> Offer {
+        let transport = match proxy {
+            Some(proxy) => Transport::Socks5 {
+                sid: Uuid::new_v4().to_hyphenated().to_string(),
+                candidates: vec![Candidate {
+                    cid: Uuid::new_v4().to_hyphenated().to_string(),
+                    host: proxy.host.clone(),
+                    jid: proxy.jid.to_string(),
+                    port: proxy.port,
+                    priority: PROXY_PRIORITY,
+                }],
+            },
+            None => Transport::Ibb {
+                sid: Uuid::new_v4().to_hyphenated().to_string(),
+                block_size: BLOCK_SIZE,
+            },
+        };
+        let description = Element::builder("description").ns(ns::JINGLE_FT)
+            .append(Element::builder("file").ns(ns::JINGLE_FT)
+                .append(Element::builder("name").ns(ns::JINGLE_FT).append(name.as_str()).build())
+                .append(Element::builder("size").ns(ns::JINGLE_FT).append(size.to_string()).build())
+                .build())
+            .build();
+        Offer {
+            from: Jid::Full(to),
+            account: account,
+            incoming: false,
+            sid: Uuid::new_v4().to_hyphenated().to_string(),
+            content: String::from("a-file-offer"),
+            creator: String::from("initiator"),
+            description: description,
+            name: name,
+            size: Some(size),
+            transport: transport,
+        }
+    }
+
+    fn initiate(&self) -> Iq {
+        let mut jingle = self.jingle("session-initiate");
+        jingle.set_attr("initiator", self.account.to_string());
+        jingle.append_child(self.content(self.transport.offer(), true));
+        self.iq(jingle)
+    }
+
+    fn jingle(&self, action: &str) -> Element {
+        Element::builder("jingle").ns(ns::JINGLE)
+            .attr("action", action)
+            .attr("sid", self.sid.as_str())
+            .build()
+    }
+
+    fn content(&self, transport: Element, description: bool) -> Element {
+        let mut content = Element::builder("content").ns(ns::JINGLE)
+            .attr("creator", self.creator.as_str())
+            .attr("name", self.content.as_str())
+            .build();
+        if description {
+            content.append_child(self.description.clone());
+        }
+        content.append_child(transport);
+        content
+    }
+
+    fn iq(&self, jingle: Element) -> Iq {
+        Iq {
+            from: None,
+            to: Some(self.from.clone()),
+            id: Uuid::new_v4().to_hyphenated().to_string(),
+            payload: IqType::Set(jingle),
+        }
+    }
+
+    fn accept(&self) -> Iq {
+        let mut jingle = self.jingle("session-accept");
+        jingle.set_attr("responder", self.account.to_string());
+        jingle.append_child(self.content(self.transport.answer(), true));
+        self.iq(jingle)
+    }
+
+    fn terminate(&self, reason: &str) -> Iq {
+        let mut jingle = self.jingle("session-terminate");
+        jingle.append_child(Element::builder("reason").ns(ns::JINGLE)
+            .append(Element::builder(reason).ns(ns::JINGLE).build())
+            .build());
+        self.iq(jingle)
+    }
+
+    /// Replace a SOCKS5 transport none of the candidates of worked for by an in-band one
+    fn fallback(&mut self) -> Iq {
+        self.transport = Transport::Ibb {
+            sid: Uuid::new_v4().to_hyphenated().to_string(),
+            block_size: BLOCK_SIZE,
+        };
+        let mut jingle = self.jingle("transport-replace");
+        jingle.append_child(self.content(self.transport.offer(), false));
+        self.iq(jingle)
+    }
+
+    fn transport_info(&self, sid: &str, payload: Element) -> Iq {
+        let mut jingle = self.jingle("transport-info");
+        let transport = Element::builder("transport").ns(ns::JINGLE_S5B)
+            .attr("sid", sid)
+            .append(payload)
+            .build();
+        jingle.append_child(self.content(transport, false));
+        self.iq(jingle)
+    }
+
+    /// Address the SOCKS5 candidates of the sender are connected to, identifying the stream
+    fn socks5_address(&self, sid: &str) -> String {
+        let (initiator, responder) = match self.incoming {
+            true => (self.from.to_string(), self.account.to_string()),
+            false => (self.account.to_string(), self.from.to_string()),
+        };
+        let hash = openssl::sha::sha1(format!("{}{}{}", sid, initiator, responder).as_bytes());
+        hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+impl fmt::Display for Offer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.size {
+            Some(bytes) => write!(f, "{} ({})", self.name, size(bytes)),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// File being received or sent
+struct Transfer {
+    offer: Offer,
+    path: PathBuf,
+    file: fs::File,
+    transferred: u64,
+    /// Line showing the progress, replaced as data is received or sent
+    progress: LogMessage,
+    shown: u64,
+    /// Sequence number of the next in-band data
+    seq: u16,
+}
+
+impl Transfer {
+    fn new(offer: Offer, path: PathBuf, file: fs::File) -> Transfer {
+        Transfer {
+            progress: LogMessage {
+                id: format!("transfer-{}", offer.sid),
+                timestamp: Utc::now(),
+                body: String::new(),
+            },
+            offer: offer,
+            path: path,
+            file: file,
+            transferred: 0,
+            shown: 0,
+            seq: 0,
+        }
+    }
+
+    fn progress(&mut self) -> Message {
+        let action = match self.offer.incoming {
+            true => format!("Receiving {} from {}", self.offer.name, self.offer.from),
+            false => format!("Sending {} to {}", self.offer.name, self.offer.from),
+        };
+        self.progress.body = match self.offer.size {
+            Some(total) if total > 0 => format!("{}: {}% ({} of {})", action, self.transferred * 100 / total, size(self.transferred), size(total)),
+            _ => format!("{}: {}", action, size(self.transferred)),
+        };
+        Message::Log(self.progress.clone())
+    }
+
+    /// Count transferred bytes, returns the progress if it changed enough to be shown
+    fn advance(&mut self, bytes: usize) -> Option<Message> {
+        self.transferred += bytes as u64;
+        let step = match self.offer.size {
+            Some(total) if total > 0 => self.transferred * 100 / total,
+            _ => self.transferred / PROGRESS_STEP,
+        };
+        match step != self.shown {
+            true => {
+                self.shown = step;
+                Some(self.progress())
+            },
+            false => None,
+        }
+    }
+
+    /// Write received data to the file, returns the progress if it changed enough to be shown
+    fn write(&mut self, data: &[u8]) -> Result<Option<Message>, String> {
+        self.file.write_all(data).map_err(|err| format!("Cannot write {}: {}", self.path.display(), err))?;
+        Ok(self.advance(data.len()))
+    }
+
+    /// Read the next data to send, none at the end of the file
+    fn read(&mut self, len: usize) -> Result<(Vec<u8>, Option<Message>), String> {
+        let mut data = vec![0; len];
+        let read = self.file.read(&mut data).map_err(|err| format!("Cannot read {}: {}", self.path.display(), err))?;
+        data.truncate(read);
+        Ok((data, self.advance(read)))
+    }
+
+    fn complete(&self) -> bool {
+        self.offer.size.map_or(false, |total| self.transferred >= total)
+    }
+}
+
+/// What happened to a transfer, to be shown once the plugin isn't borrowed anymore
+enum Outcome {
+    Nothing,
+    Progress(Message),
+    /// Negotiated, the file can be sent
+    Start(String),
+    Done(Offer, PathBuf),
+    Failed(Offer, String),
+}
+
+/// Receive and send files with Jingle file transfer (XEP-0234), over in-band (XEP-0261) or SOCKS5
+/// (XEP-0260) bytestreams
+pub struct FileTransferPlugin {
+    /// Offers waiting for /accept-file or /decline-file, oldest first
+    offers: Vec<Offer>,
+    /// Files being received or sent by Jingle session id
+    transfers: HashMap<String, Transfer>,
+    /// SOCKS5 proxy of our server, if it has one
+    proxy: Option<Proxy>,
+}
+
+/// Where to save a file, in the download directory unless told otherwise
+fn destination(name: &str, path: Option<&str>) -> Result<PathBuf, String> {
+    match path.map(profile::expand_home).transpose()? {
+        Some(path) if path.is_dir() => Ok(path.join(name)),
+        Some(path) => Ok(path),
+        None => Ok(dirs::download_dir().or_else(dirs::home_dir).unwrap_or_default().join(name)),
+    }
+}
+
+fn result(iq: &Iq) -> Iq {
+    Iq {
+        from: None,
+        to: iq.from.clone(),
+        id: iq.id.clone(),
+        payload: IqType::Result(None),
+    }
+}
+
+fn error(iq: &Iq, condition: DefinedCondition, text: &str) -> Iq {
+    let mut error = Iq::from_error(iq.id.clone(), StanzaError::new(ErrorType::Cancel, condition, "en", text));
+    error.to = iq.from.clone();
+    error
+}
+
+impl FileTransferPlugin {
+    /// Accept the oldest offer, returns the answer to send and the progress line
+    pub fn accept(&mut self, path: Option<&str>) -> Result<(Offer, Iq, Message), String> {
+        if self.offers.is_empty() {
+            return Err(format!("No file offered"));
+        }
+        let path = destination(&self.offers[0].name, path)?;
+        let file = fs::OpenOptions::new().write(true).create_new(true).open(&path)
+            .map_err(|err| format!("Cannot create {}: {}, give another path", path.display(), err))?;
+        let offer = self.offers.remove(0);
+        let accept = offer.accept();
+        let mut transfer = Transfer::new(offer.clone(), path, file);
+        let progress = transfer.progress();
+        self.transfers.insert(offer.sid.clone(), transfer);
+        Ok((offer, accept, progress))
+    }
+
+    /// Decline the oldest offer, returns the termination to send
+    pub fn decline(&mut self) -> Result<(Offer, Iq), String> {
+        if self.offers.is_empty() {
+            return Err(format!("No file offered"));
+        }
+        let offer = self.offers.remove(0);
+        let terminate = offer.terminate("decline");
+        Ok((offer, terminate))
+    }
+
+    /// Handle data received for a transfer
+    fn receive(&mut self, sid: &str, data: &[u8]) -> Outcome {
+        let written = match self.transfers.get_mut(sid) {
+            Some(transfer) => transfer.write(data).map(|progress| (progress, transfer.complete())),
+            None => return Outcome::Nothing,
+        };
+        match written {
+            Ok((_, true)) => self.finish(sid, true),
+            Ok((Some(progress), false)) => Outcome::Progress(progress),
+            Ok((None, false)) => Outcome::Nothing,
+            Err(err) => match self.finish(sid, false) {
+                Outcome::Failed(offer, _) => Outcome::Failed(offer, err),
+                outcome => outcome,
+            },
+        }
+    }
+
+    /// End a transfer, removing what was received unless it succeeded
+    fn finish(&mut self, sid: &str, success: bool) -> Outcome {
+        let transfer = match self.transfers.remove(sid) {
+            Some(transfer) => transfer,
+            None => return Outcome::Nothing,
+        };
+        match (success, transfer.offer.incoming) {
+            (true, _) => Outcome::Done(transfer.offer, transfer.path),
+            (false, incoming) => {
+                if incoming {
+                    let _ = fs::remove_file(&transfer.path);
+                }
+                let transferred = format!("{} of {} {}", size(transfer.transferred), transfer.offer.size.map(size).unwrap_or(String::from("?")), match incoming {
+                    true => "received",
+                    false => "sent",
+                });
+                Outcome::Failed(transfer.offer, transferred)
+            },
+        }
+    }
+
+    /// Show what happened to a transfer, terminating its session once the file is transferred
+    fn show(aparte: Rc<Aparte>, outcome: Outcome) {
+        match outcome {
+            Outcome::Nothing => {},
+            Outcome::Progress(progress) => {
+                aparte.get_plugin_mut::<UIPlugin>().unwrap().replace_message(Rc::clone(&aparte), &progress);
+            },
+            Outcome::Start(sid) => FileTransferPlugin::upload(aparte, sid),
+            Outcome::Done(offer, path) => {
+                // Both ends may terminate the session, the other one then not knowing it anymore
+                let terminate = Rc::clone(&aparte).send_iq(offer.terminate("success"));
+                Rc::clone(&aparte).spawn(terminate.then(|_| Ok(())));
+                match offer.incoming {
+                    true => aparte.log(format!("Received {} from {} to {}", offer, offer.from, path.display())),
+                    false => aparte.log(format!("Sent {} to {}", offer, offer.from)),
+                }
+            },
+            Outcome::Failed(offer, reason) => match offer.incoming {
+                true => aparte.log(format!("Transfer of {} from {} failed, {}", offer.name, offer.from, reason)),
+                false => aparte.log(format!("Transfer of {} to {} failed, {}", offer.name, offer.from, reason)),
+            },
+        }
+    }
+
+    /// Give up a transfer, terminating its session
+    fn abort(aparte: Rc<Aparte>, sid: &str, reason: String) {
+        let outcome = aparte.get_plugin_mut::<FileTransferPlugin>().unwrap().finish(sid, false);
+        if let Outcome::Failed(offer, transferred) = outcome {
+            aparte.send(offer.terminate("failed-transport").into());
+            FileTransferPlugin::show(aparte, Outcome::Failed(offer, format!("{}, {}", reason, transferred)));
+        }
+    }
+
+    /// Offer one of our files to `to`, returns the session id, the offer to send and the progress
+    /// line
+    pub fn send(&mut self, account: FullJid, to: FullJid, path: &str) -> Result<(String, Iq, Message), String> {
+        let path = profile::expand_home(path)?;
+        let file = fs::File::open(&path).map_err(|err| format!("Cannot open {}: {}", path.display(), err))?;
+        let metadata = file.metadata().map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+        if !metadata.is_file() {
+            return Err(format!("{} isn't a file", path.display()));
+        }
+        let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_else(|| String::from("file"));
+        let offer = Offer::outgoing(account, to, name, metadata.len(), self.proxy.as_ref());
+        let initiate = offer.initiate();
+        let mut transfer = Transfer::new(offer.clone(), path, file);
+        let progress = transfer.progress();
+        self.transfers.insert(offer.sid.clone(), transfer);
+        Ok((offer.sid, initiate, progress))
+    }
+
+    /// Forget a file we offered, the peer having refused the session
+    pub fn cancel(&mut self, sid: &str) {
+        self.transfers.remove(sid);
+    }
+
+    /// Look for the SOCKS5 proxy of our server, to offer files through it
+    fn discover(aparte: Rc<Aparte>, server: BareJid) {
+        let items = Iq::from_get(Uuid::new_v4().to_hyphenated().to_string(), DiscoItemsQuery { node: None }).with_to(Jid::Bare(server));
+        let info_aparte = Rc::clone(&aparte);
+        let query_aparte = Rc::clone(&aparte);
+        let discovery = Rc::clone(&aparte).send_iq(items).map_err(|err| format!("Cannot list the services of the server: {}", err)).and_then(move |items| {
+            let items = match items.payload {
+                IqType::Result(Some(payload)) => DiscoItemsResult::try_from(payload).map(|result| result.items).unwrap_or_default(),
+                _ => Vec::new(),
+            };
+            future::join_all(items.into_iter().filter(|item| item.node.is_none()).map(move |item| {
+                let query = Iq::from_get(Uuid::new_v4().to_hyphenated().to_string(), DiscoInfoQuery { node: None }).with_to(item.jid.clone());
+                Rc::clone(&info_aparte).send_iq(query).then(move |info| Ok(match info {
+                    Ok(Iq { payload: IqType::Result(Some(payload)), .. }) => DiscoInfoResult::try_from(payload).ok()
+                        .filter(|info| info.identities.iter().any(|identity| identity.category == "proxy" && identity.type_ == "bytestreams"))
+                        .map(|_| item.jid),
+                    _ => None,
+                }))
+            }))
+        }).and_then(move |proxies: Vec<Option<Jid>>| {
+            let jid = match proxies.into_iter().flatten().next() {
+                Some(jid) => jid,
+                None => return Either::A(future::ok(())),
+            };
+            let query = Iq {
+                from: None,
+                to: Some(jid.clone()),
+                id: Uuid::new_v4().to_hyphenated().to_string(),
+                payload: IqType::Get(Element::builder("query").ns(NS_BYTESTREAMS).build()),
+            };
+            Either::B(Rc::clone(&query_aparte).send_iq(query).map_err(|err| format!("Cannot query the SOCKS5 proxy: {}", err)).map(move |answer| {
+                if let IqType::Result(Some(query)) = answer.payload {
+                    let proxy = Proxy::parse(jid, &query);
+                    debug!("SOCKS5 proxy: {:?}", proxy);
+                    query_aparte.get_plugin_mut::<FileTransferPlugin>().unwrap().proxy = proxy;
+                }
+            }))
+        });
+        aparte.spawn(discovery);
+    }
+
+    /// Send the file of an accepted offer over the transport negotiated
+    fn upload(aparte: Rc<Aparte>, sid: String) {
+        let offer = match aparte.get_plugin::<FileTransferPlugin>().unwrap().transfers.get(&sid) {
+            Some(transfer) => transfer.offer.clone(),
+            None => return,
+        };
+        match offer.transport.clone() {
+            Transport::Ibb { sid: stream, block_size } => FileTransferPlugin::ibb_upload(aparte, offer, stream, block_size),
+            Transport::Socks5 { sid: stream, candidates } => match candidates.into_iter().next() {
+                Some(candidate) => FileTransferPlugin::proxy_upload(aparte, offer, stream, candidate),
+                None => FileTransferPlugin::abort(aparte, &sid, String::from("no candidate")),
+            },
+        }
+    }
+
+    /// Send a file in-band, waiting for each data to be acknowledged before sending the next one
+    fn ibb_upload(aparte: Rc<Aparte>, offer: Offer, stream: String, block_size: u16) {
+        let open = Element::builder("open").ns(ns::IBB)
+            .attr("block-size", block_size.to_string())
+            .attr("sid", stream.as_str())
+            .attr("stanza", "iq")
+            .build();
+        let sid = offer.sid.clone();
+        let loop_aparte = Rc::clone(&aparte);
+        let upload = Rc::clone(&aparte).send_iq(offer.iq(open)).then(move |opened| {
+            if let Err(err) = opened {
+                FileTransferPlugin::abort(loop_aparte, &sid, format!("cannot open in-band bytestream: {}", err));
+                return Either::A(future::ok(()));
+            }
+            Either::B(future::loop_fn((loop_aparte, offer, sid, stream, 0u16), move |(aparte, offer, sid, stream, seq)| {
+                let chunk = aparte.get_plugin_mut::<FileTransferPlugin>().unwrap().transfers.get_mut(&sid).map(|transfer| transfer.read(block_size as usize));
+                let (data, progress) = match chunk {
+                    // Cancelled
+                    None => return Either::A(future::ok(Loop::Break(()))),
+                    Some(Err(err)) => {
+                        FileTransferPlugin::abort(aparte, &sid, err);
+                        return Either::A(future::ok(Loop::Break(())));
+                    },
+                    Some(Ok(chunk)) => chunk,
+                };
+                if data.is_empty() {
+                    let close = Element::builder("close").ns(ns::IBB).attr("sid", stream.as_str()).build();
+                    aparte.send(offer.iq(close).into());
+                    let outcome = aparte.get_plugin_mut::<FileTransferPlugin>().unwrap().finish(&sid, true);
+                    FileTransferPlugin::show(aparte, outcome);
+                    return Either::A(future::ok(Loop::Break(())));
+                }
+                if let Some(progress) = progress {
+                    FileTransferPlugin::show(Rc::clone(&aparte), Outcome::Progress(progress));
+                }
+                let data = Element::builder("data").ns(ns::IBB)
+                    .attr("seq", seq.to_string())
+                    .attr("sid", stream.as_str())
+                    .append(base64::encode(&data))
+                    .build();
+                Either::B(Rc::clone(&aparte).send_iq(offer.iq(data)).then(move |sent| match sent {
+                    Ok(_) => Ok(Loop::Continue((aparte, offer, sid, stream, seq.wrapping_add(1)))),
+                    Err(err) => {
+                        FileTransferPlugin::abort(aparte, &sid, err.to_string());
+                        Ok(Loop::Break(()))
+                    },
+                }))
+            }))
+        });
+        aparte.spawn(upload);
+    }
+
+    /// Send a file through the proxy of our server, once activated for the stream, falling back
+    /// to in-band if it fails
+    fn proxy_upload(aparte: Rc<Aparte>, offer: Offer, stream: String, candidate: Candidate) {
+        let address = offer.socks5_address(&stream);
+        let activate = Iq {
+            from: None,
+            to: Jid::from_str(&candidate.jid).ok(),
+            id: Uuid::new_v4().to_hyphenated().to_string(),
+            payload: IqType::Set(Element::builder("query").ns(NS_BYTESTREAMS)
+                .attr("sid", stream.as_str())
+                .append(Element::builder("activate").ns(NS_BYTESTREAMS).append(offer.from.to_string()).build())
+                .build()),
+        };
+        let activate_aparte = Rc::clone(&aparte);
+        let write_aparte = Rc::clone(&aparte);
+        let upload = client::tcp(candidate.host.clone(), Some(candidate.host.clone()), Some(candidate.port))
+            .map_err(|err| err.to_string())
+            .and_then(move |connection| socks5::connect(connection, &address).map_err(|err| err.to_string()))
+            .and_then(move |connection| activate_aparte.send_iq(activate).map_err(|err| format!("cannot activate the proxy: {}", err)).map(move |_| connection))
+            .then(move |connection| {
+                let aparte = write_aparte;
+                match connection {
+                    Ok(connection) => {
+                        let activated = Element::builder("activated").ns(ns::JINGLE_S5B).attr("cid", candidate.cid.as_str()).build();
+                        aparte.send(offer.transport_info(&stream, activated).into());
+                        Either::A(FileTransferPlugin::write(aparte, offer.sid, connection))
+                    },
+                    Err(err) => {
+                        debug!("SOCKS5 proxy {}:{} failed: {}", candidate.host, candidate.port, err);
+                        aparte.send(offer.transport_info(&stream, Element::builder("proxy-error").ns(ns::JINGLE_S5B).build()).into());
+                        let replace = aparte.get_plugin_mut::<FileTransferPlugin>().unwrap().transfers.get_mut(&offer.sid).map(|transfer| transfer.offer.fallback());
+                        if let Some(replace) = replace {
+                            aparte.send(replace.into());
+                        }
+                        Either::B(future::ok(()))
+                    },
+                }
+            });
+        aparte.spawn(upload);
+    }
+
+    fn write(aparte: Rc<Aparte>, sid: String, connection: TcpStream) -> Box<dyn Future<Item = (), Error = String>> {
+        Box::new(future::loop_fn((aparte, sid, connection), |(aparte, sid, connection)| {
+            let chunk = aparte.get_plugin_mut::<FileTransferPlugin>().unwrap().transfers.get_mut(&sid).map(|transfer| transfer.read(CHUNK));
+            let (data, progress) = match chunk {
+                None => return Either::A(future::ok(Loop::Break(()))),
+                Some(Err(err)) => {
+                    FileTransferPlugin::abort(aparte, &sid, err);
+                    return Either::A(future::ok(Loop::Break(())));
+                },
+                Some(Ok(chunk)) => chunk,
+            };
+            if data.is_empty() {
+                let outcome = aparte.get_plugin_mut::<FileTransferPlugin>().unwrap().finish(&sid, true);
+                FileTransferPlugin::show(aparte, outcome);
+                return Either::A(future::ok(Loop::Break(())));
+            }
+            if let Some(progress) = progress {
+                FileTransferPlugin::show(Rc::clone(&aparte), Outcome::Progress(progress));
+            }
+            Either::B(tokio::io::write_all(connection, data).then(move |written| match written {
+                Ok((connection, _)) => Ok(Loop::Continue((aparte, sid, connection))),
+                Err(err) => {
+                    FileTransferPlugin::abort(aparte, &sid, err.to_string());
+                    Ok(Loop::Break(()))
+                },
+            }))
+        }))
+    }
+
+    /// Connect to the SOCKS5 candidates of the sender in turn, and receive the file from the
+    /// first one answering
+    pub fn connect(aparte: Rc<Aparte>, offer: Offer) {
+        let (sid, candidates) = match &offer.transport {
+            Transport::Socks5 { sid, candidates } => (sid.clone(), candidates.clone()),
+            Transport::Ibb { .. } => return,
+        };
+        let address = offer.socks5_address(&sid);
+        let candidates: VecDeque<Candidate> = candidates.into_iter().collect();
+        let connected = future::loop_fn(candidates, move |mut candidates| {
+            let candidate = match candidates.pop_front() {
+                Some(candidate) => candidate,
+                None => return Either::A(future::ok(Loop::Break(None))),
+            };
+            let address = address.clone();
+            Either::B(client::tcp(candidate.host.clone(), Some(candidate.host.clone()), Some(candidate.port))
+                .map_err(|err| err.to_string())
+                .and_then(move |stream| socks5::connect(stream, &address).map_err(|err| err.to_string()))
+                .then(move |stream| match stream {
+                    Ok(stream) => Ok(Loop::Break(Some((candidate, stream)))),
+                    Err(err) => {
+                        debug!("SOCKS5 candidate {}:{} failed: {}", candidate.host, candidate.port, err);
+                        Ok(Loop::Continue(candidates))
+                    },
+                }))
+        });
+
+        let session = offer.sid.clone();
+        let receive_aparte = Rc::clone(&aparte);
+        let transfer = connected.and_then(move |connected| {
+            let (candidate, stream) = match connected {
+                Some(connected) => connected,
+                None => {
+                    // The sender may then replace the transport with an in-band one
+                    let error = Element::builder("candidate-error").ns(ns::JINGLE_S5B).build();
+                    receive_aparte.send(offer.transport_info(&sid, error).into());
+                    return Either::A(future::ok(()));
+                },
+            };
+            let used = Element::builder("candidate-used").ns(ns::JINGLE_S5B).attr("cid", candidate.cid.as_str()).build();
+            receive_aparte.send(offer.transport_info(&sid, used).into());
+            Either::B(FileTransferPlugin::read(receive_aparte, session, stream))
+        });
+        aparte.spawn(transfer);
+    }
+
+    fn read(aparte: Rc<Aparte>, sid: String, stream: TcpStream) -> Box<dyn Future<Item = (), Error = String>> {
+        Box::new(future::loop_fn((aparte, sid, stream), |(aparte, sid, stream)| {
+            tokio::io::read(stream, vec![0; CHUNK]).map_err(|err| err.to_string()).map(move |(stream, buf, read)| {
+                let outcome = {
+                    let mut plugin = aparte.get_plugin_mut::<FileTransferPlugin>().unwrap();
+                    match (read, plugin.transfers.get(&sid).map(|transfer| transfer.offer.size)) {
+                        // Cancelled
+                        (_, None) => return Loop::Break(()),
+                        // Closed before the announced size was received
+                        (0, Some(Some(_))) => plugin.finish(&sid, false),
+                        (0, Some(None)) => plugin.finish(&sid, true),
+                        (read, Some(_)) => plugin.receive(&sid, &buf[..read]),
+                    }
+                };
+                let done = match &outcome {
+                    Outcome::Done(..) | Outcome::Failed(..) => true,
+                    Outcome::Nothing | Outcome::Progress(..) | Outcome::Start(..) => false,
+                };
+                FileTransferPlugin::show(Rc::clone(&aparte), outcome);
+                match done {
+                    true => Loop::Break(()),
+                    false => Loop::Continue((aparte, sid, stream)),
+                }
+            })
+        }))
+    }
+
+    /// Handle a Jingle action received on an account, returns the answers to send
+    fn jingle(&mut self, account: &FullJid, iq: &Iq, jingle: &Element) -> (Vec<Iq>, Outcome) {
+        let from = match &iq.from {
+            Some(from) => from.clone(),
+            None => return (vec![error(iq, DefinedCondition::BadRequest, "No sender")], Outcome::Nothing),
+        };
+        let sid = jingle.attr("sid").unwrap_or("");
+        let known = self.offers.iter().chain(self.transfers.values().map(|transfer| &transfer.offer))
+            .any(|offer| offer.sid == sid && offer.from == from);
+        let mut answers = vec![result(iq)];
+
+        match jingle.attr("action") {
+            Some("session-initiate") => {
+                let account = account.clone();
+                match Offer::parse(from.clone(), account.clone(), jingle) {
+                    Ok(offer) => self.offers.push(offer),
+                    Err(reason) => {
+                        let offer = Offer {
+                            from: from,
+                            account: account,
+                            incoming: true,
+                            sid: sid.to_string(),
+                            content: String::new(),
+                            creator: String::new(),
+                            description: Element::builder("description").ns(ns::JINGLE_FT).build(),
+                            name: String::new(),
+                            size: None,
+                            transport: Transport::Ibb { sid: String::new(), block_size: 0 },
+                        };
+                        answers.push(offer.terminate(reason));
+                    },
+                }
+                (answers, Outcome::Nothing)
+            },
+            _ if !known => (vec![error(iq, DefinedCondition::ItemNotFound, "Unknown session")], Outcome::Nothing),
+            Some("session-terminate") => {
+                let reason = jingle.get_child("reason", ns::JINGLE)
+                    .and_then(|reason| reason.children().find(|child| child.name() != "text"))
+                    .map(|reason| reason.name().to_string())
+                    .unwrap_or(String::from("no reason"));
+                if let Some(index) = self.offers.iter().position(|offer| offer.sid == sid) {
+                    let offer = self.offers.remove(index);
+                    return (answers, Outcome::Failed(offer, format!("offer withdrawn, {}", reason)));
+                }
+                let complete = self.transfers.get(sid).map_or(false, |transfer| transfer.complete());
+                match self.finish(sid, complete) {
+                    Outcome::Failed(offer, received) => (answers, Outcome::Failed(offer, format!("{}, {}", reason, received))),
+                    outcome => (answers, outcome),
+                }
+            },
+            Some(action) if self.transfers.get(sid).map_or(false, |transfer| !transfer.offer.incoming) => {
+                let outcome = self.negotiate(sid, action, jingle, &mut answers);
+                (answers, outcome)
+            },
+            Some("transport-replace") => {
+                let transport = jingle.get_child("content", ns::JINGLE)
+                    .and_then(|content| content.children().find(|child| child.name() == "transport"))
+                    .and_then(Transport::parse);
+                match (self.transfers.get_mut(sid), transport) {
+                    (Some(transfer), Some(transport @ Transport::Ibb { .. })) => {
+                        transfer.offer.transport = transport;
+                        let mut accept = transfer.offer.jingle("transport-accept");
+                        accept.append_child(transfer.offer.content(transfer.offer.transport.answer(), false));
+                        answers.push(transfer.offer.iq(accept));
+                    },
+                    (Some(transfer), _) => {
+                        let mut reject = transfer.offer.jingle("transport-reject");
+                        reject.append_child(transfer.offer.content(transfer.offer.transport.answer(), false));
+                        answers.push(transfer.offer.iq(reject));
+                    },
+                    (None, _) => {},
+                }
+                (answers, Outcome::Nothing)
+            },
+            // Candidates and their activation, data is read as soon as it comes
+            _ => (answers, Outcome::Nothing),
+        }
+    }
+
+    /// Handle the answers of the peer we offer a file to, until the file can be sent
+    fn negotiate(&mut self, sid: &str, action: &str, jingle: &Element, answers: &mut Vec<Iq>) -> Outcome {
+        let transport = jingle.get_child("content", ns::JINGLE)
+            .and_then(|content| content.children().find(|child| child.name() == "transport"));
+        let transfer = self.transfers.get_mut(sid).unwrap();
+        match (action, &transfer.offer.transport) {
+            ("session-accept", Transport::Ibb { .. }) | ("transport-accept", Transport::Ibb { .. }) => Outcome::Start(sid.to_string()),
+            ("session-accept", Transport::Socks5 { sid: stream, .. }) => {
+                // We only offer our proxy, not connecting to the candidates of the peer
+                let error = Element::builder("candidate-error").ns(ns::JINGLE_S5B).build();
+                answers.push(transfer.offer.transport_info(stream, error));
+                Outcome::Nothing
+            },
+            ("transport-info", Transport::Socks5 { candidates, .. }) => {
+                let transport = match transport {
+                    Some(transport) => transport,
+                    None => return Outcome::Nothing,
+                };
+                if let Some(used) = transport.get_child("candidate-used", ns::JINGLE_S5B) {
+                    match candidates.iter().any(|candidate| used.attr("cid") == Some(candidate.cid.as_str())) {
+                        true => Outcome::Start(sid.to_string()),
+                        false => Outcome::Nothing,
+                    }
+                } else if transport.has_child("candidate-error", ns::JINGLE_S5B) {
+                    answers.push(transfer.offer.fallback());
+                    Outcome::Nothing
+                } else {
+                    Outcome::Nothing
+                }
+            },
+            ("transport-reject", _) => match self.finish(sid, false) {
+                Outcome::Failed(offer, transferred) => {
+                    answers.push(offer.terminate("failed-transport"));
+                    Outcome::Failed(offer, format!("no transport accepted, {}", transferred))
+                },
+                outcome => outcome,
+            },
+            _ => Outcome::Nothing,
+        }
+    }
+
+    /// Handle in-band bytestream data and its opening and closing
+    fn ibb(&mut self, iq: &Iq, payload: &Element) -> (Iq, Outcome) {
+        let stream = payload.attr("sid").unwrap_or("");
+        let sid = self.transfers.iter().filter(|(_, transfer)| transfer.offer.incoming).find(|(_, transfer)| match &transfer.offer.transport {
+            Transport::Ibb { sid, .. } => sid == stream && iq.from.as_ref() == Some(&transfer.offer.from),
+            Transport::Socks5 { .. } => false,
+        }).map(|(sid, _)| sid.clone());
+        let sid = match sid {
+            Some(sid) => sid,
+            None => return (error(iq, DefinedCondition::ItemNotFound, "Unknown stream"), Outcome::Nothing),
+        };
+
+        match payload.name() {
+            "open" => (result(iq), Outcome::Nothing),
+            "data" => {
+                let transfer = self.transfers.get_mut(&sid).unwrap();
+                let seq: Option<u16> = payload.attr("seq").and_then(|seq| seq.parse().ok());
+                let text: String = payload.text().chars().filter(|c| !c.is_whitespace()).collect();
+                match (seq == Some(transfer.seq), base64::decode(&text)) {
+                    (true, Ok(data)) => {
+                        transfer.seq = transfer.seq.wrapping_add(1);
+                        (result(iq), self.receive(&sid, &data))
+                    },
+                    _ => match self.finish(&sid, false) {
+                        Outcome::Failed(offer, received) => (error(iq, DefinedCondition::UnexpectedRequest, "Invalid data"), Outcome::Failed(offer, format!("invalid data, {}", received))),
+                        outcome => (error(iq, DefinedCondition::UnexpectedRequest, "Invalid data"), outcome),
+                    },
+                }
+            },
+            "close" => {
+                let transfer = self.transfers.get(&sid).unwrap();
+                let complete = transfer.complete() || transfer.offer.size.is_none();
+                (result(iq), self.finish(&sid, complete))
+            },
+            _ => (error(iq, DefinedCondition::BadRequest, "Unknown action"), Outcome::Nothing),
+        }
+    }
+}
+
+impl Plugin for FileTransferPlugin {
+    fn new() -> FileTransferPlugin {
+        Self {
+            offers: Vec::new(),
+            transfers: HashMap::new(),
+            proxy: None,
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        let mut disco = aparte.get_plugin_mut::<disco::Disco>().unwrap();
+        disco.add_feature(ns::JINGLE)?;
+        disco.add_feature(ns::JINGLE_FT)?;
+        disco.add_feature(ns::JINGLE_IBB)?;
+        disco.add_feature(ns::JINGLE_S5B)
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Iq(account, iq) => {
+                let payload = match &iq.payload {
+                    IqType::Set(payload) => payload,
+                    _ => return,
+                };
+                let call = match aparte.get_plugin::<CallsPlugin>() {
+                    Some(calls) => payload.is("jingle", ns::JINGLE) && calls.handles(payload),
+                    None => false,
+                };
+                if call {
+                    return;
+                }
+
+                let offers = self.offers.len();
+                let (answers, outcome) = if payload.is("jingle", ns::JINGLE) {
+                    self.jingle(account, iq, payload)
+                } else if payload.has_ns(ns::IBB) {
+                    let (answer, outcome) = self.ibb(iq, payload);
+                    (vec![answer], outcome)
+                } else {
+                    return;
+                };
+
+                for answer in answers {
+                    aparte.send_on(account, answer.into());
+                }
+                if self.offers.len() > offers {
+                    let offer = self.offers.last().unwrap();
+                    Rc::clone(&aparte).log(format!("{} offers {}, use /accept-file [<path>] to download it or /decline-file to refuse it", offer.from, offer));
+                }
+                FileTransferPlugin::show(aparte, outcome);
+            },
+            Event::Connected(jid) => FileTransferPlugin::discover(aparte, BareJid::domain(&jid.domain)),
+            Event::Disconnected(_) => {
+                let sids: Vec<String> = self.transfers.keys().cloned().collect();
+                for sid in sids {
+                    let outcome = self.finish(&sid, false);
+                    FileTransferPlugin::show(Rc::clone(&aparte), outcome);
+                }
+                self.offers.clear();
+                self.proxy = None;
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for FileTransferPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "File transfer")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(transport: &str) -> Offer {
+        let jingle: Element = format!(r#"<jingle xmlns="urn:xmpp:jingle:1" action="session-initiate" initiator="romeo@montague.lit/orchard" sid="851ba2">
+            <content creator="initiator" name="a-file-offer" senders="initiator">
+                <description xmlns="urn:xmpp:jingle:apps:file-transfer:5">
+                    <file><media-type>text/plain</media-type><name>../../test.txt</name><size>6144</size></file>
+                </description>
+                {}
+            </content>
+        </jingle>"#, transport).parse().unwrap();
+        Offer::parse(Jid::from_str("romeo@montague.lit/orchard").unwrap(), FullJid::from_str("juliet@capulet.lit/balcony").unwrap(), &jingle).unwrap()
+    }
+
+    #[test]
+    fn test_offer() {
+        let offer = offer(r#"<transport xmlns="urn:xmpp:jingle:transports:s5b:1" mode="tcp" sid="vj3hs98y">
+            <candidate cid="hft54dqy" host="192.168.4.1" jid="romeo@montague.lit/orchard" port="5086" priority="8257636" type="direct"/>
+            <candidate cid="hutr46fe" host="proxy.montague.lit" jid="proxy.montague.lit" port="5087" priority="8258636" type="proxy"/>
+        </transport>"#);
+        assert_eq!(offer.name, "test.txt");
+        assert_eq!(offer.size, Some(6144));
+        assert_eq!(offer.to_string(), "test.txt (6.0 KiB)");
+        match &offer.transport {
+            Transport::Socks5 { sid, candidates } => {
+                assert_eq!(sid, "vj3hs98y");
+                assert_eq!(candidates.iter().map(|candidate| candidate.cid.as_str()).collect::<Vec<_>>(), vec!["hutr46fe", "hft54dqy"]);
+                assert_eq!(candidates[0].host, "proxy.montague.lit");
+            },
+            _ => panic!("Not SOCKS5"),
+        }
+        // SHA-1 of the stream id, the initiator and the responder
+        assert_eq!(offer.socks5_address("vj3hs98y"), "972b7bf47291ca609517f67f86b5081086052dad");
+
+        let accept = Element::from(offer.accept());
+        let jingle = accept.get_child("jingle", ns::JINGLE).unwrap();
+        assert_eq!(jingle.attr("action"), Some("session-accept"));
+        let content = jingle.get_child("content", ns::JINGLE).unwrap();
+        assert!(content.get_child("description", ns::JINGLE_FT).is_some());
+        assert_eq!(content.get_child("transport", ns::JINGLE_S5B).unwrap().attr("sid"), Some("vj3hs98y"));
+
+        let terminate = Element::from(offer.terminate("decline"));
+        let reason = terminate.get_child("jingle", ns::JINGLE).unwrap().get_child("reason", ns::JINGLE).unwrap();
+        assert!(reason.get_child("decline", ns::JINGLE).is_some());
+    }
+
+    #[test]
+    fn test_ibb() {
+        let mut plugin = FileTransferPlugin::new();
+        plugin.offers.push(offer(r#"<transport xmlns="urn:xmpp:jingle:transports:ibb:1" block-size="4096" sid="ch3d9s71"/>"#));
+        let path = std::env::temp_dir().join(format!("aparte-transfer-{}", Uuid::new_v4()));
+        let (_, accept, _) = plugin.accept(path.to_str()).unwrap();
+        let accept = Element::from(accept);
+        assert_eq!(accept.get_child("jingle", ns::JINGLE).unwrap().get_child("content", ns::JINGLE).unwrap().get_child("transport", ns::JINGLE_IBB).unwrap().attr("block-size"), Some("4096"));
+        assert!(plugin.accept(None).is_err());
+
+        let iq = |payload: String| Iq::try_from(payload.parse::<Element>().unwrap()).unwrap();
+        let open = iq(String::from(r#"<iq xmlns="jabber:client" from="romeo@montague.lit/orchard" id="1" type="set"><open xmlns="http://jabber.org/protocol/ibb" block-size="4096" sid="ch3d9s71"/></iq>"#));
+        assert!(match plugin.ibb(&open, open_payload(&open)).0.payload { IqType::Result(None) => true, _ => false });
+
+        let data = base64::encode(&vec![b'a'; 4096]);
+        let first = iq(format!(r#"<iq xmlns="jabber:client" from="romeo@montague.lit/orchard" id="2" type="set"><data xmlns="http://jabber.org/protocol/ibb" seq="0" sid="ch3d9s71">{}</data></iq>"#, data));
+        match plugin.ibb(&first, open_payload(&first)).1 {
+            Outcome::Progress(Message::Log(progress)) => assert_eq!(progress.body, "Receiving test.txt from romeo@montague.lit/orchard: 66% (4.0 KiB of 6.0 KiB)"),
+            _ => panic!("No progress"),
+        }
+
+        // Out of sequence
+        let other = iq(String::from(r#"<iq xmlns="jabber:client" from="romeo@montague.lit/orchard" id="3" type="set"><data xmlns="http://jabber.org/protocol/ibb" seq="0" sid="other">YQ==</data></iq>"#));
+        assert!(match plugin.ibb(&other, open_payload(&other)).0.payload { IqType::Error(_) => true, _ => false });
+
+        let data = base64::encode(&vec![b'b'; 2048]);
+        let last = iq(format!(r#"<iq xmlns="jabber:client" from="romeo@montague.lit/orchard" id="4" type="set"><data xmlns="http://jabber.org/protocol/ibb" seq="1" sid="ch3d9s71">{}</data></iq>"#, data));
+        match plugin.ibb(&last, open_payload(&last)).1 {
+            Outcome::Done(offer, received) => {
+                assert_eq!(offer.name, "test.txt");
+                assert_eq!(received, path);
+            },
+            _ => panic!("Not received"),
+        }
+        assert!(plugin.transfers.is_empty());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 6144);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_incoming_offer() {
+        // Offered to the account it is received on, whichever account is current
+        let account = FullJid::from_str("juliet@capulet.lit/balcony").unwrap();
+        let initiate: Element = r#"<iq xmlns="jabber:client" from="romeo@montague.lit/orchard" id="1" type="set"><jingle xmlns="urn:xmpp:jingle:1" action="session-initiate" initiator="romeo@montague.lit/orchard" sid="851ba2"><content creator="initiator" name="a-file-offer" senders="initiator"><description xmlns="urn:xmpp:jingle:apps:file-transfer:5"><file><name>test.txt</name><size>6144</size></file></description><transport xmlns="urn:xmpp:jingle:transports:ibb:1" block-size="4096" sid="ch3d9s71"/></content></jingle></iq>"#.parse().unwrap();
+        let initiate = Iq::try_from(initiate).unwrap();
+        let mut plugin = FileTransferPlugin::new();
+        let (answers, _) = plugin.jingle(&account, &initiate, open_payload(&initiate));
+        assert_eq!(answers.len(), 1);
+        assert_eq!(plugin.offers[0].account, account);
+
+        // Saved where typed, the home directory included
+        assert_eq!(destination("test.txt", Some("~/received.txt")).unwrap(), dirs::home_dir().unwrap().join("received.txt"));
+        assert_eq!(destination("test.txt", Some("/tmp")).unwrap(), PathBuf::from("/tmp/test.txt"));
+    }
+
+    fn open_payload(iq: &Iq) -> &Element {
+        match &iq.payload {
+            IqType::Set(payload) => payload,
+            _ => panic!("Not a set"),
+        }
+    }
+
+    #[test]
+    fn test_send() {
+        let path = std::env::temp_dir().join(format!("aparte-send-{}.txt", Uuid::new_v4()));
+        fs::write(&path, vec![b'a'; 6000]).unwrap();
+        let account = FullJid::from_str("juliet@capulet.lit/balcony").unwrap();
+        let to = FullJid::from_str("romeo@montague.lit/orchard").unwrap();
+        let query: Element = r#"<query xmlns="http://jabber.org/protocol/bytestreams"><streamhost host="24.24.24.1" jid="proxy.capulet.lit" port="5999"/></query>"#.parse().unwrap();
+
+        let mut plugin = FileTransferPlugin::new();
+        plugin.proxy = Proxy::parse(Jid::from_str("proxy.capulet.lit").unwrap(), &query);
+        assert_eq!(plugin.proxy.as_ref().map(|proxy| proxy.port), Some(5999));
+        let (sid, initiate, _) = plugin.send(account.clone(), to.clone(), path.to_str().unwrap()).unwrap();
+
+        // Offered as an incoming one would be
+        let initiate = Element::from(initiate);
+        let jingle = initiate.get_child("jingle", ns::JINGLE).unwrap();
+        assert_eq!(jingle.attr("action"), Some("session-initiate"));
+        let offered = Offer::parse(Jid::Full(account.clone()), to.clone(), jingle).unwrap();
+        assert_eq!(offered.to_string(), format!("{} (5.9 KiB)", path.file_name().unwrap().to_string_lossy()));
+        let stream = match &offered.transport {
+            Transport::Socks5 { sid, candidates } => {
+                assert_eq!(candidates[0].host, "24.24.24.1");
+                assert_eq!(candidates[0].jid, "proxy.capulet.lit");
+                sid.clone()
+            },
+            _ => panic!("Not SOCKS5"),
+        };
+        // Both ends connect to the proxy with the same address
+        let offer = plugin.transfers[&sid].offer.clone();
+        assert_eq!(offer.socks5_address(&stream), offered.socks5_address(&stream));
+
+        let jingle = |action: &str, transport: &str| -> Element {
+            format!(r#"<jingle xmlns="urn:xmpp:jingle:1" action="{}" sid="{}"><content creator="initiator" name="a-file-offer">{}</content></jingle>"#, action, sid, transport).parse().unwrap()
+        };
+        let name = |iq: &Iq| match &iq.payload {
+            IqType::Set(jingle) => jingle.attr("action").map(String::from),
+            _ => None,
+        };
+
+        // The peer cannot connect to our proxy, we fall back to in-band
+        let answer = jingle("session-accept", &format!(r#"<transport xmlns="urn:xmpp:jingle:transports:s5b:1" sid="{}"/>"#, stream));
+        let mut answers = Vec::new();
+        plugin.negotiate(&sid, "session-accept", &answer, &mut answers);
+        assert_eq!(answers.iter().map(name).collect::<Vec<_>>(), vec![Some(String::from("transport-info"))]);
+        let answer = jingle("transport-info", &format!(r#"<transport xmlns="urn:xmpp:jingle:transports:s5b:1" sid="{}"><candidate-error/></transport>"#, stream));
+        let mut answers = Vec::new();
+        plugin.negotiate(&sid, "transport-info", &answer, &mut answers);
+        assert_eq!(answers.iter().map(name).collect::<Vec<_>>(), vec![Some(String::from("transport-replace"))]);
+        let answer = jingle("transport-accept", r#"<transport xmlns="urn:xmpp:jingle:transports:ibb:1" block-size="4096" sid="ibb"/>"#);
+        match plugin.negotiate(&sid, "transport-accept", &answer, &mut Vec::new()) {
+            Outcome::Start(started) => assert_eq!(started, sid),
+            _ => panic!("Not started"),
+        }
+
+        let (data, _) = plugin.transfers.get_mut(&sid).unwrap().read(4096).unwrap();
+        assert_eq!(data.len(), 4096);
+        let (data, progress) = plugin.transfers.get_mut(&sid).unwrap().read(4096).unwrap();
+        assert_eq!(data.len(), 6000 - 4096);
+        assert!(progress.is_some());
+        assert!(plugin.transfers[&sid].complete());
+        match plugin.finish(&sid, true) {
+            Outcome::Done(_, sent) => assert_eq!(sent, path),
+            _ => panic!("Not done"),
+        }
+        fs::remove_file(path).unwrap();
+
+        // Looked for in the home directory
+        let missing = format!("aparte-missing-{}.txt", Uuid::new_v4());
+        let err = plugin.send(account, to, &format!("~/{}", missing)).err().unwrap();
+        assert!(err.starts_with(&format!("Cannot open {}", dirs::home_dir().unwrap().join(missing).display())));
+    }
+
+    #[test]
+    fn test_size() {
+        assert_eq!(size(512), "512 B");
+        assert_eq!(size(1536), "1.5 KiB");
+        assert_eq!(size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+}
@@ -0,0 +1,194 @@
+use futures::{future, Future};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+use xmpp_parsers::{BareJid, FullJid, Jid};
+use xmpp_parsers::disco::DiscoInfoResult;
+
+use crate::core::{Plugin, Aparte, Event};
+use crate::plugins::disco;
+use crate::plugins::ui::UIPlugin;
+
+/// Window listing the gateways of the server
+pub const WINDOW: &str = "gateways";
+
+/// Component bridging a legacy network, as biboumi for IRC or a Slack or Signal bridge
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gateway {
+    pub jid: BareJid,
+    /// Network bridged, as irc, slack or signal
+    pub type_: String,
+    pub name: Option<String>,
+}
+
+impl fmt::Display for Gateway {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{}: {} ({})", self.jid, name, self.type_),
+            None => write!(f, "{} ({})", self.jid, self.type_),
+        }
+    }
+}
+
+/// Gateway described by the disco#info of a component, if it is one
+fn gateway(jid: &Jid, info: &DiscoInfoResult) -> Option<Gateway> {
+    info.identities.iter().find(|identity| identity.category == "gateway").map(|identity| Gateway {
+        jid: BareJid::from(jid.clone()),
+        type_: identity.type_.clone(),
+        name: identity.name.clone(),
+    })
+}
+
+/// Lines listing gateways in their window
+fn list(gateways: &BTreeMap<String, Gateway>) -> Vec<String> {
+    let mut lines: Vec<String> = gateways.values().map(|gateway| format!("  {}", gateway)).collect();
+    lines.insert(0, match lines.len() {
+        0 => format!("No gateway found on the server"),
+        _ => format!("Gateways, use /gateway register <jid> to register with one:"),
+    });
+    lines
+}
+
+/// Gateways found among the components of the server, so that what they relay is routed to
+/// conversations
+pub struct GatewayPlugin {
+    /// Gateways by domain
+    gateways: BTreeMap<String, Gateway>,
+}
+
+impl GatewayPlugin {
+    /// Look for the gateways among the components of the server of an account, kept once every
+    /// component answered
+    pub fn discover(aparte: Rc<Aparte>, account: &FullJid) -> impl Future<Item = (), Error = String> {
+        let server = BareJid::domain(&account.domain);
+        let info_aparte = Rc::clone(&aparte);
+        let info_account = account.clone();
+        let error_server = server.clone();
+        aparte.send_iq_on(account, disco::items_query(Jid::Bare(server)))
+            .map_err(move |err| format!("Cannot discover the components of {}: {}", error_server, err))
+            .and_then(move |answer| {
+                let infos: Vec<_> = disco::items_result(Ok(answer)).into_iter().filter(|item| item.node.is_none()).map(|item| {
+                    let jid = item.jid;
+                    Rc::clone(&info_aparte).send_iq_on(&info_account, disco::info_query(jid.clone()))
+                        .then(move |answer| Ok(disco::info_result(answer).and_then(|info| gateway(&jid, &info))))
+                }).collect();
+                future::join_all(infos).map(move |gateways| {
+                    let mut plugin = info_aparte.get_plugin_mut::<GatewayPlugin>().unwrap();
+                    plugin.gateways = gateways.into_iter().flatten().map(|gateway| (gateway.jid.to_string(), gateway)).collect();
+                })
+            })
+    }
+
+    /// Look for the gateways of the server of an account and list them in their window
+    pub fn show(aparte: Rc<Aparte>, account: &FullJid) -> impl Future<Item = (), Error = String> {
+        let list_aparte = Rc::clone(&aparte);
+        GatewayPlugin::discover(aparte, account).map(move |()| {
+            let lines = list(&list_aparte.get_plugin::<GatewayPlugin>().unwrap().gateways);
+            let mut ui = list_aparte.get_plugin_mut::<UIPlugin>().unwrap();
+            ui.add_log_window(WINDOW);
+            ui.clear_window(WINDOW);
+            ui.change_window(WINDOW);
+            ui.log_to(Rc::clone(&list_aparte), WINDOW, lines.join("\n"));
+        })
+    }
+
+    pub fn gateways(&self) -> Vec<String> {
+        self.gateways.keys().cloned().collect()
+    }
+
+    /// Whether a JID belongs to a gateway, being the gateway itself or a user or channel of the
+    /// network it bridges
+    pub fn is_bridged(&self, jid: &Jid) -> bool {
+        let domain = match jid {
+            Jid::Bare(jid) => &jid.domain,
+            Jid::Full(jid) => &jid.domain,
+        };
+        self.gateways.contains_key(domain)
+    }
+}
+
+impl Plugin for GatewayPlugin {
+    fn new() -> GatewayPlugin {
+        Self {
+            gateways: BTreeMap::new(),
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Connected(jid) => {
+                let discovery = GatewayPlugin::discover(Rc::clone(&aparte), jid);
+                aparte.spawn(discovery.or_else(|err| {
+                    warn!("{}", err);
+                    Ok(())
+                }));
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for GatewayPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Gateways to legacy networks")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+    use xmpp_parsers::Element;
+    use xmpp_parsers::iq::Iq;
+    use crate::fakeserver::{self, FakeServer};
+
+    fn result(query: &str) -> Iq {
+        let element: Element = format!(r#"<iq xmlns="jabber:client" type="result" id="info">{}</iq>"#, query).parse().unwrap();
+        Iq::try_from(element).unwrap()
+    }
+
+    #[test]
+    fn test_discover() {
+        let mut plugin = GatewayPlugin::new();
+        assert_eq!(list(&plugin.gateways), vec!["No gateway found on the server"]);
+
+        let biboumi = Jid::from_str("biboumi.server.tld").unwrap();
+        let info = disco::info_result(Ok(result(r#"<query xmlns="http://jabber.org/protocol/disco#info"><identity category="conference" type="irc" name="IRC"/><identity category="gateway" type="irc" name="Biboumi"/><feature var="http://jabber.org/protocol/disco#info"/></query>"#))).unwrap();
+        let found = gateway(&biboumi, &info).unwrap();
+        let upload = disco::info_result(Ok(result(r#"<query xmlns="http://jabber.org/protocol/disco#info"><identity category="store" type="file"/><feature var="http://jabber.org/protocol/disco#info"/><feature var="urn:xmpp:http:upload:0"/></query>"#))).unwrap();
+        assert!(gateway(&Jid::from_str("upload.server.tld").unwrap(), &upload).is_none());
+
+        plugin.gateways.insert(found.jid.to_string(), found);
+        assert_eq!(list(&plugin.gateways), vec![
+            "Gateways, use /gateway register <jid> to register with one:",
+            "  biboumi.server.tld: Biboumi (irc)",
+        ]);
+
+        assert!(plugin.is_bridged(&Jid::from_str("nick%irc.libera.chat@biboumi.server.tld/irc.libera.chat").unwrap()));
+        assert!(plugin.is_bridged(&biboumi));
+        assert!(!plugin.is_bridged(&Jid::from_str("contact@server.tld").unwrap()));
+    }
+
+    #[test]
+    fn test_discover_components() {
+        let aparte = fakeserver::aparte(|aparte| aparte.add_plugin(GatewayPlugin::new()));
+        let account = FullJid::from_str("me@server.tld/aparte").unwrap();
+        let server = FakeServer::new()
+            .expect("<iq xmlns='jabber:client' type='get' to='server.tld' id='items'><query xmlns='http://jabber.org/protocol/disco#items'/></iq>")
+            .send("<iq xmlns='jabber:client' type='result' from='server.tld' id='items'><query xmlns='http://jabber.org/protocol/disco#items'><item jid='biboumi.server.tld'/><item jid='upload.server.tld'/></query></iq>")
+            .expect("<iq xmlns='jabber:client' type='get' to='biboumi.server.tld' id='biboumi'><query xmlns='http://jabber.org/protocol/disco#info'/></iq>")
+            .expect("<iq xmlns='jabber:client' type='get' to='upload.server.tld' id='upload'><query xmlns='http://jabber.org/protocol/disco#info'/></iq>")
+            .send("<iq xmlns='jabber:client' type='result' from='upload.server.tld' id='upload'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='store' type='file'/><feature var='http://jabber.org/protocol/disco#info'/><feature var='urn:xmpp:http:upload:0'/></query></iq>")
+            .send("<iq xmlns='jabber:client' type='result' from='biboumi.server.tld' id='biboumi'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='gateway' type='irc' name='Biboumi'/><feature var='http://jabber.org/protocol/disco#info'/></query></iq>");
+        assert!(fakeserver::run(&aparte, &account, server).unwrap().is_done());
+
+        let plugin = aparte.get_plugin::<GatewayPlugin>().unwrap();
+        assert_eq!(plugin.gateways(), vec![String::from("biboumi.server.tld")]);
+        assert!(plugin.is_bridged(&Jid::from_str("#chan%irc.libera.chat@biboumi.server.tld").unwrap()));
+    }
+}
@@ -0,0 +1,184 @@
+use futures::{future, Future};
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
+use xmpp_parsers::{BareJid, FullJid, Jid};
+
+use crate::core::{Plugin, Aparte, Event};
+use crate::plugins::disco;
+
+/// Feature recommended to the server, with what doesn't work without it
+struct Check {
+    name: &'static str,
+    /// Any of these features passes the check
+    features: &'static [&'static str],
+    hint: &'static str,
+}
+
+const CHECKS: &[Check] = &[
+    Check {
+        name: "Message Archive Management (XEP-0313)",
+        features: &["urn:xmpp:mam:2"],
+        hint: "messages received while offline or on other clients can't be fetched, /mentions only shows the local history",
+    },
+    Check {
+        name: "Message Carbons (XEP-0280)",
+        features: &["urn:xmpp:carbons:2"],
+        hint: "messages exchanged from your other clients won't be shown",
+    },
+    Check {
+        name: "HTTP File Upload (XEP-0363)",
+        features: &["urn:xmpp:http:upload:0"],
+        hint: "files can't be shared",
+    },
+    Check {
+        name: "Blocking Command (XEP-0191)",
+        features: &["urn:xmpp:blocking"],
+        hint: "/block, /unblock and /report won't work",
+    },
+    Check {
+        name: "Stream Management (XEP-0198)",
+        features: &[NS_SM],
+        hint: "messages may be lost on unstable connections",
+    },
+];
+
+/// Stream management, offered as a stream feature rather than with disco
+const NS_SM: &str = "urn:xmpp:sm:3";
+
+/// Lines of the compliance summary from the features found on the server, its components and
+/// the account
+fn summary(server: &str, features: &HashSet<String>) -> Vec<String> {
+    let mut lines = vec![format!("Features of {}:", server)];
+    let mut missing = 0;
+    for check in CHECKS {
+        match check.features.iter().any(|feature| features.contains(*feature)) {
+            true => lines.push(format!("  ✓ {}", check.name)),
+            false => {
+                missing += 1;
+                lines.push(format!("  ✗ {}: {}", check.name, check.hint));
+            },
+        }
+    }
+    lines.push(match missing {
+        0 => format!("Every recommended feature is supported"),
+        missing => format!("{} of {} recommended features are missing", missing, CHECKS.len()),
+    });
+    lines
+}
+
+/// Probe the server for recommended features after connecting, and print what is degraded
+pub struct HealthPlugin {
+}
+
+impl HealthPlugin {
+    /// Features of an entity, none if it didn't answer
+    fn features(aparte: Rc<Aparte>, account: &FullJid, jid: Jid) -> impl Future<Item = Vec<String>, Error = String> {
+        aparte.send_iq_on(account, disco::info_query(jid)).then(|answer| {
+            Ok(disco::info_result(answer).map(|info| info.features.into_iter().map(|feature| feature.var).collect()).unwrap_or_default())
+        })
+    }
+
+    /// Probe the server of an account, its components and the account itself, returns the
+    /// summary once every one answered
+    fn probe(aparte: Rc<Aparte>, account: &FullJid) -> impl Future<Item = Vec<String>, Error = String> {
+        let server = BareJid::domain(&account.domain);
+        let stream_features: Vec<String> = match aparte.stream_features(account) {
+            Some(features) if features.has_child("sm", NS_SM) => vec![NS_SM.to_string()],
+            _ => Vec::new(),
+        };
+        let components_aparte = Rc::clone(&aparte);
+        let components_account = account.clone();
+        // Components such as the upload service are items of the server
+        let components = Rc::clone(&aparte).send_iq_on(account, disco::items_query(Jid::Bare(server.clone()))).then(move |answer| {
+            let features: Vec<_> = disco::items_result(answer).into_iter().filter(|item| item.node.is_none())
+                .map(|item| HealthPlugin::features(Rc::clone(&components_aparte), &components_account, item.jid))
+                .collect();
+            future::join_all(features)
+        });
+
+        let domain = account.domain.clone();
+        HealthPlugin::features(Rc::clone(&aparte), account, Jid::Bare(server))
+            // Archives are advertised on the account itself
+            .join(HealthPlugin::features(aparte, account, Jid::Bare(BareJid::from(Jid::Full(account.clone())))))
+            .join(components)
+            .map(move |((server, account), components)| {
+                let features: HashSet<String> = server.into_iter().chain(account).chain(components.into_iter().flatten()).chain(stream_features).collect();
+                summary(&domain, &features)
+            })
+    }
+}
+
+impl Plugin for HealthPlugin {
+    fn new() -> HealthPlugin {
+        Self {
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Connected(jid) => {
+                let log_aparte = Rc::clone(&aparte);
+                Rc::clone(&aparte).spawn(HealthPlugin::probe(aparte, jid).map(move |summary| {
+                    for line in summary {
+                        Rc::clone(&log_aparte).log(line);
+                    }
+                }));
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for HealthPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Server health checks")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use crate::fakeserver::{self, FakeServer, Recorder};
+
+    #[test]
+    fn test_summary() {
+        let features: HashSet<String> = ["http://jabber.org/protocol/disco#info", "urn:xmpp:carbons:2", "urn:xmpp:blocking", "urn:xmpp:mam:2"]
+            .iter().map(|feature| feature.to_string()).collect();
+        let summary = summary("server.tld", &features);
+        assert_eq!(summary[0], "Features of server.tld:");
+        assert!(summary.contains(&String::from("  ✓ Message Archive Management (XEP-0313)")));
+        assert!(summary.iter().any(|line| line.starts_with("  ✗ HTTP File Upload (XEP-0363)")));
+        assert!(summary.iter().any(|line| line.starts_with("  ✗ Stream Management (XEP-0198)")));
+        assert_eq!(summary.last().unwrap(), "2 of 5 recommended features are missing");
+    }
+
+    #[test]
+    fn test_probe() {
+        let aparte = fakeserver::aparte(|aparte| aparte.add_plugin(HealthPlugin::new()));
+        let account = FullJid::from_str("me@server.tld/aparte").unwrap();
+        let server = FakeServer::new()
+            .expect("<iq xmlns='jabber:client' type='get' to='server.tld' id='items'><query xmlns='http://jabber.org/protocol/disco#items'/></iq>")
+            .expect("<iq xmlns='jabber:client' type='get' to='server.tld' id='server'><query xmlns='http://jabber.org/protocol/disco#info'/></iq>")
+            .expect("<iq xmlns='jabber:client' type='get' to='me@server.tld' id='account'><query xmlns='http://jabber.org/protocol/disco#info'/></iq>")
+            .send("<iq xmlns='jabber:client' type='result' from='server.tld' id='items'><query xmlns='http://jabber.org/protocol/disco#items'><item jid='upload.server.tld'/></query></iq>")
+            .expect("<iq xmlns='jabber:client' type='get' to='upload.server.tld' id='upload'><query xmlns='http://jabber.org/protocol/disco#info'/></iq>")
+            .send("<iq xmlns='jabber:client' type='result' from='server.tld' id='server'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='server' type='im'/><feature var='http://jabber.org/protocol/disco#info'/><feature var='urn:xmpp:carbons:2'/><feature var='urn:xmpp:blocking'/></query></iq>")
+            .send("<iq xmlns='jabber:client' type='result' from='me@server.tld' id='account'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='account' type='registered'/><feature var='http://jabber.org/protocol/disco#info'/><feature var='urn:xmpp:mam:2'/></query></iq>")
+            .send("<iq xmlns='jabber:client' type='result' from='upload.server.tld' id='upload'><query xmlns='http://jabber.org/protocol/disco#info'><identity category='store' type='file'/><feature var='http://jabber.org/protocol/disco#info'/><feature var='urn:xmpp:http:upload:0'/></query></iq>")
+            .features("<features xmlns='http://etherx.jabber.org/streams'><sm xmlns='urn:xmpp:sm:3'/></features>");
+        assert!(fakeserver::run(&aparte, &account, server).unwrap().is_done());
+
+        // Features of the server, the account, the upload component and of the stream all count
+        let bodies = aparte.get_plugin::<Recorder>().unwrap().bodies.clone();
+        assert!(bodies.contains(&String::from("Features of server.tld:")));
+        assert!(bodies.contains(&String::from("  ✓ HTTP File Upload (XEP-0363)")));
+        assert!(bodies.contains(&String::from("  ✓ Stream Management (XEP-0198)")));
+        assert!(bodies.contains(&String::from("Every recommended feature is supported")));
+    }
+}
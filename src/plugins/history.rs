@@ -0,0 +1,109 @@
+use std::fmt;
+use std::rc::Rc;
+use xmpp_parsers::BareJid;
+
+use crate::core::{Plugin, Aparte, Event};
+use crate::message::Message;
+use crate::store::{self, MessageStore, MemoryStore, SqliteStore, Subject};
+
+/// Keep the history of every conversation in a message store, shared with the plugins expiring
+/// it or logging mentions
+pub struct HistoryPlugin {
+    store: Box<dyn MessageStore>,
+    opened: bool,
+}
+
+impl HistoryPlugin {
+    /// Open the database of the profile, once
+    fn open(&mut self) {
+        if self.opened {
+            return;
+        }
+        self.opened = true;
+        match SqliteStore::open(&store::default_path()) {
+            Ok(store) => self.store = Box::new(store),
+            Err(err) => warn!("History will not be persisted: {}", err),
+        }
+    }
+
+    pub fn store(&self) -> &dyn MessageStore {
+        &*self.store
+    }
+
+    /// Store of the history, opened first if other plugins are initialized before this one
+    pub fn store_mut(&mut self) -> &mut dyn MessageStore {
+        self.open();
+        &mut *self.store
+    }
+
+    /// Last `limit` messages exchanged with a contact or in a channel, oldest first
+    pub fn history(&self, jid: &BareJid, limit: usize) -> Result<Vec<Message>, String> {
+        self.store.history(jid, Some(limit))
+    }
+
+    /// Every message of a conversation, or of all of them, oldest first in each conversation
+    pub fn export(&self, jid: Option<&BareJid>) -> Result<Vec<Message>, String> {
+        let conversations = match jid {
+            Some(jid) => vec![jid.clone()],
+            None => self.store.conversations()?,
+        };
+        let mut messages = Vec::new();
+        for conversation in conversations {
+            messages.extend(self.store.history(&conversation, None)?);
+        }
+        Ok(messages)
+    }
+
+    /// Store messages imported from logs, the ones already stored being ignored
+    pub fn import(&mut self, messages: &[Message]) -> Result<(), String> {
+        self.store.insert_all(messages)
+    }
+
+    /// Last `limit` subjects of a channel, newest first
+    pub fn subjects(&self, channel: &BareJid, limit: usize) -> Result<Vec<Subject>, String> {
+        self.store.subjects(channel, limit)
+    }
+}
+
+impl Plugin for HistoryPlugin {
+    fn new() -> HistoryPlugin {
+        Self {
+            store: Box::new(MemoryStore::new()),
+            opened: false,
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        self.open();
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Message(message) => {
+                let logging = store::conversation(message)
+                    .and_then(|conversation| aparte.settings.borrow().get::<bool>(&conversation, "logging"));
+                if logging == Some(false) {
+                    return;
+                }
+                if let Err(err) = self.store.insert(message) {
+                    warn!("{}", err);
+                }
+            },
+            Event::Subject(channel, subject) => {
+                if let Err(err) = self.store.insert_subject(channel, subject) {
+                    warn!("{}", err);
+                }
+            },
+            // Plugins are never dropped, the database is closed before exiting
+            Event::Quit => self.store = Box::new(MemoryStore::new()),
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for HistoryPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Messages history")
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+use xmpp_parsers::{BareJid, Jid};
+
+use crate::config::{Hook, HookEvent, PresenceChange};
+use crate::contact;
+use crate::conversation::Conversation;
+use crate::core::{Plugin, Aparte, Event};
+use crate::message::{Message, XmppMessage};
+use crate::plugins::conversation::ConversationPlugin;
+
+/// Window over which the runs of a hook are limited
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Details of an event, by name
+type Details = Vec<(&'static str, String)>;
+
+/// Details as a JSON object
+fn json(details: &Details) -> String {
+    let object: serde_json::Map<String, serde_json::Value> = details.iter().map(|(name, value)| (name.to_string(), serde_json::Value::from(value.as_str()))).collect();
+    serde_json::Value::Object(object).to_string()
+}
+
+/// Details as environment variables, as APARTE_FROM
+fn environment(details: &Details) -> Vec<(String, String)> {
+    details.iter().map(|(name, value)| (format!("APARTE_{}", name.to_uppercase()), value.clone())).collect()
+}
+
+/// Runs of a hook during the last minute, not to fork a process for every message of a busy
+/// channel
+struct Limiter {
+    rate: usize,
+    runs: VecDeque<Instant>,
+    /// Events dropped since the last run
+    dropped: usize,
+}
+
+impl Limiter {
+    fn new(rate: usize) -> Self {
+        Self {
+            rate: rate,
+            runs: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    fn allow(&mut self, now: Instant) -> bool {
+        while self.runs.front().map_or(false, |run| now.duration_since(*run) >= RATE_WINDOW) {
+            self.runs.pop_front();
+        }
+        match self.runs.len() < self.rate {
+            true => {
+                self.runs.push_back(now);
+                self.dropped = 0;
+                true
+            },
+            false => {
+                self.dropped += 1;
+                false
+            },
+        }
+    }
+}
+
+/// Shell commands run on events, for those who don't want a whole script to be notified
+pub struct HooksPlugin {
+    hooks: Vec<(Hook, Limiter)>,
+    online: contact::Availability,
+}
+
+impl HooksPlugin {
+    /// Commands of the hooks of an event allowed to run now
+    fn due(&mut self, event: HookEvent, now: Instant) -> Vec<String> {
+        let mut commands = Vec::new();
+        for (hook, limiter) in self.hooks.iter_mut().filter(|(hook, _)| hook.on == event) {
+            if limiter.allow(now) {
+                commands.push(hook.command.clone());
+            } else if limiter.dropped == 1 {
+                warn!("Hook `{}` run more than {} times a minute, dropping {} events", hook.command, hook.rate, event);
+            }
+        }
+        commands
+    }
+
+    fn fire(&mut self, event: HookEvent, mut details: Details) {
+        let commands = self.due(event, Instant::now());
+        if commands.is_empty() {
+            return;
+        }
+
+        details.insert(0, ("event", event.to_string()));
+        let input = json(&details);
+        for command in commands {
+            HooksPlugin::run(&command, environment(&details), input.clone());
+        }
+    }
+
+    fn run(command: &str, environment: Vec<(String, String)>, input: String) {
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .envs(environment)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+
+        match child {
+            Ok(mut child) => {
+                // Feed and reap the child without blocking the event loop
+                thread::spawn(move || {
+                    if let Some(mut stdin) = child.stdin.take() {
+                        let _ = stdin.write_all(input.as_bytes());
+                    }
+                    child.wait()
+                });
+            },
+            Err(err) => warn!("Cannot run hook `{}`: {}", command, err),
+        }
+    }
+
+    /// Details of a message received, and whether it mentions us
+    fn message(aparte: &Aparte, message: &Message) -> Option<(Details, bool)> {
+        match message {
+            Message::Incoming(XmppMessage::Chat(message)) => Some((vec![
+                ("type", String::from("chat")),
+                ("from", message.from.to_string()),
+                ("from_full", message.from_full.to_string()),
+                ("body", message.body.to_string()),
+            ], false)),
+            incoming @ Message::Incoming(XmppMessage::Groupchat(message)) => {
+                let nick = match aparte.get_plugin::<ConversationPlugin>().unwrap().get(&message.from) {
+                    Some(Conversation::Channel(channel)) => channel.nick.clone(),
+                    _ => return None,
+                };
+                // Neither our own messages nor the ones of the channel itself
+                let from = match &*message.from_full {
+                    Jid::Full(from) if from.resource != nick => from.resource.clone(),
+                    _ => return None,
+                };
+                Some((vec![
+                    ("type", String::from("groupchat")),
+                    ("from", message.from.to_string()),
+                    ("from_full", message.from_full.to_string()),
+                    ("nick", from),
+                    ("body", message.body.to_string()),
+                ], incoming.mentions(&nick)))
+            },
+            _ => None,
+        }
+    }
+}
+
+impl Plugin for HooksPlugin {
+    fn new() -> HooksPlugin {
+        Self {
+            hooks: Vec::new(),
+            online: contact::Availability::default(),
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        self.hooks = aparte.config.hooks.iter().map(|hook| (hook.clone(), Limiter::new(hook.rate))).collect();
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        if self.hooks.is_empty() {
+            return;
+        }
+
+        match event {
+            Event::Message(message) => if let Some((details, mention)) = HooksPlugin::message(&aparte, message) {
+                if mention {
+                    self.fire(HookEvent::Mention, details.clone());
+                }
+                self.fire(HookEvent::Message, details);
+            },
+            Event::ResourcePresence(jid, presence) => {
+                if self.online.update(jid, presence) == Some(PresenceChange::Online) {
+                    let contact: BareJid = jid.clone().into();
+                    self.fire(HookEvent::Online, vec![
+                        ("from", contact.to_string()),
+                        ("from_full", jid.to_string()),
+                    ]);
+                }
+            },
+            Event::Call(call) => self.fire(HookEvent::Call, vec![
+                ("from", BareJid::from(call.from.clone()).to_string()),
+                ("from_full", call.from.to_string()),
+                ("media", call.media.join(",")),
+            ]),
+            Event::Disconnected(_) => self.online.clear(),
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for HooksPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Shell hooks")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_details() {
+        let details = vec![
+            ("event", String::from("message")),
+            ("from", String::from("contact@server.tld")),
+            ("body", String::from("Say \"hi\"\n\tbye\\")),
+        ];
+        assert_eq!(json(&details), r#"{"body":"Say \"hi\"\n\tbye\\","event":"message","from":"contact@server.tld"}"#);
+        assert_eq!(environment(&details)[1], (String::from("APARTE_FROM"), String::from("contact@server.tld")));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let config: Config = toml::from_str(r#"
+            [accounts]
+
+            [[hooks]]
+            on = "mention"
+            command = "notify-send \"$APARTE_NICK\" \"$APARTE_BODY\""
+            rate = 2
+
+            [[hooks]]
+            on = "message"
+            command = "cat >> ~/messages.json"
+        "#).unwrap();
+        assert_eq!(config.hooks[1].rate, 10);
+
+        let mut plugin = HooksPlugin::new();
+        plugin.hooks = config.hooks.iter().map(|hook| (hook.clone(), Limiter::new(hook.rate))).collect();
+        let start = Instant::now();
+        assert_eq!(plugin.due(HookEvent::Mention, start), vec![config.hooks[0].command.clone()]);
+        assert_eq!(plugin.due(HookEvent::Mention, start + Duration::from_secs(10)).len(), 1);
+        assert!(plugin.due(HookEvent::Mention, start + Duration::from_secs(20)).is_empty());
+        assert_eq!(plugin.hooks[0].1.dropped, 1);
+        assert!(plugin.due(HookEvent::Online, start).is_empty());
+        // The first run is out of the window
+        assert_eq!(plugin.due(HookEvent::Mention, start + Duration::from_secs(60)).len(), 1);
+        assert_eq!(plugin.hooks[0].1.dropped, 0);
+    }
+}
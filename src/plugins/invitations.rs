@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use xmpp_parsers::{BareJid, Jid};
+
+use crate::core::{Plugin, Aparte, Event};
+use crate::invitation::Invitation;
+
+/// Invitations to rooms waiting to be accepted or declined
+pub struct InvitationsPlugin {
+    invitations: HashMap<BareJid, Invitation>,
+    last: Option<BareJid>,
+}
+
+impl InvitationsPlugin {
+    /// Remove a pending invitation to answer it, the last one received by default
+    pub fn take(&mut self, room: Option<BareJid>) -> Result<Invitation, String> {
+        let room = match room.or_else(|| self.last.clone()) {
+            Some(room) => room,
+            None => return Err(format!("No pending invitation")),
+        };
+
+        let invitation = self.invitations.remove(&room).ok_or(format!("No invitation to {}", room))?;
+        if self.last.as_ref() == Some(&room) {
+            self.last = None;
+        }
+        Ok(invitation)
+    }
+
+    pub fn rooms(&self) -> Vec<String> {
+        self.invitations.keys().map(|room| room.to_string()).collect()
+    }
+
+    /// Remember an invitation, returns false when it was already received, an inviter sending
+    /// both a direct and a mediated invitation
+    fn received(&mut self, invitation: &Invitation) -> bool {
+        self.last = Some(invitation.room.clone());
+        self.invitations.insert(invitation.room.clone(), invitation.clone()).is_none()
+    }
+}
+
+impl Plugin for InvitationsPlugin {
+    fn new() -> InvitationsPlugin {
+        Self {
+            invitations: HashMap::new(),
+            last: None,
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Invitation(invitation) => {
+                if self.received(invitation) {
+                    aparte.log(format!("{}, use /accept {} to join or /decline {} to decline", invitation, invitation.room, invitation.room));
+                }
+            },
+            Event::Join(_, jid) => {
+                // Joining by other means answers the invitation too
+                let room = BareJid::from(Jid::Full(jid.clone()));
+                if self.invitations.remove(&room).is_some() && self.last.as_ref() == Some(&room) {
+                    self.last = None;
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for InvitationsPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Room invitations")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use xmpp_parsers::Element;
+
+    #[test]
+    fn test_take() {
+        let from = Jid::from_str("room@conference.server.tld").unwrap();
+        let payload: Element = r#"<x xmlns="http://jabber.org/protocol/muc#user"><invite from="friend@server.tld"/></x>"#.parse().unwrap();
+        let invitation = Invitation::parse(&from, &payload).unwrap();
+
+        let mut plugin = InvitationsPlugin::new();
+        assert!(plugin.take(None).is_err());
+        assert!(plugin.received(&invitation));
+        assert!(!plugin.received(&invitation));
+        assert_eq!(plugin.take(None), Ok(invitation));
+        assert!(plugin.take(None).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use chrono::{DateTime, Duration, Local, SecondsFormat, Utc};
+use futures::Future;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use uuid::Uuid;
+use xmpp_parsers::{BareJid, Element, Jid};
+use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
+use xmpp_parsers::iq::Iq;
+use xmpp_parsers::mam::{Query, QueryId};
+use xmpp_parsers::rsm::SetQuery;
+
+use crate::conversation;
+use crate::core::{Plugin, Aparte, Event, IqError};
+use crate::i18n;
+use crate::message::{Message, XmppMessage};
+use crate::plugins::bandwidth;
+use crate::plugins::conversation::ConversationPlugin;
+use crate::plugins::ui::UIPlugin;
+use crate::plugins::history::HistoryPlugin;
+use crate::store::{self, MessageStore, MemoryStore};
+
+pub const WINDOW: &str = "mentions";
+
+/// Mentions listed by /mentions
+const MAX_MENTIONS: usize = 100;
+
+/// Messages displayed before and after a mention
+const CONTEXT: usize = 5;
+
+/// Messages fetched from the archive of the room around a mention
+const ARCHIVE_WINDOW: i64 = 15;
+const ARCHIVE_MAX: usize = 100;
+
+fn nick(message: &Message) -> String {
+    match message {
+        Message::Incoming(XmppMessage::Groupchat(message)) => match &*message.from_full {
+            Jid::Full(from) => from.resource.clone(),
+            Jid::Bare(from) => from.to_string(),
+        },
+        Message::Incoming(XmppMessage::Chat(message)) => message.from.to_string(),
+        Message::Outgoing(_) => String::from("me"),
+        Message::Log(_) => String::new(),
+    }
+}
+
+/// Entry of the mentions list
+fn mention_line(index: usize, message: &Message) -> String {
+    let room = store::conversation(message).map(|room| room.to_string()).unwrap_or_default();
+    let timestamp = message.timestamp().with_timezone(&Local).format(&i18n::date_time_format()).to_string();
+    format!("[{}] {} {} <{}> {}", index, timestamp, room, nick(message), message.body())
+}
+
+/// Lines displaying the messages around a mention, the mention being marked
+fn context_lines(mention: &Message, messages: &[Message]) -> Vec<String> {
+    let timestamp = mention.timestamp().with_timezone(&Local).format(&i18n::date_time_format()).to_string();
+    let mut lines = vec![format!("Context of the mention of {}", timestamp)];
+    for message in messages {
+        let marker = if message.id() == mention.id() { "»" } else { " " };
+        let timestamp = message.timestamp().with_timezone(&Local).format(&i18n::short_time_format()).to_string();
+        lines.push(format!("{} {} <{}> {}", marker, timestamp, nick(message), message.body()));
+    }
+    lines
+}
+
+/// Query of the messages of a room archived around a given time
+fn archive_query(id: &str, room: &BareJid, timestamp: &DateTime<Utc>, max: usize) -> Iq {
+    let field = |var: &str, time: DateTime<Utc>| Field {
+        var: var.to_string(),
+        type_: FieldType::TextSingle,
+        label: None,
+        required: false,
+        options: Vec::new(),
+        values: vec![time.to_rfc3339_opts(SecondsFormat::Secs, true)],
+        media: Vec::new(),
+    };
+    let form = DataForm {
+        type_: DataFormType::Submit,
+        form_type: Some(String::from("urn:xmpp:mam:2")),
+        title: None,
+        instructions: None,
+        fields: vec![
+            field("start", *timestamp - Duration::minutes(ARCHIVE_WINDOW)),
+            field("end", *timestamp + Duration::minutes(ARCHIVE_WINDOW)),
+        ],
+    };
+    let query = Query {
+        queryid: Some(QueryId(id.to_string())),
+        node: None,
+        form: Some(form),
+        set: Some(SetQuery { max: Some(max), after: None, before: None, index: None }),
+    };
+    Iq::from_set(id.to_string(), query).with_to(Jid::Bare(room.clone()))
+}
+
+/// Context of a mention being fetched from the archive of its room
+struct ArchiveQuery {
+    mention: Message,
+    messages: MemoryStore,
+}
+
+/// Log of the messages mentioning us in channels, kept across sessions in the store of the
+/// history, only while the history is kept
+pub struct MentionsPlugin {
+    /// Mentions as last listed, to pick one by its number
+    listed: Vec<Message>,
+    /// Archive queries by id, the id of the query being the id of the iq too
+    queries: HashMap<String, ArchiveQuery>,
+}
+
+impl MentionsPlugin {
+    /// Mentions from the oldest to the most recent, numbered from 1
+    pub fn list(&mut self, store: &dyn MessageStore) -> Result<Vec<String>, String> {
+        self.listed = store.mentions(MAX_MENTIONS)?;
+        Ok(self.listed.iter().enumerate().map(|(index, message)| mention_line(index + 1, message)).collect())
+    }
+
+    /// Context of a listed mention as stored, and the archive query to complete it when some
+    /// messages are missing, fetching less of them in low bandwidth mode
+    pub fn context(&mut self, store: &dyn MessageStore, index: usize, low_bandwidth: bool) -> Result<(BareJid, Vec<String>, Option<Iq>), String> {
+        let mention = match index {
+            index if index >= 1 && index <= self.listed.len() => self.listed[index - 1].clone(),
+            _ => return Err(format!("No mention {}, use /mentions to list them", index)),
+        };
+        let room = store::conversation(&mention).ok_or(format!("No mention {}", index))?;
+        let messages = store.around(&room, mention.timestamp(), CONTEXT)?;
+
+        let query = match messages.len() < 2 * CONTEXT + 1 {
+            true => {
+                let id = Uuid::new_v4().to_hyphenated().to_string();
+                let max = match low_bandwidth {
+                    true => bandwidth::ARCHIVE_PAGE,
+                    false => ARCHIVE_MAX,
+                };
+                let query = archive_query(&id, &room, mention.timestamp(), max);
+                self.queries.insert(id, ArchiveQuery { mention: mention.clone(), messages: MemoryStore::new() });
+                Some(query)
+            },
+            false => None,
+        };
+
+        Ok((room, context_lines(&mention, &messages), query))
+    }
+
+    fn archived(&mut self, queryid: &str, message: &Message) {
+        if let Some(query) = self.queries.get_mut(queryid) {
+            let _ = query.messages.insert(message);
+        }
+    }
+
+    /// Query the archive for the context of a mention, displayed in its room once complete
+    pub fn fetch(aparte: Rc<Aparte>, query: Iq) -> impl Future<Item = (), Error = String> {
+        let id = query.id.clone();
+        let answer_aparte = Rc::clone(&aparte);
+        aparte.send_iq(query).then(move |answer| {
+            let complete = answer_aparte.get_plugin_mut::<MentionsPlugin>().unwrap().archive_complete(&id, answer);
+            if let Some((room, lines)) = complete {
+                let mut ui = answer_aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                ui.log_to(Rc::clone(&answer_aparte), &room.to_string(), lines.join("\n"));
+            }
+            Ok(())
+        })
+    }
+
+    /// Context of a mention once its archive query is complete
+    pub fn archive_complete(&mut self, id: &str, answer: Result<Iq, IqError>) -> Option<(BareJid, Vec<String>)> {
+        let query = self.queries.remove(id)?;
+        let room = store::conversation(&query.mention)?;
+        let lines = match answer {
+            Ok(_) => {
+                let mut archive = query.messages;
+                let _ = archive.insert(&query.mention);
+                let messages = archive.around(&room, query.mention.timestamp(), CONTEXT).unwrap_or_default();
+                let mut lines = context_lines(&query.mention, &messages);
+                lines[0].push_str(", from the archive");
+                lines
+            },
+            Err(IqError::Error(error)) => {
+                let condition = Element::from(error.defined_condition.clone()).name().to_string();
+                vec![format!("Cannot fetch the context of the mention from the archive: {}", condition)]
+            },
+            Err(err) => vec![format!("Cannot fetch the context of the mention from the archive: {}", err)],
+        };
+        Some((room, lines))
+    }
+}
+
+impl Plugin for MentionsPlugin {
+    fn new() -> MentionsPlugin {
+        Self {
+            listed: Vec::new(),
+            queries: HashMap::new(),
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Message(message @ Message::Incoming(XmppMessage::Groupchat(groupchat))) => {
+                let nick = match aparte.get_plugin::<ConversationPlugin>().unwrap().get(&groupchat.from) {
+                    Some(conversation::Conversation::Channel(channel)) => channel.nick.clone(),
+                    _ => return,
+                };
+
+                if let Jid::Full(from) = &*groupchat.from_full {
+                    if from.resource != nick && message.mentions(&nick) {
+                        if let Some(mut history) = aparte.get_plugin_mut::<HistoryPlugin>() {
+                            if let Err(err) = history.store_mut().insert_mention(message) {
+                                warn!("{}", err);
+                            }
+                        }
+                    }
+                }
+            },
+            Event::ArchivedMessage(queryid, message) => self.archived(queryid, message),
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for MentionsPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Mentions log")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_context_from_archive() {
+        let us = Jid::from_str("room@conference.server.tld/me").unwrap();
+        let alice = Jid::from_str("room@conference.server.tld/alice").unwrap();
+        let mention = Message::incoming_groupchat("2", Utc.timestamp_opt(1000, 0).unwrap(), &alice, &us, "me: ping");
+
+        let mut store = MemoryStore::new();
+        store.insert_mention(&mention).unwrap();
+        let mut plugin = MentionsPlugin::new();
+        assert_eq!(plugin.list(&store).unwrap().len(), 1);
+        assert!(plugin.context(&store, 2, false).is_err());
+
+        let (room, lines, query) = plugin.context(&store, 1, false).unwrap();
+        assert_eq!(room, BareJid::from_str("room@conference.server.tld").unwrap());
+        assert_eq!(lines.len(), 2);
+        let id = query.unwrap().id;
+
+        plugin.archived(&id, &Message::incoming_groupchat("1", Utc.timestamp_opt(990, 0).unwrap(), &alice, &us, "Hello"));
+        plugin.archived(&id, &Message::incoming_groupchat("3", Utc.timestamp_opt(1010, 0).unwrap(), &alice, &us, "Anyone?"));
+        let result: Element = format!(r#"<iq xmlns="jabber:client" type="result" id="{}"/>"#, id).parse().unwrap();
+        let (_, lines) = plugin.archive_complete(&id, Ok(Iq::try_from(result).unwrap())).unwrap();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[2].starts_with("»"));
+        assert!(lines[2].ends_with("<alice> me: ping"));
+
+        // Smaller pages in low bandwidth mode
+        let (_, _, query) = plugin.context(&store, 1, true).unwrap();
+        let max = format!(r#"<max xmlns="http://jabber.org/protocol/rsm">{}</max>"#, bandwidth::ARCHIVE_PAGE);
+        let query = query.unwrap();
+        assert!(String::from(&Element::from(query.clone())).contains(&max));
+        let (_, lines) = plugin.archive_complete(&query.id, Err(IqError::Timeout)).unwrap();
+        assert_eq!(lines, vec!["Cannot fetch the context of the mention from the archive: no answer after 30s"]);
+    }
+}
@@ -1,5 +1,37 @@
 pub mod disco;
 pub mod carbons;
+pub mod blocking;
+pub mod bookmarks;
 pub mod contact;
 pub mod conversation;
+pub mod moved;
+pub mod notes;
+pub mod history;
+pub mod preview;
+pub mod notifications;
 pub mod ui;
+pub mod urls;
+pub mod xmlconsole;
+pub mod slowmode;
+pub mod outbox;
+pub mod translate;
+pub mod rooms;
+pub mod mucadmin;
+pub mod mentions;
+pub mod reconnect;
+pub mod health;
+pub mod invitations;
+pub mod register;
+pub mod retention;
+pub mod gateway;
+pub mod caps;
+pub mod triggers;
+pub mod bandwidth;
+pub mod confirm;
+pub mod scripts;
+pub mod hooks;
+pub mod certificates;
+pub mod away;
+pub mod filetransfer;
+pub mod calls;
+pub mod pep;
@@ -0,0 +1,339 @@
+use futures::Future;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::{ns, Element, BareJid, Jid, roster, presence};
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::message::{Message as XmppParsersMessage, MessageType as XmppParsersMessageType, Body};
+
+use crate::core::{Plugin, Aparte, Event, IqError};
+use crate::plugins::{contact, disco};
+
+pub const NS_MOVED: &str = "urn:xmpp:moved:0";
+
+/// Id of the item of the moved statement, on the PEP node of the namespace
+const STATEMENT_ID: &str = "current";
+
+/// Notification sent by a contact from its old account, pointing to its new address
+#[derive(Debug, Clone)]
+pub struct Moved {
+    pub new: BareJid,
+    pub reason: Option<String>,
+}
+
+impl TryFrom<Element> for Moved {
+    type Error = ();
+
+    fn try_from(element: Element) -> Result<Self, Self::Error> {
+        if !element.is("moved", NS_MOVED) {
+            return Err(());
+        }
+
+        let new = match element.get_child("new", NS_MOVED) {
+            Some(new) => BareJid::from_str(new.text().trim()).map_err(|_| ())?,
+            None => return Err(()),
+        };
+
+        let reason = element.get_child("reason", NS_MOVED).map(|reason| reason.text());
+
+        Ok(Moved {
+            new: new,
+            reason: reason,
+        })
+    }
+}
+
+impl From<Moved> for Element {
+    fn from(moved: Moved) -> Element {
+        let mut builder = Element::builder("moved").ns(NS_MOVED)
+            .append(Element::builder("new").ns(NS_MOVED).append(moved.new.to_string()).build());
+
+        if let Some(reason) = moved.reason {
+            builder = builder.append(Element::builder("reason").ns(NS_MOVED).append(reason).build());
+        }
+
+        builder.build()
+    }
+}
+
+/// Request of the moved statement published by the old account of a contact, proving that the
+/// notification comes from the contact rather than from anyone knowing its old address
+fn statement_query(old: &BareJid) -> Iq {
+    let id = Uuid::new_v4().to_hyphenated().to_string();
+    let items = Element::builder("items").ns(ns::PUBSUB)
+        .attr("node", NS_MOVED)
+        .append(Element::builder("item").ns(ns::PUBSUB).attr("id", STATEMENT_ID).build())
+        .build();
+    Iq {
+        from: None,
+        to: Some(Jid::Bare(old.clone())),
+        id: id,
+        payload: IqType::Get(Element::builder("pubsub").ns(ns::PUBSUB).append(items).build()),
+    }
+}
+
+/// Moved statement found in the answer to statement_query
+fn statement(answer: Iq) -> Option<Moved> {
+    let pubsub = match answer.payload {
+        IqType::Result(Some(pubsub)) => pubsub,
+        _ => return None,
+    };
+    let items = pubsub.get_child("items", ns::PUBSUB)?;
+    let item = items.children().find(|item| item.is("item", ns::PUBSUB) && item.attr("id") == Some(STATEMENT_ID))?;
+    item.children().find_map(|moved| Moved::try_from(moved.clone()).ok())
+}
+
+/// Publication of our moved statement on our PEP node, for contacts to check our notification
+fn statement_publish(moved: Moved) -> Iq {
+    let id = Uuid::new_v4().to_hyphenated().to_string();
+    let publish = Element::builder("publish").ns(ns::PUBSUB)
+        .attr("node", NS_MOVED)
+        .append(Element::builder("item").ns(ns::PUBSUB).attr("id", STATEMENT_ID).append(Element::from(moved)).build())
+        .build();
+    Iq {
+        from: None,
+        to: None,
+        id: id,
+        payload: IqType::Set(Element::builder("pubsub").ns(ns::PUBSUB).append(publish).build()),
+    }
+}
+
+/// Whether the statement published by the old account confirms the move notified
+fn confirms(moved: &Moved, answer: Result<Iq, IqError>) -> Result<(), String> {
+    match answer.map(statement) {
+        Ok(Some(statement)) if statement.new == moved.new => Ok(()),
+        Ok(Some(statement)) => Err(format!("it published a move to {}", statement.new)),
+        Ok(None) => Err(format!("it didn't publish it")),
+        Err(err) => Err(format!("its statement cannot be fetched: {}", err)),
+    }
+}
+
+pub struct MovedPlugin {
+    /// Contacts that told us they moved, indexed by their old address
+    moves: HashMap<BareJid, Moved>,
+    last: Option<BareJid>,
+}
+
+impl MovedPlugin {
+    /// Build the stanzas subscribing to the new address of a contact that moved, keeping the
+    /// name and groups it had in our roster.
+    pub fn accept(&mut self, aparte: &Aparte, old: Option<BareJid>) -> Result<(BareJid, Vec<Element>), String> {
+        let old = match old.or_else(|| self.last.clone()) {
+            Some(old) => old,
+            None => return Err(format!("No contact moved")),
+        };
+
+        let moved = match self.moves.remove(&old) {
+            Some(moved) => moved,
+            None => return Err(format!("{} didn't move", old)),
+        };
+
+        if self.last.as_ref() == Some(&old) {
+            self.last = None;
+        }
+
+        let contacts = aparte.get_plugin::<contact::ContactPlugin>().unwrap();
+        let known = aparte.current_connection().and_then(|account| contacts.contact(&account, &old).cloned());
+        let (name, groups) = match known {
+            Some(contact) => (contact.name.clone(), contact.groups.iter().map(|group| roster::Group(group.0.clone())).collect()),
+            None => (None, Vec::new()),
+        };
+
+        let item = roster::Item {
+            jid: moved.new.clone(),
+            name: name,
+            subscription: roster::Subscription::None,
+            ask: roster::Ask::None,
+            groups: groups,
+        };
+
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let stanzas = vec![
+            Iq::from_set(id, roster::Roster { ver: None, items: vec![item] }).into(),
+            presence::Presence::new(presence::Type::Subscribe).with_to(Jid::Bare(moved.new.clone())).into(),
+        ];
+
+        Ok((moved.new, stanzas))
+    }
+
+    /// Subscribe to the new address of a contact that moved, the last one by default
+    pub fn resubscribe(aparte: Rc<Aparte>, old: Option<BareJid>) -> Result<(), String> {
+        let result = {
+            let mut moved = aparte.get_plugin_mut::<MovedPlugin>().unwrap();
+            moved.accept(&aparte, old)
+        };
+        let (new, stanzas) = result?;
+        for stanza in stanzas {
+            aparte.send(stanza);
+        }
+        aparte.log(format!("Subscribed to {}", new));
+        Ok(())
+    }
+
+    /// Offer to subscribe to the new address of a contact once its old account confirmed the move
+    fn verify(aparte: Rc<Aparte>, old: BareJid, moved: Moved) -> impl Future<Item = (), Error = String> {
+        let answer_aparte = Rc::clone(&aparte);
+        aparte.send_iq(statement_query(&old)).then(move |answer| {
+            let aparte = answer_aparte;
+            let mut notice = format!("{} moved to {}", old, moved.new);
+            if let Some(reason) = &moved.reason {
+                notice.push_str(&format!(" ({})", reason));
+            }
+
+            match confirms(&moved, answer) {
+                Ok(()) => {
+                    notice.push_str(", use /moved accept or Alt-m to subscribe to the new address");
+                    let mut plugin = aparte.get_plugin_mut::<MovedPlugin>().unwrap();
+                    plugin.moves.insert(old.clone(), moved);
+                    plugin.last = Some(old);
+                },
+                Err(err) => notice = format!("Ignored the notification that {}, {}", notice, err),
+            }
+            aparte.log(notice);
+            Ok(())
+        })
+    }
+
+    /// Build our moved statement, followed by a moved notification for each of our contacts. A
+    /// body is included for clients not supporting the notification.
+    pub fn announce(&self, aparte: &Aparte, new: &BareJid, reason: Option<String>) -> (Iq, Vec<Element>) {
+        let statement = statement_publish(Moved {
+            new: new.clone(),
+            reason: reason.clone(),
+        });
+        let contact = aparte.get_plugin::<contact::ContactPlugin>().unwrap();
+        let contacts = match aparte.current_connection() {
+            Some(account) => contact.contacts(&account).into_iter().map(|contact| contact.jid.clone()).collect(),
+            None => Vec::new(),
+        };
+        let notifications = contacts.into_iter().map(|jid| {
+            let mut message = XmppParsersMessage::new(Some(Jid::Bare(jid)));
+            message.id = Some(Uuid::new_v4().to_string());
+            message.type_ = XmppParsersMessageType::Chat;
+            let body = match &reason {
+                Some(reason) => format!("I moved to {}: {}", new, reason),
+                None => format!("I moved to {}", new),
+            };
+            message.bodies.insert(String::new(), Body(body));
+            message.payloads.push(Moved {
+                new: new.clone(),
+                reason: reason.clone(),
+            }.into());
+            message.into()
+        }).collect();
+        (statement, notifications)
+    }
+}
+
+impl Plugin for MovedPlugin {
+    fn new() -> MovedPlugin {
+        Self {
+            moves: HashMap::new(),
+            last: None,
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        let mut disco = aparte.get_plugin_mut::<disco::Disco>().unwrap();
+        disco.add_feature(NS_MOVED)
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Moved(old, new, reason) => {
+                let known = match aparte.current_connection() {
+                    Some(account) => aparte.get_plugin::<contact::ContactPlugin>().unwrap().contact(&account, old).is_some(),
+                    None => false,
+                };
+                if !known {
+                    return;
+                }
+
+                let moved = Moved {
+                    new: new.clone(),
+                    reason: reason.clone(),
+                };
+                Rc::clone(&aparte).spawn(MovedPlugin::verify(aparte, old.clone(), moved));
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for MovedPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0283: Moved")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_moved_parsing() {
+        let element = Element::from_str("<moved xmlns='urn:xmpp:moved:0'><new>contact@new.tld</new><reason>Server shutdown</reason></moved>").unwrap();
+        let moved = Moved::try_from(element).unwrap();
+
+        assert_eq!(moved.new, BareJid::from_str("contact@new.tld").unwrap());
+        assert_eq!(moved.reason, Some("Server shutdown".to_string()));
+    }
+
+    #[test]
+    fn test_moved_without_new_jid() {
+        let element = Element::from_str("<moved xmlns='urn:xmpp:moved:0'><reason>Server shutdown</reason></moved>").unwrap();
+
+        assert!(Moved::try_from(element).is_err());
+    }
+
+    /// Answer to statement_query with the statement as published by statement_publish
+    fn published(query: &Iq, moved: Moved) -> Iq {
+        let publish = match statement_publish(moved).payload {
+            IqType::Set(pubsub) => pubsub.get_child("publish", ns::PUBSUB).unwrap().clone(),
+            _ => panic!("Not a publication"),
+        };
+        let items = Element::builder("items").ns(ns::PUBSUB).attr("node", NS_MOVED)
+            .append(publish.get_child("item", ns::PUBSUB).unwrap().clone())
+            .build();
+        answer(query, Some(Element::builder("pubsub").ns(ns::PUBSUB).append(items).build()))
+    }
+
+    fn answer(query: &Iq, payload: Option<Element>) -> Iq {
+        Iq { from: query.to.clone(), to: None, id: query.id.clone(), payload: IqType::Result(payload) }
+    }
+
+    #[test]
+    fn test_statement() {
+        let old = BareJid::from_str("contact@old.tld").unwrap();
+        let moved = Moved {
+            new: BareJid::from_str("contact@new.tld").unwrap(),
+            reason: None,
+        };
+        let query = statement_query(&old);
+        assert_eq!(query.to, Some(Jid::Bare(old)));
+
+        assert_eq!(confirms(&moved, Ok(published(&query, moved.clone()))), Ok(()));
+        let other = Moved {
+            new: BareJid::from_str("someone@else.tld").unwrap(),
+            reason: None,
+        };
+        assert!(confirms(&moved, Ok(published(&query, other))).is_err());
+        assert!(confirms(&moved, Ok(answer(&query, None))).is_err());
+        assert!(confirms(&moved, Err(IqError::Timeout)).is_err());
+    }
+
+    #[test]
+    fn test_moved_serialization() {
+        let moved = Moved {
+            new: BareJid::from_str("contact@new.tld").unwrap(),
+            reason: None,
+        };
+        let moved = Moved::try_from(Element::from(moved)).unwrap();
+
+        assert_eq!(moved.new, BareJid::from_str("contact@new.tld").unwrap());
+        assert_eq!(moved.reason, None);
+    }
+}
@@ -0,0 +1,470 @@
+use futures::Future;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+use uuid::Uuid;
+use std::convert::TryFrom;
+use xmpp_parsers::{BareJid, Element, Jid};
+use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
+use xmpp_parsers::iq::{Iq, IqType};
+
+use crate::conversation::Conversation;
+use crate::core::{Plugin, Aparte, Event, IqError};
+use crate::plugins::conversation::ConversationPlugin;
+use crate::plugins::ui::UIPlugin;
+
+pub const NS_MUC_ADMIN: &str = "http://jabber.org/protocol/muc#admin";
+pub const NS_MUC_USER: &str = "http://jabber.org/protocol/muc#user";
+pub const NS_MUC_OWNER: &str = "http://jabber.org/protocol/muc#owner";
+const NS_DATA_FORMS: &str = "jabber:x:data";
+
+/// Window displaying the configuration form of a room being edited
+pub const CONFIGURE_WINDOW: &str = "configure";
+
+pub const ROLES: &[&str] = &["moderator", "participant", "visitor", "none"];
+pub const AFFILIATIONS: &[&str] = &["owner", "admin", "member", "outcast", "none"];
+
+/// Occupant targeted by an admin request, by nick for roles and by JID for affiliations
+enum Target<'a> {
+    Nick(&'a str),
+    Jid(&'a BareJid),
+}
+
+/// Item of a muc#admin query changing the role or the affiliation of an occupant
+fn admin_item(target: Target, attr: &str, value: &str, reason: Option<&str>) -> Element {
+    let mut item = Element::builder("item").ns(NS_MUC_ADMIN).attr(attr, value);
+    item = match target {
+        Target::Nick(nick) => item.attr("nick", nick),
+        Target::Jid(jid) => item.attr("jid", jid.to_string()),
+    };
+    if let Some(reason) = reason {
+        item = item.append(Element::builder("reason").ns(NS_MUC_ADMIN).append(reason).build());
+    }
+    item.build()
+}
+
+fn admin_iq(room: &BareJid, item: Element) -> Iq {
+    Iq {
+        from: None,
+        to: Some(Jid::Bare(room.clone())),
+        id: Uuid::new_v4().to_hyphenated().to_string(),
+        payload: IqType::Set(Element::builder("query").ns(NS_MUC_ADMIN).append(item).build()),
+    }
+}
+
+/// Message changing the subject of a room
+pub fn subject(room: &BareJid, subject: &str) -> Element {
+    Element::builder("message").ns("jabber:client")
+        .attr("type", "groupchat")
+        .attr("to", room.to_string())
+        .attr("id", Uuid::new_v4().to_hyphenated().to_string())
+        .append(Element::builder("subject").ns("jabber:client").append(subject).build())
+        .build()
+}
+
+/// Mediated invitation to a room, sent through the room itself
+pub fn invite(room: &BareJid, jid: &BareJid, reason: Option<&str>) -> Element {
+    let mut invite = Element::builder("invite").ns(NS_MUC_USER).attr("to", jid.to_string());
+    if let Some(reason) = reason {
+        invite = invite.append(Element::builder("reason").ns(NS_MUC_USER).append(reason).build());
+    }
+    Element::builder("message").ns("jabber:client")
+        .attr("to", room.to_string())
+        .attr("id", Uuid::new_v4().to_hyphenated().to_string())
+        .append(Element::builder("x").ns(NS_MUC_USER).append(invite.build()).build())
+        .build()
+}
+
+fn owner_iq(room: &BareJid, form: Option<DataForm>) -> Iq {
+    let query = Element::builder("query").ns(NS_MUC_OWNER);
+    let payload = match form {
+        Some(form) => IqType::Set(query.append(Element::from(form)).build()),
+        None => IqType::Get(query.build()),
+    };
+    Iq {
+        from: None,
+        to: Some(Jid::Bare(room.clone())),
+        id: Uuid::new_v4().to_hyphenated().to_string(),
+        payload: payload,
+    }
+}
+
+/// Data form of a query, fixed fields without var being dropped as they can't be parsed
+pub fn parse_form(query: &Element) -> Result<DataForm, String> {
+    let x = query.get_child("x", NS_DATA_FORMS).ok_or(format!("No form"))?;
+    let mut builder = Element::builder("x").ns(NS_DATA_FORMS).attr("type", x.attr("type"));
+    for child in x.children().filter(|child| !child.is("field", NS_DATA_FORMS) || child.attr("var").is_some()) {
+        builder = builder.append(child.clone());
+    }
+    DataForm::try_from(builder.build()).map_err(|err| format!("Invalid form: {}", err))
+}
+
+/// Values of a field from what the user typed, checked against the type and the options of the field
+pub fn field_values(field: &Field, value: &str) -> Result<Vec<String>, String> {
+    let option = |value: &str| -> Result<String, String> {
+        if field.options.is_empty() {
+            return Ok(value.to_string());
+        }
+        match field.options.iter().find(|option| option.value == value || option.label.as_deref() == Some(value)) {
+            Some(option) => Ok(option.value.clone()),
+            None => {
+                let options: Vec<&str> = field.options.iter().map(|option| option.value.as_str()).collect();
+                Err(format!("Invalid value {} for {}, expected one of {}", value, field.var, options.join(", ")))
+            },
+        }
+    };
+    let jid = |value: &str| -> Result<String, String> {
+        Jid::from_str(value).map(|jid| jid.to_string()).map_err(|err| format!("Invalid JID {}: {}", value, err))
+    };
+    let list = |value: &str| -> Vec<String> {
+        value.split(',').map(str::trim).filter(|value| !value.is_empty()).map(String::from).collect()
+    };
+
+    match field.type_ {
+        FieldType::Boolean => match value.to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(vec![String::from("1")]),
+            "0" | "false" | "no" | "off" => Ok(vec![String::from("0")]),
+            _ => Err(format!("Invalid value {} for {}, expected yes or no", value, field.var)),
+        },
+        FieldType::ListSingle => Ok(vec![option(value)?]),
+        FieldType::ListMulti => list(value).iter().map(|value| option(value)).collect(),
+        FieldType::JidSingle => Ok(vec![jid(value)?]),
+        FieldType::JidMulti => list(value).iter().map(|value| jid(value)).collect(),
+        FieldType::Fixed | FieldType::Hidden => Err(format!("{} can't be changed", field.var)),
+        _ => Ok(vec![value.to_string()]),
+    }
+}
+
+pub fn render_field(index: usize, field: &Field) -> Option<String> {
+    let label = field.label.as_deref().unwrap_or(&field.var);
+    let option_label = |value: &String| field.options.iter()
+        .find(|option| &option.value == value)
+        .and_then(|option| option.label.clone())
+        .unwrap_or_else(|| value.clone());
+    let value = match field.type_ {
+        FieldType::Hidden => return None,
+        FieldType::Fixed => return Some(format!("    {}", field.values.join(" "))),
+        FieldType::Boolean => match field.values.first().map(String::as_str) {
+            Some("1") | Some("true") => String::from("yes"),
+            _ => String::from("no"),
+        },
+        FieldType::TextPrivate if !field.values.is_empty() => String::from("••••••"),
+        FieldType::ListSingle | FieldType::ListMulti => field.values.iter().map(option_label).collect::<Vec<_>>().join(", "),
+        _ => field.values.join(", "),
+    };
+
+    let mut line = format!("[{}] {}: {}  ({})", index, label, value, field.var);
+    if !field.options.is_empty() {
+        let options: Vec<&str> = field.options.iter().map(|option| option.value.as_str()).collect();
+        line.push_str(&format!("\n      options: {}", options.join(", ")));
+    }
+    Some(line)
+}
+
+/// Configuration form of a room being edited
+pub struct RoomConfig {
+    pub room: BareJid,
+    form: DataForm,
+}
+
+impl RoomConfig {
+    /// Change a field given by its number or its var
+    fn set(&mut self, field: &str, value: &str) -> Result<(), String> {
+        let index = match usize::from_str(field) {
+            Ok(index) if index >= 1 && index <= self.form.fields.len() => index - 1,
+            Ok(index) => return Err(format!("No field {} in the configuration form", index)),
+            Err(_) => self.form.fields.iter().position(|candidate| candidate.var == field).ok_or(format!("No field {} in the configuration form", field))?,
+        };
+        let field = &mut self.form.fields[index];
+        field.values = field_values(field, value)?;
+        Ok(())
+    }
+
+    /// Lines displaying the form, each editable field being numbered
+    pub fn render(&self) -> Vec<String> {
+        let mut lines = vec![format!("Configuration of {}", self.room)];
+        lines.extend(self.form.title.iter().cloned());
+        lines.extend(self.form.instructions.iter().cloned());
+        lines.extend(self.form.fields.iter().enumerate().filter_map(|(index, field)| render_field(index + 1, field)));
+        lines.push(String::from("Change fields with /configure set <field> <value>, then /configure submit or /configure cancel"));
+        lines
+    }
+
+    /// Form sent back to the room with the values of the fields
+    fn submit(self) -> DataForm {
+        submitted_form(self.form)
+    }
+}
+
+/// Form sent back with the values of the fields of a filled form
+pub fn submitted_form(form: DataForm) -> DataForm {
+    DataForm {
+        type_: DataFormType::Submit,
+        form_type: form.form_type,
+        title: None,
+        instructions: None,
+        fields: form.fields.into_iter().filter(|field| field.type_ != FieldType::Fixed).map(|field| Field {
+            label: None,
+            required: false,
+            options: Vec::new(),
+            media: Vec::new(),
+            ..field
+        }).collect(),
+    }
+}
+
+enum About {
+    /// Change of the room with a description of the change
+    Change(BareJid, String),
+    /// Configuration form of the room
+    Configuration(BareJid),
+}
+
+/// Request to a room, with what its answer is about
+pub struct Request {
+    iq: Iq,
+    about: About,
+}
+
+/// Moderation of rooms: roles and affiliations of occupants and configuration of the room,
+/// reporting the outcome in the room
+pub struct MucAdminPlugin {
+    configuration: Option<RoomConfig>,
+}
+
+impl MucAdminPlugin {
+    fn request(room: &BareJid, item: Element, description: String) -> Request {
+        Request {
+            iq: admin_iq(room, item),
+            about: About::Change(room.clone(), description),
+        }
+    }
+
+    /// Change the role of an occupant, a role of none kicks them out
+    pub fn set_role(room: &BareJid, nick: &str, role: &str, reason: Option<&str>) -> Result<Request, String> {
+        if !ROLES.contains(&role) {
+            return Err(format!("Invalid role {}, expected one of {}", role, ROLES.join(", ")));
+        }
+        let description = match role {
+            "none" => format!("kick {}", nick),
+            role => format!("set role of {} to {}", nick, role),
+        };
+        Ok(Self::request(room, admin_item(Target::Nick(nick), "role", role, reason), description))
+    }
+
+    /// Change the affiliation of a user, an affiliation of outcast bans them
+    pub fn set_affiliation(room: &BareJid, jid: &BareJid, affiliation: &str, reason: Option<&str>) -> Result<Request, String> {
+        if !AFFILIATIONS.contains(&affiliation) {
+            return Err(format!("Invalid affiliation {}, expected one of {}", affiliation, AFFILIATIONS.join(", ")));
+        }
+        let description = match affiliation {
+            "outcast" => format!("ban {}", jid),
+            affiliation => format!("set affiliation of {} to {}", jid, affiliation),
+        };
+        Ok(Self::request(room, admin_item(Target::Jid(jid), "affiliation", affiliation, reason), description))
+    }
+
+    /// Request the configuration form of a room, which is then displayed in the configure window
+    pub fn configure(room: &BareJid) -> Request {
+        Request {
+            iq: owner_iq(room, None),
+            about: About::Configuration(room.clone()),
+        }
+    }
+
+    /// Change a field of the configuration being edited
+    pub fn set_config(&mut self, field: &str, value: &str) -> Result<&RoomConfig, String> {
+        let configuration = self.configuration.as_mut().ok_or(format!("No configuration being edited, use /configure in a channel first"))?;
+        configuration.set(field, value)?;
+        Ok(configuration)
+    }
+
+    /// Send the configuration being edited to its room
+    pub fn submit_config(&mut self) -> Result<(BareJid, Request), String> {
+        let configuration = self.configuration.take().ok_or(format!("No configuration being edited"))?;
+        let room = configuration.room.clone();
+        let request = Request {
+            iq: owner_iq(&room, Some(configuration.submit())),
+            about: About::Change(room.clone(), format!("configure {}", room)),
+        };
+        Ok((room, request))
+    }
+
+    /// Drop the configuration being edited, telling the room so that a new room isn't kept locked
+    pub fn cancel_config(&mut self) -> Result<(BareJid, Iq), String> {
+        let configuration = self.configuration.take().ok_or(format!("No configuration being edited"))?;
+        let room = configuration.room;
+        let form = DataForm {
+            type_: DataFormType::Cancel,
+            form_type: None,
+            title: None,
+            instructions: None,
+            fields: Vec::new(),
+        };
+        Ok((room.clone(), owner_iq(&room, Some(form))))
+    }
+
+    /// Send a request to a room, its outcome being reported in the room once answered
+    pub fn send(aparte: Rc<Aparte>, request: Request) -> impl Future<Item = (), Error = String> {
+        let about = request.about;
+        let answer_aparte = Rc::clone(&aparte);
+        aparte.send_iq(request.iq).then(move |answer| {
+            let (window, outcome) = answer_aparte.get_plugin_mut::<MucAdminPlugin>().unwrap().answer(about, answer);
+            let mut ui = answer_aparte.get_plugin_mut::<UIPlugin>().unwrap();
+            if window == CONFIGURE_WINDOW {
+                ui.add_log_window(CONFIGURE_WINDOW);
+                ui.clear_window(CONFIGURE_WINDOW);
+                ui.change_window(CONFIGURE_WINDOW);
+            }
+            ui.log_to(Rc::clone(&answer_aparte), &window, outcome);
+            Ok(())
+        })
+    }
+
+    /// Window where to report the outcome of a request, and the outcome
+    fn answer(&mut self, about: About, answer: Result<Iq, IqError>) -> (String, String) {
+        match (about, answer.map(|answer| answer.payload)) {
+            (About::Change(room, description), Ok(_)) => (room.to_string(), format!("Done: {}", description)),
+            (About::Change(room, description), Err(err)) => (room.to_string(), format!("Cannot {}: {}", description, iq_error_text(&err))),
+            (About::Configuration(room), Ok(IqType::Result(Some(query)))) => match parse_form(&query) {
+                Ok(form) => {
+                    let configuration = RoomConfig { room: room, form: form };
+                    let lines = configuration.render();
+                    self.configuration = Some(configuration);
+                    (CONFIGURE_WINDOW.to_string(), lines.join("\n"))
+                },
+                Err(err) => (room.to_string(), format!("Cannot configure {}: {}", room, err)),
+            },
+            (About::Configuration(room), Ok(_)) => (room.to_string(), format!("Cannot configure {}: No form", room)),
+            (About::Configuration(room), Err(err)) => (room.to_string(), format!("Cannot configure {}: {}", room, iq_error_text(&err))),
+        }
+    }
+}
+
+pub fn error_text(error: &xmpp_parsers::stanza_error::StanzaError) -> String {
+    let condition = Element::from(error.defined_condition.clone()).name().to_string();
+    match error.texts.values().next() {
+        Some(text) => format!("{} ({})", condition, text),
+        None => condition,
+    }
+}
+
+/// Why a request failed, the error answered being described as by error_text
+pub fn iq_error_text(error: &IqError) -> String {
+    match error {
+        IqError::Error(error) => error_text(error),
+        error => error.to_string(),
+    }
+}
+
+impl Plugin for MucAdminPlugin {
+    fn new() -> MucAdminPlugin {
+        Self {
+            configuration: None,
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, _aparte: Rc<Aparte>, _event: &Event) {
+    }
+}
+
+impl fmt::Display for MucAdminPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0045: Multi-User Chat administration")
+    }
+}
+
+/// Nicks of the occupants of a room, to complete commands
+pub fn occupant_nicks(aparte: &Aparte, room: &str) -> Vec<String> {
+    let conversations = aparte.get_plugin::<ConversationPlugin>().unwrap();
+    match Jid::from_str(room).ok().map(BareJid::from).and_then(|jid| conversations.get(&jid)) {
+        Some(Conversation::Channel(channel)) => channel.occupants.keys().cloned().collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kick() {
+        let room = BareJid::from_str("room@conference.server.tld").unwrap();
+        let request = MucAdminPlugin::set_role(&room, "troll", "none", Some("Be nice")).unwrap();
+        match &request.about {
+            About::Change(_, description) => assert_eq!(description, "kick troll"),
+            _ => unreachable!(),
+        }
+        let iq = Element::from(request.iq);
+        assert_eq!(iq.attr("type"), Some("set"));
+        assert_eq!(iq.attr("to"), Some("room@conference.server.tld"));
+        let item = iq.get_child("query", NS_MUC_ADMIN).unwrap().get_child("item", NS_MUC_ADMIN).unwrap();
+        assert_eq!(item.attr("role"), Some("none"));
+        assert_eq!(item.attr("nick"), Some("troll"));
+        assert_eq!(item.get_child("reason", NS_MUC_ADMIN).unwrap().text(), "Be nice");
+
+        assert!(MucAdminPlugin::set_role(&room, "troll", "king", None).is_err());
+    }
+
+    #[test]
+    fn test_ban() {
+        let room = BareJid::from_str("room@conference.server.tld").unwrap();
+        let spammer = BareJid::from_str("spammer@server.tld").unwrap();
+        let request = MucAdminPlugin::set_affiliation(&room, &spammer, "outcast", None).unwrap();
+        match &request.about {
+            About::Change(_, description) => assert_eq!(description, "ban spammer@server.tld"),
+            _ => unreachable!(),
+        }
+        let iq = Element::from(request.iq);
+        let item = iq.get_child("query", NS_MUC_ADMIN).unwrap().get_child("item", NS_MUC_ADMIN).unwrap();
+        assert_eq!(item.attr("affiliation"), Some("outcast"));
+        assert_eq!(item.attr("jid"), Some("spammer@server.tld"));
+
+        let (window, outcome) = MucAdminPlugin::new().answer(request.about, Err(IqError::Timeout));
+        assert_eq!(window, "room@conference.server.tld");
+        assert_eq!(outcome, "Cannot ban spammer@server.tld: no answer after 30s");
+    }
+
+    #[test]
+    fn test_invite() {
+        let room = BareJid::from_str("room@conference.server.tld").unwrap();
+        let friend = BareJid::from_str("friend@server.tld").unwrap();
+        let message = invite(&room, &friend, Some("Join us"));
+        let invite = message.get_child("x", NS_MUC_USER).unwrap().get_child("invite", NS_MUC_USER).unwrap();
+        assert_eq!(invite.attr("to"), Some("friend@server.tld"));
+        assert_eq!(invite.get_child("reason", NS_MUC_USER).unwrap().text(), "Join us");
+    }
+
+    #[test]
+    fn test_configure() {
+        let room = BareJid::from_str("room@conference.server.tld").unwrap();
+        let mut plugin = MucAdminPlugin::new();
+        let request = MucAdminPlugin::configure(&room);
+        let query = Element::from(request.iq.clone());
+        assert_eq!(query.attr("type"), Some("get"));
+        assert!(query.get_child("query", NS_MUC_OWNER).is_some());
+
+        let result: Element = format!(r#"<iq xmlns="jabber:client" type="result" id="{}"><query xmlns="http://jabber.org/protocol/muc#owner"><x xmlns="jabber:x:data" type="form"><title>Room configuration</title><field var="FORM_TYPE" type="hidden"><value>http://jabber.org/protocol/muc#roomconfig</value></field><field type="fixed"><value>General</value></field><field var="muc#roomconfig_persistentroom" type="boolean" label="Make room persistent"><value>0</value></field><field var="muc#roomconfig_whois" type="list-single" label="Who may discover real JIDs?"><option label="Moderators only"><value>moderators</value></option><option label="Anyone"><value>anyone</value></option><value>moderators</value></field></x></query></iq>"#, query.attr("id").unwrap()).parse().unwrap();
+        let (window, _) = plugin.answer(request.about, Ok(Iq::try_from(result).unwrap()));
+        assert_eq!(window, CONFIGURE_WINDOW);
+
+        let lines = plugin.set_config("1", "yes").unwrap().render();
+        assert_eq!(lines[2], "[1] Make room persistent: yes  (muc#roomconfig_persistentroom)");
+        assert!(plugin.set_config("muc#roomconfig_whois", "everyone").is_err());
+        plugin.set_config("muc#roomconfig_whois", "Anyone").unwrap();
+        assert!(plugin.set_config("3", "yes").is_err());
+
+        let (_, request) = plugin.submit_config().unwrap();
+        let iq = Element::from(request.iq);
+        let form = DataForm::try_from(iq.get_child("query", NS_MUC_OWNER).unwrap().get_child("x", NS_DATA_FORMS).unwrap().clone()).unwrap();
+        assert_eq!(form.type_, DataFormType::Submit);
+        assert_eq!(form.form_type.as_deref(), Some("http://jabber.org/protocol/muc#roomconfig"));
+        assert_eq!(form.fields[0].values, vec!["1"]);
+        assert_eq!(form.fields[1].values, vec!["anyone"]);
+        assert!(plugin.submit_config().is_err());
+    }
+}
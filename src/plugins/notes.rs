@@ -0,0 +1,180 @@
+use chrono::{SecondsFormat, Utc};
+use futures::Future;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::{BareJid, Element, FullJid};
+use xmpp_parsers::iq::{Iq, IqType};
+
+use crate::core::{Plugin, Aparte, Event};
+use crate::plugins::bookmarks::PrivateXml;
+use crate::plugins::ui::UIPlugin;
+
+pub const NS_ROSTERNOTES: &str = "storage:rosternotes";
+
+/// XEP-0145 annotation of a contact
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub jid: BareJid,
+    pub text: String,
+    pub cdate: Option<String>,
+    pub mdate: Option<String>,
+}
+
+/// XEP-0145 storage of every note
+#[derive(Debug, Clone, PartialEq)]
+pub struct Storage {
+    pub notes: Vec<Note>,
+}
+
+impl TryFrom<Element> for Storage {
+    type Error = ();
+
+    fn try_from(element: Element) -> Result<Self, Self::Error> {
+        if !element.is("storage", NS_ROSTERNOTES) {
+            return Err(());
+        }
+
+        let mut notes = Vec::new();
+        for child in element.children().filter(|child| child.is("note", NS_ROSTERNOTES)) {
+            let jid = match child.attr("jid").map(BareJid::from_str) {
+                Some(Ok(jid)) => jid,
+                _ => continue,
+            };
+            notes.push(Note {
+                jid: jid,
+                text: child.text(),
+                cdate: child.attr("cdate").map(str::to_string),
+                mdate: child.attr("mdate").map(str::to_string),
+            });
+        }
+
+        Ok(Storage { notes: notes })
+    }
+}
+
+impl From<Storage> for Element {
+    fn from(storage: Storage) -> Element {
+        let mut element = Element::builder("storage").ns(NS_ROSTERNOTES).build();
+        for note in storage.notes {
+            element.append_child(Element::builder("note").ns(NS_ROSTERNOTES)
+                .attr("jid", note.jid.to_string())
+                .attr("cdate", note.cdate)
+                .attr("mdate", note.mdate)
+                .append(note.text)
+                .build());
+        }
+        element
+    }
+}
+
+/// Private notes about contacts, stored on the server so they roam between clients
+pub struct NotesPlugin {
+    notes: HashMap<BareJid, Note>,
+}
+
+impl NotesPlugin {
+    pub fn get(&self, jid: &BareJid) -> Option<&str> {
+        self.notes.get(jid).map(|note| note.text.as_str())
+    }
+
+    /// Fetch the notes stored on the server of an account
+    fn fetch(aparte: Rc<Aparte>, account: &FullJid) -> impl Future<Item = (), Error = String> {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let iq = Iq::from_get(id, PrivateXml { payload: Storage { notes: Vec::new() }.into() });
+        let answer_aparte = Rc::clone(&aparte);
+        aparte.send_iq_on(account, iq).map(move |answer| {
+            let storage = match answer.payload {
+                IqType::Result(Some(payload)) => PrivateXml::try_from(payload).ok().and_then(|private| Storage::try_from(private.payload).ok()),
+                _ => None,
+            };
+            if let Some(storage) = storage {
+                let mut notes = answer_aparte.get_plugin_mut::<NotesPlugin>().unwrap();
+                notes.notes = storage.notes.into_iter().map(|note| (note.jid.clone(), note)).collect();
+                notes.update_ui(&answer_aparte);
+            }
+        }).map_err(|err| format!("Cannot fetch the notes about contacts: {}", err))
+    }
+
+    fn storage(&self) -> Storage {
+        let mut notes: Vec<Note> = self.notes.values().cloned().collect();
+        notes.sort_by_key(|note| note.jid.to_string());
+        Storage { notes: notes }
+    }
+
+    /// Set the note about a contact, or remove it when empty. Returns the request storing every
+    /// note, as the storage can only be replaced as a whole.
+    pub fn set(&mut self, aparte: &Aparte, jid: BareJid, text: &str) -> Iq {
+        if text.is_empty() {
+            self.notes.remove(&jid);
+        } else {
+            let now = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+            let cdate = self.notes.get(&jid).and_then(|note| note.cdate.clone()).unwrap_or(now.clone());
+            self.notes.insert(jid.clone(), Note {
+                jid: jid,
+                text: text.to_string(),
+                cdate: Some(cdate),
+                mdate: Some(now),
+            });
+        }
+
+        self.update_ui(aparte);
+
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        Iq::from_set(id, PrivateXml { payload: self.storage().into() })
+    }
+
+    fn update_ui(&self, aparte: &Aparte) {
+        let notes = self.notes.iter().map(|(jid, note)| (jid.clone(), note.text.clone())).collect();
+        let mut ui = aparte.get_plugin_mut::<UIPlugin>().unwrap();
+        ui.set_notes(notes);
+    }
+}
+
+impl Plugin for NotesPlugin {
+    fn new() -> NotesPlugin {
+        Self {
+            notes: HashMap::new(),
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Connected(jid) => {
+                Rc::clone(&aparte).spawn(NotesPlugin::fetch(Rc::clone(&aparte), jid));
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for NotesPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0145: Annotations")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_roundtrip() {
+        let element: Element = r#"<storage xmlns="storage:rosternotes"><note jid="hamlet@shakespeare.lit" cdate="2004-09-24T15:23:21Z" mdate="2004-09-24T15:23:21Z">Seems to be a good writer</note><note>Ignored</note></storage>"#.parse().unwrap();
+        let storage = Storage::try_from(element).unwrap();
+        assert_eq!(storage.notes.len(), 1);
+        assert_eq!(storage.notes[0].jid, BareJid::from_str("hamlet@shakespeare.lit").unwrap());
+        assert_eq!(storage.notes[0].text, "Seems to be a good writer");
+        assert_eq!(storage.notes[0].cdate.as_deref(), Some("2004-09-24T15:23:21Z"));
+
+        let element: Element = storage.clone().into();
+        assert_eq!(Storage::try_from(element).unwrap(), storage);
+    }
+}
@@ -0,0 +1,276 @@
+use chrono::{Local, NaiveTime};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::thread;
+use xmpp_parsers::Jid;
+
+use crate::config::{Bell, Bells, NotificationLevel, QuietHours};
+use crate::core::{Plugin, Aparte, Event};
+use crate::conversation;
+use crate::message::{Message, XmppMessage};
+use crate::plugins::conversation::ConversationPlugin;
+use crate::plugins::ui::UIPlugin;
+
+/// Maximum number of chars of the body shown in a notification
+const SNIPPET_LEN: usize = 100;
+
+/// Quote a string in the GVariant text format expected by gdbus
+fn gvariant_string(string: &str) -> String {
+    format!("'{}'", string.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn snippet(body: &str) -> String {
+    let mut snippet: String = body.chars().take(SNIPPET_LEN).collect();
+    if body.chars().nth(SNIPPET_LEN).is_some() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// Kind of alert, each ringing its own bell
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alert {
+    Message,
+    Mention,
+    Status,
+}
+
+/// Notify direct messages and mentions in channels, when the conversation isn't displayed or
+/// the terminal isn't focused.
+pub struct NotificationsPlugin {
+    desktop: bool,
+    bells: Bells,
+    levels: HashMap<String, NotificationLevel>,
+    // Levels set at runtime, taking precedence over the config file
+    overrides: HashMap<String, NotificationLevel>,
+    keywords: Vec<String>,
+    quiet_hours: Option<QuietHours>,
+}
+
+impl NotificationsPlugin {
+    /// Notification level of a conversation, `channel` tells the kind of conversation and
+    /// `setting` the level set with /set, which takes precedence over the config file
+    pub fn level(&self, conversation: &str, channel: bool, setting: Option<NotificationLevel>) -> NotificationLevel {
+        match self.overrides.get(conversation).or(setting.as_ref()).or_else(|| self.levels.get(conversation)) {
+            Some(level) => *level,
+            None if channel => NotificationLevel::Mentions,
+            None => NotificationLevel::All,
+        }
+    }
+
+    /// Override the notification level of a conversation, or go back to the configured one
+    pub fn set_level(&mut self, conversation: &str, level: Option<NotificationLevel>) {
+        match level {
+            Some(level) => self.overrides.insert(conversation.to_string(), level),
+            None => self.overrides.remove(conversation),
+        };
+    }
+
+    /// Kind of alert a message should be notified with, if any
+    fn should_notify(&self, conversation: &str, channel: bool, setting: Option<NotificationLevel>, mention: bool, body: &str, now: NaiveTime) -> Option<Alert> {
+        if let Some(quiet_hours) = &self.quiet_hours {
+            if quiet_hours.contains(now) {
+                return None;
+            }
+        }
+
+        let body = body.to_lowercase();
+        let mention = mention || self.keywords.iter().any(|keyword| body.contains(keyword.as_str()));
+        let alert = match channel && mention {
+            true => Alert::Mention,
+            false => Alert::Message,
+        };
+        match self.level(conversation, channel, setting) {
+            NotificationLevel::All => Some(alert),
+            NotificationLevel::Mentions if mention => Some(alert),
+            NotificationLevel::Mentions | NotificationLevel::None => None,
+        }
+    }
+
+    fn bell(&self, alert: Alert) -> Bell {
+        match alert {
+            Alert::Message => self.bells.message,
+            Alert::Mention => self.bells.mention,
+            Alert::Status => self.bells.status,
+        }
+    }
+
+    /// Send a freedesktop notification over DBus, returns false when it cannot be sent
+    fn desktop_notify(&self, summary: &str, body: &str) -> bool {
+        if env::var_os("DBUS_SESSION_BUS_ADDRESS").is_none() {
+            return false;
+        }
+
+        let child = Command::new("gdbus")
+            .args(&["call", "--session",
+                "--dest", "org.freedesktop.Notifications",
+                "--object-path", "/org/freedesktop/Notifications",
+                "--method", "org.freedesktop.Notifications.Notify"])
+            .arg(gvariant_string("aparté"))
+            .arg("0")
+            .arg(gvariant_string(""))
+            .arg(gvariant_string(summary))
+            .arg(gvariant_string(body))
+            .arg("[]")
+            .arg("{}")
+            .arg("-1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+
+        match child {
+            Ok(mut child) => {
+                // Reap the child without blocking the event loop
+                thread::spawn(move || child.wait());
+                true
+            },
+            Err(err) => {
+                warn!("Cannot send desktop notification: {}", err);
+                false
+            },
+        }
+    }
+
+    fn notify(&self, aparte: Rc<Aparte>, alert: Alert, conversation: &str, summary: &str, body: &str) {
+        {
+            let ui = aparte.get_plugin::<UIPlugin>().unwrap();
+            if ui.is_focused() && ui.current_window() == Some(conversation) {
+                return;
+            }
+        }
+
+        self.alert(aparte, alert, summary, &snippet(body));
+    }
+
+    /// Notify something, even unrelated to a conversation as a connection being lost, ringing the
+    /// bell of its kind when no desktop notification can be sent
+    pub fn alert(&self, aparte: Rc<Aparte>, alert: Alert, summary: &str, body: &str) {
+        if self.desktop && self.desktop_notify(summary, body) {
+            return;
+        }
+
+        let mut ui = aparte.get_plugin_mut::<UIPlugin>().unwrap();
+        match self.bell(alert) {
+            Bell::Audible => ui.bell(),
+            Bell::Visual => ui.visual_bell(Rc::clone(&aparte)),
+            Bell::None => {},
+        }
+    }
+}
+
+impl Plugin for NotificationsPlugin {
+    fn new() -> NotificationsPlugin {
+        Self {
+            desktop: true,
+            bells: Bells::default(),
+            levels: HashMap::new(),
+            overrides: HashMap::new(),
+            keywords: Vec::new(),
+            quiet_hours: None,
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        let config = &aparte.config.notifications;
+        self.desktop = config.desktop;
+        self.bells = match config.bell {
+            true => config.bells,
+            false => Bells { message: Bell::None, mention: Bell::None, status: Bell::None },
+        };
+        self.levels = config.conversations.clone();
+        self.keywords = config.keywords.iter().map(|keyword| keyword.to_lowercase()).collect();
+        self.quiet_hours = config.quiet_hours.clone();
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Message(Message::Incoming(XmppMessage::Chat(message))) => {
+                let conversation = message.from.to_string();
+                let setting = aparte.settings.borrow().get(&message.from, "notifications");
+                if let Some(alert) = self.should_notify(&conversation, false, setting, true, &message.body, Local::now().time()) {
+                    self.notify(aparte, alert, &conversation, &conversation, &message.body);
+                }
+            },
+            Event::Message(incoming @ Message::Incoming(XmppMessage::Groupchat(message))) => {
+                let nick = match aparte.get_plugin::<ConversationPlugin>().unwrap().get(&message.from) {
+                    Some(conversation::Conversation::Channel(channel)) => channel.nick.clone(),
+                    _ => return,
+                };
+
+                if let Jid::Full(from) = &*message.from_full {
+                    if from.resource == nick {
+                        return;
+                    }
+
+                    let conversation = message.from.to_string();
+                    let mention = incoming.mentions(&nick);
+                    let setting = aparte.settings.borrow().get(&message.from, "notifications");
+                    if let Some(alert) = self.should_notify(&conversation, true, setting, mention, &message.body, Local::now().time()) {
+                        let summary = format!("{} in {}", from.resource, conversation);
+                        self.notify(aparte, alert, &conversation, &summary, &message.body);
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for NotificationsPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Notifications")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gvariant_string_escaping() {
+        assert_eq!(gvariant_string("it's a \\ test"), r#"'it\'s a \\ test'"#);
+    }
+
+    #[test]
+    fn test_notification_rules() {
+        let mut plugin = NotificationsPlugin::new();
+        plugin.levels.insert("busy@conference.tld".to_string(), NotificationLevel::Mentions);
+        plugin.levels.insert("quiet@conference.tld".to_string(), NotificationLevel::All);
+        plugin.keywords = vec!["release".to_string()];
+        plugin.quiet_hours = Some("22:00-08:00".parse().unwrap());
+        let noon = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        let night = NaiveTime::from_hms_opt(23, 0, 0).unwrap();
+
+        assert_eq!(plugin.should_notify("contact@server.tld", false, None, true, "Hello", noon), Some(Alert::Message));
+        assert_eq!(plugin.should_notify("contact@server.tld", false, None, true, "Hello", night), None);
+
+        assert_eq!(plugin.should_notify("busy@conference.tld", true, None, false, "Hello", noon), None);
+        assert_eq!(plugin.should_notify("busy@conference.tld", true, None, true, "Hello nick", noon), Some(Alert::Mention));
+        assert_eq!(plugin.should_notify("busy@conference.tld", true, None, false, "New Release!", noon), Some(Alert::Mention));
+        assert_eq!(plugin.should_notify("quiet@conference.tld", true, None, false, "Hello", noon), Some(Alert::Message));
+        assert_eq!(plugin.should_notify("quiet@conference.tld", true, None, true, "Hello nick", noon), Some(Alert::Mention));
+
+        plugin.set_level("quiet@conference.tld", Some(NotificationLevel::None));
+        assert_eq!(plugin.should_notify("quiet@conference.tld", true, None, true, "Hello nick", noon), None);
+        plugin.set_level("quiet@conference.tld", None);
+        assert_eq!(plugin.should_notify("quiet@conference.tld", true, None, false, "Hello", noon), Some(Alert::Message));
+        assert_eq!(plugin.should_notify("quiet@conference.tld", true, Some(NotificationLevel::Mentions), false, "Hello", noon), None);
+
+        plugin.bells.mention = Bell::Visual;
+        assert_eq!(plugin.bell(Alert::Mention), Bell::Visual);
+        assert_eq!(plugin.bell(Alert::Message), Bell::Audible);
+    }
+
+    #[test]
+    fn test_snippet_is_truncated() {
+        assert_eq!(snippet("short"), "short");
+        let long = "é".repeat(SNIPPET_LEN + 1);
+        assert_eq!(snippet(&long).chars().count(), SNIPPET_LEN + 1);
+        assert!(snippet(&long).ends_with('…'));
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+use xmpp_parsers::{BareJid, Element};
+
+use crate::core::{Plugin, Aparte, Event};
+use crate::message::{Message, XmppMessage};
+use crate::plugins::ui::UIPlugin;
+
+/// Conversation an outgoing message is sent to
+fn recipient(message: &Message) -> Option<&BareJid> {
+    match message {
+        Message::Outgoing(XmppMessage::Chat(message)) => Some(&message.to),
+        Message::Outgoing(XmppMessage::Groupchat(message)) => Some(&message.to),
+        _ => None,
+    }
+}
+
+/// Keep messages written while disconnected, to send them in order once connected again
+pub struct OutboxPlugin {
+    queue: VecDeque<Message>,
+}
+
+impl OutboxPlugin {
+    /// Queue a message until the connection is back
+    pub fn push(&mut self, mut message: Message) -> Message {
+        message.set_pending(true);
+        self.queue.push_back(message.clone());
+        message
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Take every pending message, in the order they were written
+    fn take(&mut self) -> Vec<Message> {
+        self.queue.drain(..).collect()
+    }
+
+    /// Drop pending messages of a conversation, or of every conversation
+    pub fn cancel(&mut self, conversation: Option<&BareJid>) -> Vec<Message> {
+        let (cancelled, kept) = self.queue.drain(..).partition(|message| match conversation {
+            Some(conversation) => recipient(message) == Some(conversation),
+            None => true,
+        });
+        self.queue = kept;
+        cancelled.into_iter().collect()
+    }
+
+    /// Send every pending message. Must be called while online.
+    pub fn flush(aparte: Rc<Aparte>) {
+        let messages = aparte.get_plugin_mut::<OutboxPlugin>().unwrap().take();
+        OutboxPlugin::send(aparte, messages);
+    }
+
+    fn send(aparte: Rc<Aparte>, messages: Vec<Message>) {
+        for mut message in messages {
+            message.set_pending(false);
+            {
+                let mut ui = aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                ui.replace_message(Rc::clone(&aparte), &message);
+            }
+            if let Ok(element) = Element::try_from(message) {
+                aparte.send(element);
+            }
+        }
+    }
+}
+
+impl Plugin for OutboxPlugin {
+    fn new() -> OutboxPlugin {
+        Self {
+            queue: VecDeque::new(),
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Connected(_jid) => {
+                let messages = self.take();
+                if !messages.is_empty() {
+                    Rc::clone(&aparte).log(format!("Sending {} pending messages", messages.len()));
+                    OutboxPlugin::send(aparte, messages);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for OutboxPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Offline messages queue")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use std::str::FromStr;
+    use xmpp_parsers::Jid;
+
+    #[test]
+    fn test_queue_order_and_cancel() {
+        let us = Jid::from_str("me@server.tld/aparte").unwrap();
+        let alice = Jid::from_str("alice@server.tld").unwrap();
+        let bob = Jid::from_str("bob@server.tld").unwrap();
+
+        let mut outbox = OutboxPlugin::new();
+        let first = outbox.push(Message::outgoing_chat("1", Utc::now(), &us, &alice, "first"));
+        outbox.push(Message::outgoing_chat("2", Utc::now(), &us, &bob, "second"));
+        outbox.push(Message::outgoing_chat("3", Utc::now(), &us, &alice, "third"));
+        match first {
+            Message::Outgoing(XmppMessage::Chat(message)) => assert!(message.pending),
+            _ => unreachable!(),
+        }
+
+        let cancelled = outbox.cancel(Some(&BareJid::from_str("bob@server.tld").unwrap()));
+        assert_eq!(cancelled.iter().map(Message::id).collect::<Vec<_>>(), vec!["2"]);
+
+        assert_eq!(outbox.take().iter().map(Message::id).collect::<Vec<_>>(), vec!["1", "3"]);
+        assert_eq!(outbox.len(), 0);
+    }
+}
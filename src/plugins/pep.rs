@@ -0,0 +1,359 @@
+use futures::sync::mpsc;
+use futures::{Future, Stream};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::thread;
+use tokio::runtime::current_thread::TaskExecutor;
+use uuid::Uuid;
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::{ns, BareJid, Element, FullJid, Jid};
+
+use crate::config;
+use crate::contact::Published;
+use crate::core::{Plugin, Aparte, Event, IqError};
+use crate::plugins::disco;
+
+pub const NS_ACTIVITY: &str = "http://jabber.org/protocol/activity";
+
+/// Moods of XEP-0107
+pub const MOODS: &[&str] = &["afraid", "amazed", "amorous", "angry", "annoyed", "anxious", "aroused",
+    "ashamed", "bored", "brave", "calm", "cautious", "cold", "confident", "confused", "contemplative",
+    "contented", "cranky", "crazy", "creative", "curious", "dejected", "depressed", "disappointed",
+    "disgusted", "dismayed", "distracted", "embarrassed", "envious", "excited", "flirtatious",
+    "frustrated", "grateful", "grieving", "grumpy", "guilty", "happy", "hopeful", "hot", "humbled",
+    "humiliated", "hungry", "hurt", "impressed", "in_awe", "in_love", "indignant", "interested",
+    "intoxicated", "invincible", "jealous", "lonely", "lost", "lucky", "mean", "moody", "nervous",
+    "neutral", "offended", "outraged", "playful", "proud", "relaxed", "relieved", "remorseful",
+    "restless", "sad", "sarcastic", "satisfied", "serious", "shocked", "shy", "sick", "sleepy",
+    "spontaneous", "stressed", "strong", "surprised", "thankful", "thirsty", "tired", "undefined",
+    "weak", "worried"];
+
+/// General activities of XEP-0108, each having specific ones
+pub const ACTIVITIES: &[&str] = &["doing_chores", "drinking", "eating", "exercising", "grooming",
+    "having_appointment", "inactive", "relaxing", "talking", "traveling", "undefined", "working"];
+
+/// What a tune, mood or activity is, as shown to the user
+fn words(name: &str) -> String {
+    name.replace('_', " ")
+}
+
+fn with_text(shown: String, element: &Element, ns: &str) -> String {
+    match element.get_child("text", ns).map(|text| text.text()) {
+        Some(text) if !text.is_empty() => format!("{}: {}", shown, text),
+        _ => shown,
+    }
+}
+
+/// Tune being listened to, none if stopped
+fn parse_tune(tune: &Element) -> Option<String> {
+    let field = |name| tune.get_child(name, ns::TUNE).map(|field| field.text()).filter(|text| !text.is_empty());
+    match (field("artist"), field("title")) {
+        (Some(artist), Some(title)) => Some(format!("{} - {}", artist, title)),
+        (None, Some(title)) => Some(title),
+        (artist, None) => artist.or_else(|| field("source")),
+    }
+}
+
+fn parse_mood(mood: &Element) -> Option<String> {
+    let name = mood.children().find(|child| child.ns().as_deref() == Some(ns::MOOD) && child.name() != "text")?.name();
+    Some(with_text(words(name), mood, ns::MOOD))
+}
+
+fn parse_activity(activity: &Element) -> Option<String> {
+    let general = activity.children().find(|child| child.ns().as_deref() == Some(NS_ACTIVITY) && child.name() != "text")?;
+    let shown = match general.children().next() {
+        Some(specific) => format!("{}, {}", words(general.name()), words(specific.name())),
+        None => words(general.name()),
+    };
+    Some(with_text(shown, activity, NS_ACTIVITY))
+}
+
+/// Tune, mood or activity published in a PEP notification from `from`
+pub fn event(from: &Jid, payload: &Element) -> Option<Event> {
+    if !payload.is("event", ns::PUBSUB_EVENT) {
+        return None;
+    }
+    let items = payload.get_child("items", ns::PUBSUB_EVENT)?;
+    // Retracted or emptied once stopped
+    let item = items.get_child("item", ns::PUBSUB_EVENT).and_then(|item| item.children().next());
+    let published = match items.attr("node")? {
+        ns::TUNE => Published::Tune(item.and_then(parse_tune)),
+        ns::MOOD => Published::Mood(item.and_then(parse_mood)),
+        NS_ACTIVITY => Published::Activity(item.and_then(parse_activity)),
+        _ => return None,
+    };
+    Some(Event::UserInfo(BareJid::from(from.clone()), published))
+}
+
+/// Tune to publish, as "artist - title", stopping the last one when empty
+pub fn tune(artist: Option<&str>, title: Option<&str>) -> Element {
+    let mut tune = Element::builder("tune").ns(ns::TUNE).build();
+    for (name, value) in vec![("artist", artist), ("title", title)] {
+        if let Some(value) = value.filter(|value| !value.is_empty()) {
+            tune.append_child(Element::builder(name).ns(ns::TUNE).append(value).build());
+        }
+    }
+    tune
+}
+
+/// Mood to publish, none to stop publishing it
+pub fn mood(mood: Option<&str>, text: &str) -> Result<Element, String> {
+    let mut element = Element::builder("mood").ns(ns::MOOD).build();
+    if let Some(mood) = mood {
+        if !MOODS.contains(&mood) {
+            return Err(format!("Unknown mood {}, expected one of {}", mood, MOODS.join(", ")));
+        }
+        element.append_child(Element::builder(mood).ns(ns::MOOD).build());
+        if !text.is_empty() {
+            element.append_child(Element::builder("text").ns(ns::MOOD).append(text).build());
+        }
+    }
+    Ok(element)
+}
+
+/// Activity to publish, as general/specific, none to stop publishing it
+pub fn activity(activity: Option<&str>, text: &str) -> Result<Element, String> {
+    let mut element = Element::builder("activity").ns(NS_ACTIVITY).build();
+    if let Some(activity) = activity {
+        let mut parts = activity.splitn(2, '/');
+        let general = parts.next().unwrap_or("");
+        if !ACTIVITIES.contains(&general) {
+            return Err(format!("Unknown activity {}, expected one of {}", general, ACTIVITIES.join(", ")));
+        }
+        let mut general = Element::builder(general).ns(NS_ACTIVITY).build();
+        if let Some(specific) = parts.next() {
+            if specific.is_empty() || !specific.chars().all(|c| c.is_ascii_lowercase() || c == '_') {
+                return Err(format!("Invalid specific activity {}", specific));
+            }
+            general.append_child(Element::builder(specific).ns(NS_ACTIVITY).build());
+        }
+        element.append_child(general);
+        if !text.is_empty() {
+            element.append_child(Element::builder("text").ns(NS_ACTIVITY).append(text).build());
+        }
+    }
+    Ok(element)
+}
+
+/// Request publishing an item on one of our PEP nodes
+fn publish_iq(node: &str, payload: Element) -> Iq {
+    Iq {
+        from: None,
+        to: None,
+        id: Uuid::new_v4().to_hyphenated().to_string(),
+        payload: IqType::Set(Element::builder("pubsub").ns(ns::PUBSUB)
+            .append(Element::builder("publish").ns(ns::PUBSUB)
+                .attr("node", node)
+                .append(Element::builder("item").ns(ns::PUBSUB).append(payload).build())
+                .build())
+            .build()),
+    }
+}
+
+/// Tune played, from a line of `playerctl metadata --follow`, none unless playing
+fn playing(line: &str) -> Option<(String, String)> {
+    let mut fields = line.splitn(3, '\t');
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some("Playing"), Some(artist), Some(title)) if !title.is_empty() => Some((artist.to_string(), title.to_string())),
+        _ => None,
+    }
+}
+
+/// Tunes, moods and activities (XEP-0118, XEP-0107 and XEP-0108) of contacts, received through
+/// PEP notifications we subscribe to with the +notify features, and ours published with /tune,
+/// /mood and /activity or from the media players
+pub struct PepPlugin {
+    config: config::Pep,
+    /// Tune the media players play
+    playing: Option<(String, String)>,
+    /// Tune last published from the media players on each account
+    published: HashMap<FullJid, Option<(String, String)>>,
+    watching: bool,
+}
+
+impl PepPlugin {
+    /// Publish an item on one of our PEP nodes
+    pub fn publish(aparte: Rc<Aparte>, node: &'static str, payload: Element) {
+        let published = Rc::clone(&aparte).send_iq(publish_iq(node, payload)).map(|_| ()).map_err(move |err| format!("Cannot publish on {}: {}", node, err));
+        aparte.spawn(published);
+    }
+
+    /// Publish on an account the tune of the media players, remembered once the server stored
+    /// it. Offline, it gets published when the account connects again.
+    fn publish_playing(aparte: Rc<Aparte>, account: &FullJid, playing: Option<(String, String)>) {
+        let (artist, title) = match &playing {
+            Some((artist, title)) => (Some(artist.as_str()), Some(title.as_str())),
+            None => (None, None),
+        };
+        let iq = publish_iq(ns::TUNE, tune(artist, title));
+        let account = account.clone();
+        let published = Rc::clone(&aparte).send_iq_on(&account, iq).then({
+            let aparte = Rc::clone(&aparte);
+            move |result| match result {
+                Ok(_) => {
+                    aparte.get_plugin_mut::<PepPlugin>().unwrap().published.insert(account, playing);
+                    Ok(())
+                },
+                Err(IqError::Disconnected) => Ok(()),
+                Err(err) => Err(format!("Cannot publish on {}: {}", ns::TUNE, err)),
+            }
+        });
+        aparte.spawn(published);
+    }
+
+    /// Publish what the media players play, as told by playerctl on every change
+    fn watch(aparte: Rc<Aparte>) {
+        let child = Command::new("playerctl")
+            .args(&["--follow", "metadata", "--format", "{{status}}\t{{artist}}\t{{title}}"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                aparte.log(format!("Cannot watch media players with playerctl: {}", err));
+                return;
+            },
+        };
+
+        let (sender, lines) = mpsc::unbounded();
+        let stdout = child.stdout.take().unwrap();
+        // Read without blocking the event loop, until aparté stops listening
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line.map(|line| sender.unbounded_send(line)) {
+                    Ok(Ok(())) => {},
+                    _ => break,
+                }
+            }
+            let _ = child.kill();
+            child.wait()
+        });
+
+        let watch = lines.for_each(move |line| {
+            let playing = playing(&line);
+            let accounts: Vec<FullJid> = {
+                let mut plugin = aparte.get_plugin_mut::<PepPlugin>().unwrap();
+                plugin.playing = playing.clone();
+                aparte.connections().into_iter()
+                    .filter(|account| plugin.published.get(account).unwrap_or(&None) != &playing)
+                    .collect()
+            };
+            for account in accounts {
+                PepPlugin::publish_playing(Rc::clone(&aparte), &account, playing.clone());
+            }
+            Ok(())
+        });
+        if let Err(err) = TaskExecutor::current().spawn_local(Box::new(watch)) {
+            warn!("Cannot watch media players: {:?}", err);
+        }
+    }
+}
+
+impl Plugin for PepPlugin {
+    fn new() -> PepPlugin {
+        Self {
+            config: config::Pep::default(),
+            playing: None,
+            published: HashMap::new(),
+            watching: false,
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        self.config = aparte.config.pep.clone();
+        let mut disco = aparte.get_plugin_mut::<disco::Disco>().unwrap();
+        disco.add_feature("http://jabber.org/protocol/tune+notify")?;
+        disco.add_feature("http://jabber.org/protocol/mood+notify")?;
+        disco.add_feature("http://jabber.org/protocol/activity+notify")
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            // Plugins are initialized before the runtime is started
+            Event::Connected(_) if self.config.mpris && !self.watching => {
+                self.watching = true;
+                PepPlugin::watch(aparte);
+            },
+            Event::Connected(account) if self.published.get(account).unwrap_or(&None) != &self.playing => {
+                PepPlugin::publish_playing(aparte, account, self.playing.clone());
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for PepPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tune, mood and activity")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use crate::fakeserver::{self, FakeServer};
+
+    fn published(payload: &str) -> Option<Published> {
+        let from = Jid::from_str("stpeter@jabber.org/home").unwrap();
+        match event(&from, &payload.parse().unwrap()) {
+            Some(Event::UserInfo(jid, published)) => {
+                assert_eq!(jid, BareJid::from_str("stpeter@jabber.org").unwrap());
+                Some(published)
+            },
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        assert_eq!(published(r#"<event xmlns="http://jabber.org/protocol/pubsub#event"><items node="http://jabber.org/protocol/tune"><item id="bffe6584-0f9c-11dc-84ba-001143d5d5db"><tune xmlns="http://jabber.org/protocol/tune"><artist>Yes</artist><length>686</length><source>Yessongs</source><title>Heart of the Sunrise</title></tune></item></items></event>"#),
+            Some(Published::Tune(Some(String::from("Yes - Heart of the Sunrise")))));
+        assert_eq!(published(r#"<event xmlns="http://jabber.org/protocol/pubsub#event"><items node="http://jabber.org/protocol/tune"><item><tune xmlns="http://jabber.org/protocol/tune"/></item></items></event>"#),
+            Some(Published::Tune(None)));
+        assert_eq!(published(r#"<event xmlns="http://jabber.org/protocol/pubsub#event"><items node="http://jabber.org/protocol/mood"><item><mood xmlns="http://jabber.org/protocol/mood"><in_love/><text>Yay!</text></mood></item></items></event>"#),
+            Some(Published::Mood(Some(String::from("in love: Yay!")))));
+        assert_eq!(published(r#"<event xmlns="http://jabber.org/protocol/pubsub#event"><items node="http://jabber.org/protocol/activity"><item><activity xmlns="http://jabber.org/protocol/activity"><relaxing><partying/></relaxing></activity></item></items></event>"#),
+            Some(Published::Activity(Some(String::from("relaxing, partying")))));
+        assert_eq!(published(r#"<event xmlns="http://jabber.org/protocol/pubsub#event"><items node="http://jabber.org/protocol/activity"><retract id="current"/></items></event>"#),
+            Some(Published::Activity(None)));
+        assert_eq!(published(r#"<event xmlns="http://jabber.org/protocol/pubsub#event"><items node="urn:xmpp:avatar:metadata"/></event>"#), None);
+    }
+
+    #[test]
+    fn test_publish() {
+        assert_eq!(parse_tune(&tune(Some("Yes"), Some("Roundabout"))), Some(String::from("Yes - Roundabout")));
+        assert_eq!(parse_tune(&tune(None, None)), None);
+        assert_eq!(parse_mood(&mood(Some("happy"), "Sunny day").unwrap()), Some(String::from("happy: Sunny day")));
+        assert!(mood(Some("hangry"), "").is_err());
+        assert_eq!(parse_mood(&mood(None, "").unwrap()), None);
+        assert_eq!(parse_activity(&activity(Some("working/coding"), "").unwrap()), Some(String::from("working, coding")));
+        assert!(activity(Some("napping"), "").is_err());
+        assert!(activity(Some("working/<coding>"), "").is_err());
+
+        assert_eq!(playing("Playing\tYes\tRoundabout"), Some((String::from("Yes"), String::from("Roundabout"))));
+        assert_eq!(playing("Paused\tYes\tRoundabout"), None);
+        assert_eq!(playing(""), None);
+    }
+
+    #[test]
+    fn test_publish_playing() {
+        let aparte = fakeserver::aparte(|aparte| aparte.add_plugin(PepPlugin::new()));
+        let account = FullJid::from_str("me@server.tld/aparte").unwrap();
+        // Changed while offline
+        aparte.get_plugin_mut::<PepPlugin>().unwrap().playing = Some((String::from("Yes"), String::from("Roundabout")));
+        let server = FakeServer::new()
+            .expect("<iq xmlns='jabber:client' type='set' id='tune'><pubsub xmlns='http://jabber.org/protocol/pubsub'><publish node='http://jabber.org/protocol/tune'><item><tune xmlns='http://jabber.org/protocol/tune'><artist>Yes</artist><title>Roundabout</title></tune></item></publish></pubsub></iq>")
+            .send("<iq xmlns='jabber:client' type='result' id='tune'/>");
+        let server = fakeserver::run(&aparte, &account, server).unwrap();
+        assert!(server.is_done());
+
+        let plugin = aparte.get_plugin::<PepPlugin>().unwrap();
+        assert_eq!(plugin.published.get(&account), Some(&plugin.playing));
+    }
+}
@@ -0,0 +1,301 @@
+use futures::{future, Future};
+use futures::sync::oneshot;
+use hyper::{Body, Request};
+use std::env;
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::thread;
+use tokio::runtime::current_thread::TaskExecutor;
+
+use crate::core::{Plugin, Aparte, Event};
+use crate::https;
+use crate::message::{self, Message, XmppMessage};
+use crate::plugins::bandwidth;
+use crate::plugins::ui::UIPlugin;
+
+pub const NS_OOB: &str = "jabber:x:oob";
+
+const IMAGE_EXTENSIONS: &[&str] = &[".png", ".jpg", ".jpeg", ".gif", ".webp"];
+
+/// Height of a thumbnail, in terminal rows
+const PREVIEW_ROWS: usize = 8;
+/// Maximum width of a thumbnail, in terminal columns
+const PREVIEW_COLUMNS: usize = 60;
+
+/// Diacritics encoding the row, then the column, of a kitty Unicode placeholder
+const KITTY_DIACRITICS: &[char] = &[
+    '\u{0305}', '\u{030D}', '\u{030E}', '\u{0310}', '\u{0312}', '\u{033D}', '\u{033E}', '\u{033F}',
+    '\u{0346}', '\u{034A}', '\u{034B}', '\u{034C}',
+];
+const KITTY_PLACEHOLDER: char = '\u{10EEEE}';
+
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+fn is_image_url(url: &str) -> bool {
+    let path = url.split(|c| c == '?' || c == '#').next().unwrap_or(url).to_lowercase();
+    IMAGE_EXTENSIONS.iter().any(|extension| path.ends_with(extension))
+}
+
+/// Whether the terminal implements the kitty graphics protocol with Unicode placeholders
+fn kitty_supported() -> bool {
+    env::var_os("KITTY_WINDOW_ID").is_some() || env::var("TERM").map(|term| term == "xterm-kitty").unwrap_or(false)
+}
+
+/// ImageMagick coder of an image, recognized by its magic bytes rather than trusting its URL or
+/// letting ImageMagick guess, which would decode any of the formats it knows
+fn image_format(image: &[u8]) -> Option<&'static str> {
+    if image.starts_with(PNG_MAGIC) {
+        Some("png")
+    } else if image.starts_with(b"\xff\xd8\xff") {
+        Some("jpeg")
+    } else if image.starts_with(b"GIF87a") || image.starts_with(b"GIF89a") {
+        Some("gif")
+    } else if image.len() >= 12 && image.starts_with(b"RIFF") && &image[8..12] == b"WEBP" {
+        Some("webp")
+    } else {
+        None
+    }
+}
+
+/// Image shared in a message: the file it shares out of band, or its body when only made of the
+/// URL of an image, as clients share uploaded files. Links in the middle of a text aren't
+/// downloaded, nor files shared without encryption.
+fn shared_image(body: &str, oob: Option<&str>) -> Option<String> {
+    if let Some(url) = oob.filter(|url| url.starts_with("https://")) {
+        return Some(url.to_string());
+    }
+    match message::urls(body).as_slice() {
+        [(_, url)] if *url == body.trim() && url.starts_with("https://") && is_image_url(url) => Some(url.to_string()),
+        _ => None,
+    }
+}
+
+/// Width and height of a PNG image, read from its header
+fn png_size(png: &[u8]) -> Option<(usize, usize)> {
+    if png.len() < 24 || !png.starts_with(PNG_MAGIC) {
+        return None;
+    }
+    let read = |offset: usize| (png[offset] as usize) << 24 | (png[offset + 1] as usize) << 16 | (png[offset + 2] as usize) << 8 | png[offset + 3] as usize;
+    Some((read(16), read(20)))
+}
+
+/// Columns and rows of a thumbnail keeping the aspect ratio of the image, terminal cells being
+/// about twice as high as wide
+fn thumbnail_size(width: usize, height: usize) -> (usize, usize) {
+    let width = std::cmp::max(width, 1);
+    let height = std::cmp::max(height, 1);
+    let columns = (PREVIEW_ROWS * 2 * width + height - 1) / height;
+    if columns <= PREVIEW_COLUMNS {
+        (std::cmp::max(columns, 1), PREVIEW_ROWS)
+    } else {
+        let rows = PREVIEW_COLUMNS * height / (2 * width);
+        (PREVIEW_COLUMNS, std::cmp::max(rows, 1))
+    }
+}
+
+/// Escape sequence transmitting a PNG image to the terminal, with a virtual placement to be
+/// displayed by Unicode placeholders
+fn kitty_transmit(id: u8, png: &[u8], columns: usize, rows: usize) -> String {
+    let data = base64::encode(png);
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(4096).collect();
+    let mut escape = String::new();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let more = if index + 1 < chunks.len() { 1 } else { 0 };
+        let chunk = std::str::from_utf8(chunk).unwrap();
+        if index == 0 {
+            escape.push_str(&format!("\x1b_Ga=T,U=1,f=100,q=2,i={},c={},r={},m={};{}\x1b\\", id, columns, rows, more, chunk));
+        } else {
+            escape.push_str(&format!("\x1b_Gm={};{}\x1b\\", more, chunk));
+        }
+    }
+    escape
+}
+
+/// Lines of Unicode placeholders displaying an image, its id being encoded in the foreground
+/// color
+fn kitty_placeholder(id: u8, columns: usize, rows: usize) -> String {
+    let mut lines = Vec::with_capacity(rows);
+    for row in 0..rows {
+        let mut line = format!("{}", termion::color::Fg(termion::color::AnsiValue(id)));
+        line.push(KITTY_PLACEHOLDER);
+        line.push(KITTY_DIACRITICS[row]);
+        line.push(KITTY_DIACRITICS[0]);
+        // Following columns are deduced from the previous placeholder
+        for _ in 1..columns {
+            line.push(KITTY_PLACEHOLDER);
+        }
+        line.push_str(&format!("{}", termion::color::Fg(termion::color::Reset)));
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+/// Download an image and turn it into a PNG, the only format the terminal decodes itself
+fn fetch(url: String, max_size: u64) -> Box<dyn Future<Item = Vec<u8>, Error = String>> {
+    let request = match Request::get(url.as_str()).body(Body::empty()) {
+        Ok(request) => request,
+        Err(err) => return Box::new(future::err(format!("Invalid URL {}: {}", url, err))),
+    };
+    Box::new(https::download(request, max_size as usize).map_err(|err| format!("Cannot preview image: {}", err)).and_then(move |image| {
+        let format = match image_format(&image) {
+            Some("png") => return future::Either::A(future::ok(image)),
+            Some(format) => format,
+            None => return future::Either::A(future::err(format!("{} is not an image", url))),
+        };
+
+        // Converting blocks, out of the event loop
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(convert(&url, format, &image));
+        });
+        future::Either::B(rx.map_err(|_| format!("Cannot convert image")).and_then(|result| result))
+    }))
+}
+
+/// Turn an image of a format ImageMagick knows into a PNG thumbnail
+fn convert(url: &str, format: &str, image: &[u8]) -> Result<Vec<u8>, String> {
+    let mut convert = Command::new("convert")
+        .arg(format!("{}:-", format))
+        .args(&["-thumbnail", "640x480>", "png:-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| format!("Cannot convert {}: {}", url, err))?;
+    convert.stdin.take().unwrap().write_all(image).map_err(|err| format!("Cannot convert {}: {}", url, err))?;
+    let output = convert.wait_with_output().map_err(|err| format!("Cannot convert {}: {}", url, err))?;
+    match output.status.success() && output.stdout.starts_with(PNG_MAGIC) {
+        true => Ok(output.stdout),
+        false => Err(format!("Cannot convert {}", url)),
+    }
+}
+
+/// Display thumbnails of images linked in messages, on terminals implementing the kitty graphics
+/// protocol. Other terminals only get the link.
+pub struct PreviewPlugin {
+    enabled: bool,
+    max_size: u64,
+    // Id of the last image sent to the terminal
+    last_id: u8,
+}
+
+impl PreviewPlugin {
+    fn next_id(&mut self) -> u8 {
+        // Ids are encoded in 256 colors, 0 isn't a valid id
+        self.last_id = if self.last_id == 255 { 1 } else { self.last_id + 1 };
+        self.last_id
+    }
+
+    fn preview(&mut self, aparte: Rc<Aparte>, conversation: &str, message: &Message) {
+        if let Some(url) = shared_image(message.body(), message.oob()) {
+            let id = self.next_id();
+            let aparte = Rc::clone(&aparte);
+            let conversation = conversation.to_string();
+            let display = fetch(url, self.max_size).then(move |result| {
+                match result {
+                    Ok(png) => {
+                        if let Some((width, height)) = png_size(&png) {
+                            let (columns, rows) = thumbnail_size(width, height);
+                            let mut ui = aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                            ui.preview(&conversation, &kitty_transmit(id, &png, columns, rows), kitty_placeholder(id, columns, rows));
+                        }
+                    },
+                    Err(err) => warn!("{}", err),
+                }
+                Ok(())
+            });
+
+            if let Err(err) = TaskExecutor::current().spawn_local(Box::new(display)) {
+                warn!("Cannot preview image: {:?}", err);
+            }
+        }
+    }
+}
+
+impl Plugin for PreviewPlugin {
+    fn new() -> PreviewPlugin {
+        Self {
+            enabled: false,
+            max_size: 0,
+            last_id: 0,
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        let config = &aparte.config.previews;
+        self.enabled = config.enabled && kitty_supported();
+        self.max_size = config.max_size;
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        if !self.enabled || bandwidth::is_low(&aparte) {
+            return;
+        }
+
+        match event {
+            Event::Message(message @ Message::Incoming(XmppMessage::Chat(chat))) => self.preview(aparte, &chat.from.to_string(), message),
+            Event::Message(message @ Message::Incoming(XmppMessage::Groupchat(groupchat))) => self.preview(aparte, &groupchat.from.to_string(), message),
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for PreviewPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Image previews")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_urls() {
+        assert!(is_image_url("https://upload.server.tld/abc/Photo.JPG"));
+        assert!(is_image_url("https://upload.server.tld/abc/image.png?size=big"));
+        assert!(!is_image_url("https://server.tld/page.html"));
+    }
+
+    #[test]
+    fn test_shared_image() {
+        let url = "https://upload.server.tld/abc/Photo.JPG";
+        assert_eq!(shared_image(url, None), Some(url.to_string()));
+        assert_eq!(shared_image(url, Some(url)), Some(url.to_string()));
+        assert_eq!(shared_image("https://upload.server.tld/abc/file", Some("https://upload.server.tld/abc/file")), Some(String::from("https://upload.server.tld/abc/file")));
+        // Only files shared on their own
+        assert_eq!(shared_image(&format!("Look at {}", url), None), None);
+        assert_eq!(shared_image("https://server.tld/page.html", None), None);
+        assert_eq!(shared_image("Hi", Some("file:///etc/passwd")), None);
+        assert_eq!(shared_image("http://server.tld/photo.png", Some("http://server.tld/photo.png")), None);
+    }
+
+    #[test]
+    fn test_image_format() {
+        assert_eq!(image_format(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("png"));
+        assert_eq!(image_format(b"\xff\xd8\xff\xe0\0\x10JFIF"), Some("jpeg"));
+        assert_eq!(image_format(b"GIF89a\x01\0\x01\0"), Some("gif"));
+        assert_eq!(image_format(b"RIFF\x24\0\0\0WEBPVP8 "), Some("webp"));
+        assert_eq!(image_format(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"), None);
+        assert_eq!(image_format(b"push graphic-context"), None);
+    }
+
+    #[test]
+    fn test_thumbnail_size() {
+        assert_eq!(thumbnail_size(400, 400), (16, PREVIEW_ROWS));
+        assert_eq!(thumbnail_size(4000, 100), (PREVIEW_COLUMNS, 1));
+        assert_eq!(thumbnail_size(1, 1000), (1, PREVIEW_ROWS));
+    }
+
+    #[test]
+    fn test_kitty_placeholder() {
+        let placeholder = kitty_placeholder(3, 4, 2);
+        let lines: Vec<&str> = placeholder.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].matches(KITTY_PLACEHOLDER).count(), 4);
+        assert!(lines[1].contains(&format!("{}{}{}", KITTY_PLACEHOLDER, KITTY_DIACRITICS[1], KITTY_DIACRITICS[0])));
+    }
+}
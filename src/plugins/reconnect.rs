@@ -0,0 +1,271 @@
+use chrono::{DateTime, Local};
+use futures::Future;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::runtime::current_thread::TaskExecutor;
+use tokio::timer::Delay;
+use xmpp_parsers::{BareJid, FullJid, Jid};
+
+use crate::command::Command;
+use crate::config;
+use crate::core::{Plugin, Aparte, Event};
+use crate::i18n;
+use crate::plugins::notifications::{Alert, NotificationsPlugin};
+use crate::plugins::ui::UIPlugin;
+
+/// Delay before the first reconnection, doubled after each failure
+const FIRST_DELAY: u64 = 5;
+const MAX_DELAY: u64 = 300;
+
+/// Delay before a reconnection, after some consecutive failures
+fn backoff(failures: u32) -> Duration {
+    let delay = FIRST_DELAY.saturating_mul(1 << std::cmp::min(failures.saturating_sub(1), 16));
+    Duration::from_secs(std::cmp::min(delay, MAX_DELAY))
+}
+
+struct Account {
+    /// Command connecting the account again, with its password
+    connect: Command,
+    online: bool,
+    /// Reconnections only happen once the account has been online, not for a wrong password
+    was_online: bool,
+    failures: u32,
+    offline_since: Option<DateTime<Local>>,
+    next_attempt: Option<Instant>,
+    /// Bumped on each scheduled attempt, so that attempts made obsolete by /reconnect now are
+    /// dropped
+    attempt: u64,
+}
+
+/// Reconnect lost connections with an increasing delay, warning in the status bar after some
+/// consecutive failures
+pub struct ReconnectPlugin {
+    config: config::Reconnect,
+    accounts: HashMap<FullJid, Account>,
+    quitting: bool,
+}
+
+impl ReconnectPlugin {
+    /// Remember how to connect an account again
+    pub fn register(&mut self, jid: FullJid, connect: Command) {
+        match self.accounts.get_mut(&jid) {
+            Some(account) => account.connect = connect,
+            None => {
+                self.accounts.insert(jid, Account {
+                    connect: connect,
+                    online: false,
+                    was_online: false,
+                    failures: 0,
+                    offline_since: None,
+                    next_attempt: None,
+                    attempt: 0,
+                });
+            },
+        }
+    }
+
+    fn connected(&mut self, jid: &FullJid) -> bool {
+        match self.accounts.get_mut(jid) {
+            Some(account) => {
+                let alerted = account.failures >= self.config.alert_after;
+                account.online = true;
+                account.was_online = true;
+                account.failures = 0;
+                account.offline_since = None;
+                account.next_attempt = None;
+                account.attempt += 1;
+                alerted
+            },
+            None => false,
+        }
+    }
+
+    /// Count a lost connection or a failed attempt, returns when to try again
+    fn disconnected(&mut self, jid: &FullJid) -> Option<(u64, Duration)> {
+        if self.quitting || !self.config.enabled {
+            return None;
+        }
+
+        let account = self.accounts.get_mut(jid)?;
+        if !account.was_online {
+            return None;
+        }
+
+        // The first disconnection isn't a failure, the following ones are failed reconnections
+        if !account.online {
+            account.failures += 1;
+        }
+        account.online = false;
+        account.offline_since.get_or_insert_with(Local::now);
+
+        let delay = backoff(account.failures + 1);
+        account.next_attempt = Some(Instant::now() + delay);
+        account.attempt += 1;
+        Some((account.attempt, delay))
+    }
+
+    fn warning(&self) -> Option<String> {
+        self.accounts.iter()
+            .filter(|(_, account)| account.failures >= self.config.alert_after)
+            .map(|(jid, account)| {
+                let since = account.offline_since.map(|since| since.format(&i18n::short_time_format()).to_string()).unwrap_or_default();
+                i18n::trf("{} offline since {}, {} failed reconnections", &[&BareJid::from(Jid::Full(jid.clone())), &since, &account.failures])
+            })
+            .next()
+    }
+
+    fn schedule(aparte: Rc<Aparte>, jid: FullJid, attempt: u64, delay: Duration) {
+        let retry = Delay::new(Instant::now() + delay).then(move |_| {
+            let connect = {
+                let reconnect = aparte.get_plugin::<ReconnectPlugin>().unwrap();
+                match reconnect.accounts.get(&jid) {
+                    Some(account) if account.attempt == attempt && !account.online => Some(account.connect.clone()),
+                    _ => None,
+                }
+            };
+            if let Some(connect) = connect {
+                if let Err(err) = Rc::clone(&aparte).parse_command(connect) {
+                    aparte.log(err);
+                }
+            }
+            Ok(())
+        });
+
+        if let Err(err) = TaskExecutor::current().spawn_local(Box::new(retry)) {
+            warn!("Cannot schedule reconnection: {:?}", err);
+        }
+    }
+
+    /// Try to reconnect every offline account immediately
+    pub fn reconnect_now(aparte: Rc<Aparte>) -> Result<(), String> {
+        ReconnectPlugin::retry(aparte, |_| true)
+    }
+
+    /// Try to reconnect an offline account immediately, once what prevented it is fixed
+    pub fn reconnect_account(aparte: Rc<Aparte>, account: &BareJid) -> Result<(), String> {
+        ReconnectPlugin::retry(aparte, |jid| BareJid::from(Jid::Full(jid.clone())) == *account)
+    }
+
+    fn retry<F: Fn(&FullJid) -> bool>(aparte: Rc<Aparte>, filter: F) -> Result<(), String> {
+        let attempts: Vec<(FullJid, u64)> = {
+            let mut reconnect = aparte.get_plugin_mut::<ReconnectPlugin>().unwrap();
+            reconnect.accounts.iter_mut().filter(|(jid, account)| !account.online && filter(jid)).map(|(jid, account)| {
+                account.attempt += 1;
+                (jid.clone(), account.attempt)
+            }).collect()
+        };
+
+        if attempts.is_empty() {
+            return Err(format!("No connection to retry"));
+        }
+        for (jid, attempt) in attempts {
+            ReconnectPlugin::schedule(Rc::clone(&aparte), jid, attempt, Duration::from_secs(0));
+        }
+        Ok(())
+    }
+
+    /// State of the offline accounts
+    pub fn status(&self) -> Vec<String> {
+        let now = Instant::now();
+        self.accounts.iter().filter(|(_, account)| !account.online).map(|(jid, account)| {
+            match account.next_attempt {
+                Some(next) if next > now => format!("{}: {} failed reconnections, next attempt in {}s", jid, account.failures, (next - now).as_secs()),
+                Some(_) => format!("{}: {} failed reconnections, reconnecting", jid, account.failures),
+                None => format!("{}: offline", jid),
+            }
+        }).collect()
+    }
+}
+
+impl Plugin for ReconnectPlugin {
+    fn new() -> ReconnectPlugin {
+        Self {
+            config: config::Reconnect::default(),
+            accounts: HashMap::new(),
+            quitting: false,
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        self.config = aparte.config.reconnect.clone();
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Connected(jid) => {
+                if self.connected(jid) {
+                    let mut ui = aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                    ui.set_warning(self.warning());
+                }
+            },
+            Event::Disconnected(jid) => {
+                if let Some((attempt, delay)) = self.disconnected(jid) {
+                    Rc::clone(&aparte).log(format!("Reconnecting to {} in {}s", jid, delay.as_secs()));
+                    ReconnectPlugin::schedule(Rc::clone(&aparte), jid.clone(), attempt, delay);
+
+                    let failures = self.accounts[jid].failures;
+                    if failures >= self.config.alert_after {
+                        {
+                            let mut ui = aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                            ui.set_warning(self.warning());
+                        }
+                        // Alert once, the warning stays in the status bar
+                        if failures == self.config.alert_after && self.config.notify {
+                            let body = format!("{} failed reconnections, use /reconnect now to retry", failures);
+                            match aparte.get_plugin::<NotificationsPlugin>() {
+                                Some(notifications) => notifications.alert(Rc::clone(&aparte), Alert::Status, &format!("{} is offline", BareJid::from(Jid::Full(jid.clone()))), &body),
+                                None => aparte.get_plugin_mut::<UIPlugin>().unwrap().bell(),
+                            }
+                        }
+                    }
+                }
+            },
+            Event::Quit => self.quitting = true,
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for ReconnectPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Automatic reconnection")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(5));
+        assert_eq!(backoff(2), Duration::from_secs(10));
+        assert_eq!(backoff(4), Duration::from_secs(40));
+        assert_eq!(backoff(100), Duration::from_secs(MAX_DELAY));
+    }
+
+    #[test]
+    fn test_failures() {
+        let jid = FullJid::from_str("me@server.tld/aparte").unwrap();
+        let mut plugin = ReconnectPlugin::new();
+        plugin.register(jid.clone(), Command::new(vec![String::from("connect"), jid.to_string(), String::from("password")]));
+
+        // A connection which never succeeded isn't retried
+        assert!(plugin.disconnected(&jid).is_none());
+
+        plugin.connected(&jid);
+        assert_eq!(plugin.disconnected(&jid).unwrap().1, Duration::from_secs(5));
+        assert_eq!(plugin.disconnected(&jid).unwrap().1, Duration::from_secs(10));
+        assert_eq!(plugin.disconnected(&jid).unwrap().1, Duration::from_secs(20));
+        assert!(plugin.warning().is_none());
+        plugin.disconnected(&jid);
+        assert!(plugin.warning().unwrap().contains("3 failed reconnections"));
+
+        assert!(plugin.connected(&jid));
+        assert!(plugin.warning().is_none());
+    }
+}
@@ -0,0 +1,621 @@
+use futures::{Future, Sink, Stream};
+use futures::unsync::mpsc::UnboundedSender;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::rc::Rc;
+use tokio::net::TcpStream;
+use tokio_xmpp::{Packet, StartTlsClient};
+use tokio_xmpp::xmpp_stream::XMPPStream;
+use uuid::Uuid;
+use xmpp_parsers::{BareJid, Element, FullJid, Jid};
+use xmpp_parsers::data_forms::{DataForm, DataFormType, Field, FieldType};
+use xmpp_parsers::iq::{Iq, IqType};
+
+use crate::command::Command;
+use crate::core::{Plugin, Aparte, Event, IqError};
+use crate::plugins::mucadmin;
+use crate::plugins::reconnect::ReconnectPlugin;
+use crate::plugins::ui::UIPlugin;
+use crate::wizard;
+
+pub const NS_REGISTER: &str = "jabber:iq:register";
+const NS_BOB: &str = "urn:xmpp:bob";
+const NS_CLIENT: &str = "jabber:client";
+const NS_TLS: &str = "urn:ietf:params:xml:ns:xmpp-tls";
+
+/// Window displaying the registration form being filled
+pub const WINDOW: &str = "register";
+
+/// Form to fill to register an account on a server
+struct RegistrationForm {
+    form: DataForm,
+    /// Form made of the fields of the original protocol rather than a data form
+    legacy: bool,
+    instructions: Option<String>,
+    /// Files the media of the form (CAPTCHA images) were saved to, by content id
+    media: HashMap<String, String>,
+}
+
+/// Media of the form sent along with it, saved to temporary files to be viewed
+fn save_media(query: &Element) -> HashMap<String, String> {
+    let mut media = HashMap::new();
+    for data in query.children().filter(|child| child.is("data", NS_BOB)) {
+        let (cid, type_) = match (data.attr("cid"), data.attr("type")) {
+            (Some(cid), Some(type_)) => (cid, type_),
+            _ => continue,
+        };
+        let content = match base64::decode(&data.text().split_whitespace().collect::<String>()) {
+            Ok(content) => content,
+            Err(err) => {
+                warn!("Invalid media {}: {}", cid, err);
+                continue;
+            },
+        };
+        let extension = match type_ {
+            "image/png" => "png",
+            "image/jpeg" => "jpg",
+            "image/gif" => "gif",
+            "audio/ogg" => "ogg",
+            "audio/x-wav" => "wav",
+            _ => "bin",
+        };
+        let path = env::temp_dir().join(format!("aparte-{}.{}", Uuid::new_v4(), extension));
+        match fs::write(&path, content) {
+            Ok(()) => { media.insert(format!("cid:{}", cid), path.display().to_string()); },
+            Err(err) => warn!("Cannot save media {}: {}", cid, err),
+        }
+    }
+    media
+}
+
+impl RegistrationForm {
+    fn parse(query: &Element) -> Result<RegistrationForm, String> {
+        if query.get_child("registered", NS_REGISTER).is_some() {
+            return Err(format!("Already registered"));
+        }
+        let instructions = query.get_child("instructions", NS_REGISTER).map(Element::text);
+
+        let (form, legacy) = match mucadmin::parse_form(query) {
+            Ok(form) => (form, false),
+            Err(_) => {
+                let fields: Vec<Field> = query.children()
+                    .filter(|child| child.ns().as_deref() == Some(NS_REGISTER) && !["instructions", "registered", "remove"].contains(&child.name()))
+                    .map(|child| Field {
+                        var: child.name().to_string(),
+                        type_: match child.name() {
+                            "password" => FieldType::TextPrivate,
+                            _ => FieldType::TextSingle,
+                        },
+                        label: None,
+                        required: true,
+                        options: Vec::new(),
+                        values: Some(child.text()).filter(|text| !text.is_empty()).into_iter().collect(),
+                        media: Vec::new(),
+                    })
+                    .collect();
+                if fields.is_empty() {
+                    return Err(format!("No registration form"));
+                }
+                let form = DataForm {
+                    type_: DataFormType::Form,
+                    form_type: None,
+                    title: None,
+                    instructions: None,
+                    fields: fields,
+                };
+                (form, true)
+            },
+        };
+
+        Ok(RegistrationForm {
+            form: form,
+            legacy: legacy,
+            instructions: instructions,
+            media: save_media(query),
+        })
+    }
+
+    fn field(&self, field: &str) -> Result<usize, String> {
+        match field.parse::<usize>() {
+            Ok(index) if index >= 1 && index <= self.form.fields.len() => Ok(index - 1),
+            Ok(index) => Err(format!("No field {} in the registration form", index)),
+            Err(_) => self.form.fields.iter().position(|candidate| candidate.var == field).ok_or(format!("No field {} in the registration form", field)),
+        }
+    }
+
+    fn value(&self, var: &str) -> Option<&str> {
+        self.form.fields.iter().find(|field| field.var == var).and_then(|field| field.values.first()).map(String::as_str)
+    }
+
+    /// Lines displaying the form, each field being numbered and required fields marked
+    fn render(&self, server: &BareJid) -> Vec<String> {
+        let mut lines = vec![format!("Registration on {}", server)];
+        lines.extend(self.instructions.iter().cloned());
+        lines.extend(self.form.title.iter().cloned());
+        lines.extend(self.form.instructions.iter().cloned());
+        for (index, field) in self.form.fields.iter().enumerate() {
+            if let Some(mut line) = mucadmin::render_field(index + 1, field) {
+                if field.required {
+                    line = line.replacen("] ", "] *", 1);
+                }
+                lines.push(line);
+            }
+            for uri in field.media.iter().flat_map(|media| media.uris.iter()) {
+                match self.media.get(&uri.uri) {
+                    Some(path) => lines.push(format!("      {}: {}", uri.type_, path)),
+                    None => lines.push(format!("      {}: {}", uri.type_, uri.uri)),
+                }
+            }
+        }
+        lines.push(String::from("Fill fields with /register set <field> <value>, then /register submit or /register cancel"));
+        lines
+    }
+
+    fn check(&self) -> Result<(), String> {
+        match self.form.fields.iter().find(|field| field.required && field.values.is_empty()) {
+            Some(field) => Err(format!("Missing value for {}", field.label.as_deref().unwrap_or(&field.var))),
+            None => Ok(()),
+        }
+    }
+
+    /// Query registering the account with the values of the form
+    fn submit(self) -> Result<Element, String> {
+        self.check()?;
+
+        let mut query = Element::builder("query").ns(NS_REGISTER);
+        match self.legacy {
+            true => for field in self.form.fields {
+                query = query.append(Element::builder(field.var).ns(NS_REGISTER).append(field.values.join("")).build());
+            },
+            false => query = query.append(Element::from(mucadmin::submitted_form(self.form))),
+        }
+        Ok(query.build())
+    }
+}
+
+fn register_iq(to: &BareJid, payload: IqType) -> Iq {
+    Iq {
+        from: None,
+        to: Some(Jid::Bare(to.clone())),
+        id: Uuid::new_v4().to_hyphenated().to_string(),
+        payload: payload,
+    }
+}
+
+enum About {
+    /// Registration form of a server
+    Form(BareJid),
+    /// Registration of an account with its password
+    Register(BareJid, String),
+    /// Change of the password of a connected account
+    Password(FullJid, String),
+    /// Registration with a gateway through the connection of an account
+    Gateway(BareJid),
+    Unregister(BareJid),
+}
+
+/// Request sent through the connection of an account, with what its answer is about
+pub struct Request {
+    iq: Iq,
+    about: About,
+}
+
+/// Outcome of an answer of the server
+enum Outcome {
+    Form(Vec<String>),
+    Registered(BareJid, String),
+    PasswordChanged(FullJid, String),
+    Logged(String),
+    Failed(String),
+}
+
+/// XEP-0077: In-Band Registration, to create accounts and change their password
+pub struct RegisterPlugin {
+    /// Server being registered on, with the stream to it once connected, or gateway being
+    /// registered with through the connection of the account
+    server: Option<BareJid>,
+    sink: Option<UnboundedSender<Packet>>,
+    gateway: bool,
+    form: Option<RegistrationForm>,
+    /// Requests sent on the registration stream waiting for an answer, by id
+    requests: HashMap<String, About>,
+}
+
+impl RegisterPlugin {
+    /// Send a request on the registration stream
+    fn send(&mut self, iq: Iq, about: About) -> Result<(), String> {
+        let sink = self.sink.as_ref().ok_or(format!("Not connected to the server yet"))?;
+        self.requests.insert(iq.id.clone(), about);
+        sink.unbounded_send(Packet::Stanza(iq.into())).map_err(|_| format!("Connection to the server lost"))
+    }
+
+    /// Send a request through the connection of the account, its outcome being reported once
+    /// answered
+    pub fn send_request(aparte: Rc<Aparte>, request: Request) -> impl Future<Item = (), Error = String> {
+        let about = request.about;
+        let answer_aparte = Rc::clone(&aparte);
+        aparte.send_iq(request.iq).then(move |answer| {
+            let outcome = answer_aparte.get_plugin_mut::<RegisterPlugin>().unwrap().answer(about, answer);
+            RegisterPlugin::report(answer_aparte, outcome);
+            Ok(())
+        })
+    }
+
+    /// Close the stream to the server registered on
+    fn close(&mut self) {
+        if let Some(sink) = self.sink.take() {
+            let _ = sink.unbounded_send(Packet::StreamEnd);
+        }
+        self.server = None;
+        self.gateway = false;
+        self.form = None;
+    }
+
+    /// Request the registration form of a gateway, to be sent through the connection of the
+    /// account
+    pub fn start_gateway(&mut self, gateway: BareJid) -> Request {
+        self.close();
+        self.server = Some(gateway.clone());
+        self.gateway = true;
+        Request {
+            iq: register_iq(&gateway, IqType::Get(Element::builder("query").ns(NS_REGISTER).build())),
+            about: About::Form(gateway),
+        }
+    }
+
+    /// Query cancelling the registration with a gateway
+    pub fn unregister(gateway: BareJid) -> Request {
+        let query = Element::builder("query").ns(NS_REGISTER)
+            .append(Element::builder("remove").ns(NS_REGISTER).build())
+            .build();
+        Request {
+            iq: register_iq(&gateway, IqType::Set(query)),
+            about: About::Unregister(gateway),
+        }
+    }
+
+    /// Open an unauthenticated stream to a server and request its registration form
+    pub fn start(aparte: Rc<Aparte>, server: BareJid) -> Result<(), String> {
+        {
+            let mut register = aparte.get_plugin_mut::<RegisterPlugin>().unwrap();
+            register.close();
+            register.server = Some(server.clone());
+        }
+
+        let addr = (server.domain.as_str(), 5222).to_socket_addrs()
+            .map_err(|err| format!("Cannot resolve {}: {}", server, err))?
+            .next().ok_or(format!("Cannot resolve {}", server))?;
+        Rc::clone(&aparte).log(format!("Connecting to {} to register", server));
+
+        // The stream is opened to the server, without authenticating
+        let jid = jid::Jid::Bare(jid::BareJid::domain(&server.domain));
+        let restart_jid = jid.clone();
+        let ns = NS_CLIENT.to_string();
+        let restart_ns = ns.clone();
+        let connect = TcpStream::connect(&addr).map_err(tokio_xmpp::Error::Io)
+            .and_then(move |tcp| XMPPStream::start(tcp, jid, ns))
+            .and_then(|stream| {
+                // The password is sent in the clear otherwise
+                match stream.stream_features.get_child("starttls", NS_TLS) {
+                    Some(_) => Ok(StartTlsClient::from_stream(stream)),
+                    None => Err(tokio_xmpp::Error::Io(std::io::Error::new(std::io::ErrorKind::Other, "TLS not supported"))),
+                }
+            })
+            .flatten()
+            .and_then(move |tls| XMPPStream::start(tls, restart_jid, restart_ns));
+
+        let stream_aparte = Rc::clone(&aparte);
+        let error_aparte = Rc::clone(&aparte);
+        let register = connect.map(move |stream| {
+            let (sink, stream) = stream.split();
+            let (tx, rx) = futures::unsync::mpsc::unbounded();
+            tokio::runtime::current_thread::spawn(rx.forward(sink.sink_map_err(|_| ())).map(|_| ()));
+
+            let query = {
+                let mut register = stream_aparte.get_plugin_mut::<RegisterPlugin>().unwrap();
+                register.sink = Some(tx);
+                let iq = register_iq(&server, IqType::Get(Element::builder("query").ns(NS_REGISTER).build()));
+                register.send(iq, About::Form(server.clone()))
+            };
+            if let Err(err) = query {
+                Rc::clone(&stream_aparte).log(err);
+            }
+
+            let packet_aparte = Rc::clone(&stream_aparte);
+            tokio::runtime::current_thread::spawn(stream.for_each(move |packet| {
+                if let Packet::Stanza(stanza) = packet {
+                    if let Ok(iq) = Iq::try_from(stanza) {
+                        RegisterPlugin::received(Rc::clone(&packet_aparte), iq);
+                    }
+                }
+                Ok(())
+            }).map_err(|err| warn!("Registration stream error: {:?}", err)));
+        }).map_err(move |err| {
+            error_aparte.get_plugin_mut::<RegisterPlugin>().unwrap().close();
+            error_aparte.log(format!("Cannot connect to the server: {}", err));
+        });
+
+        tokio::runtime::current_thread::spawn(register);
+        Ok(())
+    }
+
+    fn answer(&mut self, about: About, answer: Result<Iq, IqError>) -> Outcome {
+        match (about, answer.map(|answer| answer.payload)) {
+            (About::Form(server), Ok(IqType::Result(Some(query)))) => match RegistrationForm::parse(&query) {
+                Ok(form) => {
+                    let lines = form.render(&server);
+                    self.form = Some(form);
+                    Outcome::Form(lines)
+                },
+                Err(err) => {
+                    self.close();
+                    Outcome::Failed(format!("Cannot register on {}: {}", server, err))
+                },
+            },
+            (About::Form(server), Ok(_)) => {
+                self.close();
+                Outcome::Failed(format!("Cannot register on {}: No form", server))
+            },
+            (About::Register(jid, password), Ok(_)) => {
+                self.close();
+                Outcome::Registered(jid, password)
+            },
+            (About::Password(account, password), Ok(_)) => Outcome::PasswordChanged(account, password),
+            (About::Gateway(gateway), Ok(_)) => {
+                self.close();
+                Outcome::Logged(format!("Registered with {}, its contacts and channels are now reachable through it", gateway))
+            },
+            (About::Unregister(gateway), Ok(_)) => Outcome::Logged(format!("Unregistered from {}", gateway)),
+            (About::Form(server), Err(err)) => {
+                self.close();
+                Outcome::Failed(format!("Cannot register on {}: {}", server, mucadmin::iq_error_text(&err)))
+            },
+            (About::Register(jid, _), Err(err)) => Outcome::Failed(format!("Cannot register {}: {}", jid, mucadmin::iq_error_text(&err))),
+            (About::Password(account, _), Err(err)) => Outcome::Failed(format!("Cannot change the password of {}: {}", account, mucadmin::iq_error_text(&err))),
+            (About::Gateway(gateway), Err(err)) => Outcome::Failed(format!("Cannot register with {}: {}", gateway, mucadmin::iq_error_text(&err))),
+            (About::Unregister(gateway), Err(err)) => Outcome::Failed(format!("Cannot unregister from {}: {}", gateway, mucadmin::iq_error_text(&err))),
+        }
+    }
+
+    /// Handle an answer of the server on the registration stream
+    fn received(aparte: Rc<Aparte>, iq: Iq) {
+        let outcome = {
+            let mut register = aparte.get_plugin_mut::<RegisterPlugin>().unwrap();
+            let about = match register.requests.remove(&iq.id) {
+                Some(about) => about,
+                None => return,
+            };
+            let answer = match iq.payload {
+                IqType::Error(error) => Err(IqError::Error(error)),
+                _ => Ok(iq),
+            };
+            register.answer(about, answer)
+        };
+        RegisterPlugin::report(aparte, outcome);
+    }
+
+    fn report(aparte: Rc<Aparte>, outcome: Outcome) {
+        match outcome {
+            Outcome::Form(lines) => {
+                let mut ui = aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                ui.add_log_window(WINDOW);
+                ui.clear_window(WINDOW);
+                ui.change_window(WINDOW);
+                ui.log_to(Rc::clone(&aparte), WINDOW, lines.join("\n"));
+            },
+            Outcome::Registered(jid, password) => {
+                match wizard::add_account(&aparte.config_path, &jid, Some(&password)) {
+                    Ok(()) => Rc::clone(&aparte).log(format!("Account {} created and added to the config, use /connect {} to connect", jid, jid)),
+                    Err(err) => Rc::clone(&aparte).log(format!("Account {} created but not added to the config: {}", jid, err)),
+                }
+            },
+            Outcome::PasswordChanged(account, password) => {
+                // Reconnect with the new password
+                let connect = Command::new(vec![String::from("connect"), account.to_string(), password]);
+                aparte.get_plugin_mut::<ReconnectPlugin>().unwrap().register(account.clone(), connect);
+
+                let bare = BareJid::from(Jid::Full(account.clone()));
+                let stored = aparte.config.accounts.values().any(|stored| stored.login == bare.to_string() && stored.password.is_some());
+                match stored {
+                    true => Rc::clone(&aparte).log(format!("Password of {} changed, update the password stored in {}", bare, aparte.config_path.display())),
+                    false => Rc::clone(&aparte).log(format!("Password of {} changed", bare)),
+                }
+            },
+            Outcome::Logged(text) | Outcome::Failed(text) => Rc::clone(&aparte).log(text),
+        }
+    }
+
+    /// Change a field of the registration form, returns the form to display again
+    pub fn set(&mut self, field: &str, value: &str) -> Result<Vec<String>, String> {
+        let server = self.server.clone().ok_or(format!("No registration in progress, use /register <server> first"))?;
+        let form = self.form.as_mut().ok_or(format!("No registration form received yet"))?;
+        let index = form.field(field)?;
+        let field = &mut form.form.fields[index];
+        field.values = mucadmin::field_values(field, value)?;
+        Ok(form.render(&server))
+    }
+
+    /// Whether a field of the form holds a secret, to read its value without echoing it
+    pub fn is_private(&self, field: &str) -> bool {
+        match &self.form {
+            Some(form) => form.field(field).map(|index| form.form.fields[index].type_ == FieldType::TextPrivate).unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// Send the filled registration form, returns what is registered with the query to send
+    /// through the connection of the account when registering with a gateway
+    pub fn submit(&mut self) -> Result<(BareJid, Option<Request>), String> {
+        let server = self.server.clone().ok_or(format!("No registration in progress"))?;
+        self.form.as_ref().ok_or(format!("No registration form received yet"))?.check()?;
+        let form = self.form.take().unwrap();
+        let username = form.value("username").map(String::from);
+        let password = form.value("password").map(String::from);
+        let query = form.submit()?;
+
+        if self.gateway {
+            let request = Request {
+                iq: register_iq(&server, IqType::Set(query)),
+                about: About::Gateway(server.clone()),
+            };
+            return Ok((server, Some(request)));
+        }
+
+        // Data forms may name the fields differently, the account is then only reported
+        let jid = match &username {
+            Some(username) => BareJid::new(username, &server.domain),
+            None => server.clone(),
+        };
+        self.send(register_iq(&server, IqType::Set(query)), About::Register(jid.clone(), password.unwrap_or_default()))?;
+        Ok((jid, None))
+    }
+
+    pub fn cancel(&mut self) -> Result<(), String> {
+        match self.server {
+            Some(_) => {
+                self.close();
+                Ok(())
+            },
+            None => Err(format!("No registration in progress")),
+        }
+    }
+
+    /// Query changing the password of a connected account
+    pub fn change_password(account: &FullJid, password: &str) -> Request {
+        let query = Element::builder("query").ns(NS_REGISTER)
+            .append(Element::builder("username").ns(NS_REGISTER).append(account.node.clone().unwrap_or_default()).build())
+            .append(Element::builder("password").ns(NS_REGISTER).append(password).build())
+            .build();
+        Request {
+            iq: register_iq(&BareJid::domain(&account.domain), IqType::Set(query)),
+            about: About::Password(account.clone(), password.to_string()),
+        }
+    }
+}
+
+impl Plugin for RegisterPlugin {
+    fn new() -> RegisterPlugin {
+        Self {
+            server: None,
+            sink: None,
+            gateway: false,
+            form: None,
+            requests: HashMap::new(),
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, _aparte: Rc<Aparte>, _event: &Event) {
+    }
+}
+
+impl fmt::Display for RegisterPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XEP-0077: In-Band Registration")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_legacy_form() {
+        let query: Element = r#"<query xmlns="jabber:iq:register"><instructions>Choose a username and password</instructions><username/><password/><email/></query>"#.parse().unwrap();
+        let mut form = RegistrationForm::parse(&query).unwrap();
+        assert!(form.legacy);
+        let lines = form.render(&BareJid::from_str("server.tld").unwrap());
+        assert_eq!(lines[1], "Choose a username and password");
+        assert_eq!(lines[2], "[1] *username:   (username)");
+        assert_eq!(form.form.fields[1].type_, FieldType::TextPrivate);
+
+        form.form.fields[0].values = vec![String::from("me")];
+        form.form.fields[1].values = vec![String::from("secret")];
+        assert_eq!(form.value("username"), Some("me"));
+        assert!(form.submit().is_err());
+
+        let mut form = RegistrationForm::parse(&query).unwrap();
+        for (field, value) in form.form.fields.iter_mut().zip(&["me", "secret", "me@mail.tld"]) {
+            field.values = vec![value.to_string()];
+        }
+        let submit = form.submit().unwrap();
+        assert_eq!(submit.get_child("username", NS_REGISTER).unwrap().text(), "me");
+        assert_eq!(submit.get_child("password", NS_REGISTER).unwrap().text(), "secret");
+        assert!(submit.get_child("x", "jabber:x:data").is_none());
+
+        let registered: Element = r#"<query xmlns="jabber:iq:register"><registered/><username>me</username></query>"#.parse().unwrap();
+        assert!(RegistrationForm::parse(&registered).is_err());
+    }
+
+    #[test]
+    fn test_captcha_form() {
+        let query: Element = r#"<query xmlns="jabber:iq:register">
+  <instructions>Use the enclosed form to register</instructions>
+  <x xmlns="jabber:x:data" type="form">
+    <field type="hidden" var="FORM_TYPE"><value>urn:xmpp:captcha</value></field>
+    <field type="text-single" var="username"><required/></field>
+    <field type="text-private" var="password"><required/></field>
+    <field type="text-single" label="Enter the text you see" var="ocr">
+      <media xmlns="urn:xmpp:media-element" height="80" width="290">
+        <uri type="image/png">cid:sha1+f24030b8d91d233bac14777be5ab531ca3b9f102@bob.xmpp.org</uri>
+      </media>
+      <required/>
+    </field>
+  </x>
+  <data xmlns="urn:xmpp:bob" cid="sha1+f24030b8d91d233bac14777be5ab531ca3b9f102@bob.xmpp.org" type="image/png">iVBORw0KGgo=</data>
+</query>"#.parse().unwrap();
+        let mut form = RegistrationForm::parse(&query).unwrap();
+        assert!(!form.legacy);
+
+        let path = form.media["cid:sha1+f24030b8d91d233bac14777be5ab531ca3b9f102@bob.xmpp.org"].clone();
+        assert_eq!(fs::read(&path).unwrap(), b"\x89PNG\r\n\x1a\n");
+        fs::remove_file(&path).unwrap();
+        let lines = form.render(&BareJid::from_str("server.tld").unwrap());
+        assert!(lines.contains(&format!("      image/png: {}", path)));
+
+        for (field, value) in form.form.fields.iter_mut().zip(&["me", "secret", "7f3a"]) {
+            field.values = vec![value.to_string()];
+        }
+        let submit = DataForm::try_from(form.submit().unwrap().get_child("x", "jabber:x:data").unwrap().clone()).unwrap();
+        assert_eq!(submit.type_, DataFormType::Submit);
+        assert_eq!(submit.form_type.as_deref(), Some("urn:xmpp:captcha"));
+        assert_eq!(submit.fields[2].values, vec![String::from("7f3a")]);
+    }
+
+    #[test]
+    fn test_gateway_registration() {
+        let mut plugin = RegisterPlugin::new();
+        let gateway = BareJid::domain("signal.server.tld");
+        let request = plugin.start_gateway(gateway.clone());
+        assert_eq!(request.iq.to.as_ref().map(|to| to.to_string()).as_deref(), Some("signal.server.tld"));
+
+        let form: Element = format!(r#"<iq xmlns="jabber:client" type="result" id="{}" from="signal.server.tld"><query xmlns="jabber:iq:register"><username/><password/></query></iq>"#, request.iq.id).parse().unwrap();
+        match plugin.answer(request.about, Ok(Iq::try_from(form).unwrap())) {
+            Outcome::Form(_) => {},
+            _ => panic!("Expected the registration form"),
+        }
+        plugin.set("username", "+33600000000").unwrap();
+        // A missing value keeps the form to fill it
+        assert!(plugin.submit().is_err());
+        plugin.set("2", "secret").unwrap();
+
+        let (jid, request) = plugin.submit().unwrap();
+        assert_eq!(jid, gateway);
+        let request = request.unwrap();
+        match plugin.answer(request.about, Err(IqError::Timeout)) {
+            Outcome::Failed(text) => assert_eq!(text, "Cannot register with signal.server.tld: no answer after 30s"),
+            _ => panic!("Expected a failure"),
+        }
+        let query = Element::from(request.iq);
+        assert_eq!(query.attr("type"), Some("set"));
+        assert_eq!(query.get_child("query", NS_REGISTER).unwrap().get_child("password", NS_REGISTER).unwrap().text(), "secret");
+    }
+}
@@ -0,0 +1,230 @@
+use chrono::Utc;
+use futures::Future;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Instant;
+use tokio::timer::Delay;
+use xmpp_parsers::{BareJid, Element, Jid, ns};
+
+use crate::config::Retention;
+use crate::core::{Plugin, Aparte, Event};
+use crate::settings::Settings;
+use crate::plugins::history::HistoryPlugin;
+use crate::store::{self, MessageStore};
+
+/// Delay between two expirations of the history, so that messages don't outlive their retention
+/// by more than this
+const EXPIRE_INTERVAL: u64 = 10 * 60;
+
+/// Local retention of the history of some conversations, independently of what the server
+/// archives. The history is expired in the store of the history plugin, nothing being kept
+/// without it.
+pub struct RetentionPlugin {
+    /// Retention of each conversation, from the config or changed with /retention
+    retention: HashMap<BareJid, Retention>,
+    expiration_scheduled: bool,
+}
+
+impl RetentionPlugin {
+    pub fn retention(&self, conversation: &BareJid) -> Option<Retention> {
+        self.retention.get(conversation).cloned()
+    }
+
+    /// Change the retention of a conversation for this session, expiring its history right away
+    pub fn set_retention(&mut self, aparte: &Aparte, conversation: &BareJid, retention: Option<Retention>) -> Result<usize, String> {
+        match retention {
+            Some(retention) => {
+                self.retention.insert(conversation.clone(), retention);
+                match aparte.get_plugin_mut::<HistoryPlugin>() {
+                    Some(mut history) => self.expire(history.store_mut(), conversation),
+                    None => Ok(0),
+                }
+            },
+            None => {
+                self.retention.remove(conversation);
+                Ok(0)
+            },
+        }
+    }
+
+    fn expire(&self, store: &mut dyn MessageStore, conversation: &BareJid) -> Result<usize, String> {
+        match self.retention.get(conversation) {
+            Some(retention) => store.expire(conversation, &(Utc::now() - retention.0)),
+            None => Ok(0),
+        }
+    }
+
+    fn expire_all(&self, aparte: &Aparte) {
+        let mut history = match aparte.get_plugin_mut::<HistoryPlugin>() {
+            Some(history) => history,
+            None => return,
+        };
+        for conversation in self.retention.keys() {
+            if let Err(err) = self.expire(history.store_mut(), conversation) {
+                warn!("{}", err);
+            }
+        }
+    }
+
+    fn schedule_expiration(aparte: Rc<Aparte>) {
+        let delay = Delay::new(Instant::now() + std::time::Duration::from_secs(EXPIRE_INTERVAL));
+        tokio::runtime::current_thread::spawn(delay.map(move |_| {
+            aparte.get_plugin::<RetentionPlugin>().unwrap().expire_all(&aparte);
+            RetentionPlugin::schedule_expiration(aparte);
+        }).map_err(|err| warn!("Retention timer error: {}", err)));
+    }
+
+    /// Stanza as written to the log file, messages exchanged in conversations with a retention
+    /// being left out
+    pub fn loggable(&self, stanza: &Element) -> Option<String> {
+        peers(stanza).into_iter().find(|jid| self.retention.contains_key(jid))
+            .map(|jid| format!("<message/> of {} left out, retained locally", jid))
+    }
+}
+
+/// Messages forwarded in a message, as carbons and archived messages are
+fn forwarded(stanza: &Element) -> impl Iterator<Item = &Element> {
+    stanza.children()
+        .flat_map(|child| match child.is("forwarded", ns::FORWARD) {
+            true => vec![child],
+            // Wrapped in <received/>, <sent/> or <result/>
+            false => child.children().filter(|child| child.is("forwarded", ns::FORWARD)).collect(),
+        })
+        .filter_map(|forwarded| forwarded.get_child("message", ns::DEFAULT_NS))
+}
+
+/// Contacts or channels a message is exchanged with, the ones of the messages it forwards too
+fn peers(stanza: &Element) -> Vec<BareJid> {
+    if stanza.name() != "message" {
+        return Vec::new();
+    }
+    let mut peers: Vec<BareJid> = ["from", "to"].iter()
+        .filter_map(|attr| stanza.attr(attr))
+        .filter_map(|jid| Jid::from_str(jid).ok())
+        .map(BareJid::from)
+        .collect();
+    for message in forwarded(stanza) {
+        peers.extend(self::peers(message));
+    }
+    peers
+}
+
+/// Stanza as written to the log file, messages of conversations whose logging is off being left
+/// out
+fn unlogged(settings: &Settings, stanza: &Element) -> Option<String> {
+    peers(stanza).into_iter().find(|jid| settings.get::<bool>(jid, "logging") == Some(false))
+        .map(|jid| format!("<message/> of {} left out, logging is off", jid))
+}
+
+impl Plugin for RetentionPlugin {
+    fn new() -> RetentionPlugin {
+        Self {
+            retention: HashMap::new(),
+            expiration_scheduled: false,
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        for (jid, retention) in &aparte.config.retention {
+            match BareJid::from_str(jid) {
+                Ok(jid) => { self.retention.insert(jid, *retention); },
+                Err(err) => warn!("Invalid JID {} in retention config: {}", jid, err),
+            }
+        }
+
+        aparte.filter_log(|retention: &RetentionPlugin, aparte, stanza| {
+            retention.loggable(stanza).or_else(|| unlogged(&aparte.settings.borrow(), stanza))
+        });
+        self.expire_all(aparte);
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Connected(_) => {
+                // Plugins are initialized before the runtime is started
+                if !self.expiration_scheduled {
+                    self.expiration_scheduled = true;
+                    RetentionPlugin::schedule_expiration(aparte);
+                }
+            },
+            Event::Message(message) => {
+                if let Some(conversation) = store::conversation(message) {
+                    if let Some(mut history) = aparte.get_plugin_mut::<HistoryPlugin>() {
+                        if let Err(err) = self.expire(history.store_mut(), &conversation) {
+                            warn!("{}", err);
+                        }
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for RetentionPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Local history retention")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use crate::message::Message;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn test_retention() {
+        let contact = BareJid::from_str("secret@server.tld").unwrap();
+        let us = Jid::from_str("me@server.tld/aparte").unwrap();
+        let from = Jid::from_str("secret@server.tld/phone").unwrap();
+        let mut store = MemoryStore::new();
+        store.insert(&Message::incoming_chat("1", Utc::now() - Duration::days(2), &from, &us, "Old")).unwrap();
+        store.insert(&Message::incoming_chat("2", Utc::now(), &from, &us, "New")).unwrap();
+
+        let mut plugin = RetentionPlugin::new();
+        let stanza: Element = r#"<message xmlns="jabber:client" from="secret@server.tld/phone" to="me@server.tld/aparte" type="chat"><body>Hush</body></message>"#.parse().unwrap();
+        assert!(plugin.loggable(&stanza).is_none());
+
+        plugin.retention.insert(contact.clone(), Retention(Duration::days(1)));
+        assert_eq!(plugin.expire(&mut store, &contact), Ok(1));
+        assert_eq!(store.history(&contact, Some(10)).unwrap().len(), 1);
+        assert_eq!(plugin.loggable(&stanza).unwrap(), "<message/> of secret@server.tld left out, retained locally");
+
+        let presence: Element = r#"<presence xmlns="jabber:client" from="secret@server.tld/phone"/>"#.parse().unwrap();
+        assert!(plugin.loggable(&presence).is_none());
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let mut plugin = RetentionPlugin::new();
+        plugin.retention.insert(BareJid::from_str("secret@server.tld").unwrap(), Retention(Duration::days(1)));
+
+        // Carbon of a message sent from another client
+        let carbon: Element = r#"<message xmlns="jabber:client" from="me@server.tld" to="me@server.tld/aparte" type="chat"><sent xmlns="urn:xmpp:carbons:2"><forwarded xmlns="urn:xmpp:forward:0"><message xmlns="jabber:client" from="me@server.tld/phone" to="secret@server.tld" type="chat"><body>Hush</body></message></forwarded></sent></message>"#.parse().unwrap();
+        assert_eq!(plugin.loggable(&carbon).unwrap(), "<message/> of secret@server.tld left out, retained locally");
+
+        // Message from the archive
+        let archived: Element = r#"<message xmlns="jabber:client" from="me@server.tld" to="me@server.tld/aparte"><result xmlns="urn:xmpp:mam:2" queryid="q" id="1"><forwarded xmlns="urn:xmpp:forward:0"><delay xmlns="urn:xmpp:delay" stamp="2020-01-01T00:00:00Z"/><message xmlns="jabber:client" from="secret@server.tld/phone" to="me@server.tld/aparte" type="chat"><body>Hush</body></message></forwarded></result></message>"#.parse().unwrap();
+        assert_eq!(plugin.loggable(&archived).unwrap(), "<message/> of secret@server.tld left out, retained locally");
+
+        let other: Element = r#"<message xmlns="jabber:client" from="me@server.tld" to="me@server.tld/aparte"><received xmlns="urn:xmpp:carbons:2"><forwarded xmlns="urn:xmpp:forward:0"><message xmlns="jabber:client" from="friend@server.tld/phone" to="me@server.tld" type="chat"><body>Hi</body></message></forwarded></received></message>"#.parse().unwrap();
+        assert!(plugin.loggable(&other).is_none());
+    }
+
+    #[test]
+    fn test_logging_off() {
+        let mut settings = Settings::default();
+        let stanza: Element = r#"<message xmlns="jabber:client" from="room@conference.server.tld/nick" to="me@server.tld/aparte" type="groupchat"><body>Hi</body></message>"#.parse().unwrap();
+        assert!(unlogged(&settings, &stanza).is_none());
+        settings.set(Some(&BareJid::from_str("room@conference.server.tld").unwrap()), "logging", Some("off")).unwrap();
+        assert_eq!(unlogged(&settings, &stanza).unwrap(), "<message/> of room@conference.server.tld left out, logging is off");
+        settings.set(None, "logging", Some("off")).unwrap();
+        let presence: Element = r#"<presence xmlns="jabber:client" from="contact@server.tld/phone"/>"#.parse().unwrap();
+        assert!(unlogged(&settings, &presence).is_none());
+    }
+}
@@ -0,0 +1,175 @@
+use futures::{future, Future};
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+use uuid::Uuid;
+use xmpp_parsers::{BareJid, Element, Jid};
+use xmpp_parsers::disco::{DiscoInfoQuery, DiscoInfoResult, DiscoItemsQuery, DiscoItemsResult};
+use xmpp_parsers::iq::{Iq, IqType};
+
+use crate::core::{Plugin, Aparte, Event};
+use crate::plugins::ui::UIPlugin;
+
+pub const WINDOW: &str = "rooms";
+
+/// Rooms whose details are queried, the others are only listed by name
+const MAX_INFO_QUERIES: usize = 100;
+
+const NS_MUC_ROOMINFO: &str = "http://jabber.org/protocol/muc#roominfo";
+
+/// Public room of a channel service
+#[derive(Debug, Clone, PartialEq)]
+pub struct Room {
+    pub jid: BareJid,
+    pub name: Option<String>,
+    /// Number of occupants, when the service publishes it (XEP-0128)
+    pub occupants: Option<u64>,
+}
+
+impl fmt::Display for Room {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} ({})", name, self.jid)?,
+            None => write!(f, "{}", self.jid)?,
+        }
+        match self.occupants {
+            Some(1) => write!(f, " — 1 occupant"),
+            Some(occupants) => write!(f, " — {} occupants", occupants),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Number of occupants given in the room info extension
+fn occupants(info: &DiscoInfoResult) -> Option<u64> {
+    info.extensions.iter()
+        .filter(|form| form.form_type.as_deref() == Some(NS_MUC_ROOMINFO))
+        .flat_map(|form| form.fields.iter())
+        .find(|field| field.var == "muc#roominfo_occupants")
+        .and_then(|field| field.values.first())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Rooms listed in the answer to a disco#items query of a channel service
+fn rooms(payload: Option<Element>) -> Vec<Room> {
+    let items = payload.and_then(|payload| DiscoItemsResult::try_from(payload).ok()).map(|result| result.items).unwrap_or_default();
+    items.into_iter().map(|item| Room {
+        jid: BareJid::from(item.jid),
+        name: item.name,
+        occupants: None,
+    }).collect()
+}
+
+/// List the public rooms of a channel service, to join them from the list
+pub struct RoomsPlugin {
+    /// Counts the listings, the details of rooms of a previous one being dropped
+    listing: usize,
+}
+
+impl RoomsPlugin {
+    /// Query the rooms of a channel service, then the details of each one
+    pub fn list(aparte: Rc<Aparte>, service: Jid) -> impl Future<Item = (), Error = String> {
+        let listing = {
+            let mut plugin = aparte.get_plugin_mut::<RoomsPlugin>().unwrap();
+            plugin.listing += 1;
+            plugin.listing
+        };
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let query = Iq::from_get(id, DiscoItemsQuery { node: None }).with_to(service.clone());
+
+        let answer_aparte = Rc::clone(&aparte);
+        let error_service = service.clone();
+        Rc::clone(&aparte).send_iq(query)
+            .map_err(move |err| format!("Cannot list rooms of {}: {}", error_service, err))
+            .and_then(move |answer| {
+                let payload = match answer.payload {
+                    IqType::Result(payload) => payload,
+                    _ => None,
+                };
+                let rooms = rooms(payload);
+                let queries: Vec<_> = rooms.iter().take(MAX_INFO_QUERIES)
+                    .map(|room| RoomsPlugin::info(Rc::clone(&answer_aparte), listing, room.jid.clone()))
+                    .collect();
+                answer_aparte.get_plugin_mut::<UIPlugin>().unwrap().show_rooms(&service.to_string(), rooms);
+                future::join_all(queries).map(|_| ())
+            })
+    }
+
+    /// Query the details of a room, shown unless another listing started since
+    fn info(aparte: Rc<Aparte>, listing: usize, room: BareJid) -> impl Future<Item = (), Error = String> {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let query = Iq::from_get(id, DiscoInfoQuery { node: None }).with_to(Jid::Bare(room.clone()));
+
+        let answer_aparte = Rc::clone(&aparte);
+        aparte.send_iq(query).then(move |answer| {
+            if answer_aparte.get_plugin::<RoomsPlugin>().unwrap().listing != listing {
+                return Ok(());
+            }
+            let info = match answer.map(|answer| answer.payload) {
+                Ok(IqType::Result(Some(payload))) => DiscoInfoResult::try_from(payload).ok(),
+                _ => None,
+            };
+            if let Some(info) = info {
+                let name = info.identities.iter().find(|identity| identity.category == "conference").and_then(|identity| identity.name.clone());
+                let room = Room {
+                    jid: room,
+                    name: name,
+                    occupants: occupants(&info),
+                };
+                answer_aparte.get_plugin_mut::<UIPlugin>().unwrap().update_room(room);
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Plugin for RoomsPlugin {
+    fn new() -> RoomsPlugin {
+        Self {
+            listing: 0,
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, _aparte: Rc<Aparte>, _event: &Event) {
+    }
+}
+
+impl fmt::Display for RoomsPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Rooms directory")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_room_info() {
+        let element: Element = r#"<query xmlns="http://jabber.org/protocol/disco#info"><identity category="conference" type="text" name="Aparté"/><feature var="http://jabber.org/protocol/disco#info"/><feature var="http://jabber.org/protocol/muc"/><x xmlns="jabber:x:data" type="result"><field var="FORM_TYPE" type="hidden"><value>http://jabber.org/protocol/muc#roominfo</value></field><field var="muc#roominfo_occupants"><value>42</value></field></x></query>"#.parse().unwrap();
+        let info = DiscoInfoResult::try_from(element).unwrap();
+        assert_eq!(occupants(&info), Some(42));
+
+        let room = Room {
+            jid: BareJid::from_str("aparte@conference.server.tld").unwrap(),
+            name: Some(String::from("Aparté")),
+            occupants: occupants(&info),
+        };
+        assert_eq!(room.to_string(), "Aparté (aparte@conference.server.tld) — 42 occupants");
+    }
+
+    #[test]
+    fn test_rooms() {
+        let element: Element = r#"<query xmlns="http://jabber.org/protocol/disco#items"><item jid="aparte@conference.server.tld" name="Aparté"/><item jid="xsf@conference.server.tld"/></query>"#.parse().unwrap();
+        let rooms = rooms(Some(element));
+        assert_eq!(rooms.len(), 2);
+        assert_eq!(rooms[0].to_string(), "Aparté (aparte@conference.server.tld)");
+        assert_eq!(rooms[1].to_string(), "xsf@conference.server.tld");
+        assert!(super::rooms(None).is_empty());
+    }
+}
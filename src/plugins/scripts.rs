@@ -0,0 +1,392 @@
+use chrono::Utc;
+use futures::future;
+use rhai::{Array, Dynamic, Engine, FnPtr, Map, AST};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::{BareJid, Element, Jid};
+
+use crate::command::Command;
+use crate::conversation::Conversation;
+use crate::core::{Plugin, Aparte, Event};
+use crate::message::{Message, XmppMessage};
+use crate::plugins::conversation::ConversationPlugin;
+
+/// Operations a script may run before being interrupted, so that a script looping forever
+/// doesn't freeze the UI
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// What scripts ask for while they run, carried out once they return
+enum Request {
+    /// Call a function on an event
+    Subscribe(String, FnPtr),
+    /// Call a function when a command is typed
+    Command(String, FnPtr),
+    Send(String, String),
+    Log(String),
+    /// Run a command as typed in the input
+    Run(String),
+}
+
+struct Script {
+    name: String,
+    ast: AST,
+}
+
+/// Config as seen by scripts, leaving out the passwords of accounts
+fn config_map(config: &str) -> Map {
+    let mut value: toml::Value = match toml::from_str(config) {
+        Ok(value) => value,
+        Err(_) => return Map::new(),
+    };
+    if let Some(accounts) = value.get_mut("accounts").and_then(toml::Value::as_table_mut) {
+        for (_, account) in accounts.iter_mut() {
+            if let Some(account) = account.as_table_mut() {
+                account.remove("password");
+            }
+        }
+    }
+    match dynamic(&value).try_cast::<Map>() {
+        Some(map) => map,
+        None => Map::new(),
+    }
+}
+
+fn dynamic(value: &toml::Value) -> Dynamic {
+    match value {
+        toml::Value::String(string) => string.clone().into(),
+        toml::Value::Integer(integer) => (*integer).into(),
+        toml::Value::Float(float) => (*float).into(),
+        toml::Value::Boolean(boolean) => (*boolean).into(),
+        toml::Value::Datetime(datetime) => datetime.to_string().into(),
+        toml::Value::Array(array) => Dynamic::from_array(array.iter().map(dynamic).collect()),
+        toml::Value::Table(table) => Dynamic::from_map(table.iter().map(|(key, value)| (key.as_str().into(), dynamic(value))).collect()),
+    }
+}
+
+/// Message as handed to scripts
+fn message_map(message: &Message) -> Option<Map> {
+    let (type_, from, from_full, to, body) = match message {
+        Message::Incoming(XmppMessage::Chat(message)) => ("chat", &message.from, &message.from_full, &message.to, &message.body),
+        Message::Incoming(XmppMessage::Groupchat(message)) => ("groupchat", &message.from, &message.from_full, &message.to, &message.body),
+        _ => return None,
+    };
+    let mut map = Map::new();
+    map.insert("id".into(), message.id().to_string().into());
+    map.insert("type".into(), type_.into());
+    map.insert("from".into(), from.to_string().into());
+    map.insert("from_full".into(), from_full.to_string().into());
+    map.insert("to".into(), to.to_string().into());
+    map.insert("body".into(), body.to_string().into());
+    Some(map)
+}
+
+/// User scripts written in Rhai, loaded from the scripts directory next to the config file. They
+/// can:
+///  - subscribe to events with `on(event, handler)`, event being "connected", "disconnected",
+///    "message" or "join"
+///  - add commands with `register_command(name, handler)`, handler getting the arguments
+///  - send messages with `send_message(jid, body)`
+///  - run commands with `command("/join channel@conference.server.tld")`
+///  - log with `log(text)` or `print(text)`
+///  - read the config with `config()`
+pub struct ScriptsPlugin {
+    engine: Engine,
+    scripts: Vec<Script>,
+    requests: Rc<RefCell<Vec<Request>>>,
+    config: Rc<RefCell<Map>>,
+    /// Handlers of each event, with the index of their script
+    handlers: HashMap<String, Vec<(usize, FnPtr)>>,
+    commands: BTreeMap<String, (usize, FnPtr)>,
+    /// Where scripts are loaded from, none in tests
+    dir: Option<PathBuf>,
+}
+
+impl ScriptsPlugin {
+    pub fn scripts(&self) -> Vec<String> {
+        self.scripts.iter().map(|script| script.name.clone()).collect()
+    }
+
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.keys().cloned().collect()
+    }
+
+    /// Forget every script and load the ones of the scripts directory again, returns the errors
+    /// of the ones that couldn't
+    pub fn reload(&mut self) -> Vec<String> {
+        self.scripts.clear();
+        self.handlers.clear();
+        self.commands.clear();
+
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => return Vec::new(),
+        };
+        let mut paths: Vec<PathBuf> = match fs::read_dir(&dir) {
+            Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path())
+                .filter(|path| path.extension().map_or(false, |extension| extension == "rhai"))
+                .collect(),
+            Err(_) => return Vec::new(),
+        };
+        paths.sort();
+
+        let mut errors = Vec::new();
+        for path in paths {
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            let result = fs::read_to_string(&path).map_err(|err| format!("Cannot read script {}: {}", path.display(), err))
+                .and_then(|source| self.load(&name, &source));
+            if let Err(err) = result {
+                errors.push(err);
+            }
+        }
+        errors
+    }
+
+    fn load(&mut self, name: &str, source: &str) -> Result<(), String> {
+        let ast = self.engine.compile(source).map_err(|err| format!("Cannot load script {}: {}", name, err))?;
+        if let Err(err) = self.engine.run_ast(&ast) {
+            // Leave out what it registered before failing
+            self.requests.borrow_mut().retain(|request| match request {
+                Request::Subscribe(..) | Request::Command(..) => false,
+                _ => true,
+            });
+            return Err(format!("Cannot load script {}: {}", name, err));
+        }
+        self.scripts.push(Script { name: name.to_string(), ast: ast });
+        self.register(self.scripts.len() - 1);
+        Ok(())
+    }
+
+    /// Record the handlers and commands a script just registered
+    fn register(&mut self, index: usize) {
+        let mut requests = self.requests.borrow_mut();
+        let mut remaining = Vec::new();
+        for request in requests.drain(..) {
+            match request {
+                Request::Subscribe(event, handler) => self.handlers.entry(event).or_insert_with(Vec::new).push((index, handler)),
+                Request::Command(name, handler) => { self.commands.insert(name, (index, handler)); },
+                request => remaining.push(request),
+            }
+        }
+        *requests = remaining;
+    }
+
+    fn call(&mut self, index: usize, handler: &FnPtr, argument: Dynamic) -> Result<(), String> {
+        let script = &self.scripts[index];
+        let result = handler.call::<Dynamic>(&self.engine, &script.ast, (argument,))
+            .map_err(|err| format!("Script {} failed: {}", script.name, err));
+        self.register(index);
+        result.map(|_| ())
+    }
+
+    /// Call the handlers of an event
+    fn emit(&mut self, event: &str, argument: Dynamic) {
+        let handlers = match self.handlers.get(event) {
+            Some(handlers) => handlers.clone(),
+            None => return,
+        };
+        for (index, handler) in handlers {
+            if let Err(err) = self.call(index, &handler, argument.clone()) {
+                self.requests.borrow_mut().push(Request::Log(err));
+            }
+        }
+    }
+
+    /// Run a command registered by a script, none if no script registered it
+    fn command(&mut self, command: &Command) -> Option<Result<(), String>> {
+        let (index, handler) = self.commands.get(&command.args[0]).cloned()?;
+        let args: Array = command.args[1..].iter().map(|arg| Dynamic::from(arg.clone())).collect();
+        Some(self.call(index, &handler, Dynamic::from_array(args)))
+    }
+
+    /// Carry out what the scripts asked for, once this plugin is released as running commands
+    /// may need it
+    fn flush(&mut self, aparte: Rc<Aparte>) {
+        let requests: Vec<Request> = self.requests.borrow_mut().drain(..).collect();
+        if requests.is_empty() {
+            return;
+        }
+        let task = Rc::clone(&aparte);
+        aparte.spawn(future::lazy(move || {
+            for request in requests {
+                if let Err(err) = ScriptsPlugin::run(Rc::clone(&task), request) {
+                    Rc::clone(&task).log(err);
+                }
+            }
+            future::ok(())
+        }));
+    }
+
+    fn run(aparte: Rc<Aparte>, request: Request) -> Result<(), String> {
+        match request {
+            Request::Log(text) => aparte.log(text),
+            Request::Run(command) => {
+                let command = Command::try_from(command.as_str()).map_err(|err| format!("Invalid script command {}: {}", command, err))?;
+                aparte.parse_command(command)?;
+            },
+            Request::Send(to, body) => {
+                let from: Jid = match aparte.current_connection() {
+                    Some(connection) if aparte.is_online() => connection.into(),
+                    _ => return Err(format!("Cannot send script message to {}: not connected", to)),
+                };
+                let jid = BareJid::from_str(&to).map_err(|err| format!("Invalid JID {}: {}", to, err))?;
+                let channel = match aparte.get_plugin::<ConversationPlugin>().unwrap().get(&jid) {
+                    Some(Conversation::Channel(_)) => true,
+                    _ => false,
+                };
+                let id = Uuid::new_v4().to_string();
+                let to = Jid::Bare(jid);
+                let message = match channel {
+                    true => Message::outgoing_groupchat(id, Utc::now(), &from, &to, &body),
+                    false => Message::outgoing_chat(id, Utc::now(), &from, &to, &body),
+                };
+                Rc::clone(&aparte).event(Event::Message(message.clone()));
+                if let Ok(element) = Element::try_from(message) {
+                    aparte.send(element);
+                }
+            },
+            Request::Subscribe(..) | Request::Command(..) => {},
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for ScriptsPlugin {
+    fn new() -> ScriptsPlugin {
+        let requests = Rc::new(RefCell::new(Vec::new()));
+        let config = Rc::new(RefCell::new(Map::new()));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        let queue = Rc::clone(&requests);
+        engine.register_fn("on", move |event: &str, handler: FnPtr| queue.borrow_mut().push(Request::Subscribe(event.to_string(), handler)));
+        let queue = Rc::clone(&requests);
+        engine.register_fn("register_command", move |name: &str, handler: FnPtr| queue.borrow_mut().push(Request::Command(name.to_string(), handler)));
+        let queue = Rc::clone(&requests);
+        engine.register_fn("send_message", move |to: &str, body: &str| queue.borrow_mut().push(Request::Send(to.to_string(), body.to_string())));
+        let queue = Rc::clone(&requests);
+        engine.register_fn("command", move |command: &str| queue.borrow_mut().push(Request::Run(command.to_string())));
+        let queue = Rc::clone(&requests);
+        engine.register_fn("log", move |text: &str| queue.borrow_mut().push(Request::Log(text.to_string())));
+        let queue = Rc::clone(&requests);
+        engine.on_print(move |text| queue.borrow_mut().push(Request::Log(text.to_string())));
+        let shared = Rc::clone(&config);
+        engine.register_fn("config", move || shared.borrow().clone());
+
+        Self {
+            engine: engine,
+            scripts: Vec::new(),
+            requests: requests,
+            config: config,
+            handlers: HashMap::new(),
+            commands: BTreeMap::new(),
+            dir: None,
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        if let Ok(config) = fs::read_to_string(&aparte.config_path) {
+            self.config.replace(config_map(&config));
+        }
+        self.dir = aparte.config_path.parent().map(|dir| dir.join("scripts"));
+        for err in self.reload() {
+            warn!("{}", err);
+        }
+        // Commands registered by scripts
+        aparte.provide_commands(|scripts: &ScriptsPlugin| scripts.commands(), |scripts: &mut ScriptsPlugin, aparte, command| {
+            let result = scripts.command(command);
+            scripts.flush(aparte);
+            result
+        });
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Connected(jid) => self.emit("connected", jid.to_string().into()),
+            Event::Disconnected(jid) => self.emit("disconnected", jid.to_string().into()),
+            Event::Message(message) => if let Some(map) = message_map(message) {
+                self.emit("message", Dynamic::from_map(map));
+            },
+            Event::Join(_, jid) => self.emit("join", jid.to_string().into()),
+            _ => {},
+        }
+        self.flush(aparte);
+    }
+}
+
+impl fmt::Display for ScriptsPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "User scripts")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logs(plugin: &ScriptsPlugin) -> Vec<String> {
+        plugin.requests.borrow().iter().filter_map(|request| match request {
+            Request::Log(text) => Some(text.clone()),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn test_scripts() {
+        let mut plugin = ScriptsPlugin::new();
+        plugin.config.replace(config_map(r#"
+            [accounts.work]
+            login = "me@server.tld"
+            password = "secret"
+            nick = "me"
+        "#));
+        plugin.load("greeter", r#"
+            fn greet(message) {
+                if message.body.contains("hello") {
+                    send_message(message.from, "Hello " + message.from);
+                }
+            }
+            on("message", Fn("greet"));
+            register_command("shout", |args| log(args[0].to_upper()));
+            let account = config().accounts.work;
+            print(account.nick + " " + ("password" in account));
+        "#).unwrap();
+        assert_eq!(plugin.scripts(), vec!["greeter"]);
+        assert_eq!(plugin.commands(), vec!["shout"]);
+        assert_eq!(logs(&plugin), vec!["me false"]);
+        plugin.requests.borrow_mut().clear();
+
+        let us = Jid::from_str("me@server.tld/aparte").unwrap();
+        let contact = Jid::from_str("contact@server.tld/phone").unwrap();
+        let message = Message::incoming_chat("1", Utc::now(), &contact, &us, "hello there");
+        plugin.emit("message", Dynamic::from_map(message_map(&message).unwrap()));
+        match plugin.requests.borrow().as_slice() {
+            [Request::Send(to, body)] => assert_eq!((to.as_str(), body.as_str()), ("contact@server.tld", "Hello contact@server.tld")),
+            _ => panic!("Expected a message to be sent"),
+        }
+        plugin.requests.borrow_mut().clear();
+
+        assert!(plugin.command(&Command::new(vec!["shout".to_string(), "hey".to_string()])).unwrap().is_ok());
+        assert_eq!(logs(&plugin), vec!["HEY"]);
+        assert!(plugin.command(&Command::new(vec!["whisper".to_string()])).is_none());
+    }
+
+    #[test]
+    fn test_script_errors() {
+        let mut plugin = ScriptsPlugin::new();
+        assert!(plugin.load("broken", "on(").is_err());
+        assert!(plugin.load("endless", "loop {}").is_err());
+        assert!(plugin.scripts().is_empty());
+
+        plugin.load("failing", r#"on("connected", |jid| throw "oops");"#).unwrap();
+        plugin.emit("connected", "me@server.tld/aparte".into());
+        assert_eq!(logs(&plugin).len(), 1);
+    }
+}
@@ -0,0 +1,120 @@
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use xmpp_parsers::Jid;
+
+use crate::core::{Plugin, Aparte, Event};
+use crate::message::{Message, XmppMessage};
+use crate::plugins::ui::UIPlugin;
+
+/// Cooldown assumed when the server doesn't tell how long to wait
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// Error conditions returned by channels limiting the rate of messages
+const RATE_LIMIT_CONDITIONS: &[&str] = &["resource-constraint", "policy-violation"];
+
+/// Words of channel service messages announcing a rate limit
+const RATE_LIMIT_WORDS: &[&str] = &["slow mode", "slowmode", "too many messages", "too fast"];
+
+/// Delay announced in a text, as "wait 30 seconds" or "retry in 5s"
+fn announced_delay(text: &str) -> Option<Duration> {
+    let words: Vec<&str> = text.split(|c: char| c.is_whitespace() || c == ',' || c == '.').filter(|word| !word.is_empty()).collect();
+    for (index, word) in words.iter().enumerate() {
+        let digits: String = word.chars().take_while(|c| c.is_ascii_digit()).collect();
+        if digits.is_empty() {
+            continue;
+        }
+
+        let unit = match &word[digits.len()..] {
+            "" => words.get(index + 1).cloned().unwrap_or(""),
+            unit => unit,
+        };
+        let seconds: u64 = digits.parse().ok()?;
+        if unit.starts_with("min") {
+            return Some(Duration::from_secs(seconds * 60));
+        } else if unit.starts_with('s') {
+            return Some(Duration::from_secs(seconds));
+        }
+    }
+    None
+}
+
+/// Cooldown before being allowed to talk again in a channel, if the error condition is a rate
+/// limit
+fn error_cooldown(error: &str) -> Option<Duration> {
+    match RATE_LIMIT_CONDITIONS.iter().any(|condition| error.starts_with(condition)) {
+        true => Some(announced_delay(error).unwrap_or(DEFAULT_COOLDOWN)),
+        false => None,
+    }
+}
+
+/// Cooldown announced by a message of the channel service itself
+fn service_cooldown(body: &str) -> Option<Duration> {
+    let lowercase = body.to_lowercase();
+    match RATE_LIMIT_WORDS.iter().any(|words| lowercase.contains(words)) {
+        true => Some(announced_delay(&lowercase).unwrap_or(DEFAULT_COOLDOWN)),
+        false => None,
+    }
+}
+
+/// Show how long to wait before talking again in channels enforcing a slow mode, instead of
+/// messages being rejected silently
+pub struct SlowModePlugin {
+}
+
+impl Plugin for SlowModePlugin {
+    fn new() -> SlowModePlugin {
+        Self {}
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        let (conversation, cooldown) = match event {
+            Event::MessageError(conversation, _, error) => (conversation.to_string(), error_cooldown(error)),
+            // Service messages come from the channel itself, not from an occupant
+            Event::Message(Message::Incoming(XmppMessage::Groupchat(message))) => match &*message.from_full {
+                Jid::Bare(_) => (message.from.to_string(), service_cooldown(&message.body)),
+                Jid::Full(_) => return,
+            },
+            _ => return,
+        };
+
+        if let Some(cooldown) = cooldown {
+            let mut ui = aparte.get_plugin_mut::<UIPlugin>().unwrap();
+            ui.set_cooldown(Rc::clone(&aparte), &conversation, Instant::now() + cooldown);
+        }
+    }
+}
+
+impl fmt::Display for SlowModePlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Slow mode")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announced_delay() {
+        assert_eq!(announced_delay("Please wait 30 seconds."), Some(Duration::from_secs(30)));
+        assert_eq!(announced_delay("retry in 5s"), Some(Duration::from_secs(5)));
+        assert_eq!(announced_delay("slow mode: 2 min"), Some(Duration::from_secs(120)));
+        assert_eq!(announced_delay("room 42 is full"), None);
+    }
+
+    #[test]
+    fn test_cooldowns() {
+        assert_eq!(error_cooldown("resource-constraint: wait 20 seconds"), Some(Duration::from_secs(20)));
+        assert_eq!(error_cooldown("policy-violation"), Some(DEFAULT_COOLDOWN));
+        assert_eq!(error_cooldown("item-not-found"), None);
+
+        assert_eq!(service_cooldown("Slow mode is enabled, one message every 15 seconds"), Some(Duration::from_secs(15)));
+        assert_eq!(service_cooldown("You are sending too many messages"), Some(DEFAULT_COOLDOWN));
+        assert_eq!(service_cooldown("Topic changed"), None);
+    }
+}
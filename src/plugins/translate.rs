@@ -0,0 +1,200 @@
+use futures::{future, Future};
+use futures::sync::oneshot;
+use hyper::{Body, Request};
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::thread;
+use tokio::runtime::current_thread::TaskExecutor;
+
+use crate::config;
+use crate::https;
+use crate::core::{Plugin, Aparte, Event};
+use crate::message::{self, Message, XmppMessage};
+use crate::plugins::ui::UIPlugin;
+
+/// Number of messages per conversation that can be translated
+const MAX_MESSAGES: usize = 100;
+
+/// Language of a locale, as "pt-BR" for "pt_BR.UTF-8"
+fn locale_lang(locale: &str) -> Option<String> {
+    let lang = locale.split(|c| c == '.' || c == '@').next().unwrap_or("").replace('_', "-");
+    // The C and POSIX locales don't tell any language
+    match message::is_lang_tag(&lang) && lang != "C" && lang != "POSIX" {
+        true => Some(lang),
+        false => None,
+    }
+}
+
+/// Size of the largest translation read from the translation endpoint
+const MAX_TRANSLATION_SIZE: usize = 1 << 20;
+
+/// Translation written by the translation command or answered by the translation endpoint
+fn translation(output: &[u8]) -> Result<String, String> {
+    let translation = String::from_utf8_lossy(output).trim().to_string();
+    match translation.is_empty() {
+        false => Ok(translation),
+        true => Err(format!("Translation failed")),
+    }
+}
+
+/// Run the translation command with the text to translate, in a thread not to block the event loop
+fn run(command: String, text: String) -> impl Future<Item = String, Error = String> {
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        let output = Command::new("sh").arg("-c").arg(&command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .and_then(|mut child| {
+                child.stdin.take().unwrap().write_all(text.as_bytes())?;
+                child.wait_with_output()
+            });
+        let _ = tx.send(match output {
+            Ok(output) if output.status.success() => translation(&output.stdout),
+            Ok(_) => Err(format!("Translation failed")),
+            Err(err) => Err(format!("Cannot run the translation command {}: {}", command, err)),
+        });
+    });
+    rx.map_err(|_| format!("Translation failed")).and_then(|result| result)
+}
+
+/// Post the text to translate to the translation endpoint
+fn post(url: String, text: String) -> Box<dyn Future<Item = String, Error = String>> {
+    let request = Request::post(url.as_str())
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(Body::from(text));
+    match request {
+        Ok(request) => Box::new(https::download_configured(request, MAX_TRANSLATION_SIZE)
+            .map_err(|err| format!("Cannot translate: {}", err))
+            .and_then(|output| translation(&output))),
+        Err(err) => Box::new(future::err(format!("Invalid translation URL {}: {}", url, err))),
+    }
+}
+
+/// Translate messages on demand with an external command or HTTP service, the translation being
+/// displayed beneath the original message
+pub struct TranslatePlugin {
+    config: config::Translation,
+    lang: String,
+    messages: HashMap<String, VecDeque<Message>>,
+}
+
+impl TranslatePlugin {
+    fn add(&mut self, conversation: String, message: &Message) {
+        let messages = self.messages.entry(conversation).or_insert_with(VecDeque::new);
+        messages.push_back(message.clone());
+        if messages.len() > MAX_MESSAGES {
+            messages.pop_front();
+        }
+    }
+
+    /// Message of a conversation counting back from the last one, which is 1
+    fn get(&self, conversation: &str, index: usize) -> Result<&Message, String> {
+        let messages = self.messages.get(conversation).ok_or(format!("No message in {}", conversation))?;
+        match index {
+            index if index >= 1 && index <= messages.len() => Ok(&messages[messages.len() - index]),
+            _ => Err(format!("No message {} in {}", index, conversation)),
+        }
+    }
+
+    /// Translate a message of a conversation, counting back from the last one
+    pub fn translate(&self, aparte: Rc<Aparte>, conversation: &str, index: usize) -> Result<(), String> {
+        let message = self.get(conversation, index)?.clone();
+        let command = self.config.command.as_ref().map(|command| command.replace("{lang}", &self.lang));
+        let url = self.config.url.as_ref().map(|url| url.replace("{lang}", &self.lang));
+        if command.is_none() && url.is_none() {
+            return Err(format!("No translation service configured, set command or url in the [translation] section of the config file"));
+        }
+
+        let text = message.body().to_string();
+        let translation = match (command, url) {
+            (Some(command), _) => Box::new(run(command, text)),
+            (None, Some(url)) => post(url, text),
+            (None, None) => unreachable!(),
+        };
+
+        let display = translation.then(move |result| {
+            match result {
+                Ok(translation) => {
+                    let mut message = message;
+                    message.set_translation(&translation);
+                    let mut ui = aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                    ui.replace_message(Rc::clone(&aparte), &message);
+                },
+                Err(err) => aparte.log(err),
+            }
+            Ok(())
+        });
+
+        TaskExecutor::current().spawn_local(Box::new(display)).map_err(|err| format!("Cannot translate: {:?}", err))
+    }
+}
+
+impl Plugin for TranslatePlugin {
+    fn new() -> TranslatePlugin {
+        Self {
+            config: config::Translation::default(),
+            lang: String::from("en"),
+            messages: HashMap::new(),
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        self.config = aparte.config.translation.clone();
+        let locale = ["LC_ALL", "LC_MESSAGES", "LANG"].iter().filter_map(|name| env::var(name).ok()).find(|value| !value.is_empty());
+        if let Some(lang) = self.config.lang.clone().or(locale.as_deref().and_then(locale_lang)) {
+            self.lang = lang;
+        }
+        Ok(())
+    }
+
+    fn on_event(&mut self, _aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::Message(message @ Message::Incoming(XmppMessage::Chat(chat))) => self.add(chat.from.to_string(), message),
+            Event::Message(message @ Message::Incoming(XmppMessage::Groupchat(groupchat))) => self.add(groupchat.from.to_string(), message),
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for TranslatePlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Translation")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use std::str::FromStr;
+    use xmpp_parsers::Jid;
+
+    #[test]
+    fn test_locale_lang() {
+        assert_eq!(locale_lang("fr_FR.UTF-8"), Some(String::from("fr-FR")));
+        assert_eq!(locale_lang("de_DE@euro"), Some(String::from("de-DE")));
+        assert_eq!(locale_lang("C.UTF-8"), None);
+        assert_eq!(locale_lang(""), None);
+    }
+
+    #[test]
+    fn test_message_index() {
+        let us = Jid::from_str("me@server.tld/aparte").unwrap();
+        let contact = Jid::from_str("contact@server.tld/phone").unwrap();
+
+        let mut plugin = TranslatePlugin::new();
+        plugin.add(String::from("contact@server.tld"), &Message::incoming_chat("1", Utc::now(), &contact, &us, "Hallo"));
+        plugin.add(String::from("contact@server.tld"), &Message::incoming_chat("2", Utc::now(), &contact, &us, "Wie geht's?"));
+
+        assert_eq!(plugin.get("contact@server.tld", 1).unwrap().id(), "2");
+        assert_eq!(plugin.get("contact@server.tld", 2).unwrap().id(), "1");
+        assert!(plugin.get("contact@server.tld", 3).is_err());
+        assert!(plugin.get("other@server.tld", 1).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use chrono::Utc;
+use futures::future;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::{BareJid, Element, FullJid, Jid};
+
+use crate::command::Command;
+use crate::config::{PresenceChange, Trigger};
+use crate::contact;
+use crate::core::{Plugin, Aparte, Event};
+use crate::message::Message;
+use crate::plugins::notifications::{Alert, NotificationsPlugin};
+
+/// What a rule does when it fires
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Notify,
+    /// Command run as typed in the input
+    Command(String),
+    /// Message sent to the contact, the rule being removed once sent
+    Send(String),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Notify => write!(f, "notify"),
+            Action::Command(command) => write!(f, "command {}", command),
+            Action::Send(message) => write!(f, "send \"{}\"", message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub jid: BareJid,
+    pub on: PresenceChange,
+    pub action: Action,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "when {} goes {}: {}", self.jid, self.on, self.action)
+    }
+}
+
+/// Rules of a trigger from the config, one per action
+fn rules(trigger: &Trigger) -> Result<Vec<Rule>, String> {
+    let jid = BareJid::from_str(&trigger.jid).map_err(|err| format!("Invalid JID {} in triggers config: {}", trigger.jid, err))?;
+    let mut actions = Vec::new();
+    if trigger.notify {
+        actions.push(Action::Notify);
+    }
+    if let Some(command) = &trigger.command {
+        actions.push(Action::Command(command.clone()));
+    }
+    if let Some(message) = &trigger.message {
+        actions.push(Action::Send(message.clone()));
+    }
+    if actions.is_empty() {
+        return Err(format!("Trigger for {} without action, set notify, command or message", jid));
+    }
+    Ok(actions.into_iter().map(|action| Rule { jid: jid.clone(), on: trigger.on, action: action }).collect())
+}
+
+/// Actions run when contacts come online or go offline, as the watch lists of IRC clients
+pub struct TriggersPlugin {
+    rules: Vec<Rule>,
+    online: contact::Availability,
+}
+
+impl TriggersPlugin {
+    pub fn add(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<Rule, String> {
+        match index < self.rules.len() {
+            true => Ok(self.rules.remove(index)),
+            false => Err(format!("No trigger {}", index)),
+        }
+    }
+
+    /// Rules numbered as expected by remove
+    pub fn list(&self) -> Vec<String> {
+        self.rules.iter().enumerate().map(|(index, rule)| format!("  {}: {}", index, rule)).collect()
+    }
+
+    /// Handle the presence of a resource, returns the contact's change if it came online or went
+    /// offline with the actions of the rules it fires
+    fn presence(&mut self, jid: &FullJid, presence: &contact::Presence) -> Option<(PresenceChange, Vec<Action>)> {
+        let change = self.online.update(jid, presence)?;
+        let contact: BareJid = jid.clone().into();
+
+        let mut actions = Vec::new();
+        self.rules.retain(|rule| {
+            if rule.jid != contact || rule.on != change {
+                return true;
+            }
+            actions.push(rule.action.clone());
+            match rule.action {
+                Action::Send(_) => false,
+                _ => true,
+            }
+        });
+        Some((change, actions))
+    }
+
+    fn run(aparte: Rc<Aparte>, jid: &FullJid, change: PresenceChange, action: Action) {
+        match action {
+            Action::Notify => {
+                let contact: BareJid = jid.clone().into();
+                let summary = format!("{} is {}", contact, change);
+                Rc::clone(&aparte).log(summary.clone());
+                if let Some(notifications) = aparte.get_plugin::<NotificationsPlugin>() {
+                    notifications.alert(Rc::clone(&aparte), Alert::Status, &summary, "");
+                }
+            },
+            Action::Command(command) => {
+                // Commands may need this plugin, which is borrowed until the event is handled
+                tokio::runtime::current_thread::spawn(future::lazy(move || {
+                    let result = Command::try_from(command.as_str()).map_err(String::from).and_then(|command| Rc::clone(&aparte).parse_command(command));
+                    if let Err(err) = result {
+                        aparte.log(format!("Trigger command {} failed: {}", command, err));
+                    }
+                    future::ok(())
+                }));
+            },
+            Action::Send(body) => {
+                let from: Jid = match aparte.current_connection() {
+                    Some(connection) => connection.into(),
+                    None => return,
+                };
+                let to = Jid::Bare(jid.clone().into());
+                let message = Message::outgoing_chat(Uuid::new_v4().to_string(), Utc::now(), &from, &to, &body);
+                Rc::clone(&aparte).event(Event::Message(message.clone()));
+                if let Ok(element) = Element::try_from(message) {
+                    aparte.send(element);
+                }
+            },
+        }
+    }
+}
+
+impl Plugin for TriggersPlugin {
+    fn new() -> TriggersPlugin {
+        Self {
+            rules: Vec::new(),
+            online: contact::Availability::default(),
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        for trigger in &aparte.config.triggers {
+            match rules(trigger) {
+                Ok(rules) => self.rules.extend(rules),
+                Err(err) => warn!("{}", err),
+            }
+        }
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::ResourcePresence(jid, presence) => {
+                if let Some((change, actions)) = self.presence(jid, presence) {
+                    for action in actions {
+                        TriggersPlugin::run(Rc::clone(&aparte), jid, change, action);
+                    }
+                }
+            },
+            // Contacts aren't going offline, we are
+            Event::Disconnected(_) => self.online.clear(),
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for TriggersPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Presence triggers")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_triggers() {
+        let config: Config = toml::from_str(r#"
+            [accounts]
+
+            [[triggers]]
+            jid = "alice@server.tld"
+            notify = true
+            message = "Call me back"
+
+            [[triggers]]
+            jid = "alice@server.tld"
+            on = "offline"
+            command = "/notify none alice@server.tld"
+        "#).unwrap();
+        let mut plugin = TriggersPlugin::new();
+        for trigger in &config.triggers {
+            plugin.rules.extend(rules(trigger).unwrap());
+        }
+        assert_eq!(plugin.list(), vec![
+            "  0: when alice@server.tld goes online: notify",
+            "  1: when alice@server.tld goes online: send \"Call me back\"",
+            "  2: when alice@server.tld goes offline: command /notify none alice@server.tld",
+        ]);
+
+        let phone = FullJid::from_str("alice@server.tld/phone").unwrap();
+        let laptop = FullJid::from_str("alice@server.tld/laptop").unwrap();
+        assert_eq!(plugin.presence(&phone, &contact::Presence::Available), Some((PresenceChange::Online, vec![Action::Notify, Action::Send(String::from("Call me back"))])));
+        // Already online
+        assert_eq!(plugin.presence(&laptop, &contact::Presence::Away), None);
+        assert_eq!(plugin.presence(&phone, &contact::Presence::Dnd), None);
+        assert_eq!(plugin.presence(&phone, &contact::Presence::Unavailable), None);
+        assert_eq!(plugin.presence(&laptop, &contact::Presence::Unavailable), Some((PresenceChange::Offline, vec![Action::Command(String::from("/notify none alice@server.tld"))])));
+
+        // The message is only sent once
+        assert_eq!(plugin.presence(&phone, &contact::Presence::Available), Some((PresenceChange::Online, vec![Action::Notify])));
+        assert_eq!(plugin.presence(&FullJid::from_str("bob@server.tld/phone").unwrap(), &contact::Presence::Available), Some((PresenceChange::Online, vec![])));
+
+        assert!(plugin.remove(3).is_err());
+        assert_eq!(plugin.remove(0).unwrap().action, Action::Notify);
+    }
+
+    #[test]
+    fn test_trigger_without_action() {
+        let trigger: Trigger = toml::from_str(r#"jid = "alice@server.tld""#).unwrap();
+        assert!(rules(&trigger).is_err());
+    }
+}
@@ -1,47 +1,163 @@
 use bytes::BytesMut;
-use chrono::Utc;
+use futures::{future, Future, Stream};
+use futures::sync::mpsc;
+use chrono::{DateTime, Utc};
 use chrono::offset::{TimeZone, Local};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
+use std::env;
 use std::fmt;
+use std::fs;
 use std::io::{Error as IoError, ErrorKind};
-use std::io::{Write, Stdout};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process;
 use std::rc::Rc;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
-use termion::color;
-use termion::event::Key;
+use std::time::{Duration, Instant};
+use termion::event::{Event as TermEvent, Key, MouseButton, MouseEvent};
 use termion::input::TermRead;
-use termion::raw::{IntoRawMode, RawTerminal};
+use termion::raw::IntoRawMode;
 use termion::screen::AlternateScreen;
 use tokio::codec::FramedRead;
+use tokio::runtime::current_thread::TaskExecutor;
+use tokio::timer::Delay;
 use tokio_codec::{Decoder};
 use uuid::Uuid;
 use xmpp_parsers::{BareJid, Jid};
 
 use crate::core::{Plugin, Aparte, Event, CommandOrMessage};
-use crate::{contact, conversation};
-use crate::message::{Message, XmppMessage};
+use crate::plugins::confirm::ConfirmPlugin;
+use crate::plugins::contact::ContactPlugin;
+use crate::plugins::conversation::ConversationPlugin;
+use crate::plugins::history::HistoryPlugin;
+use crate::plugins::rooms;
+use crate::plugins::urls::{self, UrlsPlugin};
+use crate::{clipboard, config, contact, conversation, fuzzy, i18n, profile, theme};
+use crate::message::{self, Message, XmppMessage};
 use crate::command::{Command, CommandError};
-use crate::terminus::{View, ViewTrait, Dimension, LinearLayout, FrameLayout, Input, Orientation, BufferedWin, Window, ListView};
+use crate::macros::{Macros, Notice};
+use crate::relay::Relay;
+use crate::spell::{self, Speller};
+use crate::terminus::{View, ViewTrait, Dimension, LinearLayout, FrameLayout, Input, Orientation, BufferedWin, Window, ListView, Screen, BUFFER_SIZE, term_string_visible_len};
+#[cfg(test)]
+use crate::terminus::Offscreen;
 
-pub type CommandStream = FramedRead<tokio::reactor::PollEvented2<tokio_file_unix::File<std::fs::File>>, KeyCodec>;
-type Screen = AlternateScreen<RawTerminal<Stdout>>;
+pub type CommandStream = Box<dyn Stream<Item = CommandOrMessage, Error = CommandError>>;
 
 enum UIEvent<'a> {
     Key(Key),
     Validate(Rc<RefCell<Option<(String, bool)>>>),
     Complete(Rc<RefCell<Option<(String, usize, bool)>>>),
+    // Complete the emoji shortcode being typed, telling whether there was one
+    CompleteEmoji(Rc<RefCell<bool>>),
     Completed(String),
+    InputContent(Rc<RefCell<Option<String>>>),
+    SetInput(String),
+    // Status shown at the right of the input
+    InputStatus(Option<String>),
+    // Spell checker of the input, for the conversation of the current window
+    Speller(Option<Rc<RefCell<Speller>>>),
+    /// Hunspell answered about a word of the input
+    Spelled,
+    // Text pasted in the input at once
+    Paste(String),
+    // Message selected in the current window, the search match or the last one
+    Selected(Rc<RefCell<Option<Message>>>),
+    // Mouse wheel over the current window, up or down
+    Scroll(bool),
+    // Mouse click at a position of the screen, with what was clicked
+    Click(u16, u16, Rc<RefCell<Option<Click>>>),
+    // Log message displayed in the given window instead of the console
+    WindowLog(String, Message),
+    // Error returned by the server for a message of a conversation, with the message id
+    MessageError(String, Option<String>, String),
+    ReplaceMessage(String, Message),
+    ClearWindow(String),
+    // Keep every message of a window from now on, its messages no longer being stored
+    Unlimit(String),
+    Notes(HashMap<BareJid, String>),
     ReadPassword,
     Connected(String),
+    // Warning shown in the status bar, as a connection being lost
+    Warning(Option<String>),
+    // Register of the keyboard macro being recorded
+    Recording(Option<char>),
+    // Status bar inverted by a visual bell
+    Flash(bool),
     Message(Message),
+    Activity(String, bool),
+    // Unread messages and mentions of each window with activity
+    Unread(Rc<RefCell<Vec<(String, usize, usize)>>>),
+    Search(String, bool),
+    EndSearch(bool),
+    HistorySearch(String, bool),
+    EndHistorySearch(bool),
+    LoadInputHistory(HashMap<String, Vec<String>>),
+    InputHistory(Rc<RefCell<HashMap<String, Vec<String>>>>),
+    RosterFocus(bool),
+    RosterKey(Key),
+    RosterSelect(Rc<RefCell<Option<BareJid>>>),
+    RosterGrouping(config::RosterGrouping),
+    Rooms(String, Vec<rooms::Room>),
+    Room(rooms::Room),
+    RoomsKey(Key),
+    RoomsSelect(Rc<RefCell<Option<BareJid>>>),
     AddWindow(String, Option<Box<dyn ViewTrait<UIEvent<'a>> + 'a>>),
     ChangeWindow(String),
+    Split(Orientation, String),
+    Only,
+    Panes(Rc<RefCell<Vec<String>>>),
+    Theme,
+    Flush,
     Contact(contact::Contact),
     ContactUpdate(contact::Contact),
     Occupant(conversation::Occupant),
+    // Occupant removed from the occupants list of a channel window, by nick
+    RemoveOccupant(String, String),
+}
+
+/// What a mouse click landed on
+#[derive(Debug, Clone, PartialEq)]
+enum Click {
+    Window(String),
+    Url(String),
+    Contact(BareJid),
+}
+
+/// Fuzzy finder of the windows and contacts to switch to, drawn over the windows
+struct Switcher {
+    pattern: String,
+    // Window or contact to switch to, with the label the pattern is matched against
+    candidates: Vec<(String, String)>,
+    // Candidates matching the pattern, best first
+    matches: Vec<usize>,
+    selected: usize,
+}
+
+impl Switcher {
+    fn new(candidates: Vec<(String, String)>) -> Self {
+        let matches = (0..candidates.len()).collect();
+        Self {
+            pattern: String::new(),
+            candidates: candidates,
+            matches: matches,
+            selected: 0,
+        }
+    }
+
+    fn refine(&mut self) {
+        let labels: Vec<&str> = self.candidates.iter().map(|(_, label)| label.as_str()).collect();
+        self.matches = fuzzy::filter(&self.pattern, &labels);
+        self.selected = 0;
+    }
+
+    fn selected(&self) -> Option<&str> {
+        self.matches.get(self.selected).map(|index| self.candidates[*index].0.as_str())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -96,7 +212,7 @@ impl ViewTrait<UIEvent<'_>> for View<'_, TitleBar, UIEvent<'_>> {
             let mut screen = self.screen.borrow_mut();
 
             write!(screen, "{}", termion::cursor::Goto(self.x, self.y)).unwrap();
-            write!(screen, "{}{}", color::Bg(color::Blue), color::Fg(color::White)).unwrap();
+            write!(screen, "{}", theme::current().bar).unwrap();
 
             for _ in 0 .. self.w.unwrap() {
                 write!(screen, " ").unwrap();
@@ -106,7 +222,7 @@ impl ViewTrait<UIEvent<'_>> for View<'_, TitleBar, UIEvent<'_>> {
                 write!(screen, " {}", window_name).unwrap();
             }
 
-            write!(screen, "{}{}", color::Bg(color::Reset), color::Fg(color::Reset)).unwrap();
+            write!(screen, "{}", termion::style::Reset).unwrap();
         }
 
         self.restore_cursor();
@@ -123,11 +239,25 @@ impl ViewTrait<UIEvent<'_>> for View<'_, TitleBar, UIEvent<'_>> {
     }
 }
 
+/// Unread messages received in a window while it wasn't the current one
+#[derive(Default)]
+struct Activity {
+    unread: usize,
+    mentions: usize,
+}
+
 struct WinBar {
     connection: Option<String>,
+    warning: Option<String>,
+    recording: Option<char>,
+    flash: bool,
     windows: Vec<String>,
     current_window: Option<String>,
-    highlighted: Vec<String>,
+    activity: HashMap<String, Activity>,
+    // Whether activity changed since last redraw
+    unflushed: bool,
+    // Columns each window is drawn between, to be clicked
+    spans: Vec<(u16, u16, String)>,
 }
 
 impl View<'_, WinBar, UIEvent<'_>> {
@@ -147,9 +277,14 @@ impl View<'_, WinBar, UIEvent<'_>> {
             cursor_y: None,
             content: WinBar {
                 connection: None,
+                warning: None,
+                recording: None,
+                flash: false,
                 windows: Vec::new(),
                 current_window: None,
-                highlighted: Vec::new(),
+                activity: HashMap::new(),
+                unflushed: false,
+                spans: Vec::new(),
             },
             event_handler: None,
         }
@@ -163,27 +298,41 @@ impl View<'_, WinBar, UIEvent<'_>> {
 
     fn set_current_window(&mut self, window: &str) {
         self.content.current_window = Some(window.to_string());
-        self.content.highlighted.drain_filter(|w| w == &window);
+        self.content.activity.remove(window);
         self.redraw();
     }
 
-    fn highlight_window(&mut self, window: &str) {
-        if self.content.highlighted.iter().find(|w| w == &window).is_none() {
-            self.content.highlighted.push(window.to_string());
-            self.redraw();
+    fn add_activity(&mut self, window: &str, mention: bool) {
+        if self.content.current_window.as_deref() == Some(window) {
+            return;
+        }
+
+        let activity = self.content.activity.entry(window.to_string()).or_insert_with(Activity::default);
+        activity.unread += 1;
+        if mention {
+            activity.mentions += 1;
         }
+        self.content.unflushed = true;
     }
 }
 
 impl ViewTrait<UIEvent<'_>> for View<'_, WinBar, UIEvent<'_>> {
     fn redraw(&mut self) {
+        self.content.unflushed = false;
         self.save_cursor();
 
         {
             let mut screen = self.screen.borrow_mut();
 
+            let theme = theme::current();
+            // Styles reset the attributes, a visual bell inverts the bar again after each of them
+            let invert = match self.content.flash {
+                true => termion::style::Invert.to_string(),
+                false => String::new(),
+            };
+
             write!(screen, "{}", termion::cursor::Goto(self.x, self.y)).unwrap();
-            write!(screen, "{}{}", color::Bg(color::Blue), color::Fg(color::White)).unwrap();
+            write!(screen, "{}{}", theme.bar, invert).unwrap();
 
             for _ in 0 .. self.w.unwrap() {
                 write!(screen, " ").unwrap();
@@ -193,25 +342,41 @@ impl ViewTrait<UIEvent<'_>> for View<'_, WinBar, UIEvent<'_>> {
             if let Some(connection) = &self.content.connection {
                 write!(screen, " {}", connection).unwrap();
             }
+            if let Some(warning) = &self.content.warning {
+                write!(screen, " {}{}⚠ {}{}{}", theme.error, invert, warning, theme.bar, invert).unwrap();
+            }
+            if let Some(register) = self.content.recording {
+                write!(screen, " recording @{}", register).unwrap();
+            }
 
             let mut windows = String::new();
             let mut windows_len = 0;
+            let mut spans = Vec::new();
 
             let mut index = 1;
             for window in &self.content.windows {
                 if let Some(current) = &self.content.current_window {
                     if window == current {
                         let win = format!("-{}: {}- ", index, window);
+                        spans.push((windows_len, win.len() - 1, window.clone()));
                         windows_len += win.len();
                         windows.push_str(&win);
                     } else {
-                        if self.content.highlighted.iter().find(|w| w == &window).is_some() {
-                            windows.push_str(&format!("{}", termion::style::Bold));
-                        }
-                        let win = format!("[{}: {}] ", index, window);
+                        let win = match self.content.activity.get(window) {
+                            Some(activity) if activity.mentions > 0 => {
+                                windows.push_str(&format!("{}{}", theme.bar_mention, invert));
+                                format!("[{}: {} ({}, @{})] ", index, window, activity.unread, activity.mentions)
+                            },
+                            Some(activity) => {
+                                windows.push_str(&format!("{}{}", theme.bar_activity, invert));
+                                format!("[{}: {} ({})] ", index, window, activity.unread)
+                            },
+                            None => format!("[{}: {}] ", index, window),
+                        };
+                        spans.push((windows_len, win.len() - 1, window.clone()));
                         windows_len += win.len();
                         windows.push_str(&win);
-                        windows.push_str(&format!("{}", termion::style::NoBold));
+                        windows.push_str(&format!("{}{}", theme.bar, invert));
                     }
                 }
                 index += 1;
@@ -219,8 +384,9 @@ impl ViewTrait<UIEvent<'_>> for View<'_, WinBar, UIEvent<'_>> {
 
             let start = self.x + self.w.unwrap() - windows_len as u16;
             write!(screen, "{}{}", termion::cursor::Goto(start, self.y), windows).unwrap();
+            self.content.spans = spans.into_iter().map(|(offset, len, window)| (start + offset as u16, start + (offset + len) as u16, window)).collect();
 
-            write!(screen, "{}{}", color::Bg(color::Reset), color::Fg(color::Reset)).unwrap();
+            write!(screen, "{}", termion::style::Reset).unwrap();
         }
 
         self.restore_cursor();
@@ -235,266 +401,1756 @@ impl ViewTrait<UIEvent<'_>> for View<'_, WinBar, UIEvent<'_>> {
             UIEvent::AddWindow(name, _) => {
                 self.add_window(name);
             }
+            UIEvent::Activity(window, mention) => {
+                self.add_activity(window, *mention);
+            }
+            UIEvent::Unread(result) => {
+                let mut unread: Vec<(String, usize, usize)> = self.content.activity.iter()
+                    .map(|(window, activity)| (window.clone(), activity.unread, activity.mentions))
+                    .collect();
+                unread.sort();
+                result.replace(unread);
+            }
             UIEvent::Connected(jid) => {
                 self.content.connection = Some(jid.clone());
                 self.redraw();
             }
+            UIEvent::Warning(warning) => {
+                self.content.warning = warning.clone();
+                self.redraw();
+            }
+            UIEvent::Recording(register) => {
+                self.content.recording = *register;
+                self.redraw();
+            }
+            UIEvent::Flash(flash) => {
+                self.content.flash = *flash;
+                self.redraw();
+            }
+            UIEvent::Flush => {
+                if self.content.unflushed {
+                    self.redraw();
+                }
+            }
+            UIEvent::Click(x, y, result) if *y == self.y => {
+                if let Some((_, _, window)) = self.content.spans.iter().find(|(start, end, _)| *x >= *start && *x < *end) {
+                    result.replace(Some(Click::Window(window.clone())));
+                }
+            }
             _ => {},
         }
     }
 }
 
-impl fmt::Display for Message {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Message::Log(message) => {
-                let timestamp = Local.from_utc_datetime(&message.timestamp.naive_local());
-                for line in message.body.lines() {
-                    write!(f, "{} - {}\n", timestamp.format("%T"), line);
-                }
+enum RosterRow {
+    Group(contact::Group, bool, usize, usize),
+    Contact(contact::Contact),
+}
 
-                Ok(())
-            },
-            Message::Incoming(XmppMessage::Chat(message)) => {
-                let timestamp = Local.from_utc_datetime(&message.timestamp.naive_local());
-                let padding_len = format!("{} - {}: ", timestamp.format("%T"), message.from).len();
-                let padding = " ".repeat(padding_len);
+/// Group of a contact when grouping by server. Contacts bridged through a gateway escaping the
+/// remote address with %, as IRC ones, are grouped by remote server.
+fn server_group(jid: &BareJid) -> contact::Group {
+    match jid.node.as_ref().and_then(|node| node.rfind('%').map(|index| &node[index + 1..])) {
+        Some(remote) if !remote.is_empty() => contact::Group(format!("{} via {}", remote, jid.domain)),
+        _ => contact::Group(jid.domain.clone()),
+    }
+}
 
-                write!(f, "{} - {}{}:{} ", timestamp.format("%T"), color::Fg(color::Green), message.from, color::Fg(color::White))?;
+struct Roster {
+    contacts: HashMap<BareJid, contact::Contact>,
+    grouping: config::RosterGrouping,
+    unread: HashMap<String, usize>,
+    collapsed: HashSet<contact::Group>,
+    current_window: Option<String>,
+    visible: bool,
+    focused: bool,
+    selected: usize,
+    notes: HashMap<BareJid, String>,
+    // Rows with their rendering and its visible length, computed again only when the roster
+    // changes
+    rendered: Option<Rc<Vec<(RosterRow, String, usize)>>>,
+}
 
-                let mut iter = message.body.lines();
-                if let Some(line) = iter.next() {
-                    write!(f, "{}", line)?;
-                }
-                while let Some(line) = iter.next() {
-                    write!(f, "\n{}{}", padding, line)?;
-                }
+fn presence_rank(presence: &contact::Presence) -> u8 {
+    match presence {
+        contact::Presence::Chat => 0,
+        contact::Presence::Available => 1,
+        contact::Presence::Away => 2,
+        contact::Presence::Xa => 3,
+        contact::Presence::Dnd => 4,
+        contact::Presence::Unavailable => 5,
+    }
+}
 
-                Ok(())
-            },
-            Message::Outgoing(XmppMessage::Chat(message)) => {
-                let timestamp = Local.from_utc_datetime(&message.timestamp.naive_local());
-                write!(f, "{} - {}me:{} {}", timestamp.format("%T"), color::Fg(color::Yellow), color::Fg(color::White), message.body)
+impl Roster {
+    /// Contacts grouped by roster group, or by server, and sorted by presence then name.
+    /// Contacts without group come first, without header.
+    fn rows(&self) -> Vec<RosterRow> {
+        let mut groups: BTreeMap<Option<&contact::Group>, Vec<&contact::Contact>> = BTreeMap::new();
+        let servers: HashMap<&BareJid, contact::Group> = match self.grouping {
+            config::RosterGrouping::Servers => self.contacts.keys().map(|jid| (jid, server_group(jid))).collect(),
+            config::RosterGrouping::Groups => HashMap::new(),
+        };
+        for contact in self.contacts.values() {
+            if let Some(server) = servers.get(&contact.jid) {
+                groups.entry(Some(server)).or_insert_with(Vec::new).push(contact);
+                continue;
             }
-            Message::Incoming(XmppMessage::Groupchat(message)) => {
-                if let Jid::Full(from) = &message.from_full {
-                    let timestamp = Local.from_utc_datetime(&message.timestamp.naive_local());
-                    let padding_len = format!("{} - {}: ", timestamp.format("%T"), from.resource).len();
-                    let padding = " ".repeat(padding_len);
 
-                    write!(f, "{} - {}{}:{} ", timestamp.format("%T"), color::Fg(color::Green), from.resource, color::Fg(color::White))?;
+            if contact.groups.is_empty() {
+                groups.entry(None).or_insert_with(Vec::new).push(contact);
+            }
+            for group in &contact.groups {
+                groups.entry(Some(group)).or_insert_with(Vec::new).push(contact);
+            }
+        }
 
-                    let mut iter = message.body.lines();
-                    if let Some(line) = iter.next() {
-                        write!(f, "{}", line)?;
-                    }
-                    while let Some(line) = iter.next() {
-                        write!(f, "\n{}{}", padding, line)?;
-                    }
+        let mut rows = Vec::new();
+        for (group, mut contacts) in groups {
+            contacts.sort_by_key(|contact| {
+                let name = contact.name.clone().unwrap_or_else(|| contact::display_jid(&contact.jid));
+                (presence_rank(&contact.presence), name.to_lowercase())
+            });
+
+            let collapsed = match group {
+                Some(group) => {
+                    let online = contacts.iter().filter(|contact| contact.presence != contact::Presence::Unavailable).count();
+                    let collapsed = self.collapsed.contains(group);
+                    rows.push(RosterRow::Group(group.clone(), collapsed, online, contacts.len()));
+                    collapsed
+                },
+                None => false,
+            };
+
+            if !collapsed {
+                rows.extend(contacts.into_iter().map(|contact| RosterRow::Contact(contact.clone())));
+            }
+        }
+
+        rows
+    }
+
+    fn rendered(&mut self) -> Rc<Vec<(RosterRow, String, usize)>> {
+        if self.rendered.is_none() {
+            let rows = self.rows().into_iter().map(|row| {
+                let formatted = self.format_row(&row);
+                let len = term_string_visible_len(&formatted);
+                (row, formatted, len)
+            }).collect();
+            self.rendered = Some(Rc::new(rows));
+        }
+
+        Rc::clone(self.rendered.as_ref().unwrap())
+    }
+
+    fn format_row(&self, row: &RosterRow) -> String {
+        match row {
+            RosterRow::Group(group, collapsed, online, total) => {
+                let arrow = if *collapsed { "▸" } else { "▾" };
+                format!("{} {} ({}/{})", arrow, group, online, total)
+            },
+            RosterRow::Contact(contact) => {
+                let note = match self.notes.contains_key(&contact.jid) {
+                    true => " ✎",
+                    false => "",
+                };
+                match self.unread.get(&contact.jid.to_string()) {
+                    Some(unread) => {
+                        let theme = theme::current();
+                        format!("  {}{} {}[{}]{}", contact, note, theme.highlight, unread, theme.text)
+                    },
+                    None => format!("  {}{}", contact, note),
                 }
-                Ok(())
             },
-            Message::Outgoing(XmppMessage::Groupchat(message)) => {
-                let timestamp = Local.from_utc_datetime(&message.timestamp.naive_local());
-                write!(f, "{} - {}me:{} {}", timestamp.format("%T"), color::Fg(color::Yellow), color::Fg(color::White), message.body)
-            }
         }
     }
 }
 
-impl fmt::Display for contact::Group {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}{}{}", color::Fg(color::Yellow), self.0, color::Fg(color::White))
+impl View<'_, Roster, UIEvent<'_>> {
+    fn new(screen: Rc<RefCell<Screen>>) -> Self {
+        Self {
+            screen: screen,
+            width: Dimension::WrapContent,
+            height: Dimension::MatchParent,
+            x: 0,
+            y: 0,
+            w: None,
+            h: None,
+            dirty: true,
+            #[cfg(feature = "no-cursor-save")]
+            cursor_x: None,
+            #[cfg(feature = "no-cursor-save")]
+            cursor_y: None,
+            content: Roster {
+                contacts: HashMap::new(),
+                grouping: config::RosterGrouping::Groups,
+                unread: HashMap::new(),
+                collapsed: HashSet::new(),
+                current_window: None,
+                visible: true,
+                focused: false,
+                selected: 0,
+                notes: HashMap::new(),
+                rendered: None,
+            },
+            event_handler: None,
+        }
     }
-}
 
-impl fmt::Display for contact::Contact {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self.presence {
-            contact::Presence::Available | contact::Presence::Chat => write!(f, "{}", color::Fg(color::Green))?,
-            contact::Presence::Away | contact::Presence::Dnd | contact::Presence::Xa | contact::Presence::Unavailable => write!(f, "{}", color::Fg(color::White))?,
-        };
+    fn toggle(&mut self) {
+        self.content.visible = !self.content.visible;
+        // Changing the width of the sidebar requires a relayout of the whole screen
+        self.dirty = true;
+    }
 
-        match &self.name {
-            Some(name) => write!(f, "{} ({}){}", name, self.jid, color::Fg(color::White)),
-            None => write!(f, "{}{}", self.jid, color::Fg(color::White)),
+    fn key(&mut self, key: &Key) {
+        let rows = self.content.rendered();
+        match key {
+            Key::Up | Key::Char('k') => {
+                if self.content.selected > 0 {
+                    self.content.selected -= 1;
+                }
+            },
+            Key::Down | Key::Char('j') => {
+                if self.content.selected + 1 < rows.len() {
+                    self.content.selected += 1;
+                }
+            },
+            Key::Char(' ') | Key::Left | Key::Right => {
+                if let Some((RosterRow::Group(group, collapsed, _, _), _, _)) = rows.get(self.content.selected) {
+                    match (key, collapsed) {
+                        (Key::Left, false) | (Key::Char(' '), false) => { self.content.collapsed.insert(group.clone()); },
+                        (Key::Right, true) | (Key::Char(' '), true) => { self.content.collapsed.remove(group); },
+                        _ => {},
+                    }
+                    self.content.rendered = None;
+                    self.dirty = true;
+                }
+            },
+            _ => {},
         }
+        self.redraw();
     }
-}
 
-impl fmt::Display for conversation::Occupant {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}{}{}", color::Fg(color::Green), self.nick, color::Fg(color::White))
+    /// Rows scrolled out of the top to keep the selected row visible
+    fn skip(&self) -> usize {
+        let height = self.h.unwrap_or(0) as usize;
+        match self.content.selected >= height {
+            true => self.content.selected + 1 - height,
+            false => 0,
+        }
     }
-}
 
-impl fmt::Display for conversation::Role {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            conversation::Role::Moderator => write!(f, "{}Moderators{}", color::Fg(color::Yellow), color::Fg(color::Yellow)),
-            conversation::Role::Participant => write!(f, "{}Participants{}", color::Fg(color::Yellow), color::Fg(color::Yellow)),
-            conversation::Role::Visitor => write!(f, "{}Visitors{}", color::Fg(color::Yellow), color::Fg(color::Yellow)),
+    /// Select the row clicked at `y`, returning the contact clicked
+    fn click(&mut self, y: u16) -> Option<BareJid> {
+        let row = self.skip() + (y - self.y) as usize;
+        if row >= self.content.rendered().len() {
+            return None;
         }
+        self.content.selected = row;
+        self.select()
     }
-}
 
-pub struct UIPlugin<'a> {
-    screen: Rc<RefCell<Screen>>,
-    windows: Vec<String>,
-    current_window: Option<String>,
-    conversations: HashMap<String, Conversation>,
-    root: Box<dyn ViewTrait<UIEvent<'a>> + 'a>,
-    password_command: Option<Command>,
-    completion: Option<Vec<String>>,
-    current_completion: usize,
-    running: Rc<AtomicBool>,
+    fn select(&mut self) -> Option<BareJid> {
+        let rows = self.content.rendered();
+        match rows.get(self.content.selected) {
+            Some((RosterRow::Contact(contact), _, _)) => Some(contact.jid.clone()),
+            Some((RosterRow::Group(group, collapsed, _, _), _, _)) => {
+                if *collapsed {
+                    self.content.collapsed.remove(group);
+                } else {
+                    self.content.collapsed.insert(group.clone());
+                }
+                self.content.rendered = None;
+                self.dirty = true;
+                None
+            },
+            None => None,
+        }
+    }
 }
 
-impl<'a> UIPlugin<'a> {
-    pub fn command_stream(&self, aparte: Rc<Aparte>) -> CommandStream {
-        let file = tokio_file_unix::raw_stdin().unwrap();
-        let file = tokio_file_unix::File::new_nb(file).unwrap();
-        let file = file.into_io(&tokio::reactor::Handle::default()).unwrap();
+impl ViewTrait<UIEvent<'_>> for View<'_, Roster, UIEvent<'_>> {
+    fn measure(&mut self, width_spec: Option<u16>, height_spec: Option<u16>) {
+        let width = match self.content.visible {
+            true => {
+                let rows = self.content.rendered();
+                let width = rows.iter().map(|(_, _, len)| *len).max().unwrap_or(0);
+                // Keep a column to separate the sidebar from the window
+                width as u16 + 1
+            },
+            false => 0,
+        };
 
-        FramedRead::new(file, KeyCodec::new(aparte, Rc::clone(&self.running)))
+        self.w = match width_spec {
+            Some(width_spec) => Some(cmp::min(width, width_spec)),
+            None => Some(width),
+        };
+        self.h = height_spec;
     }
 
-    fn event(&mut self, mut event: UIEvent<'a>) {
-        match event {
-            UIEvent::Key(_) => self.reset_completion(),
-            _ => {},
+    fn redraw(&mut self) {
+        if self.w.unwrap_or(0) == 0 {
+            return;
         }
-        self.root.event(&mut event);
-    }
 
-    fn add_conversation(&mut self, conversation: Conversation) {
-        match conversation.kind {
-            ConversationKind::Chat => {
-                let chat = View::<BufferedWin<Message>, UIEvent<'a>>::new(self.screen.clone()).with_event(|view, event| {
-                    match event {
-                        UIEvent::Message(Message::Incoming(XmppMessage::Chat(message))) => {
-                            // TODO check to == us
-                            view.recv_message(&Message::Incoming(XmppMessage::Chat(message.clone())), true);
-                        },
-                        UIEvent::Message(Message::Outgoing(XmppMessage::Chat(message))) => {
-                            // TODO check from == us
-                            view.recv_message(&Message::Outgoing(XmppMessage::Chat(message.clone())), true);
-                        },
-                        UIEvent::Key(Key::PageUp) => view.page_up(),
-                        UIEvent::Key(Key::PageDown) => view.page_down(),
-                        _ => {},
-                    }
-                });
+        self.save_cursor();
 
-                self.windows.push(conversation.jid.to_string());
-                self.root.event(&mut UIEvent::AddWindow(conversation.jid.to_string(), Some(Box::new(chat))));
-                self.conversations.insert(conversation.jid.to_string(), conversation);
-            },
-            ConversationKind::Group => {
-                let mut layout = View::<LinearLayout::<UIEvent<'a>>, UIEvent<'a>>::new(self.screen.clone(), Orientation::Horizontal, Dimension::MatchParent, Dimension::MatchParent).with_event(|layout, event| {
-                    for child in layout.content.children.iter_mut() {
-                        child.event(event);
-                    }
-                });
-                let chat = View::<BufferedWin<Message>, UIEvent<'a>>::new(self.screen.clone()).with_event(|view, event| {
-                    match event {
-                        UIEvent::Message(Message::Incoming(XmppMessage::Groupchat(message))) => {
-                            // TODO check to == us
-                            view.recv_message(&Message::Incoming(XmppMessage::Groupchat(message.clone())), true);
-                        },
-                        UIEvent::Message(Message::Outgoing(XmppMessage::Groupchat(message))) => {
-                            // TODO check from == us
-                            view.recv_message(&Message::Outgoing(XmppMessage::Groupchat(message.clone())), true);
-                        },
-                        UIEvent::Key(Key::PageUp) => view.page_up(),
-                        UIEvent::Key(Key::PageDown) => view.page_down(),
-                        _ => {},
-                    }
-                });
-                layout.push(chat);
+        let rows = self.content.rendered();
+        if self.content.selected >= rows.len() {
+            self.content.selected = cmp::max(rows.len(), 1) - 1;
+        }
 
-                let roster = View::<ListView<conversation::Role, conversation::Occupant>, UIEvent<'a>>::new(self.screen.clone()).with_none_group().with_event(|view, event| {
-                    match event {
-                        UIEvent::Occupant(occupant) => {
-                            view.insert(occupant.clone(), Some(occupant.role));
-                        },
-                        _ => {},
-                    }
-                });
-                layout.push(roster);
+        let height = self.h.unwrap() as usize;
+        let skip = self.skip();
+
+        // Note about the selected contact, shown on the last row
+        let tooltip = match rows.get(self.content.selected) {
+            Some((RosterRow::Contact(contact), _, _)) if self.content.focused => self.content.notes.get(&contact.jid).map(|note| {
+                let width = (self.w.unwrap() as usize).saturating_sub(4);
+                let note: String = note.lines().next().unwrap_or("").chars().take(width).collect();
+                format!("{}✎ {}{}", termion::style::Invert, note, termion::style::NoInvert)
+            }),
+            _ => None,
+        };
 
-                self.windows.push(conversation.jid.to_string());
-                self.root.event(&mut UIEvent::AddWindow(conversation.jid.to_string(), Some(Box::new(layout))));
-                self.conversations.insert(conversation.jid.to_string(), conversation);
+        {
+            let mut screen = self.screen.borrow_mut();
+            for (index, y) in (self.y .. self.y + self.h.unwrap()).enumerate() {
+                write!(screen, "{}", termion::cursor::Goto(self.x, y)).unwrap();
+                for _ in 0 .. self.w.unwrap() {
+                    write!(screen, " ").unwrap();
+                }
+                write!(screen, "{}", termion::cursor::Goto(self.x + 1, y)).unwrap();
+
+                if index + 1 == height && tooltip.is_some() {
+                    write!(screen, "{}", tooltip.as_ref().unwrap()).unwrap();
+                } else if let Some((_, row, _)) = rows.get(skip + index) {
+                    if self.content.focused && skip + index == self.content.selected {
+                        write!(screen, "{}{}{}", termion::style::Invert, row, termion::style::NoInvert).unwrap();
+                    } else {
+                        write!(screen, "{}", row).unwrap();
+                    }
+                }
             }
         }
+
+        self.restore_cursor();
+        self.screen.borrow_mut().flush().unwrap();
     }
 
-    pub fn change_window(&mut self, window: &str) {
-        self.root.event(&mut UIEvent::ChangeWindow(window.to_string()));
-        self.current_window = Some(window.to_string());
+    fn event(&mut self, event: &mut UIEvent) {
+        match event {
+            UIEvent::Contact(contact) | UIEvent::ContactUpdate(contact) => {
+                self.content.contacts.insert(contact.jid.clone(), contact.clone());
+                self.content.rendered = None;
+                self.dirty = true;
+            },
+            UIEvent::Activity(window, _) => {
+                if self.content.current_window.as_ref() != Some(window) {
+                    *self.content.unread.entry(window.clone()).or_insert(0) += 1;
+                    self.content.rendered = None;
+                    self.redraw();
+                }
+            },
+            UIEvent::ChangeWindow(name) => {
+                self.content.current_window = Some(name.clone());
+                if self.content.unread.remove(name).is_some() {
+                    self.content.rendered = None;
+                    self.redraw();
+                }
+            },
+            UIEvent::RosterGrouping(grouping) => {
+                self.content.grouping = *grouping;
+                self.content.rendered = None;
+                self.dirty = true;
+            },
+            UIEvent::Notes(notes) => {
+                self.content.notes = notes.clone();
+                self.content.rendered = None;
+                self.dirty = true;
+            },
+            UIEvent::Theme => self.content.rendered = None,
+            UIEvent::Key(Key::F(2)) => self.toggle(),
+            UIEvent::RosterFocus(focused) => {
+                self.content.focused = *focused;
+                if *focused && !self.content.visible {
+                    self.toggle();
+                } else {
+                    self.redraw();
+                }
+            },
+            UIEvent::RosterKey(key) => self.key(key),
+            UIEvent::RosterSelect(result) => {
+                let mut result = result.borrow_mut();
+                *result = self.select();
+                self.redraw();
+            },
+            UIEvent::Click(x, y, result) if self.w.unwrap_or(0) > 0 && *x >= self.x && *y >= self.y && *y < self.y + self.h.unwrap_or(0) => {
+                if let Some(jid) = self.click(*y) {
+                    result.replace(Some(Click::Contact(jid)));
+                }
+                self.redraw();
+            },
+            _ => {},
+        }
     }
+}
 
-    pub fn next_window(&mut self) {
-        if let Some(current) = &self.current_window {
-            let index = self.windows.iter().position(|e| e == current).unwrap();
-            if index < self.windows.len() - 1 {
-                self.change_window(&self.windows[index + 1].clone());
-            }
-        } else if self.windows.len() > 0 {
-            self.change_window(&self.windows[0].clone());
+/// Rooms of a channel service listed by /rooms, one of them being selected to be joined
+struct RoomList {
+    service: String,
+    rooms: Vec<rooms::Room>,
+    selected: usize,
+}
+
+impl View<'_, RoomList, UIEvent<'_>> {
+    fn new(screen: Rc<RefCell<Screen>>) -> Self {
+        Self {
+            screen: screen,
+            width: Dimension::MatchParent,
+            height: Dimension::MatchParent,
+            x: 0,
+            y: 0,
+            w: None,
+            h: None,
+            dirty: true,
+            #[cfg(feature = "no-cursor-save")]
+            cursor_x: None,
+            #[cfg(feature = "no-cursor-save")]
+            cursor_y: None,
+            content: RoomList {
+                service: String::new(),
+                rooms: Vec::new(),
+                selected: 0,
+            },
+            event_handler: None,
+        }
+    }
+
+    fn key(&mut self, key: &Key) {
+        match key {
+            Key::Up => self.content.selected = self.content.selected.saturating_sub(1),
+            Key::Down => {
+                if self.content.selected + 1 < self.content.rooms.len() {
+                    self.content.selected += 1;
+                }
+            },
+            Key::PageUp => self.content.selected = self.content.selected.saturating_sub(self.h.unwrap_or(1) as usize),
+            Key::PageDown => self.content.selected = cmp::min(self.content.selected + self.h.unwrap_or(1) as usize, cmp::max(self.content.rooms.len(), 1) - 1),
+            _ => {},
+        }
+        self.redraw();
+    }
+}
+
+impl ViewTrait<UIEvent<'_>> for View<'_, RoomList, UIEvent<'_>> {
+    fn measure(&mut self, width_spec: Option<u16>, height_spec: Option<u16>) {
+        self.w = width_spec;
+        self.h = height_spec;
+    }
+
+    fn redraw(&mut self) {
+        if self.w.unwrap_or(0) == 0 || self.h.unwrap_or(0) == 0 {
+            return;
+        }
+
+        self.save_cursor();
+
+        let theme = theme::current();
+        // The first row is used by the header
+        let height = self.h.unwrap() as usize - 1;
+        let skip = match self.content.selected >= height {
+            true => self.content.selected + 1 - height,
+            false => 0,
+        };
+
+        {
+            let mut screen = self.screen.borrow_mut();
+            for (index, y) in (self.y .. self.y + self.h.unwrap()).enumerate() {
+                write!(screen, "{}", termion::cursor::Goto(self.x, y)).unwrap();
+                for _ in 0 .. self.w.unwrap() {
+                    write!(screen, " ").unwrap();
+                }
+                write!(screen, "{}", termion::cursor::Goto(self.x, y)).unwrap();
+
+                if index == 0 {
+                    write!(screen, "{}Rooms of {} ({}), ↑/↓ to select, Enter to join{}", theme.group, self.content.service, self.content.rooms.len(), theme.text).unwrap();
+                } else if let Some(room) = self.content.rooms.get(skip + index - 1) {
+                    let row: String = format!("{}", room).chars().take(self.w.unwrap() as usize).collect();
+                    if skip + index - 1 == self.content.selected {
+                        write!(screen, "{}{}{}", termion::style::Invert, row, termion::style::NoInvert).unwrap();
+                    } else {
+                        write!(screen, "{}", row).unwrap();
+                    }
+                }
+            }
+        }
+
+        self.restore_cursor();
+        self.screen.borrow_mut().flush().unwrap();
+    }
+
+    fn event(&mut self, event: &mut UIEvent) {
+        match event {
+            UIEvent::Rooms(service, rooms) => {
+                self.content.service = service.clone();
+                self.content.rooms = rooms.clone();
+                self.content.selected = 0;
+                self.redraw();
+            },
+            UIEvent::Room(room) => {
+                if let Some(listed) = self.content.rooms.iter_mut().find(|listed| listed.jid == room.jid) {
+                    if room.name.is_some() {
+                        listed.name = room.name.clone();
+                    }
+                    listed.occupants = room.occupants;
+                    self.redraw();
+                }
+            },
+            UIEvent::RoomsKey(key) => self.key(key),
+            UIEvent::RoomsSelect(result) => {
+                let mut result = result.borrow_mut();
+                *result = self.content.rooms.get(self.content.selected).map(|room| room.jid.clone());
+            },
+            UIEvent::Theme => self.redraw(),
+            _ => {},
+        }
+    }
+}
+
+/// Mark the message of a conversation the error relates to, or log the error in the
+/// conversation when the message isn't known
+fn message_error<E>(view: &mut View<BufferedWin<Message>, E>, id: &Option<String>, error: &str) {
+    let failed = id.as_ref().and_then(|id| view.content.buf.iter().find(|message| message.id() == id).cloned());
+    match failed {
+        Some(mut message) => {
+            message.set_error(error);
+            view.replace_message(&message);
+        },
+        None => view.recv_message(&Message::log(format!("Error: {}", error)), false),
+    }
+}
+
+/// Messages kept in the window of a conversation, older ones being dropped only when they are
+/// stored in the history
+fn buffer_limit(aparte: &Aparte, jid: &BareJid) -> Option<usize> {
+    let logging = aparte.settings.borrow().get::<bool>(jid, "logging");
+    match aparte.get_plugin::<HistoryPlugin>() {
+        Some(_) if logging != Some(false) => Some(BUFFER_SIZE),
+        _ => None,
+    }
+}
+
+/// URL clicked in a window
+fn click<E>(view: &View<BufferedWin<Message>, E>, x: u16, y: u16, result: &Rc<RefCell<Option<Click>>>) {
+    let url = view.word_at(x, y).and_then(|word| message::urls(&word).first().map(|(_, url)| url.to_string()));
+    if let Some(url) = url {
+        result.replace(Some(Click::Url(url)));
+    }
+}
+
+/// Name of the window displaying a message
+fn window_name(message: &Message) -> String {
+    match message {
+        Message::Incoming(XmppMessage::Chat(message)) => message.from.to_string(),
+        Message::Incoming(XmppMessage::Groupchat(message)) => message.from.to_string(),
+        Message::Outgoing(XmppMessage::Chat(message)) => message.to.to_string(),
+        Message::Outgoing(XmppMessage::Groupchat(message)) => message.to.to_string(),
+        Message::Log(_) => "console".to_string(),
+    }
+}
+
+/// Write a message sent by `nick`, lines after the first one are aligned with it. URLs are
+/// followed by their index for /open when numbered, starting at `url_index`.
+fn write_message(f: &mut fmt::Formatter<'_>, theme: &theme::Theme, timestamp: &DateTime<Utc>, nick_style: &theme::Style, nick: &str, body: &str, mut url_index: Option<usize>) -> fmt::Result {
+    let timestamp = Local.from_utc_datetime(&timestamp.naive_local());
+    write!(f, "{}{}{} - ", theme.timestamp, timestamp.format(&i18n::time_format()), theme.text)?;
+
+    let (header_len, body) = match message::action(body) {
+        Some(action) => {
+            write!(f, "{}* {}{} ", nick_style, nick, theme.text)?;
+            (format!("* {} ", nick).chars().count(), action)
+        },
+        None => {
+            write!(f, "{}{}:{} ", nick_style, nick, theme.text)?;
+            (format!("{}: ", nick).chars().count(), body)
+        },
+    };
+    let padding = " ".repeat(format!("{} - ", timestamp.format(&i18n::time_format())).len() + header_len);
+
+    for (index, line) in body.lines().enumerate() {
+        if index > 0 {
+            write!(f, "\n{}", padding)?;
+        }
+
+        let mut written = 0;
+        for (start, url) in message::urls(line) {
+            write!(f, "{}{}{}{}", &line[written..start], theme.url, url, theme.text)?;
+            if let Some(index) = url_index {
+                write!(f, " {}[{}]{}", theme.timestamp, index, theme.text)?;
+                url_index = Some(index + 1);
+            }
+            written = start + url.len();
+        }
+        write!(f, "{}", &line[written..])?;
+    }
+
+    Ok(())
+}
+
+/// Write the translation of a message beneath its body, aligned with the body
+fn write_translation(f: &mut fmt::Formatter<'_>, theme: &theme::Theme, translation: &Option<Rc<str>>) -> fmt::Result {
+    if let Some(translation) = translation {
+        let padding = " ".repeat("00:00:00 - ".len());
+        for line in translation.lines() {
+            write!(f, "\n{}{}↳ {}{}", padding, theme.timestamp, line, theme.text)?;
+        }
+    }
+    Ok(())
+}
+
+/// Mark an outgoing message waiting to be sent, or the server couldn't deliver
+fn write_status(f: &mut fmt::Formatter<'_>, theme: &theme::Theme, pending: bool, error: &Option<Rc<str>>) -> fmt::Result {
+    match error {
+        Some(error) => write!(f, " {}✗ {}{}", theme.error, error, theme.text),
+        None if pending => write!(f, " {}⧗ pending{}", theme.timestamp, theme.text),
+        None => Ok(()),
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let theme = theme::current();
+        match self {
+            Message::Log(message) => {
+                let timestamp = Local.from_utc_datetime(&message.timestamp.naive_local());
+                for line in message.body.lines() {
+                    write!(f, "{}{}{} - {}\n", theme.timestamp, timestamp.format(&i18n::time_format()), theme.text, line)?;
+                }
+
+                Ok(())
+            },
+            Message::Incoming(XmppMessage::Chat(message)) => {
+                let conversation = message.from.to_string();
+                let url_index = urls::first_index(&conversation, &message.id);
+                write_message(f, &theme, &message.timestamp, &theme::nick(&theme, &conversation), &conversation, &message.body, url_index)?;
+                write_translation(f, &theme, &message.translation)
+            },
+            Message::Outgoing(XmppMessage::Chat(message)) => {
+                write_message(f, &theme, &message.timestamp, &theme.own_nick, "me", &message.body, None)?;
+                write_status(f, &theme, message.pending, &message.error)?;
+                write_translation(f, &theme, &message.translation)
+            }
+            Message::Incoming(XmppMessage::Groupchat(message)) => {
+                if let Jid::Full(from) = &*message.from_full {
+                    let url_index = urls::first_index(&message.from.to_string(), &message.id);
+                    write_message(f, &theme, &message.timestamp, &theme::nick(&theme, &message.from.to_string()), &from.resource, &message.body, url_index)?;
+                    write_translation(f, &theme, &message.translation)?;
+                }
+                Ok(())
+            },
+            Message::Outgoing(XmppMessage::Groupchat(message)) => {
+                write_message(f, &theme, &message.timestamp, &theme.own_nick, "me", &message.body, None)?;
+                write_status(f, &theme, message.pending, &message.error)?;
+                write_translation(f, &theme, &message.translation)
+            }
+        }
+    }
+}
+
+impl fmt::Display for contact::Group {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let theme = theme::current();
+        write!(f, "{}{}{}", theme.group, self.0, theme.text)
+    }
+}
+
+impl fmt::Display for contact::Contact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let theme = theme::current();
+        match self.presence {
+            contact::Presence::Available | contact::Presence::Chat => write!(f, "{}● ", theme.presence_available)?,
+            contact::Presence::Away | contact::Presence::Xa => write!(f, "{}◐ ", theme.presence_away)?,
+            contact::Presence::Dnd => write!(f, "{}⊘ ", theme.presence_dnd)?,
+            contact::Presence::Unavailable => write!(f, "{}○ ", theme.presence_unavailable)?,
+        };
+
+        let jid = contact::display_jid(&self.jid);
+        match &self.name {
+            Some(name) => write!(f, "{} ({}){}", name, jid, theme.text)?,
+            None => write!(f, "{}{}", jid, theme.text)?,
+        };
+
+        match self.info.is_empty() {
+            true => Ok(()),
+            false => write!(f, " {}", self.info),
+        }
+    }
+}
+
+impl fmt::Display for contact::UserInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(tune) = &self.tune {
+            parts.push(format!("♫ {}", tune));
+        }
+        if let Some(mood) = &self.mood {
+            parts.push(mood.clone());
+        }
+        if let Some(activity) = &self.activity {
+            parts.push(activity.clone());
+        }
+        write!(f, "{}", parts.join(" · "))
+    }
+}
+
+impl fmt::Display for conversation::Occupant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let theme = theme::current();
+        write!(f, "{}{}{}", theme.nick, self.nick, theme.text)
+    }
+}
+
+impl fmt::Display for conversation::Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let theme = theme::current();
+        match self {
+            conversation::Role::Moderator => write!(f, "{}Moderators{}", theme.group, theme.text),
+            conversation::Role::Participant => write!(f, "{}Participants{}", theme.group, theme.text),
+            conversation::Role::Visitor => write!(f, "{}Visitors{}", theme.group, theme.text),
+        }
+    }
+}
+
+pub struct UIPlugin<'a> {
+    screen: Rc<RefCell<Screen>>,
+    // Size of an offscreen buffer, the size of the terminal being used otherwise
+    size: Option<(u16, u16)>,
+    windows: Vec<String>,
+    current_window: Option<String>,
+    conversations: HashMap<String, Conversation>,
+    root: Box<dyn ViewTrait<UIEvent<'a>> + 'a>,
+    password_command: Option<Command>,
+    completion: Option<Vec<String>>,
+    current_completion: usize,
+    search: Option<String>,
+    history_search: Option<String>,
+    flush_scheduled: bool,
+    // End of the slow mode cooldown of each channel
+    cooldowns: HashMap<String, Instant>,
+    cooldown_scheduled: bool,
+    roster_focus: bool,
+    running: Rc<AtomicBool>,
+    // Whether the terminal has the focus, as reported by the terminal
+    focused: Rc<AtomicBool>,
+    // Frontends the UI is drawn for when detached
+    relay: Option<Relay>,
+    // Dictionary used for every conversation, and the ones set for some conversations, off
+    // disabling spell checking
+    dictionary: Option<String>,
+    dictionaries: HashMap<String, String>,
+    spellers: HashMap<String, Rc<RefCell<Speller>>>,
+    // What hunspell answered about the words of the input, until listened to
+    spelling: mpsc::UnboundedSender<spell::Answer>,
+    answers: Option<mpsc::UnboundedReceiver<spell::Answer>>,
+    // Whether the mouse is reported by the terminal
+    mouse: bool,
+    switcher: Option<Switcher>,
+}
+
+/// Duration of the flash of a visual bell
+const VISUAL_BELL: Duration = Duration::from_millis(150);
+
+/// Sequences sent by the terminal when focus reporting is enabled
+const FOCUS_IN: &[u8] = b"\x1b[I";
+const FOCUS_OUT: &[u8] = b"\x1b[O";
+/// Sequences around pasted text when bracketed paste is enabled
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+/// Focus reporting and bracketed paste, disabling mouse reporting too
+pub const ENABLE_TERMINAL_MODES: &str = "\x1b[?1004h\x1b[?2004h";
+pub const DISABLE_TERMINAL_MODES: &str = "\x1b[?1004l\x1b[?2004l\x1b[?1000l\x1b[?1006l";
+/// Mouse reporting of presses, wheel included, with coordinates as decimal numbers
+const ENABLE_MOUSE: &str = "\x1b[?1000h\x1b[?1006h";
+const DISABLE_MOUSE: &str = "\x1b[?1000l\x1b[?1006l";
+/// Lines scrolled by a step of the mouse wheel
+const SCROLL_LINES: usize = 3;
+/// Messages of the history shown in a conversation when its window is opened
+const HISTORY_MESSAGES: usize = 50;
+
+fn input_history_path() -> PathBuf {
+    profile::data_dir().join("input_history.toml")
+}
+
+impl<'a> UIPlugin<'a> {
+    pub fn command_stream(&self, aparte: Rc<Aparte>) -> CommandStream {
+        let codec = KeyCodec::new(aparte, Rc::clone(&self.running), Rc::clone(&self.focused));
+        if let Some(relay) = &self.relay {
+            return relay.command_stream(codec);
+        }
+
+        let file = tokio_file_unix::raw_stdin().unwrap();
+        let file = tokio_file_unix::File::new_nb(file).unwrap();
+        let file = file.into_io(&tokio::reactor::Handle::default()).unwrap();
+
+        Box::new(FramedRead::new(file, codec))
+    }
+
+    /// Listen to what hunspell answers about the words of the input, to underline the misspelled
+    /// ones once known
+    pub fn listen_spelling(&mut self, aparte: Rc<Aparte>) -> impl Future<Item = (), Error = ()> {
+        let answers = self.answers.take().expect("Spelling already listened to");
+        answers.for_each(move |answer| {
+            aparte.get_plugin_mut::<UIPlugin>().unwrap().spelled(answer);
+            Ok(())
+        })
+    }
+
+    fn event(&mut self, mut event: UIEvent<'a>) {
+        match event {
+            UIEvent::Key(_) => self.reset_completion(),
+            _ => {},
+        }
+        self.root.event(&mut event);
+
+        // Keep the switcher over windows drawn beneath it
+        if self.switcher.is_some() {
+            self.draw_switcher();
+        }
+    }
+
+    fn add_conversation(&mut self, aparte: &Aparte, conversation: Conversation) {
+        let jid = conversation.jid.clone();
+        let limit = buffer_limit(aparte, &jid);
+        match conversation.kind {
+            ConversationKind::Chat => {
+                let chat = View::<BufferedWin<Message>, UIEvent<'a>>::new(self.screen.clone()).with_limit(limit).with_event(|view, event| {
+                    match event {
+                        UIEvent::Message(Message::Incoming(XmppMessage::Chat(message))) => {
+                            // TODO check to == us
+                            view.recv_message(&Message::Incoming(XmppMessage::Chat(message.clone())), false);
+                        },
+                        UIEvent::Message(Message::Outgoing(XmppMessage::Chat(message))) => {
+                            // TODO check from == us
+                            view.recv_message(&Message::Outgoing(XmppMessage::Chat(message.clone())), false);
+                        },
+                        UIEvent::WindowLog(_, message) => view.recv_message(message, false),
+                        UIEvent::ClearWindow(_) => view.clear(),
+                        UIEvent::Unlimit(_) => view.unlimit(),
+                        UIEvent::MessageError(_, id, error) => message_error(view, id, error),
+                        UIEvent::ReplaceMessage(_, message) => view.replace_message(message),
+                        UIEvent::Key(Key::PageUp) => view.page_up(),
+                        UIEvent::Key(Key::PageDown) => view.page_down(),
+                        UIEvent::Key(Key::End) => view.page_end(),
+                        UIEvent::Scroll(true) => view.scroll_up(SCROLL_LINES),
+                        UIEvent::Scroll(false) => view.scroll_down(SCROLL_LINES),
+                        UIEvent::Click(x, y, result) => click(view, *x, *y, result),
+                        UIEvent::Search(pattern, next) => view.search(pattern, *next),
+                        UIEvent::EndSearch(tail) => view.end_search(*tail),
+                        UIEvent::Selected(result) => { result.replace(view.content.selected().cloned()); },
+                        UIEvent::Theme => view.refresh(),
+                        UIEvent::Flush => view.flush(),
+                        _ => {},
+                    }
+                });
+
+                self.windows.push(conversation.jid.to_string());
+                self.root.event(&mut UIEvent::AddWindow(conversation.jid.to_string(), Some(Box::new(chat))));
+                self.conversations.insert(conversation.jid.to_string(), conversation);
+            },
+            ConversationKind::Group => {
+                let mut layout = View::<LinearLayout::<UIEvent<'a>>, UIEvent<'a>>::new(self.screen.clone(), Orientation::Horizontal, Dimension::MatchParent, Dimension::MatchParent).with_event(|layout, event| {
+                    for child in layout.content.children.iter_mut() {
+                        child.event(event);
+                    }
+                });
+                let chat = View::<BufferedWin<Message>, UIEvent<'a>>::new(self.screen.clone()).with_limit(limit).with_event(|view, event| {
+                    match event {
+                        UIEvent::Message(Message::Incoming(XmppMessage::Groupchat(message))) => {
+                            // TODO check to == us
+                            view.recv_message(&Message::Incoming(XmppMessage::Groupchat(message.clone())), false);
+                        },
+                        UIEvent::Message(Message::Outgoing(XmppMessage::Groupchat(message))) => {
+                            // TODO check from == us
+                            view.recv_message(&Message::Outgoing(XmppMessage::Groupchat(message.clone())), false);
+                        },
+                        UIEvent::WindowLog(_, message) => view.recv_message(message, false),
+                        UIEvent::Unlimit(_) => view.unlimit(),
+                        UIEvent::MessageError(_, id, error) => message_error(view, id, error),
+                        UIEvent::ReplaceMessage(_, message) => view.replace_message(message),
+                        UIEvent::Key(Key::PageUp) => view.page_up(),
+                        UIEvent::Key(Key::PageDown) => view.page_down(),
+                        UIEvent::Key(Key::End) => view.page_end(),
+                        UIEvent::Scroll(true) => view.scroll_up(SCROLL_LINES),
+                        UIEvent::Scroll(false) => view.scroll_down(SCROLL_LINES),
+                        UIEvent::Click(x, y, result) => click(view, *x, *y, result),
+                        UIEvent::Search(pattern, next) => view.search(pattern, *next),
+                        UIEvent::EndSearch(tail) => view.end_search(*tail),
+                        UIEvent::Selected(result) => { result.replace(view.content.selected().cloned()); },
+                        UIEvent::Theme => view.refresh(),
+                        UIEvent::Flush => view.flush(),
+                        _ => {},
+                    }
+                });
+                layout.push(chat);
+
+                let roster = View::<ListView<conversation::Role, conversation::Occupant>, UIEvent<'a>>::new(self.screen.clone()).with_none_group().with_event(|view, event| {
+                    match event {
+                        UIEvent::Occupant(occupant) => {
+                            view.insert(occupant.clone(), Some(occupant.role));
+                        },
+                        UIEvent::RemoveOccupant(_, nick) => view.retain(|occupant| &occupant.nick != nick),
+                        UIEvent::Theme => view.refresh(),
+                        _ => {},
+                    }
+                });
+                layout.push(roster);
+
+                self.windows.push(conversation.jid.to_string());
+                self.root.event(&mut UIEvent::AddWindow(conversation.jid.to_string(), Some(Box::new(layout))));
+                self.conversations.insert(conversation.jid.to_string(), conversation);
+            }
+        }
+
+        // Last messages of the history, the one opening the window not being shown twice when
+        // stored first
+        if let Some(history) = aparte.get_plugin::<HistoryPlugin>() {
+            match history.history(&jid, HISTORY_MESSAGES) {
+                Ok(messages) => for message in messages {
+                    self.root.event(&mut UIEvent::WindowLog(jid.to_string(), message));
+                },
+                Err(err) => warn!("Cannot load the history of {}: {}", jid, err),
+            }
+        }
+    }
+
+    pub fn change_window(&mut self, window: &str) {
+        self.root.event(&mut UIEvent::ChangeWindow(window.to_string()));
+        self.current_window = Some(window.to_string());
+        self.cooldown_status();
+        self.update_speller();
+    }
+
+    /// Spell checker of a dictionary, started once
+    fn speller(&mut self, dictionary: &str) -> Result<Rc<RefCell<Speller>>, String> {
+        if let Some(speller) = self.spellers.get(dictionary) {
+            return Ok(Rc::clone(speller));
+        }
+        let speller = Rc::new(RefCell::new(Speller::new(dictionary, self.spelling.clone())?));
+        self.spellers.insert(dictionary.to_string(), Rc::clone(&speller));
+        Ok(speller)
+    }
+
+    /// Check the spelling of the input with the dictionary of the current window
+    fn update_speller(&mut self) {
+        let dictionary = self.current_window.as_ref()
+            .and_then(|window| self.dictionaries.get(window))
+            .or(self.dictionary.as_ref())
+            .filter(|dictionary| dictionary.as_str() != "off")
+            .cloned();
+        let speller = match dictionary {
+            Some(dictionary) => match self.speller(&dictionary) {
+                Ok(speller) => Some(speller),
+                Err(err) => {
+                    warn!("{}", err);
+                    None
+                },
+            },
+            None => None,
+        };
+        self.event(UIEvent::Speller(speller));
+    }
+
+    /// Check that hunspell has a dictionary
+    pub fn check_dictionary(&mut self, dictionary: &str) -> Result<(), String> {
+        self.speller(dictionary).map(|_| ())
+    }
+
+    /// Underline the misspelled word hunspell answered about
+    fn spelled(&mut self, (dictionary, word, suggestions): spell::Answer) {
+        let misspelled = suggestions.is_some();
+        if let Some(speller) = self.spellers.get(&dictionary) {
+            speller.borrow_mut().answered(word, suggestions);
+        }
+        if misspelled {
+            self.event(UIEvent::Spelled);
+        }
+    }
+
+    /// Change the dictionary of a conversation, or of every conversation without `conversation`,
+    /// checking that hunspell has it
+    pub fn set_dictionary(&mut self, conversation: Option<&str>, dictionary: Option<&str>) -> Result<(), String> {
+        if let Some(dictionary) = dictionary.filter(|dictionary| *dictionary != "off") {
+            self.speller(dictionary)?;
+        }
+        match (conversation, dictionary) {
+            (Some(conversation), Some(dictionary)) => { self.dictionaries.insert(conversation.to_string(), dictionary.to_string()); },
+            (Some(conversation), None) => { self.dictionaries.remove(conversation); },
+            (None, dictionary) => self.dictionary = dictionary.map(String::from),
+        }
+        self.update_speller();
+        Ok(())
+    }
+
+    /// Keep every message of the windows of the conversations whose messages are no longer stored
+    /// in the history
+    pub fn update_buffer_limits(&mut self, aparte: &Aparte) {
+        let unstored: Vec<String> = self.conversations.values()
+            .filter(|conversation| buffer_limit(aparte, &conversation.jid).is_none())
+            .map(|conversation| conversation.jid.to_string())
+            .collect();
+        for window in unstored {
+            self.event(UIEvent::Unlimit(window));
+        }
+    }
+
+    pub fn next_window(&mut self) {
+        if let Some(current) = &self.current_window {
+            let index = self.windows.iter().position(|e| e == current).unwrap();
+            if index < self.windows.len() - 1 {
+                self.change_window(&self.windows[index + 1].clone());
+            }
+        } else if self.windows.len() > 0 {
+            self.change_window(&self.windows[0].clone());
+        }
+    }
+
+    pub fn prev_window(&mut self) {
+        if let Some(current) = &self.current_window {
+            let index = self.windows.iter().position(|e| e == current).unwrap();
+            if index > 0 {
+                self.change_window(&self.windows[index - 1].clone());
+            }
+        } else if self.windows.len() > 0 {
+            self.change_window(&self.windows[0].clone());
+        }
+    }
+
+    pub fn autocomplete(&mut self, command: &mut Command) {
+        let completion = match &self.completion {
+            None => {
+                return;
+            }
+            Some(completion) => {
+                completion
+            }
+        };
+
+        if completion.len() == 0 {
+            return;
+        }
+
+        if command.cursor < command.args.len() {
+            command.args[command.cursor] = completion[self.current_completion].clone();
+        } else {
+            command.args.push(completion[self.current_completion].clone());
+        }
+
+        self.current_completion += 1;
+        self.current_completion %= completion.len();
+    }
+
+    pub fn reset_completion(&mut self) {
+        self.completion = None;
+        self.current_completion = 0;
+    }
+
+    /// Switch to another theme, everything is rendered again with it
+    pub fn set_theme(&mut self, theme: theme::Theme) {
+        theme::set(theme);
+        self.event(UIEvent::Theme);
+        self.redraw_all();
+    }
+
+    fn size(&self) -> (u16, u16) {
+        match self.size {
+            Some(size) => size,
+            None => termion::terminal_size().unwrap(),
+        }
+    }
+
+    /// Size of the terminal of the frontend last attached or resized
+    pub fn resize(&mut self, size: (u16, u16)) {
+        self.size = Some(size);
+        self.redraw_all();
+    }
+
+    fn redraw_all(&mut self) {
+        {
+            let mut screen = self.screen.borrow_mut();
+            write!(screen, "{}", termion::clear::All).unwrap();
+        }
+
+        let (width, height) = self.size();
+        self.root.measure(Some(width), Some(height));
+        self.root.layout(1, 1);
+        self.root.redraw();
+
+        // Frontends attaching to a relay redraw everything, and have to report the mouse too
+        if self.mouse {
+            self.set_mouse(true);
+        }
+    }
+
+    pub fn is_mouse_enabled(&self) -> bool {
+        self.mouse
+    }
+
+    /// Have the terminal report the mouse, or leave it to select text
+    pub fn set_mouse(&mut self, enabled: bool) {
+        self.mouse = enabled;
+        let mut screen = self.screen.borrow_mut();
+        match enabled {
+            true => write!(screen, "{}", ENABLE_MOUSE).unwrap(),
+            false => write!(screen, "{}", DISABLE_MOUSE).unwrap(),
+        }
+        screen.flush().unwrap();
+    }
+
+    /// Scroll the current window with the mouse wheel
+    pub fn scroll(&mut self, up: bool) {
+        self.event(UIEvent::Scroll(up));
+    }
+
+    /// What was clicked at a position of the screen
+    fn click(&mut self, x: u16, y: u16) -> Option<Click> {
+        let result = Rc::new(RefCell::new(None));
+        self.event(UIEvent::Click(x, y, Rc::clone(&result)));
+        let clicked = result.borrow_mut().take();
+        clicked
+    }
+
+    pub fn current_window(&self) -> Option<&str> {
+        self.current_window.as_deref()
+    }
+
+    /// Whether the terminal has the focus, terminals not reporting focus are always focused
+    pub fn is_focused(&self) -> bool {
+        self.focused.load(Ordering::Relaxed)
+    }
+
+    pub fn bell(&mut self) {
+        let mut screen = self.screen.borrow_mut();
+        write!(screen, "\x07").unwrap();
+        screen.flush().unwrap();
+    }
+
+    /// Flash the status bar instead of ringing the terminal bell
+    pub fn visual_bell(&mut self, aparte: Rc<Aparte>) {
+        self.event(UIEvent::Flash(true));
+
+        let end = Delay::new(Instant::now() + VISUAL_BELL).then(move |_| {
+            aparte.get_plugin_mut::<UIPlugin>().unwrap().event(UIEvent::Flash(false));
+            Ok(())
+        });
+        if TaskExecutor::current().spawn_local(Box::new(end)).is_err() {
+            self.event(UIEvent::Flash(false));
+        }
+    }
+
+    /// Display an image in a conversation, `transmit` sends it to the terminal and `placeholder`
+    /// shows it
+    pub fn preview(&mut self, window: &str, transmit: &str, placeholder: String) {
+        {
+            let mut screen = self.screen.borrow_mut();
+            write!(screen, "{}", transmit).unwrap();
+        }
+        self.root.event(&mut UIEvent::WindowLog(window.to_string(), Message::log(placeholder)));
+        self.root.event(&mut UIEvent::Flush);
+    }
+
+    /// Show the rooms of a channel service in the rooms window
+    pub fn show_rooms(&mut self, service: &str, list: Vec<rooms::Room>) {
+        if !self.windows.iter().any(|window| window == rooms::WINDOW) {
+            let view = View::<RoomList, UIEvent<'a>>::new(self.screen.clone());
+            self.windows.push(rooms::WINDOW.to_string());
+            self.root.event(&mut UIEvent::AddWindow(rooms::WINDOW.to_string(), Some(Box::new(view))));
+        }
+
+        self.change_window(rooms::WINDOW);
+        self.event(UIEvent::Rooms(service.to_string(), list));
+    }
+
+    /// Update a room of the rooms window once its details are known
+    pub fn update_room(&mut self, room: rooms::Room) {
+        self.event(UIEvent::Room(room));
+    }
+
+    fn is_rooms_window(&self) -> bool {
+        self.current_window.as_deref() == Some(rooms::WINDOW)
+    }
+
+    /// Room selected in the rooms window, when it is the current one
+    fn selected_room(&mut self) -> Option<BareJid> {
+        if !self.is_rooms_window() {
+            return None;
+        }
+
+        let result = Rc::new(RefCell::new(None));
+        self.event(UIEvent::RoomsSelect(Rc::clone(&result)));
+        let selected = result.borrow_mut().take();
+        selected
+    }
+
+    /// Add a window displaying log messages sent with `log_to`
+    pub fn add_log_window(&mut self, name: &str) {
+        if self.windows.iter().any(|window| window == name) {
+            return;
+        }
+
+        let window = View::<BufferedWin<Message>, UIEvent<'a>>::new(self.screen.clone()).with_event(|view, event| {
+            match event {
+                UIEvent::WindowLog(_, message) => view.recv_message(message, false),
+                UIEvent::Key(Key::PageUp) => view.page_up(),
+                UIEvent::Key(Key::PageDown) => view.page_down(),
+                UIEvent::Key(Key::End) => view.page_end(),
+                UIEvent::Scroll(true) => view.scroll_up(SCROLL_LINES),
+                UIEvent::Scroll(false) => view.scroll_down(SCROLL_LINES),
+                UIEvent::Click(x, y, result) => click(view, *x, *y, result),
+                UIEvent::Search(pattern, next) => view.search(pattern, *next),
+                UIEvent::EndSearch(tail) => view.end_search(*tail),
+                UIEvent::Theme => view.refresh(),
+                UIEvent::Flush => view.flush(),
+                _ => {},
+            }
+        });
+
+        self.windows.push(name.to_string());
+        self.root.event(&mut UIEvent::AddWindow(name.to_string(), Some(Box::new(window))));
+    }
+
+    /// Remove every message of a window added with `add_log_window`
+    pub fn clear_window(&mut self, window: &str) {
+        self.event(UIEvent::ClearWindow(window.to_string()));
+    }
+
+    pub fn log_to(&mut self, aparte: Rc<Aparte>, window: &str, body: String) {
+        self.event(UIEvent::WindowLog(window.to_string(), Message::log(body)));
+        self.schedule_flush(aparte);
+    }
+
+    /// Redraw a message already displayed, after its state changed
+    pub fn replace_message(&mut self, aparte: Rc<Aparte>, message: &Message) {
+        self.event(UIEvent::ReplaceMessage(window_name(message), message.clone()));
+        self.schedule_flush(aparte);
+    }
+
+    /// Show a warning in the status bar, or remove it
+    pub fn set_warning(&mut self, warning: Option<String>) {
+        self.event(UIEvent::Warning(warning));
+    }
+
+    /// Show the register of the keyboard macro being recorded in the status bar
+    fn set_recording(&mut self, register: Option<char>) {
+        self.event(UIEvent::Recording(register));
+    }
+
+    /// Wait until `until` before talking again in a channel
+    pub fn set_cooldown(&mut self, aparte: Rc<Aparte>, window: &str, until: Instant) {
+        self.cooldowns.insert(window.to_string(), until);
+        self.update_cooldowns(aparte);
+    }
+
+    /// Show the cooldown of the current window in the input
+    fn cooldown_status(&mut self) {
+        let now = Instant::now();
+        let remaining = self.current_window.as_ref().and_then(|window| self.cooldowns.get(window)).filter(|until| **until > now).map(|until| *until - now);
+        let status = remaining.map(|remaining| format!("slow mode: {}s", (remaining.as_millis() + 999) / 1000));
+        self.event(UIEvent::InputStatus(status));
+    }
+
+    /// Refresh the cooldown shown every second until every cooldown is over
+    fn update_cooldowns(&mut self, aparte: Rc<Aparte>) {
+        let now = Instant::now();
+        self.cooldowns.retain(|_, until| *until > now);
+        self.cooldown_status();
+
+        if self.cooldowns.is_empty() || self.cooldown_scheduled {
+            return;
+        }
+
+        let tick = Delay::new(now + Duration::from_secs(1)).then(move |_| {
+            let mut ui = aparte.get_plugin_mut::<UIPlugin>().unwrap();
+            ui.cooldown_scheduled = false;
+            ui.update_cooldowns(Rc::clone(&aparte));
+            Ok(())
+        });
+
+        if TaskExecutor::current().spawn_local(Box::new(tick)).is_ok() {
+            self.cooldown_scheduled = true;
+        }
+    }
+
+    pub fn set_notes(&mut self, notes: HashMap<BareJid, String>) {
+        self.event(UIEvent::Notes(notes));
+    }
+
+    pub fn get_windows(&self) -> Vec<String> {
+        self.windows.clone()
+    }
+
+    /// Unread messages and mentions of each window with activity
+    pub fn unread(&mut self) -> Vec<(String, usize, usize)> {
+        let result = Rc::new(RefCell::new(Vec::new()));
+        self.event(UIEvent::Unread(Rc::clone(&result)));
+        let unread = result.borrow().clone();
+        unread
+    }
+
+    fn panes(&mut self) -> Vec<String> {
+        let result = Rc::new(RefCell::new(Vec::new()));
+        self.event(UIEvent::Panes(Rc::clone(&result)));
+        let panes = result.borrow().clone();
+        panes
+    }
+
+    /// Split the focused pane, the new pane displays `window` or the first window not already
+    /// displayed.
+    pub fn split(&mut self, orientation: Orientation, window: Option<String>) -> Result<(), String> {
+        let panes = self.panes();
+        let window = match window {
+            Some(window) => {
+                if !self.windows.contains(&window) {
+                    return Err(format!("Unknown window {}", window));
+                }
+                if panes.contains(&window) {
+                    return Err(format!("Window {} is already displayed", window));
+                }
+                window
+            },
+            None => match self.windows.iter().find(|window| !panes.contains(window)) {
+                Some(window) => window.clone(),
+                None => return Err(format!("No window left to display")),
+            },
+        };
+
+        self.event(UIEvent::Split(orientation, window.clone()));
+        self.change_window(&window);
+        Ok(())
+    }
+
+    pub fn only(&mut self) {
+        self.event(UIEvent::Only);
+    }
+
+    pub fn next_pane(&mut self) {
+        let panes = self.panes();
+        let index = match &self.current_window {
+            Some(current) => panes.iter().position(|pane| pane == current).map(|index| index + 1).unwrap_or(0),
+            None => 0,
+        };
+
+        if let Some(window) = panes.get(index % cmp::max(panes.len(), 1)) {
+            self.change_window(&window.clone());
+        }
+    }
+
+    pub fn focus_roster(&mut self, focus: bool) {
+        self.roster_focus = focus;
+        self.event(UIEvent::RosterFocus(focus));
+    }
+
+    pub fn set_roster_grouping(&mut self, grouping: config::RosterGrouping) {
+        self.event(UIEvent::RosterGrouping(grouping));
+    }
+
+    pub fn is_roster_focused(&self) -> bool {
+        self.roster_focus
+    }
+
+    /// Navigate in the roster sidebar, returning the contact to open a conversation with when
+    /// one is selected.
+    pub fn roster_key(&mut self, key: Key) -> Option<BareJid> {
+        match key {
+            Key::Esc | Key::F(3) => {
+                self.focus_roster(false);
+                None
+            },
+            Key::Char('\n') => {
+                let result = Rc::new(RefCell::new(None));
+                self.event(UIEvent::RosterSelect(Rc::clone(&result)));
+                let selected = result.borrow_mut().take();
+                if selected.is_some() {
+                    self.focus_roster(false);
+                }
+                selected
+            },
+            key => {
+                self.event(UIEvent::RosterKey(key));
+                None
+            },
+        }
+    }
+
+    pub fn start_search(&mut self) {
+        self.search = Some(String::new());
+        self.event(UIEvent::Search(String::new(), false));
+    }
+
+    pub fn is_searching(&self) -> bool {
+        self.search.is_some()
+    }
+
+    /// Incremental backward search in the current window: typed chars refine the pattern,
+    /// Ctrl-f jumps to the previous match, Enter stops at the match and Esc goes back to the
+    /// end of the window.
+    pub fn search_key(&mut self, key: Key) {
+        let mut search = match self.search.take() {
+            Some(search) => search,
+            None => return,
+        };
+
+        match key {
+            Key::Char('\n') => {
+                self.event(UIEvent::EndSearch(false));
+                return;
+            },
+            Key::Esc => {
+                self.event(UIEvent::EndSearch(true));
+                return;
+            },
+            Key::Ctrl('f') => {
+                self.event(UIEvent::Search(search.clone(), true));
+            },
+            Key::Backspace => {
+                search.pop();
+                self.event(UIEvent::Search(search.clone(), false));
+            },
+            Key::Char(c) => {
+                search.push(c);
+                self.event(UIEvent::Search(search.clone(), false));
+            },
+            _ => {},
+        }
+
+        self.search = Some(search);
+    }
+
+    /// Message selected in the current window: the search match, the last message otherwise
+    pub fn selected_message(&mut self) -> Option<Message> {
+        let result = Rc::new(RefCell::new(None));
+        self.event(UIEvent::Selected(Rc::clone(&result)));
+        let selected = result.borrow_mut().take();
+        selected
+    }
+
+    /// Copy text to the system clipboard, through the terminal and the graphical session
+    pub fn copy(&mut self, text: &str) -> Result<(), String> {
+        {
+            let mut screen = self.screen.borrow_mut();
+            write!(screen, "{}", clipboard::osc52(text)).map_err(|err| format!("Cannot copy: {}", err))?;
+            screen.flush().map_err(|err| format!("Cannot copy: {}", err))?;
+        }
+        clipboard::copy(text)
+    }
+
+    /// Compose the content of the input in an external editor
+    pub fn edit(&mut self) -> Result<(), String> {
+        let result = Rc::new(RefCell::new(None));
+        self.event(UIEvent::InputContent(Rc::clone(&result)));
+        let content = result.borrow_mut().take().unwrap_or_default();
+
+        let edited = self.run_editor(&content)?;
+        self.event(UIEvent::SetInput(edited));
+        Ok(())
+    }
+
+    /// Suspend the interface while $VISUAL or $EDITOR edits `text`, and return the edited text
+    fn run_editor(&mut self, text: &str) -> Result<String, String> {
+        if self.relay.is_some() {
+            return Err(format!("Cannot run an editor while detached"));
+        }
+
+        let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).unwrap_or("vi".to_string());
+        let path = env::temp_dir().join(format!("aparte-{}.txt", Uuid::new_v4()));
+        fs::write(&path, text).map_err(|err| format!("Cannot write {}: {}", path.display(), err))?;
+
+        // The editor shares our stdin, it expects it to be blocking
+        let stdin = tokio_file_unix::raw_stdin().map_err(|err| format!("Cannot get stdin: {}", err))?;
+        let stdin = tokio_file_unix::File::raw_new(stdin);
+
+        {
+            let mut screen = self.screen.borrow_mut();
+            write!(screen, "{}{}", DISABLE_TERMINAL_MODES, termion::screen::ToMainScreen).unwrap();
+            screen.flush().unwrap();
+            if let Err(err) = screen.suspend_raw_mode() {
+                warn!("Cannot suspend raw mode: {}", err);
+            }
+        }
+        if let Err(err) = stdin.set_nonblocking(false) {
+            warn!("Cannot set stdin blocking: {}", err);
+        }
+
+        // Run through the shell as the editor command can contain arguments
+        let status = process::Command::new("sh").arg("-c").arg(format!("{} \"$1\"", editor))
+            .arg("sh").arg(&path).status();
+
+        if let Err(err) = stdin.set_nonblocking(true) {
+            warn!("Cannot set stdin non blocking: {}", err);
+        }
+        {
+            let mut screen = self.screen.borrow_mut();
+            if let Err(err) = screen.activate_raw_mode() {
+                warn!("Cannot restore raw mode: {}", err);
+            }
+            write!(screen, "{}{}", termion::screen::ToAlternateScreen, ENABLE_TERMINAL_MODES).unwrap();
+        }
+        self.redraw_all();
+
+        let edited = match status {
+            Ok(status) if status.success() => fs::read_to_string(&path).map_err(|err| format!("Cannot read {}: {}", path.display(), err)),
+            Ok(status) => Err(format!("{} exited with {}", editor, status)),
+            Err(err) => Err(format!("Cannot run {}: {}", editor, err)),
+        };
+        let _ = fs::remove_file(&path);
+
+        Ok(edited?.trim_end_matches('\n').to_string())
+    }
+
+    /// Draw received messages once the current tick of the event loop is over, so a burst of
+    /// messages results in a single redraw.
+    fn schedule_flush(&mut self, aparte: Rc<Aparte>) {
+        if self.flush_scheduled {
+            return;
+        }
+
+        let flush = future::lazy(move || {
+            let mut ui = aparte.get_plugin_mut::<UIPlugin>().unwrap();
+            ui.flush_scheduled = false;
+            ui.event(UIEvent::Flush);
+            Ok(())
+        });
+
+        match TaskExecutor::current().spawn_local(Box::new(flush)) {
+            Ok(()) => self.flush_scheduled = true,
+            // Not running in the event loop yet
+            Err(_) => self.event(UIEvent::Flush),
+        }
+    }
+
+    /// Open the switcher over the windows, listing the windows then the contacts without one
+    pub fn start_switcher(&mut self, contacts: Vec<contact::Contact>) {
+        let label = |jid: &str| {
+            let contact = contacts.iter().find(|contact| contact.jid.to_string() == jid);
+            let shown = contact.map(|contact| contact::display_jid(&contact.jid)).unwrap_or(jid.to_string());
+            match contact.and_then(|contact| contact.name.as_ref()) {
+                Some(name) => format!("{} ({})", name, shown),
+                None => shown,
+            }
+        };
+        let mut candidates: Vec<(String, String)> = self.windows.iter().map(|window| (window.clone(), label(window))).collect();
+        let mut others: Vec<(String, String)> = contacts.iter()
+            .map(|contact| contact.jid.to_string())
+            .filter(|jid| !self.windows.contains(jid))
+            .map(|jid| {
+                let label = label(&jid);
+                (jid, label)
+            })
+            .collect();
+        others.sort_by_key(|(_, label)| label.to_lowercase());
+        candidates.extend(others);
+
+        self.switcher = Some(Switcher::new(candidates));
+        self.draw_switcher();
+    }
+
+    pub fn is_switching(&self) -> bool {
+        self.switcher.is_some()
+    }
+
+    /// Typed chars refine the candidates of the switcher, arrows select one of them, Enter
+    /// switches to its window and returns the contact to open a conversation with when it has
+    /// none, Esc or Ctrl-g closes the switcher.
+    pub fn switcher_key(&mut self, key: Key) -> Option<BareJid> {
+        let mut switcher = self.switcher.take()?;
+
+        match key {
+            Key::Char('\n') => {
+                self.redraw_all();
+                let selected = switcher.selected()?.to_string();
+                if self.windows.contains(&selected) {
+                    self.change_window(&selected);
+                    return None;
+                }
+                return BareJid::from_str(&selected).ok();
+            },
+            Key::Esc | Key::Ctrl('g') => {
+                self.redraw_all();
+                return None;
+            },
+            Key::Up | Key::Ctrl('p') => switcher.selected = switcher.selected.saturating_sub(1),
+            Key::Down | Key::Ctrl('n') | Key::Char('\t') => {
+                if switcher.selected + 1 < switcher.matches.len() {
+                    switcher.selected += 1;
+                }
+            },
+            Key::Backspace => {
+                switcher.pattern.pop();
+                switcher.refine();
+            },
+            Key::Char(c) => {
+                switcher.pattern.push(c);
+                switcher.refine();
+            },
+            _ => {},
+        }
+
+        self.switcher = Some(switcher);
+        self.draw_switcher();
+        None
+    }
+
+    /// Draw the switcher in a box centered over the windows, the cursor after the pattern
+    fn draw_switcher(&mut self) {
+        let switcher = match &self.switcher {
+            Some(switcher) => switcher,
+            None => return,
+        };
+        let (width, height) = self.size();
+        let w = cmp::min(width.saturating_sub(4), 60) as usize;
+        let h = cmp::min(height.saturating_sub(4), 14) as usize;
+        if w < 10 || h < 4 {
+            return;
+        }
+        let x = (width - w as u16) / 2 + 1;
+        let y = (height - h as u16) / 2 + 1;
+        let inner = w - 2;
+        let fit = |text: &str| {
+            let text: String = text.chars().take(inner).collect();
+            let len = text.chars().count();
+            format!("{}{}", text, " ".repeat(inner - len))
+        };
+
+        // Keep the selected candidate visible
+        let rows = h - 3;
+        let skip = match switcher.selected >= rows {
+            true => switcher.selected + 1 - rows,
+            false => 0,
+        };
+
+        let mut screen = self.screen.borrow_mut();
+        let title = format!("─ Switch to ({}/{}) ", switcher.matches.len(), switcher.candidates.len());
+        let title: String = title.chars().take(inner).collect();
+        write!(screen, "{}┌{}{}┐", termion::cursor::Goto(x, y), title, "─".repeat(inner - title.chars().count())).unwrap();
+        write!(screen, "{}│{}│", termion::cursor::Goto(x, y + 1), fit(&format!("> {}", switcher.pattern))).unwrap();
+        for row in 0..rows {
+            write!(screen, "{}│", termion::cursor::Goto(x, y + 2 + row as u16)).unwrap();
+            match switcher.matches.get(skip + row) {
+                Some(index) if skip + row == switcher.selected => {
+                    write!(screen, "{}{}{}", termion::style::Invert, fit(&switcher.candidates[*index].1), termion::style::NoInvert).unwrap();
+                },
+                Some(index) => write!(screen, "{}", fit(&switcher.candidates[*index].1)).unwrap(),
+                None => write!(screen, "{}", fit("")).unwrap(),
+            }
+            write!(screen, "│").unwrap();
+        }
+        write!(screen, "{}└{}┘", termion::cursor::Goto(x, y + h as u16 - 1), "─".repeat(inner)).unwrap();
+
+        let cursor = cmp::min(3 + switcher.pattern.chars().count(), inner);
+        write!(screen, "{}", termion::cursor::Goto(x + 1 + cursor as u16, y + 1)).unwrap();
+        screen.flush().unwrap();
+    }
+
+    pub fn start_history_search(&mut self) {
+        self.history_search = Some(String::new());
+        self.event(UIEvent::HistorySearch(String::new(), false));
+    }
+
+    pub fn is_history_searching(&self) -> bool {
+        self.history_search.is_some()
+    }
+
+    /// Incremental reverse search in the input history of the current window: typed chars
+    /// refine the pattern, Ctrl-r jumps to the previous match, Enter puts the match in the input
+    /// and Esc or Ctrl-g leaves the input untouched.
+    pub fn history_search_key(&mut self, key: Key) {
+        let mut search = match self.history_search.take() {
+            Some(search) => search,
+            None => return,
+        };
+
+        match key {
+            Key::Char('\n') => {
+                self.event(UIEvent::EndHistorySearch(true));
+                return;
+            },
+            Key::Esc | Key::Ctrl('g') => {
+                self.event(UIEvent::EndHistorySearch(false));
+                return;
+            },
+            Key::Ctrl('r') => {
+                self.event(UIEvent::HistorySearch(search.clone(), true));
+            },
+            Key::Backspace => {
+                search.pop();
+                self.event(UIEvent::HistorySearch(search.clone(), false));
+            },
+            Key::Char(c) => {
+                search.push(c);
+                self.event(UIEvent::HistorySearch(search.clone(), false));
+            },
+            _ => {},
         }
-    }
 
-    pub fn prev_window(&mut self) {
-        if let Some(current) = &self.current_window {
-            let index = self.windows.iter().position(|e| e == current).unwrap();
-            if index > 0 {
-                self.change_window(&self.windows[index - 1].clone());
-            }
-        } else if self.windows.len() > 0 {
-            self.change_window(&self.windows[0].clone());
-        }
+        self.history_search = Some(search);
     }
 
-    pub fn autocomplete(&mut self, command: &mut Command) {
-        let completion = match &self.completion {
-            None => {
-                return;
-            }
-            Some(completion) => {
-                completion
-            }
+    fn load_input_history(&mut self) {
+        let path = input_history_path();
+        let histories = match fs::read_to_string(&path) {
+            Ok(content) => match toml::from_str(&content) {
+                Ok(histories) => histories,
+                Err(err) => {
+                    warn!("Cannot parse input history {}: {}", path.display(), err);
+                    return;
+                },
+            },
+            Err(_) => return,
         };
 
-        if completion.len() == 0 {
-            return;
-        }
+        self.event(UIEvent::LoadInputHistory(histories));
+    }
 
-        if command.cursor < command.args.len() {
-            command.args[command.cursor] = completion[self.current_completion].clone();
-        } else {
-            command.args.push(completion[self.current_completion].clone());
-        }
+    /// Write the input history of every window to disk on quit so it survives restarts
+    fn save_input_history(&mut self) {
+        let result = Rc::new(RefCell::new(HashMap::new()));
+        self.event(UIEvent::InputHistory(Rc::clone(&result)));
 
-        self.current_completion += 1;
-        self.current_completion %= completion.len();
+        let path = input_history_path();
+        let content = match toml::to_string(&*result.borrow()) {
+            Ok(content) => content,
+            Err(err) => {
+                warn!("Cannot serialize input history: {}", err);
+                return;
+            },
+        };
+        if let Err(err) = fs::write(&path, content) {
+            warn!("Cannot write input history {}: {}", path.display(), err);
+        }
     }
+}
 
-    pub fn reset_completion(&mut self) {
-        self.completion = None;
-        self.current_completion = 0;
+impl<'a> UIPlugin<'a> {
+    /// UI rendered into an offscreen buffer instead of the terminal
+    #[cfg(test)]
+    pub fn offscreen(screen: Offscreen) -> Self {
+        let size = screen.size();
+        let mut ui = Self::with_screen(Box::new(screen));
+        ui.size = Some(size);
+        ui
     }
 
-    pub fn get_windows(&self) -> Vec<String> {
-        self.windows.clone()
+    /// UI drawn for the frontends attached to the relay instead of the terminal
+    pub fn relayed(relay: Relay) -> Self {
+        let mut ui = Self::with_screen(Box::new(relay.clone()));
+        ui.size = Some((80, 24));
+        ui.relay = Some(relay);
+        ui
     }
-}
 
-impl<'a> Plugin for UIPlugin<'a> {
-    fn new() -> Self {
-        let stdout = std::io::stdout().into_raw_mode().unwrap();
-        let screen = Rc::new(RefCell::new(AlternateScreen::from(stdout)));
+    fn with_screen(screen: Screen) -> Self {
+        let screen = Rc::new(RefCell::new(screen));
         let mut layout = View::<LinearLayout::<UIEvent<'a>>, UIEvent<'a>>::new(screen.clone(), Orientation::Vertical, Dimension::MatchParent, Dimension::MatchParent).with_event(|layout, event| {
             for child in layout.content.children.iter_mut() {
                 child.event(event);
@@ -518,6 +2174,45 @@ impl<'a> Plugin for UIPlugin<'a> {
                     let view = view.take().unwrap();
                     frame.insert(name.to_string(), view);
                 },
+                UIEvent::Split(orientation, name) => {
+                    frame.split(orientation.clone(), name.to_string());
+                },
+                UIEvent::Only => frame.only(),
+                UIEvent::Panes(result) => {
+                    result.replace(frame.visible());
+                },
+                UIEvent::Key(Key::PageUp) | UIEvent::Key(Key::PageDown) | UIEvent::Key(Key::End) | UIEvent::Scroll(_)
+                    | UIEvent::Search(_, _) | UIEvent::EndSearch(_) | UIEvent::Selected(_) => {
+                    // Scrolling only applies to the window of the focused pane
+                    if let Some(current) = frame.current_key() {
+                        if let Some(child) = frame.content.children.get_mut(&current) {
+                            child.event(event);
+                        }
+                    }
+                },
+                UIEvent::Click(x, y, _) => {
+                    // A click on a pane focuses it, unless it was on a URL
+                    if let Some(key) = frame.pane_at(*x, *y) {
+                        if let Some(child) = frame.content.children.get_mut(&key) {
+                            child.event(event);
+                        }
+                        if let UIEvent::Click(_, _, result) = event {
+                            if result.borrow().is_none() {
+                                result.replace(Some(Click::Window(key)));
+                            }
+                        }
+                    }
+                },
+                UIEvent::Message(message) => {
+                    if let Some(child) = frame.content.children.get_mut(&window_name(message)) {
+                        child.event(event);
+                    }
+                },
+                UIEvent::WindowLog(window, _) | UIEvent::MessageError(window, _, _) | UIEvent::ReplaceMessage(window, _) | UIEvent::ClearWindow(window) | UIEvent::Unlimit(window) | UIEvent::RemoveOccupant(window, _) => {
+                    if let Some(child) = frame.content.children.get_mut(window) {
+                        child.event(event);
+                    }
+                },
                 event => {
                     for (_, child) in frame.content.children.iter_mut() {
                         child.event(event);
@@ -538,6 +2233,17 @@ impl<'a> Plugin for UIPlugin<'a> {
                 UIEvent::Key(Key::Left) => input.left(),
                 UIEvent::Key(Key::Right) => input.right(),
                 UIEvent::Key(Key::Ctrl('w')) => input.backward_delete_word(),
+                UIEvent::Key(Key::Ctrl('a')) => input.home(),
+                UIEvent::Key(Key::Ctrl('e')) => input.end(),
+                UIEvent::Key(Key::Ctrl('k')) => input.kill_end(),
+                UIEvent::Key(Key::Ctrl('u')) => input.kill_start(),
+                UIEvent::Key(Key::Ctrl('y')) => input.yank(),
+                // Ctrl-_ is read as Ctrl-7
+                UIEvent::Key(Key::Ctrl('7')) => input.undo(),
+                UIEvent::Key(Key::Alt('b')) => input.backward_word(),
+                UIEvent::Key(Key::Alt('f')) => input.forward_word(),
+                UIEvent::Key(Key::Alt('d')) => input.delete_word(),
+                UIEvent::Key(Key::Alt('\r')) | UIEvent::Key(Key::Alt('\n')) => input.newline(),
                 UIEvent::Validate(result) => {
                     let mut result = result.borrow_mut();
                     result.replace(input.validate());
@@ -552,17 +2258,53 @@ impl<'a> Plugin for UIPlugin<'a> {
                     input.redraw();
                 },
                 UIEvent::ReadPassword => input.password(),
+                UIEvent::InputContent(result) => {
+                    result.replace(Some(input.content.buf.clone()));
+                },
+                UIEvent::SetInput(buf) => input.set(buf),
+                UIEvent::InputStatus(status) => {
+                    input.content.status = status.clone();
+                    input.redraw();
+                },
+                UIEvent::Speller(speller) => {
+                    input.content.speller = speller.clone();
+                    input.content.suggesting = None;
+                    input.redraw();
+                },
+                UIEvent::Spelled => input.redraw(),
+                UIEvent::Key(Key::Alt('s')) => input.suggest(),
+                UIEvent::Paste(text) => input.paste(text),
+                UIEvent::CompleteEmoji(result) => {
+                    result.replace(!input.content.password && input.complete_emoji());
+                },
+                UIEvent::ChangeWindow(name) => input.set_history_window(name),
+                UIEvent::HistorySearch(pattern, next) => input.history_search(pattern, *next),
+                UIEvent::EndHistorySearch(accept) => input.end_history_search(*accept),
+                UIEvent::LoadInputHistory(histories) => input.load_histories(histories.clone()),
+                UIEvent::InputHistory(result) => {
+                    result.replace(input.content.histories.clone());
+                },
                 _ => {}
             }
         });
 
+        let mut main = View::<LinearLayout::<UIEvent<'a>>, UIEvent<'a>>::new(screen.clone(), Orientation::Horizontal, Dimension::MatchParent, Dimension::MatchParent).with_event(|layout, event| {
+            for child in layout.content.children.iter_mut() {
+                child.event(event);
+            }
+        });
+        main.push(frame);
+        main.push(View::<Roster, UIEvent>::new(screen.clone()));
+
         layout.push(title_bar);
-        layout.push(frame);
+        layout.push(main);
         layout.push(win_bar);
         layout.push(input);
 
+        let (spelling, answers) = mpsc::unbounded();
         Self {
             screen: screen,
+            size: None,
             root: Box::new(layout),
             windows: Vec::new(),
             current_window: None,
@@ -570,21 +2312,66 @@ impl<'a> Plugin for UIPlugin<'a> {
             password_command: None,
             completion: None,
             current_completion: 0,
+            search: None,
+            history_search: None,
+            flush_scheduled: false,
+            cooldowns: HashMap::new(),
+            cooldown_scheduled: false,
+            roster_focus: false,
             running: Rc::new(AtomicBool::new(true)),
+            focused: Rc::new(AtomicBool::new(true)),
+            relay: None,
+            dictionary: None,
+            dictionaries: HashMap::new(),
+            spellers: HashMap::new(),
+            spelling,
+            answers: Some(answers),
+            mouse: false,
+            switcher: None,
         }
     }
+}
+
+impl<'a> Plugin for UIPlugin<'a> {
+    fn new() -> Self {
+        let stdout = std::io::stdout().into_raw_mode().unwrap();
+        UIPlugin::with_screen(Box::new(AlternateScreen::from(stdout)))
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        if let Some(name) = &aparte.config.theme {
+            match theme::Theme::find(&aparte.config.themes, name) {
+                Some(theme) => theme::set(theme),
+                None => warn!("Unknown theme {}", name),
+            }
+        }
+        {
+            let settings = aparte.settings.borrow();
+            theme::set_color(None, settings.get_raw(None, "color").and_then(|color| theme::Color::from_str(color).ok()));
+            for (conversation, color) in settings.conversations("color") {
+                theme::set_color(Some(&conversation.to_string()), theme::Color::from_str(color).ok());
+            }
+            self.dictionary = settings.get_raw(None, "spelling").map(String::from).or_else(|| aparte.config.spelling.clone());
+            self.dictionaries = settings.conversations("spelling").map(|(conversation, dictionary)| (conversation.to_string(), dictionary.to_string())).collect();
+        }
+        self.mouse = aparte.config.mouse;
 
-    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
         {
             let mut screen = self.screen.borrow_mut();
-            write!(screen, "{}", termion::clear::All).unwrap();
+            write!(screen, "{}{}", termion::clear::All, ENABLE_TERMINAL_MODES).unwrap();
+            if self.mouse {
+                write!(screen, "{}", ENABLE_MOUSE).unwrap();
+            }
         }
 
-        let (width, height) = termion::terminal_size().unwrap();
+        let (width, height) = self.size();
         self.root.measure(Some(width), Some(height));
         self.root.layout(1, 1);
         self.root.redraw();
 
+        // Once laid out, as changing the grouping of the roster relayouts the screen
+        self.root.event(&mut UIEvent::RosterGrouping(aparte.config.roster_grouping));
+
         let mut console = View::<LinearLayout::<UIEvent<'a>>, UIEvent<'a>>::new(self.screen.clone(), Orientation::Horizontal, Dimension::MatchParent, Dimension::MatchParent).with_event(|layout, event| {
             for child in layout.content.children.iter_mut() {
                 child.event(event);
@@ -593,32 +2380,28 @@ impl<'a> Plugin for UIPlugin<'a> {
         console.push(View::<BufferedWin<Message>, UIEvent<'a>>::new(self.screen.clone()).with_event(|view, event| {
             match event {
                 UIEvent::Message(Message::Log(message)) => {
-                    view.recv_message(&Message::Log(message.clone()), true);
+                    view.recv_message(&Message::Log(message.clone()), false);
                 },
+                UIEvent::ReplaceMessage(_, message) => view.replace_message(message),
                 UIEvent::Key(Key::PageUp) => view.page_up(),
                 UIEvent::Key(Key::PageDown) => view.page_down(),
+                UIEvent::Key(Key::End) => view.page_end(),
+                UIEvent::Scroll(true) => view.scroll_up(SCROLL_LINES),
+                UIEvent::Scroll(false) => view.scroll_down(SCROLL_LINES),
+                UIEvent::Click(x, y, result) => click(view, *x, *y, result),
+                UIEvent::Search(pattern, next) => view.search(pattern, *next),
+                UIEvent::EndSearch(tail) => view.end_search(*tail),
+                UIEvent::Selected(result) => { result.replace(view.content.selected().cloned()); },
+                UIEvent::Theme => view.refresh(),
+                UIEvent::Flush => view.flush(),
                 _ => {},
             }
         }));
-        let roster = View::<ListView<contact::Group, contact::Contact>, UIEvent<'a>>::new(self.screen.clone()).with_none_group().with_event(|view, event| {
-            match event {
-                UIEvent::Contact(contact) | UIEvent::ContactUpdate(contact) => {
-                    if contact.groups.len() > 0 {
-                        for group in &contact.groups {
-                            view.insert(contact.clone(), Some(group.clone()));
-                        }
-                    } else {
-                            view.insert(contact.clone(), None);
-                    }
-                }
-                _ => {},
-            }
-        });
-        console.push(roster);
 
         self.windows.push("console".to_string());
         self.root.event(&mut UIEvent::AddWindow("console".to_string(), Some(Box::new(console))));
         self.change_window("console");
+        self.load_input_history();
 
         Ok(())
     }
@@ -637,7 +2420,7 @@ impl<'a> Plugin for UIPlugin<'a> {
                     Message::Incoming(XmppMessage::Chat(message)) => {
                         let window_name = message.from.to_string();
                         if !self.conversations.contains_key(&window_name) {
-                            self.add_conversation(Conversation {
+                            self.add_conversation(&aparte, Conversation {
                                 jid: BareJid::from_str(&window_name).unwrap(),
                                 kind: ConversationKind::Chat,
                             });
@@ -646,7 +2429,7 @@ impl<'a> Plugin for UIPlugin<'a> {
                     Message::Outgoing(XmppMessage::Chat(message)) => {
                         let window_name = message.to.to_string();
                         if !self.conversations.contains_key(&window_name) {
-                            self.add_conversation(Conversation {
+                            self.add_conversation(&aparte, Conversation {
                                 jid: BareJid::from_str(&window_name).unwrap(),
                                 kind: ConversationKind::Chat,
                             });
@@ -655,7 +2438,7 @@ impl<'a> Plugin for UIPlugin<'a> {
                     Message::Incoming(XmppMessage::Groupchat(message)) => {
                         let window_name = message.from.to_string();
                         if !self.conversations.contains_key(&window_name) {
-                            self.add_conversation(Conversation {
+                            self.add_conversation(&aparte, Conversation {
                                 jid: BareJid::from_str(&window_name).unwrap(),
                                 kind: ConversationKind::Group,
                             });
@@ -664,7 +2447,7 @@ impl<'a> Plugin for UIPlugin<'a> {
                     Message::Outgoing(XmppMessage::Groupchat(message)) => {
                         let window_name = message.to.to_string();
                         if !self.conversations.contains_key(&window_name) {
-                            self.add_conversation(Conversation {
+                            self.add_conversation(&aparte, Conversation {
                                 jid: BareJid::from_str(&window_name).unwrap(),
                                 kind: ConversationKind::Group,
                             });
@@ -674,22 +2457,55 @@ impl<'a> Plugin for UIPlugin<'a> {
                 };
 
                 self.root.event(&mut UIEvent::Message(message.clone()));
+
+                match message {
+                    Message::Incoming(XmppMessage::Chat(message)) => {
+                        // Direct messages are addressed to us, count them as mentions
+                        self.root.event(&mut UIEvent::Activity(message.from.to_string(), true));
+                    },
+                    incoming @ Message::Incoming(XmppMessage::Groupchat(message)) => {
+                        let nick = match aparte.get_plugin::<ConversationPlugin>().unwrap().get(&message.from) {
+                            Some(conversation::Conversation::Channel(channel)) => Some(channel.nick.clone()),
+                            _ => None,
+                        };
+
+                        let own = match (&nick, &*message.from_full) {
+                            (Some(nick), Jid::Full(from)) => &from.resource == nick,
+                            _ => false,
+                        };
+
+                        if !own {
+                            let mention = match &nick {
+                                Some(nick) => incoming.mentions(nick),
+                                None => false,
+                            };
+                            self.root.event(&mut UIEvent::Activity(message.from.to_string(), mention));
+                        }
+                    },
+                    _ => {},
+                }
+
+                self.schedule_flush(Rc::clone(&aparte));
+            },
+            Event::MessageError(conversation, id, error) => {
+                self.root.event(&mut UIEvent::MessageError(conversation.to_string(), id.clone(), error.clone()));
+                self.schedule_flush(Rc::clone(&aparte));
             },
             Event::Chat(jid) => {
                 let win_name = jid.to_string();
                 if !self.conversations.contains_key(&win_name) {
-                    self.add_conversation(Conversation {
+                    self.add_conversation(&aparte, Conversation {
                         jid: BareJid::from_str(&win_name).unwrap(),
                         kind: ConversationKind::Chat,
                     });
                 }
                 self.change_window(&win_name);
             },
-            Event::Join(jid) => {
+            Event::Join(_, jid) => {
                 let bare: BareJid = jid.clone().into();
                 let win_name = bare.to_string();
                 if !self.conversations.contains_key(&win_name) {
-                    self.add_conversation(Conversation {
+                    self.add_conversation(&aparte, Conversation {
                         jid: BareJid::from_str(&win_name).unwrap(),
                         kind: ConversationKind::Group,
                     });
@@ -712,14 +2528,42 @@ impl<'a> Plugin for UIPlugin<'a> {
             Event::Occupant(occupant) => {
                 self.root.event(&mut UIEvent::Occupant(occupant.clone()));
             },
-            Event::Signal(signal_hook::SIGWINCH) => {
-                let (width, height) = termion::terminal_size().unwrap();
-                self.root.measure(Some(width), Some(height));
-                self.root.layout(1, 1);
-                self.root.redraw();
+            Event::OccupantLeft(channel, nick) => {
+                self.root.event(&mut UIEvent::RemoveOccupant(channel.to_string(), nick.clone()));
+            },
+            Event::NickChange(channel, old, new) => {
+                self.root.event(&mut UIEvent::RemoveOccupant(channel.to_string(), old.clone()));
+                let own = match aparte.get_plugin::<ConversationPlugin>().unwrap().get(channel) {
+                    Some(conversation::Conversation::Channel(channel)) => &channel.nick == new,
+                    _ => false,
+                };
+                match own {
+                    true => self.log_to(aparte, &channel.to_string(), format!("You are now known as {}", new)),
+                    false => self.log_to(aparte, &channel.to_string(), format!("{} is now known as {}", old, new)),
+                }
+            },
+            Event::Subject(channel, subject) => {
+                let text = match (&subject.nick, subject.subject.as_str()) {
+                    (Some(nick), "") => format!("{} removed the subject", nick),
+                    (Some(nick), text) => format!("Subject set by {}: {}", nick, text),
+                    (None, "") => format!("No subject"),
+                    (None, text) => format!("Subject: {}", text),
+                };
+                self.log_to(aparte, &channel.to_string(), text);
+            },
+            Event::NickChangeError(channel, nick, condition) => {
+                self.log_to(aparte, &channel.to_string(), format!("Cannot change nick to {}: {}", nick, condition));
             },
+            Event::Signal(signal_hook::SIGWINCH) => self.redraw_all(),
             Event::Quit => {
                 self.running.swap(false, Ordering::Relaxed);
+                self.save_input_history();
+                let mut screen = self.screen.borrow_mut();
+                write!(screen, "{}", DISABLE_TERMINAL_MODES).unwrap();
+                screen.flush().unwrap();
+                if let Some(relay) = &self.relay {
+                    relay.close();
+                }
             }
             _ => {},
         }
@@ -734,16 +2578,59 @@ impl<'a> fmt::Display for UIPlugin<'a> {
 
 pub struct KeyCodec {
     queue: Vec<Result<CommandOrMessage, CommandError>>,
+    macros: Macros,
     aparte: Rc<Aparte>,
     running: Rc<AtomicBool>,
+    focused: Rc<AtomicBool>,
+    // Text being pasted, until the end of the bracketed paste
+    paste: Option<String>,
 }
 
 impl KeyCodec {
-    pub fn new(aparte: Rc<Aparte>, running: Rc<AtomicBool>) -> Self {
+    pub fn new(aparte: Rc<Aparte>, running: Rc<AtomicBool>, focused: Rc<AtomicBool>) -> Self {
         Self {
             queue: Vec::new(),
+            macros: Macros::new(&aparte.config.macros),
             aparte: aparte,
             running: running,
+            focused: focused,
+            paste: None,
+        }
+    }
+
+    /// Scroll with the wheel, or act on what was clicked
+    fn mouse(&mut self, event: MouseEvent) {
+        let clicked = {
+            let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+            match event {
+                MouseEvent::Press(MouseButton::WheelUp, _, _) => {
+                    ui.scroll(true);
+                    None
+                },
+                MouseEvent::Press(MouseButton::WheelDown, _, _) => {
+                    ui.scroll(false);
+                    None
+                },
+                MouseEvent::Press(MouseButton::Left, x, y) => ui.click(x, y),
+                _ => None,
+            }
+        };
+
+        match clicked {
+            Some(Click::Window(window)) => {
+                let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                if ui.is_roster_focused() {
+                    ui.focus_roster(false);
+                }
+                ui.change_window(&window);
+            },
+            Some(Click::Contact(jid)) => Rc::clone(&self.aparte).event(Event::Chat(jid)),
+            Some(Click::Url(url)) => {
+                if let Err(err) = urls::open(&url) {
+                    Rc::clone(&self.aparte).log(err);
+                }
+            },
+            None => {},
         }
     }
 }
@@ -754,8 +2641,139 @@ impl Decoder for KeyCodec {
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         if self.running.load(Ordering::Relaxed) {
-            let mut keys = buf.keys();
+            let mut keys = Vec::new();
+            for event in buf.events() {
+                match event {
+                    Ok(TermEvent::Unsupported(ref sequence)) if sequence.as_slice() == FOCUS_IN => self.focused.store(true, Ordering::Relaxed),
+                    Ok(TermEvent::Unsupported(ref sequence)) if sequence.as_slice() == FOCUS_OUT => self.focused.store(false, Ordering::Relaxed),
+                    Ok(TermEvent::Unsupported(ref sequence)) if sequence.as_slice() == PASTE_START => self.paste = Some(String::new()),
+                    Ok(TermEvent::Unsupported(ref sequence)) if sequence.as_slice() == PASTE_END => {
+                        // Pasted lines are kept in the input, to be sent as a single message
+                        if let Some(text) = self.paste.take() {
+                            self.aparte.get_plugin_mut::<UIPlugin>().unwrap().event(UIEvent::Paste(text));
+                        }
+                    },
+                    Ok(TermEvent::Key(Key::Char(c))) if self.paste.is_some() => self.paste.as_mut().unwrap().push(c),
+                    Ok(TermEvent::Key(key)) => keys.push(Ok(key)),
+                    Ok(TermEvent::Mouse(event)) => self.mouse(event),
+                    Ok(_) => {},
+                    Err(err) => keys.push(Err(err)),
+                }
+            }
+            if !keys.is_empty() {
+                Rc::clone(&self.aparte).event(Event::Activity);
+            }
+
+            // Keys driving macros are handled before anything else, as they work everywhere
+            let mut notices = Vec::new();
+            let macros = &mut self.macros;
+            let mut keys = keys.into_iter().flat_map(|key| match key {
+                Ok(key) => {
+                    let (keys, notice) = macros.feed(key);
+                    notices.extend(notice);
+                    keys.into_iter().map(Ok).collect()
+                },
+                Err(err) => vec![Err(err)],
+            }).collect::<Vec<_>>().into_iter();
+            for notice in notices {
+                match notice {
+                    Notice::Recording(register) => {
+                        let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                        ui.set_recording(Some(register));
+                    },
+                    Notice::Recorded(register, count) => {
+                        self.aparte.get_plugin_mut::<UIPlugin>().unwrap().set_recording(None);
+                        Rc::clone(&self.aparte).log(format!("Macro {} recorded, {} keys", register, count));
+                    },
+                    Notice::Empty(register) => Rc::clone(&self.aparte).log(format!("Macro {} is empty", register)),
+                }
+            }
+
             while let Some(key) = keys.next() {
+                let confirming = match self.aparte.get_plugin::<ConfirmPlugin>() {
+                    Some(confirm) => confirm.is_pending(),
+                    None => false,
+                };
+
+                if confirming {
+                    if let Ok(key) = key {
+                        let confirmed = key == Key::Char('y');
+                        let message = self.aparte.get_plugin_mut::<ConfirmPlugin>().unwrap().answer(confirmed);
+                        match (message, confirmed) {
+                            (Some(message), true) => self.queue.push(Ok(CommandOrMessage::Message(message))),
+                            (Some(message), false) => {
+                                let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                                ui.log_to(Rc::clone(&self.aparte), &window_name(&message), format!("Not sent"));
+                                ui.event(UIEvent::Completed(message.body().to_string()));
+                            },
+                            (None, _) => {},
+                        }
+                    }
+                    continue;
+                }
+
+                let searching = {
+                    let ui = self.aparte.get_plugin::<UIPlugin>().unwrap();
+                    ui.is_searching()
+                };
+
+                if searching {
+                    if let Ok(key) = key {
+                        let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                        ui.search_key(key);
+                    }
+                    continue;
+                }
+
+                let history_searching = {
+                    let ui = self.aparte.get_plugin::<UIPlugin>().unwrap();
+                    ui.is_history_searching()
+                };
+
+                if history_searching {
+                    if let Ok(key) = key {
+                        let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                        ui.history_search_key(key);
+                    }
+                    continue;
+                }
+
+                let switching = {
+                    let ui = self.aparte.get_plugin::<UIPlugin>().unwrap();
+                    ui.is_switching()
+                };
+
+                if switching {
+                    if let Ok(key) = key {
+                        let selected = {
+                            let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                            ui.switcher_key(key)
+                        };
+                        if let Some(jid) = selected {
+                            Rc::clone(&self.aparte).event(Event::Chat(jid));
+                        }
+                    }
+                    continue;
+                }
+
+                let roster_focused = {
+                    let ui = self.aparte.get_plugin::<UIPlugin>().unwrap();
+                    ui.is_roster_focused()
+                };
+
+                if roster_focused {
+                    if let Ok(key) = key {
+                        let selected = {
+                            let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                            ui.roster_key(key)
+                        };
+                        if let Some(jid) = selected {
+                            Rc::clone(&self.aparte).event(Event::Chat(jid));
+                        }
+                    }
+                    continue;
+                }
+
                 match key {
                     Ok(Key::Backspace) => {
                         let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
@@ -781,23 +2799,25 @@ impl Decoder for KeyCodec {
                         let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
                         ui.event(UIEvent::Key(Key::Right));
                     },
-                    Ok(Key::Up) => {
+                    Ok(key @ Key::Up) | Ok(key @ Key::Down) | Ok(key @ Key::PageUp) | Ok(key @ Key::PageDown) => {
                         let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
-                        ui.event(UIEvent::Key(Key::Up));
-                    },
-                    Ok(Key::Down) => {
-                        let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
-                        ui.event(UIEvent::Key(Key::Down));
-                    },
-                    Ok(Key::PageUp) => {
-                        let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
-                        ui.event(UIEvent::Key(Key::PageUp));
-                    },
-                    Ok(Key::PageDown) => {
-                        let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
-                        ui.event(UIEvent::Key(Key::PageDown));
+                        // The rooms window is browsed with arrows instead of the input history
+                        if ui.is_rooms_window() {
+                            ui.event(UIEvent::RoomsKey(key));
+                        } else {
+                            ui.event(UIEvent::Key(key));
+                        }
                     },
                     Ok(Key::Char('\t')) => {
+                        let completed = Rc::new(RefCell::new(false));
+                        {
+                            let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                            ui.event(UIEvent::CompleteEmoji(Rc::clone(&completed)));
+                        }
+                        if *completed.borrow() {
+                            continue;
+                        }
+
                         let result = Rc::new(RefCell::new(None));
                         let event = UIEvent::Complete(Rc::clone(&result));
 
@@ -860,7 +2880,7 @@ impl Decoder for KeyCodec {
                             let mut command = ui.password_command.take().unwrap();
                             command.args.push(raw_buf.clone());
                             self.queue.push(Ok(CommandOrMessage::Command(command)));
-                        } else if raw_buf.starts_with("/") {
+                        } else if raw_buf.starts_with("/") && message::action(&raw_buf).is_none() {
                             match Command::try_from(&*raw_buf) {
                                 Ok(command) => {
                                     self.queue.push(Ok(CommandOrMessage::Command(command)));
@@ -891,6 +2911,10 @@ impl Decoder for KeyCodec {
                                     }
                                 }
                             }
+                        } else if let Some(room) = ui.selected_room() {
+                            // Enter on an empty input joins the room selected in the rooms window
+                            let command = Command::new(vec!["join".to_string(), room.to_string()]);
+                            self.queue.push(Ok(CommandOrMessage::Command(command)));
                         }
                     },
                     Ok(Key::Alt('\x1b')) => {
@@ -918,9 +2942,82 @@ impl Decoder for KeyCodec {
                         let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
                         ui.event(UIEvent::Key(Key::Char(c)));
                     },
-                    Ok(Key::Ctrl('w')) => {
+                    Ok(key @ Key::Ctrl('w')) | Ok(key @ Key::Ctrl('a')) | Ok(key @ Key::Ctrl('e'))
+                        | Ok(key @ Key::Ctrl('k')) | Ok(key @ Key::Ctrl('u')) | Ok(key @ Key::Ctrl('y'))
+                        | Ok(key @ Key::Ctrl('7')) | Ok(key @ Key::Alt('b')) | Ok(key @ Key::Alt('f'))
+                        | Ok(key @ Key::Alt('d')) | Ok(key @ Key::Alt('\r')) | Ok(key @ Key::Alt('\n'))
+                        | Ok(key @ Key::Alt('s')) => {
+                        let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                        ui.event(UIEvent::Key(key));
+                    },
+                    Ok(Key::F(2)) => {
+                        let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                        ui.event(UIEvent::Key(Key::F(2)));
+                    },
+                    Ok(Key::F(3)) => {
+                        let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                        ui.focus_roster(true);
+                    },
+                    Ok(Key::Ctrl('f')) => {
+                        let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                        ui.start_search();
+                    },
+                    Ok(Key::Alt('w')) => {
+                        let result = {
+                            let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                            match ui.selected_message() {
+                                Some(message) => ui.copy(message.body()),
+                                None => Err(format!("No message to copy")),
+                            }
+                        };
+                        if let Err(err) = result {
+                            Rc::clone(&self.aparte).log(err);
+                        }
+                    },
+                    Ok(Key::Alt('u')) => {
+                        let result = {
+                            let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                            let urls = self.aparte.get_plugin::<UrlsPlugin>().unwrap();
+                            match ui.current_window.clone() {
+                                Some(window) => urls.get(&window, None).map(String::from).and_then(|url| ui.copy(&url)),
+                                None => Err(format!("No URL to copy")),
+                            }
+                        };
+                        if let Err(err) = result {
+                            Rc::clone(&self.aparte).log(err);
+                        }
+                    },
+                    Ok(Key::Alt('m')) => {
+                        let command = Command::new(vec!["moved".to_string(), "accept".to_string()]);
+                        self.queue.push(Ok(CommandOrMessage::Command(command)));
+                    },
+                    Ok(Key::Alt('e')) => {
+                        let result = {
+                            let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                            ui.edit()
+                        };
+                        if let Err(err) = result {
+                            Rc::clone(&self.aparte).log(err);
+                        }
+                    },
+                    Ok(Key::Ctrl('r')) => {
+                        let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                        ui.start_history_search();
+                    },
+                    Ok(Key::Ctrl('t')) => {
+                        let contacts = match self.aparte.get_plugin::<ContactPlugin>() {
+                            Some(plugin) => match self.aparte.current_connection() {
+                                Some(account) => plugin.contacts(&account).into_iter().cloned().collect(),
+                                None => Vec::new(),
+                            },
+                            None => Vec::new(),
+                        };
+                        let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
+                        ui.start_switcher(contacts);
+                    },
+                    Ok(Key::Ctrl('o')) => {
                         let mut ui = self.aparte.get_plugin_mut::<UIPlugin>().unwrap();
-                        ui.event(UIEvent::Key(Key::Ctrl('w')));
+                        ui.next_pane();
                     },
                     Ok(_) => {},
                     Err(_) => {},
@@ -939,3 +3036,238 @@ impl Decoder for KeyCodec {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use xmpp_parsers::FullJid;
+    use xmpp_parsers::roster::Subscription;
+    use crate::message::LogMessage;
+
+    /// UI drawn into an offscreen buffer of the given size, with the console opened
+    fn offscreen<'a>(width: u16, height: u16) -> (UIPlugin<'a>, Offscreen) {
+        let config = env::temp_dir().join(format!("aparte-ui-test-{}-{}.toml", process::id(), Uuid::new_v4()));
+        fs::write(&config, "[accounts]\n").unwrap();
+        let aparte = Aparte::new(config.clone());
+        fs::remove_file(config).unwrap();
+
+        let screen = Offscreen::new(width, height);
+        let mut ui = UIPlugin::offscreen(screen.clone());
+        ui.init(&aparte).unwrap();
+        (ui, screen)
+    }
+
+    fn log(body: &str) -> (Message, String) {
+        let timestamp = Utc.timestamp_opt(1_000_000_000, 0).unwrap();
+        let time = Local.from_utc_datetime(&timestamp.naive_local()).format("%T").to_string();
+        (Message::Log(LogMessage { id: Uuid::new_v4().to_string(), timestamp: timestamp, body: body.to_string() }), time)
+    }
+
+    fn contact(jid: &str, name: Option<&str>, presence: contact::Presence, group: Option<&str>) -> contact::Contact {
+        contact::Contact {
+            jid: BareJid::from_str(jid).unwrap(),
+            name: name.map(String::from),
+            subscription: Subscription::Both,
+            presence: presence,
+            groups: group.into_iter().map(|group| contact::Group(group.to_string())).collect(),
+            info: contact::UserInfo::default(),
+        }
+    }
+
+    #[test]
+    fn test_snapshot_empty_layout() {
+        let (_ui, screen) = offscreen(40, 6);
+        assert_eq!(screen.render(), [
+            " console",
+            "",
+            "",
+            "",
+            "                           -1: console-",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_snapshot_roster() {
+        let (mut ui, screen) = offscreen(50, 7);
+        ui.event(UIEvent::Contact(contact("alice@server.tld", Some("Alice"), contact::Presence::Available, Some("Friends"))));
+        ui.event(UIEvent::Contact(contact("bob@server.tld", None, contact::Presence::Away, Some("Friends"))));
+        ui.event(UIEvent::Contact(contact("carole@server.tld", None, contact::Presence::Unavailable, None)));
+        assert_eq!(screen.render(), [
+            " console",
+            "                        ○ carole@server.tld",
+            "                      ▾ Friends (2/2)",
+            "                        ● Alice (alice@server.tld)",
+            "                        ◐ bob@server.tld",
+            "                                     -1: console-",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_snapshot_status_bar() {
+        let (mut ui, screen) = offscreen(80, 4);
+        ui.event(UIEvent::Connected(String::from("me@server.tld")));
+        ui.add_log_window("mentions");
+        ui.event(UIEvent::Activity(String::from("mentions"), true));
+        ui.set_warning(Some(String::from("offline since 12:00")));
+        assert_eq!(screen.render().lines().nth(2).unwrap(), " me@server.tld ⚠ offline since 12:00         -1: console- [2: mentions (1, @1)]");
+        assert_eq!(ui.unread(), vec![(String::from("mentions"), 1, 1)]);
+        ui.set_warning(None);
+        ui.set_recording(Some('a'));
+        assert!(screen.render().lines().nth(2).unwrap().starts_with(" me@server.tld recording @a  "));
+    }
+
+    #[test]
+    fn test_unread() {
+        let (mut ui, _screen) = offscreen(80, 6);
+        let aparte = crate::fakeserver::aparte(|aparte| aparte.add_plugin(ConversationPlugin::new()));
+        let account = FullJid::from_str("me@server.tld/aparte").unwrap();
+        let room = FullJid::from_str("room@chat.server.tld/me").unwrap();
+        Rc::clone(&aparte).event(Event::Join(account.clone(), room.clone()));
+        ui.on_event(Rc::clone(&aparte), &Event::Join(account, room));
+        ui.change_window("console");
+
+        let me = Jid::from_str("me@server.tld/aparte").unwrap();
+        let timestamp = Utc::now();
+        let groupchat = |nick: &str, body: &str| Event::Message(Message::incoming_groupchat(Uuid::new_v4().to_string(), timestamp,
+            &Jid::from_str(&format!("room@chat.server.tld/{}", nick)).unwrap(), &me, body));
+        ui.on_event(Rc::clone(&aparte), &groupchat("alice", "hello"));
+        ui.on_event(Rc::clone(&aparte), &groupchat("alice", "me: ping"));
+        // Neither our own messages nor words merely containing our nick count
+        ui.on_event(Rc::clone(&aparte), &groupchat("me", "pong"));
+        ui.on_event(Rc::clone(&aparte), &groupchat("alice", "meanwhile"));
+        // Direct messages are mentions
+        let chat = Message::incoming_chat(Uuid::new_v4().to_string(), timestamp, &Jid::from_str("juliet@capulet.lit/balcony").unwrap(), &me, "hi");
+        ui.on_event(Rc::clone(&aparte), &Event::Message(chat));
+        assert_eq!(ui.unread(), vec![
+            (String::from("juliet@capulet.lit"), 1, 1),
+            (String::from("room@chat.server.tld"), 3, 1),
+        ]);
+
+        // Reading a window clears its counters, messages to the current window aren't unread
+        ui.change_window("room@chat.server.tld");
+        ui.on_event(Rc::clone(&aparte), &groupchat("alice", "me: still there?"));
+        assert_eq!(ui.unread(), vec![(String::from("juliet@capulet.lit"), 1, 1)]);
+    }
+
+    #[test]
+    fn test_mouse_click() {
+        let (mut ui, screen) = offscreen(40, 6);
+        ui.add_log_window("mentions");
+        let bar = screen.render().lines().nth(4).unwrap().to_string();
+        let column = |label| bar.find(label).unwrap() as u16 + 1;
+        assert_eq!(ui.click(column("2: mentions"), 5), Some(Click::Window(String::from("mentions"))));
+        assert_eq!(ui.click(column("1: console"), 5), Some(Click::Window(String::from("console"))));
+        assert_eq!(ui.click(1, 5), None);
+
+        let (message, _) = log("see https://aparte.dev");
+        ui.event(UIEvent::Message(message));
+        ui.event(UIEvent::Flush);
+        assert_eq!(ui.click(20, 2), Some(Click::Url(String::from("https://aparte.dev"))));
+        assert_eq!(ui.click(2, 2), Some(Click::Window(String::from("console"))));
+        assert_eq!(ui.click(2, 1), None);
+    }
+
+    #[test]
+    fn test_switcher() {
+        let (mut ui, screen) = offscreen(40, 12);
+        ui.add_log_window("mentions");
+        ui.start_switcher(vec![
+            contact("juliet@example.com", Some("Juliet"), contact::Presence::Available, None),
+            contact("romeo@montague.lit", None, contact::Presence::Away, None),
+        ]);
+        assert!(screen.render().contains("Switch to (4/4)"));
+
+        for c in "ju ex".chars() {
+            ui.switcher_key(Key::Char(c));
+        }
+        let render = screen.render();
+        assert!(render.contains("Switch to (1/4)"));
+        assert!(render.contains("│Juliet (juliet@example.com)"));
+        assert_eq!(ui.switcher_key(Key::Char('\n')), Some(BareJid::from_str("juliet@example.com").unwrap()));
+        assert!(!ui.is_switching());
+
+        ui.start_switcher(Vec::new());
+        ui.switcher_key(Key::Char('m'));
+        ui.switcher_key(Key::Char('\n'));
+        assert_eq!(ui.current_window(), Some("mentions"));
+    }
+
+    #[test]
+    fn test_snapshot_wrapping() {
+        let (mut ui, screen) = offscreen(30, 6);
+        let (message, time) = log("Connecté à server.tld, réponse très longue");
+        ui.event(UIEvent::Message(message));
+        ui.event(UIEvent::Flush);
+        assert_eq!(screen.render(), [
+            " console".to_string(),
+            format!("{} - Connecté à server.", time),
+            "tld, réponse très longue".to_string(),
+            "".to_string(),
+            "                 -1: console-".to_string(),
+            "".to_string(),
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_roster_rows() {
+        let mut roster = Roster {
+            contacts: HashMap::new(),
+            grouping: config::RosterGrouping::Groups,
+            unread: HashMap::new(),
+            collapsed: HashSet::new(),
+            current_window: None,
+            visible: true,
+            focused: false,
+            selected: 0,
+            notes: HashMap::new(),
+            rendered: None,
+        };
+        let mut dave = contact("dave@other.tld", None, contact::Presence::Dnd, Some("Friends"));
+        dave.groups.push(contact::Group(String::from("Work")));
+        for contact in vec![
+            contact("bob@server.tld", Some("Bob"), contact::Presence::Available, Some("Friends")),
+            contact("alice@server.tld", Some("alice"), contact::Presence::Available, Some("Friends")),
+            contact("carole@server.tld", None, contact::Presence::Unavailable, None),
+            contact("frank@server.tld", None, contact::Presence::Unavailable, Some("Friends")),
+            contact("eve@other.tld", None, contact::Presence::Away, Some("Work")),
+            dave,
+        ] {
+            roster.contacts.insert(contact.jid.clone(), contact);
+        }
+        let rows = |roster: &Roster| roster.rows().into_iter().map(|row| match row {
+            RosterRow::Group(group, true, online, total) => format!("{} ({}/{}) collapsed", group.0, online, total),
+            RosterRow::Group(group, false, online, total) => format!("{} ({}/{})", group.0, online, total),
+            RosterRow::Contact(contact) => contact.jid.to_string(),
+        }).collect::<Vec<_>>();
+
+        // Contacts without group first, then each group sorted by presence then by name, a contact
+        // being in every group it has
+        assert_eq!(rows(&roster), vec![
+            "carole@server.tld",
+            "Friends (3/4)", "alice@server.tld", "bob@server.tld", "dave@other.tld", "frank@server.tld",
+            "Work (2/2)", "eve@other.tld", "dave@other.tld",
+        ]);
+
+        roster.collapsed.insert(contact::Group(String::from("Friends")));
+        assert_eq!(rows(&roster), vec!["carole@server.tld", "Friends (3/4) collapsed", "Work (2/2)", "eve@other.tld", "dave@other.tld"]);
+
+        // Every contact in the group of its server instead
+        roster.collapsed.clear();
+        roster.grouping = config::RosterGrouping::Servers;
+        assert_eq!(rows(&roster), vec![
+            "other.tld (2/2)", "eve@other.tld", "dave@other.tld",
+            "server.tld (2/4)", "alice@server.tld", "bob@server.tld", "carole@server.tld", "frank@server.tld",
+        ]);
+    }
+
+    #[test]
+    fn test_server_group() {
+        let group = |jid| server_group(&BareJid::from_str(jid).unwrap()).0;
+        assert_eq!(group("contact@server.tld"), "server.tld");
+        assert_eq!(group("nick%irc.libera.chat@biboumi.server.tld"), "irc.libera.chat via biboumi.server.tld");
+        assert_eq!(group("alice_matrix.org@matrix.server.tld"), "matrix.server.tld");
+    }
+}
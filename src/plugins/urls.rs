@@ -0,0 +1,153 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use crate::core::{Plugin, Aparte, Event, EventKind, Priority, Propagation};
+use crate::message::{self, Message, XmppMessage};
+
+/// Number of URLs remembered per conversation
+const MAX_URLS: usize = 100;
+
+/// Open a URL with the desktop default application
+pub fn open(url: &str) -> Result<(), String> {
+    let child = Command::new("xdg-open")
+        .arg(url)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+
+    match child {
+        Ok(mut child) => {
+            // Reap the child without blocking the event loop
+            thread::spawn(move || child.wait());
+            Ok(())
+        },
+        Err(err) => Err(format!("Cannot open {}: {}", url, err)),
+    }
+}
+
+thread_local! {
+    /// Index of the first URL of the messages numbered, by conversation and message id, for the
+    /// interface to show the indices inline
+    static FIRST_INDEX: RefCell<HashMap<(String, String), usize>> = RefCell::new(HashMap::new());
+}
+
+/// Index of the first URL of a message, the next ones following it
+pub fn first_index(conversation: &str, id: &str) -> Option<usize> {
+    FIRST_INDEX.with(|first_index| first_index.borrow().get(&(conversation.to_string(), id.to_string())).cloned())
+}
+
+/// URLs remembered in a conversation
+#[derive(Default)]
+struct Urls {
+    /// Index of the next URL, never reused so that an index keeps designating the same URL
+    next: usize,
+    /// Index, URL and id of the message, oldest first
+    urls: VecDeque<(usize, String, String)>,
+}
+
+/// Remember URLs received in each conversation so they can be listed and opened by index
+pub struct UrlsPlugin {
+    urls: HashMap<String, Urls>,
+}
+
+impl UrlsPlugin {
+    /// URLs received in a conversation with their index, numbered from 1 by order of arrival
+    pub fn urls(&self, conversation: &str) -> Vec<(usize, &str)> {
+        match self.urls.get(conversation) {
+            Some(urls) => urls.urls.iter().map(|(index, url, _)| (*index, url.as_str())).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// URL with the given index in a conversation, the last one when no index is given
+    pub fn get(&self, conversation: &str, index: Option<usize>) -> Result<&str, String> {
+        let urls = self.urls.get(conversation).map(|urls| &urls.urls);
+        let url = match index {
+            None => urls.and_then(|urls| urls.back()).ok_or(format!("No URL in {}", conversation)),
+            Some(index) => urls.and_then(|urls| urls.iter().find(|(other, _, _)| *other == index)).ok_or(format!("No URL {} in {}", index, conversation)),
+        };
+        url.map(|(_, url, _)| url.as_str())
+    }
+
+    fn add(&mut self, conversation: String, id: &str, body: &str) {
+        let found = message::urls(body);
+        if found.is_empty() {
+            return;
+        }
+
+        let urls = self.urls.entry(conversation.clone()).or_insert_with(Urls::default);
+        FIRST_INDEX.with(|first_index| first_index.borrow_mut().insert((conversation.clone(), id.to_string()), urls.next + 1));
+        for (_, url) in found {
+            urls.next += 1;
+            urls.urls.push_back((urls.next, url.to_string(), id.to_string()));
+        }
+
+        while urls.urls.len() > MAX_URLS {
+            let (_, _, id) = urls.urls.pop_front().unwrap();
+            // Forget the message once none of its URLs is left
+            if urls.urls.front().map_or(true, |(_, _, next)| *next != id) {
+                FIRST_INDEX.with(|first_index| first_index.borrow_mut().remove(&(conversation.clone(), id)));
+            }
+        }
+    }
+}
+
+impl Plugin for UrlsPlugin {
+    fn new() -> UrlsPlugin {
+        Self {
+            urls: HashMap::new(),
+        }
+    }
+
+    fn init(&mut self, aparte: &Aparte) -> Result<(), ()> {
+        // Number the URLs before the interface displays the message, with their indices
+        aparte.subscribe(EventKind::Message, Priority::ANNOTATE, |urls: &mut UrlsPlugin, _aparte, event| {
+            match event {
+                Event::Message(Message::Incoming(XmppMessage::Chat(message))) => urls.add(message.from.to_string(), &message.id, &message.body),
+                Event::Message(Message::Incoming(XmppMessage::Groupchat(message))) => urls.add(message.from.to_string(), &message.id, &message.body),
+                _ => {},
+            }
+            Propagation::Continue
+        });
+        Ok(())
+    }
+}
+
+impl fmt::Display for UrlsPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "URLs")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_urls_by_index() {
+        let mut plugin = UrlsPlugin::new();
+        plugin.add("contact@server.tld".to_string(), "1", "https://one.tld and https://two.tld");
+        plugin.add("contact@server.tld".to_string(), "2", "no link");
+
+        assert_eq!(plugin.get("contact@server.tld", None), Ok("https://two.tld"));
+        assert_eq!(plugin.get("contact@server.tld", Some(1)), Ok("https://one.tld"));
+        assert!(plugin.get("contact@server.tld", Some(3)).is_err());
+        assert!(plugin.get("other@server.tld", None).is_err());
+        assert_eq!(first_index("contact@server.tld", "1"), Some(1));
+        assert_eq!(first_index("contact@server.tld", "2"), None);
+
+        for id in 3..MAX_URLS + 3 {
+            plugin.add("contact@server.tld".to_string(), &id.to_string(), "https://more.tld");
+        }
+        assert_eq!(plugin.urls("contact@server.tld").len(), MAX_URLS);
+        // Indices aren't shifted by the URLs dropped, which are forgotten
+        assert!(plugin.get("contact@server.tld", Some(2)).is_err());
+        assert_eq!(plugin.urls("contact@server.tld")[0], (3, "https://more.tld"));
+        assert_eq!(first_index("contact@server.tld", "1"), None);
+        assert_eq!(first_index("contact@server.tld", "4"), Some(4));
+    }
+}
@@ -0,0 +1,184 @@
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+use xmpp_parsers::{BareJid, Element, Jid};
+
+use crate::core::{Plugin, Aparte, Event};
+use crate::plugins::ui::UIPlugin;
+
+pub const WINDOW: &str = "xmlconsole";
+
+const INDENT: &str = "  ";
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn write_pretty(element: &Element, parent_ns: Option<&str>, depth: usize, lines: &mut Vec<String>) {
+    let indent = INDENT.repeat(depth);
+    let mut tag = format!("{}<{}", indent, element.name());
+    let ns = element.ns();
+    if ns.is_some() && ns.as_deref() != parent_ns {
+        tag.push_str(&format!(" xmlns=\"{}\"", escape(ns.as_deref().unwrap())));
+    }
+    for (name, value) in element.attrs() {
+        tag.push_str(&format!(" {}=\"{}\"", name, escape(value)));
+    }
+
+    let children: Vec<&Element> = element.children().collect();
+    let text = element.text();
+    let text = text.trim();
+
+    if children.is_empty() && text.is_empty() {
+        lines.push(format!("{}/>", tag));
+    } else if children.is_empty() {
+        // Elements only holding text are kept on a single line
+        lines.push(format!("{}>{}</{}>", tag, escape(text), element.name()));
+    } else {
+        lines.push(format!("{}>", tag));
+        for child in children {
+            write_pretty(child, ns.as_deref(), depth + 1, lines);
+        }
+        if !text.is_empty() {
+            lines.push(format!("{}{}{}", indent, INDENT, escape(text)));
+        }
+        lines.push(format!("{}</{}>", indent, element.name()));
+    }
+}
+
+/// Indented rendering of a stanza, one element per line
+pub fn pretty(element: &Element) -> String {
+    let mut lines = Vec::new();
+    write_pretty(element, None, 0, &mut lines);
+    lines.join("\n")
+}
+
+/// Stanzas shown in the XML console
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Message,
+    Presence,
+    Iq,
+    /// Stanzas from or to a contact or channel
+    Jid(BareJid),
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "message" => Ok(Filter::Message),
+            "presence" => Ok(Filter::Presence),
+            "iq" => Ok(Filter::Iq),
+            jid => match BareJid::from_str(jid) {
+                Ok(jid) => Ok(Filter::Jid(jid)),
+                Err(err) => Err(format!("Invalid filter {}: {}", jid, err)),
+            },
+        }
+    }
+}
+
+impl Filter {
+    fn matches(&self, stanza: &Element) -> bool {
+        match self {
+            Filter::Message => stanza.name() == "message",
+            Filter::Presence => stanza.name() == "presence",
+            Filter::Iq => stanza.name() == "iq",
+            Filter::Jid(jid) => ["from", "to"].iter().any(|attr| {
+                match stanza.attr(attr).map(Jid::from_str) {
+                    Some(Ok(stanza_jid)) => &BareJid::from(stanza_jid) == jid,
+                    _ => false,
+                }
+            }),
+        }
+    }
+}
+
+/// Show raw stanzas exchanged with the server in a dedicated window
+pub struct XmlConsolePlugin {
+    enabled: bool,
+    filters: Vec<Filter>,
+}
+
+impl XmlConsolePlugin {
+    /// Start showing stanzas matching any of the filters, every stanza when there is none
+    pub fn open(&mut self, filters: Vec<Filter>) {
+        self.enabled = true;
+        self.filters = filters;
+    }
+
+    pub fn close(&mut self) {
+        self.enabled = false;
+    }
+
+    fn show(&self, stanza: &Element) -> bool {
+        self.enabled && (self.filters.is_empty() || self.filters.iter().any(|filter| filter.matches(stanza)))
+    }
+
+    fn log(&self, aparte: Rc<Aparte>, marker: &str, stanza: &Element) {
+        if self.show(stanza) {
+            let mut ui = aparte.get_plugin_mut::<UIPlugin>().unwrap();
+            ui.log_to(Rc::clone(&aparte), WINDOW, format!("{}\n{}", marker, pretty(stanza)));
+        }
+    }
+}
+
+impl Plugin for XmlConsolePlugin {
+    fn new() -> XmlConsolePlugin {
+        Self {
+            enabled: false,
+            filters: Vec::new(),
+        }
+    }
+
+    fn init(&mut self, _aparte: &Aparte) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn on_event(&mut self, aparte: Rc<Aparte>, event: &Event) {
+        match event {
+            Event::ReceivedStanza(stanza) => self.log(aparte, "<<< received", stanza),
+            Event::SentStanza(stanza) => self.log(aparte, ">>> sent", stanza),
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for XmlConsolePlugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "XML console")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pretty() {
+        let element: Element = r#"<message xmlns="jabber:client" to="contact@server.tld" type="chat"><body>Fish &amp; chips</body><active xmlns="http://jabber.org/protocol/chatstates"/></message>"#.parse().unwrap();
+        assert_eq!(pretty(&element), r#"<message xmlns="jabber:client" to="contact@server.tld" type="chat">
+  <body>Fish &amp; chips</body>
+  <active xmlns="http://jabber.org/protocol/chatstates"/>
+</message>"#);
+    }
+
+    #[test]
+    fn test_filters() {
+        let message: Element = r#"<message xmlns="jabber:client" from="contact@server.tld/phone"/>"#.parse().unwrap();
+        let iq: Element = r#"<iq xmlns="jabber:client" to="server.tld" type="get" id="1"/>"#.parse().unwrap();
+
+        let mut plugin = XmlConsolePlugin::new();
+        assert!(!plugin.show(&message));
+
+        plugin.open(Vec::new());
+        assert!(plugin.show(&message) && plugin.show(&iq));
+
+        plugin.open(vec![Filter::from_str("contact@server.tld").unwrap()]);
+        assert!(plugin.show(&message) && !plugin.show(&iq));
+
+        plugin.open(vec![Filter::from_str("iq").unwrap(), Filter::from_str("presence").unwrap()]);
+        assert!(!plugin.show(&message) && plugin.show(&iq));
+    }
+}
@@ -0,0 +1,123 @@
+//! Profiles keep apart the config, history, caches, logs and sockets of several aparté instances,
+//! as one for work and one for personal accounts, each started with `--profile <name>`. Without
+//! profile, files are where they always were. The profile is kept in the environment, so that
+//! scripts and hooks started by aparté, and aparte-ctl run from them, use the same one.
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+pub const ENV: &str = "APARTE_PROFILE";
+
+/// Name of the profile aparté runs with, none for the default one
+pub fn name() -> Option<String> {
+    env::var(ENV).ok().filter(|name| !name.is_empty())
+}
+
+/// Whether a profile name can be used as a directory name
+pub fn is_valid(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+/// Run with a profile, before anything is read or written
+pub fn set(name: &str) -> Result<(), String> {
+    if !is_valid(name) {
+        return Err(format!("Invalid profile name {}", name));
+    }
+    env::set_var(ENV, name);
+    Ok(())
+}
+
+fn dir(base: Option<PathBuf>) -> PathBuf {
+    let dir = base.unwrap().join("aparté");
+    match name() {
+        Some(name) => dir.join("profiles").join(name),
+        None => dir,
+    }
+}
+
+/// Directory of the history, settings, caches and logs of the profile
+pub fn data_dir() -> PathBuf {
+    dir(dirs::data_dir())
+}
+
+/// Directory of the config of the profile
+pub fn config_dir() -> PathBuf {
+    dir(dirs::config_dir())
+}
+
+/// Directory of the sockets of every profile, only accessible by us, so that no one else can
+/// connect to them whatever their own permissions
+pub fn sockets_dir() -> Result<PathBuf, String> {
+    match (dirs::runtime_dir(), dirs::data_dir()) {
+        (Some(runtime_dir), _) => Ok(runtime_dir.join("aparté")),
+        (None, Some(data_dir)) => Ok(data_dir.join("aparté").join("sockets")),
+        (None, None) => Err(String::from("Cannot find a directory for sockets, neither XDG_RUNTIME_DIR nor HOME is set")),
+    }
+}
+
+/// Path of a socket of the profile
+pub fn socket(name: &str) -> Result<PathBuf, String> {
+    let file = match self::name() {
+        Some(profile) => format!("{}-{}.sock", name, profile),
+        None => format!("{}.sock", name),
+    };
+    Ok(sockets_dir()?.join(file))
+}
+
+/// Path of the control socket of the profile, aparte-ctl connecting where aparté listens
+pub fn control_socket() -> Result<PathBuf, String> {
+    socket("control")
+}
+
+/// Path typed by the user, starting with `~/` for the home directory
+pub fn expand_home(path: &str) -> Result<PathBuf, String> {
+    match path.strip_prefix("~/") {
+        Some(path) => Ok(dirs::home_dir().ok_or(format!("No home directory"))?.join(path)),
+        None => Ok(PathBuf::from(path)),
+    }
+}
+
+/// Profiles created so far, the default one being listed first as default
+pub fn list() -> Vec<String> {
+    let mut profiles = Vec::new();
+    for base in &[dirs::config_dir(), dirs::data_dir()] {
+        let entries = match base.as_ref().map(|base| fs::read_dir(base.join("aparté").join("profiles"))) {
+            Some(Ok(entries)) => entries,
+            _ => continue,
+        };
+        for entry in entries.filter_map(Result::ok) {
+            if let Some(name) = entry.file_name().to_str() {
+                if is_valid(name) && entry.path().is_dir() && !profiles.contains(&name.to_string()) {
+                    profiles.push(name.to_string());
+                }
+            }
+        }
+    }
+    profiles.sort();
+    profiles.insert(0, String::from("default"));
+    profiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_name() {
+        assert!(is_valid("work"));
+        assert!(is_valid("perso-2.0"));
+        assert!(!is_valid(""));
+        assert!(!is_valid(".."));
+        assert!(!is_valid("../work"));
+        assert!(!is_valid("a/b"));
+        assert!(set("a/b").is_err());
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(expand_home("~/Downloads/file.txt").unwrap(), home.join("Downloads/file.txt"));
+        assert_eq!(expand_home("/tmp/~/file.txt").unwrap(), PathBuf::from("/tmp/~/file.txt"));
+        assert_eq!(expand_home("file.txt").unwrap(), PathBuf::from("file.txt"));
+    }
+}
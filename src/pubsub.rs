@@ -0,0 +1,178 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+use uuid::Uuid;
+use xmpp_parsers::disco::{DiscoItemsQuery, DiscoItemsResult};
+use xmpp_parsers::iq::{Iq, IqType};
+use xmpp_parsers::{ns, BareJid, Element, Jid};
+
+use crate::plugins::xmlconsole;
+
+/// Requests of XEP-0060 made with /pubsub
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Nodes of a service, through disco#items
+    Nodes,
+    Items,
+    Publish,
+    Subscribe,
+    /// Delete a node, or retract one of its items
+    Delete,
+}
+
+pub const ACTIONS: [&str; 5] = ["nodes", "items", "publish", "subscribe", "delete"];
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nodes" => Ok(Action::Nodes),
+            "items" => Ok(Action::Items),
+            "publish" => Ok(Action::Publish),
+            "subscribe" => Ok(Action::Subscribe),
+            "delete" => Ok(Action::Delete),
+            action => Err(format!("Unknown action {}, expected one of {}", action, ACTIONS.join(", "))),
+        }
+    }
+}
+
+fn pubsub(ns: &str, child: Element) -> Element {
+    Element::builder("pubsub").ns(ns).append(child).build()
+}
+
+/// Iq of a request to `service`, `args` being the item for publish and its id for delete
+pub fn request(action: Action, service: Jid, node: Option<&str>, args: &str, account: &BareJid) -> Result<Iq, String> {
+    let id = Uuid::new_v4().to_hyphenated().to_string();
+    let node = match (action, node) {
+        (Action::Nodes, node) => return Ok(Iq::from_get(id, DiscoItemsQuery { node: node.map(String::from) }).with_to(service)),
+        (_, Some(node)) => node,
+        (_, None) => return Err(format!("Missing node argument")),
+    };
+    let payload = match action {
+        Action::Nodes => return Err(format!("Nodes are listed with disco#items, not with a pubsub request")),
+        Action::Items => IqType::Get(pubsub(ns::PUBSUB, Element::builder("items").ns(ns::PUBSUB).attr("node", node).build())),
+        Action::Publish => {
+            let item = Element::from_str(args).map_err(|err| format!("Invalid item {}: {}", args, err))?;
+            if item.ns().is_none() {
+                return Err(format!("Item {} has no namespace", item.name()));
+            }
+            IqType::Set(pubsub(ns::PUBSUB, Element::builder("publish").ns(ns::PUBSUB)
+                .attr("node", node)
+                .append(Element::builder("item").ns(ns::PUBSUB).append(item).build())
+                .build()))
+        },
+        Action::Subscribe => IqType::Set(pubsub(ns::PUBSUB, Element::builder("subscribe").ns(ns::PUBSUB)
+            .attr("node", node)
+            .attr("jid", account.to_string())
+            .build())),
+        Action::Delete => match args {
+            "" => IqType::Set(pubsub(ns::PUBSUB_OWNER, Element::builder("delete").ns(ns::PUBSUB_OWNER).attr("node", node).build())),
+            item => IqType::Set(pubsub(ns::PUBSUB, Element::builder("retract").ns(ns::PUBSUB)
+                .attr("node", node)
+                .attr("notify", "true")
+                .append(Element::builder("item").ns(ns::PUBSUB).attr("id", item).build())
+                .build())),
+        },
+    };
+    Ok(Iq { from: None, to: Some(service), id: id, payload: payload })
+}
+
+/// Lines printed for the answer to a request, payloads of items pretty-printed
+pub fn answer(action: Action, node: Option<&str>, payload: Option<Element>) -> Vec<String> {
+    let node = node.unwrap_or("");
+    let child = |name| payload.as_ref()
+        .and_then(|payload| payload.get_child(name, ns::PUBSUB))
+        .map(Element::clone);
+    match action {
+        Action::Nodes => {
+            let items = payload.and_then(|payload| DiscoItemsResult::try_from(payload).ok()).map(|result| result.items).unwrap_or_default();
+            let mut lines: Vec<String> = items.iter().map(|item| {
+                let mut line = format!("  {}", item.node.as_deref().unwrap_or(""));
+                if item.node.is_none() {
+                    line.push_str(&item.jid.to_string());
+                }
+                if let Some(name) = &item.name {
+                    line.push_str(&format!(" ({})", name));
+                }
+                line
+            }).collect();
+            lines.insert(0, match lines.len() {
+                0 => String::from("No node"),
+                1 => String::from("1 node:"),
+                count => format!("{} nodes:", count),
+            });
+            lines
+        },
+        Action::Items => {
+            let items: Vec<Element> = child("items").map(|items| items.children().filter(|item| item.is("item", ns::PUBSUB)).cloned().collect()).unwrap_or_default();
+            let mut lines = vec![match items.len() {
+                0 => format!("No item in {}", node),
+                1 => format!("1 item in {}:", node),
+                count => format!("{} items in {}:", count, node),
+            }];
+            for item in items {
+                lines.push(format!("Item {}:", item.attr("id").unwrap_or("")));
+                lines.extend(item.children().map(xmlconsole::pretty));
+            }
+            lines
+        },
+        Action::Publish => {
+            let id = child("publish").and_then(|publish| publish.get_child("item", ns::PUBSUB).and_then(|item| item.attr("id")).map(String::from));
+            match id {
+                Some(id) => vec![format!("Published item {} on {}", id, node)],
+                None => vec![format!("Published on {}", node)],
+            }
+        },
+        Action::Subscribe => {
+            let state = child("subscription").and_then(|subscription| subscription.attr("subscription").map(String::from));
+            vec![format!("Subscription to {}: {}", node, state.as_deref().unwrap_or("subscribed"))]
+        },
+        Action::Delete => vec![format!("Deleted from {}", node)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests() {
+        let account = BareJid::from_str("juliet@capulet.lit").unwrap();
+        let service = Jid::from_str("pubsub.shakespeare.lit").unwrap();
+
+        let publish = request(Action::Publish, service.clone(), Some("princely_musings"), r#"<entry xmlns="http://www.w3.org/2005/Atom"><title>Soliloquy</title></entry>"#, &account).unwrap();
+        match publish.payload {
+            IqType::Set(pubsub) => {
+                let publish = pubsub.get_child("publish", ns::PUBSUB).unwrap();
+                assert_eq!(publish.attr("node"), Some("princely_musings"));
+                assert!(publish.get_child("item", ns::PUBSUB).unwrap().has_child("entry", "http://www.w3.org/2005/Atom"));
+            },
+            _ => panic!("Not a set"),
+        }
+        assert!(request(Action::Publish, service.clone(), Some("princely_musings"), "<entry/>", &account).is_err());
+        assert!(request(Action::Items, service.clone(), None, "", &account).is_err());
+
+        match request(Action::Delete, service.clone(), Some("princely_musings"), "", &account).unwrap().payload {
+            IqType::Set(pubsub) => assert!(pubsub.is("pubsub", ns::PUBSUB_OWNER) && pubsub.has_child("delete", ns::PUBSUB_OWNER)),
+            _ => panic!("Not a set"),
+        }
+        match request(Action::Subscribe, service, Some("princely_musings"), "", &account).unwrap().payload {
+            IqType::Set(pubsub) => assert_eq!(pubsub.get_child("subscribe", ns::PUBSUB).unwrap().attr("jid"), Some("juliet@capulet.lit")),
+            _ => panic!("Not a set"),
+        }
+    }
+
+    #[test]
+    fn test_answers() {
+        let items: Element = r#"<pubsub xmlns="http://jabber.org/protocol/pubsub"><items node="princely_musings"><item id="368866411b877c30064a5f62b917cffe"><entry xmlns="http://www.w3.org/2005/Atom"><title>Soliloquy</title></entry></item></items></pubsub>"#.parse().unwrap();
+        assert_eq!(answer(Action::Items, Some("princely_musings"), Some(items)), vec![
+            "1 item in princely_musings:",
+            "Item 368866411b877c30064a5f62b917cffe:",
+            "<entry xmlns=\"http://www.w3.org/2005/Atom\">\n  <title>Soliloquy</title>\n</entry>",
+        ]);
+
+        let nodes: Element = r#"<query xmlns="http://jabber.org/protocol/disco#items"><item jid="pubsub.shakespeare.lit" node="blogs" name="Weblog updates"/></query>"#.parse().unwrap();
+        assert_eq!(answer(Action::Nodes, None, Some(nodes)), vec!["1 node:", "  blogs (Weblog updates)"]);
+        assert_eq!(answer(Action::Subscribe, Some("blogs"), None), vec!["Subscription to blogs: subscribed"]);
+    }
+}
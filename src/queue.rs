@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use xmpp_parsers::Element;
+
+use crate::config::RateLimit;
+
+const CHATSTATES_NS: &str = "http://jabber.org/protocol/chatstates";
+
+/// Stanzas waiting to be sent on a connection, in order, paced not to exceed the rate servers
+/// limit them to, as mod_limits of Prosody. Chat states still waiting are replaced by newer ones.
+pub struct SendQueue {
+    limit: RateLimit,
+    /// Stanzas that can be sent right away, up to the burst
+    tokens: f64,
+    refilled: Instant,
+    stanzas: VecDeque<Element>,
+}
+
+/// Recipient of a message only made of a chat state
+fn chat_state_to(element: &Element) -> Option<String> {
+    if !element.is("message", "jabber:client") || element.get_child("body", "jabber:client").is_some() {
+        return None;
+    }
+    match element.children().any(|child| child.ns().as_deref() == Some(CHATSTATES_NS)) {
+        true => Some(element.attr("to").unwrap_or("").to_string()),
+        false => None,
+    }
+}
+
+impl SendQueue {
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            limit: limit,
+            refilled: now,
+            stanzas: VecDeque::new(),
+        }
+    }
+
+    fn is_limited(&self) -> bool {
+        self.limit.rate > 0.
+    }
+
+    pub fn len(&self) -> usize {
+        self.stanzas.len()
+    }
+
+    pub fn push(&mut self, element: Element) {
+        // The chat state of a message, or a newer one, makes the one still waiting useless
+        if element.is("message", "jabber:client") {
+            let to = element.attr("to").unwrap_or("").to_string();
+            self.stanzas.retain(|waiting| chat_state_to(waiting).as_ref() != Some(&to));
+        }
+        self.stanzas.push_back(element);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        self.refilled = now;
+        self.tokens = f64::min(self.limit.burst as f64, self.tokens + elapsed.as_secs_f64() * self.limit.rate);
+    }
+
+    /// Next stanza that can be sent now, if any
+    pub fn pop(&mut self, now: Instant) -> Option<Element> {
+        if self.is_limited() {
+            self.refill(now);
+            if self.tokens < 1. || self.len() == 0 {
+                return None;
+            }
+            self.tokens -= 1.;
+        }
+        self.stanzas.pop_front()
+    }
+
+    /// Every stanza waiting, regardless of the rate
+    pub fn drain(&mut self) -> Vec<Element> {
+        self.stanzas.drain(..).collect()
+    }
+
+    /// Time to wait before the next stanza waiting can be sent
+    pub fn delay(&self) -> Option<Duration> {
+        if !self.is_limited() || self.len() == 0 {
+            return None;
+        }
+        let missing = f64::max(0., 1. - self.tokens);
+        Some(Duration::from_secs_f64(missing / self.limit.rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn stanza(xml: &str) -> Element {
+        Element::from_str(xml).unwrap()
+    }
+
+    fn message(to: &str, body: &str) -> Element {
+        stanza(&format!("<message xmlns='jabber:client' to='{}' type='chat'><body>{}</body><active xmlns='{}'/></message>", to, body, CHATSTATES_NS))
+    }
+
+    fn chat_state(to: &str, state: &str) -> Element {
+        stanza(&format!("<message xmlns='jabber:client' to='{}' type='chat'><{} xmlns='{}'/></message>", to, state, CHATSTATES_NS))
+    }
+
+    #[test]
+    fn test_rate() {
+        let now = Instant::now();
+        let mut queue = SendQueue::new(RateLimit { rate: 2., burst: 2 }, now);
+        for body in &["1", "2", "3"] {
+            queue.push(message("juliet@capulet.lit", body));
+        }
+        assert!(queue.pop(now).is_some());
+        assert!(queue.pop(now).is_some());
+        assert!(queue.pop(now).is_none());
+        assert_eq!(queue.delay(), Some(Duration::from_millis(500)));
+        assert!(queue.pop(now + Duration::from_millis(500)).is_some());
+        assert_eq!(queue.delay(), None);
+
+        // Without limit, every stanza goes right away
+        let mut queue = SendQueue::new(RateLimit { rate: 0., burst: 0 }, now);
+        queue.push(message("juliet@capulet.lit", "1"));
+        queue.push(message("juliet@capulet.lit", "2"));
+        assert!(queue.pop(now).is_some());
+        assert!(queue.pop(now).is_some());
+    }
+
+    #[test]
+    fn test_order() {
+        let now = Instant::now();
+        let mut queue = SendQueue::new(RateLimit { rate: 1., burst: 10 }, now);
+        queue.push(chat_state("juliet@capulet.lit", "composing"));
+        queue.push(chat_state("romeo@montague.lit", "composing"));
+        queue.push(message("juliet@capulet.lit", "1"));
+        queue.push(chat_state("romeo@montague.lit", "paused"));
+        queue.push(stanza("<iq xmlns='jabber:client' type='get' id='ping'><ping xmlns='urn:xmpp:ping'/></iq>"));
+        assert_eq!(queue.len(), 3);
+
+        // Requests wait for their turn like any other stanza
+        let sent: Vec<Element> = (0..3).filter_map(|_| queue.pop(now)).collect();
+        assert_eq!(sent[0].get_child("body", "jabber:client").unwrap().text(), "1");
+        assert!(sent[1].has_child("paused", CHATSTATES_NS));
+        assert!(sent[2].is("iq", "jabber:client"));
+
+        // Everything goes when closing, the rate aside
+        queue.push(message("juliet@capulet.lit", "2"));
+        queue.push(stanza("<iq xmlns='jabber:client' type='get' id='ping'><ping xmlns='urn:xmpp:ping'/></iq>"));
+        let sent = queue.drain();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[1].is("iq", "jabber:client"));
+        assert_eq!(queue.len(), 0);
+    }
+}
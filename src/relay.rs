@@ -0,0 +1,364 @@
+//! Detached sessions: `aparte --detached` keeps the connections of a session alive without a
+//! terminal, drawing the interface for the frontends attached with `aparte --attach`, as dtach or
+//! tmux do. Any number of terminals can attach, they all show the interface at the size of the last
+//! one attached or resized, and type into it. The session daemonizes, leaving the terminal it
+//! was started from at once.
+use bytes::BytesMut;
+use futures::sync::mpsc;
+use futures::{future, stream, Async, Future, Sink, Stream};
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+use tokio::codec::FramedRead;
+use tokio::io::AsyncRead;
+use tokio_codec::Decoder;
+
+use crate::command::CommandError;
+use crate::control;
+use crate::core::{Aparte, CommandOrMessage};
+use crate::plugins::ui::{KeyCodec, UIPlugin, ENABLE_TERMINAL_MODES, DISABLE_TERMINAL_MODES};
+use crate::profile;
+use crate::terminus::Terminal;
+
+/// Key detaching a frontend, Ctrl-\ as for dtach
+const DETACH: u8 = 0x1c;
+/// Screen updates waiting to be written to a frontend, which is dropped when lagging further
+/// behind, as over a slow link. Attaching again draws everything anew.
+const OUTPUT_BACKLOG: usize = 64;
+/// Keys typed in frontends waiting to be handled, frontends not being read further meanwhile
+const KEYS_BACKLOG: usize = 16;
+
+pub fn socket_path() -> Result<PathBuf, String> {
+    profile::socket("relay")
+}
+
+/// What a frontend sends, the interface being sent back as is
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    /// Bytes typed
+    Keys(Vec<u8>),
+    /// Width and height of the terminal
+    Size(u16, u16),
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            Frame::Keys(keys) => {
+                for chunk in keys.chunks(u16::max_value() as usize) {
+                    bytes.push(b'k');
+                    bytes.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+                    bytes.extend_from_slice(chunk);
+                }
+            },
+            Frame::Size(width, height) => {
+                bytes.push(b's');
+                bytes.extend_from_slice(&width.to_be_bytes());
+                bytes.extend_from_slice(&height.to_be_bytes());
+            },
+        }
+        bytes
+    }
+}
+
+pub struct FrameCodec;
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let header = match buf.first() {
+            None => return Ok(None),
+            Some(b'k') => 3,
+            Some(b's') => 5,
+            Some(kind) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown frame {}", kind))),
+        };
+        if buf.len() < header {
+            return Ok(None);
+        }
+        let first = u16::from_be_bytes([buf[1], buf[2]]);
+        match buf[0] {
+            b'k' => {
+                let len = first as usize;
+                if buf.len() < header + len {
+                    return Ok(None);
+                }
+                let frame = buf.split_to(header + len);
+                Ok(Some(Frame::Keys(frame[header..].to_vec())))
+            },
+            _ => {
+                let second = u16::from_be_bytes([buf[3], buf[4]]);
+                buf.split_to(header);
+                Ok(Some(Frame::Size(first, second)))
+            },
+        }
+    }
+}
+
+struct Frontends {
+    /// Output not flushed yet
+    buffer: Vec<u8>,
+    frontends: Vec<mpsc::Sender<Vec<u8>>>,
+    /// Keys typed in every frontend, None once closed
+    keys: Option<mpsc::Sender<Vec<u8>>>,
+    received: Option<mpsc::Receiver<Vec<u8>>>,
+}
+
+/// Screen sent to the attached frontends. Clones share the same frontends.
+#[derive(Clone)]
+pub struct Relay {
+    frontends: Rc<RefCell<Frontends>>,
+}
+
+impl Relay {
+    pub fn new() -> Self {
+        let (keys, received) = mpsc::channel(KEYS_BACKLOG);
+        Self {
+            frontends: Rc::new(RefCell::new(Frontends {
+                buffer: Vec::new(),
+                frontends: Vec::new(),
+                keys: Some(keys),
+                received: Some(received),
+            })),
+        }
+    }
+
+    /// Commands and messages typed in the frontends, decoded as if typed in our own terminal
+    pub fn command_stream(&self, mut codec: KeyCodec) -> Box<dyn Stream<Item = CommandOrMessage, Error = CommandError>> {
+        let mut received = self.frontends.borrow_mut().received.take().unwrap();
+        let mut buf = BytesMut::new();
+        Box::new(stream::poll_fn(move || loop {
+            if let Some(item) = codec.decode(&mut buf)? {
+                return Ok(Async::Ready(Some(item)));
+            }
+            match received.poll() {
+                Ok(Async::Ready(Some(keys))) => buf.extend_from_slice(&keys),
+                Ok(Async::Ready(None)) | Err(()) => return Ok(Async::Ready(None)),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+            }
+        }))
+    }
+
+    /// Stop reading the frontends and drop them, once quitting
+    pub fn close(&self) {
+        let mut frontends = self.frontends.borrow_mut();
+        frontends.keys = None;
+        frontends.frontends.clear();
+    }
+
+    /// Type keys, resolving once they are queued, when the session is busy
+    fn type_keys(&self, keys: Vec<u8>) -> impl Future<Item = (), Error = io::Error> {
+        let sender = self.frontends.borrow().keys.clone();
+        match sender {
+            // Dropped when quitting
+            Some(sender) => future::Either::A(sender.send(keys).then(|_| Ok(()))),
+            None => future::Either::B(future::ok(())),
+        }
+    }
+}
+
+impl Terminal for Relay {
+    fn suspend_raw_mode(&self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "Detached"))
+    }
+
+    fn activate_raw_mode(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for Relay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.frontends.borrow_mut().buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut frontends = self.frontends.borrow_mut();
+        let output = std::mem::replace(&mut frontends.buffer, Vec::new());
+        if !output.is_empty() {
+            // Frontends gone or lagging are dropped on the way
+            frontends.frontends.retain_mut(|frontend| match frontend.try_send(output.clone()) {
+                Ok(()) => true,
+                Err(err) => {
+                    if err.is_full() {
+                        info!("Frontend dropped, lagging behind");
+                    }
+                    false
+                },
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Run in the background, away from the terminal it was started from, before any thread is
+/// spawned. Only the forked session returns, the process started exits at once.
+pub fn daemonize() -> Result<(), String> {
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")
+        .map_err(|err| format!("Cannot open /dev/null: {}", err))?;
+    match unsafe { libc::fork() } {
+        -1 => return Err(format!("Cannot detach: {}", io::Error::last_os_error())),
+        0 => {},
+        _ => {
+            println!("Session detached, attach to it with aparte --attach");
+            unsafe { libc::_exit(0) };
+        },
+    }
+    if unsafe { libc::setsid() } == -1 {
+        return Err(format!("Cannot detach: {}", io::Error::last_os_error()));
+    }
+    for fd in 0..3 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(format!("Cannot detach: {}", io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+/// Listen for frontends attaching
+pub fn serve(aparte: Rc<Aparte>, relay: Relay) -> impl Future<Item = (), Error = ()> {
+    future::lazy(move || {
+        let listener = match socket_path().and_then(|path| control::bind(&path)) {
+            Ok(listener) => listener,
+            Err(err) => {
+                warn!("{}", err);
+                return future::Either::A(future::ok(()));
+            },
+        };
+
+        future::Either::B(listener.incoming().map_err(|err| warn!("Relay socket error: {}", err)).for_each(move |stream| {
+            info!("Frontend attached");
+            let (reader, writer) = stream.split();
+            let (sender, output) = mpsc::channel(OUTPUT_BACKLOG);
+            relay.frontends.borrow_mut().frontends.push(sender);
+
+            let output = output.map_err(|()| io::Error::new(io::ErrorKind::Other, "Relay closed")).fold(writer, |writer, output| {
+                tokio::io::write_all(writer, output).map(|(writer, _)| writer)
+            });
+            tokio::runtime::current_thread::spawn(output.map(|_| ()).map_err(|err| info!("Frontend detached: {}", err)));
+
+            let aparte = Rc::clone(&aparte);
+            let relay = relay.clone();
+            let input = FramedRead::new(reader, FrameCodec).for_each(move |frame| {
+                match frame {
+                    Frame::Keys(keys) => future::Either::A(relay.type_keys(keys)),
+                    Frame::Size(width, height) => {
+                        // Also draws everything again for the frontend just attached
+                        aparte.get_plugin_mut::<UIPlugin>().unwrap().resize((width, height));
+                        future::Either::B(future::ok(()))
+                    },
+                }
+            });
+            tokio::runtime::current_thread::spawn(input.map(|_| info!("Frontend detached")).map_err(|err| info!("Frontend detached: {}", err)));
+            Ok(())
+        }))
+    })
+}
+
+fn send(stream: &Mutex<StdUnixStream>, frame: Frame) -> io::Result<()> {
+    stream.lock().unwrap().write_all(&frame.encode())
+}
+
+fn send_size(stream: &Mutex<StdUnixStream>) -> io::Result<()> {
+    let (width, height) = termion::terminal_size()?;
+    send(stream, Frame::Size(width, height))
+}
+
+/// Attach the terminal to a detached session, until detached with Ctrl-\ or the session quits
+pub fn attach() -> Result<(), String> {
+    let path = socket_path()?;
+    let mut session = StdUnixStream::connect(&path).map_err(|err| format!("Cannot attach to {}: {}", path.display(), err))?;
+    let sender = Arc::new(Mutex::new(session.try_clone().map_err(|err| format!("Cannot attach: {}", err))?));
+    send_size(&sender).map_err(|err| format!("Cannot attach: {}", err))?;
+
+    let stdout = io::stdout().into_raw_mode().map_err(|err| format!("Cannot set raw mode: {}", err))?;
+    let mut screen = AlternateScreen::from(stdout);
+    write!(screen, "{}", ENABLE_TERMINAL_MODES).unwrap();
+    screen.flush().unwrap();
+
+    let resized = Arc::clone(&sender);
+    let signals = signal_hook::iterator::Signals::new(&[signal_hook::SIGWINCH]).map_err(|err| format!("Cannot watch resizes: {}", err))?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            if send_size(&resized).is_err() {
+                break;
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        let mut stdin = io::stdin();
+        let mut buf = [0; 1024];
+        while let Ok(len) = stdin.read(&mut buf) {
+            let keys = &buf[..len];
+            let detach = keys.iter().position(|key| *key == DETACH);
+            let keys = &keys[..detach.unwrap_or(len)];
+            if len == 0 || (!keys.is_empty() && send(&sender, Frame::Keys(keys.to_vec())).is_err()) || detach.is_some() {
+                // Ends the copy of the interface below
+                let _ = sender.lock().unwrap().shutdown(std::net::Shutdown::Both);
+                break;
+            }
+        }
+    });
+
+    let copied = io::copy(&mut session, &mut screen);
+    write!(screen, "{}", DISABLE_TERMINAL_MODES).unwrap();
+    screen.flush().unwrap();
+    drop(screen);
+    match copied {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Detached: {}", err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        let mut bytes = Frame::Size(80, 24).encode();
+        bytes.extend(Frame::Keys(b"/quit\r".to_vec()).encode());
+        let mut buf = BytesMut::from(&bytes[..bytes.len() - 4]);
+
+        let mut codec = FrameCodec;
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Frame::Size(80, 24)));
+        // Waits for the rest of the keys
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&bytes[bytes.len() - 4..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Frame::Keys(b"/quit\r".to_vec())));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert!(codec.decode(&mut BytesMut::from(&b"x0000"[..])).is_err());
+    }
+
+    #[test]
+    fn test_relay() {
+        let mut relay = Relay::new();
+        let (sender, output) = mpsc::channel(OUTPUT_BACKLOG);
+        relay.frontends.borrow_mut().frontends.push(sender);
+        let (gone, _) = mpsc::channel(OUTPUT_BACKLOG);
+        relay.frontends.borrow_mut().frontends.push(gone);
+        let (lagging, _lagging_output) = mpsc::channel(0);
+        relay.frontends.borrow_mut().frontends.push(lagging);
+
+        write!(relay, "hello").unwrap();
+        relay.flush().unwrap();
+        relay.flush().unwrap();
+        write!(relay, "world").unwrap();
+        relay.flush().unwrap();
+        assert_eq!(relay.frontends.borrow().frontends.len(), 1);
+        relay.close();
+        assert_eq!(output.collect().wait().unwrap(), vec![b"hello".to_vec(), b"world".to_vec()]);
+    }
+}
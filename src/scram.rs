@@ -0,0 +1,248 @@
+//! SCRAM, RFC 5802 and RFC 7677, with the tls-server-end-point channel binding of RFC 5929 for
+//! the -PLUS variants. The sasl crate only binds to tls-unique, which native-tls doesn't give
+//! access to and which TLS 1.3 doesn't define.
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use sasl::client::Mechanism;
+use sasl::common::{ChannelBinding, Credentials, Identity, Password, Secret};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hash {
+    Sha1,
+    Sha256,
+}
+
+impl Hash {
+    fn digest(&self) -> MessageDigest {
+        match self {
+            Hash::Sha1 => MessageDigest::sha1(),
+            Hash::Sha256 => MessageDigest::sha256(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Hash::Sha1 => "SHA-1",
+            Hash::Sha256 => "SHA-256",
+        }
+    }
+}
+
+/// Channel binding, as told to the server in the GS2 header
+#[derive(Debug, Clone, PartialEq)]
+pub enum Binding {
+    /// Not bound to the connection, the server offering -PLUS without a binding we support
+    None,
+    /// Not bound, the server not offering -PLUS. It fails if it does and a -PLUS mechanism was
+    /// removed from what it offers, as by someone in the middle.
+    Unsupported,
+    /// Bound to the hash of the certificate of the server
+    EndPoint(Vec<u8>),
+}
+
+impl Binding {
+    fn header(&self) -> &'static str {
+        match self {
+            Binding::None => "n,,",
+            Binding::Unsupported => "y,,",
+            Binding::EndPoint(_) => "p=tls-server-end-point,,",
+        }
+    }
+}
+
+enum State {
+    Initial,
+    /// First message sent, without the GS2 header
+    FirstSent(String),
+    /// Proof sent, waiting for the signature of the server
+    FinalSent(Vec<u8>),
+    Verified,
+}
+
+pub struct Scram {
+    name: String,
+    hash: Hash,
+    binding: Binding,
+    username: String,
+    password: String,
+    nonce: String,
+    state: State,
+}
+
+fn hmac(hash: Hash, key: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    let key = PKey::hmac(key).map_err(|err| err.to_string())?;
+    let mut signer = Signer::new(hash.digest(), &key).map_err(|err| err.to_string())?;
+    signer.update(data).map_err(|err| err.to_string())?;
+    signer.sign_to_vec().map_err(|err| err.to_string())
+}
+
+/// Username escaped as a saslname
+fn escape(username: &str) -> String {
+    username.replace('=', "=3D").replace(',', "=2C")
+}
+
+/// Attributes of a message of the server, as `r=…,s=…`
+fn attribute<'a>(message: &'a str, name: char) -> Option<&'a str> {
+    message.split(',').find(|part| part.starts_with(name) && part[name.len_utf8()..].starts_with('=')).map(|part| &part[name.len_utf8() + 1..])
+}
+
+impl Scram {
+    pub fn new(hash: Hash, username: &str, password: &str, binding: Binding) -> Result<Self, String> {
+        let mut nonce = [0; 24];
+        openssl::rand::rand_bytes(&mut nonce).map_err(|err| err.to_string())?;
+        Ok(Self::with_nonce(hash, username, password, binding, base64::encode(&nonce)))
+    }
+
+    fn with_nonce(hash: Hash, username: &str, password: &str, binding: Binding, nonce: String) -> Self {
+        let plus = match binding {
+            Binding::EndPoint(_) => "-PLUS",
+            _ => "",
+        };
+        Self {
+            name: format!("SCRAM-{}{}", hash.name(), plus),
+            hash: hash,
+            binding: binding,
+            username: username.to_string(),
+            password: password.to_string(),
+            nonce: nonce,
+            state: State::Initial,
+        }
+    }
+
+    /// Check the signature of the server, telling it knows the password as well
+    fn verify(&mut self, data: &[u8]) -> Result<(), String> {
+        let message = String::from_utf8_lossy(data);
+        if let Some(error) = attribute(&message, 'e') {
+            return Err(format!("the server refused the proof: {}", error));
+        }
+        let signature = match (&self.state, attribute(&message, 'v')) {
+            (State::FinalSent(signature), Some(verifier)) if base64::decode(verifier).ok().as_ref() == Some(signature) => true,
+            _ => false,
+        };
+        match signature {
+            true => {
+                self.state = State::Verified;
+                Ok(())
+            },
+            false => Err(String::from("the server didn't prove it knows the password, someone may be in the middle")),
+        }
+    }
+}
+
+impl Mechanism for Scram {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn from_credentials(credentials: Credentials) -> Result<Self, String> {
+        let username = match credentials.identity {
+            Identity::Username(username) => username,
+            Identity::None => return Err(String::from("SCRAM requires a username")),
+        };
+        let password = match credentials.secret {
+            Secret::Password(Password::Plain(password)) => password,
+            _ => return Err(String::from("SCRAM requires a password")),
+        };
+        let binding = match credentials.channel_binding {
+            ChannelBinding::None => Binding::None,
+            ChannelBinding::Unsupported => Binding::Unsupported,
+            ChannelBinding::TlsUnique(_) => return Err(String::from("tls-unique isn't supported")),
+        };
+        Scram::new(Hash::Sha256, &username, &password, binding)
+    }
+
+    fn initial(&mut self) -> Result<Vec<u8>, String> {
+        let first = format!("n={},r={}", escape(&self.username), self.nonce);
+        let initial = format!("{}{}", self.binding.header(), first);
+        self.state = State::FirstSent(first);
+        Ok(initial.into_bytes())
+    }
+
+    fn response(&mut self, challenge: &[u8]) -> Result<Vec<u8>, String> {
+        let first = match &self.state {
+            State::FirstSent(first) => first.clone(),
+            // Some servers send their signature as a challenge rather than with the success
+            State::FinalSent(_) => return self.verify(challenge).map(|()| Vec::new()),
+            State::Initial | State::Verified => return Err(String::from("unexpected challenge")),
+        };
+
+        let server_first = String::from_utf8(challenge.to_vec()).map_err(|_| String::from("invalid challenge"))?;
+        let nonce = attribute(&server_first, 'r').ok_or("challenge without nonce")?;
+        let salt = attribute(&server_first, 's').and_then(|salt| base64::decode(salt).ok()).ok_or("challenge without salt")?;
+        let iterations: usize = attribute(&server_first, 'i').and_then(|iterations| iterations.parse().ok()).ok_or("challenge without iterations")?;
+        if !nonce.starts_with(&self.nonce) || nonce.len() == self.nonce.len() {
+            return Err(String::from("the server changed our nonce"));
+        }
+
+        let mut salted = vec![0; self.hash.digest().size()];
+        openssl::pkcs5::pbkdf2_hmac(self.password.as_bytes(), &salt, iterations, self.hash.digest(), &mut salted).map_err(|err| err.to_string())?;
+        let client_key = hmac(self.hash, &salted, b"Client Key")?;
+        let stored_key = hash(self.hash.digest(), &client_key).map_err(|err| err.to_string())?;
+        let server_key = hmac(self.hash, &salted, b"Server Key")?;
+
+        let mut binding = self.binding.header().as_bytes().to_vec();
+        if let Binding::EndPoint(data) = &self.binding {
+            binding.extend_from_slice(data);
+        }
+        let without_proof = format!("c={},r={}", base64::encode(&binding), nonce);
+        let auth_message = format!("{},{},{}", first, server_first, without_proof);
+        let client_signature = hmac(self.hash, &stored_key, auth_message.as_bytes())?;
+        let proof: Vec<u8> = client_key.iter().zip(client_signature.iter()).map(|(key, signature)| key ^ signature).collect();
+
+        self.state = State::FinalSent(hmac(self.hash, &server_key, auth_message.as_bytes())?);
+        Ok(format!("{},p={}", without_proof, base64::encode(&proof)).into_bytes())
+    }
+
+    fn success(&mut self, data: &[u8]) -> Result<(), String> {
+        match (&self.state, data.is_empty()) {
+            (State::Verified, true) => Ok(()),
+            _ => self.verify(data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha1() {
+        // Example of RFC 5802
+        let mut scram = Scram::with_nonce(Hash::Sha1, "user", "pencil", Binding::None, String::from("fyko+d2lbbFgONRv9qkxdawL"));
+        assert_eq!(scram.name(), "SCRAM-SHA-1");
+        assert_eq!(scram.initial().unwrap(), b"n,,n=user,r=fyko+d2lbbFgONRv9qkxdawL".to_vec());
+        let response = scram.response(b"r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096").unwrap();
+        assert_eq!(String::from_utf8(response).unwrap(), "c=biws,r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,p=v0X8v3Bz2T0CJGbJQyF0X+HI4Ts=");
+        assert!(scram.success(b"v=rmF9pqV8S7suAoZWja4dJRkFsKQ=").is_ok());
+    }
+
+    #[test]
+    fn test_sha256() {
+        // Example of RFC 7677
+        let mut scram = Scram::with_nonce(Hash::Sha256, "user", "pencil", Binding::None, String::from("rOprNGfwEbeRWgbNEkqO"));
+        scram.initial().unwrap();
+        let response = scram.response(b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096").unwrap();
+        assert_eq!(String::from_utf8(response).unwrap(), "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=");
+        // Signature sent as a challenge
+        assert_eq!(scram.response(b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=").unwrap(), Vec::<u8>::new());
+        assert!(scram.success(b"").is_ok());
+    }
+
+    #[test]
+    fn test_failures() {
+        let mut scram = Scram::with_nonce(Hash::Sha256, "us=er,", "pencil", Binding::EndPoint(vec![1, 2, 3]), String::from("nonce"));
+        assert_eq!(scram.name(), "SCRAM-SHA-256-PLUS");
+        assert_eq!(scram.initial().unwrap(), b"p=tls-server-end-point,,n=us=3Der=2C,r=nonce".to_vec());
+        assert!(scram.response(b"r=other,s=QSXCR+Q6sek8bf92,i=4096").is_err());
+        let response = String::from_utf8(scram.response(b"r=nonceserver,s=QSXCR+Q6sek8bf92,i=4096").unwrap()).unwrap();
+        assert!(response.starts_with(&format!("c={},", base64::encode(b"p=tls-server-end-point,,\x01\x02\x03"))));
+        assert!(scram.success(b"v=rmF9pqV8S7suAoZWja4dJRkFsKQ=").is_err());
+        assert!(scram.success(b"").is_err());
+
+        let mut scram = Scram::with_nonce(Hash::Sha1, "user", "pencil", Binding::Unsupported, String::from("nonce"));
+        assert!(scram.initial().unwrap().starts_with(b"y,,"));
+        scram.response(b"r=nonceserver,s=QSXCR+Q6sek8bf92,i=4096").unwrap();
+        assert_eq!(scram.success(b"e=invalid-proof"), Err(String::from("the server refused the proof: invalid-proof")));
+    }
+}
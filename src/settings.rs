@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use xmpp_parsers::BareJid;
+
+use crate::config::{Config, NotificationLevel};
+use crate::i18n;
+use crate::profile;
+use crate::theme::Color;
+
+/// Settings that can be changed for every conversation or for a single one
+pub const KEYS: &[&str] = &["color", "logging", "nick", "notifications", "spelling"];
+
+/// Value of a setting in its canonical form, an error if the key is unknown or the value invalid
+pub fn canonical(key: &str, value: &str) -> Result<String, String> {
+    let switch = |value: &str| match value {
+        "on" | "true" | "yes" => Ok(String::from("true")),
+        "off" | "false" | "no" => Ok(String::from("false")),
+        _ => Err(format!("Invalid value {} for {}, expected on or off", value, key)),
+    };
+    match key {
+        "color" => Color::from_str(value).map(|_| value.to_string()),
+        "logging" => switch(value),
+        "nick" if value.is_empty() => Err(String::from("Empty nick")),
+        "nick" => Ok(value.to_string()),
+        "notifications" => NotificationLevel::from_str(value).map(|level| level.to_string()),
+        "spelling" if value.is_empty() || !value.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-') => Err(format!("Invalid dictionary {}", value)),
+        "spelling" => Ok(value.to_string()),
+        _ => Err(i18n::trf("Unknown setting {}, expected one of {}", &[&key, &KEYS.join(", ")])),
+    }
+}
+
+/// State file of the settings, in the data directory
+pub fn default_path() -> PathBuf {
+    profile::data_dir().join("settings.toml")
+}
+
+/// Settings changed with /set, for every conversation or for some of them by JID, kept in a state
+/// file rather than in the config
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Settings {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    #[serde(default)]
+    global: BTreeMap<String, String>,
+    #[serde(default)]
+    local: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl Settings {
+    /// Settings stored at `path`, none if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut settings: Settings = match fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content).map_err(|err| format!("Invalid settings {}: {}", path.display(), err))?,
+            Err(_) => Settings::default(),
+        };
+        settings.path = Some(path.to_path_buf());
+        Ok(settings)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let content = toml::to_string(self).map_err(|err| err.to_string())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| format!("Cannot create {}: {}", dir.display(), err))?;
+        }
+        fs::write(path, content).map_err(|err| format!("Cannot save settings to {}: {}", path.display(), err))
+    }
+
+    /// Value of a setting for a conversation, the one set for every conversation otherwise
+    pub fn get<T: FromStr>(&self, jid: &BareJid, key: &str) -> Option<T> {
+        self.local.get(&jid.to_string()).and_then(|settings| settings.get(key))
+            .or_else(|| self.global.get(key))
+            .and_then(|value| T::from_str(value).ok())
+    }
+
+    /// Value of a setting as set for a conversation only, or for every conversation without `jid`
+    pub fn get_raw(&self, jid: Option<&BareJid>, key: &str) -> Option<&str> {
+        let settings = match jid {
+            Some(jid) => self.local.get(&jid.to_string())?,
+            None => &self.global,
+        };
+        settings.get(key).map(|value| value.as_str())
+    }
+
+    /// Change a setting for a conversation, or for every conversation without `jid`, unsetting it
+    /// without `value`
+    pub fn set(&mut self, jid: Option<&BareJid>, key: &str, value: Option<&str>) -> Result<(), String> {
+        let value = match value {
+            Some(value) => Some(canonical(key, value)?),
+            None if KEYS.contains(&key) => None,
+            None => return Err(i18n::trf("Unknown setting {}, expected one of {}", &[&key, &KEYS.join(", ")])),
+        };
+        let settings = match jid {
+            Some(jid) => self.local.entry(jid.to_string()).or_insert_with(BTreeMap::new),
+            None => &mut self.global,
+        };
+        match value {
+            Some(value) => settings.insert(key.to_string(), value),
+            None => settings.remove(key),
+        };
+        self.local.retain(|_, settings| !settings.is_empty());
+        self.save()
+    }
+
+    /// Nick to join a channel with: the one set for the channel, the one configured for it, the
+    /// one of its bookmark, the one set for every channel or the one configured for the account
+    pub fn channel_nick(&self, config: &Config, channel: &BareJid, bookmark: Option<String>, account: &BareJid) -> Option<String> {
+        let nicks = config.nicks.get(&channel.to_string()).cloned().or(bookmark);
+        self.get_raw(Some(channel), "nick").map(String::from)
+            .or(nicks)
+            .or_else(|| self.get_raw(None, "nick").map(String::from))
+            .or_else(|| config.channel_nick(channel, None, account))
+    }
+
+    /// Conversations with a setting of their own for `key`, and its value
+    pub fn conversations<'a>(&'a self, key: &'a str) -> impl Iterator<Item = (BareJid, &'a str)> + 'a {
+        self.local.iter().filter_map(move |(jid, settings)| {
+            match (BareJid::from_str(jid), settings.get(key)) {
+                (Ok(jid), Some(value)) => Some((jid, value.as_str())),
+                _ => None,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings() {
+        let path = std::env::temp_dir().join(format!("aparte-settings-{}.toml", uuid::Uuid::new_v4()));
+        let room = BareJid::from_str("room@conference.server.tld").unwrap();
+        let contact = BareJid::from_str("contact@server.tld").unwrap();
+
+        let mut settings = Settings::load(&path).unwrap();
+        settings.set(None, "logging", Some("off")).unwrap();
+        settings.set(Some(&room), "logging", Some("on")).unwrap();
+        settings.set(Some(&room), "notifications", Some("mentions")).unwrap();
+        settings.set(Some(&contact), "color", Some("#268bd2")).unwrap();
+        assert!(settings.set(Some(&room), "logging", Some("maybe")).is_err());
+        assert!(settings.set(Some(&room), "colour", Some("red")).is_err());
+        assert!(settings.set(Some(&room), "color", Some("color256")).is_err());
+        assert!(settings.set(Some(&room), "spelling", Some("../en_US")).is_err());
+
+        let settings = Settings::load(&path).unwrap();
+        assert_eq!(settings.get::<bool>(&room, "logging"), Some(true));
+        assert_eq!(settings.get::<bool>(&contact, "logging"), Some(false));
+        assert_eq!(settings.get::<NotificationLevel>(&room, "notifications"), Some(NotificationLevel::Mentions));
+        assert_eq!(settings.get::<NotificationLevel>(&contact, "notifications"), None);
+        assert_eq!(settings.get::<Color>(&contact, "color"), Some(Color::Rgb(0x26, 0x8b, 0xd2)));
+        assert_eq!(settings.get_raw(Some(&contact), "logging"), None);
+        assert_eq!(settings.conversations("color").collect::<Vec<_>>(), vec![(contact.clone(), "#268bd2")]);
+
+        let mut settings = settings;
+        settings.set(Some(&contact), "color", None).unwrap();
+        assert_eq!(Settings::load(&path).unwrap().conversations("color").count(), 0);
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,132 @@
+//! Synthetic traffic generator, used to profile rendering and the event loop without any network
+//! connection. Only built with the `simulate` feature and started with `--simulate`.
+use chrono::Utc;
+use futures::{Future, Stream};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::timer::Interval;
+use uuid::Uuid;
+use xmpp_parsers::{BareJid, FullJid, Jid};
+
+use crate::core::{Aparte, Event};
+use crate::conversation::{Affiliation, Occupant, Role};
+use crate::message::Message;
+
+const ACCOUNT: &str = "simulation@aparte.local/aparte";
+const NICK: &str = "simulation";
+const CHANNELS: usize = 3;
+const CONTACTS: usize = 5;
+const OCCUPANTS: usize = 50;
+const TICK: Duration = Duration::from_millis(20);
+/// Number of ticks between two reports of the simulation
+const REPORT: u64 = 500;
+
+const WORDS: &[&str] = &[
+    "hello", "xmpp", "server", "room", "message", "terminal", "rust", "tokio", "works", "for",
+    "me", "again", "please", "review", "the", "patch", "is", "broken", "fixed", "now",
+];
+
+struct Simulation {
+    account: FullJid,
+    channels: Vec<BareJid>,
+    contacts: Vec<BareJid>,
+    state: u64,
+    messages: u64,
+    elapsed: Duration,
+}
+
+impl Simulation {
+    fn new() -> Self {
+        Self {
+            account: FullJid::from_str(ACCOUNT).unwrap(),
+            channels: (0..CHANNELS).map(|i| BareJid::from_str(&format!("channel{}@conference.aparte.local", i)).unwrap()).collect(),
+            contacts: (0..CONTACTS).map(|i| BareJid::from_str(&format!("contact{}@aparte.local", i)).unwrap()).collect(),
+            state: 0x2545_f491_4f6c_dd1d,
+            messages: 0,
+            elapsed: Duration::from_secs(0),
+        }
+    }
+
+    /// xorshift, good enough to vary the generated traffic without pulling a dependency
+    fn random(&mut self, max: usize) -> usize {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state % max as u64) as usize
+    }
+
+    fn body(&mut self) -> String {
+        let count = 1 + self.random(12);
+        let mut words = Vec::with_capacity(count);
+        for _ in 0..count {
+            let word = WORDS[self.random(WORDS.len())];
+            words.push(word);
+        }
+
+        // Regularly highlight us
+        if self.random(10) == 0 {
+            words.insert(0, NICK);
+        }
+
+        words.join(" ")
+    }
+
+    fn setup(&mut self, aparte: Rc<Aparte>) {
+        for channel in self.channels.clone() {
+            Rc::clone(&aparte).event(Event::Join(self.account.clone(), channel.clone().with_resource(NICK)));
+            for i in 0..OCCUPANTS {
+                Rc::clone(&aparte).event(Event::Occupant(Occupant {
+                    nick: format!("user{}", i),
+                    jid: None,
+                    affiliation: Affiliation::None,
+                    role: match i % 10 {
+                        0 => Role::Moderator,
+                        _ => Role::Participant,
+                    },
+                }));
+            }
+        }
+
+        Rc::clone(&aparte).event(Event::Win("console".to_string()));
+    }
+
+    fn tick(&mut self, aparte: Rc<Aparte>) {
+        let to = Jid::Full(self.account.clone());
+        let body = self.body();
+        let message = match self.random(5) {
+            0 => {
+                let index = self.random(CONTACTS);
+                let contact = self.contacts[index].clone();
+                Message::incoming_chat(Uuid::new_v4().to_string(), Utc::now(), &Jid::Bare(contact), &to, &body)
+            },
+            _ => {
+                let index = self.random(CHANNELS);
+                let channel = self.channels[index].clone();
+                let from = channel.with_resource(format!("user{}", self.random(OCCUPANTS)));
+                Message::incoming_groupchat(Uuid::new_v4().to_string(), Utc::now(), &Jid::Full(from), &to, &body)
+            },
+        };
+
+        let start = Instant::now();
+        Rc::clone(&aparte).event(Event::Message(message));
+        self.elapsed += start.elapsed();
+        self.messages += 1;
+
+        if self.messages % REPORT == 0 {
+            let average = self.elapsed / self.messages as u32;
+            aparte.log(format!("Simulated {} messages, {}µs per message on average", self.messages, average.as_micros()));
+        }
+    }
+}
+
+pub fn start(aparte: Rc<Aparte>) -> impl Future<Item = (), Error = ()> {
+    let mut simulation = Simulation::new();
+    Rc::clone(&aparte).log(format!("Simulating {} channels and {} contacts", CHANNELS, CONTACTS));
+    simulation.setup(Rc::clone(&aparte));
+
+    Interval::new(Instant::now(), TICK).for_each(move |_| {
+        simulation.tick(Rc::clone(&aparte));
+        Ok(())
+    }).map_err(|err| warn!("Simulation timer error: {}", err))
+}
@@ -0,0 +1,73 @@
+//! SOCKS5 client, RFC 1928, as used by SOCKS5 bytestreams (XEP-0065): the address connected to
+//! is the hash identifying the stream rather than a host, and no authentication is done
+use futures::Future;
+use std::io;
+use tokio::io::{read_exact, write_all};
+use tokio::net::TcpStream;
+
+/// Connect request to a domain name, the port being unused by bytestreams
+fn request(address: &str) -> Result<Vec<u8>, io::Error> {
+    if address.len() > 255 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "SOCKS5 address too long"));
+    }
+    let mut request = vec![5, 1, 0, 3, address.len() as u8];
+    request.extend_from_slice(address.as_bytes());
+    request.extend_from_slice(&[0, 0]);
+    Ok(request)
+}
+
+/// Length of the rest of a reply, after its first five bytes
+fn remaining(reply: &[u8; 5]) -> Result<usize, io::Error> {
+    if reply[0] != 5 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a SOCKS5 reply"));
+    }
+    if reply[1] != 0 {
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("SOCKS5 connection refused with error {}", reply[1])));
+    }
+    // The port follows the bound address
+    match reply[3] {
+        1 => Ok(4 - 1 + 2),
+        3 => Ok(reply[4] as usize + 2),
+        4 => Ok(16 - 1 + 2),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid SOCKS5 address type")),
+    }
+}
+
+/// Ask the SOCKS5 server at the other end of `stream` to connect it to `address`
+pub fn connect(stream: TcpStream, address: &str) -> Box<dyn Future<Item = TcpStream, Error = io::Error>> {
+    let request = match request(address) {
+        Ok(request) => request,
+        Err(err) => return Box::new(futures::future::err(err)),
+    };
+    Box::new(write_all(stream, [5, 1, 0])
+        .and_then(|(stream, _)| read_exact(stream, [0; 2]))
+        .and_then(|(stream, method)| match method {
+            [5, 0] => Ok(stream),
+            _ => Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5 server requires authentication")),
+        })
+        .and_then(move |stream| write_all(stream, request))
+        .and_then(|(stream, _)| read_exact(stream, [0; 5]))
+        .and_then(|(stream, reply)| {
+            let remaining = remaining(&reply)?;
+            Ok(read_exact(stream, vec![0; remaining]))
+        })
+        .flatten()
+        .map(|(stream, _)| stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        assert_eq!(request("hash").unwrap(), vec![5, 1, 0, 3, 4, b'h', b'a', b's', b'h', 0, 0]);
+        assert!(request(&"a".repeat(256)).is_err());
+
+        assert_eq!(remaining(&[5, 0, 0, 3, 40]).unwrap(), 42);
+        assert_eq!(remaining(&[5, 0, 0, 1, 127]).unwrap(), 5);
+        assert_eq!(remaining(&[5, 0, 0, 4, 0]).unwrap(), 17);
+        assert_eq!(remaining(&[5, 5, 0, 3, 40]).unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+        assert!(remaining(&[4, 0, 0, 3, 40]).is_err());
+    }
+}
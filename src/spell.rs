@@ -0,0 +1,169 @@
+use futures::sync::mpsc::UnboundedSender;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+/// Words remembered by a speller, every one being forgotten past it rather than keeping all of
+/// those ever typed
+const MAX_CHECKED: usize = 10000;
+
+/// Word checked with a dictionary, with its suggestions if misspelled
+pub type Answer = (String, String, Option<Vec<String>>);
+
+/// Suggestions of a line answered by `hunspell -a` for a word, none if it is spelled right
+fn parse(line: &str) -> Option<Vec<String>> {
+    match line.chars().next() {
+        // & word count offset: suggestions
+        Some('&') | Some('?') => Some(line.splitn(2, ": ").nth(1)
+            .map(|suggestions| suggestions.split(", ").map(String::from).collect())
+            .unwrap_or_default()),
+        // # word offset, without any suggestion
+        Some('#') => Some(Vec::new()),
+        _ => None,
+    }
+}
+
+/// Ask hunspell about a word, none if it is spelled right
+fn ask(stdin: &mut ChildStdin, stdout: &mut BufReader<ChildStdout>, word: &str) -> io::Result<Option<Vec<String>>> {
+    // Lines starting with ^ are checked even when looking like commands
+    writeln!(stdin, "^{}", word)?;
+    stdin.flush()?;
+
+    let mut suggestions = None;
+    loop {
+        let mut line = String::new();
+        if stdout.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "hunspell exited"));
+        }
+        match line.trim_end() {
+            "" => return Ok(suggestions),
+            line => suggestions = suggestions.or_else(|| parse(line)),
+        }
+    }
+}
+
+/// Spell checker of a dictionary, asking a hunspell process running in pipe mode (the ispell
+/// protocol) about each word once, from a thread so the interface never waits for it
+#[derive(Default)]
+pub struct Speller {
+    /// Words to ask hunspell about, none once it exited
+    words: Option<Sender<String>>,
+    /// Suggestions for each word checked, none if spelled right
+    checked: HashMap<String, Option<Vec<String>>>,
+    /// Words hunspell didn't answer about yet
+    pending: HashSet<String>,
+}
+
+impl Speller {
+    /// Start hunspell with a dictionary, as en_US, its answers being sent to `answers`
+    pub fn new(dictionary: &str, answers: UnboundedSender<Answer>) -> Result<Speller, String> {
+        let mut child = Command::new("hunspell")
+            .args(&["-a", "-i", "utf-8", "-d", dictionary])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|err| format!("Cannot run hunspell: {}", err))?;
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+        // Hunspell exits without its banner when the dictionary cannot be found
+        let mut banner = String::new();
+        match stdout.read_line(&mut banner) {
+            Ok(len) if len > 0 => {},
+            _ => {
+                let _ = child.wait();
+                return Err(format!("No {} dictionary for hunspell", dictionary));
+            },
+        }
+
+        let (words, asked) = mpsc::channel::<String>();
+        let dictionary = dictionary.to_string();
+        // Until the speller is dropped or hunspell fails, leaving every word unchecked
+        thread::spawn(move || {
+            for word in asked {
+                let suggestions = match ask(&mut stdin, &mut stdout, &word) {
+                    Ok(suggestions) => suggestions,
+                    Err(err) => {
+                        warn!("Cannot check spelling: {}", err);
+                        break;
+                    },
+                };
+                if answers.unbounded_send((dictionary.clone(), word, suggestions)).is_err() {
+                    break;
+                }
+            }
+            let _ = child.kill();
+            child.wait()
+        });
+
+        Ok(Speller {
+            words: Some(words),
+            checked: HashMap::new(),
+            pending: HashSet::new(),
+        })
+    }
+
+    /// Speller knowing some words only, with their suggestions when misspelled
+    #[cfg(test)]
+    pub fn known(words: &[(&str, Option<&[&str]>)]) -> Speller {
+        Speller {
+            words: None,
+            checked: words.iter().map(|(word, suggestions)| {
+                (word.to_string(), suggestions.map(|suggestions| suggestions.iter().map(|suggestion| suggestion.to_string()).collect()))
+            }).collect(),
+            pending: HashSet::new(),
+        }
+    }
+
+    /// Suggestions for a misspelled word, none if it is spelled right or not checked yet, hunspell
+    /// being asked about it then
+    pub fn check(&mut self, word: &str) -> Option<Vec<String>> {
+        if let Some(checked) = self.checked.get(word) {
+            return checked.clone();
+        }
+        let words = self.words.as_ref()?;
+        if !self.pending.contains(word) {
+            match words.send(word.to_string()) {
+                Ok(()) => { self.pending.insert(word.to_string()); },
+                Err(_) => {
+                    self.words = None;
+                    self.pending.clear();
+                },
+            }
+        }
+        None
+    }
+
+    /// Remember what hunspell answered about a word
+    pub fn answered(&mut self, word: String, suggestions: Option<Vec<String>>) {
+        self.pending.remove(&word);
+        if self.checked.len() >= MAX_CHECKED {
+            self.checked.clear();
+        }
+        self.checked.insert(word, suggestions);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("*"), None);
+        assert_eq!(parse("+ walk"), None);
+        assert_eq!(parse("& helo 3 0: hello, help, hell"), Some(vec![String::from("hello"), String::from("help"), String::from("hell")]));
+        assert_eq!(parse("# aparté 0"), Some(Vec::new()));
+
+        let mut speller = Speller::known(&[("helo", Some(&["hello"]))]);
+        assert_eq!(speller.check("helo"), Some(vec![String::from("hello")]));
+        assert_eq!(speller.check("hello"), None);
+        speller.answered(String::from("hello"), None);
+        assert_eq!(speller.check("hello"), None);
+        speller.answered(String::from("wrld"), Some(Vec::new()));
+        assert_eq!(speller.check("wrld"), Some(Vec::new()));
+    }
+}
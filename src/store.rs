@@ -0,0 +1,511 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use xmpp_parsers::{BareJid, Jid};
+
+use crate::message::{Message, XmppMessage};
+use crate::profile;
+
+/// Subject of a channel, as set by an occupant or by the channel itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subject {
+    pub nick: Option<String>,
+    pub subject: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Storage of the messages history. Messages are grouped by conversation, identified by the bare
+/// JID of the contact or channel.
+pub trait MessageStore {
+    /// Store a message, storing a message already stored does nothing
+    fn insert(&mut self, message: &Message) -> Result<(), String>;
+    /// Last `limit` messages of a conversation, or all of them without limit, oldest first
+    fn history(&self, conversation: &BareJid, limit: Option<usize>) -> Result<Vec<Message>, String>;
+    /// Store a message mentioning us, the message being stored too
+    fn insert_mention(&mut self, message: &Message) -> Result<(), String>;
+    /// Last `limit` mentions across conversations, oldest first
+    fn mentions(&self, limit: usize) -> Result<Vec<Message>, String>;
+    /// Up to `limit` messages of a conversation before and after a given time, oldest first
+    fn around(&self, conversation: &BareJid, timestamp: &DateTime<Utc>, limit: usize) -> Result<Vec<Message>, String>;
+    /// Remove the messages of a conversation older than a given time, with their mentions,
+    /// returns how many were removed
+    fn expire(&mut self, conversation: &BareJid, before: &DateTime<Utc>) -> Result<usize, String>;
+    /// Store a subject of a channel, unless it is the last one stored, as channels send their
+    /// subject on each join
+    fn insert_subject(&mut self, channel: &BareJid, subject: &Subject) -> Result<(), String>;
+    /// Last `limit` subjects of a channel, newest first
+    fn subjects(&self, channel: &BareJid, limit: usize) -> Result<Vec<Subject>, String>;
+    /// Every conversation with stored messages
+    fn conversations(&self) -> Result<Vec<BareJid>, String>;
+    /// Store many messages at once, as imported logs
+    fn insert_all(&mut self, messages: &[Message]) -> Result<(), String> {
+        for message in messages {
+            self.insert(message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Conversation a message belongs to, log messages aren't part of any
+pub fn conversation(message: &Message) -> Option<BareJid> {
+    match message {
+        Message::Incoming(XmppMessage::Chat(message)) => Some((*message.from).clone()),
+        Message::Incoming(XmppMessage::Groupchat(message)) => Some((*message.from).clone()),
+        Message::Outgoing(XmppMessage::Chat(message)) => Some((*message.to).clone()),
+        Message::Outgoing(XmppMessage::Groupchat(message)) => Some((*message.to).clone()),
+        Message::Log(_) => None,
+    }
+}
+
+/// Database storing the history, in the data directory
+pub fn default_path() -> PathBuf {
+    profile::data_dir().join("history.sqlite")
+}
+
+fn message_id(message: &Message) -> &str {
+    match message {
+        Message::Incoming(XmppMessage::Chat(message)) | Message::Outgoing(XmppMessage::Chat(message)) => &message.id,
+        Message::Incoming(XmppMessage::Groupchat(message)) | Message::Outgoing(XmppMessage::Groupchat(message)) => &message.id,
+        Message::Log(message) => &message.id,
+    }
+}
+
+/// Messages kept in memory only, for tests and headless use
+pub struct MemoryStore {
+    messages: HashMap<String, Vec<Message>>,
+    mentions: Vec<Message>,
+    subjects: HashMap<String, Vec<Subject>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self {
+            messages: HashMap::new(),
+            mentions: Vec::new(),
+            subjects: HashMap::new(),
+        }
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn insert(&mut self, message: &Message) -> Result<(), String> {
+        let conversation = match conversation(message) {
+            Some(conversation) => conversation,
+            None => return Ok(()),
+        };
+
+        let messages = self.messages.entry(conversation.to_string()).or_insert_with(Vec::new);
+        let id = message_id(message);
+        if !messages.iter().any(|stored| message_id(stored) == id) {
+            messages.push(message.clone());
+        }
+        Ok(())
+    }
+
+    fn history(&self, conversation: &BareJid, limit: Option<usize>) -> Result<Vec<Message>, String> {
+        Ok(match self.messages.get(&conversation.to_string()) {
+            Some(messages) => {
+                let limit = limit.map_or(messages.len(), |limit| std::cmp::min(limit, messages.len()));
+                messages[messages.len() - limit..].to_vec()
+            },
+            None => Vec::new(),
+        })
+    }
+
+    fn insert_mention(&mut self, message: &Message) -> Result<(), String> {
+        self.insert(message)?;
+        let id = message_id(message);
+        if !self.mentions.iter().any(|mention| message_id(mention) == id && conversation(mention) == conversation(message)) {
+            self.mentions.push(message.clone());
+        }
+        Ok(())
+    }
+
+    fn mentions(&self, limit: usize) -> Result<Vec<Message>, String> {
+        let mut mentions = self.mentions.clone();
+        mentions.sort_by_key(|message| *message.timestamp());
+        Ok(mentions[mentions.len() - std::cmp::min(limit, mentions.len())..].to_vec())
+    }
+
+    fn around(&self, conversation: &BareJid, timestamp: &DateTime<Utc>, limit: usize) -> Result<Vec<Message>, String> {
+        let messages = match self.messages.get(&conversation.to_string()) {
+            Some(messages) => messages,
+            None => return Ok(Vec::new()),
+        };
+        let mut messages = messages.clone();
+        messages.sort_by_key(|message| *message.timestamp());
+        let index = messages.iter().position(|message| message.timestamp() > timestamp).unwrap_or(messages.len());
+        let start = index.saturating_sub(limit + 1);
+        let end = std::cmp::min(index + limit, messages.len());
+        Ok(messages[start..end].to_vec())
+    }
+
+    fn expire(&mut self, jid: &BareJid, before: &DateTime<Utc>) -> Result<usize, String> {
+        self.mentions.retain(|mention| conversation(mention).as_ref() != Some(jid) || mention.timestamp() >= before);
+        Ok(match self.messages.get_mut(&jid.to_string()) {
+            Some(messages) => {
+                let count = messages.len();
+                messages.retain(|message| message.timestamp() >= before);
+                count - messages.len()
+            },
+            None => 0,
+        })
+    }
+
+    fn insert_subject(&mut self, channel: &BareJid, subject: &Subject) -> Result<(), String> {
+        let subjects = self.subjects.entry(channel.to_string()).or_insert_with(Vec::new);
+        if subjects.last().map(|last| (&last.nick, &last.subject)) != Some((&subject.nick, &subject.subject)) {
+            subjects.push(subject.clone());
+        }
+        Ok(())
+    }
+
+    fn subjects(&self, channel: &BareJid, limit: usize) -> Result<Vec<Subject>, String> {
+        Ok(match self.subjects.get(&channel.to_string()) {
+            Some(subjects) => subjects.iter().rev().take(limit).cloned().collect(),
+            None => Vec::new(),
+        })
+    }
+
+    fn conversations(&self) -> Result<Vec<BareJid>, String> {
+        let mut conversations = self.messages.keys().filter_map(|conversation| BareJid::from_str(conversation).ok()).collect::<Vec<_>>();
+        conversations.sort_by_key(|conversation| conversation.to_string());
+        Ok(conversations)
+    }
+}
+
+/// Messages stored in a SQLite database, the default store
+pub struct SqliteStore {
+    connection: Connection,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let connection = Connection::open(path).map_err(|err| format!("Cannot open {}: {}", path.display(), err))?;
+        SqliteStore::with_connection(connection)
+    }
+
+    #[cfg(test)]
+    fn open_in_memory() -> Result<Self, String> {
+        let connection = Connection::open_in_memory().map_err(|err| format!("Cannot open database: {}", err))?;
+        SqliteStore::with_connection(connection)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, String> {
+        connection.execute_batch("
+            CREATE TABLE IF NOT EXISTS messages (
+                id TEXT NOT NULL,
+                conversation TEXT NOT NULL,
+                direction TEXT NOT NULL,
+                type TEXT NOT NULL,
+                timestamp TEXT NOT NULL,
+                from_full TEXT NOT NULL,
+                to_full TEXT NOT NULL,
+                body TEXT NOT NULL,
+                PRIMARY KEY (conversation, id)
+            );
+            CREATE INDEX IF NOT EXISTS messages_timestamp ON messages (conversation, timestamp);
+            CREATE TABLE IF NOT EXISTS mentions (
+                id TEXT NOT NULL,
+                conversation TEXT NOT NULL,
+                PRIMARY KEY (conversation, id)
+            );
+            CREATE TABLE IF NOT EXISTS subjects (
+                channel TEXT NOT NULL,
+                nick TEXT,
+                subject TEXT NOT NULL,
+                timestamp TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS subjects_timestamp ON subjects (channel, timestamp);
+        ").map_err(|err| format!("Cannot create history tables: {}", err))?;
+
+        Ok(Self {
+            connection: connection,
+        })
+    }
+}
+
+impl MessageStore for SqliteStore {
+    fn insert(&mut self, message: &Message) -> Result<(), String> {
+        let conversation = match conversation(message) {
+            Some(conversation) => conversation,
+            None => return Ok(()),
+        };
+
+        let (direction, type_, timestamp, from_full, to_full, body) = match message {
+            Message::Incoming(XmppMessage::Chat(message)) => ("incoming", "chat", &message.timestamp, &message.from_full, &message.to_full, &*message.body),
+            Message::Outgoing(XmppMessage::Chat(message)) => ("outgoing", "chat", &message.timestamp, &message.from_full, &message.to_full, &*message.body),
+            Message::Incoming(XmppMessage::Groupchat(message)) => ("incoming", "groupchat", &message.timestamp, &message.from_full, &message.to_full, &*message.body),
+            Message::Outgoing(XmppMessage::Groupchat(message)) => ("outgoing", "groupchat", &message.timestamp, &message.from_full, &message.to_full, &*message.body),
+            Message::Log(_) => unreachable!(),
+        };
+
+        self.connection.execute(
+            "INSERT OR IGNORE INTO messages (id, conversation, direction, type, timestamp, from_full, to_full, body) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![message_id(message), conversation.to_string(), direction, type_, timestamp, from_full.to_string(), to_full.to_string(), body],
+        ).map_err(|err| format!("Cannot store message: {}", err))?;
+
+        Ok(())
+    }
+
+    fn history(&self, conversation: &BareJid, limit: Option<usize>) -> Result<Vec<Message>, String> {
+        // A negative limit means no limit to SQLite
+        let limit = limit.map_or(-1, |limit| limit as i64);
+        let mut messages = self.query(
+            "SELECT id, direction, type, timestamp, from_full, to_full, body FROM messages WHERE conversation = ?1 ORDER BY timestamp DESC LIMIT ?2",
+            params![conversation.to_string(), limit],
+        )?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    fn insert_mention(&mut self, message: &Message) -> Result<(), String> {
+        let conversation = match conversation(message) {
+            Some(conversation) => conversation,
+            None => return Ok(()),
+        };
+
+        self.insert(message)?;
+        self.connection.execute(
+            "INSERT OR IGNORE INTO mentions (id, conversation) VALUES (?1, ?2)",
+            params![message_id(message), conversation.to_string()],
+        ).map_err(|err| format!("Cannot store mention: {}", err))?;
+
+        Ok(())
+    }
+
+    fn mentions(&self, limit: usize) -> Result<Vec<Message>, String> {
+        let mut messages = self.query(
+            "SELECT messages.id, direction, type, timestamp, from_full, to_full, body FROM mentions JOIN messages USING (conversation, id) ORDER BY timestamp DESC LIMIT ?1",
+            params![limit as i64],
+        )?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    fn around(&self, conversation: &BareJid, timestamp: &DateTime<Utc>, limit: usize) -> Result<Vec<Message>, String> {
+        let mut messages = self.query(
+            "SELECT id, direction, type, timestamp, from_full, to_full, body FROM messages WHERE conversation = ?1 AND timestamp <= ?2 ORDER BY timestamp DESC LIMIT ?3",
+            params![conversation.to_string(), timestamp, (limit + 1) as i64],
+        )?;
+        messages.reverse();
+        messages.extend(self.query(
+            "SELECT id, direction, type, timestamp, from_full, to_full, body FROM messages WHERE conversation = ?1 AND timestamp > ?2 ORDER BY timestamp ASC LIMIT ?3",
+            params![conversation.to_string(), timestamp, limit as i64],
+        )?);
+        Ok(messages)
+    }
+
+    fn expire(&mut self, conversation: &BareJid, before: &DateTime<Utc>) -> Result<usize, String> {
+        self.connection.execute(
+            "DELETE FROM mentions WHERE conversation = ?1 AND id IN (SELECT id FROM messages WHERE conversation = ?1 AND timestamp < ?2)",
+            params![conversation.to_string(), before],
+        ).map_err(|err| format!("Cannot expire mentions: {}", err))?;
+        self.connection.execute(
+            "DELETE FROM messages WHERE conversation = ?1 AND timestamp < ?2",
+            params![conversation.to_string(), before],
+        ).map_err(|err| format!("Cannot expire history: {}", err))
+    }
+
+    fn insert_subject(&mut self, channel: &BareJid, subject: &Subject) -> Result<(), String> {
+        if self.subjects(channel, 1)?.first().map(|last| (&last.nick, &last.subject)) == Some((&subject.nick, &subject.subject)) {
+            return Ok(());
+        }
+
+        self.connection.execute(
+            "INSERT INTO subjects (channel, nick, subject, timestamp) VALUES (?1, ?2, ?3, ?4)",
+            params![channel.to_string(), subject.nick, subject.subject, subject.timestamp],
+        ).map_err(|err| format!("Cannot store subject: {}", err))?;
+
+        Ok(())
+    }
+
+    fn subjects(&self, channel: &BareJid, limit: usize) -> Result<Vec<Subject>, String> {
+        let mut statement = self.connection.prepare(
+            "SELECT nick, subject, timestamp FROM subjects WHERE channel = ?1 ORDER BY timestamp DESC, rowid DESC LIMIT ?2",
+        ).map_err(|err| format!("Cannot read subjects: {}", err))?;
+
+        let rows = statement.query_map(params![channel.to_string(), limit as i64], |row| {
+            Ok(Subject {
+                nick: row.get(0)?,
+                subject: row.get(1)?,
+                timestamp: row.get(2)?,
+            })
+        }).map_err(|err| format!("Cannot read subjects: {}", err))?;
+
+        rows.collect::<Result<Vec<_>, _>>().map_err(|err| format!("Cannot read subjects: {}", err))
+    }
+
+    fn conversations(&self) -> Result<Vec<BareJid>, String> {
+        let mut statement = self.connection.prepare(
+            "SELECT DISTINCT conversation FROM messages ORDER BY conversation",
+        ).map_err(|err| format!("Cannot read history: {}", err))?;
+
+        let rows = statement.query_map(params![], |row| row.get::<_, String>(0))
+            .map_err(|err| format!("Cannot read history: {}", err))?;
+
+        let mut conversations = Vec::new();
+        for row in rows {
+            let conversation = row.map_err(|err| format!("Cannot read history: {}", err))?;
+            conversations.push(BareJid::from_str(&conversation).map_err(|err| format!("Invalid JID {} in history: {}", conversation, err))?);
+        }
+        Ok(conversations)
+    }
+
+    fn insert_all(&mut self, messages: &[Message]) -> Result<(), String> {
+        // A single transaction, as committing each message of years of logs takes ages
+        self.connection.execute_batch("BEGIN").map_err(|err| format!("Cannot store messages: {}", err))?;
+        for message in messages {
+            if let Err(err) = self.insert(message) {
+                let _ = self.connection.execute_batch("ROLLBACK");
+                return Err(err);
+            }
+        }
+        self.connection.execute_batch("COMMIT").map_err(|err| format!("Cannot store messages: {}", err))
+    }
+}
+
+impl SqliteStore {
+    /// Messages of the rows returned by a query selecting the columns of the messages table
+    fn query(&self, sql: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<Message>, String> {
+        let mut statement = self.connection.prepare(sql).map_err(|err| format!("Cannot read history: {}", err))?;
+
+        let rows = statement.query_map(params, |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, DateTime<Utc>>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, String>(6)?,
+            ))
+        }).map_err(|err| format!("Cannot read history: {}", err))?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            let (id, direction, type_, timestamp, from_full, to_full, body) = row.map_err(|err| format!("Cannot read history: {}", err))?;
+            let from_full = Jid::from_str(&from_full).map_err(|err| format!("Invalid JID {} in history: {}", from_full, err))?;
+            let to_full = Jid::from_str(&to_full).map_err(|err| format!("Invalid JID {} in history: {}", to_full, err))?;
+            let message = match (direction.as_str(), type_.as_str()) {
+                ("incoming", "chat") => Message::incoming_chat(id, timestamp, &from_full, &to_full, &body),
+                ("outgoing", "chat") => Message::outgoing_chat(id, timestamp, &from_full, &to_full, &body),
+                ("incoming", "groupchat") => Message::incoming_groupchat(id, timestamp, &from_full, &to_full, &body),
+                ("outgoing", "groupchat") => Message::outgoing_groupchat(id, timestamp, &from_full, &to_full, &body),
+                (direction, type_) => return Err(format!("Invalid {} {} message in history", direction, type_)),
+            };
+            messages.push(message);
+        }
+
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn messages() -> Vec<Message> {
+        let us = Jid::from_str("me@server.tld/aparte").unwrap();
+        let contact = Jid::from_str("contact@server.tld/phone").unwrap();
+        vec![
+            Message::incoming_chat("1", Utc.timestamp_opt(1000, 0).unwrap(), &contact, &us, "Hello"),
+            Message::outgoing_chat("2", Utc.timestamp_opt(1001, 0).unwrap(), &us, &contact, "Hi"),
+            Message::incoming_chat("3", Utc.timestamp_opt(1002, 0).unwrap(), &contact, &us, "How are you?"),
+        ]
+    }
+
+    fn check_store(store: &mut dyn MessageStore) {
+        for message in messages() {
+            store.insert(&message).unwrap();
+        }
+        // Storing twice the same message must not duplicate it
+        store.insert(&messages()[0]).unwrap();
+
+        let contact = BareJid::from_str("contact@server.tld").unwrap();
+        let history = store.history(&contact, Some(2)).unwrap();
+        let ids: Vec<&str> = history.iter().map(message_id).collect();
+        assert_eq!(ids, vec!["2", "3"]);
+        assert_eq!(history[1].body(), "How are you?");
+
+        assert_eq!(store.history(&contact, Some(10)).unwrap().len(), 3);
+        assert!(store.history(&BareJid::from_str("other@server.tld").unwrap(), Some(10)).unwrap().is_empty());
+        assert_eq!(store.conversations().unwrap(), vec![contact.clone()]);
+
+        let around = store.around(&contact, &Utc.timestamp_opt(1001, 0).unwrap(), 1).unwrap();
+        assert_eq!(around.iter().map(message_id).collect::<Vec<_>>(), vec!["1", "2", "3"]);
+        let around = store.around(&contact, &Utc.timestamp_opt(1000, 0).unwrap(), 1).unwrap();
+        assert_eq!(around.iter().map(message_id).collect::<Vec<_>>(), vec!["1", "2"]);
+    }
+
+    fn check_import(store: &mut dyn MessageStore) {
+        store.insert_all(&messages()).unwrap();
+        // Importing the same logs again must not duplicate them
+        store.insert_all(&messages()).unwrap();
+        let contact = BareJid::from_str("contact@server.tld").unwrap();
+        assert_eq!(store.history(&contact, None).unwrap().len(), 3);
+    }
+
+    fn check_mentions(store: &mut dyn MessageStore) {
+        let us = Jid::from_str("room@conference.server.tld/me").unwrap();
+        let alice = Jid::from_str("room@conference.server.tld/alice").unwrap();
+        let first = Message::incoming_groupchat("1", Utc.timestamp_opt(1000, 0).unwrap(), &alice, &us, "me: hello");
+        let second = Message::incoming_groupchat("2", Utc.timestamp_opt(1001, 0).unwrap(), &alice, &us, "me: still there?");
+        store.insert_mention(&second).unwrap();
+        store.insert_mention(&first).unwrap();
+        store.insert_mention(&first).unwrap();
+
+        let mentions = store.mentions(10).unwrap();
+        assert_eq!(mentions.iter().map(message_id).collect::<Vec<_>>(), vec!["1", "2"]);
+        // Mentioning messages are part of the history too
+        assert_eq!(store.history(&BareJid::from_str("room@conference.server.tld").unwrap(), Some(10)).unwrap().len(), 2);
+    }
+
+    fn check_expire(store: &mut dyn MessageStore) {
+        check_mentions(store);
+        let room = BareJid::from_str("room@conference.server.tld").unwrap();
+        assert_eq!(store.expire(&room, &Utc.timestamp_opt(1001, 0).unwrap()).unwrap(), 1);
+        assert_eq!(store.history(&room, Some(10)).unwrap().iter().map(message_id).collect::<Vec<_>>(), vec!["2"]);
+        assert_eq!(store.mentions(10).unwrap().iter().map(message_id).collect::<Vec<_>>(), vec!["2"]);
+        assert_eq!(store.expire(&BareJid::from_str("other@server.tld").unwrap(), &Utc.timestamp_opt(2000, 0).unwrap()).unwrap(), 0);
+    }
+
+    fn check_subjects(store: &mut dyn MessageStore) {
+        let room = BareJid::from_str("room@conference.server.tld").unwrap();
+        let subject = |nick: &str, subject: &str, timestamp| Subject {
+            nick: Some(nick.to_string()),
+            subject: subject.to_string(),
+            timestamp: Utc.timestamp_opt(timestamp, 0).unwrap(),
+        };
+        store.insert_subject(&room, &subject("alice", "Release on friday", 1000)).unwrap();
+        store.insert_subject(&room, &subject("bob", "lol", 1001)).unwrap();
+        // The subject sent again when joining
+        store.insert_subject(&room, &subject("bob", "lol", 1002)).unwrap();
+
+        let subjects = store.subjects(&room, 10).unwrap();
+        assert_eq!(subjects, vec![subject("bob", "lol", 1001), subject("alice", "Release on friday", 1000)]);
+        assert_eq!(store.subjects(&room, 1).unwrap().len(), 1);
+        assert!(store.subjects(&BareJid::from_str("other@conference.server.tld").unwrap(), 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_memory_store() {
+        check_store(&mut MemoryStore::new());
+        check_import(&mut MemoryStore::new());
+        check_mentions(&mut MemoryStore::new());
+        check_expire(&mut MemoryStore::new());
+        check_subjects(&mut MemoryStore::new());
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        check_store(&mut SqliteStore::open_in_memory().unwrap());
+        check_import(&mut SqliteStore::open_in_memory().unwrap());
+        check_mentions(&mut SqliteStore::open_in_memory().unwrap());
+        check_expire(&mut SqliteStore::open_in_memory().unwrap());
+        check_subjects(&mut SqliteStore::open_in_memory().unwrap());
+    }
+}
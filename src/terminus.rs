@@ -1,17 +1,39 @@
 use std::cell::RefCell;
 use std::cmp;
-use std::collections::{HashMap, hash_map::Entry, HashSet};
+use std::collections::{HashMap, VecDeque, hash_map::Entry};
 use std::fmt;
 use std::hash::Hash;
-use std::io::{Write, Stdout};
+use std::io::{self, Write, Stdout};
+use std::iter;
 use std::rc::Rc;
 use termion::raw::RawTerminal;
 use termion::screen::AlternateScreen;
+use unicode_width::UnicodeWidthChar;
 
-type Screen = AlternateScreen<RawTerminal<Stdout>>;
+use crate::emoji;
+use crate::i18n;
+use crate::spell::Speller;
 
-fn term_string_visible_len(string: &str) -> usize {
-    let mut len = 0;
+/// Output the views are drawn to
+pub trait Terminal: Write {
+    fn suspend_raw_mode(&self) -> io::Result<()>;
+    fn activate_raw_mode(&self) -> io::Result<()>;
+}
+
+impl Terminal for AlternateScreen<RawTerminal<Stdout>> {
+    fn suspend_raw_mode(&self) -> io::Result<()> {
+        RawTerminal::suspend_raw_mode(self)
+    }
+
+    fn activate_raw_mode(&self) -> io::Result<()> {
+        RawTerminal::activate_raw_mode(self)
+    }
+}
+
+pub type Screen = Box<dyn Terminal>;
+
+fn term_string_visible(string: &str) -> String {
+    let mut visible = String::with_capacity(string.len());
     let mut iter = string.chars();
 
     while let Some(c) = iter.next() {
@@ -30,11 +52,118 @@ fn term_string_visible_len(string: &str) -> usize {
                     }
                 }
             },
-            _ => { len += 1; },
+            c => visible.push(c),
+        }
+    }
+
+    visible
+}
+
+/// Columns taken by a character, skin tones being drawn over the emoji they follow
+fn char_width(c: char) -> usize {
+    match c {
+        '\u{1F3FB}'..='\u{1F3FF}' => 0,
+        c => c.width().unwrap_or(0),
+    }
+}
+
+/// Width of the characters of a string in turn, an emoji sequence joined by zero width joiners
+/// being drawn as a single emoji and a variation selector widening the text character before it
+#[derive(Default)]
+struct Columns {
+    last: usize,
+    joined: bool,
+}
+
+impl Columns {
+    fn width(&mut self, c: char) -> usize {
+        match c {
+            '\u{200D}' => {
+                self.joined = true;
+                0
+            },
+            '\u{FE0F}' if self.last == 1 => {
+                self.last = 2;
+                1
+            },
+            _ if self.joined => {
+                self.joined = false;
+                0
+            },
+            c => {
+                self.last = char_width(c);
+                self.last
+            },
+        }
+    }
+}
+
+/// Columns taken by a string without escape sequences
+pub fn string_width(string: &str) -> usize {
+    let mut columns = Columns::default();
+    string.chars().map(|c| columns.width(c)).sum()
+}
+
+pub fn term_string_visible_len(string: &str) -> usize {
+    string_width(&term_string_visible(string))
+}
+
+/// Split a string into rows of at most `width` columns, escape sequences being kept in the row
+/// they start
+fn term_string_wrap(string: &str, width: usize) -> Vec<String> {
+    let mut rows = vec![String::new()];
+    let mut len = 0;
+    let mut columns = Columns::default();
+    let mut iter = string.chars();
+
+    while let Some(c) = iter.next() {
+        let row = rows.last_mut().unwrap();
+        match c {
+            '\x1B' => {
+                row.push(c);
+                if let Some(c) = iter.next() {
+                    row.push(c);
+                    if c == '[' {
+                        while let Some(c) = iter.next() {
+                            row.push(c);
+                            if let '\x40'..='\x7E' = c {
+                                break;
+                            }
+                        }
+                    }
+                }
+            },
+            c => {
+                let c_width = columns.width(c);
+                if len + c_width > width && len > 0 && c_width > 0 && width > 0 {
+                    rows.push(c.to_string());
+                    len = c_width;
+                } else {
+                    row.push(c);
+                    len += c_width;
+                }
+            },
         }
     }
 
-    len
+    rows
+}
+
+/// Word of a string without escape sequences drawn at `column`, words being separated by spaces
+fn word_at(string: &str, column: usize) -> Option<&str> {
+    let mut columns = Columns::default();
+    let mut len = 0;
+    let (index, c) = string.char_indices().find(|(_, c)| {
+        len += columns.width(*c);
+        len > column
+    })?;
+    if c.is_whitespace() {
+        return None;
+    }
+
+    let start = string[..index].char_indices().rev().find(|(_, c)| c.is_whitespace()).map(|(space, c)| space + c.len_utf8()).unwrap_or(0);
+    let end = string[index..].find(char::is_whitespace).map(|space| index + space).unwrap_or(string.len());
+    Some(&string[start..end])
 }
 
 #[derive(Clone)]
@@ -177,15 +306,90 @@ default impl<'a, T, E> ViewTrait<E> for View<'a, T, E> {
     }
 }
 
+/// Tree of panes splitting a frame, each leaf displaying one child of the frame
+#[derive(Clone)]
+pub enum Pane<K> {
+    Leaf(K),
+    Split(Orientation, Box<Pane<K>>, Box<Pane<K>>),
+}
+
+impl<K: Clone + PartialEq> Pane<K> {
+    pub fn leaves(&self) -> Vec<K> {
+        match self {
+            Pane::Leaf(key) => vec![key.clone()],
+            Pane::Split(_, first, second) => {
+                let mut leaves = first.leaves();
+                leaves.extend(second.leaves());
+                leaves
+            },
+        }
+    }
+
+    /// Split the leaf at `index`, the new pane displaying `key` is put after it
+    fn split(&mut self, index: &mut usize, orientation: &Orientation, key: &K) -> bool {
+        match self {
+            Pane::Leaf(current) => {
+                if *index == 0 {
+                    let current = current.clone();
+                    *self = Pane::Split(orientation.clone(), Box::new(Pane::Leaf(current)), Box::new(Pane::Leaf(key.clone())));
+                    true
+                } else {
+                    *index -= 1;
+                    false
+                }
+            },
+            Pane::Split(_, first, second) => {
+                first.split(index, orientation, key) || second.split(index, orientation, key)
+            },
+        }
+    }
+
+    fn set(&mut self, index: &mut usize, key: &K) -> bool {
+        match self {
+            Pane::Leaf(current) => {
+                if *index == 0 {
+                    *current = key.clone();
+                    true
+                } else {
+                    *index -= 1;
+                    false
+                }
+            },
+            Pane::Split(_, first, second) => first.set(index, key) || second.set(index, key),
+        }
+    }
+
+    /// Compute position and size of each leaf, and of the separators drawn between them
+    fn geometry(&self, x: u16, y: u16, w: u16, h: u16, panes: &mut Vec<(K, u16, u16, u16, u16)>, separators: &mut Vec<(Orientation, u16, u16, u16)>) {
+        match self {
+            Pane::Leaf(key) => panes.push((key.clone(), x, y, w, h)),
+            Pane::Split(Orientation::Horizontal, first, second) => {
+                let first_w = w.saturating_sub(1) / 2;
+                first.geometry(x, y, first_w, h, panes, separators);
+                separators.push((Orientation::Vertical, x + first_w, y, h));
+                second.geometry(x + first_w + 1, y, w.saturating_sub(first_w + 1), h, panes, separators);
+            },
+            Pane::Split(Orientation::Vertical, first, second) => {
+                let first_h = h.saturating_sub(1) / 2;
+                first.geometry(x, y, w, first_h, panes, separators);
+                separators.push((Orientation::Horizontal, x, y + first_h, w));
+                second.geometry(x, y + first_h + 1, w, h.saturating_sub(first_h + 1), panes, separators);
+            },
+        }
+    }
+}
+
 pub struct FrameLayout<'a, K, E>
     where K: Hash + Eq
 {
     pub children: HashMap<K, Box<dyn ViewTrait<E> + 'a>>,
-    pub current: Option<K>,
+    pub panes: Option<Pane<K>>,
+    // Index of the focused pane
+    pub focus: usize,
 }
 
 impl<'a, K, E> View<'a, FrameLayout<'a, K, E>, E>
-    where K: Hash + Eq
+    where K: Hash + Eq + Clone
 {
     pub fn new(screen: Rc<RefCell<Screen>>) -> Self {
         Self {
@@ -203,7 +407,8 @@ impl<'a, K, E> View<'a, FrameLayout<'a, K, E>, E>
             cursor_y: None,
             content: FrameLayout {
                 children: HashMap::new(),
-                current: None,
+                panes: None,
+                focus: 0,
             },
             event_handler: None,
         }
@@ -216,28 +421,100 @@ impl<'a, K, E> View<'a, FrameLayout<'a, K, E>, E>
         self
     }
 
+    /// Display `key` in the focused pane, or focus the pane already displaying it
     pub fn current(&mut self, key: K) {
-        self.content.current = Some(key);
-        self.redraw();
+        let visible = self.visible();
+        match visible.iter().position(|visible| visible == &key) {
+            Some(index) => self.content.focus = index,
+            None => match &mut self.content.panes {
+                Some(panes) => {
+                    panes.set(&mut self.content.focus.clone(), &key);
+                },
+                None => self.content.panes = Some(Pane::Leaf(key)),
+            },
+        }
+        self.relayout();
+    }
+
+    pub fn current_key(&self) -> Option<K> {
+        self.visible().get(self.content.focus).cloned()
+    }
+
+    /// Keys of the children displayed, in pane order
+    pub fn visible(&self) -> Vec<K> {
+        match &self.content.panes {
+            Some(panes) => panes.leaves(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Key of the child displayed in the pane at a position of the screen
+    pub fn pane_at(&self, x: u16, y: u16) -> Option<K> {
+        let (panes, _) = self.geometry();
+        panes.into_iter()
+            .find(|(_, left, top, w, h)| x >= *left && x < left + w && y >= *top && y < top + h)
+            .map(|(key, _, _, _, _)| key)
+    }
+
+    /// Split the focused pane, displaying `key` in the new pane
+    pub fn split(&mut self, orientation: Orientation, key: K) {
+        if let Some(panes) = &mut self.content.panes {
+            panes.split(&mut self.content.focus.clone(), &orientation, &key);
+            self.relayout();
+        }
+    }
+
+    /// Close all panes but the focused one
+    pub fn only(&mut self) {
+        if let Some(key) = self.current_key() {
+            self.content.panes = Some(Pane::Leaf(key));
+            self.content.focus = 0;
+            self.relayout();
+        }
     }
 
     pub fn insert(&mut self, key: K, mut widget: Box<dyn ViewTrait<E> + 'a>)
     {
-        widget.measure(self.w, self.h);
+        widget.measure(self.w, Some(0));
         widget.layout(self.y, self.x);
         self.content.children.insert(key, widget);
     }
+
+    fn geometry(&self) -> (Vec<(K, u16, u16, u16, u16)>, Vec<(Orientation, u16, u16, u16)>) {
+        let mut panes = Vec::new();
+        let mut separators = Vec::new();
+        if let (Some(tree), Some(w), Some(h)) = (&self.content.panes, self.w, self.h) {
+            tree.geometry(self.x, self.y, w, h, &mut panes, &mut separators);
+        }
+        (panes, separators)
+    }
+
+    fn relayout(&mut self) {
+        if self.w.is_some() && self.h.is_some() {
+            self.measure(self.w, self.h);
+            self.layout(self.y, self.x);
+            self.redraw();
+        }
+    }
 }
 
-impl<K, E> ViewTrait<E> for View<'_, FrameLayout<'_, K, E>, E>
-    where K: Hash + Eq
+impl<'a, K, E> ViewTrait<E> for View<'a, FrameLayout<'a, K, E>, E>
+    where K: Hash + Eq + Clone
 {
     fn measure(&mut self, width_spec: Option<u16>, height_spec: Option<u16>) {
         self.w = width_spec;
         self.h = height_spec;
 
+        // Children not displayed in a pane are given no height so they don't draw anything
         for (_, child) in self.content.children.iter_mut() {
-            child.measure(self.w, self.h);
+            child.measure(self.w, Some(0));
+        }
+
+        let (panes, _) = self.geometry();
+        for (key, _, _, w, h) in panes {
+            if let Some(child) = self.content.children.get_mut(&key) {
+                child.measure(Some(w), Some(h));
+            }
         }
     }
 
@@ -249,17 +526,46 @@ impl<K, E> ViewTrait<E> for View<'_, FrameLayout<'_, K, E>, E>
         for (_, child) in self.content.children.iter_mut() {
             child.layout(top, left);
         }
+
+        let (panes, _) = self.geometry();
+        for (key, x, y, _, _) in panes {
+            if let Some(child) = self.content.children.get_mut(&key) {
+                child.layout(y, x);
+            }
+        }
     }
 
     fn redraw(&mut self) {
-        if let Some(current) = &self.content.current {
-            let child = self.content.children.get_mut(current).unwrap();
-            child.redraw();
+        let (panes, separators) = self.geometry();
+        for (key, _, _, _, _) in panes {
+            if let Some(child) = self.content.children.get_mut(&key) {
+                child.redraw();
+            }
+        }
+
+        self.save_cursor();
+        for (orientation, x, y, length) in separators {
+            match orientation {
+                Orientation::Vertical => {
+                    for y in y .. y + length {
+                        goto!(self, x, y);
+                        vprint!(self, "│");
+                    }
+                },
+                Orientation::Horizontal => {
+                    goto!(self, x, y);
+                    for _ in 0 .. length {
+                        vprint!(self, "─");
+                    }
+                },
+            }
         }
+        self.restore_cursor();
+        flush!(self);
     }
 
     fn is_dirty(&self) -> bool {
-        let mut dirty = false;
+        let mut dirty = self.dirty;
         for (_, child) in self.content.children.iter() {
             dirty |= child.is_dirty()
         }
@@ -364,12 +670,12 @@ impl<E> ViewTrait<E> for View<'_, LinearLayout<'_, E>, E> {
         }
 
         let remaining_width = match max_width {
-            Some(max_width) => max_width - min_width,
+            Some(max_width) => max_width.saturating_sub(min_width),
             None => 0,
         };
 
         let remaining_height = match max_height {
-            Some(max_height) => max_height - min_height,
+            Some(max_height) => max_height.saturating_sub(min_height),
             None => 0,
         };
 
@@ -410,11 +716,11 @@ impl<E> ViewTrait<E> for View<'_, LinearLayout<'_, E>, E> {
             };
 
             if self.content.orientation == Orientation::Horizontal && max_width.is_some() {
-               width_spec = Some(cmp::min(width_spec.unwrap(), max_width.unwrap() - self.w.unwrap()));
+               width_spec = Some(cmp::min(width_spec.unwrap(), max_width.unwrap().saturating_sub(self.w.unwrap())));
             }
 
             if self.content.orientation == Orientation::Vertical && max_height.is_some() {
-                height_spec = Some(cmp::min(height_spec.unwrap(), max_height.unwrap() - self.h.unwrap()));
+                height_spec = Some(cmp::min(height_spec.unwrap(), max_height.unwrap().saturating_sub(self.h.unwrap())));
             }
 
             child.measure(width_spec, height_spec);
@@ -430,6 +736,15 @@ impl<E> ViewTrait<E> for View<'_, LinearLayout<'_, E>, E> {
                 },
             }
         }
+
+        // Without constraint from the parent, a layout matching it doesn't know its size yet and
+        // must be given a share of the remaining space
+        if let (Dimension::MatchParent, None) = (&self.width, max_width) {
+            self.w = None;
+        }
+        if let (Dimension::MatchParent, None) = (&self.height, max_height) {
+            self.h = None;
+        }
     }
 
     fn layout(&mut self, top: u16, left: u16) {
@@ -464,14 +779,55 @@ impl<E> ViewTrait<E> for View<'_, LinearLayout<'_, E>, E> {
     }
 }
 
+/// Maximum number of entries kept in the input history of each window
+pub const INPUT_HISTORY_SIZE: usize = 1000;
+
+#[derive(Default)]
 pub struct Input {
     pub buf: String,
     pub tmp_buf: Option<String>,
     pub password: bool,
-    pub history: Vec<String>,
+    // Input history of each window
+    pub histories: HashMap<String, Vec<String>>,
+    // Window whose history is browsed
+    pub history_window: String,
     pub history_index: usize,
+    // Pattern and matching history entry of the ongoing reverse search
+    pub history_search: Option<(String, Option<usize>)>,
     // Used to index code points in buf (don't use it to directly index buf)
     pub cursor: usize,
+    // Last text killed, inserted back by yank
+    pub killed: String,
+    // Previous states of buf and cursor
+    pub undo: Vec<(String, usize)>,
+    // Whether last edit was a char inserted in a word, consecutive ones are undone at once
+    pub inserting: bool,
+    // Shown at the right of the input, as long as the input leaves room for it
+    pub status: Option<String>,
+    // Spell checker of the conversation, misspelled words being underlined
+    pub speller: Option<Rc<RefCell<Speller>>>,
+    // Suggestions the text being completed is replaced by in turn
+    pub suggesting: Option<Suggestions>,
+}
+
+/// Replacements of a word of the input, each shown in turn then the word typed again
+pub struct Suggestions {
+    // Position of the word replaced
+    pub start: usize,
+    pub typed: String,
+    // Replacements and how they are listed in the input
+    pub candidates: Vec<(String, String)>,
+    // Replacement shown, the word typed once past the last one
+    pub index: usize,
+}
+
+impl Suggestions {
+    fn current(&self) -> &str {
+        match self.candidates.get(self.index) {
+            Some((replacement, _)) => replacement,
+            None => &self.typed,
+        }
+    }
 }
 
 impl Input {
@@ -485,6 +841,150 @@ impl Input {
         }
         byte_index
     }
+
+    fn len(&self) -> usize {
+        self.buf.chars().count()
+    }
+
+    fn history(&self) -> &[String] {
+        match self.histories.get(&self.history_window) {
+            Some(history) => history,
+            None => &[],
+        }
+    }
+
+    fn push_history(&mut self, buf: String) {
+        let history = self.histories.entry(self.history_window.clone()).or_insert_with(Vec::new);
+        if history.last() != Some(&buf) {
+            history.push(buf);
+        }
+        if history.len() > INPUT_HISTORY_SIZE {
+            history.drain(..history.len() - INPUT_HISTORY_SIZE);
+        }
+        self.history_index = history.len();
+    }
+
+    /// Index of the most recent history entry containing `pattern`, older than `before`
+    pub fn search_history(&self, pattern: &str, before: usize) -> Option<usize> {
+        let history = self.history();
+        history[..cmp::min(before, history.len())].iter().rposition(|entry| entry.contains(pattern))
+    }
+
+    /// Position of the start of the word before the cursor
+    pub fn word_backward(&self) -> usize {
+        let chars: Vec<char> = self.buf.chars().collect();
+        let mut cursor = self.cursor;
+        while cursor > 0 && !chars[cursor - 1].is_alphanumeric() {
+            cursor -= 1;
+        }
+        while cursor > 0 && chars[cursor - 1].is_alphanumeric() {
+            cursor -= 1;
+        }
+        cursor
+    }
+
+    /// Position of the end of the word after the cursor
+    pub fn word_forward(&self) -> usize {
+        let chars: Vec<char> = self.buf.chars().collect();
+        let mut cursor = self.cursor;
+        while cursor < chars.len() && !chars[cursor].is_alphanumeric() {
+            cursor += 1;
+        }
+        while cursor < chars.len() && chars[cursor].is_alphanumeric() {
+            cursor += 1;
+        }
+        cursor
+    }
+
+    /// Positions of the words of a message to check, commands being left unchecked
+    pub fn words(&self) -> Vec<(usize, usize)> {
+        if self.password || (self.buf.starts_with('/') && !self.buf.starts_with("/me ")) {
+            return Vec::new();
+        }
+
+        let chars: Vec<char> = self.buf.chars().collect();
+        let mut words = Vec::new();
+        let mut cursor = 0;
+        while cursor < chars.len() {
+            let mut end = cursor;
+            while end < chars.len() && !chars[end].is_whitespace() {
+                end += 1;
+            }
+            // Addresses and links aren't words
+            let token: String = chars[cursor..end].iter().collect();
+            if !token.contains('@') && !token.contains("://") && !token.chars().any(|c| c.is_numeric()) {
+                let mut start = cursor;
+                let mut stop = end;
+                while start < stop && !chars[start].is_alphabetic() {
+                    start += 1;
+                }
+                while stop > start && !chars[stop - 1].is_alphabetic() {
+                    stop -= 1;
+                }
+                if start < stop {
+                    words.push((start, stop));
+                }
+            }
+            cursor = end + 1;
+        }
+        words
+    }
+
+    /// Position and text of the shortcode being typed before the cursor, without its colon
+    pub fn shortcode(&self) -> Option<(usize, String)> {
+        let chars: Vec<char> = self.buf.chars().take(self.cursor).collect();
+        let start = chars.iter().rposition(|c| !emoji::is_shortcode_char(*c))?;
+        let shortcode: String = chars[start + 1..].iter().collect();
+        match chars[start] == ':' && (start == 0 || chars[start - 1].is_whitespace()) && shortcode.len() >= 2 {
+            true => Some((start, shortcode)),
+            false => None,
+        }
+    }
+
+    /// Suggestions being cycled through, if the input still shows one before the cursor
+    fn cycling(&self) -> Option<&Suggestions> {
+        let suggestions = self.suggesting.as_ref()?;
+        let shown: String = self.buf.chars().skip(suggestions.start).take(self.cursor.saturating_sub(suggestions.start)).collect();
+        match shown == suggestions.current() {
+            true => Some(suggestions),
+            false => None,
+        }
+    }
+
+    /// Positions of the misspelled words known, the word being typed at the cursor aside
+    pub fn misspelled(&self) -> Vec<(usize, usize)> {
+        let speller = match &self.speller {
+            Some(speller) => speller,
+            None => return Vec::new(),
+        };
+        let chars: Vec<char> = self.buf.chars().collect();
+        let mut speller = speller.borrow_mut();
+        self.words().into_iter().filter(|(start, end)| {
+            if *end == self.cursor {
+                return false;
+            }
+            let word: String = chars[*start..*end].iter().collect();
+            speller.check(&word).is_some()
+        }).collect()
+    }
+
+    fn save_undo(&mut self) {
+        self.inserting = false;
+        self.undo.push((self.buf.clone(), self.cursor));
+    }
+
+    /// Remove text between two cursor positions, keeping it to be yanked
+    fn kill(&mut self, start: usize, end: usize) {
+        if start == end {
+            return;
+        }
+
+        self.save_undo();
+        let range = self.byte_index(start)..self.byte_index(end);
+        self.killed = self.buf[range.clone()].to_string();
+        self.buf.replace_range(range, "");
+        self.cursor = start;
+    }
 }
 
 impl<'a, E> View<'a, Input, E> {
@@ -502,14 +1002,7 @@ impl<'a, E> View<'a, Input, E> {
             cursor_x: None,
             #[cfg(feature = "no-cursor-save")]
             cursor_y: None,
-            content: Input {
-                buf: String::new(),
-                tmp_buf: None,
-                password: false,
-                history: Vec::new(),
-                history_index: 0,
-                cursor: 0,
-            },
+            content: Input::default(),
             event_handler: None,
         }
     }
@@ -522,6 +1015,22 @@ impl<'a, E> View<'a, Input, E> {
     }
 
     pub fn key(&mut self, c: char) {
+        if !self.content.inserting || c == ' ' {
+            self.content.save_undo();
+            self.content.inserting = true;
+        }
+
+        // A :shortcode: is replaced by its emoji once closed
+        if let (':', Some((start, shortcode))) = (c, self.content.shortcode()) {
+            if let Some(emoji) = emoji::get(&shortcode) {
+                let range = self.content.byte_index(start)..self.content.byte_index(self.content.cursor);
+                self.content.buf.replace_range(range, emoji);
+                self.content.cursor = start + emoji.chars().count();
+                self.redraw();
+                return;
+            }
+        }
+
         let byte_index = self.content.byte_index(self.content.cursor);
         self.content.buf.insert(byte_index, c);
         self.content.cursor += 1;
@@ -532,6 +1041,7 @@ impl<'a, E> View<'a, Input, E> {
 
     pub fn backspace(&mut self) {
         if self.content.cursor > 0 {
+            self.content.save_undo();
             let byte_index = self.content.byte_index(self.content.cursor - 1);
             self.content.buf.remove(byte_index);
             while !self.content.buf.is_char_boundary(byte_index) {
@@ -594,16 +1104,17 @@ impl<'a, E> View<'a, Input, E> {
 
             word_start -= 1;
         }
-        self.content.buf.replace_range(self.content.byte_index(word_start)..self.content.byte_index(self.content.cursor), "");
-        self.content.cursor = word_start;
+        self.content.kill(word_start, self.content.cursor);
         if !self.content.password {
             self.redraw();
         }
     }
 
     pub fn delete(&mut self) {
-        if self.content.cursor < self.content.buf.len() {
-            self.content.buf.remove(self.content.cursor);
+        if self.content.cursor < self.content.len() {
+            self.content.save_undo();
+            let byte_index = self.content.byte_index(self.content.cursor);
+            self.content.buf.remove(byte_index);
         }
         if !self.content.password {
             self.redraw();
@@ -618,7 +1129,7 @@ impl<'a, E> View<'a, Input, E> {
     }
 
     pub fn end(&mut self) {
-        self.content.cursor = self.content.buf.len();
+        self.content.cursor = self.content.len();
         if !self.content.password {
             self.redraw();
         }
@@ -627,6 +1138,8 @@ impl<'a, E> View<'a, Input, E> {
     pub fn clear(&mut self) {
         self.content.buf.clear();
         self.content.cursor = 0;
+        self.content.undo.clear();
+        self.content.inserting = false;
         let _ = self.content.tmp_buf.take();
         self.content.password = false;
         goto!(self, self.x, self.y);
@@ -647,7 +1160,7 @@ impl<'a, E> View<'a, Input, E> {
     }
 
     pub fn right(&mut self) {
-        if self.content.cursor < self.content.buf.len() {
+        if self.content.cursor < self.content.len() {
             self.content.cursor += 1;
         }
         if !self.content.password {
@@ -655,85 +1168,470 @@ impl<'a, E> View<'a, Input, E> {
         }
     }
 
-    pub fn password(&mut self) {
-        self.clear();
-        self.content.password = true;
-        vprint!(self, "password: ");
-        flush!(self);
+    pub fn backward_word(&mut self) {
+        self.content.cursor = self.content.word_backward();
+        if !self.content.password {
+            self.redraw();
+        }
     }
 
-    pub fn validate(&mut self) -> (String, bool) {
+    pub fn forward_word(&mut self) {
+        self.content.cursor = self.content.word_forward();
         if !self.content.password {
-            self.content.history.push(self.content.buf.clone());
-            self.content.history_index = self.content.history.len();
+            self.redraw();
         }
-        let buf = self.content.buf.clone();
-        let password = self.content.password;
-        self.clear();
-        (buf, password)
     }
 
-    pub fn previous(&mut self) {
-        if self.content.history_index == 0 {
-            return;
+    pub fn delete_word(&mut self) {
+        let end = self.content.word_forward();
+        self.content.kill(self.content.cursor, end);
+        if !self.content.password {
+            self.redraw();
         }
+    }
 
-        if self.content.tmp_buf.is_none() {
-            self.content.tmp_buf = Some(self.content.buf.clone());
+    pub fn kill_end(&mut self) {
+        let end = self.content.len();
+        self.content.kill(self.content.cursor, end);
+        if !self.content.password {
+            self.redraw();
         }
+    }
 
-        self.content.history_index -= 1;
-        self.content.buf = self.content.history[self.content.history_index].clone();
-        self.content.cursor = self.content.buf.len();
-        self.redraw();
+    pub fn kill_start(&mut self) {
+        self.content.kill(0, self.content.cursor);
+        if !self.content.password {
+            self.redraw();
+        }
     }
 
-    pub fn next(&mut self) {
-        if self.content.history_index == self.content.history.len() {
+    pub fn yank(&mut self) {
+        if self.content.killed.is_empty() {
             return;
         }
 
-        self.content.history_index += 1;
-        if self.content.history_index == self.content.history.len() {
-            self.content.buf = self.content.tmp_buf.take().unwrap();
-        } else {
-            self.content.buf = self.content.history[self.content.history_index].clone();
+        self.content.save_undo();
+        let byte_index = self.content.byte_index(self.content.cursor);
+        let killed = self.content.killed.clone();
+        self.content.buf.insert_str(byte_index, &killed);
+        self.content.cursor += killed.chars().count();
+        if !self.content.password {
+            self.redraw();
         }
-        self.content.cursor = self.content.buf.len();
+    }
 
-        self.redraw();
+    pub fn undo(&mut self) {
+        if let Some((buf, cursor)) = self.content.undo.pop() {
+            self.content.buf = buf;
+            self.content.cursor = cursor;
+            self.content.inserting = false;
+        }
+        if !self.content.password {
+            self.redraw();
+        }
     }
-}
 
-impl<E> ViewTrait<E> for View<'_, Input, E> {
-    fn redraw(&mut self) {
-        goto!(self, self.x, self.y);
-        for _ in 0 .. self.w.unwrap() {
-            vprint!(self, " ");
+    /// Replace the whole content, it can be undone
+    pub fn set(&mut self, buf: &str) {
+        self.content.save_undo();
+        self.content.buf = buf.to_string();
+        self.content.cursor = self.content.len();
+        if !self.content.password {
+            self.redraw();
         }
+    }
 
-        goto!(self, self.x, self.y);
-        vprint!(self, "{}", self.content.buf);
-        goto!(self, self.x + self.content.cursor as u16, self.y);
+    /// Replace the text between `start` and the cursor by the next suggestion if cycling through
+    /// them, or by the first of `candidates`
+    fn cycle<F>(&mut self, start: usize, candidates: F) -> bool
+        where F: FnOnce(&str) -> Vec<(String, String)>
+    {
+        let cycling = self.content.cycling().map_or(false, |suggestions| suggestions.start == start);
+        let replacement = match cycling {
+            true => {
+                let suggestions = self.content.suggesting.as_mut().unwrap();
+                suggestions.index = (suggestions.index + 1) % (suggestions.candidates.len() + 1);
+                suggestions.current().to_string()
+            },
+            false => {
+                let typed: String = self.content.buf.chars().skip(start).take(self.content.cursor - start).collect();
+                let candidates = candidates(&typed);
+                if candidates.is_empty() {
+                    return false;
+                }
+                self.content.save_undo();
+                let suggestions = Suggestions { start: start, typed: typed, candidates: candidates, index: 0 };
+                let replacement = suggestions.current().to_string();
+                self.content.suggesting = Some(suggestions);
+                replacement
+            },
+        };
 
-        flush!(self);
+        let range = self.content.byte_index(start)..self.content.byte_index(self.content.cursor);
+        self.content.buf.replace_range(range, &replacement);
+        self.content.cursor = start + replacement.chars().count();
+        self.redraw();
+        true
     }
-}
 
-pub trait BufferedMessage = fmt::Display + Hash + std::cmp::Eq + std::clone::Clone;
+    /// Replace the misspelled word under the cursor by its first suggestion, then by the next
+    /// ones and back to the word typed on each call
+    pub fn suggest(&mut self) {
+        let speller = match &self.content.speller {
+            Some(speller) => Rc::clone(speller),
+            None => return,
+        };
+        let cursor = self.content.cursor;
+        let start = match self.content.cycling() {
+            Some(suggestions) => suggestions.start,
+            None => match self.content.words().into_iter().find(|(start, end)| *start <= cursor && cursor <= *end) {
+                Some((start, end)) => {
+                    self.content.cursor = end;
+                    start
+                },
+                None => return,
+            },
+        };
+        let suggested = self.cycle(start, |word| {
+            let suggestions = speller.borrow_mut().check(word).unwrap_or_default();
+            suggestions.into_iter().map(|suggestion| (suggestion.clone(), suggestion)).collect()
+        });
+        if !suggested {
+            self.content.cursor = cursor;
+        }
+    }
 
-pub trait Window<T: BufferedMessage, E>: ViewTrait<E> {
+    /// Complete the :shortcode being typed into the emoji it starts, each one in turn, returns
+    /// whether there was one to complete
+    pub fn complete_emoji(&mut self) -> bool {
+        let start = match self.content.cycling() {
+            Some(suggestions) if suggestions.typed.starts_with(':') => suggestions.start,
+            _ => match self.content.shortcode() {
+                Some((start, _)) => start,
+                None => return false,
+            },
+        };
+        self.cycle(start, |typed| {
+            emoji::complete(&typed[1..]).into_iter().map(|(name, emoji)| (emoji.to_string(), format!("{} :{}:", emoji, name))).collect()
+        })
+    }
+
+    /// Insert pasted text at once, line breaks included
+    pub fn paste(&mut self, text: &str) {
+        self.content.save_undo();
+        let byte_index = self.content.byte_index(self.content.cursor);
+        self.content.buf.insert_str(byte_index, text);
+        self.content.cursor += text.chars().count();
+        if !self.content.password {
+            self.redraw();
+        }
+    }
+
+    /// Insert a line break, the whole buffer is still sent as a single message
+    pub fn newline(&mut self) {
+        self.content.inserting = false;
+        self.key('\n');
+    }
+
+    pub fn password(&mut self) {
+        self.clear();
+        self.content.password = true;
+        vprint!(self, "password: ");
+        flush!(self);
+    }
+
+    pub fn validate(&mut self) -> (String, bool) {
+        if !self.content.password && !self.content.buf.is_empty() {
+            let buf = self.content.buf.clone();
+            self.content.push_history(buf);
+        }
+        self.content.history_index = self.content.history().len();
+        let buf = self.content.buf.clone();
+        let password = self.content.password;
+        self.clear();
+        (buf, password)
+    }
+
+    pub fn previous(&mut self) {
+        if self.content.history_index == 0 {
+            return;
+        }
+
+        if self.content.tmp_buf.is_none() {
+            self.content.tmp_buf = Some(self.content.buf.clone());
+        }
+
+        self.content.history_index -= 1;
+        self.content.buf = self.content.history()[self.content.history_index].clone();
+        self.content.cursor = self.content.len();
+        self.redraw();
+    }
+
+    pub fn next(&mut self) {
+        if self.content.history_index == self.content.history().len() {
+            return;
+        }
+
+        self.content.history_index += 1;
+        if self.content.history_index == self.content.history().len() {
+            self.content.buf = self.content.tmp_buf.take().unwrap();
+        } else {
+            self.content.buf = self.content.history()[self.content.history_index].clone();
+        }
+        self.content.cursor = self.content.len();
+
+        self.redraw();
+    }
+
+    /// Browse the history of another window, the text being typed is kept
+    pub fn set_history_window(&mut self, window: &str) {
+        if self.content.history_window == window {
+            return;
+        }
+
+        if let Some(buf) = self.content.tmp_buf.take() {
+            self.content.buf = buf;
+            self.content.cursor = self.content.len();
+        }
+        self.content.history_search = None;
+        self.content.history_window = window.to_string();
+        self.content.history_index = self.content.history().len();
+        self.redraw();
+    }
+
+    pub fn load_histories(&mut self, histories: HashMap<String, Vec<String>>) {
+        self.content.histories = histories;
+        self.content.tmp_buf = None;
+        self.content.history_index = self.content.history().len();
+    }
+
+    /// Incremental reverse search in the history of the current window. The current match is
+    /// kept while it still matches `pattern`, unless `next` asks for an older one.
+    pub fn history_search(&mut self, pattern: &str, next: bool) {
+        let current = match &self.content.history_search {
+            Some((_, Some(index))) => Some(*index),
+            _ => None,
+        };
+
+        let before = match current {
+            Some(index) if next => index,
+            Some(index) => index + 1,
+            None => self.content.history_index,
+        };
+
+        let found = match self.content.search_history(pattern, before) {
+            Some(index) => Some(index),
+            None if next => current,
+            None => None,
+        };
+
+        self.content.history_search = Some((pattern.to_string(), found));
+        self.redraw();
+    }
+
+    /// Stop the reverse search, putting the matching entry in the input if `accept` is set
+    pub fn end_history_search(&mut self, accept: bool) {
+        if let Some((_, Some(index))) = self.content.history_search.take() {
+            if accept {
+                if self.content.tmp_buf.is_none() {
+                    self.content.tmp_buf = Some(self.content.buf.clone());
+                }
+                self.content.history_index = index;
+                self.content.buf = self.content.history()[index].clone();
+                self.content.cursor = self.content.len();
+            }
+        }
+        self.redraw();
+    }
+}
+
+impl<E> ViewTrait<E> for View<'_, Input, E> {
+    fn redraw(&mut self) {
+        goto!(self, self.x, self.y);
+        for _ in 0 .. self.w.unwrap() {
+            vprint!(self, " ");
+        }
+
+        goto!(self, self.x, self.y);
+        if let Some((pattern, found)) = &self.content.history_search {
+            let found = match found {
+                Some(index) => self.content.history()[*index].replace('\n', "↵"),
+                None => String::new(),
+            };
+            let prompt = format!("(reverse-i-search)`{}': ", pattern);
+            vprint!(self, "{}{}", prompt, found);
+            goto!(self, self.x + string_width(&prompt) as u16, self.y);
+        } else {
+            // Line breaks are shown as a single char to keep the input on one line
+            let misspelled = self.content.misspelled();
+            let mut buf = String::with_capacity(self.content.buf.len());
+            for (index, c) in self.content.buf.chars().enumerate() {
+                if misspelled.iter().any(|(start, _)| *start == index) {
+                    buf.push_str(&termion::style::Underline.to_string());
+                }
+                buf.push(match c {
+                    '\n' => '↵',
+                    c => c,
+                });
+                if misspelled.iter().any(|(_, end)| *end == index + 1) {
+                    buf.push_str(&termion::style::NoUnderline.to_string());
+                }
+            }
+            vprint!(self, "{}", buf);
+
+            // Suggestions being cycled through are listed instead of the status
+            let width = term_string_visible_len(&buf);
+            let room = (self.w.unwrap() as usize).saturating_sub(width + 2);
+            let listed = self.content.cycling().map(|suggestions| {
+                // From the one shown, as many as fit
+                let first = match suggestions.index < suggestions.candidates.len() {
+                    true => suggestions.index,
+                    false => 0,
+                };
+                let mut listed = String::new();
+                let mut len = 0;
+                for (index, (_, label)) in suggestions.candidates.iter().enumerate().skip(first) {
+                    let label_len = string_width(label) + 1;
+                    if len + label_len > room {
+                        break;
+                    }
+                    len += label_len;
+                    match index == suggestions.index {
+                        true => listed.push_str(&format!(" {}{}{}", termion::style::NoInvert, label, termion::style::Invert)),
+                        false => listed.push_str(&format!(" {}", label)),
+                    }
+                }
+                listed
+            });
+            if let Some(status) = listed.as_ref().or(self.content.status.as_ref()) {
+                let len = term_string_visible_len(status);
+                if width + len + 1 < self.w.unwrap() as usize {
+                    goto!(self, self.x + self.w.unwrap() - len as u16, self.y);
+                    vprint!(self, "{}{}{}", termion::style::Invert, status, termion::style::NoInvert);
+                }
+            }
+            let before: String = self.content.buf.chars().take(self.content.cursor).map(|c| match c {
+                '\n' => '↵',
+                c => c,
+            }).collect();
+            goto!(self, self.x + string_width(&before) as u16, self.y);
+        }
+
+        flush!(self);
+    }
+}
+
+pub trait BufferedMessage = fmt::Display + Hash + std::cmp::Eq + std::clone::Clone;
+
+pub trait Window<T: BufferedMessage, E>: ViewTrait<E> {
     fn recv_message(&mut self, message: &T, print: bool);
+    fn replace_message(&mut self, message: &T);
+    fn clear(&mut self);
     fn send_message(&self);
     fn page_up(&mut self);
     fn page_down(&mut self);
+    /// Scroll by some lines, as with the mouse wheel
+    fn scroll_up(&mut self, count: usize);
+    fn scroll_down(&mut self, count: usize);
+    fn page_end(&mut self);
+    fn search(&mut self, pattern: &str, next: bool);
+    fn end_search(&mut self, tail: bool);
+    fn refresh(&mut self);
+    fn flush(&mut self);
 }
 
+/// Maximum number of messages kept in a window whose messages are stored in the history, older
+/// ones being dropped from the window. Other windows keep every message.
+pub const BUFFER_SIZE: usize = 5000;
+
 pub struct BufferedWin<T: BufferedMessage> {
     pub next_line: u16,
-    pub buf: Vec<T>,
+    pub buf: VecDeque<T>,
+    // Sequence number of each message, still valid once older messages are dropped
     pub history: HashMap<T, usize>,
+    // Number of messages dropped from the front of the buffer
+    dropped: usize,
+    // Maximum number of messages kept, every message being kept without
+    limit: Option<usize>,
+    // Number of lines scrolled up from the bottom of the buffer
     pub view: usize,
+    pub search: Option<String>,
+    // Index of the line matching the current search
+    pub search_match: Option<usize>,
+    // Rendered lines of the buffer, formatted once when a message is received or replaced
+    lines: VecDeque<String>,
+    // Number of lines of each message
+    counts: VecDeque<usize>,
+    // Rows each line is wrapped to with the column they start at, laid out when first drawn
+    layout: VecDeque<Option<Vec<(String, usize)>>>,
+    // Width the lines are laid out for
+    layout_width: usize,
+    // Rows currently on screen, only rows that changed are written on redraw
+    drawn: Vec<String>,
+    // Line and column within it each row on screen starts at, to find what is clicked
+    origins: Vec<(usize, usize)>,
+    // Whether messages were received without being drawn yet
+    unflushed: bool,
+}
+
+impl<T: BufferedMessage> BufferedWin<T> {
+    fn format(message: &T) -> Vec<String> {
+        format!("{}", message).lines().map(str::to_owned).collect()
+    }
+
+    /// Drop the oldest message, keeping the same lines on screen
+    fn drop_oldest(&mut self) {
+        let message = match self.buf.pop_front() {
+            Some(message) => message,
+            None => return,
+        };
+        self.history.remove(&message);
+        self.dropped += 1;
+        let count = self.counts.pop_front().unwrap_or(0);
+        self.lines.drain(..count);
+        self.layout.drain(..count);
+        self.origins.clear();
+        self.view = cmp::min(self.view, self.lines.len());
+        self.search_match = match self.search_match {
+            Some(line) if line >= count => Some(line - count),
+            _ => None,
+        };
+    }
+
+    /// Rows a line is wrapped to, laid out once for the current width
+    fn layout(&mut self, line: usize) -> &[(String, usize)] {
+        let lines = &self.lines;
+        let width = self.layout_width;
+        self.layout[line].get_or_insert_with(|| {
+            let wrapped = term_string_wrap(&lines[line], width);
+            let offsets: Vec<usize> = wrapped.iter().scan(0, |offset, row| {
+                let start = *offset;
+                *offset += term_string_visible_len(row);
+                Some(start)
+            }).collect();
+            wrapped.into_iter().zip(offsets).collect()
+        })
+    }
+
+    fn has_status(&self) -> bool {
+        self.view > 0 || self.search.is_some()
+    }
+
+    /// Message of the search match, the last one when not searching
+    pub fn selected(&self) -> Option<&T> {
+        let line = match self.search_match {
+            Some(line) => line,
+            None => return self.buf.back(),
+        };
+        let mut start = 0;
+        for (message, count) in self.buf.iter().zip(&self.counts) {
+            start += count;
+            if line < start {
+                return Some(message);
+            }
+        }
+        None
+    }
 }
 
 impl<'a, T: BufferedMessage, E> View<'a, BufferedWin<T>, E> {
@@ -753,9 +1651,20 @@ impl<'a, T: BufferedMessage, E> View<'a, BufferedWin<T>, E> {
             cursor_y: None,
             content: BufferedWin {
                 next_line: 0,
-                buf: Vec::new(),
+                buf: VecDeque::new(),
                 history: HashMap::new(),
+                dropped: 0,
+                limit: None,
                 view: 0,
+                search: None,
+                search_match: None,
+                lines: VecDeque::new(),
+                counts: VecDeque::new(),
+                layout: VecDeque::new(),
+                layout_width: 0,
+                drawn: Vec::new(),
+                origins: Vec::new(),
+                unflushed: false,
             },
             event_handler: None,
         }
@@ -767,6 +1676,27 @@ impl<'a, T: BufferedMessage, E> View<'a, BufferedWin<T>, E> {
         self.event_handler = Some(Rc::new(RefCell::new(Box::new(event_handler))));
         self
     }
+
+    /// Drop the oldest messages beyond a number of messages, none keeping every message
+    pub fn with_limit(mut self, limit: Option<usize>) -> Self {
+        self.content.limit = limit;
+        self
+    }
+
+    /// Keep every message from now on
+    pub fn unlimit(&mut self) {
+        self.content.limit = None;
+    }
+
+    /// Word drawn at a position of the screen, a wrapped word being found whole
+    pub fn word_at(&self, x: u16, y: u16) -> Option<String> {
+        if x < self.x || x >= self.x + self.w.unwrap_or(0) || y < self.y {
+            return None;
+        }
+        let (line, offset) = *self.content.origins.get((y - self.y) as usize)?;
+        let line = term_string_visible(self.content.lines.get(line)?);
+        word_at(&line, offset + (x - self.x) as usize).map(String::from)
+    }
 }
 
 impl<T: BufferedMessage, E> Window<T, E> for View<'_, BufferedWin<T>, E> {
@@ -775,26 +1705,89 @@ impl<T: BufferedMessage, E> Window<T, E> for View<'_, BufferedWin<T>, E> {
             return;
         }
 
-        self.content.history.insert(message.clone(), self.content.buf.len());
-        self.content.buf.push(message.clone());
+        self.content.history.insert(message.clone(), self.content.dropped + self.content.buf.len());
+        self.content.buf.push_back(message.clone());
+
+        let lines = BufferedWin::format(message);
+        let count = lines.len();
+        self.content.lines.extend(lines);
+        self.content.counts.push_back(count);
+        self.content.layout.extend(iter::repeat(None).take(count));
+        if self.content.limit.map_or(false, |limit| self.content.buf.len() > limit) {
+            self.content.drop_oldest();
+        }
+
+        // Keep showing the same lines when scrolled up
+        if self.content.view > 0 {
+            self.content.view += count;
+        }
 
         if print {
             self.redraw();
+        } else {
+            self.content.unflushed = true;
         }
     }
 
+    /// Update a message already received, equal messages being replaced. Only its lines are
+    /// formatted and laid out again.
+    fn replace_message(&mut self, message: &T) {
+        let sequence = match self.content.history.remove(message) {
+            Some(sequence) => sequence,
+            None => return,
+        };
+
+        self.content.history.insert(message.clone(), sequence);
+        let index = sequence - self.content.dropped;
+        self.content.buf[index] = message.clone();
+
+        let start: usize = self.content.counts.range(..index).sum();
+        let count = self.content.counts[index];
+        self.content.lines.drain(start..start + count);
+        self.content.layout.drain(start..start + count);
+        let lines = BufferedWin::format(message);
+        self.content.counts[index] = lines.len();
+        for (offset, line) in lines.into_iter().enumerate() {
+            self.content.lines.insert(start + offset, line);
+            self.content.layout.insert(start + offset, None);
+        }
+        self.content.unflushed = true;
+    }
+
+    fn clear(&mut self) {
+        self.content.buf.clear();
+        self.content.history.clear();
+        self.content.dropped = 0;
+        self.content.view = 0;
+        self.content.search = None;
+        self.content.search_match = None;
+        self.refresh();
+        self.content.unflushed = true;
+    }
+
     fn page_up(&mut self) {
-        let buffers = self.content.buf.iter().flat_map(|m| format!("{}", m).lines().map(str::to_owned).collect::<Vec<_>>());
-        let count = buffers.collect::<Vec<_>>().len();
+        // One line is used by the status line once scrolled
+        let height = (self.h.unwrap() as usize).saturating_sub(1);
+        self.scroll_up(height);
+    }
 
-        if count < self.h.unwrap() as usize {
+    fn page_down(&mut self) {
+        let height = (self.h.unwrap() as usize).saturating_sub(1);
+        self.scroll_down(height);
+    }
+
+    fn scroll_up(&mut self, count: usize) {
+        let lines = self.content.lines.len();
+        let height = (self.h.unwrap() as usize).saturating_sub(1);
+
+        if lines <= height {
             return;
         }
 
-        let max = count - self.h.unwrap() as usize;
+        let max = lines - height;
 
-        if self.content.view + (self.h.unwrap() as usize) < max {
-            self.content.view += self.h.unwrap() as usize;
+        if self.content.view + count < max {
+            self.content.view += count;
         } else {
             self.content.view = max;
         }
@@ -802,50 +1795,168 @@ impl<T: BufferedMessage, E> Window<T, E> for View<'_, BufferedWin<T>, E> {
         self.redraw();
     }
 
-    fn page_down(&mut self) {
-        if self.content.view > self.h.unwrap() as usize {
-            self.content.view -= self.h.unwrap() as usize;
+    fn scroll_down(&mut self, count: usize) {
+        if self.content.view > count {
+            self.content.view -= count;
         } else {
             self.content.view = 0;
         }
         self.redraw();
     }
 
+    fn page_end(&mut self) {
+        if self.content.view > 0 {
+            self.content.view = 0;
+            self.redraw();
+        }
+    }
+
+    fn search(&mut self, pattern: &str, next: bool) {
+        self.content.search = Some(pattern.to_string());
+
+        if pattern.len() > 0 {
+            let lines = &self.content.lines;
+            let until = match self.content.search_match {
+                Some(current) if next => current,
+                // Current match is still a candidate when refining the pattern
+                Some(current) => cmp::min(current + 1, lines.len()),
+                None => lines.len(),
+            };
+
+            let count = lines.len();
+            if let Some(found) = lines.range(..until).rposition(|line| term_string_visible(line).contains(pattern)) {
+                self.content.search_match = Some(found);
+                self.content.view = count - found - 1;
+            }
+        }
+
+        self.redraw();
+    }
+
+    fn end_search(&mut self, tail: bool) {
+        self.content.search = None;
+        self.content.search_match = None;
+        if tail {
+            self.content.view = 0;
+        }
+        self.redraw();
+    }
+
+    fn refresh(&mut self) {
+        self.content.lines.clear();
+        self.content.counts.clear();
+        for message in &self.content.buf {
+            let lines = BufferedWin::format(message);
+            self.content.counts.push_back(lines.len());
+            self.content.lines.extend(lines);
+        }
+        self.content.layout = self.content.lines.iter().map(|_| None).collect();
+        self.content.drawn.clear();
+    }
+
+    /// Draw messages received since last redraw
+    fn flush(&mut self) {
+        if self.content.unflushed {
+            self.redraw();
+        }
+    }
+
     fn send_message(&self) {
     }
 }
 
 impl<T: BufferedMessage, E> ViewTrait<E> for View<'_, BufferedWin<T>, E> {
+    fn layout(&mut self, top: u16, left: u16) {
+        self.x = left;
+        self.y = top;
+        self.dirty = false;
+        // Whatever is on screen at the new position has to be overwritten
+        self.content.drawn.clear();
+    }
+
     fn redraw(&mut self) {
-        self.save_cursor();
+        self.content.unflushed = false;
+        let height = self.h.unwrap() as usize;
+        if height == 0 {
+            return;
+        }
 
-        self.content.next_line = 0;
-        let buffers = self.content.buf.iter().flat_map(|m| format!("{}", m).lines().map(str::to_owned).collect::<Vec<_>>());
-        let count = buffers.collect::<Vec<_>>().len();
+        let mut lines_height = height;
+        if self.content.has_status() {
+            lines_height -= 1;
+        }
 
-        let mut buffers = self.content.buf.iter().flat_map(|m| format!("{}", m).lines().map(str::to_owned).collect::<Vec<_>>());
+        let mut rows = Vec::with_capacity(height);
+        let mut origins = Vec::with_capacity(height);
+        {
+            // Lines are laid out again for a new width, as they are shown
+            let width = self.w.unwrap() as usize;
+            if self.content.layout_width != width {
+                self.content.layout_width = width;
+                self.content.layout.iter_mut().for_each(|layout| *layout = None);
+            }
 
-        if count > self.h.unwrap() as usize {
-            for _ in 0 .. count - self.h.unwrap() as usize - self.content.view {
-                if buffers.next().is_none() {
-                    break;
+            // Fill the window from its last line, lines longer than the window being wrapped
+            let lines = self.content.lines.len();
+            let search_match = self.content.search_match;
+            let mut line = lines - cmp::min(self.content.view, lines);
+            while line > 0 && rows.len() < lines_height {
+                line -= 1;
+                for (row, offset) in self.content.layout(line).iter().rev() {
+                    origins.push((line, *offset));
+                    match search_match == Some(line) {
+                        true => rows.push(format!("{}{}{}", termion::style::Invert, row, termion::style::NoInvert)),
+                        false => rows.push(row.clone()),
+                    }
                 }
             }
+            rows.truncate(lines_height);
+            rows.reverse();
+            rows.resize(lines_height, String::new());
+            origins.truncate(lines_height);
+            origins.reverse();
         }
+        self.content.origins = origins;
 
-        for y in self.y .. self.y + self.h.unwrap() {
+        if self.content.has_status() {
+            let mut status = String::new();
+            if let Some(search) = &self.content.search {
+                status.push_str(&i18n::trf("(search) `{}'", &[search]));
+                status.push(' ');
+            }
+            if self.content.view > 0 {
+                status.push_str(&i18n::tr("-- more messages below --"));
+            }
+            rows.push(format!("{}{}", termion::style::Invert, status));
+        }
+
+        self.save_cursor();
+
+        self.content.next_line = 0;
+        for (index, row) in rows.iter().enumerate() {
+            if index < lines_height && !row.is_empty() {
+                self.content.next_line += 1;
+            }
+
+            if self.content.drawn.get(index) == Some(row) {
+                continue;
+            }
+
+            let y = self.y + index as u16;
             goto!(self, self.x, y);
+            if index == lines_height {
+                // Status line is fully inverted
+                vprint!(self, "{}", termion::style::Invert);
+            }
             for _ in self.x  .. self.x + self.w.unwrap() {
                 vprint!(self, " ");
             }
-
             goto!(self, self.x, y);
-            if let Some(buf) = buffers.next() {
-                vprint!(self, "{}", buf);
-                self.content.next_line += 1;
-            }
+            vprint!(self, "{}{}", row, termion::style::NoInvert);
         }
 
+        self.content.drawn = rows;
+
         self.restore_cursor();
         flush!(self);
     }
@@ -854,7 +1965,8 @@ impl<T: BufferedMessage, E> ViewTrait<E> for View<'_, BufferedWin<T>, E> {
 pub struct ListView<G, V>
     where G: fmt::Display + Hash + std::cmp::Eq, V: fmt::Display + Hash + std::cmp::Eq
 {
-    items: HashMap<Option<G>, HashSet<V>>,
+    // Items of each group, with their rendering and its visible length
+    items: HashMap<Option<G>, HashMap<V, (String, usize)>>,
 }
 
 impl<'a, G: fmt::Display + Hash + std::cmp::Eq, V: fmt::Display + Hash + std::cmp::Eq, E> View<'a, ListView<G, V>, E> {
@@ -888,29 +2000,62 @@ impl<'a, G: fmt::Display + Hash + std::cmp::Eq, V: fmt::Display + Hash + std::cm
 
     pub fn with_none_group(mut self) -> Self {
         if let Entry::Vacant(vacant) = self.content.items.entry(None) {
-            vacant.insert(HashSet::new());
+            vacant.insert(HashMap::new());
         }
         self
     }
 
     pub fn add_group(&mut self, group: G) {
         if let Entry::Vacant(vacant) = self.content.items.entry(Some(group)) {
-            vacant.insert(HashSet::new());
+            vacant.insert(HashMap::new());
         }
     }
 
     pub fn insert(&mut self, item: V, group: Option<G>) {
-        match self.content.items.entry(group) {
-            Entry::Vacant(vacant) => {
-                let mut items = HashSet::new();
-                items.insert(item);
-                vacant.insert(items);
-            },
-            Entry::Occupied(mut occupied) => {
-                occupied.get_mut().replace(item);
+        let indent = match group {
+            Some(_) => "  ",
+            None => "",
+        };
+        let rendered = format!("{}{}", indent, item);
+        let len = term_string_visible_len(&rendered);
+
+        let items = self.content.items.entry(group).or_insert_with(HashMap::new);
+        // Replace the item itself too, not only its rendering
+        items.remove(&item);
+        items.insert(item, (rendered, len));
+
+        // Only relayout everything when the list needs more room
+        let width = self.w;
+        self.measure(None, self.h);
+        if self.w != width {
+            self.w = width;
+            self.dirty = true
+        } else if self.h.is_some() {
+            self.redraw();
+        }
+    }
+
+    /// Keep only the items matching a predicate
+    pub fn retain<F: Fn(&V) -> bool>(&mut self, keep: F) {
+        for items in self.content.items.values_mut() {
+            items.retain(|item, _| keep(item));
+        }
+        self.dirty = true;
+    }
+
+    /// Render all items again, when the theme changed
+    pub fn refresh(&mut self) {
+        for (group, items) in self.content.items.iter_mut() {
+            let indent = match group {
+                Some(_) => "  ",
+                None => "",
+            };
+
+            for (item, (rendered, len)) in items.iter_mut() {
+                *rendered = format!("{}{}", indent, item);
+                *len = term_string_visible_len(rendered);
             }
         }
-        self.dirty = true
     }
 }
 
@@ -925,13 +2070,8 @@ impl<G: fmt::Display + Hash + std::cmp::Eq, V: fmt::Display + Hash + std::cmp::E
                         width = cmp::max(width, term_string_visible_len(&format!("{}", group)) as u16);
                     }
 
-                    let indent = match group {
-                        Some(_) => "  ",
-                        None => "",
-                    };
-
-                    for item in items {
-                        width = cmp::max(width, term_string_visible_len(&format!("{}{}", indent, item)) as u16);
+                    for (_, len) in items.values() {
+                        width = cmp::max(width, *len as u16);
                     }
                 }
                 match width_spec {
@@ -987,18 +2127,23 @@ impl<G: fmt::Display + Hash + std::cmp::Eq, V: fmt::Display + Hash + std::cmp::E
         }
 
         for (group, items) in &self.content.items {
+            if y >= self.y + self.h.unwrap() {
+                break;
+            }
+
             goto!(self, self.x, y);
             if group.is_some() {
                 vprint!(self, "{}", group.as_ref().unwrap());
                 y += 1;
             }
 
-            for item in items {
+            for (rendered, _) in items.values() {
+                if y >= self.y + self.h.unwrap() {
+                    break;
+                }
+
                 goto!(self, self.x, y);
-                match group {
-                    Some(_) => vprint!(self, "  {}", item),
-                    None => vprint!(self, "{}", item),
-                };
+                vprint!(self, "{}", rendered);
 
                 y += 1;
             }
@@ -1009,6 +2154,177 @@ impl<G: fmt::Display + Hash + std::cmp::Eq, V: fmt::Display + Hash + std::cmp::E
     }
 }
 
+/// State of the parser of the escape sequences written to an offscreen buffer
+#[cfg(test)]
+enum Escape {
+    None,
+    /// After ESC
+    Start,
+    /// Control sequence, with its parameters so far
+    Csi(String),
+    /// Operating system command, ignored until BEL or ST
+    Osc,
+}
+
+#[cfg(test)]
+struct Grid {
+    width: u16,
+    height: u16,
+    cells: Vec<Vec<char>>,
+    /// Cursor position, from 1 as in escape sequences
+    x: u16,
+    y: u16,
+    saved: (u16, u16),
+    escape: Escape,
+    /// Bytes of a character split between two writes
+    partial: Vec<u8>,
+}
+
+#[cfg(test)]
+impl Grid {
+    fn put(&mut self, c: char) {
+        match self.escape {
+            Escape::None => match c {
+                '\x1B' => self.escape = Escape::Start,
+                '\r' => self.x = 1,
+                '\n' => self.y += 1,
+                '\x07' => {},
+                c => {
+                    // Wrap at the right margin like terminals do
+                    if self.x > self.width {
+                        self.x = 1;
+                        self.y += 1;
+                    }
+                    if self.x >= 1 && self.y >= 1 && self.y <= self.height {
+                        self.cells[self.y as usize - 1][self.x as usize - 1] = c;
+                    }
+                    self.x += 1;
+                },
+            },
+            Escape::Start => self.escape = match c {
+                '[' => Escape::Csi(String::new()),
+                ']' => Escape::Osc,
+                _ => Escape::None,
+            },
+            Escape::Csi(ref mut params) => match c {
+                '\x20'..='\x3f' => params.push(c),
+                c => {
+                    let params = params.clone();
+                    self.escape = Escape::None;
+                    self.csi(&params, c);
+                },
+            },
+            Escape::Osc => match c {
+                '\x07' | '\\' => self.escape = Escape::None,
+                _ => {},
+            },
+        }
+    }
+
+    fn csi(&mut self, params: &str, command: char) {
+        let mut args = params.split(';').map(|arg| arg.parse::<u16>().ok());
+        let mut arg = |default| args.next().flatten().unwrap_or(default);
+        match command {
+            'H' => {
+                self.y = arg(1);
+                self.x = arg(1);
+            },
+            'J' if params == "2" => {
+                for row in self.cells.iter_mut() {
+                    row.iter_mut().for_each(|cell| *cell = ' ');
+                }
+            },
+            'K' => {
+                if let Some(row) = self.cells.get_mut(self.y as usize - 1) {
+                    row.iter_mut().skip(self.x as usize - 1).for_each(|cell| *cell = ' ');
+                }
+            },
+            'C' => self.x += arg(1),
+            'D' => self.x = self.x.saturating_sub(arg(1)),
+            's' => self.saved = (self.x, self.y),
+            'u' => {
+                let (x, y) = self.saved;
+                self.x = x;
+                self.y = y;
+            },
+            // Colors, styles and terminal modes aren't rendered
+            _ => {},
+        }
+    }
+}
+
+/// Screen rendering the views into a grid of characters instead of a terminal, for snapshot
+/// tests of the layouts. Clones share the same grid.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct Offscreen {
+    grid: Rc<RefCell<Grid>>,
+}
+
+#[cfg(test)]
+impl Offscreen {
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            grid: Rc::new(RefCell::new(Grid {
+                width: width,
+                height: height,
+                cells: vec![vec![' '; width as usize]; height as usize],
+                x: 1,
+                y: 1,
+                saved: (1, 1),
+                escape: Escape::None,
+                partial: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn size(&self) -> (u16, u16) {
+        let grid = self.grid.borrow();
+        (grid.width, grid.height)
+    }
+
+    /// Rows of the screen, without trailing spaces
+    pub fn render(&self) -> String {
+        let grid = self.grid.borrow();
+        let rows: Vec<String> = grid.cells.iter().map(|row| row.iter().collect::<String>().trim_end().to_string()).collect();
+        rows.join("\n")
+    }
+}
+
+#[cfg(test)]
+impl Terminal for Offscreen {
+    fn suspend_raw_mode(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn activate_raw_mode(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Write for Offscreen {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut grid = self.grid.borrow_mut();
+        let mut bytes = std::mem::replace(&mut grid.partial, Vec::new());
+        bytes.extend_from_slice(buf);
+
+        let valid = match std::str::from_utf8(&bytes) {
+            Ok(_) => bytes.len(),
+            Err(err) => err.valid_up_to(),
+        };
+        for c in std::str::from_utf8(&bytes[..valid]).unwrap().chars() {
+            grid.put(c);
+        }
+        grid.partial = bytes[valid..].to_vec();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1019,15 +2335,39 @@ mod tests {
         assert_eq!(term_string_visible_len(&format!("{}ab{}", termion::cursor::Goto(1, 123), termion::color::Bg(termion::color::Red))), 2);
     }
 
+    #[test]
+    fn test_term_string_visible_strips_escape_sequences() {
+        assert_eq!(term_string_visible(&format!("{}a{}b", termion::color::Fg(termion::color::Green), termion::style::Invert)), "ab");
+    }
+
+    #[test]
+    fn test_term_string_wrap() {
+        let red = format!("{}", termion::color::Fg(termion::color::Red));
+        assert_eq!(term_string_wrap(&format!("{}héllo wörld", red), 4), vec![format!("{}héll", red), "o wö".to_string(), "rld".to_string()]);
+        assert_eq!(term_string_wrap("", 4), vec![String::new()]);
+    }
+
+    #[test]
+    fn test_offscreen_interprets_escape_sequences() {
+        let mut screen = Offscreen::new(8, 2);
+        write!(screen, "{}{}héllo{}", termion::clear::All, termion::cursor::Goto(2, 2), termion::cursor::Save).unwrap();
+        // Characters and sequences split between writes
+        screen.write_all(&"é".as_bytes()[..1]).unwrap();
+        screen.write_all(&"é".as_bytes()[1..]).unwrap();
+        screen.write_all(b"\x1b[1;").unwrap();
+        screen.write_all(b"1Hab").unwrap();
+        write!(screen, "{}{}", termion::color::Fg(termion::color::Red), termion::cursor::Restore).unwrap();
+        write!(screen, "!{}?", termion::cursor::Left(2)).unwrap();
+        assert_eq!(screen.render(), "ab\n héll?!");
+    }
+
     #[test]
     fn test_input_byte_index_for_cursor() {
         let input = Input {
             buf: "aça".to_string(),
-            tmp_buf: None,
             password: true,
-            history: Vec::new(),
-            history_index: 0,
             cursor: 1,
+            ..Input::default()
         };
 
         assert_eq!(input.buf.len(), 4);
@@ -1035,4 +2375,199 @@ mod tests {
         assert_eq!(input.byte_index(1), 1);
         assert_eq!(input.byte_index(2), 3);
     }
+
+    #[test]
+    fn test_pane_split_geometry() {
+        let mut panes = Pane::Leaf("console");
+        panes.split(&mut 0, &Orientation::Horizontal, &"chat");
+        panes.split(&mut 1, &Orientation::Vertical, &"channel");
+
+        assert_eq!(panes.leaves(), vec!["console", "chat", "channel"]);
+
+        let mut geometry = Vec::new();
+        let mut separators = Vec::new();
+        panes.geometry(1, 1, 81, 21, &mut geometry, &mut separators);
+
+        assert_eq!(geometry, vec![("console", 1, 1, 40, 21), ("chat", 42, 1, 40, 10), ("channel", 42, 12, 40, 10)]);
+        assert_eq!(separators.len(), 2);
+    }
+
+    #[test]
+    fn test_input_word_movement() {
+        let input = Input {
+            buf: "/join chan@conférence.tld".to_string(),
+            cursor: 10,
+            ..Input::default()
+        };
+
+        assert_eq!(input.word_backward(), 6);
+        assert_eq!(input.word_forward(), 21);
+    }
+
+    #[test]
+    fn test_input_kill_and_undo() {
+        let mut input = Input {
+            buf: "hello wörld".to_string(),
+            cursor: 6,
+            ..Input::default()
+        };
+
+        input.kill(6, 11);
+        assert_eq!(input.buf, "hello ");
+        assert_eq!(input.killed, "wörld");
+
+        let (buf, cursor) = input.undo.pop().unwrap();
+        assert_eq!(buf, "hello wörld");
+        assert_eq!(cursor, 6);
+    }
+
+    #[test]
+    fn test_input_spelling() {
+        let mut input = Input {
+            buf: "Helo, wrld! see https://example.org or me@example.org at 5pm".to_string(),
+            speller: Some(Rc::new(RefCell::new(Speller::known(&[("Helo", Some(&["Hello", "Help"])), ("wrld", Some(&[]))])))),
+            ..Input::default()
+        };
+        assert_eq!(input.words(), vec![(0, 4), (6, 10), (12, 15), (36, 38), (54, 56)]);
+        assert_eq!(input.misspelled(), vec![(0, 4), (6, 10)]);
+        input.cursor = 4;
+        assert_eq!(input.misspelled(), vec![(6, 10)]);
+
+        input.buf = "/join room@conference.server.tld".to_string();
+        assert!(input.misspelled().is_empty());
+
+        let screen: Rc<RefCell<Screen>> = Rc::new(RefCell::new(Box::new(Offscreen::new(40, 1))));
+        let mut view = View::<Input, ()>::new(screen);
+        view.measure(Some(40), Some(1));
+        view.layout(1, 1);
+        view.content = input;
+        view.set("Helo world");
+        view.content.cursor = 2;
+        view.suggest();
+        assert_eq!(view.content.buf, "Hello world");
+        assert_eq!(view.content.cursor, 5);
+        view.suggest();
+        assert_eq!(view.content.buf, "Help world");
+        view.suggest();
+        assert_eq!(view.content.buf, "Helo world");
+        view.suggest();
+        assert_eq!(view.content.buf, "Hello world");
+    }
+
+    #[test]
+    fn test_input_emoji() {
+        let screen: Rc<RefCell<Screen>> = Rc::new(RefCell::new(Box::new(Offscreen::new(40, 1))));
+        let mut view = View::<Input, ()>::new(screen);
+        view.measure(Some(40), Some(1));
+        view.layout(1, 1);
+        for c in "ok :+1: :nope: :thumbs".chars() {
+            view.key(c);
+        }
+        assert_eq!(view.content.buf, "ok 👍 :nope: :thumbs");
+        assert!(view.complete_emoji());
+        assert_eq!(view.content.buf, "ok 👍 :nope: 👍");
+        assert!(view.complete_emoji());
+        assert_eq!(view.content.buf, "ok 👍 :nope: 👎");
+        view.key(' ');
+        assert!(!view.complete_emoji());
+
+        assert_eq!(string_width("👍 a"), 4);
+        assert_eq!(string_width("\u{2764}\u{fe0f}"), 2);
+        // Family emoji joined by zero width joiners
+        assert_eq!(string_width("👨\u{200d}👩\u{200d}👧"), 2);
+        assert_eq!(term_string_wrap("ab👍c", 3), vec!["ab".to_string(), "👍c".to_string()]);
+    }
+
+    #[test]
+    fn test_selected_message() {
+        let screen: Rc<RefCell<Screen>> = Rc::new(RefCell::new(Box::new(Offscreen::new(20, 5))));
+        let mut view = View::<BufferedWin<String>, ()>::new(screen);
+        view.measure(Some(20), Some(5));
+        view.layout(1, 1);
+        assert_eq!(view.content.selected(), None);
+        view.recv_message(&String::from("first\nline"), false);
+        view.recv_message(&String::from("second"), false);
+        assert_eq!(view.content.selected(), Some(&String::from("second")));
+
+        view.search("line", false);
+        assert_eq!(view.content.selected(), Some(&String::from("first\nline")));
+        view.end_search(true);
+        assert_eq!(view.content.selected(), Some(&String::from("second")));
+    }
+
+    #[test]
+    fn test_buffer_ring() {
+        let screen: Rc<RefCell<Screen>> = Rc::new(RefCell::new(Box::new(Offscreen::new(6, 4))));
+        let mut view = View::<BufferedWin<String>, ()>::new(Rc::clone(&screen)).with_limit(Some(BUFFER_SIZE));
+        view.measure(Some(6), Some(4));
+        view.layout(1, 1);
+        for index in 0..BUFFER_SIZE + 2 {
+            view.recv_message(&format!("{}\nline", index), false);
+        }
+        assert_eq!(view.content.buf.len(), BUFFER_SIZE);
+        assert_eq!(view.content.buf.front(), Some(&String::from("2\nline")));
+        assert_eq!(view.content.lines.len(), 2 * BUFFER_SIZE);
+
+        // Messages are found by their sequence number once older ones are dropped
+        view.replace_message(&String::from("2\nline"));
+        assert_eq!(view.content.lines.len(), 2 * BUFFER_SIZE);
+        view.search("5001", false);
+        assert_eq!(view.content.selected(), Some(&format!("{}\nline", BUFFER_SIZE + 1)));
+        view.end_search(true);
+        // Lines are laid out again for the new width
+        view.measure(Some(3), Some(4));
+        view.redraw();
+        assert_eq!(view.content.drawn, vec!["500", "1", "lin", "e"]);
+        // Kept from now on, once messages are no longer stored
+        view.unlimit();
+        view.recv_message(&String::from("unstored"), false);
+        assert_eq!(view.content.buf.len(), BUFFER_SIZE + 1);
+
+        // Messages which aren't stored are all kept
+        let mut view = View::<BufferedWin<String>, ()>::new(screen);
+        for index in 0..BUFFER_SIZE + 2 {
+            view.recv_message(&format!("{}\nline", index), false);
+        }
+        assert_eq!(view.content.buf.len(), BUFFER_SIZE + 2);
+    }
+
+    #[test]
+    fn test_word_at() {
+        assert_eq!(word_at("see https://aparte.dev now", 4), Some("https://aparte.dev"));
+        assert_eq!(word_at("see https://aparte.dev now", 21), Some("https://aparte.dev"));
+        assert_eq!(word_at("see https://aparte.dev now", 3), None);
+        assert_eq!(word_at("👍 ok", 1), Some("👍"));
+        assert_eq!(word_at("👍 ok", 3), Some("ok"));
+        assert_eq!(word_at("👍 ok", 5), None);
+
+        let screen: Rc<RefCell<Screen>> = Rc::new(RefCell::new(Box::new(Offscreen::new(10, 4))));
+        let mut view = View::<BufferedWin<String>, ()>::new(screen);
+        view.measure(Some(10), Some(4));
+        view.layout(1, 1);
+        view.recv_message(&String::from("go to https://a.tld/x now"), true);
+        // The URL is wrapped over the first three rows
+        assert_eq!(view.word_at(2, 1), Some(String::from("go")));
+        assert_eq!(view.word_at(3, 2), Some(String::from("https://a.tld/x")));
+        assert_eq!(view.word_at(1, 3), Some(String::from("https://a.tld/x")));
+        assert_eq!(view.word_at(1, 4), None);
+    }
+
+    #[test]
+    fn test_input_history_per_window() {
+        let mut input = Input::default();
+        input.history_window = "chat@server.tld".to_string();
+        input.push_history("/me waves".to_string());
+        input.push_history("hello".to_string());
+        input.push_history("hello".to_string());
+        input.push_history("how are you?".to_string());
+
+        assert_eq!(input.history(), &["/me waves", "hello", "how are you?"]);
+        assert_eq!(input.search_history("h", 3), Some(2));
+        assert_eq!(input.search_history("h", 2), Some(1));
+        assert_eq!(input.search_history("waves", 3), Some(0));
+        assert_eq!(input.search_history("bye", 3), None);
+
+        input.history_window = "console".to_string();
+        assert!(input.history().is_empty());
+    }
 }
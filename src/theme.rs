@@ -0,0 +1,335 @@
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+
+const NAMED_COLORS: &[&str] = &[
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    "bright_black", "bright_red", "bright_green", "bright_yellow", "bright_blue", "bright_magenta", "bright_cyan", "bright_white",
+];
+
+pub const BUILTIN_THEMES: &[&str] = &["default", "light", "solarized"];
+
+/// Terminal color: one of the 16 named colors, an entry of the 256 colors palette or a truecolor
+/// value.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Color {
+    Default,
+    Ansi(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "default" {
+            return Ok(Color::Default);
+        }
+
+        if let Some(index) = NAMED_COLORS.iter().position(|name| *name == s) {
+            return Ok(Color::Ansi(index as u8));
+        }
+
+        // Slicing by bytes needs single byte characters
+        if s.starts_with('#') && s.len() == 7 && s.is_ascii() {
+            let component = |index: usize| u8::from_str_radix(&s[index..index + 2], 16);
+            return match (component(1), component(3), component(5)) {
+                (Ok(r), Ok(g), Ok(b)) => Ok(Color::Rgb(r, g, b)),
+                _ => Err(format!("Invalid color {}", s)),
+            };
+        }
+
+        match u8::from_str(s.trim_start_matches("color")) {
+            Ok(index) => Ok(Color::Ansi(index)),
+            Err(_) => Err(format!("Invalid color {}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Color::from_str(&s)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub underline: bool,
+    // Escape sequence of the style, computed once the theme is in use
+    #[serde(skip)]
+    escape: Option<String>,
+}
+
+impl PartialEq for Style {
+    fn eq(&self, other: &Self) -> bool {
+        self.fg == other.fg && self.bg == other.bg && self.bold == other.bold && self.underline == other.underline
+    }
+}
+
+impl Style {
+    fn fg(color: Color) -> Self {
+        Style { fg: Some(color), ..Default::default() }
+    }
+
+    fn bold(mut self) -> Self {
+        self.bold = true;
+        self
+    }
+
+    fn underline(mut self) -> Self {
+        self.underline = true;
+        self
+    }
+
+    fn on(mut self, color: Color) -> Self {
+        self.bg = Some(color);
+        self
+    }
+
+    fn render(&mut self) {
+        self.escape = None;
+        self.escape = Some(self.to_string());
+    }
+
+    fn write_escape(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", termion::style::Reset)?;
+
+        match &self.fg {
+            Some(Color::Ansi(index)) => write!(f, "{}", termion::color::Fg(termion::color::AnsiValue(*index)))?,
+            Some(Color::Rgb(r, g, b)) => write!(f, "{}", termion::color::Fg(termion::color::Rgb(*r, *g, *b)))?,
+            Some(Color::Default) | None => {},
+        }
+
+        match &self.bg {
+            Some(Color::Ansi(index)) => write!(f, "{}", termion::color::Bg(termion::color::AnsiValue(*index)))?,
+            Some(Color::Rgb(r, g, b)) => write!(f, "{}", termion::color::Bg(termion::color::Rgb(*r, *g, *b)))?,
+            Some(Color::Default) | None => {},
+        }
+
+        if self.bold {
+            write!(f, "{}", termion::style::Bold)?;
+        }
+
+        if self.underline {
+            write!(f, "{}", termion::style::Underline)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writing a style resets any previously written one
+impl fmt::Display for Style {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.escape {
+            Some(escape) => f.write_str(escape),
+            None => self.write_escape(f),
+        }
+    }
+}
+
+/// Styles of every element of the interface. Themes from the config file only need to define the
+/// styles they change from the default theme.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Theme {
+    pub text: Style,
+    pub timestamp: Style,
+    pub own_nick: Style,
+    pub nick: Style,
+    pub highlight: Style,
+    pub group: Style,
+    pub presence_available: Style,
+    pub presence_away: Style,
+    pub presence_dnd: Style,
+    pub presence_unavailable: Style,
+    pub bar: Style,
+    pub bar_activity: Style,
+    pub bar_mention: Style,
+    pub url: Style,
+    pub error: Style,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        let bar = Color::Ansi(4);
+        Theme {
+            text: Style::default(),
+            timestamp: Style::default(),
+            own_nick: Style::fg(Color::Ansi(3)),
+            nick: Style::fg(Color::Ansi(2)),
+            highlight: Style::fg(Color::Ansi(3)).bold(),
+            group: Style::fg(Color::Ansi(3)),
+            presence_available: Style::fg(Color::Ansi(2)),
+            presence_away: Style::fg(Color::Ansi(3)),
+            presence_dnd: Style::fg(Color::Ansi(1)),
+            presence_unavailable: Style::fg(Color::Ansi(7)),
+            bar: Style::fg(Color::Ansi(7)).on(bar.clone()),
+            bar_activity: Style::fg(Color::Ansi(7)).on(bar.clone()).bold(),
+            bar_mention: Style::fg(Color::Ansi(3)).on(bar).bold(),
+            url: Style::default().underline(),
+            error: Style::fg(Color::Ansi(1)),
+        }
+    }
+}
+
+impl Theme {
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(Theme::default()),
+            "light" => {
+                let bar = Color::Ansi(7);
+                Some(Theme {
+                    timestamp: Style::fg(Color::Ansi(8)),
+                    own_nick: Style::fg(Color::Ansi(4)),
+                    nick: Style::fg(Color::Ansi(6)),
+                    highlight: Style::fg(Color::Ansi(1)).bold(),
+                    group: Style::fg(Color::Ansi(4)),
+                    presence_away: Style::fg(Color::Ansi(5)),
+                    presence_unavailable: Style::fg(Color::Ansi(8)),
+                    bar: Style::fg(Color::Ansi(0)).on(bar.clone()),
+                    bar_activity: Style::fg(Color::Ansi(0)).on(bar.clone()).bold(),
+                    bar_mention: Style::fg(Color::Ansi(1)).on(bar).bold(),
+                    ..Theme::default()
+                })
+            },
+            "solarized" => {
+                let base02 = Color::Rgb(0x07, 0x36, 0x42);
+                let base1 = Color::Rgb(0x93, 0xa1, 0xa1);
+                Some(Theme {
+                    text: Style::fg(Color::Rgb(0x83, 0x94, 0x96)),
+                    timestamp: Style::fg(Color::Rgb(0x58, 0x6e, 0x75)),
+                    own_nick: Style::fg(Color::Rgb(0x26, 0x8b, 0xd2)),
+                    nick: Style::fg(Color::Rgb(0x2a, 0xa1, 0x98)),
+                    highlight: Style::fg(Color::Rgb(0xcb, 0x4b, 0x16)).bold(),
+                    group: Style::fg(Color::Rgb(0xb5, 0x89, 0x00)),
+                    presence_available: Style::fg(Color::Rgb(0x85, 0x99, 0x00)),
+                    presence_away: Style::fg(Color::Rgb(0xb5, 0x89, 0x00)),
+                    presence_dnd: Style::fg(Color::Rgb(0xdc, 0x32, 0x2f)),
+                    presence_unavailable: Style::fg(Color::Rgb(0x58, 0x6e, 0x75)),
+                    bar: Style::fg(base1.clone()).on(base02.clone()),
+                    bar_activity: Style::fg(base1).on(base02.clone()).bold(),
+                    bar_mention: Style::fg(Color::Rgb(0xcb, 0x4b, 0x16)).on(base02).bold(),
+                    url: Style::fg(Color::Rgb(0x26, 0x8b, 0xd2)).underline(),
+                    error: Style::fg(Color::Rgb(0xdc, 0x32, 0x2f)),
+                })
+            },
+            _ => None,
+        }
+    }
+
+    /// Compute the escape sequences of every style
+    fn render(&mut self) {
+        for style in &mut [
+            &mut self.text, &mut self.timestamp, &mut self.own_nick, &mut self.nick, &mut self.highlight,
+            &mut self.group, &mut self.presence_available, &mut self.presence_away, &mut self.presence_dnd,
+            &mut self.presence_unavailable, &mut self.bar, &mut self.bar_activity, &mut self.bar_mention,
+            &mut self.url, &mut self.error,
+        ] {
+            style.render();
+        }
+    }
+
+    /// Look for a theme defined in the config file, then for a built-in one
+    pub fn find(themes: &HashMap<String, Theme>, name: &str) -> Option<Self> {
+        match themes.get(name) {
+            Some(theme) => Some(theme.clone()),
+            None => Theme::builtin(name),
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Rc<Theme>> = RefCell::new(Rc::new({
+        let mut theme = Theme::default();
+        theme.render();
+        theme
+    }));
+}
+
+/// Theme used to render the interface
+pub fn current() -> Rc<Theme> {
+    CURRENT.with(|current| Rc::clone(&current.borrow()))
+}
+
+pub fn set(mut theme: Theme) {
+    theme.render();
+    CURRENT.with(|current| current.replace(Rc::new(theme)));
+}
+
+thread_local! {
+    /// Color of the nicks in some conversations, by JID, or in every one by the empty string
+    static COLORS: RefCell<HashMap<String, Style>> = RefCell::new(HashMap::new());
+}
+
+/// Color the nicks of a conversation, or of every one without `conversation`, instead of using
+/// the nick style of the theme
+pub fn set_color(conversation: Option<&str>, color: Option<Color>) {
+    let conversation = conversation.unwrap_or("").to_string();
+    COLORS.with(|colors| match color {
+        Some(color) => {
+            let mut style = Style::fg(color);
+            style.render();
+            colors.borrow_mut().insert(conversation, style)
+        },
+        None => colors.borrow_mut().remove(&conversation),
+    });
+}
+
+/// Style of the nicks of the others in a conversation
+pub fn nick(theme: &Theme, conversation: &str) -> Style {
+    COLORS.with(|colors| {
+        let colors = colors.borrow();
+        colors.get(conversation).or_else(|| colors.get("")).cloned().unwrap_or_else(|| theme.nick.clone())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_parsing() {
+        assert_eq!(Color::from_str("default"), Ok(Color::Default));
+        assert_eq!(Color::from_str("yellow"), Ok(Color::Ansi(3)));
+        assert_eq!(Color::from_str("bright_blue"), Ok(Color::Ansi(12)));
+        assert_eq!(Color::from_str("color208"), Ok(Color::Ansi(208)));
+        assert_eq!(Color::from_str("42"), Ok(Color::Ansi(42)));
+        assert_eq!(Color::from_str("#268bd2"), Ok(Color::Rgb(0x26, 0x8b, 0xd2)));
+        assert!(Color::from_str("#26zzd2").is_err());
+        assert!(Color::from_str("#ééé").is_err());
+        assert!(Color::from_str("color256").is_err());
+    }
+
+    #[test]
+    fn test_theme_from_config_overrides_default() {
+        let theme: Theme = toml::from_str(r##"
+            [nick]
+            fg = "#ff8700"
+            bold = true
+        "##).unwrap();
+
+        assert_eq!(theme.nick, Style { fg: Some(Color::Rgb(0xff, 0x87, 0x00)), bold: true, ..Default::default() });
+        assert_eq!(theme.own_nick, Theme::default().own_nick);
+    }
+
+    #[test]
+    fn test_rendered_style_is_unchanged() {
+        let mut style = Style::fg(Color::Ansi(3)).on(Color::Rgb(0x07, 0x36, 0x42)).bold();
+        let escape = style.to_string();
+        style.render();
+        assert_eq!(style.escape.as_deref(), Some(escape.as_str()));
+        assert_eq!(style.to_string(), escape);
+    }
+}
@@ -0,0 +1,219 @@
+//! Trust in the certificates of servers: signed by a known authority, pinned in the config, or
+//! accepted once by the user and trusted until it changes, as ssh does for host keys
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::x509::{X509, X509NameRef};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trust {
+    Authority,
+    Pinned,
+    Accepted,
+}
+
+impl fmt::Display for Trust {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trust::Authority => write!(f, "signed by a known authority"),
+            Trust::Pinned => write!(f, "pinned in the config"),
+            Trust::Accepted => write!(f, "accepted"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Certificate {
+    pub subject: String,
+    pub issuer: String,
+    /// DNS names the certificate is valid for
+    pub names: Vec<String>,
+    /// SHA-256 fingerprint, as AB:CD:…
+    pub fingerprint: String,
+    pub not_before: String,
+    pub not_after: String,
+    /// Days until expiry, negative once expired
+    pub expires_in: i32,
+}
+
+fn name(name: &X509NameRef) -> String {
+    let entries: Vec<String> = name.entries().map(|entry| {
+        let key = entry.object().nid().short_name().unwrap_or("?");
+        let value = entry.data().to_string().unwrap_or_default();
+        format!("{}={}", key, value)
+    }).collect();
+    entries.join(", ")
+}
+
+pub fn fingerprint(digest: &[u8]) -> String {
+    let bytes: Vec<String> = digest.iter().map(|byte| format!("{:02X}", byte)).collect();
+    bytes.join(":")
+}
+
+/// Fingerprint compared regardless of case and separators
+fn normalize(fingerprint: &str) -> String {
+    fingerprint.chars().filter(char::is_ascii_hexdigit).collect::<String>().to_uppercase()
+}
+
+impl Certificate {
+    pub fn from_der(der: &[u8]) -> Result<Self, String> {
+        let certificate = X509::from_der(der).map_err(|err| format!("Invalid certificate: {}", err))?;
+        let digest = certificate.digest(MessageDigest::sha256()).map_err(|err| format!("Cannot hash certificate: {}", err))?;
+        let now = Asn1Time::days_from_now(0).map_err(|err| err.to_string())?;
+        let expires_in = now.diff(certificate.not_after()).map_err(|err| err.to_string())?.days;
+        let names = match certificate.subject_alt_names() {
+            Some(names) => names.iter().filter_map(|name| name.dnsname().map(String::from)).collect(),
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            subject: name(certificate.subject_name()),
+            issuer: name(certificate.issuer_name()),
+            names: names,
+            fingerprint: fingerprint(&digest),
+            not_before: certificate.not_before().to_string(),
+            not_after: certificate.not_after().to_string(),
+            expires_in: expires_in,
+        })
+    }
+
+    pub fn details(&self) -> Vec<String> {
+        let mut details = vec![format!("Subject: {}", self.subject)];
+        if !self.names.is_empty() {
+            details.push(format!("Names: {}", self.names.join(", ")));
+        }
+        details.push(format!("Issuer: {}", self.issuer));
+        details.push(format!("Valid from {} to {}", self.not_before, self.not_after));
+        details.push(format!("SHA-256: {}", self.fingerprint));
+        details
+    }
+}
+
+/// What the certificate of a server is checked against
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    /// Fingerprint set in the config of the account
+    pub pin: Option<String>,
+    /// Fingerprint of the certificate last accepted for the server
+    pub accepted: Option<String>,
+}
+
+impl Policy {
+    /// How a certificate is trusted, `verified` telling whether it is signed by a known authority
+    /// for the domain, or why it isn't
+    pub fn check(&self, certificate: &Certificate, verified: bool) -> Result<Trust, String> {
+        let fingerprint = normalize(&certificate.fingerprint);
+        if let Some(pin) = &self.pin {
+            return match normalize(pin) == fingerprint {
+                true => Ok(Trust::Pinned),
+                false => Err(format!("it doesn't match the fingerprint pinned in the config, {}", pin)),
+            };
+        }
+
+        match (verified, &self.accepted) {
+            (true, _) => Ok(Trust::Authority),
+            (false, Some(accepted)) if normalize(accepted) == fingerprint => Ok(Trust::Accepted),
+            (false, Some(accepted)) => Err(format!("it changed since accepted, it was {}", accepted)),
+            (false, None) => Err(format!("it isn't signed by a known authority")),
+        }
+    }
+}
+
+/// Fingerprints of the certificates accepted by the user, by server
+pub struct Accepted {
+    path: PathBuf,
+    fingerprints: BTreeMap<String, String>,
+}
+
+impl Accepted {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path: path,
+            fingerprints: BTreeMap::new(),
+        }
+    }
+
+    pub fn load(&mut self) -> Result<(), String> {
+        if let Ok(content) = fs::read_to_string(&self.path) {
+            self.fingerprints = toml::from_str(&content).map_err(|err| format!("Cannot read {}: {}", self.path.display(), err))?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, domain: &str) -> Option<&String> {
+        self.fingerprints.get(domain)
+    }
+
+    pub fn accept(&mut self, domain: &str, fingerprint: &str) -> Result<(), String> {
+        self.fingerprints.insert(domain.to_string(), fingerprint.to_string());
+        let content = toml::to_string(&self.fingerprints).map_err(|err| err.to_string())?;
+        fs::write(&self.path, content).map_err(|err| format!("Cannot write {}: {}", self.path.display(), err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::bn::BigNum;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::X509NameBuilder;
+    use openssl::x509::extension::SubjectAlternativeName;
+
+    fn self_signed(days: u32) -> Vec<u8> {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "server.tld").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(days).unwrap()).unwrap();
+        let names = SubjectAlternativeName::new().dns("server.tld").dns("conference.server.tld").build(&builder.x509v3_context(None, None)).unwrap();
+        builder.append_extension(names).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        builder.build().to_der().unwrap()
+    }
+
+    #[test]
+    fn test_certificate() {
+        let certificate = Certificate::from_der(&self_signed(10)).unwrap();
+        assert_eq!(certificate.subject, "CN=server.tld");
+        assert_eq!(certificate.issuer, "CN=server.tld");
+        assert_eq!(certificate.names, vec!["server.tld", "conference.server.tld"]);
+        assert_eq!(certificate.fingerprint.len(), 32 * 3 - 1);
+        assert!(certificate.expires_in == 10 || certificate.expires_in == 9);
+        assert_eq!(fingerprint(&[0xab, 0x01, 0xff]), "AB:01:FF");
+    }
+
+    #[test]
+    fn test_policy() {
+        let certificate = Certificate::from_der(&self_signed(30)).unwrap();
+        let lowercase = certificate.fingerprint.replace(":", "").to_lowercase();
+        let other = fingerprint(&[0; 32]);
+
+        let policy = Policy::default();
+        assert_eq!(policy.check(&certificate, true), Ok(Trust::Authority));
+        assert!(policy.check(&certificate, false).is_err());
+
+        let pinned = Policy { pin: Some(lowercase.clone()), accepted: None };
+        assert_eq!(pinned.check(&certificate, false), Ok(Trust::Pinned));
+        // Pinning is stricter than authorities
+        let pinned = Policy { pin: Some(other.clone()), accepted: None };
+        assert!(pinned.check(&certificate, true).is_err());
+
+        let accepted = Policy { pin: None, accepted: Some(certificate.fingerprint.clone()) };
+        assert_eq!(accepted.check(&certificate, false), Ok(Trust::Accepted));
+        let changed = Policy { pin: None, accepted: Some(other.clone()) };
+        assert_eq!(changed.check(&certificate, false), Err(format!("it changed since accepted, it was {}", other)));
+        assert_eq!(changed.check(&certificate, true), Ok(Trust::Authority));
+    }
+}
@@ -0,0 +1,170 @@
+//! XMPP over WebSocket, RFC 7395: every message carries a whole element, the stream being opened
+//! and closed by elements of their own rather than by the stream header
+use futures::future::{self, Either};
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use std::collections::HashMap;
+use std::io;
+use tokio::net::TcpStream;
+use tokio_tls::TlsStream;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{client_async, WebSocketStream};
+use tokio_xmpp::{Error as XmppError, Packet};
+use url::Url;
+use xmpp_parsers::Element;
+
+use crate::client::{Connection, Stanzas};
+
+const NS_FRAMING: &str = "urn:ietf:params:xml:ns:xmpp-framing";
+pub const NS_STREAMS: &str = "http://etherx.jabber.org/streams";
+
+type Socket = WebSocketStream<TlsStream<TcpStream>>;
+
+fn error(err: WsError) -> XmppError {
+    XmppError::Io(io::Error::new(io::ErrorKind::Other, err.to_string()))
+}
+
+/// Element alone, without the XML declaration
+pub fn serialize(element: &Element) -> String {
+    let xml = String::from(element);
+    match (xml.starts_with("<?xml"), xml.find("?>")) {
+        (true, Some(end)) => xml[end + 2..].to_string(),
+        _ => xml,
+    }
+}
+
+fn packet(text: &str) -> Result<Packet, XmppError> {
+    let element: Element = text.parse().map_err(|err| XmppError::Io(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid element: {}", err))))?;
+    if element.is("open", NS_FRAMING) {
+        let attributes: HashMap<String, String> = element.attrs().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        Ok(Packet::StreamStart(attributes))
+    } else if element.is("close", NS_FRAMING) {
+        Ok(Packet::StreamEnd)
+    } else {
+        Ok(Packet::Stanza(element))
+    }
+}
+
+pub struct WebSocket {
+    socket: Socket,
+    domain: String,
+    features: Element,
+}
+
+/// Open the stream, and wait for the features of the server
+fn start(socket: Socket, domain: String) -> Box<dyn Future<Item = WebSocket, Error = XmppError>> {
+    let open = Element::builder("open").ns(NS_FRAMING).attr("to", domain.as_str()).attr("version", "1.0").build();
+    Box::new(socket.send(Message::Text(serialize(&open))).map_err(error).and_then(move |socket| features(socket, domain)))
+}
+
+fn features(socket: Socket, domain: String) -> Box<dyn Future<Item = WebSocket, Error = XmppError>> {
+    Box::new(socket.into_future().map_err(|(err, _)| error(err)).and_then(move |(message, socket)| {
+        let text = match message {
+            Some(Message::Text(text)) => text,
+            Some(_) => return Either::B(features(socket, domain)),
+            None => return Either::A(future::err(XmppError::Disconnected)),
+        };
+        match packet(&text) {
+            Ok(Packet::Stanza(features)) if features.is("features", NS_STREAMS) => Either::A(future::ok(WebSocket {
+                socket: socket,
+                domain: domain,
+                features: features,
+            })),
+            Ok(Packet::StreamEnd) => Either::A(future::err(XmppError::Disconnected)),
+            Ok(_) => Either::B(features(socket, domain)),
+            Err(err) => Either::A(future::err(err)),
+        }
+    }))
+}
+
+/// Open a stream to `domain` over a connection to the WebSocket service at `url`
+pub fn open(url: Url, stream: TlsStream<TcpStream>, domain: String) -> Box<dyn Future<Item = WebSocket, Error = XmppError>> {
+    let mut request = Request::from(url);
+    request.add_protocol("xmpp".into());
+    Box::new(client_async(request, stream).map_err(error).and_then(move |(socket, _)| start(socket, domain)))
+}
+
+impl Stream for WebSocket {
+    type Item = Packet;
+    type Error = XmppError;
+
+    fn poll(&mut self) -> Poll<Option<Packet>, XmppError> {
+        loop {
+            match self.socket.poll().map_err(error)? {
+                Async::Ready(Some(Message::Text(text))) => return packet(&text).map(|packet| Async::Ready(Some(packet))),
+                // Pings are answered by tungstenite
+                Async::Ready(Some(Message::Binary(_))) | Async::Ready(Some(Message::Ping(_))) | Async::Ready(Some(Message::Pong(_))) => continue,
+                Async::Ready(Some(Message::Close(_))) | Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+impl Sink for WebSocket {
+    type SinkItem = Packet;
+    type SinkError = XmppError;
+
+    fn start_send(&mut self, packet: Packet) -> StartSend<Packet, XmppError> {
+        let text = match &packet {
+            Packet::Stanza(stanza) => serialize(stanza),
+            Packet::StreamEnd => serialize(&Element::builder("close").ns(NS_FRAMING).build()),
+            Packet::StreamStart(_) | Packet::Text(_) => return Ok(AsyncSink::Ready),
+        };
+        match self.socket.start_send(Message::Text(text)).map_err(error)? {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(_) => Ok(AsyncSink::NotReady(packet)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), XmppError> {
+        self.socket.poll_complete().map_err(error)
+    }
+
+    fn close(&mut self) -> Poll<(), XmppError> {
+        self.socket.close().map_err(error)
+    }
+}
+
+impl Stanzas for WebSocket {
+    fn features(&self) -> &Element {
+        &self.features
+    }
+
+    fn restart(self: Box<Self>) -> Box<dyn Future<Item = Connection, Error = XmppError>> {
+        let WebSocket { socket, domain, .. } = *self;
+        Box::new(start(socket, domain).map(|stream| Box::new(stream) as Connection))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_framing() {
+        let open = Element::builder("open").ns(NS_FRAMING).attr("to", "server.tld").build();
+        assert_eq!(serialize(&open), r#"<open xmlns="urn:ietf:params:xml:ns:xmpp-framing" to="server.tld"/>"#);
+
+        match packet(r#"<open xmlns="urn:ietf:params:xml:ns:xmpp-framing" from="server.tld" id="1" version="1.0"/>"#).unwrap() {
+            Packet::StreamStart(attributes) => assert_eq!(attributes["from"], "server.tld"),
+            _ => panic!("Not a stream start"),
+        }
+        match packet(r#"<stream:features xmlns:stream="http://etherx.jabber.org/streams"><bind xmlns="urn:ietf:params:xml:ns:xmpp-bind"/></stream:features>"#).unwrap() {
+            Packet::Stanza(features) => {
+                assert!(features.is("features", NS_STREAMS));
+                assert!(features.get_child("bind", "urn:ietf:params:xml:ns:xmpp-bind").is_some());
+            },
+            _ => panic!("Not features"),
+        }
+        match packet(r#"<message xmlns="jabber:client" from="contact@server.tld"><body>Hi</body></message>"#).unwrap() {
+            Packet::Stanza(message) => assert!(message.is("message", "jabber:client")),
+            _ => panic!("Not a stanza"),
+        }
+        assert!(match packet(r#"<close xmlns="urn:ietf:params:xml:ns:xmpp-framing"/>"#).unwrap() {
+            Packet::StreamEnd => true,
+            _ => false,
+        });
+        assert!(packet("<message></body>").is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::str::FromStr;
+use xmpp_parsers::BareJid;
+
+use crate::theme::BUILTIN_THEMES;
+
+/// Answers given to the first run setup
+#[derive(Debug, Clone)]
+pub struct Setup {
+    pub jid: BareJid,
+    /// Password stored in the config file, asked on connection otherwise
+    pub password: Option<String>,
+    pub autoconnect: bool,
+    pub theme: String,
+    /// Join the channels bookmarked on the server
+    pub autojoin: bool,
+}
+
+fn quote(string: &str) -> String {
+    toml::Value::String(string.to_string()).to_string()
+}
+
+/// Section of the config file describing an account
+fn account_toml(jid: &BareJid, password: Option<&str>, autoconnect: bool, autojoin: bool) -> String {
+    let mut account = String::new();
+    account.push_str(&format!("[accounts.{}]\n", quote(&jid.to_string())));
+    account.push_str(&format!("login = {}\n", quote(&jid.to_string())));
+    if let Some(password) = password {
+        account.push_str(&format!("password = {}\n", quote(password)));
+    }
+    account.push_str(&format!("autoconnect = {}\n", autoconnect));
+    if !autojoin {
+        account.push_str("autojoin = false\n");
+    }
+    account
+}
+
+/// Content of the config file resulting of the setup
+pub fn config_toml(setup: &Setup) -> String {
+    let mut config = String::new();
+    config.push_str(&format!("theme = {}\n\n", quote(&setup.theme)));
+    config.push_str(&account_toml(&setup.jid, setup.password.as_deref(), setup.autoconnect, setup.autojoin));
+    config
+}
+
+fn ask(prompt: &str) -> Result<String, String> {
+    print!("{}", prompt);
+    io::stdout().flush().map_err(|err| format!("{}", err))?;
+
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(0) => Err(format!("Setup aborted")),
+        Ok(_) => Ok(line.trim().to_string()),
+        Err(err) => Err(format!("Cannot read answer: {}", err)),
+    }
+}
+
+fn confirm(prompt: &str, default: bool) -> Result<bool, String> {
+    let choices = if default { "[Y/n]" } else { "[y/N]" };
+    loop {
+        match ask(&format!("{} {} ", prompt, choices))?.to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer yes or no"),
+        }
+    }
+}
+
+fn questions() -> Result<Setup, String> {
+    println!("Welcome to aparté! Let's setup your account, press Ctrl-D to skip.\n");
+
+    let jid = loop {
+        match BareJid::from_str(&ask("Account JID (e.g. me@server.tld): ")?) {
+            Ok(jid) if jid.node.is_some() => break jid,
+            _ => println!("Invalid JID, expected user@server"),
+        }
+    };
+
+    let password = match confirm("Store password in the config file? Otherwise it is asked on each connection.", false)? {
+        true => Some(rpassword::read_password_from_tty(Some("Password: ")).map_err(|err| format!("Cannot read password: {}", err))?),
+        false => None,
+    };
+
+    let autoconnect = confirm("Connect on startup?", true)?;
+
+    let theme = loop {
+        let theme = ask(&format!("Theme ({}) [default]: ", BUILTIN_THEMES.join(", ")))?;
+        if theme.is_empty() {
+            break "default".to_string();
+        } else if BUILTIN_THEMES.contains(&theme.as_str()) {
+            break theme;
+        }
+        println!("Unknown theme {}", theme);
+    };
+
+    let autojoin = confirm("Join channels bookmarked on your server?", true)?;
+
+    Ok(Setup {
+        jid: jid,
+        password: password,
+        autoconnect: autoconnect,
+        theme: theme,
+        autojoin: autojoin,
+    })
+}
+
+/// Open the config file to write a password in it, only readable by the user even when it
+/// already existed, the mode being only applied on creation
+fn open_private(path: &Path, options: &mut OpenOptions) -> Result<File, String> {
+    let file = options.mode(0o600).open(path).map_err(|err| format!("Cannot write {}: {}", path.display(), err))?;
+    file.set_permissions(Permissions::from_mode(0o600)).map_err(|err| format!("Cannot restrict the permissions of {}: {}", path.display(), err))?;
+    Ok(file)
+}
+
+/// Walk the user through the creation of the config file
+pub fn run(path: &Path) -> Result<(), String> {
+    let setup = questions()?;
+
+    let mut file = open_private(path, OpenOptions::new().write(true).create(true).truncate(true))?;
+    file.write_all(config_toml(&setup).as_bytes()).map_err(|err| format!("Cannot write {}: {}", path.display(), err))?;
+
+    println!("\nConfig written to {}", path.display());
+    Ok(())
+}
+
+/// Whether a config file already has an account, by name or by login
+fn has_account(config: &str, jid: &BareJid) -> bool {
+    let jid = jid.to_string();
+    let accounts = match config.parse::<toml::Value>() {
+        Ok(toml::Value::Table(mut config)) => match config.remove("accounts") {
+            Some(toml::Value::Table(accounts)) => accounts,
+            _ => return false,
+        },
+        _ => return false,
+    };
+    accounts.iter().any(|(name, account)| *name == jid || account.get("login").and_then(toml::Value::as_str) == Some(jid.as_str()))
+}
+
+/// Add an account to an existing config file, such as an account just registered, unless it is
+/// already there as a second table of the same name would make the file invalid
+pub fn add_account(path: &Path, jid: &BareJid, password: Option<&str>) -> Result<(), String> {
+    let config = match std::fs::read_to_string(path) {
+        Ok(config) => config,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(format!("Cannot read {}: {}", path.display(), err)),
+    };
+    if has_account(&config, jid) {
+        return Err(format!("{} is already in {}", jid, path.display()));
+    }
+
+    let mut file = open_private(path, OpenOptions::new().append(true).create(true))?;
+    file.write_all(format!("\n{}", account_toml(jid, password, false, true)).as_bytes())
+        .map_err(|err| format!("Cannot write {}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_setup_config_is_valid() {
+        let setup = Setup {
+            jid: BareJid::from_str("me@server.tld").unwrap(),
+            password: Some("p\"ss".to_string()),
+            autoconnect: true,
+            theme: "solarized".to_string(),
+            autojoin: false,
+        };
+
+        let config: Config = toml::from_str(&config_toml(&setup)).unwrap();
+        let account = &config.accounts["me@server.tld"];
+        assert_eq!(account.login, "me@server.tld");
+        assert_eq!(account.password.as_deref(), Some("p\"ss"));
+        assert!(account.autoconnect);
+        assert_eq!(config.theme.as_deref(), Some("solarized"));
+        // Only the channels aren't joined, bookmarks are still used
+        assert!(!config.autojoin(&setup.jid));
+        assert!(config.plugin_enabled("bookmarks"));
+    }
+
+    #[test]
+    fn test_private_config() {
+        let path = std::env::temp_dir().join(format!("aparte-wizard-private-{}.toml", std::process::id()));
+        // Created empty with the default mode before the setup
+        std::fs::write(&path, "").unwrap();
+        std::fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        open_private(&path, OpenOptions::new().write(true).truncate(true)).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_add_account() {
+        let path = std::env::temp_dir().join(format!("aparte-wizard-test-{}.toml", std::process::id()));
+        std::fs::write(&path, "theme = \"solarized\"\n\n[plugins]\nbookmarks = false\n").unwrap();
+        add_account(&path, &BareJid::from_str("new@server.tld").unwrap(), Some("secret")).unwrap();
+        let config: Config = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(config.accounts["new@server.tld"].password.as_deref(), Some("secret"));
+        assert!(config.autojoin(&BareJid::from_str("new@server.tld").unwrap()));
+        assert!(!config.plugin_enabled("bookmarks"));
+    }
+
+    #[test]
+    fn test_add_existing_account() {
+        let path = std::env::temp_dir().join(format!("aparte-wizard-existing-{}.toml", std::process::id()));
+        std::fs::write(&path, "[accounts.work]\nlogin = \"me@work.tld\"\n").unwrap();
+        std::fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        assert!(add_account(&path, &BareJid::from_str("me@work.tld").unwrap(), Some("secret")).is_err());
+        add_account(&path, &BareJid::from_str("new@server.tld").unwrap(), Some("secret")).unwrap();
+        assert!(add_account(&path, &BareJid::from_str("new@server.tld").unwrap(), Some("secret")).is_err());
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let config: Config = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(config.accounts.len(), 2);
+    }
+}